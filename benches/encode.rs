@@ -0,0 +1,70 @@
+//! Encodes a packet of every kind, once into a new buffer for each packet, like `to_packet()`
+//! does, and once into a reused buffer, like the network loop sends them. Before the timings, the
+//! allocations of each are counted and printed:
+//!
+//! ```text
+//! cargo bench --bench encode --no-default-features
+//! ```
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use the_checker_mater::net::interface;
+
+/// Counts the allocations, and leaves them to the system allocator.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// The amount of allocations `f` makes.
+fn allocations(f: impl FnOnce() -> usize) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    black_box(f());
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn encode(c: &mut Criterion) {
+    let packets = interface::sample_packets();
+    // The buffer has grown to the largest packet after the first round, like the send buffer has
+    let mut buf = Vec::new();
+    packets.encode_into(&mut buf);
+
+    println!(
+        "Allocations to encode every packet: {} into new buffers, {} into a reused buffer",
+        allocations(|| packets.encode_each()),
+        allocations(|| packets.encode_into(&mut buf))
+    );
+
+    let mut group = c.benchmark_group("encode");
+    group.bench_function("new buffers", |b| b.iter(|| packets.encode_each()));
+    group.bench_function("reused buffer", |b| {
+        b.iter(|| packets.encode_into(&mut buf))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, encode);
+criterion_main!(benches);
@@ -1,4 +1,4 @@
-
-fn main(){
+fn main() {
+    #[cfg(feature = "gui")]
     slint_build::compile("ui/game_window.slint").unwrap();
 }
@@ -0,0 +1,753 @@
+//! Plays a scripted game over the network, without the GUI. It shows how another frontend can use
+//! the networking stack and the game types, and is built without Slint:
+//!
+//! ```text
+//! cargo run --example headless --no-default-features -- host
+//! cargo run --example headless --no-default-features -- join <join code>
+//! ```
+//!
+//! The peers are two processes, since the network state is global. Each one keeps its own board.
+//! After the script, the side to move asks for a pause, which the other side declines. It asks
+//! again, and the other side accepts. During the pause the client asks the host for its board, like
+//! after a reconnect, which must land it in the pause, and then the other side asks to go on, which
+//! is accepted. Then the side to move offers a draw, which the other side declines. The draw is
+//! offered again and accepted, the side accepting it tells the other one the game is over, and both
+//! print the hash of their board. Both peers send the hash of their board after every move. Between
+//! the offers, the client throws away its board and sends its hash, which must make it resync by
+//! itself, so it ends with the board the host sent. It also pings the host with a payload too large
+//! for one datagram, which must come back intact.
+//!
+//! A rematch offered before the draw is refused, since the game isn't over. The side that offered
+//! the draw offers a rematch again after the game, which the other side accepts. The colors are
+//! swapped in the new game, where the new White makes the first move of the script. Both peers
+//! must have measured a ping by then, since they ping each other.
+//!
+//! `smoke` runs both peers as child processes of its own, over real UDP sockets, and checks that
+//! they end with the same board within `SMOKE_BUDGET`. A real network can fail on its own, so the
+//! whole game is tried again once before it fails:
+//!
+//! ```text
+//! cargo run --example headless --no-default-features -- smoke
+//! ```
+//!
+//! With the `--sim-*` flags the packets we send go through a simulated bad network, e.g. to soak
+//! the protocol under loss:
+//!
+//! ```text
+//! cargo run --example headless --no-default-features -- host --sim-loss 10 --sim-latency 80±20
+//! ```
+//!
+//! At the end, the faults that were injected are printed next to the loss the pings saw, and the
+//! traffic of the session.
+
+use std::{
+    env,
+    io::{BufRead, BufReader},
+    process::{Child, Command, ExitCode, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::{Parser, Subcommand};
+use the_checker_mater::{
+    game::{
+        fen::from_fen, options::GameOptions, position_hash::position_hash, GameAction, Move,
+        PieceColor, PieceData,
+    },
+    net::interface::{
+        self, BoardSync, GameOverReason, GameResult, HostBoard, Latency, NetworkSimulation,
+        OptionsState, PauseState,
+    },
+};
+
+/// The moves of the game, as (index, end, captured) seen from White's side. White makes the first
+/// move. Every move is legal, and a capture is made whenever one can be.
+const SCRIPT: [(usize, usize, &[usize]); 20] = [
+    (23, 19, &[]),
+    (10, 13, &[]),
+    (19, 14, &[]),
+    (11, 18, &[14]),
+    (21, 14, &[18]),
+    (13, 18, &[]),
+    (22, 13, &[18]),
+    (9, 18, &[13]),
+    (14, 10, &[]),
+    (6, 13, &[10]),
+    (26, 22, &[]),
+    (5, 9, &[]),
+    (29, 26, &[]),
+    (2, 6, &[]),
+    (25, 21, &[]),
+    (18, 25, &[21]),
+    (28, 21, &[25]),
+    (13, 18, &[]),
+    (22, 13, &[18]),
+    (9, 25, &[13, 21]),
+];
+
+/// The starting position, seen from White's side.
+const START: &str = "W:W21,22,23,24,25,26,27,28,29,30,31,32:B1,2,3,4,5,6,7,8,9,10,11,12";
+
+/// The line each peer ends with, followed by the hash of its board.
+const FINAL_HASH: &str = "Final position hash:";
+
+/// The longest an attempt of the smoke test may take, from starting the host until both peers
+/// are done.
+const SMOKE_BUDGET: Duration = Duration::from_secs(30);
+/// How many times the smoke test plays the game, before it fails.
+const SMOKE_ATTEMPTS: usize = 2;
+
+/// The payload of the ping the client sends while resyncing, which is split into fragments both
+/// ways.
+const LARGE_PING: usize = 10 * 1024;
+/// How many times the large ping is sent, since a simulated bad network can drop a fragment.
+const LARGE_PING_ATTEMPTS: usize = 5;
+
+/// The longest wait for the other peer: For the answer to a rematch offer, for the first move of
+/// the rematch to be taken, or for the resync after the boards differed.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+
+const POLL: Duration = Duration::from_millis(50);
+
+/// How long White waits before the first move of the rematch. The answer to the rematch offer
+/// starts the new game for the peer that offered it, and the move must not overtake it, even when
+/// it's held back on a simulated bad network.
+const THINK: Duration = Duration::from_millis(500);
+/// How long Black stays after taking the first move of the rematch. The answer to it can be held
+/// back on a simulated bad network, and White sends the move again until it has one.
+const LINGER: Duration = Duration::from_secs(2);
+
+/// Plays a scripted game over the network.
+#[derive(Debug, Parser)]
+struct Args {
+    #[command(subcommand)]
+    role: Role,
+    /// Drop this percent of the sent packets
+    #[arg(long, global = true, value_name = "PERCENT", default_value_t = 0, value_parser = percent())]
+    sim_loss: u8,
+    /// Delay the sent packets this many milliseconds, with optional jitter, e.g. 80±20
+    #[arg(long, global = true, value_name = "MS[±JITTER]")]
+    sim_latency: Option<Latency>,
+    /// Send this percent of the packets twice
+    #[arg(long, global = true, value_name = "PERCENT", default_value_t = 0, value_parser = percent())]
+    sim_dup: u8,
+    /// Hold back this percent of the packets, so later ones arrive first
+    #[arg(long, global = true, value_name = "PERCENT", default_value_t = 0, value_parser = percent())]
+    sim_reorder: u8,
+    /// Seed of the simulation, to repeat the same faults
+    #[arg(long, global = true, value_name = "SEED")]
+    sim_seed: Option<u64>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Role {
+    /// Host a game, and print the join code
+    Host,
+    /// Join the game with a join code
+    Join { join_code: String },
+    /// Play the game between a host and a client started by this, and check that they agree
+    Smoke,
+}
+
+fn percent() -> clap::builder::RangedI64ValueParser<u8> {
+    clap::value_parser!(u8).range(0..=100)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    if let Role::Smoke = args.role {
+        return match smoke() {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                println!("The smoke test failed: {}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    interface::set_network_simulation(NetworkSimulation {
+        loss: args.sim_loss,
+        latency: args.sim_latency.unwrap_or_default(),
+        duplicate: args.sim_dup,
+        reorder: args.sim_reorder,
+        seed: args.sim_seed,
+    });
+
+    // The interface blocks, so it's used from a blocking thread, not from an async task
+    let is_host = matches!(args.role, Role::Host);
+    let game = tokio::task::spawn_blocking(move || {
+        let color = match args.role {
+            Role::Join { join_code } => join(&join_code),
+            Role::Host => host(),
+            Role::Smoke => unreachable!("The smoke test doesn't play itself"),
+        };
+        color.and_then(|color| play(color, is_host))
+    });
+    let result = game.await;
+
+    if let Some(stats) = interface::get_simulation_stats() {
+        println!("Injected: {}", stats);
+    }
+    let ping_loss = interface::get_ping_loss();
+    if ping_loss.sent > 0 {
+        println!(
+            "Observed: {} of {} pings lost ({:.1}%)",
+            ping_loss.lost,
+            ping_loss.sent,
+            ping_loss.percent()
+        );
+    }
+    println!("Traffic: {}", interface::get_connection_stats());
+
+    match result {
+        Ok(Ok(())) => ExitCode::SUCCESS,
+        Ok(Err(e)) => {
+            println!("The game failed: {}", e);
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            println!("The game panicked: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Host a game, and wait for a client to join. Returns our color.
+fn host() -> anyhow::Result<PieceColor> {
+    let join_code = interface::start_lan_host();
+    println!("Hosting. Join with: headless join {}", join_code);
+
+    loop {
+        match interface::get_options_state() {
+            OptionsState::Agreed if interface::is_connected() => break,
+            OptionsState::Mismatch => anyhow::bail!("the client plays with other options"),
+            _ => thread::sleep(POLL),
+        }
+    }
+    interface::get_my_color().ok_or_else(|| anyhow::anyhow!("no color after the coin flip"))
+}
+
+/// Join the game with `join_code`. Returns our color.
+fn join(join_code: &str) -> anyhow::Result<PieceColor> {
+    interface::start_lan_client();
+    let (color, host_username) = interface::connect_to_host_loop(
+        join_code,
+        "headless",
+        interface::JOIN_RETRY,
+        |progress| println!("No answer to attempt {} to join", progress.attempt),
+    )?;
+    println!("Joined {}", host_username);
+    Ok(color)
+}
+
+/// Play the script as `color`. The moves are sent from our own side of the board, where our
+/// pieces are at the bottom, like the GUI does.
+fn play(color: PieceColor, is_host: bool) -> anyhow::Result<()> {
+    println!("Playing as {:?}", color);
+    let options = GameOptions::new();
+    let geometry = options.geometry();
+    let (mut board, _) = from_fen(START)?;
+
+    for (move_number, (index, end, captured)) in SCRIPT.into_iter().enumerate() {
+        let mover = PieceColor::side_to_move(move_number as u16);
+        let white_move = Move {
+            index,
+            end,
+            captured: (!captured.is_empty()).then(|| captured.to_vec()),
+            promoted: !board[index].is_king
+                && options.is_promotion_square(end, mover, PieceColor::White),
+        };
+        let mov = match color {
+            PieceColor::White => white_move.clone(),
+            PieceColor::Black => white_move.reverse(geometry),
+        };
+
+        if mover == color {
+            println!("Move {}: {} -> {}", move_number, mov.index, mov.end);
+            interface::send_game_action(GameAction::MovePiece(mov), move |res| {
+                if let Err(e) = res {
+                    println!("Move {} was rejected: {}", move_number, e);
+                }
+            });
+        } else {
+            match wait_for_action()? {
+                GameAction::MovePiece(theirs) => {
+                    let theirs = theirs.reverse(geometry);
+                    if theirs != mov {
+                        anyhow::bail!("move {} isn't the scripted move: {:?}", move_number, theirs);
+                    }
+                    println!("Move {}: {} -> {}", move_number, theirs.index, theirs.end);
+                }
+                action => anyhow::bail!("expected move {}, got {:?}", move_number, action),
+            }
+        }
+        play_on(&mut board, &white_move);
+        interface::publish_board(board.clone());
+        interface::send_board_hash(&board, move_number as u16 + 1);
+    }
+
+    let move_number = SCRIPT.len() as u16;
+    pause_and_resume(
+        color,
+        PieceColor::side_to_move(move_number) == color,
+        is_host,
+    )?;
+
+    // The first draw offer is declined, and the second one accepted. The host waits for the
+    // client in between, with its last move published, so that's when the client resyncs
+    if PieceColor::side_to_move(move_number) == color {
+        match offer_rematch() {
+            Err(e) if e.is::<interface::GameInProgress>() => {
+                println!("The rematch was refused: {}", e)
+            }
+            answer => anyhow::bail!("expected the rematch to be refused, got {:?}", answer),
+        }
+        for expected in [false, true] {
+            println!("Offering a draw");
+            interface::send_game_action(GameAction::OfferDraw, |_| {});
+            match wait_for_action()? {
+                GameAction::DrawResponse(accepted) if accepted == expected => {
+                    println!("The other player answered the draw offer: {}", accepted)
+                }
+                action => anyhow::bail!("expected a draw answer of {}, got {:?}", expected, action),
+            }
+            if !is_host && !expected {
+                resync(&mut board, move_number)?;
+            }
+        }
+        let result = wait_for_game_over()?;
+        if result.winner.is_some() || result.reason != GameOverReason::DrawAccepted {
+            anyhow::bail!("expected the game to end in a draw, got {:?}", result);
+        }
+        println!(
+            "The other player says the game is over: {:?}",
+            result.reason
+        );
+        println!("Offering a rematch");
+        match offer_rematch()? {
+            Some(new_color) if new_color == color.get_opposite() => {}
+            answer => anyhow::bail!(
+                "expected to play {:?} in the rematch, got {:?}",
+                color.get_opposite(),
+                answer
+            ),
+        }
+    } else {
+        for accept in [false, true] {
+            match wait_for_action()? {
+                GameAction::OfferDraw => println!("The other player offered a draw"),
+                action => anyhow::bail!("expected a draw offer, got {:?}", action),
+            }
+            if !is_host && !accept {
+                resync(&mut board, move_number)?;
+            }
+            println!("Answering the draw offer: {}", accept);
+            interface::send_game_action(GameAction::DrawResponse(accept), |_| {});
+        }
+        // Blocks until the other player has taken it, so the network loop doesn't stop with the
+        // process before
+        println!("Telling the other player the game is over");
+        interface::send_game_over(None, GameOverReason::DrawAccepted)?;
+
+        let offer = wait_for_rematch_offer()?;
+        println!("Accepting the rematch");
+        let answer = interface::answer_rematch(offer, true);
+        if answer != Some(color.get_opposite()) {
+            anyhow::bail!(
+                "expected to play {:?} in the rematch, got {:?}",
+                color.get_opposite(),
+                answer
+            );
+        }
+    }
+
+    let side_to_move = PieceColor::side_to_move(move_number);
+    let hash = position_hash(&board, side_to_move, move_number, &options);
+    play_rematch(color.get_opposite())?;
+    check_ping()?;
+    println!("{} {:016x}", FINAL_HASH, hash);
+    Ok(())
+}
+
+/// Pause the game and go on with it. The player asking for the pause is declined the first time,
+/// and accepted the second time, and the other player asks to go on.
+///
+/// ## Params
+/// * `color` - The color we play.
+/// * `is_asking` - If we ask for the pause.
+/// * `is_host` - If we host, or else resync during the pause, like after a reconnect.
+fn pause_and_resume(color: PieceColor, is_asking: bool, is_host: bool) -> anyhow::Result<()> {
+    if is_asking {
+        for expected in [false, true] {
+            println!("Asking for a pause");
+            interface::send_game_action(GameAction::PauseRequest, |_| {});
+            // The other player may ask to go on right after accepting, so only our own request
+            // has to be gone
+            let pause = wait_for_pause(|pause| pause.request != Some(color))?;
+            if pause.paused != expected {
+                anyhow::bail!("expected a pause of {}, got {:?}", expected, pause);
+            }
+        }
+    } else {
+        for accept in [false, true] {
+            wait_for_pause(|pause| pause.is_asked(color))?;
+            println!("Answering the pause request: {}", accept);
+            interface::send_game_action(GameAction::PauseResponse(accept), |_| {});
+        }
+    }
+    if !is_host {
+        resync_paused()?;
+    }
+
+    if is_asking {
+        wait_for_pause(|pause| pause.is_asked(color))?;
+        println!("Accepting to go on");
+        interface::send_game_action(GameAction::PauseResponse(true), |_| {});
+    } else {
+        println!("Asking to go on");
+        interface::send_game_action(GameAction::ResumeRequest, |_| {});
+    }
+    wait_for_pause(|pause| pause == PauseState::default())?;
+    Ok(())
+}
+
+/// Wait until the pause is as `is_done` wants it.
+fn wait_for_pause(is_done: impl Fn(PauseState) -> bool) -> anyhow::Result<PauseState> {
+    let deadline = Instant::now() + ANSWER_TIMEOUT;
+    while Instant::now() < deadline {
+        let pause = interface::get_pause();
+        if is_done(pause) {
+            return Ok(pause);
+        }
+        thread::sleep(POLL);
+    }
+    anyhow::bail!("the pause stayed at {:?}", interface::get_pause())
+}
+
+/// Ask the host for its board during the pause, like after a reconnect, until the host has taken
+/// that the game is paused. Its pause must be taken as ours.
+fn resync_paused() -> anyhow::Result<()> {
+    let deadline = Instant::now() + ANSWER_TIMEOUT;
+    while Instant::now() < deadline {
+        println!("Asking the host for its board during the pause");
+        interface::request_resync()?;
+        let host = wait_for_resync_board()?;
+        if host.pause.paused {
+            if interface::get_pause() != host.pause {
+                anyhow::bail!("the host's pause {:?} wasn't taken", host.pause);
+            }
+            return Ok(());
+        }
+        thread::sleep(POLL);
+    }
+    anyhow::bail!("the host never sent that the game is paused")
+}
+
+/// Both peers ping each other, so both must have measured a ping by the end of the game.
+fn check_ping() -> anyhow::Result<()> {
+    match (interface::get_ping_average(), interface::get_ping_jitter()) {
+        (Some(average), Some(jitter)) if !average.is_zero() => {
+            println!("Measured a ping of {:?}, with {:?} jitter", average, jitter);
+            Ok(())
+        }
+        ping => anyhow::bail!("no ping was measured, got {:?}", ping),
+    }
+}
+
+/// Offer the other player a rematch, and wait for the answer.
+fn offer_rematch() -> anyhow::Result<Option<PieceColor>> {
+    let (sender, answers) = mpsc::channel();
+    interface::offer_rematch(move |answer| {
+        let _ = sender.send(answer);
+    });
+    answers.recv_timeout(ANSWER_TIMEOUT)?
+}
+
+/// Wait for the other player to offer a rematch.
+fn wait_for_rematch_offer() -> anyhow::Result<interface::RematchOffer> {
+    loop {
+        if let Some(offer) = interface::take_rematch_offer() {
+            return Ok(offer);
+        }
+        if !interface::is_connected() && !interface::is_reconnecting() {
+            anyhow::bail!("the other player disconnected");
+        }
+        thread::sleep(POLL);
+    }
+}
+
+/// Play the first move of the script in the rematch, where we play `color`. The move numbers
+/// start over, so the move is only taken if both peers reset them.
+fn play_rematch(color: PieceColor) -> anyhow::Result<()> {
+    println!("Playing the rematch as {:?}", color);
+    let (index, end, _) = SCRIPT[0];
+    let white_move = Move {
+        index,
+        end,
+        captured: None,
+        promoted: false,
+    };
+
+    if color == PieceColor::White {
+        if interface::get_move_number() != 0 {
+            anyhow::bail!(
+                "the rematch starts at move {}",
+                interface::get_move_number()
+            );
+        }
+        // Like a player, who doesn't move the moment the rematch starts
+        thread::sleep(THINK);
+        let (sender, results) = mpsc::channel();
+        interface::send_game_action(GameAction::MovePiece(white_move), move |res| {
+            let _ = sender.send(res);
+        });
+        results.recv_timeout(ANSWER_TIMEOUT)??;
+        println!("The first move of the rematch was taken");
+        return Ok(());
+    }
+    let mov = white_move.reverse(GameOptions::new().geometry());
+    match wait_for_action()? {
+        GameAction::MovePiece(theirs) if theirs.reverse(GameOptions::new().geometry()) == mov => {
+            println!("The other player made the first move of the rematch");
+        }
+        action => anyhow::bail!("expected the first move of the rematch, got {:?}", action),
+    }
+    // White's move can be taken before we look, so the move number is checked after it
+    if interface::get_move_number() != 1 {
+        anyhow::bail!(
+            "the rematch is at move {}, not move 1",
+            interface::get_move_number()
+        );
+    }
+    thread::sleep(LINGER);
+    Ok(())
+}
+
+/// Throw away our board, and send its hash. The host's hash differs, which must make us take the
+/// host's board by ourselves. The final hash is of the host's board then, so it only matches the
+/// host's if the resync sent it exactly. Then send the `large_ping()`.
+///
+/// ## Params
+/// * `board` - Our board, seen from White's side.
+/// * `move_number` - The number of moves both peers have made.
+fn resync(board: &mut Vec<PieceData>, move_number: u16) -> anyhow::Result<()> {
+    board.fill(PieceData::default());
+    println!("Sending the hash of a board that was thrown away");
+    interface::send_board_hash(board, move_number);
+    match interface::get_board_sync() {
+        Some(BoardSync::Desynced { move_count, .. }) if move_count == move_number => {}
+        sync => anyhow::bail!("expected the boards to differ, got {:?}", sync),
+    }
+    let host = wait_for_resync_board()?;
+    if host.move_number != move_number || host.side_to_move != PieceColor::side_to_move(move_number)
+    {
+        anyhow::bail!(
+            "the host is at move {} with {:?} to move, not at move {}",
+            host.move_number,
+            host.side_to_move,
+            move_number
+        );
+    }
+    *board = host.board;
+    interface::send_board_hash(board, move_number);
+    if !interface::get_board_sync().is_some_and(|sync| sync.is_in_sync()) {
+        anyhow::bail!("the host's board differs from the host's hash");
+    }
+    large_ping()
+}
+
+/// Wait for the host's board, from the resync after our board differed.
+fn wait_for_resync_board() -> anyhow::Result<HostBoard> {
+    let deadline = Instant::now() + ANSWER_TIMEOUT;
+    while Instant::now() < deadline {
+        if let Some(host) = interface::take_resync_board() {
+            return Ok(host);
+        }
+        thread::sleep(POLL);
+    }
+    anyhow::bail!("no resync after the boards differed")
+}
+
+/// Ping the other peer with a payload of `LARGE_PING` bytes, which must be echoed back intact.
+fn large_ping() -> anyhow::Result<()> {
+    for attempt in 1..=LARGE_PING_ATTEMPTS {
+        println!("Pinging with {} bytes, attempt {}", LARGE_PING, attempt);
+        if interface::probe_path(&[LARGE_PING]).largest.is_some() {
+            return Ok(());
+        }
+    }
+    anyhow::bail!("no intact echo of a {} byte ping", LARGE_PING)
+}
+
+/// Play `mov`, seen from White's side, on `board`.
+fn play_on(board: &mut [PieceData], mov: &Move) {
+    let mut piece = std::mem::take(&mut board[mov.index]);
+    for captured in mov.captured.iter().flatten() {
+        board[*captured] = PieceData::default();
+    }
+    piece.is_king |= mov.promoted;
+    board[mov.end] = piece;
+}
+
+/// Wait for the next game action from the other player.
+fn wait_for_action() -> anyhow::Result<GameAction> {
+    loop {
+        if let Some(action) = interface::get_next_game_action() {
+            return Ok(action);
+        }
+        // A lost packet can make the connection look lost for a moment, so only a connection
+        // that isn't coming back ends the game
+        if !interface::is_connected() && !interface::is_reconnecting() {
+            anyhow::bail!("the other player disconnected");
+        }
+        thread::sleep(POLL);
+    }
+}
+
+/// Wait for the other player to tell that the game is over.
+fn wait_for_game_over() -> anyhow::Result<GameResult> {
+    loop {
+        if let Some(result) = interface::take_game_result() {
+            return Ok(result);
+        }
+        if !interface::is_connected() && !interface::is_reconnecting() {
+            anyhow::bail!("the other player disconnected");
+        }
+        thread::sleep(POLL);
+    }
+}
+
+/// Play the game between a host and a client, each in a child process, and check that both end
+/// with the same board. The `--sim-*` flags are passed on to both.
+fn smoke() -> anyhow::Result<()> {
+    let sim_args: Vec<String> = env::args().skip(1).filter(|arg| arg != "smoke").collect();
+    let mut attempt = 1;
+    loop {
+        match smoke_attempt(&sim_args) {
+            Ok(hash) => {
+                println!("Both peers ended with the board {:016x}", hash);
+                return Ok(());
+            }
+            Err(e) if attempt < SMOKE_ATTEMPTS => {
+                println!("Attempt {} failed, trying again: {}", attempt, e);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Play the game once. Returns the hash of the board both peers ended with.
+fn smoke_attempt(sim_args: &[String]) -> anyhow::Result<u64> {
+    let deadline = Instant::now() + SMOKE_BUDGET;
+
+    let mut host = Peer::spawn("host", &[&["host".to_owned()], sim_args].concat())?;
+    let join_code = host
+        .wait_for_line(|line| line.contains("Join with:"), deadline)
+        .and_then(|line| line.split_whitespace().last().map(str::to_owned))
+        .ok_or_else(|| host.failure("the host printed no join code"))?;
+    let join_args = [&["join".to_owned(), join_code], sim_args].concat();
+    let mut client = Peer::spawn("client", &join_args)?;
+
+    let host_hash = host.final_hash(deadline)?;
+    let client_hash = client.final_hash(deadline)?;
+    if host_hash != client_hash {
+        anyhow::bail!(
+            "the host ended with {:016x}, and the client with {:016x}",
+            host_hash,
+            client_hash
+        );
+    }
+    Ok(host_hash)
+}
+
+/// A peer of the smoke test, running this example in a child process.
+struct Peer {
+    name: &'static str,
+    child: Child,
+    lines: mpsc::Receiver<String>,
+    /// Everything the peer printed so far, shown if it fails.
+    output: Vec<String>,
+}
+
+impl Peer {
+    fn spawn(name: &'static str, args: &[String]) -> anyhow::Result<Self> {
+        let mut child = Command::new(env::current_exe()?)
+            .args(args)
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        // The output is read on its own thread, so waiting for it can time out
+        let stdout = child.stdout.take().unwrap();
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Self {
+            name,
+            child,
+            lines,
+            output: vec![],
+        })
+    }
+
+    /// Wait for the first line printed that `matches`. Returns `None` if the peer exits, or
+    /// `deadline` passes first.
+    fn wait_for_line(
+        &mut self,
+        matches: impl Fn(&str) -> bool,
+        deadline: Instant,
+    ) -> Option<String> {
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let line = self.lines.recv_timeout(timeout).ok()?;
+            self.output.push(line.clone());
+            if matches(&line) {
+                return Some(line);
+            }
+        }
+    }
+
+    /// Wait for the peer to finish the game. Returns the hash of its board.
+    fn final_hash(&mut self, deadline: Instant) -> anyhow::Result<u64> {
+        let hash = self
+            .wait_for_line(|line| line.starts_with(FINAL_HASH), deadline)
+            .and_then(|line| u64::from_str_radix(line[FINAL_HASH.len()..].trim(), 16).ok())
+            .ok_or_else(|| self.failure("didn't finish the game"))?;
+
+        // The peer exits right after the hash, unless it's stuck
+        while Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait()? {
+                if !status.success() {
+                    return Err(self.failure(&format!("exited with {}", status)));
+                }
+                return Ok(hash);
+            }
+            thread::sleep(POLL);
+        }
+        Err(self.failure("didn't exit after the game"))
+    }
+
+    /// An error telling what went wrong, after printing what the peer printed.
+    fn failure(&self, what: &str) -> anyhow::Error {
+        for line in &self.output {
+            println!("[{}] {}", self.name, line);
+        }
+        anyhow::anyhow!("the {} {}", self.name, what)
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        // A peer that is done has exited already, so this only stops one that is stuck
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
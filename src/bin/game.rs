@@ -1,25 +1,361 @@
-use std::process::exit;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::exit,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
 
+use clap::Parser;
 use slint::ComponentHandle;
 
-use the_checker_mater::game::data::Context;
+use the_checker_mater::{
+    game::{data::Context, demo, PieceColor},
+    net::interface::{self, Latency, NetworkSimulation},
+};
+
+/// How often the network is checked for protocol errors to show, for a board to resync to, for
+/// chat, and for the ping.
+const NETWORK_POLL_MS: u64 = 500;
+
+/// Where the debug bundle is written when the game panics.
+const DEBUG_BUNDLE_DIR: &str = "debug_bundle";
+/// How long exiting waits for the session log to be written.
+const SESSION_LOG_FLUSH_MS: u64 = 1000;
+
+/// Checkers over LAN.
+#[derive(Debug, Parser)]
+#[command(about)]
+struct Args {
+    /// Skip the menu, and join the game with this join code
+    #[arg(long, value_name = "CODE", conflicts_with = "host", value_parser = join_code)]
+    join: Option<String>,
+    /// Skip the menu, and host a game
+    #[arg(long)]
+    host: bool,
+    /// The username to play with, instead of typing it in the menu
+    #[arg(long, value_name = "NAME")]
+    username: Option<String>,
+    /// The color you wish to play. You get it if the other player wishes the other color, and
+    /// otherwise the coin flip decides
+    #[arg(long, value_enum, value_name = "COLOR")]
+    color: Option<Color>,
+    /// Play the commands in this demo script in the window. See `game::demo`
+    #[arg(long, value_name = "FILE")]
+    script: Option<PathBuf>,
+    /// Play a match of this many games, instead of single games. The other player has to play
+    /// the same amount
+    #[arg(long, value_name = "GAMES", value_parser = clap::value_parser!(u8).range(1..))]
+    best_of: Option<u8>,
+    #[command(flatten)]
+    simulation: SimulationArgs,
+    /// Serve the state of the game to streaming overlays on this port
+    #[cfg(feature = "state-server")]
+    #[arg(long, value_name = "PORT")]
+    state_server: Option<u16>,
+}
+
+/// A simulated bad network for the packets we send, to reproduce flakiness.
+#[derive(Debug, clap::Args)]
+struct SimulationArgs {
+    /// Drop this percent of the packets
+    #[arg(long, value_name = "PERCENT", default_value_t = 0, value_parser = percent())]
+    sim_loss: u8,
+    /// Delay the packets this many milliseconds, with optional jitter, e.g. 80±20
+    #[arg(long, value_name = "MS[±JITTER]")]
+    sim_latency: Option<Latency>,
+    /// Send this percent of the packets twice
+    #[arg(long, value_name = "PERCENT", default_value_t = 0, value_parser = percent())]
+    sim_dup: u8,
+    /// Hold back this percent of the packets, so later ones arrive first
+    #[arg(long, value_name = "PERCENT", default_value_t = 0, value_parser = percent())]
+    sim_reorder: u8,
+    /// Seed of the simulation, to repeat the same faults
+    #[arg(long, value_name = "SEED")]
+    sim_seed: Option<u64>,
+}
+
+/// A color picked with `--color`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum Color {
+    White,
+    Black,
+}
+
+impl From<Color> for PieceColor {
+    fn from(color: Color) -> Self {
+        match color {
+            Color::White => PieceColor::White,
+            Color::Black => PieceColor::Black,
+        }
+    }
+}
+
+fn percent() -> clap::builder::RangedI64ValueParser<u8> {
+    clap::value_parser!(u8).range(0..=100)
+}
+
+/// Refuse a join code that doesn't decode to an address, before the window opens.
+fn join_code(code: &str) -> Result<String, String> {
+    match interface::decode_join_code(code) {
+        Ok(_) => Ok(code.to_owned()),
+        Err(e) => Err(format!("{} isn't a join code: {}", code, e)),
+    }
+}
+
+impl SimulationArgs {
+    fn simulation(&self) -> NetworkSimulation {
+        NetworkSimulation {
+            loss: self.sim_loss,
+            latency: self.sim_latency.unwrap_or_default(),
+            duplicate: self.sim_dup,
+            reorder: self.sim_reorder,
+            seed: self.sim_seed,
+        }
+    }
+}
+
+/// Where the game starts.
+#[derive(Debug, PartialEq, Eq)]
+enum Start {
+    Menu,
+    Join(String),
+    Host,
+}
+
+impl Args {
+    /// Where the game starts with these arguments. Clap rejects `--join` together with `--host`.
+    fn start(&self) -> Start {
+        match (&self.join, self.host) {
+            (Some(join_code), _) => Start::Join(join_code.clone()),
+            (None, true) => Start::Host,
+            (None, false) => Start::Menu,
+        }
+    }
+}
+
+/// Write a debug bundle when the game panics, before running the default panic hook.
+fn set_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+
+        // The bundle is written on another thread, since the panicking thread may be holding one of
+        // the locks the bundle needs
+        let (done, wait) = mpsc::channel();
+        thread::spawn(move || {
+            let result = interface::export_debug_bundle(Path::new(DEBUG_BUNDLE_DIR), None);
+            let _ = done.send(result);
+        });
+        match wait.recv_timeout(Duration::from_secs(2)) {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("Failed to write debug bundle: {}", e),
+            Err(_) => eprintln!("Timed out writing debug bundle"),
+        }
+        interface::log_to_session(format!("panic: {}", info));
+        interface::flush_session_log(Duration::from_millis(SESSION_LOG_FLUSH_MS));
+    }));
+}
 
 #[tokio::main]
 async fn main() -> Result<(), slint::PlatformError> {
+    // Invalid arguments exit here, with the error and a nonzero code
+    let args = Args::parse();
+    // An invalid script exits before the window opens, with the line of the error
+    let script = args.script.as_ref().map(|path| {
+        fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|source| demo::parse(&source))
+            .unwrap_or_else(|e| {
+                eprintln!("Invalid script {}: {}", path.display(), e);
+                exit(2);
+            })
+    });
+    set_panic_hook();
+    interface::set_network_simulation(args.simulation.simulation());
+    interface::set_color_preference(args.color.map(PieceColor::from));
+    if let Some(games) = args.best_of {
+        let mut options = interface::get_game_options();
+        options.match_length = games;
+        interface::set_game_options(options);
+    }
+
+    #[cfg(feature = "state-server")]
+    if let Some(port) = args.state_server {
+        tokio::spawn(async move {
+            if let Err(e) = the_checker_mater::game::state_server::serve(port).await {
+                eprintln!("State server stopped: {}", e);
+            }
+        });
+    }
+
     let gamedata = Context::new()?;
 
     let window = gamedata.get_window();
 
     window.on_clicked(gamedata.on_board_clicked());
+    window.on_drag_started(gamedata.on_drag_started());
+    window.on_drag_dropped(gamedata.on_drag_dropped());
 
     window.on_join_game(gamedata.on_join_game());
     window.on_host_game(gamedata.on_host_game());
+    window.on_join_instead(gamedata.on_join_instead());
+    window.on_reconnect(gamedata.on_reconnect());
+    window.on_coin_flipped(gamedata.on_coin_flipped());
     window.on_move_piece(gamedata.on_move_piece());
+    window.on_toggle_history(gamedata.on_toggle_history());
+    window.on_history_step(gamedata.on_history_step());
+    window.on_move_clicked(gamedata.on_move_clicked());
+    window.on_confirm_move(gamedata.on_confirm_move());
+    window.on_cancel_move(gamedata.on_cancel_move());
+    window.on_move_accepted(gamedata.on_move_accepted());
+    window.on_move_rejected(gamedata.on_move_rejected());
+    window.on_resync_board(gamedata.on_resync_board());
+    window.on_accept_resync(gamedata.on_accept_resync());
+    window.on_decline_resync(gamedata.on_decline_resync());
+    window.on_offer_draw(gamedata.on_offer_draw());
+    window.on_draw_offered(gamedata.on_draw_offered());
+    window.on_accept_draw(gamedata.on_accept_draw());
+    window.on_decline_draw(gamedata.on_decline_draw());
+    window.on_draw_answered(gamedata.on_draw_answered());
+    window.on_surrender(gamedata.on_surrender());
+    window.on_game_ended(gamedata.on_game_ended());
+    window.on_offer_rematch(gamedata.on_offer_rematch());
+    window.on_rematch_offered(gamedata.on_rematch_offered());
+    window.on_accept_rematch(gamedata.on_accept_rematch());
+    window.on_decline_rematch(gamedata.on_decline_rematch());
+    window.on_rematch_started(gamedata.on_rematch_started());
+    window.on_rematch_declined(gamedata.on_rematch_declined());
+    window.on_resign_match(gamedata.on_resign_match());
+    window.on_analyze(gamedata.on_analyze());
+    window.on_close_analysis(gamedata.on_close_analysis());
+    window.on_analysis_step(gamedata.on_analysis_step());
+    window.on_promote_variation(gamedata.on_promote_variation());
+    window.on_delete_branch(gamedata.on_delete_branch());
+    window.on_copy_pdn(gamedata.on_copy_pdn());
+    window.on_request_pause(gamedata.on_request_pause());
+    window.on_answer_pause(gamedata.on_answer_pause());
+    window.on_pause_updated(gamedata.on_pause_updated());
+    window.on_chat_edited(gamedata.on_chat_edited());
+    window.on_send_chat(gamedata.on_send_chat());
+    window.on_chat_received(gamedata.on_chat_received());
+    window.on_start_tutorial(gamedata.on_start_tutorial());
+    window.on_show_rules(gamedata.on_show_rules());
+    window.on_onboarding_username_edited(gamedata.on_onboarding_username_edited());
+    window.on_onboarding_next(gamedata.on_onboarding_next());
+    window.on_piece_set_selected(gamedata.on_piece_set_selected());
+    window.on_language_selected(gamedata.on_language_selected());
+    window.on_anonymous_toggled(gamedata.on_anonymous_toggled());
 
     window.on_exit(|| {
+        interface::disconnect();
+        interface::flush_session_log(Duration::from_millis(SESSION_LOG_FLUSH_MS));
         exit(0);
     });
 
+    // Protocol anomalies only become errors in strict mode. The last one stays on screen
+    let network_timer = slint::Timer::default();
+    let weak_window = window.as_weak();
+    network_timer.start(
+        slint::TimerMode::Repeated,
+        Duration::from_millis(NETWORK_POLL_MS),
+        move || {
+            let window = weak_window.unwrap();
+            if let Some(error) = interface::take_protocol_error() {
+                window.set_protocol_error(error.into());
+            }
+            window.set_ping_text(interface::ping_text().into());
+            window.set_sync_text(interface::board_sync_text().into());
+            window.set_typing_text(interface::typing_text().into());
+            window.set_draw_offer_text(interface::draw_offer_text().into());
+            window.set_version_warning(interface::version_warning().unwrap_or_default().into());
+            window.set_opponent_left(
+                interface::opponent_left_message()
+                    .unwrap_or_default()
+                    .into(),
+            );
+            window.invoke_resync_board();
+            window.invoke_game_ended();
+            window.invoke_pause_updated();
+            window.invoke_rematch_offered();
+            window.invoke_chat_received();
+        },
+    );
+
+    if let Some(username) = &args.username {
+        window.set_username(username.into());
+    }
+    match args.start() {
+        Start::Menu => {}
+        Start::Join(join_code) => {
+            window.invoke_join_game();
+            window.set_lan_code(join_code.into());
+            window.invoke_join_prompt();
+        }
+        Start::Host => window.invoke_host_game(),
+    }
+    if let Some(script) = script {
+        demo::run(script, window.as_weak());
+    }
+
     let window = gamedata.get_window();
-    window.run()
+    let result = window.run();
+    interface::disconnect();
+    interface::flush_session_log(Duration::from_millis(SESSION_LOG_FLUSH_MS));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(std::iter::once("game").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn arguments_pick_where_the_game_starts() {
+        assert_eq!(parse(&[]).unwrap().start(), Start::Menu);
+        assert_eq!(parse(&["--host"]).unwrap().start(), Start::Host);
+        assert_eq!(
+            parse(&["--join", "c0a8000a1b58"]).unwrap().start(),
+            Start::Join("c0a8000a1b58".to_owned())
+        );
+
+        let args = parse(&["--join", "c0a8000a1b58", "--username", "Søren"]).unwrap();
+        assert_eq!(args.username.as_deref(), Some("Søren"));
+        assert_eq!(parse(&[]).unwrap().color, None);
+        let args = parse(&["--host", "--color", "black"]).unwrap();
+        assert_eq!(args.color.map(PieceColor::from), Some(PieceColor::Black));
+    }
+
+    #[test]
+    fn invalid_arguments_are_refused() {
+        let e = parse(&["--join", "c0a8000a1b58", "--host"]).unwrap_err();
+        assert_eq!(e.kind(), clap::error::ErrorKind::ArgumentConflict);
+        for code in ["", "not a code", "c0a8000a1b", "c0a8000a1b58ff"] {
+            let e = parse(&["--join", code]).unwrap_err();
+            assert_eq!(
+                e.kind(),
+                clap::error::ErrorKind::ValueValidation,
+                "{:?}",
+                code
+            );
+        }
+        assert!(parse(&["--best-of", "0"]).is_err());
+        assert!(parse(&["--sim-loss", "101"]).is_err());
+        assert!(parse(&["--color", "red"]).is_err());
+    }
+
+    #[test]
+    fn simulation_arguments_map_to_the_simulation() {
+        let args = parse(&["--sim-loss", "10", "--sim-dup", "5", "--sim-seed", "42"]).unwrap();
+        let simulation = args.simulation.simulation();
+        assert_eq!(simulation.loss, 10);
+        assert_eq!(simulation.duplicate, 5);
+        assert_eq!(simulation.reorder, 0);
+        assert_eq!(simulation.seed, Some(42));
+        assert_eq!(parse(&["--best-of", "3"]).unwrap().best_of, Some(3));
+    }
 }
@@ -0,0 +1,53 @@
+use std::{fs, path::Path, process::exit};
+
+use the_checker_mater::game::replay::Script;
+
+/// The directory with the scripts and their golden files.
+const REPLAY_DIR: &str = "replay";
+
+/// Runs every `replay/<name>.ron` script, and compares its snapshot to `replay/<name>.golden`.
+/// With `--bless`, the golden files are written instead.
+fn main() {
+    let bless = std::env::args().any(|arg| arg == "--bless");
+
+    let mut scripts: Vec<_> = fs::read_dir(REPLAY_DIR)
+        .expect("Run from the root of the repository")
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ron"))
+        .collect();
+    scripts.sort();
+
+    let mut failed = 0;
+    for script in &scripts {
+        let name = script.file_stem().unwrap().to_string_lossy();
+        match run(script, bless) {
+            Ok(()) => println!("ok      {}", name),
+            Err(e) => {
+                println!("FAILED  {}\n{}", name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("\n{} scripts, {} failed", scripts.len(), failed);
+    if failed > 0 {
+        exit(1);
+    }
+}
+
+fn run(script: &Path, bless: bool) -> anyhow::Result<()> {
+    let snapshot = Script::parse(&fs::read_to_string(script)?)?.run()?;
+    let golden = script.with_extension("golden");
+
+    if bless {
+        fs::write(golden, snapshot)?;
+        return Ok(());
+    }
+
+    let expected = fs::read_to_string(&golden)
+        .map_err(|e| anyhow::anyhow!("Can't read {:?}: {}. Run with --bless", golden, e))?;
+    if snapshot != expected {
+        return Err(anyhow::anyhow!("expected:\n{}got:\n{}", expected, snapshot));
+    }
+    Ok(())
+}
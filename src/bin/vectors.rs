@@ -0,0 +1,33 @@
+use std::{fs, process::exit};
+
+use the_checker_mater::net::interface;
+
+/// The file with the protocol test vectors.
+const VECTORS_PATH: &str = "protocol/vectors.json";
+
+/// Checks the protocol test vectors in `protocol/vectors.json` against the encoders and decoders.
+/// With `gen-vectors`, the vectors are generated and written instead.
+fn main() {
+    let generate = std::env::args().any(|arg| arg == "gen-vectors");
+
+    if generate {
+        let vectors = interface::generate_protocol_vectors().expect("Failed to generate vectors");
+        fs::create_dir_all("protocol").expect("Run from the root of the repository");
+        fs::write(VECTORS_PATH, vectors).expect("Failed to write the vectors");
+        println!("Wrote {}", VECTORS_PATH);
+        return;
+    }
+
+    let source = fs::read_to_string(VECTORS_PATH)
+        .unwrap_or_else(|e| panic!("Can't read {}: {}. Run with gen-vectors", VECTORS_PATH, e));
+    let (count, failures) =
+        interface::verify_protocol_vectors(&source).expect("The vectors aren't valid JSON");
+
+    for (name, reason) in &failures {
+        println!("FAILED  {}: {}", name, reason);
+    }
+    println!("\n{} vectors, {} failed", count, failures.len());
+    if !failures.is_empty() {
+        exit(1);
+    }
+}
@@ -1,4 +1,7 @@
-use super::{BoardSquare, Direction, GameWindow, Move, PieceColor, PieceData};
+use super::{
+    fen, options::GameOptions, position_hash::position_hash, square, ui, BoardSquare, Direction,
+    GameWindow, Move, PieceColor, PieceData,
+};
 use futures::executor;
 use slint::ComponentHandle;
 use slint::{Model, Weak};
@@ -6,7 +9,7 @@ use std::mem::{transmute, MaybeUninit};
 use std::rc::Rc;
 use tokio::sync::Mutex;
 
-pub static mut BOARD_MOVE: Mutex<Move> = Mutex::const_new(Move {
+pub static BOARD_MOVE: Mutex<Move> = Mutex::const_new(Move {
     index: 0,
     end: 0,
     promoted: false,
@@ -14,21 +17,34 @@ pub static mut BOARD_MOVE: Mutex<Move> = Mutex::const_new(Move {
 });
 
 pub fn set_board_move(mov: &Move) {
-    unsafe { *executor::block_on(BOARD_MOVE.lock()) = mov.clone() };
+    *executor::block_on(BOARD_MOVE.lock()) = mov.clone();
 }
 
 pub fn get_board_move() -> Move {
-    unsafe { executor::block_on(BOARD_MOVE.lock()).clone() }
+    executor::block_on(BOARD_MOVE.lock()).clone()
+}
+
+/// What happens to a dragged piece when it's dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DragEnd {
+    /// It was dropped on its own square, and stays selected.
+    Kept,
+    /// It was dropped on a square it can move to, which is played like a click on that square.
+    Moved(usize),
+    /// It was dropped anywhere else, and nothing is selected.
+    Cancelled,
 }
 
 /// Struct holding gamestate of the checkers board
 #[derive(Default, Clone)]
 pub struct Board {
     game: Weak<GameWindow>,
-    pieces: Rc<slint::VecModel<PieceData>>,
+    pieces: Rc<slint::VecModel<ui::PieceData>>,
     player_color: PieceColor,
     squares: Rc<slint::VecModel<BoardSquare>>,
     pub selected_square: i32,
+    /// The rules the board plays.
+    options: GameOptions,
 }
 
 impl Board {
@@ -47,6 +63,35 @@ impl Board {
         }
     }
 
+    /// Creates a board that isn't shown in a window, e.g. for replaying input scripts.
+    pub fn new_headless() -> Board {
+        let squares: Vec<BoardSquare> = vec![BoardSquare { marked: false }; 32];
+
+        Board {
+            squares: Rc::new(slint::VecModel::from(squares)),
+            ..Default::default()
+        }
+    }
+
+    /// The model shown in the window for `pieces`
+    pub(super) fn model(pieces: Vec<PieceData>) -> Rc<slint::VecModel<ui::PieceData>> {
+        Rc::new(slint::VecModel::from(
+            pieces
+                .into_iter()
+                .map(ui::PieceData::from)
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    /// The piece on `index`, or `None` if it's off the board
+    fn piece(&self, index: usize) -> Option<PieceData> {
+        self.pieces.row_data(index).map(PieceData::from)
+    }
+
+    fn set_piece(&self, index: usize, piece: PieceData) {
+        self.pieces.set_row_data(index, piece.into());
+    }
+
     /// Returns the starting setup of a checkers board based off `player_color`
     fn default_setup(player_color: PieceColor) -> Vec<PieceData> {
         let enemy_color = player_color.get_opposite();
@@ -78,45 +123,258 @@ impl Board {
 
     /// Resets the board to starting state based off `player_color`
     pub fn start_new_game(&mut self, color: PieceColor) {
-        self.player_color = color;
-        self.pieces = Rc::new(slint::VecModel::from(Board::default_setup(color)));
+        self.load_position(Board::default_setup(color), color);
+    }
+
+    /// Sets up the board with `pieces`, which are seen from the side of `player_color`
+    pub fn load_position(&mut self, pieces: Vec<PieceData>, player_color: PieceColor) {
+        self.player_color = player_color;
+        self.pieces = Board::model(pieces);
 
-        let game = self.game.unwrap();
-        game.set_pieces(self.pieces.clone().into());
+        if let Some(game) = self.game.upgrade() {
+            game.set_pieces(self.pieces.clone().into());
+        }
 
         self.reset_squares();
     }
 
+    /// Shows `pieces` in the window instead of the board, without changing the board.
+    /// `show_live()` shows the board again.
+    pub fn show_position(&self, pieces: Vec<PieceData>) {
+        if let Some(game) = self.game.upgrade() {
+            game.set_pieces(Board::model(pieces).into());
+        }
+    }
+
+    /// Shows the board in the window again, after `show_position()`.
+    pub fn show_live(&self) {
+        if let Some(game) = self.game.upgrade() {
+            game.set_pieces(self.pieces.clone().into());
+        }
+    }
+
+    pub fn player_color(&self) -> PieceColor {
+        self.player_color
+    }
+
+    /// Returns a copy of the pieces, as seen from the side of the player.
+    pub fn pieces(&self) -> Vec<PieceData> {
+        self.pieces.iter().map(PieceData::from).collect()
+    }
+
+    /// Returns the pieces as seen from White's side. The player is always at the bottom of the
+    /// board, so they are reversed if the player is Black.
+    pub fn white_pieces(&self) -> Vec<PieceData> {
+        let mut pieces: Vec<PieceData> = self.pieces.iter().map(PieceData::from).collect();
+        square::orient(&mut pieces, self.player_color);
+        pieces
+    }
+
+    /// Returns the `position_hash()` of the board, played with the `GameOptions` the board plays.
+    pub fn position_hash(&self, side_to_move: PieceColor, move_number: u16) -> u64 {
+        position_hash(
+            &self.white_pieces(),
+            side_to_move,
+            move_number,
+            &self.options,
+        )
+    }
+
+    /// The rules the board plays.
+    pub fn options(&self) -> &GameOptions {
+        &self.options
+    }
+
+    /// Play with `options`. Set them before a game starts, since the rules can't change in the
+    /// middle of one.
+    pub fn set_options(&mut self, options: GameOptions) {
+        self.options = options;
+    }
+
+    /// Returns the board as a FEN string. See `fen::to_fen()`.
+    pub fn to_fen(&self, side_to_move: PieceColor) -> String {
+        // FEN is seen from White's side
+        fen::to_fen(&self.white_pieces(), side_to_move)
+    }
+
+    /// Returns `mov` as seen from White's side, instead of the player's.
+    pub fn to_white_move(&self, mov: &Move) -> Move {
+        match self.player_color {
+            PieceColor::White => mov.clone(),
+            PieceColor::Black => mov.reverse(self.options.geometry()),
+        }
+    }
+
     /// Takes a `Move` struct and performs the move described within
     pub fn move_piece(&mut self) {
         let mov = get_board_move();
 
         println!("\nPerformed move: {:#?}", mov);
 
-        let mut start_data = self.pieces.row_data(mov.index).unwrap();
+        let (Some(mut start_data), true) =
+            (self.piece(mov.index), mov.end < self.pieces.row_count())
+        else {
+            println!("Can't perform a move off the board: {:?}", mov);
+            return;
+        };
+
+        // A move from the other player may claim a promotion it didn't earn
+        if let Err(e) = mov.check_promotion(&self.options, start_data.color, self.player_color) {
+            println!("Can't perform the move: {}", e);
+            return;
+        }
 
         // Promotion to king
         start_data.is_king |= mov.promoted;
 
-        self.pieces.set_row_data(mov.end, start_data);
-        self.pieces
-            .set_row_data(mov.index, PieceData::const_default());
+        self.set_piece(mov.end, start_data);
+        self.set_piece(mov.index, PieceData::const_default());
 
         if let Some(captured) = &mov.captured {
             for piece in captured {
-                self.pieces.set_row_data(*piece, PieceData::const_default())
+                self.set_piece(*piece, PieceData::const_default())
             }
         }
     }
 
     /// Gives all the squares in `indices` the "marked" color
     pub fn mark_squares(&mut self, indices: &[usize]) {
-        for index in indices {
+        for index in indices
+            .iter()
+            .filter(|index| **index < self.squares.row_count())
+        {
             self.squares
                 .set_row_data(*index, BoardSquare { marked: true });
         }
     }
 
+    /// Returns the indices of the squares with the "marked" color
+    pub fn marked_squares(&self) -> Vec<usize> {
+        self.squares
+            .iter()
+            .enumerate()
+            .filter(|(_, square)| square.marked)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Returns the legal move of the selected piece to `index`, if the selected piece is the
+    /// player's and has one
+    pub fn find_move_to(&self, index: usize) -> Option<Move> {
+        let selected_piece = self.selected()?;
+        if !self.piece_is_player(selected_piece) {
+            return None;
+        }
+
+        self.get_legal_moves()?
+            .into_iter()
+            .find(|mov| mov.end == index && mov.index == selected_piece)
+    }
+
+    /// Returns true if the player's piece at `index` has a legal move. While a capture is forced,
+    /// only the pieces that can capture have one.
+    pub fn can_move(&self, index: usize) -> bool {
+        self.piece_is_player(index)
+            && self
+                .get_legal_moves()
+                .is_some_and(|moves| moves.iter().any(|mov| mov.index == index))
+    }
+
+    /// Returns the legal move of the selected piece to `index`, whichever color it is. Both colors
+    /// are moved when a game is analyzed.
+    pub fn find_any_move_to(&self, index: usize) -> Option<Move> {
+        let selected_piece = self.selected()?;
+        let color = self
+            .piece(selected_piece)
+            .filter(|piece| piece.is_active)?
+            .color;
+
+        self.get_legal_moves_of(color)?
+            .into_iter()
+            .find(|mov| mov.end == index && mov.index == selected_piece)
+    }
+
+    /// Returns true if the piece at `index` has a legal move, whichever color it is.
+    pub fn can_move_any(&self, index: usize) -> bool {
+        self.piece(index)
+            .filter(|piece| piece.is_active)
+            .and_then(|piece| self.get_legal_moves_of(piece.color))
+            .is_some_and(|moves| moves.iter().any(|mov| mov.index == index))
+    }
+
+    /// Selects the square at `index`, and marks the squares its piece can move to
+    pub fn select_square(&mut self, index: usize) {
+        self.reset_squares();
+        if let Some(moves) = self.get_legal_moves_piece(index) {
+            let mark_indicies: Vec<usize> = moves.0.iter().map(|mov| mov.end).collect();
+            self.mark_squares(mark_indicies.as_slice());
+        }
+        self.selected_square = index as i32;
+    }
+
+    /// Returns the index of the selected square, if a square on the board is selected
+    pub fn selected(&self) -> Option<usize> {
+        usize::try_from(self.selected_square)
+            .ok()
+            .filter(|index| *index < self.squares.row_count())
+    }
+
+    /// Starts dragging the piece on `index`, if it has a legal move. It's selected, so the squares
+    /// it can be dropped on are marked. Returns if the piece can be dragged.
+    ///
+    /// ## Params
+    /// * `index` - The square the drag started on, from the UI.
+    /// * `any_color` - If either color can be moved, like when a game is analyzed.
+    pub fn start_drag(&mut self, index: i32, any_color: bool) -> bool {
+        let Some(index) = Board::square_index(index) else {
+            return false;
+        };
+        let can_drag = match any_color {
+            true => self.can_move_any(index),
+            false => self.can_move(index),
+        };
+        if can_drag {
+            self.select_square(index);
+        }
+        can_drag
+    }
+
+    /// Drops the dragged piece, which is the selected one, on `index`. A drop that cancels the drag
+    /// clears the selection, while a move is left to the caller.
+    ///
+    /// ## Params
+    /// * `index` - The square the piece was dropped on, from the UI.
+    /// * `any_color` - If either color can be moved, like when a game is analyzed.
+    pub fn drop_dragged(&mut self, index: i32, any_color: bool) -> DragEnd {
+        let Some(selected) = self.selected() else {
+            return DragEnd::Cancelled;
+        };
+        let find_move_to = match any_color {
+            true => Board::find_any_move_to,
+            false => Board::find_move_to,
+        };
+        match Board::square_index(index) {
+            Some(index) if index == selected => DragEnd::Kept,
+            Some(index) if find_move_to(self, index).is_some() => DragEnd::Moved(index),
+            _ => {
+                self.clear_selection();
+                DragEnd::Cancelled
+            }
+        }
+    }
+
+    /// Unselects the selected square, and unmarks all squares
+    pub fn clear_selection(&mut self) {
+        self.reset_squares();
+        self.selected_square = -1;
+    }
+
+    /// Returns `index` as a square index, if it's on the board. Indices from the UI must be
+    /// checked with this, since a click can be outside the board.
+    pub fn square_index(index: i32) -> Option<usize> {
+        usize::try_from(index).ok().filter(|index| *index < 32)
+    }
+
     /// Turns all squares back to their original color
     pub fn reset_squares(&mut self) {
         for index in 0..32 {
@@ -126,35 +384,25 @@ impl Board {
     }
 
     /// Returns true if the `index` corresponds to an active piece on the board
+    #[allow(dead_code)]
     pub fn piece_is_empty(&self, index: usize) -> bool {
-        assert!(index < self.pieces.row_count());
-        !self.pieces.row_data(index).unwrap().is_active
+        self.piece(index).is_some_and(|piece| !piece.is_active)
     }
 
     /// Returns true if the `index` corresponds to a player piece on the board
     pub fn piece_is_player(&self, index: usize) -> bool {
-        assert!(
-            index < self.pieces.row_count(),
-            "index ({}) is greater than row_count ({})",
-            index,
-            self.pieces.row_count()
-        );
-        let piece = self.pieces.row_data(index).unwrap();
-        piece.color == self.player_color && piece.is_active
+        self.piece(index)
+            .is_some_and(|piece| piece.color == self.player_color && piece.is_active)
     }
 
     /// Returns true if the `index` corresponds to a non-player piece on the board
+    #[allow(dead_code)]
     pub fn piece_is_enemy(&self, index: usize) -> bool {
-        assert!(
-            index < self.pieces.row_count(),
-            "index ({}) is greater than row_count ({})",
-            index,
-            self.pieces.row_count()
-        );
-        let piece = self.pieces.row_data(index).unwrap();
-        piece.color != self.player_color && piece.is_active
+        self.piece(index)
+            .is_some_and(|piece| piece.color != self.player_color && piece.is_active)
     }
 
+    #[allow(dead_code)]
     pub fn get_player_piece_count(&self) -> u8 {
         let mut count = 0;
         for i in 0..32 {
@@ -163,6 +411,7 @@ impl Board {
         count
     }
 
+    #[allow(dead_code)]
     pub fn get_enemy_piece_count(&self) -> u8 {
         let mut count = 0;
         for i in 0..32 {
@@ -171,6 +420,7 @@ impl Board {
         count
     }
 
+    #[allow(dead_code)]
     pub fn get_empty_piece_count(&self) -> u8 {
         let mut count = 0;
         for i in 0..32 {
@@ -181,9 +431,10 @@ impl Board {
 
     /// Get's all the legal moves for the given piece
     /// This works for both enemy pieces and player pieces
+    /// A man reaching the last row in the middle of a capture follows
+    /// `GameOptions::promotion_ends_capture`
     pub fn get_legal_moves_piece(&self, index: usize) -> Option<(Vec<Move>, bool)> {
-        assert!(index < self.pieces.row_count());
-        let piece = self.pieces.row_data(index)?;
+        let piece = self.piece(index)?;
         if !piece.is_active {
             return None;
         }
@@ -198,19 +449,8 @@ impl Board {
             is_king: bool,
             direction: &Direction,
             is_taking: bool,
+            options: &GameOptions,
         ) -> Option<(Vec<Move>, bool)> {
-            // Check if the piece is on the edge of the direction
-            let row_left_shifted = index % 8 < 4;
-            let piece_left_side = index % 4 == 0;
-            let peice_right_side = index % 4 == 3;
-            if row_left_shifted && direction.is_left() && piece_left_side {
-                return None;
-            }
-
-            if !row_left_shifted && direction.is_right() && peice_right_side {
-                return None;
-            }
-
             let is_local_player = local_player_color != enemy_color;
             // If the piece isn't a king it cant move backwards
             if !is_king {
@@ -223,11 +463,8 @@ impl Board {
                 }
             }
 
-            let next = index as i32 + direction.get_value(index);
-            if next < 0 || next > pieces.len() as i32 {
-                return None;
-            }
-            let next_tile = &pieces[next as usize];
+            let next = direction.neighbor(index, options.geometry())?;
+            let next_tile = &pieces[next];
 
             // If the next piece is an enemy check if the next tile is empty
             // If so this piece can be taken
@@ -239,12 +476,13 @@ impl Board {
                 return if let Some(mut next_move) = check_move(
                     pieces,
                     start,
-                    next as usize,
+                    next,
                     local_player_color,
                     enemy_color,
                     is_king,
                     direction,
                     true,
+                    options,
                 ) {
                     if !next_move.1 {
                         return Some(next_move);
@@ -264,12 +502,26 @@ impl Board {
                 };
             }
 
-            let promoting = is_local_player && next < 4 || !is_local_player && next > 32 - 4;
+            let promoting =
+                options.is_promotion_square(next, enemy_color.get_opposite(), local_player_color);
 
             // If we are taking a piece, since the next tile is empty
             // We need to return this move, but also check if we can take more pieces
             if is_taking {
+                let capture = Move {
+                    index: start,
+                    end: next,
+                    captured: Some(vec![index]),
+                    promoted: promoting,
+                };
+                // The man is crowned on the last row, and the capture ends there
+                if promoting && !is_king && options.promotion_ends_capture {
+                    return Some((vec![capture], true));
+                }
+
                 // Check to see if we can take further pieces
+                // A man passing the last row carries on as a man, and is only crowned if the
+                // capture ends there
                 let mut further_moves = None;
 
                 pieces[index] = PieceData::const_default();
@@ -277,12 +529,13 @@ impl Board {
                     let moves = check_move(
                         pieces.clone(),
                         start,
-                        next as usize,
+                        next,
                         local_player_color,
                         enemy_color,
-                        is_king || promoting,
+                        is_king,
                         direction,
                         false,
+                        options,
                     );
 
                     if let Some(mut moves) = moves {
@@ -293,22 +546,13 @@ impl Board {
                         // Append the current piece to the captured vector
                         for mov in &mut moves.0 {
                             unsafe { mov.captured.as_mut().unwrap_unchecked().push(index) };
-                            mov.promoted |= promoting;
                         }
                         // Add to list of possible moves
                         further_moves.get_or_insert(vec![]).append(&mut moves.0);
                     }
                 }
 
-                return Some((
-                    further_moves.unwrap_or(vec![Move {
-                        index: start,
-                        end: next as usize,
-                        captured: Some(vec![index]),
-                        promoted: promoting,
-                    }]),
-                    true,
-                ));
+                return Some((further_moves.unwrap_or(vec![capture]), true));
             }
 
             // If we aren't taking a piece, and this tile is empty
@@ -321,12 +565,13 @@ impl Board {
                 if let Some(mut next_moves) = check_move(
                     pieces,
                     start,
-                    next as usize,
+                    next,
                     local_player_color,
                     enemy_color,
                     is_king,
                     direction,
                     false,
+                    options,
                 ) {
                     moves.append(&mut next_moves.0);
                     is_taking = next_moves.1;
@@ -338,7 +583,7 @@ impl Board {
             if !is_taking {
                 moves.push(Move {
                     index: start,
-                    end: next as usize,
+                    end: next,
                     captured: None,
                     promoted: promoting,
                 });
@@ -355,7 +600,7 @@ impl Board {
             unsafe { MaybeUninit::uninit().assume_init() };
 
         for (i, element) in pieces.iter_mut().enumerate() {
-            let piece = self.pieces.row_data(i)?;
+            let piece = self.piece(i)?;
             *element = MaybeUninit::new(piece);
         }
 
@@ -372,6 +617,7 @@ impl Board {
                 piece.is_king,
                 direction,
                 false,
+                &self.options,
             );
 
             if next_moves.is_none() {
@@ -401,12 +647,29 @@ impl Board {
         })
     }
 
+    /// Returns true if `color` has a piece with a legal move. The player to move loses without
+    /// one, which includes having no pieces left.
+    pub fn has_legal_move(&self, color: PieceColor) -> bool {
+        (0..self.pieces.row_count()).any(|index| {
+            self.piece(index)
+                .is_some_and(|piece| piece.is_active && piece.color == color)
+                && self
+                    .get_legal_moves_piece(index)
+                    .is_some_and(|(moves, _)| !moves.is_empty())
+        })
+    }
+
     /// Returns all legal moves for the `player_color`
     pub fn get_legal_moves(&self) -> Option<Vec<Move>> {
+        self.get_legal_moves_of(self.player_color)
+    }
+
+    /// Returns all legal moves for `color`
+    pub fn get_legal_moves_of(&self, color: PieceColor) -> Option<Vec<Move>> {
         let mut moves = None;
         let mut is_taking = false;
         for index in 0..self.pieces.row_count() {
-            if self.pieces.row_data(index)?.color != self.player_color {
+            if self.piece(index)?.color != color {
                 continue;
             }
 
@@ -429,3 +692,148 @@ impl Board {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn board_plays_its_options() {
+        let mut board = Board::new_headless();
+        board.start_new_game(PieceColor::White);
+        assert_eq!(board.options(), &GameOptions::new());
+        let default_hash = board.position_hash(PieceColor::White, 0);
+
+        let american = GameOptions {
+            flying_kings: false,
+            promotion_ends_capture: true,
+            ..GameOptions::new()
+        };
+        board.set_options(american);
+        board.start_new_game(PieceColor::White);
+        assert_eq!(board.options(), &american);
+        assert_eq!(
+            board.position_hash(PieceColor::White, 0),
+            position_hash(&board.white_pieces(), PieceColor::White, 0, &american)
+        );
+        assert_ne!(board.position_hash(PieceColor::White, 0), default_hash);
+    }
+
+    /// A board in the starting position, with White at the bottom.
+    fn started_board() -> Board {
+        let mut board = Board::new_headless();
+        board.start_new_game(PieceColor::White);
+        board
+    }
+
+    #[test]
+    fn dragged_piece_moves_to_a_marked_square() {
+        let mut board = started_board();
+        assert!(board.start_drag(21, false));
+        assert_eq!(board.selected(), Some(21));
+        assert_eq!(board.marked_squares(), [17, 18]);
+
+        assert_eq!(board.drop_dragged(18, false), DragEnd::Moved(18));
+        // The move is played by the caller, like a click on the square
+        assert_eq!(board.find_move_to(18).map(|mov| mov.end), Some(18));
+    }
+
+    #[test]
+    fn drag_is_kept_on_its_own_square_and_cancelled_elsewhere() {
+        let mut board = started_board();
+        assert!(board.start_drag(21, false));
+        assert_eq!(board.drop_dragged(21, false), DragEnd::Kept);
+        assert_eq!(board.selected(), Some(21));
+        assert_eq!(board.marked_squares(), [17, 18]);
+
+        // An illegal square, another piece and outside the board all cancel
+        for index in [13, 22, -1, 32] {
+            assert!(board.start_drag(21, false));
+            assert_eq!(board.drop_dragged(index, false), DragEnd::Cancelled);
+            assert_eq!(board.selected(), None, "{}", index);
+            assert!(board.marked_squares().is_empty(), "{}", index);
+        }
+
+        // A drop without a drag does nothing
+        assert_eq!(board.drop_dragged(18, false), DragEnd::Cancelled);
+        assert_eq!(board.pieces(), started_board().pieces());
+    }
+
+    #[test]
+    fn only_pieces_that_can_move_are_dragged() {
+        let mut board = started_board();
+        // A blocked piece, an enemy piece, an empty square, and outside the board
+        for index in [29, 9, 14, -1, 32] {
+            assert!(!board.start_drag(index, false), "{}", index);
+            assert!(board.marked_squares().is_empty(), "{}", index);
+        }
+        // Both colors are moved when analyzing
+        assert!(board.start_drag(9, true));
+        assert_eq!(board.drop_dragged(13, false), DragEnd::Cancelled);
+        assert!(board.start_drag(9, true));
+        assert_eq!(board.drop_dragged(13, true), DragEnd::Moved(13));
+    }
+
+    #[test]
+    fn forced_capture_restricts_the_drag() {
+        let man = |color| PieceData {
+            is_active: true,
+            color,
+            is_king: false,
+        };
+        let mut pieces = vec![PieceData::default(); 32];
+        for index in [21, 22, 29] {
+            pieces[index] = man(PieceColor::White);
+        }
+        for index in [5, 17] {
+            pieces[index] = man(PieceColor::Black);
+        }
+        let mut board = Board::new_headless();
+        board.load_position(pieces, PieceColor::White);
+
+        // Only the piece that can capture is dragged, and only to where it captures
+        assert!(!board.start_drag(22, false));
+        assert!(board.start_drag(21, false));
+        assert_eq!(board.marked_squares(), [12]);
+        assert_eq!(board.drop_dragged(18, false), DragEnd::Cancelled);
+        assert!(board.start_drag(21, false));
+        assert_eq!(board.drop_dragged(12, false), DragEnd::Moved(12));
+    }
+
+    #[test]
+    fn only_indices_on_the_board_are_squares() {
+        for (index, square) in [
+            (0, Some(0)),
+            (31, Some(31)),
+            (32, None),
+            (-1, None),
+            (i32::MIN, None),
+            (i32::MAX, None),
+        ] {
+            assert_eq!(Board::square_index(index), square, "{}", index);
+        }
+    }
+
+    #[test]
+    fn indices_off_the_board_are_no_pieces() {
+        let mut board = Board::new_headless();
+        board.start_new_game(PieceColor::White);
+        // A move is looked for from the selected piece
+        board.select_square(21);
+        for index in [32, 33, usize::MAX] {
+            assert!(!board.piece_is_player(index), "{}", index);
+            assert!(!board.piece_is_enemy(index), "{}", index);
+            assert!(!board.piece_is_empty(index), "{}", index);
+            assert!(!board.can_move(index), "{}", index);
+            assert!(!board.can_move_any(index), "{}", index);
+            assert!(board.get_legal_moves_piece(index).is_none(), "{}", index);
+            assert!(board.find_move_to(index).is_none(), "{}", index);
+        }
+
+        assert_eq!(board.selected(), Some(21));
+        board.selected_square = 32;
+        assert_eq!(board.selected(), None);
+        board.selected_square = -1;
+        assert_eq!(board.selected(), None);
+    }
+}
@@ -1,297 +1,2428 @@
-use arboard::Clipboard;
-use slint::ComponentHandle;
-
-use crate::net::interface;
-
-use super::{
-    board::{set_board_move, Board},
-    GameAction, GameWindow, PieceColor, WindowType,
-};
-use std::cell::RefCell;
-use std::ops::{Deref, DerefMut};
-use std::rc::Rc;
-use std::thread::sleep;
-use std::time::Duration;
-
-pub struct Context {
-    gamedata: Rc<RefCell<GameData>>,
-}
-
-impl Context {
-    pub fn new() -> Result<Self, slint::PlatformError> {
-        Ok(Self {
-            gamedata: Rc::new(RefCell::new(GameData::new()?)),
-        })
-    }
-
-    pub fn try_get_static_func(&self) -> impl FnMut() -> Option<Self> + 'static {
-        let weak = Rc::downgrade(&self.gamedata);
-
-        move || {
-            if let Some(gamedata) = weak.upgrade() {
-                return Some(Self { gamedata });
-            }
-
-            None
-        }
-    }
-}
-
-impl Deref for Context {
-    type Target = GameData;
-
-    fn deref(&self) -> &Self::Target {
-        unsafe { self.gamedata.as_ptr().as_ref().unwrap_unchecked() }
-    }
-}
-
-impl DerefMut for Context {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { self.gamedata.as_ptr().as_mut().unwrap_unchecked() }
-    }
-}
-
-impl Context {
-    pub fn on_join_game(&self) -> impl FnMut() + 'static {
-        let mut try_get_static_self = self.try_get_static_func();
-
-        move || {
-            let mut gamedata = try_get_static_self().unwrap();
-            gamedata.start_new_game(PieceColor::Black);
-
-            gamedata.load_prompt_client_window();
-
-            gamedata.window.on_join_prompt({
-                let mut gamedata = try_get_static_self().unwrap();
-
-                move || {
-                    let mut join_code: String = gamedata.window.get_lan_code().into();
-                    join_code = join_code.trim().to_owned();
-
-                    println!("Code was: \"{}\"", &join_code);
-
-                    gamedata.load_connecting_window(join_code.clone(), false);
-
-                    interface::start_lan_client();
-
-                    let username: String = gamedata.window.get_username().into();
-
-                    let handle_weak = gamedata.window.as_weak();
-                    tokio::spawn(async move {
-                        let (color, host_username) =
-                            interface::connect_to_host_loop(&join_code, &username).unwrap();
-
-                        println!("Joined {}'s game. You are {:?}", host_username, color);
-
-                        let handle_copy = handle_weak.clone();
-                        slint::invoke_from_event_loop(move || {
-                            handle_copy
-                                .unwrap()
-                                .invoke_set_usernames(username.into(), host_username.into());
-                        })
-                        .unwrap();
-
-                        let handle_copy = handle_weak.clone();
-                        slint::invoke_from_event_loop(move || {
-                            handle_copy.unwrap().invoke_load_game_window();
-                        })
-                        .unwrap();
-                    });
-
-                    gamedata.get_board_mut().start_new_game(PieceColor::Black);
-                    gamedata.wait_for_opponent();
-                }
-            });
-        }
-    }
-
-    pub fn on_host_game(&self) -> impl FnMut() + 'static {
-        let mut try_get_static_self = self.try_get_static_func();
-
-        move || {
-            let mut gamedata = try_get_static_self().unwrap();
-            let join_code = interface::start_lan_host();
-
-            gamedata.load_connecting_window(join_code.clone(), true);
-
-            let mut clipboard = Clipboard::new().unwrap();
-            clipboard.set_text(join_code).unwrap();
-
-            let username: String = gamedata.window.get_username().into();
-            interface::set_my_username(&username);
-
-            let handle_weak = gamedata.window.as_weak();
-            std::thread::spawn(move || {
-                loop {
-                    if interface::is_connected() {
-                        break;
-                    }
-                    // Think this is important
-                    sleep(Duration::from_millis(50));
-                }
-
-                let client_username =
-                    interface::get_other_username().unwrap_or("NO USERNAME".to_owned());
-
-                let handle_copy = handle_weak.clone();
-                slint::invoke_from_event_loop(move || {
-                    handle_copy
-                        .unwrap()
-                        .invoke_set_usernames(username.into(), client_username.into());
-                })
-                .unwrap();
-
-                let handle_copy = handle_weak.clone();
-                slint::invoke_from_event_loop(move || {
-                    handle_copy.unwrap().invoke_load_game_window();
-                })
-                .unwrap();
-            });
-            gamedata.get_board_mut().start_new_game(PieceColor::White);
-            gamedata.is_player_turn = true;
-        }
-        // self.on_join_game()
-    }
-
-    pub fn on_board_clicked(&self) -> impl FnMut(i32) + 'static {
-        let mut try_get_static_self = self.try_get_static_func();
-
-        move |index: i32| {
-            let mut gamedata = try_get_static_self().unwrap();
-            let board = gamedata.get_board_mut();
-
-            let mut gamedata = try_get_static_self().unwrap();
-
-            let selected_piece = board.selected_square as usize;
-
-            if !gamedata.is_player_turn {
-                return;
-            }
-
-            if board.piece_is_player(selected_piece) {
-                let legal_moves = board.get_legal_moves();
-                if let Some(moves) = legal_moves {
-                    for mov in &moves {
-                        let input_matches_move =
-                            mov.end == index as usize && mov.index == selected_piece;
-
-                        board.selected_square = index;
-
-                        if input_matches_move {
-                            set_board_move(mov);
-                            gamedata.window.invoke_move_piece();
-                            interface::send_game_action(GameAction::MovePiece(mov.clone()), |_| ());
-                            gamedata.wait_for_opponent();
-                            break;
-                        }
-                    }
-                }
-            }
-            // If there was no move with the input
-            board.reset_squares();
-            if let Some(moves) = board.get_legal_moves_piece(index as usize) {
-                let mark_indicies: Vec<usize> = moves.0.iter().map(|mov| mov.end).collect();
-                board.mark_squares(mark_indicies.as_slice());
-            }
-            board.selected_square = index;
-        }
-    }
-
-    pub fn on_move_piece(&self) -> impl FnMut() + 'static {
-        let mut try_get_static_self = self.try_get_static_func();
-
-        move || {
-            let mut gamedata = try_get_static_self().unwrap();
-            gamedata.get_board_mut().move_piece();
-
-            gamedata.is_player_turn = true;
-        }
-    }
-
-    pub fn wait_for_opponent(&mut self) {
-        self.is_player_turn = false;
-        let weak_window = self.window.as_weak();
-        tokio::spawn(async move {
-            let mut action;
-            loop {
-                action = interface::get_next_game_action();
-                if action.is_none() {
-                    tokio::time::sleep(Duration::from_millis(50)).await;
-                    continue;
-                }
-                break;
-            }
-
-            let action = unsafe { action.unwrap_unchecked() };
-            match action {
-                GameAction::MovePiece(mov) => {
-                    println!("Recieved move: {:#?}", mov);
-                    set_board_move(&mov.reverse());
-                    slint::invoke_from_event_loop(move || {
-                        weak_window.unwrap().invoke_move_piece();
-                    })
-                    .unwrap();
-                }
-                _ => {
-                    println!(
-                        "Got GameAction {:?} while waiting for opponent,
-                                     this is not implemented yet",
-                        action
-                    );
-                }
-            }
-        });
-    }
-}
-
-pub struct GameData {
-    window: GameWindow,
-    board: Board,
-    is_host: Option<bool>,
-    is_player_turn: bool,
-}
-
-impl GameData {
-    pub fn new() -> Result<Self, slint::PlatformError> {
-        let window = GameWindow::new()?;
-        let board = Board::new(&window);
-
-        Ok(GameData {
-            window,
-            board,
-            is_host: None,
-            is_player_turn: false,
-        })
-    }
-
-    #[inline]
-    pub fn get_window(&self) -> &GameWindow {
-        &self.window
-    }
-
-    fn get_board_mut(&mut self) -> &mut Board {
-        &mut self.board
-    }
-
-    pub fn start_new_game(&mut self, your_color: PieceColor) {
-        self.get_board_mut().start_new_game(your_color);
-    }
-
-    pub fn load_start_window(&self) {
-        self.window.set_window_state(WindowType::Start);
-    }
-
-    pub fn load_game_window(&self) {
-        self.window.set_window_state(WindowType::Game);
-    }
-
-    pub fn load_connecting_window(&self, join_code: String, is_host: bool) {
-        self.window.set_join_code(join_code.into());
-        self.window.set_is_host(is_host);
-        self.window.set_window_state(WindowType::Connecting);
-    }
-
-    pub fn load_prompt_client_window(&self) {
-        self.window.set_window_state(WindowType::LanPrompt);
-    }
-}
+use slint::{ComponentHandle, ModelRc, SharedString, VecModel};
+
+use crate::{
+    i18n::{get_language, set_language, tr, Language, MessageKey},
+    net::interface::{
+        self, ConnectProgress, GameOverReason, HostBoard, OptionsState, PauseState, RematchOffer,
+        TargetClass,
+    },
+};
+
+use super::{
+    board::{get_board_move, set_board_move, Board, DragEnd},
+    fen::from_fen,
+    history::{self, Source},
+    last_game::LastGame,
+    match_state::{Match, Outcome},
+    notation::move_notation,
+    piece_set::{PieceSetManager, BUILT_IN},
+    position_hash::position_hash,
+    profile::Profile,
+    square,
+    tutorial::{Tutorial, TutorialClick},
+    ui,
+    variations::MoveHistory,
+    BoardSquare, GameAction, GameWindow, Move, PieceColor, PieceData, WindowType,
+};
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// How long "Flipping coin..." is shown, before the result.
+const COIN_FLIP_MS: u64 = 1000;
+/// How long the result of the coin flip is shown, before the game window loads.
+const COIN_RESULT_MS: u64 = 1500;
+
+pub struct Context {
+    gamedata: Rc<RefCell<GameData>>,
+}
+
+impl Context {
+    pub fn new() -> Result<Self, slint::PlatformError> {
+        Ok(Self {
+            gamedata: Rc::new(RefCell::new(GameData::new()?)),
+        })
+    }
+
+    pub fn try_get_static_func(&self) -> impl FnMut() -> Option<Self> + 'static {
+        let weak = Rc::downgrade(&self.gamedata);
+
+        move || {
+            if let Some(gamedata) = weak.upgrade() {
+                return Some(Self { gamedata });
+            }
+
+            None
+        }
+    }
+}
+
+impl Deref for Context {
+    type Target = GameData;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.gamedata.as_ptr().as_ref().unwrap_unchecked() }
+    }
+}
+
+impl DerefMut for Context {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.gamedata.as_ptr().as_mut().unwrap_unchecked() }
+    }
+}
+
+impl Context {
+    pub fn on_join_game(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            gamedata.start_new_game(PieceColor::Black);
+
+            gamedata.load_prompt_client_window();
+
+            gamedata.window.on_join_prompt({
+                let mut gamedata = try_get_static_self().unwrap();
+
+                move || {
+                    let mut join_code: String = gamedata.window.get_lan_code().into();
+                    join_code = join_code.trim().to_owned();
+
+                    println!("Code was: \"{}\"", &join_code);
+
+                    if let Err(message) = gamedata.check_join_code(&join_code, None) {
+                        gamedata.window.set_lan_message(message.into());
+                        return;
+                    }
+                    gamedata.window.set_lan_message("".into());
+
+                    interface::start_lan_client();
+                    interface::set_anonymous(gamedata.window.get_anonymous());
+
+                    gamedata.connect_to_host(join_code, false);
+                }
+            });
+        }
+    }
+
+    /// Handles the player clicking "Reconnect to last game", by joining the host of the last game
+    /// again with the same username.
+    pub fn on_reconnect(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            let Some(last_game) = LastGame::load() else {
+                gamedata.window.set_last_game_host("".into());
+                return;
+            };
+            println!(
+                "Reconnecting to {}'s game with code \"{}\"",
+                last_game.host_username, last_game.join_code
+            );
+
+            gamedata.start_new_game(last_game.color());
+            gamedata.window.set_username(last_game.username.into());
+            // The game is played like it was, even if the setting changed since
+            gamedata.window.set_anonymous(last_game.anonymous);
+
+            interface::start_lan_client();
+            interface::set_anonymous(last_game.anonymous);
+
+            gamedata.connect_to_host(last_game.join_code, false);
+        }
+    }
+
+    /// Handles the host clicking "Join their game instead". The other user is probed first, and
+    /// if they are hosting too, this host stops hosting and joins them as client.
+    pub fn on_join_instead(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            let join_code: String = gamedata.window.get_other_join_code().into();
+            let join_code = join_code.trim().to_owned();
+
+            let own_join_code: String = gamedata.window.get_join_code().into();
+            if let Err(message) = gamedata.check_join_code(&join_code, Some(&own_join_code)) {
+                gamedata.window.set_connecting_message(message.into());
+                return;
+            }
+
+            let message = match interface::probe(&join_code) {
+                Ok(true) => {
+                    gamedata.connect_to_host(join_code, true);
+                    tr(MessageKey::BothHosting, &[])
+                }
+                Ok(false) => tr(MessageKey::ProbeNotHosting, &[]),
+                Err(e) if e.is::<interface::ProtocolMismatch>() => e.to_string(),
+                Err(e) => {
+                    println!("Probe failed: {}", e);
+                    tr(MessageKey::ProbeNoAnswer, &[])
+                }
+            };
+            gamedata.window.set_connecting_message(message.into());
+        }
+    }
+
+    pub fn on_host_game(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let gamedata = try_get_static_self().unwrap();
+            let join_code = interface::start_lan_host();
+
+            gamedata.load_connecting_window(join_code.clone(), true);
+
+            copy_join_code(&join_code);
+
+            let username: String = gamedata.window.get_username().into();
+            if let Err(e) = interface::set_my_username(&username) {
+                println!("Can't use the username {:?}: {}", username, e);
+            }
+            interface::set_anonymous(gamedata.window.get_anonymous());
+
+            let handle_weak = gamedata.window.as_weak();
+            std::thread::spawn(move || {
+                // The game starts once a client has joined, and confirmed it plays with the same
+                // options. A client with other options is dropped, and we keep waiting.
+                let mut last_state = OptionsState::Pending;
+                loop {
+                    let state = interface::get_options_state();
+                    if state == OptionsState::Agreed && interface::is_connected() {
+                        break;
+                    }
+                    if state == OptionsState::Mismatch && last_state != state {
+                        let handle_copy = handle_weak.clone();
+                        slint::invoke_from_event_loop(move || {
+                            handle_copy.unwrap().set_connecting_message(
+                                tr(MessageKey::OptionsMismatch, &[]).into(),
+                            );
+                        })
+                        .unwrap();
+                    }
+                    last_state = state;
+                    // Think this is important
+                    sleep(Duration::from_millis(50));
+                }
+
+                let client_username =
+                    interface::get_other_username().unwrap_or(tr(MessageKey::NoUsername, &[]));
+
+                let handle_copy = handle_weak.clone();
+                slint::invoke_from_event_loop(move || {
+                    handle_copy
+                        .unwrap()
+                        .invoke_set_usernames(username.into(), client_username.into());
+                })
+                .unwrap();
+
+                // The client joined, so the coin flip is done
+                let is_white = interface::get_my_color() == Some(PieceColor::White);
+                let handle_copy = handle_weak.clone();
+                slint::invoke_from_event_loop(move || {
+                    handle_copy.unwrap().invoke_coin_flipped(is_white);
+                })
+                .unwrap();
+            });
+        }
+        // self.on_join_game()
+    }
+
+    pub fn on_show_rules(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            try_get_static_self().unwrap().load_rules_window();
+        }
+    }
+
+    /// Validates the username of the onboarding while it's typed.
+    pub fn on_onboarding_username_edited(&self) -> impl FnMut(SharedString) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |username| {
+            try_get_static_self()
+                .unwrap()
+                .show_username_message(&username);
+        }
+    }
+
+    /// Shows the piece set picked in the start window, and remembers it in the profile.
+    pub fn on_piece_set_selected(&self) -> impl FnMut(SharedString) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |name| {
+            let gamedata = try_get_static_self().unwrap();
+            gamedata.piece_sets.select(&name, &gamedata.window);
+
+            let mut profile = Profile::load()
+                .unwrap_or_else(|| Profile::new(gamedata.window.get_username().into()));
+            profile.piece_set = (name != BUILT_IN).then(|| name.into());
+            if let Err(e) = profile.save() {
+                println!("Couldn't save the profile: {}", e);
+            }
+        }
+    }
+
+    /// Translates the messages into the language picked in the start window, and remembers it in
+    /// the profile.
+    pub fn on_language_selected(&self) -> impl FnMut(SharedString) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |name| {
+            let gamedata = try_get_static_self().unwrap();
+            let Some(language) = Language::from_name(&name) else {
+                return;
+            };
+            set_language(language);
+
+            let mut profile = Profile::load()
+                .unwrap_or_else(|| Profile::new(gamedata.window.get_username().into()));
+            profile.language = language;
+            if let Err(e) = profile.save() {
+                println!("Couldn't save the profile: {}", e);
+            }
+        }
+    }
+
+    /// Remembers in the profile if the player plays anonymously, when it's toggled in the start
+    /// window.
+    pub fn on_anonymous_toggled(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let gamedata = try_get_static_self().unwrap();
+            let mut profile = Profile::load()
+                .unwrap_or_else(|| Profile::new(gamedata.window.get_username().into()));
+            profile.anonymous = gamedata.window.get_anonymous();
+            if let Err(e) = profile.save() {
+                println!("Couldn't save the profile: {}", e);
+            }
+        }
+    }
+
+    /// Goes to the next step of the onboarding. After the last step the profile is saved, so the
+    /// onboarding isn't shown again, and the start window is loaded.
+    pub fn on_onboarding_next(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let gamedata = try_get_static_self().unwrap();
+            let username: String = gamedata.window.get_onboarding_username().into();
+            match OnboardingNext::at(gamedata.window.get_onboarding_step(), &username) {
+                OnboardingNext::Stay => {
+                    gamedata.show_username_message(&username);
+                }
+                OnboardingNext::CheckConnectivity => {
+                    gamedata.window.set_onboarding_diagnostics("".into());
+                    gamedata.window.set_onboarding_step(1);
+
+                    // The ports are tried on another thread, so the window doesn't freeze
+                    let handle_weak = gamedata.window.as_weak();
+                    std::thread::spawn(move || {
+                        let summary = interface::run_diagnostics().summary().join("\n");
+                        slint::invoke_from_event_loop(move || {
+                            handle_weak
+                                .unwrap()
+                                .set_onboarding_diagnostics(summary.into());
+                        })
+                        .unwrap();
+                    });
+                }
+                OnboardingNext::ShowHostOrJoin => {
+                    gamedata.window.set_onboarding_host_or_join(
+                        tr(MessageKey::OnboardingHostOrJoin, &[]).into(),
+                    );
+                    gamedata.window.set_onboarding_step(2);
+                }
+                OnboardingNext::Finish(profile) => {
+                    if let Err(e) = profile.save() {
+                        println!("Couldn't save the profile: {}", e);
+                    }
+                    gamedata.window.set_username(profile.username.into());
+                    gamedata.load_start_window();
+                }
+            }
+        }
+    }
+
+    pub fn on_start_tutorial(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            gamedata.tutorial = Some(Tutorial::load());
+            gamedata.window.set_window_state(WindowType::Tutorial);
+            gamedata.load_tutorial_scenario();
+        }
+    }
+
+    /// Handles a click on the square at `index`, which either moves the selected piece there or
+    /// selects the square.
+    fn click_square(&mut self, index: usize) {
+        if self.tutorial.is_some() {
+            self.on_tutorial_clicked(index);
+            return;
+        }
+        if self.analysis.is_some() {
+            self.click_analysis_square(index);
+            return;
+        }
+
+        if !self.is_waiting_for_move() {
+            return;
+        }
+
+        if let Some(mov) = self.board.find_move_to(index) {
+            if self.window.get_confirm_moves() {
+                self.unconfirmed_move = Some(UnconfirmedMove::hold(mov, &mut self.board));
+                self.window.set_move_pending(true);
+                return;
+            }
+            self.play_move(mov);
+        }
+        // The clicked square is selected, also after a move
+        self.board.select_square(index);
+    }
+
+    /// Handles a click on the board while in the tutorial. Only the expected move of the scenario
+    /// is played, other moves shows the scenario's explanation.
+    fn on_tutorial_clicked(&mut self, index: usize) {
+        let gamedata: &mut GameData = self;
+        let Some(tutorial) = &mut gamedata.tutorial else {
+            return;
+        };
+        match tutorial.click(&gamedata.board, index) {
+            TutorialClick::Select => {}
+            TutorialClick::Wrong(explanation) => {
+                self.window.set_tutorial_text(explanation.into());
+            }
+            TutorialClick::Correct(mov) => {
+                set_board_move(&mov);
+                self.window.invoke_move_piece();
+                self.board.reset_squares();
+                self.window
+                    .set_tutorial_text(tr(MessageKey::TutorialCorrect, &[]).into());
+
+                // Give the player a moment to see the move, before loading the next scenario
+                let mut try_get_static_self = self.try_get_static_func();
+                slint::Timer::single_shot(Duration::from_millis(1500), move || {
+                    if let Some(mut gamedata) = try_get_static_self() {
+                        gamedata.load_tutorial_scenario();
+                    }
+                });
+                return;
+            }
+        }
+
+        self.board.select_square(index);
+    }
+
+    pub fn on_board_clicked(&self) -> impl FnMut(i32) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |index: i32| {
+            let mut gamedata = try_get_static_self().unwrap();
+            // A click outside the board clears the selection
+            let Some(index) = Board::square_index(index) else {
+                if gamedata.unconfirmed_move.is_none() {
+                    gamedata.board.clear_selection();
+                }
+                return;
+            };
+
+            gamedata.click_square(index);
+        }
+    }
+
+    /// Starts dragging a piece, if it could be moved by clicking it. The piece is selected, so the
+    /// squares it can be dropped on are marked. Returns if the piece can be dragged.
+    pub fn on_drag_started(&self) -> impl FnMut(i32) -> bool + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |index: i32| {
+            let mut gamedata = try_get_static_self().unwrap();
+            let is_analyzing = gamedata.analysis.is_some();
+            let may_move =
+                is_analyzing || gamedata.tutorial.is_some() || gamedata.is_waiting_for_move();
+            may_move && gamedata.board.start_drag(index, is_analyzing)
+        }
+    }
+
+    /// Drops the dragged piece. A drop on a square the piece can move to is the same as clicking
+    /// that square, so ambiguous captures and "Confirm moves" work like with clicks. A drop on
+    /// the piece's own square keeps it selected, and a drop anywhere else cancels the drag.
+    pub fn on_drag_dropped(&self) -> impl FnMut(i32) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |index: i32| {
+            let mut gamedata = try_get_static_self().unwrap();
+            let is_analyzing = gamedata.analysis.is_some();
+            if let DragEnd::Moved(index) = gamedata.board.drop_dragged(index, is_analyzing) {
+                gamedata.click_square(index);
+            }
+        }
+    }
+
+    /// Plays the move waiting for confirmation, when "Confirm moves" is on.
+    pub fn on_confirm_move(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            let Some(unconfirmed) = gamedata.unconfirmed_move.take() else {
+                return;
+            };
+            gamedata.window.set_move_pending(false);
+
+            let mov = unconfirmed.confirm();
+            let end = mov.end;
+            gamedata.play_move(mov);
+            gamedata.board.select_square(end);
+        }
+    }
+
+    /// Drops the move waiting for confirmation, and selects its piece again.
+    pub fn on_cancel_move(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            let Some(unconfirmed) = gamedata.unconfirmed_move.take() else {
+                return;
+            };
+            gamedata.window.set_move_pending(false);
+            unconfirmed.cancel(&mut gamedata.board);
+        }
+    }
+
+    pub fn on_move_piece(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            gamedata.get_board_mut().move_piece();
+            gamedata.record_move();
+
+            // Our confirmed moves are taken out before they are played, so this move is the
+            // other player's, and the move waiting for confirmation was chosen on the old board
+            if gamedata.unconfirmed_move.take().is_some() {
+                gamedata.window.set_move_pending(false);
+            }
+
+            // A move from the other player, while our own move hasn't been acknowledged. Both
+            // thought it was their turn, so the turn is decided when the host answers.
+            let is_remote = !gamedata.is_player_turn;
+            if let (true, Some(pending)) = (is_remote, &mut gamedata.pending_move) {
+                pending.remote_moves.push(get_board_move());
+                return;
+            }
+
+            // The player to move loses without a legal move. Both boards can tell, but only the
+            // player who made the last move tells the other one
+            let player_color = gamedata.board.player_color();
+            let mover = if is_remote {
+                player_color.get_opposite()
+            } else {
+                player_color
+            };
+            if gamedata.tutorial.is_none()
+                && !gamedata.window.get_game_over()
+                && !gamedata.board.has_legal_move(mover.get_opposite())
+            {
+                if !is_remote {
+                    send_game_over(Some(mover), GameOverReason::NoMoves);
+                }
+                gamedata.end_game(Some(mover), GameOverReason::NoMoves);
+                return;
+            }
+
+            gamedata.is_player_turn = true;
+        }
+    }
+
+    /// Makes our move `mov` on the board, and sends it to the other player. The position before
+    /// the move is kept, in case the host rejects it.
+    fn play_move(&mut self, mov: Move) {
+        let move_number = interface::get_move_number();
+        self.pending_move = Some(PendingMove {
+            snapshot: self.board.pieces(),
+            move_number,
+            remote_moves: Vec::new(),
+        });
+        self.window.set_game_message("".into());
+
+        set_board_move(&mov);
+        self.window.invoke_move_piece();
+
+        // The closure has to be `Sync`, which the window handle isn't
+        let weak_window = std::sync::Mutex::new(self.window.as_weak());
+        interface::send_game_action(GameAction::MovePiece(mov), move |resp| {
+            let weak_window = weak_window.lock().unwrap().clone();
+            match resp {
+                Ok(()) => slint::invoke_from_event_loop(move || {
+                    weak_window.unwrap().invoke_move_accepted();
+                })
+                .unwrap(),
+                Err(e) if e.is::<interface::InvalidMove>() => {
+                    // The other board never got the move, so it's taken back to the same move
+                    slint::invoke_from_event_loop(move || {
+                        let window = weak_window.unwrap();
+                        window.invoke_move_rejected(move_number as i32);
+                        window.set_game_message(tr(MessageKey::MoveRefused, &[]).into());
+                    })
+                    .unwrap()
+                }
+                Err(e)
+                    if e.is::<interface::QueueFull>() || e.is::<interface::NoTransactionId>() =>
+                {
+                    // Neither was the move sent
+                    slint::invoke_from_event_loop(move || {
+                        let window = weak_window.unwrap();
+                        window.invoke_move_rejected(move_number as i32);
+                        window.set_game_message(tr(MessageKey::MoveNotSent, &[]).into());
+                    })
+                    .unwrap()
+                }
+                Err(e) => match e.downcast::<interface::NotYourTurn>() {
+                    Ok(rejection) => slint::invoke_from_event_loop(move || {
+                        weak_window
+                            .unwrap()
+                            .invoke_move_rejected(rejection.move_number as i32);
+                    })
+                    .unwrap(),
+                    Err(e) => println!("Move failed: {}", e),
+                },
+            }
+        });
+        self.wait_for_opponent();
+    }
+
+    /// Opens or closes the history view, where the last board states can be stepped through
+    /// without affecting the live game.
+    pub fn on_toggle_history(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            if gamedata.analysis.is_some() {
+                return;
+            }
+            if gamedata.history_index.take().is_some() {
+                gamedata.board.show_live();
+                gamedata.window.set_history_open(false);
+                return;
+            }
+
+            let len = history::history_ring().len();
+            if len > 0 {
+                gamedata.show_history_entry(len - 1);
+            }
+        }
+    }
+
+    /// Steps `step` entries through the history, while the history view is open.
+    pub fn on_history_step(&self) -> impl FnMut(i32) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |step: i32| {
+            let mut gamedata = try_get_static_self().unwrap();
+            let Some(index) = gamedata.history_index else {
+                return;
+            };
+
+            let last = history::history_ring().len().saturating_sub(1) as i32;
+            let index = (index as i32 + step).clamp(0, last);
+            gamedata.show_history_entry(index as usize);
+        }
+    }
+
+    /// Opens the history view at move `ply`, when it's clicked in the move list. Nothing happens if
+    /// the move is too old to still be in the history. In analysis mode the move of the line shown
+    /// is shown on the board.
+    pub fn on_move_clicked(&self) -> impl FnMut(i32) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |ply: i32| {
+            let mut gamedata = try_get_static_self().unwrap();
+            if let Some(analysis) = &mut gamedata.analysis {
+                let line = analysis.moves.line_through(analysis.moves.current());
+                if let Some(id) = usize::try_from(ply).ok().and_then(|ply| line.get(ply)) {
+                    analysis.moves.go_to(*id);
+                }
+                gamedata.show_analysis();
+                return;
+            }
+            let index = history::history_ring()
+                .iter()
+                .position(|entry| entry.move_number as i32 == ply);
+            if let Some(index) = index {
+                gamedata.show_history_entry(index);
+            }
+        }
+    }
+
+    /// Opens analysis mode after the game, where both colors can be moved from any position of the
+    /// game to try other lines. The game itself isn't changed, and nothing is sent to the other
+    /// player.
+    pub fn on_analyze(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            if !gamedata.window.get_game_over() || gamedata.analysis.is_some() {
+                return;
+            }
+            if gamedata.history_index.take().is_some() {
+                gamedata.window.set_history_open(false);
+            }
+
+            let mut moves = history::move_history();
+            // The analysis starts at the end of the game
+            while moves.forward() {}
+            gamedata.analysis = Some(Analysis {
+                moves,
+                final_board: gamedata.board.pieces(),
+            });
+            gamedata.window.set_analyzing(true);
+            gamedata.show_analysis();
+        }
+    }
+
+    /// Closes analysis mode, and shows the end of the game again.
+    pub fn on_close_analysis(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            gamedata.close_analysis();
+        }
+    }
+
+    /// Steps back through the line shown in analysis mode for a negative `step`, and forward for a
+    /// positive one.
+    pub fn on_analysis_step(&self) -> impl FnMut(i32) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |step: i32| {
+            let mut gamedata = try_get_static_self().unwrap();
+            let Some(analysis) = &mut gamedata.analysis else {
+                return;
+            };
+            for _ in 0..step.unsigned_abs() {
+                let is_moved = match step < 0 {
+                    true => analysis.moves.back(),
+                    false => analysis.moves.forward(),
+                };
+                if !is_moved {
+                    break;
+                }
+            }
+            gamedata.show_analysis();
+        }
+    }
+
+    /// Makes the variation shown in analysis mode the line it branches from. See
+    /// `MoveHistory::promote()`.
+    pub fn on_promote_variation(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            let Some(analysis) = &mut gamedata.analysis else {
+                return;
+            };
+            let current = analysis.moves.current();
+            analysis.moves.promote(current);
+            gamedata.show_analysis();
+        }
+    }
+
+    /// Deletes the move shown in analysis mode, and everything played after it. The moves of the
+    /// game can't be deleted.
+    pub fn on_delete_branch(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            let Some(analysis) = &mut gamedata.analysis else {
+                return;
+            };
+            let current = analysis.moves.current();
+            analysis.moves.delete(current);
+            gamedata.show_analysis();
+        }
+    }
+
+    /// Copies the analyzed game as PDN, with every variation tried on it.
+    pub fn on_copy_pdn(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let gamedata = try_get_static_self().unwrap();
+            let Some(analysis) = &gamedata.analysis else {
+                return;
+            };
+            let my_name = gamedata.window.get_my_username().to_string();
+            let other_name = gamedata.window.get_other_username().to_string();
+            let (white, black) = match gamedata.board.player_color() {
+                PieceColor::White => (my_name, other_name),
+                PieceColor::Black => (other_name, my_name),
+            };
+            let pdn = analysis
+                .moves
+                .to_pdn(&[("White", &white), ("Black", &black)], gamedata.pdn_result);
+
+            let message = match copy_pdn(&pdn) {
+                true => MessageKey::PdnCopied,
+                false => {
+                    println!("{}", pdn);
+                    MessageKey::PdnNotCopied
+                }
+            };
+            gamedata
+                .window
+                .set_analysis_message(tr(message, &[]).into());
+        }
+    }
+
+    pub fn on_move_accepted(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            if let Some(pending) = gamedata.pending_move.take() {
+                // The other player may have moved before the acknowledgement got here
+                if !pending.remote_moves.is_empty() {
+                    gamedata.is_player_turn = true;
+                }
+            }
+        }
+    }
+
+    /// Takes back our last move, when the host rejected it because it wasn't our turn. The moves
+    /// the host made in the meantime are played again, and the turn is set from the host's move
+    /// number.
+    pub fn on_move_rejected(&self) -> impl FnMut(i32) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |host_move_number: i32| {
+            let mut gamedata = try_get_static_self().unwrap();
+            let Some(pending) = gamedata.pending_move.take() else {
+                return;
+            };
+            let host_move_number = host_move_number as u16;
+            println!(
+                "Move {} was rejected, the host is at move {}",
+                pending.move_number, host_move_number
+            );
+
+            let applied = pending.roll_back(&mut gamedata.board);
+            gamedata.sync_move_list();
+            interface::set_move_number(applied);
+            // The hash sent after our move is replaced, so the boards are compared again
+            interface::send_board_hash(&gamedata.board.white_pieces(), applied);
+
+            let player_color = gamedata.board.player_color();
+            match PendingMove::turn_after_rejection(host_move_number, applied, player_color) {
+                Some(is_player_turn) => gamedata.is_player_turn = is_player_turn,
+                None => println!(
+                    "The host is at move {}, but only {} moves are played here. A full resync is needed",
+                    host_move_number, applied
+                ),
+            }
+        }
+    }
+
+    /// Shows the host's board in the resync preview, if the client has resynced after a reconnect
+    /// and the boards differ. The live board is left alone until the player accepts it. The resync
+    /// waits while our own move is unacknowledged, since the host's board may not have it yet.
+    pub fn on_resync_board(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            if gamedata.pending_move.is_some() {
+                return;
+            }
+            let Some(host) = interface::take_resync_board() else {
+                return;
+            };
+            gamedata.take_host_draw_offer(host.draw_offer);
+            if ResyncPreview::is_same_position(&host, &gamedata.board) {
+                // Only the turn can differ, and the host's turn is always the right one
+                gamedata.take_host_turn(host.move_number, host.side_to_move);
+                return;
+            }
+
+            println!("The board differs from the host's. Asking before loading the host's board");
+            let preview = ResyncPreview::new(host, gamedata.board.player_color());
+            gamedata
+                .window
+                .set_resync_pieces(ModelRc::from(Board::model(preview.pieces().to_vec())));
+            gamedata
+                .window
+                .set_resync_message(tr(MessageKey::ResyncPreview, &[]).into());
+            gamedata.window.set_resync_pending(true);
+            gamedata.resync_preview = Some(preview);
+        }
+    }
+
+    /// Loads the host's board shown in the resync preview, and takes the host's turn.
+    pub fn on_accept_resync(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            let Some(preview) = gamedata.close_resync_preview() else {
+                return;
+            };
+            println!("Loading the host's board");
+            gamedata.unconfirmed_move = None;
+            gamedata.window.set_move_pending(false);
+            let (move_number, side_to_move) = preview.accept(&mut gamedata.board);
+            interface::publish_board(gamedata.board.white_pieces());
+            gamedata.take_host_turn(move_number, side_to_move);
+        }
+    }
+
+    /// Keeps our own board, and drops the host's board shown in the resync preview.
+    pub fn on_decline_resync(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            if gamedata.close_resync_preview().is_some() {
+                println!("Keeping our own board instead of the host's");
+            }
+        }
+    }
+
+    /// Offers the other player a draw. Only the player to move can offer one, and the game waits
+    /// for the answer instead of a move.
+    pub fn on_offer_draw(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            if !gamedata.is_waiting_for_move()
+                || gamedata.pending_move.is_some()
+                || gamedata.tutorial.is_some()
+                || gamedata.window.get_game_over()
+            {
+                gamedata
+                    .window
+                    .set_game_message(tr(MessageKey::DrawOfferNotYourTurn, &[]).into());
+                return;
+            }
+
+            println!("Offering a draw");
+            interface::send_game_action(GameAction::OfferDraw, |resp| {
+                if let Err(e) = resp {
+                    println!("Draw offer failed: {}", e);
+                }
+            });
+            gamedata
+                .window
+                .set_game_message(tr(MessageKey::DrawOfferSent, &[]).into());
+            gamedata.wait_for_opponent();
+        }
+    }
+
+    /// Shows the draw offer the other player sent, until the player accepts or declines it.
+    pub fn on_draw_offered(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let gamedata = try_get_static_self().unwrap();
+            println!("The other player offered a draw");
+            gamedata
+                .window
+                .set_game_message(tr(MessageKey::DrawOffered, &[]).into());
+            gamedata.window.set_draw_offer_open(true);
+        }
+    }
+
+    /// Accepts the other player's draw offer, which ends the game.
+    pub fn on_accept_draw(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            gamedata.window.set_draw_offer_open(false);
+            interface::send_game_action(GameAction::DrawResponse(true), |resp| {
+                if let Err(e) = resp {
+                    println!("Accepting the draw failed: {}", e);
+                }
+            });
+            send_game_over(None, GameOverReason::DrawAccepted);
+            gamedata.end_game(None, GameOverReason::DrawAccepted);
+        }
+    }
+
+    /// Declines the other player's draw offer. It's still their move, so it's waited for again.
+    pub fn on_decline_draw(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            gamedata.window.set_draw_offer_open(false);
+            gamedata.window.set_game_message("".into());
+            interface::send_game_action(GameAction::DrawResponse(false), |resp| {
+                if let Err(e) = resp {
+                    println!("Declining the draw failed: {}", e);
+                }
+            });
+            gamedata.wait_for_opponent();
+        }
+    }
+
+    /// Ends the game if the other player accepted our draw offer, and gives the move back to the
+    /// player if they declined it.
+    pub fn on_draw_answered(&self) -> impl FnMut(bool) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |accepted: bool| {
+            let mut gamedata = try_get_static_self().unwrap();
+            if accepted {
+                gamedata.end_game(None, GameOverReason::DrawAccepted);
+                return;
+            }
+            println!("The other player declined the draw");
+            gamedata.is_player_turn = true;
+            gamedata
+                .window
+                .set_game_message(tr(MessageKey::DrawDeclined, &[]).into());
+        }
+    }
+
+    /// Gives up the game, which the other player wins.
+    pub fn on_surrender(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            if gamedata.window.get_game_over() || gamedata.tutorial.is_some() {
+                return;
+            }
+
+            println!("Surrendering");
+            let winner = gamedata.board.player_color().get_opposite();
+            send_game_over(Some(winner), GameOverReason::Surrender);
+            gamedata.end_game(Some(winner), GameOverReason::Surrender);
+        }
+    }
+
+    /// Ends the game, if the other player has told that it ended, or lost it by not coming back in
+    /// time, and ends the match if they resigned it. Called regularly, like `on_resync_board()`.
+    pub fn on_game_ended(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let result = interface::take_game_result();
+            let match_resigned = interface::take_match_resigned();
+            if result.is_none() && !match_resigned {
+                return;
+            }
+            let mut gamedata = try_get_static_self().unwrap();
+            if gamedata.window.get_window_state() != WindowType::Game {
+                return;
+            }
+            if let Some(result) = result {
+                gamedata.end_game(result.winner, result.reason);
+            }
+            if match_resigned {
+                // The other player surrendered a game they resigned the match in, and the end of
+                // the game may come after the resignation
+                let winner = gamedata.board.player_color();
+                gamedata.end_game(Some(winner), GameOverReason::Surrender);
+                gamedata.match_state.resign(Source::Remote);
+                gamedata.show_match();
+            }
+        }
+    }
+
+    /// Offers the other player a rematch, once the game has ended. The colors are swapped in the
+    /// new game, which starts when the other player accepts.
+    pub fn on_offer_rematch(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let gamedata = try_get_static_self().unwrap();
+            if !gamedata.window.get_game_over()
+                || gamedata.window.get_rematch_sent()
+                || gamedata.tutorial.is_some()
+            {
+                return;
+            }
+
+            println!("Offering a rematch");
+            gamedata.window.set_rematch_sent(true);
+            gamedata
+                .window
+                .set_game_message(tr(MessageKey::RematchOfferSent, &[]).into());
+
+            // The closure has to be `Sync`, which the window handle isn't
+            let weak_window = std::sync::Mutex::new(gamedata.window.as_weak());
+            interface::offer_rematch(move |answer| {
+                let weak_window = weak_window.lock().unwrap().clone();
+                let message = match answer {
+                    Ok(Some(color)) => {
+                        slint::invoke_from_event_loop(move || {
+                            weak_window
+                                .unwrap()
+                                .invoke_rematch_started(color == PieceColor::White);
+                        })
+                        .unwrap();
+                        return;
+                    }
+                    Ok(None) => {
+                        slint::invoke_from_event_loop(move || {
+                            weak_window.unwrap().invoke_rematch_declined();
+                        })
+                        .unwrap();
+                        return;
+                    }
+                    Err(e) if e.is::<interface::GameInProgress>() => MessageKey::RematchNotOver,
+                    Err(e) => {
+                        println!("Rematch offer failed: {}", e);
+                        MessageKey::RematchDeclined
+                    }
+                };
+                slint::invoke_from_event_loop(move || {
+                    let window = weak_window.unwrap();
+                    // A new game may have started in the meantime, from the other player's offer
+                    if window.get_game_over() {
+                        window.set_rematch_sent(false);
+                        window.set_game_message(tr(message, &[]).into());
+                    }
+                })
+                .unwrap();
+            });
+        }
+    }
+
+    /// Shows the rematch the other player offered, until the player accepts or declines it. Called
+    /// regularly, like `on_resync_board()`.
+    pub fn on_rematch_offered(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let Some(offer) = interface::take_rematch_offer() else {
+                return;
+            };
+            let mut gamedata = try_get_static_self().unwrap();
+            // The game may have been left already, e.g. for the start window
+            if gamedata.window.get_window_state() != WindowType::Game
+                || !gamedata.window.get_game_over()
+            {
+                interface::answer_rematch(offer, false);
+                return;
+            }
+            println!("The other player offered a rematch");
+            if let Some(earlier) = gamedata.rematch_offer.replace(offer) {
+                interface::answer_rematch(earlier, false);
+            }
+            gamedata
+                .window
+                .set_game_message(tr(MessageKey::RematchOffered, &[]).into());
+            gamedata.window.set_rematch_offer_open(true);
+        }
+    }
+
+    /// Accepts the other player's rematch offer, which starts a new game with the colors swapped.
+    pub fn on_accept_rematch(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            gamedata.window.set_rematch_offer_open(false);
+            let Some(offer) = gamedata.rematch_offer.take() else {
+                return;
+            };
+            match interface::answer_rematch(offer, true) {
+                Some(color) => gamedata.start_rematch(color),
+                None => gamedata.window.set_game_message("".into()),
+            }
+        }
+    }
+
+    /// Declines the other player's rematch offer, which ends a match on its score.
+    pub fn on_decline_rematch(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            gamedata.window.set_rematch_offer_open(false);
+            gamedata.window.set_game_message("".into());
+            if let Some(offer) = gamedata.rematch_offer.take() {
+                interface::answer_rematch(offer, false);
+                gamedata.match_state.decline_rematch(Source::Local);
+                gamedata.show_match();
+            }
+        }
+    }
+
+    /// Shows that the other player declined our rematch offer, which ends a match on its score.
+    pub fn on_rematch_declined(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            // A new game may have started in the meantime, from the other player's offer
+            if !gamedata.window.get_game_over() {
+                return;
+            }
+            gamedata.window.set_rematch_sent(false);
+            gamedata
+                .window
+                .set_game_message(tr(MessageKey::RematchDeclined, &[]).into());
+            gamedata.match_state.decline_rematch(Source::Remote);
+            gamedata.show_match();
+        }
+    }
+
+    /// Resigns the rest of the match, which the other player wins. A game that is still being
+    /// played is surrendered first.
+    pub fn on_resign_match(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            if !gamedata.match_state.is_match()
+                || gamedata.match_state.is_over()
+                || gamedata.tutorial.is_some()
+            {
+                return;
+            }
+
+            println!("Resigning the match");
+            if !gamedata.window.get_game_over() {
+                let winner = gamedata.board.player_color().get_opposite();
+                send_game_over(Some(winner), GameOverReason::Surrender);
+                gamedata.end_game(Some(winner), GameOverReason::Surrender);
+                // Losing the game may have lost the match already
+                if gamedata.match_state.is_over() {
+                    return;
+                }
+            }
+            interface::resign_match(|resp| {
+                if let Err(e) = resp {
+                    println!("Resigning the match failed: {}", e);
+                }
+            });
+            gamedata.match_state.resign(Source::Local);
+            gamedata.show_match();
+        }
+    }
+
+    /// Starts the rematch the other player accepted.
+    pub fn on_rematch_started(&self) -> impl FnMut(bool) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |is_white: bool| {
+            let mut gamedata = try_get_static_self().unwrap();
+            let color = match is_white {
+                true => PieceColor::White,
+                false => PieceColor::Black,
+            };
+            gamedata.start_rematch(color);
+        }
+    }
+
+    /// Asks the other player for a pause, or to go on while the game is paused. Either player can
+    /// ask at any time during the game.
+    pub fn on_request_pause(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let mut gamedata = try_get_static_self().unwrap();
+            if gamedata.window.get_game_over()
+                || gamedata.tutorial.is_some()
+                || gamedata.pause.request.is_some()
+            {
+                return;
+            }
+
+            let action = match gamedata.pause.paused {
+                true => GameAction::ResumeRequest,
+                false => GameAction::PauseRequest,
+            };
+            println!("Sending {:?}", action);
+            interface::send_game_action(action, |resp| {
+                if let Err(e) = resp {
+                    println!("Pause request failed: {}", e);
+                }
+            });
+            gamedata.take_pause(interface::get_pause());
+        }
+    }
+
+    /// Answers the other player's request for a pause, or to go on.
+    pub fn on_answer_pause(&self) -> impl FnMut(bool) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |accepted: bool| {
+            let mut gamedata = try_get_static_self().unwrap();
+            if !gamedata.pause.is_asked(gamedata.board.player_color()) {
+                return;
+            }
+            interface::send_game_action(GameAction::PauseResponse(accepted), |resp| {
+                if let Err(e) = resp {
+                    println!("Answering the pause request failed: {}", e);
+                }
+            });
+            gamedata.take_pause(interface::get_pause());
+        }
+    }
+
+    /// Follows the pause, after either player asked about it, or answered. Called regularly, like
+    /// `on_resync_board()`, since the other player can ask at any time, also after a reconnect.
+    pub fn on_pause_updated(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let pause = interface::get_pause();
+            let mut gamedata = try_get_static_self().unwrap();
+            if pause != gamedata.pause && gamedata.window.get_window_state() == WindowType::Game {
+                gamedata.take_pause(pause);
+            }
+        }
+    }
+
+    /// Tells the other player that a chat message is being typed.
+    pub fn on_chat_edited(&self) -> impl FnMut() + 'static {
+        || interface::notify_typing()
+    }
+
+    /// Sends a chat message to the other player, and shows it as the last message.
+    pub fn on_send_chat(&self) -> impl FnMut(SharedString) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |message| {
+            let gamedata = try_get_static_self().unwrap();
+            let message = message.trim();
+            if message.is_empty() {
+                return;
+            }
+            if let Err(e) = interface::send_chat_message(message) {
+                println!("The chat message wasn't sent: {}", e);
+                return;
+            }
+            let username = gamedata.window.get_my_username();
+            gamedata
+                .window
+                .set_chat_text(tr(MessageKey::ChatLine, &[&username, &message]).into());
+        }
+    }
+
+    /// Shows the chat messages the other player sent since the last time. Called regularly, like
+    /// `on_resync_board()`.
+    pub fn on_chat_received(&self) -> impl FnMut() + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move || {
+            let gamedata = try_get_static_self().unwrap();
+            while let Some((sender, message)) = interface::get_next_chat_message() {
+                gamedata
+                    .window
+                    .set_chat_text(tr(MessageKey::ChatLine, &[&sender, &message]).into());
+            }
+        }
+    }
+
+    /// Connects to the host with `join_code` in the background, and loads the game window once
+    /// connected. The client network loop has to be started first, unless `from_host` is set.
+    ///
+    /// ## Params
+    /// * `join_code` - The join code of the host.
+    /// * `from_host` - If this peer is hosting, and should stop hosting to join as client.
+    fn connect_to_host(&mut self, join_code: String, from_host: bool) {
+        self.load_connecting_window(join_code.clone(), false);
+        let host_address = interface::decode_join_code(&join_code)
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        self.window.set_host_address(host_address.into());
+
+        let username: String = self.window.get_username().into();
+        let anonymous = self.window.get_anonymous();
+
+        let handle_weak = self.window.as_weak();
+        tokio::spawn(async move {
+            let handle_progress = handle_weak.clone();
+            let on_progress = move |progress: ConnectProgress| {
+                let message = tr(
+                    MessageKey::ConnectRetrying,
+                    &[
+                        &progress.retry_in.as_secs(),
+                        &(progress.attempt + 1),
+                        &progress.max_attempts,
+                    ],
+                );
+                let handle_progress = handle_progress.clone();
+                slint::invoke_from_event_loop(move || {
+                    handle_progress
+                        .unwrap()
+                        .set_connecting_message(message.into());
+                })
+                .unwrap();
+            };
+            let joined = if from_host {
+                interface::join_as_client_from_host(
+                    &join_code,
+                    &username,
+                    interface::JOIN_RETRY,
+                    on_progress,
+                )
+            } else {
+                interface::connect_to_host_loop(
+                    &join_code,
+                    &username,
+                    interface::JOIN_RETRY,
+                    on_progress,
+                )
+            };
+            let (color, host_username) = match joined {
+                Ok(joined) => joined,
+                Err(e) => {
+                    println!("Couldn't join the game: {}", e);
+                    let message = e.to_string();
+                    slint::invoke_from_event_loop(move || {
+                        handle_weak.unwrap().set_connecting_message(message.into());
+                    })
+                    .unwrap();
+                    return;
+                }
+            };
+
+            println!("Joined {}'s game. You are {:?}", host_username, color);
+
+            let last_game = LastGame::new(
+                join_code.clone(),
+                host_username.clone(),
+                username.clone(),
+                color,
+                anonymous,
+            );
+            if let Err(e) = last_game.save() {
+                println!("Couldn't save the last game: {}", e);
+            }
+
+            let handle_copy = handle_weak.clone();
+            slint::invoke_from_event_loop(move || {
+                handle_copy
+                    .unwrap()
+                    .invoke_set_usernames(username.into(), host_username.into());
+            })
+            .unwrap();
+
+            let is_white = color == PieceColor::White;
+            let handle_copy = handle_weak.clone();
+            slint::invoke_from_event_loop(move || {
+                handle_copy.unwrap().invoke_coin_flipped(is_white);
+            })
+            .unwrap();
+
+            let report = interface::probe_path(&interface::PATH_PROBE_SIZES);
+            println!("Largest ping payload to the host: {:?}", report.largest);
+        });
+    }
+
+    /// Reveals the coin flip deciding the colors, and starts the game once it's shown.
+    pub fn on_coin_flipped(&self) -> impl FnMut(bool) + 'static {
+        let mut try_get_static_self = self.try_get_static_func();
+
+        move |is_white: bool| {
+            let gamedata = try_get_static_self().unwrap();
+            gamedata
+                .window
+                .set_connecting_message(tr(MessageKey::CoinFlipping, &[]).into());
+
+            let mut try_get_static_self = gamedata.try_get_static_func();
+            slint::Timer::single_shot(Duration::from_millis(COIN_FLIP_MS), move || {
+                let Some(mut gamedata) = try_get_static_self() else {
+                    return;
+                };
+                let (color, message) = match is_white {
+                    true => (PieceColor::White, MessageKey::CoinFlipWhite),
+                    false => (PieceColor::Black, MessageKey::CoinFlipBlack),
+                };
+                gamedata
+                    .window
+                    .set_connecting_message(tr(message, &[]).into());
+
+                gamedata.start_new_game(color);
+                if color == PieceColor::White {
+                    gamedata.is_player_turn = true;
+                } else {
+                    gamedata.wait_for_opponent();
+                }
+
+                // Give the player a moment to see their color
+                let mut try_get_static_self = gamedata.try_get_static_func();
+                slint::Timer::single_shot(Duration::from_millis(COIN_RESULT_MS), move || {
+                    if let Some(gamedata) = try_get_static_self() {
+                        gamedata.window.set_connecting_message("".into());
+                        gamedata.load_game_window();
+                    }
+                });
+            });
+        }
+    }
+
+    /// Takes the host's turn after a resync. The host decides the order of the moves, so its turn
+    /// is the right one when they differ.
+    ///
+    /// ## Params
+    /// * `move_number` - The number of moves the host has made in the game.
+    /// * `side_to_move` - The color whose turn it is on the host.
+    fn take_host_turn(&mut self, move_number: u16, side_to_move: PieceColor) {
+        if move_number != interface::get_move_number() {
+            println!(
+                "The host is at move {}, and {:?} is to move. Taking the host's turn",
+                move_number, side_to_move
+            );
+            interface::set_move_number(move_number);
+        }
+
+        let is_player_turn = side_to_move == self.board.player_color();
+        if is_player_turn {
+            self.is_player_turn = true;
+        } else if self.is_player_turn {
+            self.wait_for_opponent();
+        }
+    }
+
+    /// Starts a new game against the same player after a rematch was accepted, where we play
+    /// `color`. An offer of our own that is still open is declined, since the game has started.
+    /// It's the next game of the match, if one is being played.
+    fn start_rematch(&mut self, color: PieceColor) {
+        if let Some(offer) = self.rematch_offer.take() {
+            interface::answer_rematch(offer, false);
+        }
+        // The match goes on, unless it's over and the rematch starts a new one
+        let current = std::mem::replace(&mut self.match_state, Match::new(1));
+        self.start_new_game(color);
+        if !current.is_over() {
+            self.match_state = current;
+            self.show_match();
+        }
+        let message = match color {
+            PieceColor::White => MessageKey::RematchWhite,
+            PieceColor::Black => MessageKey::RematchBlack,
+        };
+        self.window.set_game_message(tr(message, &[]).into());
+        if color == PieceColor::White {
+            self.is_player_turn = true;
+        } else {
+            self.wait_for_opponent();
+        }
+    }
+
+    pub fn wait_for_opponent(&mut self) {
+        self.is_player_turn = false;
+        let weak_window = self.window.as_weak();
+        tokio::spawn(async move {
+            let mut action;
+            loop {
+                action = interface::get_next_game_action();
+                if action.is_none() {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    continue;
+                }
+                // The move indexes the board, so one that doesn't fit is dropped
+                if let Some(GameAction::MovePiece(mov)) = &action {
+                    if let Err(e) = mov.check_squares(interface::get_game_options().squares()) {
+                        println!("Ignored a move that doesn't fit on the board: {}", e);
+                        continue;
+                    }
+                }
+                break;
+            }
+
+            let action = unsafe { action.unwrap_unchecked() };
+            match action {
+                GameAction::MovePiece(mov) => {
+                    println!("Recieved move: {:#?}", mov);
+                    set_board_move(&mov.reverse(interface::get_game_options().geometry()));
+                    slint::invoke_from_event_loop(move || {
+                        weak_window.unwrap().invoke_move_piece();
+                    })
+                    .unwrap();
+                }
+                GameAction::OfferDraw => {
+                    slint::invoke_from_event_loop(move || {
+                        weak_window.unwrap().invoke_draw_offered();
+                    })
+                    .unwrap();
+                }
+                GameAction::DrawResponse(accepted) => {
+                    slint::invoke_from_event_loop(move || {
+                        weak_window.unwrap().invoke_draw_answered(accepted);
+                    })
+                    .unwrap();
+                }
+                _ => {
+                    println!(
+                        "Got GameAction {:?} while waiting for opponent,
+                                     this is not implemented yet",
+                        action
+                    );
+                }
+            }
+        });
+    }
+}
+
+pub struct GameData {
+    window: GameWindow,
+    board: Board,
+    #[allow(dead_code)]
+    is_host: Option<bool>,
+    is_player_turn: bool,
+    /// A join code outside the local network, that the user has been warned about. Joining it
+    /// again confirms it.
+    confirmed_join_code: Option<String>,
+    /// Our last move, until the other player has acknowledged it.
+    pending_move: Option<PendingMove>,
+    /// A move that has been clicked, but not confirmed yet. Only used when "Confirm moves" is on.
+    unconfirmed_move: Option<UnconfirmedMove>,
+    /// The history entry shown on the board, while the history view is open.
+    history_index: Option<usize>,
+    tutorial: Option<Tutorial>,
+    piece_sets: PieceSetManager,
+    /// The host's board shown in the resync preview, until the player accepts or declines it.
+    resync_preview: Option<ResyncPreview>,
+    /// The rematch the other player offered, until the player accepts or declines it.
+    rematch_offer: Option<RematchOffer>,
+    /// The match the game is part of. A single game is a match of one.
+    match_state: Match,
+    /// If the game is paused, as last shown.
+    pause: PauseState,
+    /// The game being analyzed, while analysis mode is open.
+    analysis: Option<Analysis>,
+    /// The result of the game in PDN, e.g. `"1-0"`, or `"*"` until it ends.
+    pdn_result: &'static str,
+}
+
+/// A game analyzed after it ended.
+struct Analysis {
+    /// The moves of the game, and the variations tried on them.
+    moves: MoveHistory,
+    /// The board at the end of the game, shown again when the analysis is closed.
+    final_board: Vec<PieceData>,
+}
+
+/// A move we have made on the board, before the other player has acknowledged it.
+struct PendingMove {
+    /// The pieces before the move.
+    snapshot: Vec<PieceData>,
+    /// The move number of the move.
+    move_number: u16,
+    /// The moves the other player made after ours, before the acknowledgement.
+    remote_moves: Vec<Move>,
+}
+
+impl PendingMove {
+    /// Takes our move back on `board`, and plays the moves the other player made after it again.
+    /// Returns the move number the board is at afterwards.
+    fn roll_back(self, board: &mut Board) -> u16 {
+        let player_color = board.player_color();
+        board.load_position(self.snapshot, player_color);
+        history::truncate(self.move_number);
+        for mov in &self.remote_moves {
+            set_board_move(mov);
+            board.move_piece();
+            history::push(
+                Source::Remote,
+                board.to_white_move(mov),
+                board.to_fen(player_color),
+            );
+        }
+        self.move_number + self.remote_moves.len() as u16
+    }
+
+    /// If it's our turn after the host rejected our move, with `applied` moves played here. `None`
+    /// if the host is more than a move ahead, which needs a full resync.
+    fn turn_after_rejection(
+        host_move_number: u16,
+        applied: u16,
+        player_color: PieceColor,
+    ) -> Option<bool> {
+        if host_move_number == applied {
+            Some(PieceColor::side_to_move(host_move_number) == player_color)
+        } else if host_move_number == applied + 1 {
+            // The host's move is on its way, and is still being waited for
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// What the "next" button of the onboarding does.
+#[derive(Debug)]
+enum OnboardingNext {
+    /// The username can't be used, so the onboarding stays at the first step.
+    Stay,
+    /// The username is taken, and the connectivity check is run.
+    CheckConnectivity,
+    /// How one player hosts and the other joins is shown.
+    ShowHostOrJoin,
+    /// The onboarding is done, and the profile with the username is saved.
+    Finish(Profile),
+}
+
+impl OnboardingNext {
+    /// What the button does at `step` of the onboarding, with `username` typed in.
+    fn at(step: i32, username: &str) -> Self {
+        match step {
+            0 if interface::validate_username(username).is_err() => Self::Stay,
+            0 => Self::CheckConnectivity,
+            // A failed check doesn't stop the player, since the check may be wrong
+            1 => Self::ShowHostOrJoin,
+            _ => Self::Finish(Profile::new(username.trim().to_owned())),
+        }
+    }
+}
+
+/// A move that has been clicked, but not confirmed yet, when "Confirm moves" is on. Its start and
+/// end squares are marked on the board until it's confirmed or cancelled.
+struct UnconfirmedMove(Move);
+
+impl UnconfirmedMove {
+    /// Holds `mov` back for confirmation, and marks its squares on `board`, which isn't moved.
+    fn hold(mov: Move, board: &mut Board) -> Self {
+        board.reset_squares();
+        board.mark_squares(&[mov.index, mov.end]);
+        Self(mov)
+    }
+
+    /// The move, to be played.
+    fn confirm(self) -> Move {
+        self.0
+    }
+
+    /// Drops the move, and selects its piece on `board` again.
+    fn cancel(self, board: &mut Board) {
+        board.select_square(self.0.index);
+    }
+}
+
+/// The host's board after a resync, shown next to ours until the player decides which to keep. Our
+/// board is only touched if the player accepts the host's.
+struct ResyncPreview {
+    /// The host's board, as seen from our side.
+    host: HostBoard,
+}
+
+impl ResyncPreview {
+    /// If the host's board is the position of `board`, where only the turn can differ.
+    fn is_same_position(host: &HostBoard, board: &Board) -> bool {
+        let (move_number, side_to_move) = (host.move_number, host.side_to_move);
+        position_hash(&host.board, side_to_move, move_number, board.options())
+            == board.position_hash(side_to_move, move_number)
+    }
+
+    /// The preview of the host's board, for a player of `player_color`.
+    fn new(mut host: HostBoard, player_color: PieceColor) -> Self {
+        square::orient(&mut host.board, player_color);
+        Self { host }
+    }
+
+    /// The pieces to show in the preview.
+    fn pieces(&self) -> &[PieceData] {
+        &self.host.board
+    }
+
+    /// Loads the host's board on `board`. Returns the host's move number and the color to move,
+    /// for the turn to take.
+    fn accept(self, board: &mut Board) -> (u16, PieceColor) {
+        let player_color = board.player_color();
+        board.load_position(self.host.board, player_color);
+        (self.host.move_number, self.host.side_to_move)
+    }
+}
+
+impl GameData {
+    /// Returns true if the player can make a move on the board. A move waiting for confirmation
+    /// has to be confirmed or cancelled first, and so does an open resync preview. Nothing can be
+    /// moved during a pause.
+    fn is_waiting_for_move(&self) -> bool {
+        self.is_player_turn
+            && self.history_index.is_none()
+            && self.unconfirmed_move.is_none()
+            && self.resync_preview.is_none()
+            && !self.pause.paused
+    }
+
+    /// Ends the game, won by `winner` or drawn if it's `None`, and shows why. There is nothing to
+    /// reconnect to afterwards. A game that has already ended is left alone, since both players
+    /// can find out that it ended.
+    fn end_game(&mut self, winner: Option<PieceColor>, reason: GameOverReason) {
+        if self.window.get_game_over() {
+            return;
+        }
+        println!("The game is over: {:?}, won by {:?}", reason, winner);
+
+        let won = winner == Some(self.board.player_color());
+        let message = match reason {
+            _ if winner.is_none() => MessageKey::DrawAgreed,
+            GameOverReason::DrawAccepted => MessageKey::DrawAgreed,
+            GameOverReason::Surrender if won => MessageKey::GameWonSurrender,
+            GameOverReason::Surrender => MessageKey::GameLostSurrender,
+            GameOverReason::NoMoves if won => MessageKey::GameWonNoMoves,
+            GameOverReason::NoMoves => MessageKey::GameLostNoMoves,
+            GameOverReason::Timeout if won => MessageKey::GameWonTimeout,
+            GameOverReason::Timeout => MessageKey::GameLostTimeout,
+        };
+        self.is_player_turn = false;
+        self.window.set_game_over(true);
+        self.window.set_draw_offer_open(false);
+        self.window.set_game_message(tr(message, &[]).into());
+        let outcome = match winner {
+            None => Outcome::Drawn,
+            Some(_) if won => Outcome::Won,
+            Some(_) => Outcome::Lost,
+        };
+        self.match_state.record_game(outcome, reason);
+        self.show_match();
+        self.pdn_result = match winner {
+            Some(PieceColor::White) => "1-0",
+            Some(PieceColor::Black) => "0-1",
+            None => "1/2-1/2",
+        };
+        LastGame::clear();
+        self.window.set_last_game_host("".into());
+    }
+
+    /// Shows the draw offer the host has open after a resync, so an offer made before a reconnect
+    /// can still be answered after it. An offer the host doesn't have is closed.
+    fn take_host_draw_offer(&mut self, offerer: Option<PieceColor>) {
+        let is_offered = offerer == Some(self.board.player_color().get_opposite());
+        if is_offered == self.window.get_draw_offer_open() || self.window.get_game_over() {
+            return;
+        }
+        let message = match is_offered {
+            true => tr(MessageKey::DrawOffered, &[]),
+            false => String::new(),
+        };
+        self.window.set_game_message(message.into());
+        self.window.set_draw_offer_open(is_offered);
+    }
+
+    /// Shows the pause, and what it waits for. A move waiting for confirmation is dropped when the
+    /// game is paused, since it can't be played until the game goes on.
+    fn take_pause(&mut self, pause: PauseState) {
+        let my_color = self.board.player_color();
+        let was_declined = self.pause.request == Some(my_color)
+            && pause.request.is_none()
+            && pause.paused == self.pause.paused;
+        self.pause = pause;
+
+        if pause.paused {
+            if let Some(unconfirmed) = self.unconfirmed_move.take() {
+                self.window.set_move_pending(false);
+                unconfirmed.cancel(&mut self.board);
+            }
+        }
+        let message = match pause.request {
+            _ if was_declined => Some(MessageKey::PauseDeclined),
+            Some(color) if color != my_color && pause.paused => Some(MessageKey::ResumeAsked),
+            Some(color) if color != my_color => Some(MessageKey::PauseAsked),
+            Some(_) if pause.paused => Some(MessageKey::ResumeRequestSent),
+            Some(_) => Some(MessageKey::PauseRequestSent),
+            None if pause.paused => Some(MessageKey::GamePaused),
+            None => None,
+        };
+        let text = message.map_or(String::new(), |message| tr(message, &[]));
+        self.window.set_pause_text(text.into());
+        self.window.set_paused(pause.paused);
+        self.window.set_pause_asked(pause.is_asked(my_color));
+        self.window.set_pause_sent(pause.request == Some(my_color));
+    }
+
+    /// Shows the score of the match, and if the rest of it can still be resigned.
+    fn show_match(&self) {
+        self.window
+            .set_match_text(self.match_state.summary().into());
+        self.window
+            .set_match_open(self.match_state.is_match() && !self.match_state.is_over());
+    }
+
+    /// Closes the resync preview, and returns the host's board that was shown in it.
+    fn close_resync_preview(&mut self) -> Option<ResyncPreview> {
+        self.window.set_resync_pending(false);
+        self.window
+            .set_resync_pieces(ModelRc::new(VecModel::<ui::PieceData>::default()));
+        self.resync_preview.take()
+    }
+
+    pub fn new() -> Result<Self, slint::PlatformError> {
+        let window = GameWindow::new()?;
+        let board = Board::new(&window);
+
+        let gamedata = GameData {
+            window,
+            board,
+            is_host: None,
+            is_player_turn: false,
+            confirmed_join_code: None,
+            pending_move: None,
+            unconfirmed_move: None,
+            history_index: None,
+            tutorial: None,
+            piece_sets: PieceSetManager::scan(),
+            resync_preview: None,
+            rematch_offer: None,
+            match_state: Match::new(1),
+            pause: PauseState::default(),
+            analysis: None,
+            pdn_result: "*",
+        };
+        gamedata
+            .window
+            .set_resync_squares(ModelRc::new(VecModel::from(vec![
+                BoardSquare {
+                    marked: false
+                };
+                32
+            ])));
+        let names: Vec<SharedString> = gamedata
+            .piece_sets
+            .names()
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        gamedata
+            .window
+            .set_piece_sets(ModelRc::new(VecModel::from(names)));
+
+        let profile = Profile::load();
+        let piece_set = profile
+            .as_ref()
+            .and_then(|profile| profile.piece_set.clone())
+            .unwrap_or_else(|| BUILT_IN.to_owned());
+        gamedata.piece_sets.select(&piece_set, &gamedata.window);
+        gamedata.window.set_piece_set(piece_set.into());
+
+        let languages: Vec<SharedString> = Language::values()
+            .iter()
+            .map(|lang| lang.name().into())
+            .collect();
+        gamedata
+            .window
+            .set_languages(ModelRc::new(VecModel::from(languages)));
+        if let Some(profile) = &profile {
+            set_language(profile.language);
+        }
+        gamedata.window.set_language(get_language().name().into());
+
+        match profile {
+            Some(profile) => {
+                gamedata.window.set_username(profile.username.into());
+                gamedata.window.set_anonymous(profile.anonymous);
+                gamedata.load_start_window();
+            }
+            None => gamedata.load_onboarding_window(),
+        }
+
+        Ok(gamedata)
+    }
+
+    #[inline]
+    pub fn get_window(&self) -> &GameWindow {
+        &self.window
+    }
+
+    fn get_board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    pub fn start_new_game(&mut self, your_color: PieceColor) {
+        let board = self.get_board_mut();
+        board.set_options(interface::get_game_options());
+        board.start_new_game(your_color);
+        self.close_resync_preview();
+        self.unconfirmed_move = None;
+        self.window.set_move_pending(false);
+        self.window.set_game_over(false);
+        self.window.set_game_message("".into());
+        self.window.set_draw_offer_open(false);
+        self.window.set_rematch_offer_open(false);
+        self.window.set_rematch_sent(false);
+        self.match_state = Match::new(interface::get_game_options().match_length);
+        self.show_match();
+        self.pause = PauseState::default();
+        self.take_pause(PauseState::default());
+        self.analysis = None;
+        self.window.set_analyzing(false);
+        self.pdn_result = "*";
+        history::clear(self.board.to_fen(PieceColor::White));
+        self.sync_move_list();
+        interface::publish_board(self.board.white_pieces());
+
+        #[cfg(feature = "state-server")]
+        super::state_server::new_game(self.board.to_fen(PieceColor::White));
+    }
+
+    /// Saves the move that was just made on the board in the history, publishes the board for
+    /// resyncs, sends its hash to the other player, and sends it to the state server. Must be called before `is_player_turn` is updated, since it tells who made the move.
+    fn record_move(&self) {
+        if self.tutorial.is_some() {
+            return;
+        }
+
+        let my_color = self.board.player_color();
+        let (mover, source) = if self.is_player_turn {
+            (my_color, Source::Local)
+        } else {
+            (my_color.get_opposite(), Source::Remote)
+        };
+        let mov = self.board.to_white_move(&get_board_move());
+        let fen = self.board.to_fen(mover.get_opposite());
+
+        #[cfg(feature = "state-server")]
+        {
+            super::state_server::set_names(
+                my_color,
+                self.window.get_my_username().into(),
+                self.window.get_other_username().into(),
+            );
+            super::state_server::push_move(&mov, fen.clone());
+        }
+
+        history::push(source, mov, fen);
+        self.sync_move_list();
+        interface::publish_board(self.board.white_pieces());
+        interface::send_board_hash(&self.board.white_pieces(), history::move_count());
+    }
+
+    /// Shows the moves in the history in the move list. Must be called whenever the history
+    /// changes.
+    fn sync_move_list(&self) {
+        let moves: Vec<SharedString> = history::move_list().into_iter().map(Into::into).collect();
+        self.window
+            .set_move_list(ModelRc::new(VecModel::from(moves)));
+    }
+
+    /// Shows entry `index` of the history on the board, instead of the live game.
+    fn show_history_entry(&mut self, index: usize) {
+        let entries = history::history_ring();
+        let Some(entry) = entries.get(index) else {
+            return;
+        };
+
+        // The history is only written by `record_move()`, so the FEN is always valid
+        let (mut pieces, _) = from_fen(&entry.fen).unwrap();
+        square::orient(&mut pieces, self.board.player_color());
+        self.board.show_position(pieces);
+
+        self.history_index = Some(index);
+        let text = format!("History {} / {}: {}", index + 1, entries.len(), entry);
+        self.window.set_history_text(text.into());
+        self.window.set_history_open(true);
+    }
+
+    /// Handles a click on the square at `index` in analysis mode, which moves the selected piece
+    /// there whichever color it is, or selects the square. The move is only added to the
+    /// analysis.
+    fn click_analysis_square(&mut self, index: usize) {
+        if let Some(mov) = self.board.find_any_move_to(index) {
+            let player_color = self.board.player_color();
+            let mover = match self.board.piece_is_player(mov.index) {
+                true => player_color,
+                false => player_color.get_opposite(),
+            };
+            let white_move = self.board.to_white_move(&mov);
+            // Only the board is moved, since `invoke_move_piece()` would record and send the move
+            set_board_move(&mov);
+            self.board.move_piece();
+            let fen = self.board.to_fen(mover.get_opposite());
+            if let Some(analysis) = &mut self.analysis {
+                analysis.moves.play(white_move, fen);
+            }
+            self.show_analysis();
+        }
+        self.board.select_square(index);
+    }
+
+    /// Shows the position of the analysis on the board, the line through it in the move list, and
+    /// every line tried as PDN.
+    fn show_analysis(&mut self) {
+        let Some(analysis) = &self.analysis else {
+            return;
+        };
+        let moves = &analysis.moves;
+        let current = moves.current();
+        let player_color = self.board.player_color();
+        // The FENs are all made by `Board::to_fen()`
+        if let Some(Ok((mut pieces, _))) = moves.fen(current).map(from_fen) {
+            square::orient(&mut pieces, player_color);
+            self.board.load_position(pieces, player_color);
+        }
+        self.board.clear_selection();
+        self.window.set_analysis_message("".into());
+
+        let line = moves.line_through(current);
+        let notation: Vec<SharedString> = line
+            .iter()
+            .filter_map(|id| moves.mov(*id))
+            .map(|mov| move_notation(mov).into())
+            .collect();
+        self.window
+            .set_move_list(ModelRc::new(VecModel::from(notation)));
+        let ply = line.iter().position(|id| *id == current);
+        self.window
+            .set_analysis_ply(ply.map_or(-1, |ply| ply as i32));
+        self.window.set_analysis_text(moves.movetext().into());
+    }
+
+    /// Closes analysis mode, and shows the end of the game and its moves again.
+    fn close_analysis(&mut self) {
+        let Some(analysis) = self.analysis.take() else {
+            return;
+        };
+        let player_color = self.board.player_color();
+        self.board.load_position(analysis.final_board, player_color);
+        self.board.clear_selection();
+        self.window.set_analyzing(false);
+        self.sync_move_list();
+    }
+
+    /// Checks that `join_code` can point to a host, before connecting to it. Returns the message to
+    /// show the user if it can't, or if it's outside the local network and hasn't been confirmed
+    /// by joining it again.
+    ///
+    /// ## Params
+    /// * `join_code` - The join code of the host.
+    /// * `own_join_code` - Our own join code, if we are hosting.
+    fn check_join_code(
+        &mut self,
+        join_code: &str,
+        own_join_code: Option<&str>,
+    ) -> Result<(), String> {
+        let (addr, class) =
+            interface::classify_join_code(join_code, own_join_code).map_err(|e| {
+                println!("Invalid join code: {}", e);
+                tr(MessageKey::JoinCodeInvalid, &[])
+            })?;
+
+        if class.is_invalid() {
+            return Err(tr(MessageKey::JoinTargetInvalid, &[&addr]));
+        }
+        if class == TargetClass::Public && self.confirmed_join_code.as_deref() != Some(join_code) {
+            self.confirmed_join_code = Some(join_code.to_owned());
+            return Err(tr(MessageKey::JoinTargetPublic, &[&addr]));
+        }
+        Ok(())
+    }
+
+    pub fn load_start_window(&self) {
+        let host = LastGame::load()
+            .map(|last_game| last_game.host_username)
+            .unwrap_or_default();
+        self.window.set_last_game_host(host.into());
+        self.window.set_window_state(WindowType::Start);
+    }
+
+    pub fn load_game_window(&self) {
+        self.window.invoke_load_game_window();
+    }
+
+    pub fn load_connecting_window(&self, join_code: String, is_host: bool) {
+        self.window.set_join_code(join_code.into());
+        self.window.set_is_host(is_host);
+        self.window.set_window_state(WindowType::Connecting);
+    }
+
+    /// Shows the rules of the game, as described by the `GameOptions` the board plays.
+    pub fn load_rules_window(&self) {
+        let rules: Vec<String> = self
+            .board
+            .options()
+            .describe()
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        self.window.set_rules_text(rules.join("\n").into());
+        self.window.set_window_state(WindowType::Rules);
+    }
+
+    /// Starts the onboarding, shown the first time the game starts.
+    pub fn load_onboarding_window(&self) {
+        self.window.set_onboarding_step(0);
+        self.show_username_message(&self.window.get_onboarding_username());
+        self.window.set_window_state(WindowType::Onboarding);
+    }
+
+    /// Shows why `username` can't be used in the onboarding, or nothing if it can.
+    /// Returns if it can be used.
+    fn show_username_message(&self, username: &str) -> bool {
+        let result = interface::validate_username(username);
+        let message = result.err().map(|e| e.to_string()).unwrap_or_default();
+        self.window.set_onboarding_username_message(message.into());
+        result.is_ok()
+    }
+
+    pub fn load_prompt_client_window(&self) {
+        self.window.set_window_state(WindowType::LanPrompt);
+    }
+
+    /// Sets up the board and texts for the current tutorial scenario.
+    /// When the tutorial is done, the start window is loaded.
+    fn load_tutorial_scenario(&mut self) {
+        let Some(tutorial) = &self.tutorial else {
+            return;
+        };
+
+        let Some(scenario) = tutorial.current() else {
+            self.tutorial = None;
+            self.load_start_window();
+            return;
+        };
+
+        let (number, count) = tutorial.progress();
+        let title = tr(
+            MessageKey::TutorialTitle,
+            &[&number, &count, &scenario.title],
+        );
+        self.window.set_tutorial_title(title.into());
+        self.window
+            .set_tutorial_text(scenario.instruction.clone().into());
+
+        // Scenarios are validated when loaded, so the position is always valid
+        let pieces = scenario.pieces().unwrap();
+        self.board.load_position(pieces, PieceColor::White);
+        self.board.selected_square = 0;
+        self.is_player_turn = true;
+    }
+}
+
+/// Tell the other player the game has ended, in the background, since it's sent until it's
+/// acknowledged.
+fn send_game_over(winner: Option<PieceColor>, reason: GameOverReason) {
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = interface::send_game_over(winner, reason) {
+            println!("Failed to tell the other player the game is over: {}", e);
+        }
+    });
+}
+
+/// Copies `pdn`, so the analyzed game can be pasted into other programs. Returns false if it
+/// couldn't be copied.
+#[cfg(feature = "clipboard")]
+fn copy_pdn(pdn: &str) -> bool {
+    let copied = arboard::Clipboard::new().and_then(|mut clipboard| clipboard.set_text(pdn));
+    if let Err(e) = &copied {
+        println!("Couldn't copy the PDN: {}", e);
+    }
+    copied.is_ok()
+}
+
+#[cfg(not(feature = "clipboard"))]
+fn copy_pdn(_pdn: &str) -> bool {
+    false
+}
+
+/// Copies the join code, so it can be pasted to the other player. Without a clipboard the code has
+/// to be typed from the window.
+#[cfg(feature = "clipboard")]
+fn copy_join_code(join_code: &str) {
+    let copied = arboard::Clipboard::new().and_then(|mut clipboard| clipboard.set_text(join_code));
+    if let Err(e) = copied {
+        println!("Couldn't copy the join code: {}", e);
+    }
+}
+
+#[cfg(not(feature = "clipboard"))]
+fn copy_join_code(_join_code: &str) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{interface::MAX_USERNAME_LEN, lock_global_state};
+
+    /// Play `mov` on `board`, like a move from the window or the other player.
+    fn play(board: &mut Board, mov: &Move) {
+        set_board_move(mov);
+        board.move_piece();
+    }
+
+    fn step(index: usize, end: usize) -> Move {
+        Move {
+            index,
+            end,
+            captured: None,
+            promoted: false,
+        }
+    }
+
+    #[test]
+    fn rejected_move_is_taken_back_under_the_hosts_move() {
+        let _state = lock_global_state();
+        // The client plays Black. Both thought it was their turn at move 0, and the host's move
+        // came before its rejection of ours
+        let mut board = Board::new_headless();
+        board.start_new_game(PieceColor::Black);
+        history::clear(board.to_fen(PieceColor::White));
+        let pending = PendingMove {
+            snapshot: board.pieces(),
+            move_number: 0,
+            remote_moves: vec![step(9, 13)],
+        };
+        play(&mut board, &step(22, 18));
+        play(&mut board, &step(9, 13));
+
+        assert_eq!(pending.roll_back(&mut board), 1);
+        let mut hosts = Board::new_headless();
+        hosts.start_new_game(PieceColor::Black);
+        play(&mut hosts, &step(9, 13));
+        assert_eq!(board.pieces(), hosts.pieces());
+        assert_eq!(history::move_count(), 1);
+    }
+
+    /// The host's board after White's first move, for a client playing Black.
+    fn hosts_board_after_a_move() -> HostBoard {
+        let mut hosts = Board::new_headless();
+        hosts.start_new_game(PieceColor::White);
+        play(&mut hosts, &step(22, 18));
+        HostBoard {
+            board: hosts.white_pieces(),
+            move_number: 1,
+            side_to_move: PieceColor::Black,
+            draw_offer: None,
+            pause: PauseState::default(),
+        }
+    }
+
+    #[test]
+    fn preview_leaves_the_live_board_untouched() {
+        let _state = lock_global_state();
+        let mut board = Board::new_headless();
+        board.start_new_game(PieceColor::Black);
+        board.select_square(9);
+        let (pieces, marked) = (board.pieces(), board.marked_squares());
+
+        let host = hosts_board_after_a_move();
+        assert!(!ResyncPreview::is_same_position(&host, &board));
+        let preview = ResyncPreview::new(host, board.player_color());
+        assert_ne!(preview.pieces(), pieces);
+        assert_eq!(board.pieces(), pieces);
+        assert_eq!(board.marked_squares(), marked);
+
+        // Declining drops the preview, and keeps our board
+        drop(preview);
+        assert_eq!(board.pieces(), pieces);
+        assert_eq!(board.selected(), Some(9));
+    }
+
+    #[test]
+    fn accepted_preview_replaces_the_live_board() {
+        let _state = lock_global_state();
+        let mut board = Board::new_headless();
+        board.start_new_game(PieceColor::Black);
+
+        let host = hosts_board_after_a_move();
+        let hosts_pieces = host.board.clone();
+        let preview = ResyncPreview::new(host, board.player_color());
+        let shown = preview.pieces().to_vec();
+        assert_eq!(preview.accept(&mut board), (1, PieceColor::Black));
+        // The board is what the preview showed, seen from our side
+        assert_eq!(board.pieces(), shown);
+        assert_eq!(board.white_pieces(), hosts_pieces);
+        assert_eq!(board.player_color(), PieceColor::Black);
+    }
+
+    #[test]
+    fn same_position_needs_no_preview() {
+        let mut board = Board::new_headless();
+        board.start_new_game(PieceColor::Black);
+        let host = HostBoard {
+            board: board.white_pieces(),
+            move_number: 0,
+            side_to_move: PieceColor::White,
+            draw_offer: None,
+            pause: PauseState::default(),
+        };
+        assert!(ResyncPreview::is_same_position(&host, &board));
+    }
+
+    #[test]
+    fn held_move_is_played_once_confirmed() {
+        let _state = lock_global_state();
+        let mut board = Board::new_headless();
+        board.start_new_game(PieceColor::White);
+        board.select_square(22);
+        let pieces = board.pieces();
+        let mov = board.find_move_to(18).unwrap();
+
+        let unconfirmed = UnconfirmedMove::hold(mov.clone(), &mut board);
+        // Only its squares are marked, and nothing has moved yet
+        assert_eq!(board.marked_squares(), [18, 22]);
+        assert_eq!(board.pieces(), pieces);
+
+        let confirmed = unconfirmed.confirm();
+        assert_eq!(confirmed, mov);
+        play(&mut board, &confirmed);
+        assert!(board.piece_is_empty(22));
+        assert!(board.piece_is_player(18));
+    }
+
+    #[test]
+    fn cancelled_move_selects_its_piece_again() {
+        let _state = lock_global_state();
+        let mut board = Board::new_headless();
+        board.start_new_game(PieceColor::White);
+        board.select_square(22);
+        let pieces = board.pieces();
+        let mov = board.find_move_to(18).unwrap();
+
+        UnconfirmedMove::hold(mov, &mut board).cancel(&mut board);
+        assert_eq!(board.pieces(), pieces);
+        assert_eq!(board.selected(), Some(22));
+        assert_eq!(board.marked_squares(), [18, 19]);
+        // The piece can be moved elsewhere instead
+        assert!(board.find_move_to(19).is_some());
+    }
+
+    #[test]
+    fn onboarding_goes_on_once_the_username_can_be_used() {
+        for username in ["", "   ", "Al\u{7}ice", &"x".repeat(MAX_USERNAME_LEN + 1)] {
+            assert!(
+                matches!(OnboardingNext::at(0, username), OnboardingNext::Stay),
+                "{:?} was taken",
+                username
+            );
+        }
+        assert!(matches!(
+            OnboardingNext::at(0, " Alice "),
+            OnboardingNext::CheckConnectivity
+        ));
+        assert!(matches!(
+            OnboardingNext::at(1, " Alice "),
+            OnboardingNext::ShowHostOrJoin
+        ));
+        let OnboardingNext::Finish(profile) = OnboardingNext::at(2, " Alice ") else {
+            panic!("The onboarding didn't finish");
+        };
+        assert_eq!(profile.username, "Alice");
+        assert!(!profile.anonymous);
+    }
+
+    #[test]
+    fn turn_is_taken_from_the_rejection() {
+        let turn = PendingMove::turn_after_rejection;
+        // The host's move was played here, so it's our turn
+        assert_eq!(turn(1, 1, PieceColor::Black), Some(true));
+        assert_eq!(turn(2, 2, PieceColor::Black), Some(false));
+        // The host's move hasn't come yet
+        assert_eq!(turn(1, 0, PieceColor::Black), Some(false));
+        // Too far apart to catch up on
+        assert_eq!(turn(4, 1, PieceColor::Black), None);
+        assert_eq!(turn(0, 1, PieceColor::Black), None);
+    }
+}
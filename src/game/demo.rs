@@ -0,0 +1,301 @@
+//! Demo scripts: Timed input for the game window, for demo recordings and manual QA. Started with
+//! `--script <file>`.
+//!
+//! A script has a command on each line. Empty lines and lines starting with `#` are skipped:
+//! ```text
+//! host
+//! wait-for-connect
+//! wait-for-turn
+//! click 20
+//! click 16
+//! sleep 2000
+//! ```
+//! * `host` - Host a game, like clicking Host Game.
+//! * `join <code>` - Join the game with the join code.
+//! * `wait-for-connect` - Wait until the game window is shown.
+//! * `wait-for-turn` - Wait until it's the player's turn.
+//! * `click <square>` - Click the square, counted from the top left as the player sees the board.
+//! * `sleep <ms>` - Wait this many milliseconds.
+//!
+//! The commands invoke the same window callbacks as the player's input, so the game handles them
+//! the same way. The window is drawn live, and every command but `sleep` waits `STEP_DELAY_MS`
+//! first, so a recording can be followed.
+
+use std::{sync::mpsc, thread, time::Duration};
+
+use anyhow::{anyhow, bail};
+use slint::Weak;
+
+use crate::net::interface;
+
+use super::{GameWindow, PieceColor, WindowType};
+
+/// The pause before each command, so the viewer can see what happens.
+const STEP_DELAY_MS: u64 = 600;
+/// How often a `wait-for-*` command checks if it's done.
+const POLL_MS: u64 = 100;
+
+/// A command of a demo script.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Host,
+    Join(String),
+    WaitForConnect,
+    WaitForTurn,
+    Click(usize),
+    Sleep(Duration),
+}
+
+/// Parse a demo script. An invalid command gives an error with its line number.
+pub fn parse(source: &str) -> anyhow::Result<Vec<Command>> {
+    source
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| parse_line(line).map_err(|e| anyhow!("line {}: {}", number, e)))
+        .collect()
+}
+
+fn parse_line(line: &str) -> anyhow::Result<Command> {
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let argument = words.next();
+    if let Some(extra) = words.next() {
+        bail!("unexpected {:?} after {}", extra, name);
+    }
+
+    match (name, argument) {
+        ("host", None) => Ok(Command::Host),
+        ("join", Some(join_code)) => Ok(Command::Join(join_code.to_owned())),
+        ("wait-for-connect", None) => Ok(Command::WaitForConnect),
+        ("wait-for-turn", None) => Ok(Command::WaitForTurn),
+        ("click", Some(square)) => {
+            let squares = interface::get_game_options().squares();
+            square
+                .parse()
+                .ok()
+                .filter(|square| *square < squares)
+                .map(Command::Click)
+                .ok_or_else(|| anyhow!("{:?} isn't a square from 0 to {}", square, squares - 1))
+        }
+        ("sleep", Some(millis)) => millis
+            .parse()
+            .map(|millis| Command::Sleep(Duration::from_millis(millis)))
+            .map_err(|_| anyhow!("{:?} isn't a number of milliseconds", millis)),
+        ("host" | "wait-for-connect" | "wait-for-turn", Some(_)) => {
+            bail!("{} takes no argument", name)
+        }
+        ("join" | "click" | "sleep", None) => bail!("{} needs an argument", name),
+        _ => bail!("unknown command {:?}", name),
+    }
+}
+
+/// Run the commands on the window, on another thread. Stops when the window is closed.
+pub fn run(commands: Vec<Command>, window: Weak<GameWindow>) {
+    thread::spawn(move || {
+        for command in commands {
+            if !matches!(command, Command::Sleep(_)) {
+                thread::sleep(Duration::from_millis(STEP_DELAY_MS));
+            }
+            println!("Demo script: {:?}", command);
+            if run_command(command, &window).is_none() {
+                return;
+            }
+        }
+        println!("Demo script done");
+    });
+}
+
+/// Run one command. Returns `None` if the window is closed.
+fn run_command(command: Command, window: &Weak<GameWindow>) -> Option<()> {
+    match command {
+        Command::Host => on_window(window, |window| window.invoke_host_game()),
+        Command::Join(join_code) => on_window(window, move |window| {
+            window.invoke_join_game();
+            window.set_lan_code(join_code.into());
+            window.invoke_join_prompt();
+        }),
+        Command::WaitForConnect => wait_until(|| {
+            on_window(window, |window| {
+                window.get_window_state() == WindowType::Game
+            })
+        }),
+        Command::WaitForTurn => wait_until(|| {
+            let move_number = interface::get_move_number();
+            let my_turn = interface::get_my_color() == Some(PieceColor::side_to_move(move_number));
+            // The window is checked too, so the script stops if it's closed
+            on_window(window, move |_| my_turn)
+        }),
+        Command::Click(square) => on_window(window, move |window| {
+            window.invoke_clicked(square as i32);
+        }),
+        Command::Sleep(duration) => {
+            thread::sleep(duration);
+            Some(())
+        }
+    }
+}
+
+/// Call `f` with the window on the event loop, and wait for the result. Returns `None` if the
+/// window is closed.
+fn on_window<T: Send + 'static>(
+    window: &Weak<GameWindow>,
+    f: impl FnOnce(&GameWindow) -> T + Send + 'static,
+) -> Option<T> {
+    let (sender, receiver) = mpsc::channel();
+    window
+        .upgrade_in_event_loop(move |window| {
+            let _ = sender.send(f(&window));
+        })
+        .ok()?;
+    receiver.recv().ok()
+}
+
+/// Poll `done` until it gives true. Returns `None` if it gives `None`.
+fn wait_until(mut done: impl FnMut() -> Option<bool>) -> Option<()> {
+    while !done()? {
+        thread::sleep(Duration::from_millis(POLL_MS));
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::{
+        game::{
+            board::{set_board_move, Board},
+            Move,
+        },
+        net::lock_global_state,
+    };
+
+    const HOST_DEMO: &str = include_str!("../../scripts/host_demo.txt");
+    const JOIN_DEMO: &str = include_str!("../../scripts/join_demo.txt");
+
+    /// What a player's script did, until it had to wait.
+    enum Step {
+        /// A move was made, as the player sees the board.
+        Moved(Move),
+        /// It's waiting for the player's turn.
+        Waiting,
+        Done,
+    }
+
+    /// A player running a script on a board of its own, without a window or a network. Connecting
+    /// is left to the test, and the sleeps are skipped.
+    struct HeadlessPlayer {
+        commands: VecDeque<Command>,
+        board: Board,
+    }
+
+    impl HeadlessPlayer {
+        fn new(script: &str, color: PieceColor) -> Self {
+            let mut board = Board::new_headless();
+            board.start_new_game(color);
+            Self {
+                commands: parse(script).unwrap().into(),
+                board,
+            }
+        }
+
+        /// Run the commands until a move is made, or the script waits for the turn while it isn't
+        /// `my_turn`. A click is a move if the selected piece can move to the square, and selects
+        /// the square otherwise, like in the window.
+        fn step(&mut self, my_turn: bool) -> Step {
+            while let Some(command) = self.commands.pop_front() {
+                match command {
+                    Command::WaitForTurn if !my_turn => {
+                        self.commands.push_front(command);
+                        return Step::Waiting;
+                    }
+                    Command::Click(square) if my_turn => {
+                        let mov = self.board.find_move_to(square);
+                        if let Some(mov) = &mov {
+                            set_board_move(mov);
+                            self.board.move_piece();
+                        }
+                        self.board.select_square(square);
+                        if let Some(mov) = mov {
+                            return Step::Moved(mov);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Step::Done
+        }
+
+        /// Play the other player's move `mov`, as the other player sees the board.
+        fn take_move(&mut self, mov: &Move) {
+            set_board_move(&mov.reverse(self.board.options().geometry()));
+            self.board.move_piece();
+        }
+    }
+
+    /// Run the demo scripts of the host and the client against each other, with the host playing
+    /// `host_color`. Returns both players and the amount of moves made.
+    fn run_demo(host_color: PieceColor) -> ([HeadlessPlayer; 2], usize) {
+        let mut players = [
+            HeadlessPlayer::new(HOST_DEMO, host_color),
+            HeadlessPlayer::new(JOIN_DEMO, host_color.get_opposite()),
+        ];
+        let mut side_to_move = PieceColor::White;
+        let mut moves = 0;
+        loop {
+            let mover = usize::from(players[0].board.player_color() != side_to_move);
+            match players[mover].step(true) {
+                Step::Moved(mov) => {
+                    players[1 - mover].take_move(&mov);
+                    side_to_move = side_to_move.get_opposite();
+                    moves += 1;
+                }
+                Step::Waiting => unreachable!("The player to move waited for its turn"),
+                Step::Done => {
+                    // The other script must be done as well, or it waits for a move never made
+                    assert!(matches!(players[1 - mover].step(false), Step::Done));
+                    return (players, moves);
+                }
+            }
+            assert!(matches!(
+                players[1 - mover].step(false),
+                Step::Waiting | Step::Done
+            ));
+        }
+    }
+
+    #[test]
+    fn demo_scripts_play_a_game_whoever_gets_white() {
+        let _state = lock_global_state();
+        for host_color in [PieceColor::White, PieceColor::Black] {
+            let ([host, client], moves) = run_demo(host_color);
+            assert_eq!(moves, 6, "The host played {:?}", host_color);
+            assert_eq!(host.board.white_pieces(), client.board.white_pieces());
+            assert!(host.commands.is_empty() && client.commands.is_empty());
+        }
+    }
+
+    #[test]
+    fn invalid_commands_give_their_line() {
+        // The squares depend on the options
+        let _state = lock_global_state();
+        let e = parse("host\n\n# a comment\nclick 40").unwrap_err();
+        assert_eq!(e.to_string(), "line 4: \"40\" isn't a square from 0 to 31");
+        let e = parse("wait-for-turn now").unwrap_err();
+        assert_eq!(e.to_string(), "line 1: wait-for-turn takes no argument");
+        let e = parse("  sleep\n").unwrap_err();
+        assert_eq!(e.to_string(), "line 1: sleep needs an argument");
+        let e = parse("host\ndance").unwrap_err();
+        assert_eq!(e.to_string(), "line 2: unknown command \"dance\"");
+        assert_eq!(
+            parse("join c0a8000a1b58\nsleep 250").unwrap(),
+            [
+                Command::Join("c0a8000a1b58".to_owned()),
+                Command::Sleep(Duration::from_millis(250)),
+            ]
+        );
+    }
+}
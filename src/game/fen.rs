@@ -0,0 +1,112 @@
+use anyhow::anyhow;
+
+use super::{PieceColor, PieceData};
+
+/// Converts a board into a FEN string, e.g. `"W:W21,22,K30:B1,2"`.
+/// The string starts with the color to move, followed by the squares of the white and the black
+/// pieces. Kings are prefixed with a `K`.
+///
+/// Squares are numbered 1 to 32, from the top left of the board, with White at the bottom. This
+/// means square `n` is index `n - 1` on a board seen from White's side.
+///
+/// ## Params
+/// * `pieces` - The 32 squares of the board, seen from White's side.
+/// * `side_to_move` - The color whose turn it is.
+pub fn to_fen(pieces: &[PieceData], side_to_move: PieceColor) -> String {
+    let squares = |color: PieceColor| -> String {
+        pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| piece.is_active && piece.color == color)
+            .map(|(index, piece)| {
+                let king = if piece.is_king { "K" } else { "" };
+                format!("{}{}", king, index + 1)
+            })
+            .collect::<Vec<String>>()
+            .join(",")
+    };
+
+    format!(
+        "{}:W{}:B{}",
+        color_to_char(side_to_move),
+        squares(PieceColor::White),
+        squares(PieceColor::Black)
+    )
+}
+
+/// Parses a FEN string made by `to_fen()`.
+/// Returns the 32 squares of the board seen from White's side, and the color to move.
+pub fn from_fen(fen: &str) -> anyhow::Result<(Vec<PieceData>, PieceColor)> {
+    let mut parts = fen.trim().split(':');
+
+    let side_to_move = match parts.next() {
+        Some(side) if side.len() == 1 => char_to_color(side.chars().next().unwrap())?,
+        _ => {
+            return Err(anyhow!(
+                "FEN \"{}\" doesn't start with a color to move",
+                fen
+            ))
+        }
+    };
+
+    let mut pieces = vec![PieceData::const_default(); 32];
+
+    for part in parts {
+        let mut chars = part.chars();
+        let color = match chars.next() {
+            Some(c) => char_to_color(c)?,
+            None => return Err(anyhow!("FEN \"{}\" has an empty piece list", fen)),
+        };
+
+        let squares = chars.as_str();
+        if squares.is_empty() {
+            continue;
+        }
+
+        for square in squares.split(',') {
+            let (is_king, number) = match square.strip_prefix('K') {
+                Some(number) => (true, number),
+                None => (false, square),
+            };
+
+            let index = match number.parse::<usize>() {
+                Ok(number @ 1..=32) => number - 1,
+                _ => return Err(anyhow!("Invalid square \"{}\" in FEN \"{}\"", square, fen)),
+            };
+
+            if pieces[index].is_active {
+                return Err(anyhow!(
+                    "Square {} is used twice in FEN \"{}\"",
+                    index + 1,
+                    fen
+                ));
+            }
+
+            pieces[index] = PieceData {
+                is_active: true,
+                color,
+                is_king,
+            };
+        }
+    }
+
+    Ok((pieces, side_to_move))
+}
+
+const fn color_to_char(color: PieceColor) -> char {
+    match color {
+        PieceColor::White => 'W',
+        PieceColor::Black => 'B',
+    }
+}
+
+fn char_to_color(c: char) -> anyhow::Result<PieceColor> {
+    match c {
+        'W' => Ok(PieceColor::White),
+        'B' => Ok(PieceColor::Black),
+        _ => Err(anyhow!(
+            "Expected 'W' or 'B' for a color in FEN, got '{}'",
+            c
+        )),
+    }
+}
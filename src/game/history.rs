@@ -0,0 +1,249 @@
+//! A ring of the last board states of the game, for diagnosing rules bugs. It's written to the
+//! debug bundle, and can be stepped through in the history view (F12 in the game window).
+//!
+//! Every move of the game is kept as well, for the move list next to the board and for analyzing
+//! the game after it ended.
+
+use std::{collections::VecDeque, fmt::Display, sync::Mutex};
+
+use lazy_static::lazy_static;
+
+use crate::net::interface;
+
+use super::{
+    fen::from_fen, notation::move_notation, position_hash::position_hash, variations::MoveHistory,
+    Move,
+};
+
+/// The amount of board states kept.
+pub const HISTORY_LEN: usize = 32;
+
+/// Who made a move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// The local player.
+    Local,
+    /// The other player.
+    Remote,
+}
+
+/// A move, and the board after it.
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    /// The number of the move in the game, counted from 0.
+    pub move_number: u16,
+    /// The board after the move, as a FEN string.
+    pub fen: String,
+    /// The `position_hash()` of the board after the move, or 0 if the FEN couldn't be read.
+    pub hash: u64,
+    /// The move, with indices as seen from White's side.
+    pub mov: Move,
+    pub source: Source,
+}
+
+impl Display for HistoryEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {:?} {} {} {:016x}",
+            self.move_number,
+            self.source,
+            move_notation(&self.mov),
+            self.fen,
+            self.hash
+        )
+    }
+}
+
+#[derive(Default)]
+struct History {
+    entries: VecDeque<HistoryEntry>,
+    /// Every move in the game with the board after it, unlike `entries`.
+    moves: Vec<(Move, String)>,
+    /// The board the game started from, as a FEN string.
+    start_fen: String,
+    next_move_number: u16,
+}
+
+lazy_static! {
+    static ref HISTORY: Mutex<History> = Mutex::new(History::default());
+}
+
+/// Clear the history for a new game, which starts from `start_fen`.
+pub fn clear(start_fen: String) {
+    *HISTORY.lock().unwrap() = History {
+        start_fen,
+        ..Default::default()
+    };
+}
+
+/// Add a move to the history. It gets the number after the last move.
+///
+/// ## Params
+/// * `source` - Who made the move.
+/// * `mov` - The move, with indices as seen from White's side.
+/// * `fen` - The board after the move.
+pub fn push(source: Source, mov: Move, fen: String) {
+    let mut history = HISTORY.lock().unwrap();
+    let move_number = history.next_move_number;
+    history.next_move_number += 1;
+
+    let hash = from_fen(&fen).map_or(0, |(pieces, side_to_move)| {
+        position_hash(
+            &pieces,
+            side_to_move,
+            move_number + 1,
+            &interface::get_game_options(),
+        )
+    });
+
+    history.moves.push((mov.clone(), fen.clone()));
+    if history.entries.len() == HISTORY_LEN {
+        history.entries.pop_front();
+    }
+    let entry = HistoryEntry {
+        move_number,
+        fen,
+        hash,
+        mov,
+        source,
+    };
+    interface::log_to_session(format!("move: {}", entry));
+    history.entries.push_back(entry);
+}
+
+/// Remove the moves from `move_number` and on, e.g. when a move is taken back. The next move gets
+/// `move_number`.
+pub fn truncate(move_number: u16) {
+    interface::log_to_session(format!("take back from move {}", move_number));
+    let mut history = HISTORY.lock().unwrap();
+    history
+        .entries
+        .retain(|entry| entry.move_number < move_number);
+    history.moves.truncate(move_number as usize);
+    history.next_move_number = move_number;
+}
+
+/// The amount of moves in the game, which is the number the next move gets.
+pub fn move_count() -> u16 {
+    HISTORY.lock().unwrap().next_move_number
+}
+
+/// Get the saved moves, oldest first.
+pub fn history_ring() -> Vec<HistoryEntry> {
+    HISTORY.lock().unwrap().entries.iter().cloned().collect()
+}
+
+/// Get the notation of every move in the game, in order.
+pub fn move_list() -> Vec<String> {
+    let history = HISTORY.lock().unwrap();
+    history
+        .moves
+        .iter()
+        .map(|(mov, _)| move_notation(mov))
+        .collect()
+}
+
+/// Every move in the game as the main line of a tree, where the game can be analyzed without
+/// changing it.
+pub fn move_history() -> MoveHistory {
+    let history = HISTORY.lock().unwrap();
+    MoveHistory::from_game(history.start_fen.clone(), history.moves.iter().cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        game::{fen::to_fen, PieceColor, PieceData},
+        net::lock_global_state,
+    };
+
+    /// The `n`th move of a made-up game: Any move will do for the history.
+    fn nth_move(n: usize) -> Move {
+        Move {
+            index: n % 28,
+            end: n % 28 + 4,
+            promoted: false,
+            captured: None,
+        }
+    }
+
+    /// Play `count` moves, alternating sides, each leaving the board `fen`.
+    fn push_moves(count: usize, fen: &str) {
+        for n in 0..count {
+            let source = if n % 2 == 0 {
+                Source::Local
+            } else {
+                Source::Remote
+            };
+            push(source, nth_move(n), fen.to_owned());
+        }
+    }
+
+    #[test]
+    fn ring_keeps_the_last_moves() {
+        let _state = lock_global_state();
+        let fen = to_fen(&vec![PieceData::default(); 32], PieceColor::Black);
+        clear(fen.clone());
+        push_moves(HISTORY_LEN + 8, &fen);
+
+        let ring = history_ring();
+        assert_eq!(ring.len(), HISTORY_LEN);
+        for (entry, n) in ring.iter().zip(8..) {
+            assert_eq!(entry.move_number as usize, n);
+            assert_eq!(entry.mov, nth_move(n));
+            assert_eq!(entry.source == Source::Local, n % 2 == 0);
+        }
+        // The moves that fell out of the ring are still in the game
+        assert_eq!(move_count() as usize, HISTORY_LEN + 8);
+        assert_eq!(move_list().len(), HISTORY_LEN + 8);
+        assert_eq!(move_list()[0], move_notation(&nth_move(0)));
+    }
+
+    #[test]
+    fn entries_hash_their_board() {
+        let _state = lock_global_state();
+        let pieces = vec![PieceData::default(); 32];
+        let fen = to_fen(&pieces, PieceColor::Black);
+        clear(fen.clone());
+        push(Source::Local, nth_move(0), fen);
+        push(Source::Remote, nth_move(1), "not a board".to_owned());
+
+        let ring = history_ring();
+        let options = interface::get_game_options();
+        assert_eq!(
+            ring[0].hash,
+            position_hash(&pieces, PieceColor::Black, 1, &options)
+        );
+        assert_ne!(ring[0].hash, 0);
+        assert_eq!(ring[1].hash, 0);
+    }
+
+    #[test]
+    fn take_back_numbers_the_next_move_again() {
+        let _state = lock_global_state();
+        let fen = to_fen(&vec![PieceData::default(); 32], PieceColor::White);
+        clear(fen.clone());
+        push_moves(HISTORY_LEN + 4, &fen);
+
+        let from = HISTORY_LEN as u16;
+        truncate(from);
+        assert_eq!(move_count(), from);
+        assert_eq!(move_list().len(), HISTORY_LEN);
+        let ring = history_ring();
+        assert_eq!(ring.last().unwrap().move_number, from - 1);
+        assert_eq!(ring.first().unwrap().move_number, 4);
+
+        push(Source::Remote, nth_move(0), fen.clone());
+        let last = history_ring().pop().unwrap();
+        assert_eq!(last.move_number, from);
+        assert_eq!(last.source, Source::Remote);
+
+        // A new game starts over
+        clear(fen);
+        assert_eq!(move_count(), 0);
+        assert!(history_ring().is_empty());
+        assert!(move_list().is_empty());
+    }
+}
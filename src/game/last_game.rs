@@ -0,0 +1,292 @@
+//! Remembers the last game that was joined, so the player can reconnect to the same host after a
+//! disconnect or a restart, without getting the join code again.
+//!
+//! The game is stored as RON in `last_game.ron` in the working directory, and is removed when the
+//! game ends normally.
+
+use std::{fs, io::ErrorKind};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    migrations::{to_ron, Format},
+    PieceColor,
+};
+
+/// The file the last game is stored in.
+const LAST_GAME_PATH: &str = "last_game.ron";
+
+/// The stored last game. See `migrations`.
+const FORMAT: Format = Format {
+    path: LAST_GAME_PATH,
+    migrations: &[add_schema_version, add_anonymous],
+};
+
+/// The color we played, since `PieceColor` can't be serialized.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+enum Color {
+    White,
+    Black,
+}
+
+impl From<PieceColor> for Color {
+    fn from(color: PieceColor) -> Self {
+        match color {
+            PieceColor::White => Color::White,
+            PieceColor::Black => Color::Black,
+        }
+    }
+}
+
+impl From<Color> for PieceColor {
+    fn from(color: Color) -> Self {
+        match color {
+            Color::White => PieceColor::White,
+            Color::Black => PieceColor::Black,
+        }
+    }
+}
+
+/// The last game that was successfully joined.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LastGame {
+    schema_version: u32,
+    /// The join code of the host.
+    pub join_code: String,
+    /// The username of the host.
+    pub host_username: String,
+    /// Our username in the game.
+    pub username: String,
+    color: Color,
+    /// If we played anonymously, so a reconnect does too.
+    pub anonymous: bool,
+}
+
+impl LastGame {
+    pub fn new(
+        join_code: String,
+        host_username: String,
+        username: String,
+        color: PieceColor,
+        anonymous: bool,
+    ) -> Self {
+        Self {
+            schema_version: FORMAT.current_version(),
+            join_code,
+            host_username,
+            username,
+            color: color.into(),
+            anonymous,
+        }
+    }
+
+    /// The color we played.
+    pub fn color(&self) -> PieceColor {
+        self.color.into()
+    }
+
+    /// Load the last game, if one is stored. A file that can't be read is treated as no game.
+    pub fn load() -> Option<Self> {
+        Self::load_from(&FORMAT)
+    }
+
+    fn load_from(format: &Format) -> Option<Self> {
+        format.load().unwrap_or_else(|e| {
+            println!("Ignoring {}: {}", format.path, e);
+            None
+        })
+    }
+
+    /// Store the game, replacing the one stored before.
+    pub fn save(&self) -> anyhow::Result<()> {
+        FORMAT.save(self)
+    }
+
+    /// Remove the stored game, e.g. when it has ended.
+    pub fn clear() {
+        Self::clear_from(&FORMAT)
+    }
+
+    fn clear_from(format: &Format) {
+        if let Err(e) = fs::remove_file(format.path) {
+            if e.kind() != ErrorKind::NotFound {
+                println!("Couldn't remove {}: {}", format.path, e);
+            }
+        }
+    }
+}
+
+/// Version 0 to 1: Only `schema_version` is added.
+fn add_schema_version(source: &str) -> anyhow::Result<String> {
+    #[derive(Deserialize)]
+    struct LastGameV0 {
+        join_code: String,
+        host_username: String,
+        username: String,
+        color: Color,
+    }
+
+    let old: LastGameV0 = ron::from_str(source)?;
+    to_ron(&LastGameV1 {
+        schema_version: 1,
+        join_code: old.join_code,
+        host_username: old.host_username,
+        username: old.username,
+        color: old.color,
+    })
+}
+
+/// The last game before it remembered if we played anonymously.
+#[derive(Serialize, Deserialize)]
+struct LastGameV1 {
+    schema_version: u32,
+    join_code: String,
+    host_username: String,
+    username: String,
+    color: Color,
+}
+
+/// Version 1 to 2: Games stored before playing anonymously was remembered were played with our
+/// name.
+fn add_anonymous(source: &str) -> anyhow::Result<String> {
+    let old: LastGameV1 = ron::from_str(source)?;
+    to_ron(&LastGame {
+        schema_version: 2,
+        join_code: old.join_code,
+        host_username: old.host_username,
+        username: old.username,
+        color: old.color,
+        anonymous: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::game::migrations::tests::{load_fixture, temp_dir};
+
+    /// The stored last game, in a directory of its own for the test `name`.
+    fn format_in(name: &str) -> Format {
+        let path = temp_dir(name).join(LAST_GAME_PATH);
+        Format {
+            path: Box::leak(path.to_str().unwrap().into()),
+            migrations: FORMAT.migrations,
+        }
+    }
+
+    /// Remove the directory of `format_in()`.
+    fn remove(format: Format) {
+        fs::remove_dir_all(Path::new(format.path).parent().unwrap()).unwrap();
+    }
+
+    fn game() -> LastGame {
+        LastGame::new(
+            "c0a8000a1b58".to_owned(),
+            "Alice".to_owned(),
+            "Bob".to_owned(),
+            PieceColor::Black,
+            false,
+        )
+    }
+
+    /// Run every migration from `version` on `source`.
+    fn migrate(source: &str, version: usize) -> LastGame {
+        let mut source = source.to_owned();
+        for migration in &FORMAT.migrations[version..] {
+            source = migration(&source).unwrap();
+        }
+        ron::from_str(&source).unwrap()
+    }
+
+    #[test]
+    fn old_games_were_played_with_our_name() {
+        let fields = r#"join_code: "c0a8000a1b58", host_username: "Alice", username: "Bob""#;
+        let v0 = migrate(&format!("({}, color: Black)", fields), 0);
+        let v1 = migrate(&format!("(schema_version: 1, {}, color: Black)", fields), 1);
+        for game in [v0, v1] {
+            assert_eq!(game.schema_version, FORMAT.current_version());
+            assert_eq!(game.join_code, "c0a8000a1b58");
+            assert_eq!(game.host_username, "Alice");
+            assert_eq!(game.username, "Bob");
+            assert_eq!(game.color(), PieceColor::Black);
+            assert!(!game.anonymous);
+        }
+    }
+
+    #[test]
+    fn stored_games_of_every_version_are_migrated() {
+        for version in 0..FORMAT.current_version() {
+            let fixture = format!("last_game.v{}.ron", version);
+            let loaded = load_fixture::<LastGame>(&fixture, FORMAT.migrations);
+            let game = loaded.value;
+            assert_eq!(game.schema_version, FORMAT.current_version(), "{}", fixture);
+            assert_eq!(game.join_code, "c0a8000a1b58", "{}", fixture);
+            assert_eq!(game.host_username, "Alice", "{}", fixture);
+            assert_eq!(game.username, "Bob", "{}", fixture);
+            assert_eq!(game.color(), PieceColor::Black, "{}", fixture);
+            assert!(!game.anonymous, "{}", fixture);
+            assert_eq!(loaded.stored, to_ron(&game).unwrap());
+            assert_eq!(loaded.backup, Some(format!("stored.ron.v{}.bak", version)));
+        }
+    }
+
+    #[test]
+    fn joined_game_is_remembered_until_it_ends() {
+        let format = format_in("last_game");
+        assert!(LastGame::load_from(&format).is_none());
+        format.save(&game()).unwrap();
+
+        // Like after a restart
+        let stored = LastGame::load_from(&format).expect("The game wasn't stored");
+        assert_eq!(stored.join_code, "c0a8000a1b58");
+        assert_eq!(stored.host_username, "Alice");
+        assert_eq!(stored.username, "Bob");
+        assert_eq!(stored.color(), PieceColor::Black);
+        assert!(!stored.anonymous);
+
+        // Joining another game replaces it
+        let mut other = game();
+        other.join_code = "c0a8000b1b58".to_owned();
+        format.save(&other).unwrap();
+        assert_eq!(
+            LastGame::load_from(&format).unwrap().join_code,
+            "c0a8000b1b58"
+        );
+
+        LastGame::clear_from(&format);
+        assert!(LastGame::load_from(&format).is_none());
+        // There is nothing to remove after a game that wasn't joined
+        LastGame::clear_from(&format);
+        remove(format);
+    }
+
+    #[test]
+    fn unreadable_game_is_no_game() {
+        let format = format_in("last_game_unreadable");
+        for source in [
+            "not ron",
+            "(schema_version: 99, join_code: \"c0a8000a1b58\")",
+        ] {
+            fs::write(format.path, source).unwrap();
+            assert!(LastGame::load_from(&format).is_none(), "{}", source);
+        }
+        remove(format);
+    }
+
+    #[test]
+    fn anonymous_game_is_stored() {
+        let game = LastGame::new(
+            "c0a8000a1b58".to_owned(),
+            "Player-7f3a".to_owned(),
+            "Bob".to_owned(),
+            PieceColor::White,
+            true,
+        );
+        let stored: LastGame = ron::from_str(&to_ron(&game).unwrap()).unwrap();
+        assert!(stored.anonymous);
+        assert_eq!(stored.host_username, "Player-7f3a");
+        assert_eq!(stored.color(), PieceColor::White);
+    }
+}
@@ -0,0 +1,279 @@
+//! Matches of several games between the same players. A match is `GameOptions::match_length`
+//! games, and the player who wins the most of them wins it. Every game after the first starts
+//! like a rematch, with the colors swapped.
+//!
+//! Either player can resign the rest of the match with `GameAction::ResignMatch`, which the other
+//! player wins, and a declined rematch ends the match on its score. Both peers keep their own
+//! `Match` from what they see happen, so nothing else about it is sent.
+
+use crate::{
+    i18n::{tr, MessageKey},
+    net::interface::GameOverReason,
+};
+
+use super::history::Source;
+
+/// How a game of a match ended for the local player.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Won,
+    Lost,
+    Drawn,
+}
+
+/// What happened in a match, in the order it happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchEntry {
+    /// A game ended.
+    Game {
+        outcome: Outcome,
+        /// Why the game ended.
+        reason: GameOverReason,
+    },
+    /// A player resigned the rest of the match. The games that weren't played aren't counted as
+    /// lost.
+    Resigned { by: Source },
+    /// A player declined the next game, which ended the match on its score.
+    RematchDeclined { by: Source },
+}
+
+/// Where a match is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchState {
+    /// More games are to be played.
+    InProgress,
+    /// The match is over, won by `winner`, or tied if it's `None`.
+    Over { winner: Option<Source> },
+}
+
+/// A match between the local player and the other player.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Match {
+    length: u8,
+    history: Vec<MatchEntry>,
+    state: MatchState,
+}
+
+impl Match {
+    /// A new match of `length` games. A length of 0 is a single game.
+    pub fn new(length: u8) -> Self {
+        Self {
+            length: length.max(1),
+            history: vec![],
+            state: MatchState::InProgress,
+        }
+    }
+
+    /// The amount of games in the match.
+    pub fn length(&self) -> u8 {
+        self.length
+    }
+
+    /// If the match is more than a single game.
+    pub fn is_match(&self) -> bool {
+        self.length > 1
+    }
+
+    pub fn state(&self) -> MatchState {
+        self.state
+    }
+
+    pub fn is_over(&self) -> bool {
+        matches!(self.state, MatchState::Over { .. })
+    }
+
+    /// Everything that happened in the match, the oldest first.
+    pub fn history(&self) -> &[MatchEntry] {
+        &self.history
+    }
+
+    /// The games won by the local player and by the other player. A drawn game counts for
+    /// neither.
+    pub fn score(&self) -> (u8, u8) {
+        let count = |wanted: Outcome| {
+            let games = self.history.iter().filter(|entry| match entry {
+                MatchEntry::Game { outcome, .. } => *outcome == wanted,
+                _ => false,
+            });
+            games.count() as u8
+        };
+        (count(Outcome::Won), count(Outcome::Lost))
+    }
+
+    /// The amount of games that ended.
+    pub fn games_played(&self) -> u8 {
+        self.history
+            .iter()
+            .filter(|entry| matches!(entry, MatchEntry::Game { .. }))
+            .count() as u8
+    }
+
+    /// A game of the match ended. The match is over once a player has won more than half of its
+    /// games, or every game is played. Nothing is recorded after the match is over.
+    pub fn record_game(&mut self, outcome: Outcome, reason: GameOverReason) -> MatchState {
+        if self.is_over() {
+            return self.state;
+        }
+        self.history.push(MatchEntry::Game { outcome, reason });
+
+        let (won, lost) = self.score();
+        let majority = self.length / 2 + 1;
+        if won >= majority || lost >= majority || self.games_played() >= self.length {
+            self.state = MatchState::Over {
+                winner: self.leader(),
+            };
+        }
+        self.state
+    }
+
+    /// `by` resigned the rest of the match, which the other player wins.
+    pub fn resign(&mut self, by: Source) -> MatchState {
+        if self.is_over() {
+            return self.state;
+        }
+        self.history.push(MatchEntry::Resigned { by });
+        let winner = match by {
+            Source::Local => Source::Remote,
+            Source::Remote => Source::Local,
+        };
+        self.state = MatchState::Over {
+            winner: Some(winner),
+        };
+        self.state
+    }
+
+    /// `by` declined the next game of the match, which ends on its score.
+    pub fn decline_rematch(&mut self, by: Source) -> MatchState {
+        if self.is_over() {
+            return self.state;
+        }
+        self.history.push(MatchEntry::RematchDeclined { by });
+        self.state = MatchState::Over {
+            winner: self.leader(),
+        };
+        self.state
+    }
+
+    /// The player who won the most games, or `None` if they won as many.
+    fn leader(&self) -> Option<Source> {
+        let (won, lost) = self.score();
+        match won.cmp(&lost) {
+            std::cmp::Ordering::Greater => Some(Source::Local),
+            std::cmp::Ordering::Less => Some(Source::Remote),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+
+    /// The score of the match for the player, and how it ended once it's over. Empty for a single
+    /// game.
+    pub fn summary(&self) -> String {
+        if !self.is_match() {
+            return String::new();
+        }
+        let (won, lost) = self.score();
+        let resigned = self.history.iter().find_map(|entry| match entry {
+            MatchEntry::Resigned { by } => Some(*by),
+            _ => None,
+        });
+        let key = match (self.state, resigned) {
+            (MatchState::InProgress, _) => MessageKey::MatchScore,
+            (_, Some(Source::Local)) => MessageKey::MatchResignedByUs,
+            (_, Some(Source::Remote)) => MessageKey::MatchResignedByThem,
+            (MatchState::Over { winner }, None) => match winner {
+                Some(Source::Local) => MessageKey::MatchWon,
+                Some(Source::Remote) => MessageKey::MatchLost,
+                None => MessageKey::MatchTied,
+            },
+        };
+        tr(key, &[&self.length, &won, &lost])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resigning_ends_a_best_of_three() {
+        let mut best_of_three = Match::new(3);
+        assert_eq!(
+            best_of_three.record_game(Outcome::Won, GameOverReason::NoMoves),
+            MatchState::InProgress
+        );
+        assert_eq!(best_of_three.score(), (1, 0));
+
+        // The other player gives up the two games left, which don't count as lost for them
+        assert_eq!(
+            best_of_three.resign(Source::Remote),
+            MatchState::Over {
+                winner: Some(Source::Local)
+            }
+        );
+        assert_eq!(best_of_three.score(), (1, 0));
+        assert_eq!(
+            best_of_three.history(),
+            [
+                MatchEntry::Game {
+                    outcome: Outcome::Won,
+                    reason: GameOverReason::NoMoves
+                },
+                MatchEntry::Resigned { by: Source::Remote },
+            ]
+        );
+
+        // Nothing changes the match once it's over
+        best_of_three.record_game(Outcome::Lost, GameOverReason::Surrender);
+        best_of_three.decline_rematch(Source::Local);
+        assert_eq!(best_of_three.history().len(), 2);
+        assert!(best_of_three.is_over());
+    }
+
+    #[test]
+    fn declining_a_rematch_ends_on_the_score() {
+        let mut best_of_three = Match::new(3);
+        best_of_three.record_game(Outcome::Drawn, GameOverReason::DrawAccepted);
+        best_of_three.record_game(Outcome::Lost, GameOverReason::Surrender);
+        assert_eq!(best_of_three.state(), MatchState::InProgress);
+
+        assert_eq!(
+            best_of_three.decline_rematch(Source::Local),
+            MatchState::Over {
+                winner: Some(Source::Remote)
+            }
+        );
+        assert_eq!(best_of_three.score(), (0, 1));
+        assert_eq!(best_of_three.games_played(), 2);
+        assert_eq!(
+            best_of_three.history().last(),
+            Some(&MatchEntry::RematchDeclined { by: Source::Local })
+        );
+
+        // With an even score, nobody wins
+        let mut tied = Match::new(3);
+        tied.record_game(Outcome::Drawn, GameOverReason::DrawAccepted);
+        assert_eq!(
+            tied.decline_rematch(Source::Remote),
+            MatchState::Over { winner: None }
+        );
+    }
+
+    #[test]
+    fn a_majority_of_wins_ends_the_match() {
+        let mut best_of_three = Match::new(3);
+        best_of_three.record_game(Outcome::Lost, GameOverReason::NoMoves);
+        best_of_three.record_game(Outcome::Lost, GameOverReason::NoMoves);
+        assert_eq!(
+            best_of_three.state(),
+            MatchState::Over {
+                winner: Some(Source::Remote)
+            }
+        );
+
+        let mut single = Match::new(0);
+        assert!(!single.is_match());
+        assert_eq!(
+            single.record_game(Outcome::Drawn, GameOverReason::DrawAccepted),
+            MatchState::Over { winner: None }
+        );
+    }
+}
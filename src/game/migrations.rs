@@ -0,0 +1,278 @@
+//! Brings files stored by older versions of the game up to date, so a format change never strands
+//! the player's data.
+//!
+//! Every stored file has a `schema_version`. A file from before the version was added counts as
+//! version 0. When a file with an older version is loaded, it's copied to a backup, the migrations
+//! from its version are run on it, and it's stored again with the current version. A file with a
+//! newer version than this game knows is left alone.
+
+use std::{fs, io::ErrorKind};
+
+use anyhow::{anyhow, bail};
+use ron::{Map, Value};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// The field with the version of a stored file.
+const VERSION_FIELD: &str = "schema_version";
+
+/// Turns the RON of one version into the RON of the next. Each migration parses the file with
+/// its own copy of the old struct, since `ron::Value` loses the names of enum variants.
+pub type Migration = fn(&str) -> anyhow::Result<String>;
+
+/// A format that is stored on disk.
+pub struct Format {
+    /// The file it's stored in.
+    pub path: &'static str,
+    /// The migrations in order. The migration at index `n` turns version `n` into `n + 1`, so the
+    /// current version is the amount of migrations.
+    pub migrations: &'static [Migration],
+}
+
+impl Format {
+    /// The version of the format written by this version of the game.
+    pub const fn current_version(&self) -> u32 {
+        self.migrations.len() as u32
+    }
+
+    /// Load the file, migrating it if it's from an older version. Returns `None` if there is no
+    /// file.
+    pub fn load<T: Serialize + DeserializeOwned>(&self) -> anyhow::Result<Option<T>> {
+        let source = match fs::read_to_string(self.path) {
+            Ok(source) => source,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let version = version_of(&source)?;
+        let current = self.current_version();
+        if version > current {
+            bail!(
+                "{} was created by a newer version of the game (schema version {}, this version \
+                 reads up to {})",
+                self.path,
+                version,
+                current
+            );
+        }
+        if version == current {
+            return Ok(Some(ron::from_str(&source)?));
+        }
+
+        let backup = format!("{}.v{}.bak", self.path, version);
+        fs::write(&backup, &source)?;
+        let mut migrated = source;
+        for (from, migration) in self.migrations.iter().enumerate().skip(version as usize) {
+            migrated = migration(&migrated)
+                .map_err(|e| anyhow!("migrating {} from version {}: {}", self.path, from, e))?;
+        }
+
+        let migrated: T = ron::from_str(&migrated)?;
+        self.save(&migrated)?;
+        println!(
+            "Migrated {} from schema version {} to {}, the old file is in {}",
+            self.path, version, current, backup
+        );
+        Ok(Some(migrated))
+    }
+
+    /// Store `value` in the file. It must have the current version in its `schema_version`.
+    pub fn save<T: Serialize>(&self, value: &T) -> anyhow::Result<()> {
+        fs::write(self.path, to_ron(value)?)?;
+        Ok(())
+    }
+}
+
+/// `value` as RON, the way the stored files are written.
+pub fn to_ron<T: Serialize>(value: &T) -> anyhow::Result<String> {
+    Ok(ron::ser::to_string_pretty(
+        value,
+        ron::ser::PrettyConfig::default(),
+    )?)
+}
+
+/// The version of a stored file, which is 0 if it has none.
+fn version_of(source: &str) -> anyhow::Result<u32> {
+    let Value::Map(fields) = ron::from_str(source)? else {
+        bail!("expected a struct");
+    };
+    match field(&fields, VERSION_FIELD) {
+        None => Ok(0),
+        Some(Value::Number(number)) => number
+            .as_i64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| anyhow!("invalid {}: {:?}", VERSION_FIELD, number)),
+        Some(other) => bail!("invalid {}: {:?}", VERSION_FIELD, other),
+    }
+}
+
+fn field<'a>(fields: &'a Map, name: &str) -> Option<&'a Value> {
+    fields
+        .iter()
+        .find(|(key, _)| matches!(key, Value::String(key) if key == name))
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{
+        env,
+        path::{Path, PathBuf},
+        process,
+    };
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    /// A stored file that was loaded by `Format::load()`.
+    pub struct Loaded<T> {
+        pub value: T,
+        /// The file after it was loaded, which is the old file migrated to the current version.
+        pub stored: String,
+        /// The name of the backup of the old file, if there is one. It's checked to hold the old
+        /// file.
+        pub backup: Option<String>,
+    }
+
+    /// An empty directory for the test `name`, which isn't used by other tests.
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("the_checker_mater-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Store `source` in a directory of its own as the file of a format with `migrations`, and
+    /// load it.
+    fn load_source<T: Serialize + DeserializeOwned>(
+        name: &str,
+        source: &str,
+        migrations: &'static [Migration],
+    ) -> anyhow::Result<Option<Loaded<T>>> {
+        let dir = temp_dir(name);
+        let path = dir.join("stored.ron");
+        let format = Format {
+            path: Box::leak(path.to_str().unwrap().into()),
+            migrations,
+        };
+        fs::write(&path, source).unwrap();
+        let loaded = format.load().map(|value| {
+            value.map(|value| {
+                let backups: Vec<_> = fs::read_dir(&dir)
+                    .unwrap()
+                    .map(|entry| entry.unwrap().path())
+                    .filter(|file| *file != path)
+                    .collect();
+                assert!(backups.len() <= 1, "More than one backup: {:?}", backups);
+                let backup = backups.first().map(|backup| {
+                    assert_eq!(fs::read_to_string(backup).unwrap(), source);
+                    backup.file_name().unwrap().to_str().unwrap().to_owned()
+                });
+                Loaded {
+                    value,
+                    stored: fs::read_to_string(&path).unwrap(),
+                    backup,
+                }
+            })
+        });
+        if loaded.is_err() {
+            assert_eq!(fs::read_to_string(&path).unwrap(), source);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+        loaded
+    }
+
+    /// Load the fixture `file` in `fixtures/`, which was stored by an older version of the game, as
+    /// the file of a format with `migrations`.
+    pub fn load_fixture<T: Serialize + DeserializeOwned>(
+        file: &str,
+        migrations: &'static [Migration],
+    ) -> Loaded<T> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(file);
+        let source = fs::read_to_string(path).unwrap();
+        load_source(file, &source, migrations)
+            .unwrap()
+            .expect("The fixture wasn't stored")
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Named {
+        schema_version: u32,
+        name: String,
+    }
+
+    fn add_version(source: &str) -> anyhow::Result<String> {
+        #[derive(Deserialize)]
+        struct NamedV0 {
+            name: String,
+        }
+
+        let old: NamedV0 = ron::from_str(source)?;
+        to_ron(&Named {
+            schema_version: 1,
+            name: old.name,
+        })
+    }
+
+    fn fail(_: &str) -> anyhow::Result<String> {
+        bail!("no way")
+    }
+
+    #[test]
+    fn old_file_is_migrated_and_backed_up() {
+        let loaded: Loaded<Named> = load_source("migrated", r#"(name: "Bob")"#, &[add_version])
+            .unwrap()
+            .unwrap();
+        let migrated = Named {
+            schema_version: 1,
+            name: "Bob".to_owned(),
+        };
+        assert_eq!(loaded.value, migrated);
+        assert_eq!(loaded.stored, to_ron(&migrated).unwrap());
+        assert_eq!(loaded.backup.as_deref(), Some("stored.ron.v0.bak"));
+    }
+
+    #[test]
+    fn current_file_is_left_alone() {
+        let source = r#"(schema_version: 1, name: "Bob")"#;
+        let loaded: Loaded<Named> = load_source("current", source, &[add_version])
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.value.name, "Bob");
+        assert_eq!(loaded.stored, source);
+        assert_eq!(loaded.backup, None);
+    }
+
+    #[test]
+    fn newer_file_is_refused() {
+        let source = r#"(schema_version: 2, name: "Bob", age: 7)"#;
+        let e = load_source::<Named>("newer", source, &[add_version])
+            .err()
+            .expect("A newer file was loaded");
+        assert!(
+            e.to_string()
+                .contains("created by a newer version of the game"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn failed_migration_leaves_the_file() {
+        let e = load_source::<Named>("failed", r#"(name: "Bob")"#, &[fail])
+            .err()
+            .expect("A migration that failed was loaded");
+        assert!(e.to_string().contains("from version 0: no way"), "{}", e);
+    }
+
+    #[test]
+    fn missing_file_is_none() {
+        let format = Format {
+            path: "/nonexistent/the_checker_mater/stored.ron",
+            migrations: &[add_version],
+        };
+        assert!(format.load::<Named>().unwrap().is_none());
+    }
+}
@@ -1,29 +1,46 @@
-slint::include_modules!();
+#[cfg(feature = "gui")]
+mod ui {
+    // The generated code stubs out embedded Rust components with `todo!()`
+    #![allow(clippy::todo)]
+    slint::include_modules!();
+}
+// `PieceColor` and `PieceData` from `piece` shadow the generated ones
+#[cfg(feature = "gui")]
+pub use ui::*;
 
+#[cfg(feature = "gui")]
 mod board;
+#[cfg(feature = "gui")]
 pub mod data;
-
-impl PieceColor {
-    /// Get the opposite color
-    const fn get_opposite(&self) -> Self {
-        match self {
-            Self::White => Self::Black,
-            Self::Black => Self::White,
-        }
-    }
-}
-
-impl PieceData {
-    const fn const_default() -> Self {
-        PieceData {
-            is_king: false,
-            is_active: false,
-            color: PieceColor::White,
-        }
-    }
-}
-
-#[derive(Clone, Debug)]
+#[cfg(feature = "gui")]
+pub mod demo;
+pub mod fen;
+pub mod history;
+#[cfg(feature = "gui")]
+mod last_game;
+pub mod match_state;
+#[cfg(feature = "gui")]
+mod migrations;
+pub mod notation;
+pub mod options;
+mod piece;
+#[cfg(feature = "gui")]
+pub mod piece_set;
+pub mod position_hash;
+#[cfg(feature = "gui")]
+mod profile;
+#[cfg(feature = "gui")]
+pub mod replay;
+pub mod square;
+#[cfg(feature = "state-server")]
+pub mod state_server;
+#[cfg(feature = "gui")]
+mod tutorial;
+pub mod variations;
+
+pub use piece::{PieceColor, PieceData};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Move {
     pub index: usize,
     pub end: usize,
@@ -32,17 +49,78 @@ pub struct Move {
 }
 
 impl Move {
-    fn reverse(&self) -> Self {
-        let captured = self.captured.as_ref().map(|captured| {
-            let mut captured = captured.clone();
-            captured.iter_mut().for_each(|piece| *piece = 31 - *piece);
+    /// Check that the move fits on a board with `squares` playable squares: Every index is on the
+    /// board, the piece moves, and no piece is captured twice. Moves from the other peer must be
+    /// checked before they are played, since the indices are used to index the board.
+    pub fn check_squares(&self, squares: usize) -> anyhow::Result<()> {
+        let captured = self.captured.as_deref().unwrap_or_default();
+        if let Some(index) = [self.index, self.end]
+            .iter()
+            .chain(captured)
+            .find(|index| **index >= squares)
+        {
+            return Err(anyhow::anyhow!(
+                "Square {} is outside of the {} squares of the board",
+                index,
+                squares
+            ));
+        }
+        if self.index == self.end {
+            return Err(anyhow::anyhow!(
+                "The move starts and ends on {}",
+                self.index
+            ));
+        }
+        if let Some((i, piece)) = captured
+            .iter()
+            .enumerate()
+            .find(|(i, piece)| captured[..*i].contains(piece))
+        {
+            return Err(anyhow::anyhow!(
+                "Square {} is captured twice, the second time as capture {}",
+                piece,
+                i + 1
+            ));
+        }
+        Ok(())
+    }
 
+    /// Check that the move is only promoted if it ends on a square where the moved piece is
+    /// crowned. See `GameOptions::is_promotion_square()`.
+    ///
+    /// ## Params
+    /// * `options` - The rules the move is played with.
+    /// * `color` - The color of the moved piece.
+    /// * `bottom` - The color at the bottom of the board the move is seen on.
+    pub fn check_promotion(
+        &self,
+        options: &options::GameOptions,
+        color: PieceColor,
+        bottom: PieceColor,
+    ) -> anyhow::Result<()> {
+        if self.promoted && !options.is_promotion_square(self.end, color, bottom) {
+            return Err(anyhow::anyhow!(
+                "The move is promoted on {}, which isn't on the last row of {:?}",
+                self.end,
+                color
+            ));
+        }
+        Ok(())
+    }
+
+    /// The move seen from the other side of the board. Each player has their own pieces at the
+    /// bottom, so a move is reversed when it goes between the players.
+    pub fn reverse(&self, geometry: square::BoardGeometry) -> Self {
+        let captured = self.captured.as_ref().map(|captured| {
             captured
+                .iter()
+                .map(|piece| geometry.mirror(*piece))
+                .collect()
         });
 
         Self {
-            index: 31 - self.index,
-            end: 31 - self.end,
+            index: geometry.mirror(self.index),
+            end: geometry.mirror(self.end),
             promoted: self.promoted,
             captured,
         }
@@ -50,15 +128,30 @@ impl Move {
 }
 
 /// An enum which holds the possible actions a user can make in the game.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum GameAction {
     /// Move a piece, by its current position, and its target position.
     /// It is not guarenteed that this move is valid yet, so it should be validated before use.
     MovePiece(Move),
-    /// Indicated that the player want's to suggest a stalemate
-    Stalemate,
+    /// Offer the other player a draw. It's answered with a `DrawResponse`, and the game goes on
+    /// until then. A draw can only be offered by the player to move.
+    OfferDraw,
+    /// The answer to an `OfferDraw`. An accepted draw ends the game, and a declined one gives the
+    /// move back to the player who offered it.
+    DrawResponse(bool),
     /// Indicates that the player want's to end the game by surrender
     Surrender,
+    /// Give up the rest of the match, which the other player wins. The games that weren't played
+    /// aren't counted as lost. See `match_state`.
+    ResignMatch,
+    /// Ask the other player for a pause. It's answered with a `PauseResponse`, and either player
+    /// can ask at any time during the game. See `interface::PauseState`.
+    PauseRequest,
+    /// Ask the other player to go on with a paused game. It's answered with a `PauseResponse`.
+    ResumeRequest,
+    /// The answer to a `PauseRequest` or a `ResumeRequest`. An accepted one pauses the game, or
+    /// makes it go on.
+    PauseResponse(bool),
 }
 
 impl GameAction {
@@ -81,14 +174,16 @@ impl GameAction {
     }
 }
 
+#[cfg(feature = "gui")]
 #[derive(Clone, Copy, Debug)]
 enum Direction {
-    UpLeft = -5,
-    UpRight = -4,
-    DownLeft = 3,
-    DownRight = 4,
+    UpLeft,
+    UpRight,
+    DownLeft,
+    DownRight,
 }
 
+#[cfg(feature = "gui")]
 impl Direction {
     /// Returns an array to iterate over all enum values
     const fn values() -> &'static [Direction; 4] {
@@ -96,23 +191,22 @@ impl Direction {
         &[UpRight, UpLeft, DownLeft, DownRight]
     }
 
-    /// Get's the value used to traverse the array of tiles in
-    /// the Board struct, based off the index of the piece
-    const fn get_value(&self, index: usize) -> i32 {
-        let row_type: i32 = ((index % 8) / 4) as i32;
-        *self as i32 + row_type
-    }
-
-    /// Returns wether the direction is left
-    const fn is_left(&self) -> bool {
+    /// The rows and columns a step in the direction moves
+    const fn offset(&self) -> (i32, i32) {
         use Direction::*;
-        matches!(self, UpLeft | DownLeft)
+        match self {
+            UpLeft => (-1, -1),
+            UpRight => (-1, 1),
+            DownLeft => (1, -1),
+            DownRight => (1, 1),
+        }
     }
 
-    /// Returns wether the direction is right
-    const fn is_right(&self) -> bool {
-        use Direction::*;
-        matches!(self, UpRight | DownRight)
+    /// Get's the square a step in the direction from `index` lands on,
+    /// or `None` if it's off the board
+    const fn neighbor(&self, index: usize, geometry: square::BoardGeometry) -> Option<usize> {
+        let (d_row, d_col) = self.offset();
+        geometry.neighbor(index, d_row, d_col)
     }
 
     /// Returns wether the direction is down
@@ -127,3 +221,31 @@ impl Direction {
         matches!(self, UpRight | UpLeft)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reversed_move_is_seen_from_the_other_side() {
+        let geometry = square::BoardGeometry::CHECKERS;
+        let mov = Move {
+            index: 22,
+            end: 13,
+            promoted: false,
+            captured: Some(vec![17]),
+        };
+        let reversed = mov.reverse(geometry);
+        assert_eq!(
+            reversed,
+            Move {
+                index: 9,
+                end: 18,
+                promoted: false,
+                captured: Some(vec![14]),
+            }
+        );
+        // Turning it twice gives the move back, so a remote move must be turned exactly once
+        assert_eq!(reversed.reverse(geometry), mov);
+    }
+}
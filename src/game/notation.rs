@@ -0,0 +1,105 @@
+//! Standard draughts notation for moves, e.g. `"11-15"` for a move, `"22x15x8"` for a capture
+//! landing on 15 and then 8, and a `K` suffix when the piece is promoted.
+//!
+//! Squares are numbered 1 to 32 like in `fen`, from the top left of the board with White at the
+//! bottom, so moves must be seen from White's side. See `Board::to_white_move()`.
+
+use super::{square::BoardGeometry, Move};
+
+/// The board the squares are numbered on.
+const GEOMETRY: BoardGeometry = BoardGeometry::CHECKERS;
+
+/// The notation of `mov`.
+/// A capture lists every square the piece lands on. If the squares can't be worked out, which
+/// can happen for a flying king, only the start and end squares are listed.
+///
+/// ## Params
+/// * `mov` - The move, with indices as seen from White's side.
+pub fn move_notation(mov: &Move) -> String {
+    let squares = match &mov.captured {
+        Some(captured) => capture_path(mov.index, mov.end, captured)
+            .unwrap_or(vec![mov.index, mov.end])
+            .iter()
+            .map(|index| (index + 1).to_string())
+            .collect::<Vec<String>>()
+            .join("x"),
+        None => format!("{}-{}", mov.index + 1, mov.end + 1),
+    };
+
+    if mov.promoted {
+        format!("{}K", squares)
+    } else {
+        squares
+    }
+}
+
+/// Works out the squares a capture lands on, by jumping over an adjacent captured piece until
+/// they are all taken. Returns `None` if the path doesn't end on `end`.
+fn capture_path(start: usize, end: usize, captured: &[usize]) -> Option<Vec<usize>> {
+    let mut path = vec![start];
+    let mut left = captured.to_vec();
+    let mut position = start;
+
+    while !left.is_empty() {
+        let (row, col) = GEOMETRY.to_rowcol(position);
+        let (i, landing) = left.iter().enumerate().find_map(|(i, piece)| {
+            let (piece_row, piece_col) = GEOMETRY.to_rowcol(*piece);
+            let d_row = piece_row as i32 - row as i32;
+            let d_col = piece_col as i32 - col as i32;
+            if d_row.abs() != 1 || d_col.abs() != 1 {
+                return None;
+            }
+            GEOMETRY
+                .neighbor(*piece, d_row, d_col)
+                .map(|landing| (i, landing))
+        })?;
+
+        left.remove(i);
+        position = landing;
+        path.push(position);
+    }
+
+    (position == end).then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notation(index: usize, end: usize, captured: Option<Vec<usize>>, promoted: bool) -> String {
+        move_notation(&Move {
+            index,
+            end,
+            captured,
+            promoted,
+        })
+    }
+
+    #[test]
+    fn simple_move() {
+        assert_eq!(notation(8, 12, None, false), "9-13");
+        assert_eq!(notation(23, 19, None, false), "24-20");
+    }
+
+    #[test]
+    fn captures_list_every_landing_square() {
+        assert_eq!(notation(8, 17, Some(vec![12]), false), "9x18");
+        assert_eq!(notation(8, 26, Some(vec![12, 21]), false), "9x18x27");
+        // The captured pieces can come in any order
+        assert_eq!(notation(8, 26, Some(vec![21, 12]), false), "9x18x27");
+    }
+
+    #[test]
+    fn promotion_has_a_k_suffix() {
+        assert_eq!(notation(5, 1, None, true), "6-2K");
+        assert_eq!(notation(9, 2, Some(vec![5]), true), "10x3K");
+    }
+
+    #[test]
+    fn flying_king_capture_lists_start_and_end() {
+        // The king lands 4 rows away, so its path can't be worked out from the captured piece
+        assert_eq!(notation(0, 22, Some(vec![18]), false), "1x23");
+        // A path that doesn't end where the move does isn't listed either
+        assert_eq!(notation(8, 26, Some(vec![12]), false), "9x27");
+    }
+}
@@ -0,0 +1,446 @@
+//! The rules a game is played with. After connecting, the client sends the hash of its options in
+//! `P2pRequestPacket::OptionsAck`, and the game only starts if the host has the same hash. That
+//! way a client and a host that disagree about the rules find out before the first move, instead
+//! of when the moves start to look illegal.
+//!
+//! The rules window is generated from the same options with `describe()`, so it can't drift from
+//! what the board plays.
+
+use std::{fmt::Display, time::Duration};
+
+use sha2::{Digest, Sha256};
+
+use crate::i18n::{tr, MessageKey};
+
+use super::{square::BoardGeometry, PieceColor};
+
+/// How long a player who lost the connection has to come back by default, before they forfeit.
+pub const DEFAULT_GRACE_PERIOD_MS: u64 = 30_000;
+
+/// What happens to the game when the other player loses the connection and doesn't come back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AbandonmentPolicy {
+    /// The game waits for the other player for as long as it takes.
+    PauseForever,
+    /// The other player forfeits, if they haven't reconnected within the time.
+    ForfeitAfter(Duration),
+}
+
+impl AbandonmentPolicy {
+    /// The time the other player has to reconnect, or `None` if the game waits forever.
+    pub const fn grace_period(&self) -> Option<Duration> {
+        match self {
+            Self::PauseForever => None,
+            Self::ForfeitAfter(grace_period) => Some(*grace_period),
+        }
+    }
+
+    /// If the other player has been gone for too long, and has forfeited the game.
+    ///
+    /// ## Params
+    /// * `gone_for` - The time since the connection was lost, while it was really lost.
+    pub fn has_forfeited(&self, gone_for: Duration) -> bool {
+        self.grace_period()
+            .is_some_and(|grace_period| gone_for >= grace_period)
+    }
+}
+
+/// The rules of the game.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GameOptions {
+    /// The amount of squares along each side of the board.
+    pub board_size: u8,
+    /// If a piece that can capture must capture.
+    pub mandatory_capture: bool,
+    /// If the capture taking the most pieces must be chosen, when there are several.
+    pub longest_capture: bool,
+    /// If kings can move, and capture, more than one square at a time.
+    pub flying_kings: bool,
+    /// If men can capture backwards.
+    pub men_capture_backwards: bool,
+    /// If a man reaching the last row in the middle of a capture is crowned and stops there, like
+    /// in American checkers. Otherwise it carries on capturing as a man, and is only crowned if the
+    /// capture ends on the last row, like in international draughts.
+    pub promotion_ends_capture: bool,
+    /// What happens when the other player loses the connection.
+    pub abandonment_policy: AbandonmentPolicy,
+    /// The amount of games in a match, which the player who wins most of them wins. A match of 1
+    /// is a single game. See `match_state`.
+    pub match_length: u8,
+}
+
+impl GameOptions {
+    /// The rules the board plays.
+    pub const fn new() -> Self {
+        Self {
+            board_size: 8,
+            mandatory_capture: true,
+            longest_capture: false,
+            flying_kings: true,
+            men_capture_backwards: false,
+            promotion_ends_capture: false,
+            abandonment_policy: AbandonmentPolicy::ForfeitAfter(Duration::from_millis(
+                DEFAULT_GRACE_PERIOD_MS,
+            )),
+            match_length: 1,
+        }
+    }
+
+    /// The geometry of the board.
+    pub const fn geometry(&self) -> BoardGeometry {
+        BoardGeometry::new(self.board_size as usize)
+    }
+
+    /// The amount of playable squares on the board, which is half of them.
+    pub const fn squares(&self) -> usize {
+        self.geometry().squares()
+    }
+
+    /// If a man of `color` is crowned on `square`. Each side is crowned on the row farthest from
+    /// where its pieces start.
+    ///
+    /// ## Params
+    /// * `square` - The index of a playable square, counted from the top left.
+    /// * `color` - The color of the man.
+    /// * `bottom` - The color at the bottom of the board. Each player has their own pieces at the
+    ///   bottom, and a move from the other player is seen from their side.
+    pub fn is_promotion_square(
+        &self,
+        square: usize,
+        color: PieceColor,
+        bottom: PieceColor,
+    ) -> bool {
+        let geometry = self.geometry();
+        if color == bottom {
+            geometry.row(square) == 0
+        } else {
+            geometry.row(square) == geometry.size() - 1
+        }
+    }
+
+    /// Every option as a name and a value. The abandonment policy is the grace period in
+    /// milliseconds, or 0 when the game waits forever. The match length is left out for a single
+    /// game, so its options hash the same as before there were matches.
+    fn fields(&self) -> Vec<(&'static str, u64)> {
+        let grace_period_ms = self
+            .abandonment_policy
+            .grace_period()
+            .map_or(0, |grace_period| grace_period.as_millis() as u64);
+        let mut fields = vec![
+            ("board_size", self.board_size as u64),
+            ("mandatory_capture", self.mandatory_capture as u64),
+            ("longest_capture", self.longest_capture as u64),
+            ("flying_kings", self.flying_kings as u64),
+            ("men_capture_backwards", self.men_capture_backwards as u64),
+            ("promotion_ends_capture", self.promotion_ends_capture as u64),
+            ("abandonment_policy", grace_period_ms),
+        ];
+        if self.match_length != 1 {
+            fields.push(("match_length", self.match_length as u64));
+        }
+        fields
+    }
+
+    /// Serialize the options as `name=value;` pairs, sorted by name. Unlike Rusts `Hash`, this
+    /// doesn't change between builds, or when the fields are reordered.
+    pub fn to_stable_bytes(&self) -> Vec<u8> {
+        stable_bytes(self.fields())
+    }
+
+    /// Describe the rules for the player, one line per option. The options are destructured, so
+    /// a new option doesn't compile until it's described here.
+    pub fn describe(&self) -> Vec<RuleLine> {
+        let Self {
+            board_size,
+            mandatory_capture,
+            longest_capture,
+            flying_kings,
+            men_capture_backwards,
+            promotion_ends_capture,
+            abandonment_policy,
+            match_length,
+        } = *self;
+
+        let pick = |on: bool, yes: MessageKey, no: MessageKey| if on { yes } else { no };
+        vec![
+            RuleLine::new("board_size", tr(MessageKey::RuleBoardSize, &[&board_size])),
+            RuleLine::from_key(
+                "mandatory_capture",
+                pick(
+                    mandatory_capture,
+                    MessageKey::RuleCaptureMandatory,
+                    MessageKey::RuleCaptureOptional,
+                ),
+            ),
+            RuleLine::from_key(
+                "longest_capture",
+                pick(
+                    longest_capture,
+                    MessageKey::RuleLongestCapture,
+                    MessageKey::RuleAnyCapture,
+                ),
+            ),
+            RuleLine::from_key(
+                "flying_kings",
+                pick(
+                    flying_kings,
+                    MessageKey::RuleFlyingKings,
+                    MessageKey::RuleShortKings,
+                ),
+            ),
+            RuleLine::from_key(
+                "men_capture_backwards",
+                pick(
+                    men_capture_backwards,
+                    MessageKey::RuleMenCaptureBackwards,
+                    MessageKey::RuleMenCaptureForwards,
+                ),
+            ),
+            RuleLine::from_key(
+                "promotion_ends_capture",
+                pick(
+                    promotion_ends_capture,
+                    MessageKey::RulePromotionEndsCapture,
+                    MessageKey::RulePromotionContinuesCapture,
+                ),
+            ),
+            RuleLine::new(
+                "abandonment_policy",
+                match abandonment_policy.grace_period() {
+                    Some(grace_period) => {
+                        tr(MessageKey::RuleForfeitAfter, &[&grace_period.as_secs()])
+                    }
+                    None => tr(MessageKey::RulePauseForever, &[]),
+                },
+            ),
+            RuleLine::new(
+                "match_length",
+                match match_length {
+                    0 | 1 => tr(MessageKey::RuleSingleGame, &[]),
+                    games => tr(MessageKey::RuleMatchLength, &[&games]),
+                },
+            ),
+        ]
+    }
+
+    /// The hash sent in `OptionsAck`: The first 8 bytes of the SHA-256 hash of
+    /// `to_stable_bytes()`.
+    pub fn options_hash(&self) -> u64 {
+        let digest = Sha256::digest(self.to_stable_bytes());
+        u64::from_be_bytes(digest[..8].try_into().unwrap())
+    }
+}
+
+/// Serialize `fields` as `name=value;` pairs, sorted by name, so their order doesn't matter.
+fn stable_bytes(mut fields: Vec<(&'static str, u64)>) -> Vec<u8> {
+    fields.sort_by_key(|(name, _)| *name);
+
+    let mut bytes = vec![];
+    for (name, value) in fields {
+        bytes.extend_from_slice(format!("{}={};", name, value).as_bytes());
+    }
+    bytes
+}
+
+impl Default for GameOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A rule, as described to the player by `GameOptions::describe()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleLine {
+    /// The name of the option the rule comes from.
+    pub option: &'static str,
+    /// The rule in the current language.
+    pub text: String,
+}
+
+impl RuleLine {
+    fn new(option: &'static str, text: String) -> Self {
+        Self { option, text }
+    }
+
+    fn from_key(option: &'static str, key: MessageKey) -> Self {
+        Self::new(option, tr(key, &[]))
+    }
+}
+
+impl Display for RuleLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The options with one option changed each, away from `GameOptions::new()`.
+    fn changed_options() -> Vec<GameOptions> {
+        let new = GameOptions::new();
+        vec![
+            GameOptions {
+                board_size: 10,
+                ..new
+            },
+            GameOptions {
+                mandatory_capture: false,
+                ..new
+            },
+            GameOptions {
+                longest_capture: true,
+                ..new
+            },
+            GameOptions {
+                flying_kings: false,
+                ..new
+            },
+            GameOptions {
+                men_capture_backwards: true,
+                ..new
+            },
+            GameOptions {
+                promotion_ends_capture: true,
+                ..new
+            },
+            GameOptions {
+                abandonment_policy: AbandonmentPolicy::PauseForever,
+                ..new
+            },
+            GameOptions {
+                match_length: 3,
+                ..new
+            },
+        ]
+    }
+
+    #[test]
+    fn field_order_doesnt_change_the_hash() {
+        for options in [GameOptions::new()].into_iter().chain(changed_options()) {
+            let mut fields = options.fields();
+            let bytes = options.to_stable_bytes();
+            for _ in 0..fields.len() {
+                fields.rotate_left(1);
+                assert_eq!(stable_bytes(fields.clone()), bytes);
+            }
+            fields.reverse();
+            assert_eq!(stable_bytes(fields), bytes);
+        }
+    }
+
+    #[test]
+    fn hash_is_stable() {
+        // Written out, since a host and a client of different builds must agree on it
+        let options = GameOptions::new();
+        let expected = concat!(
+            "abandonment_policy=30000;board_size=8;flying_kings=1;longest_capture=0;",
+            "mandatory_capture=1;men_capture_backwards=0;promotion_ends_capture=0;"
+        );
+        assert_eq!(options.to_stable_bytes(), expected.as_bytes());
+        assert_eq!(options.options_hash(), 0x57e6_cfec_46e5_e683);
+    }
+
+    #[test]
+    fn every_option_changes_the_hash() {
+        let mut hashes = vec![GameOptions::new().options_hash()];
+        for options in changed_options() {
+            let hash = options.options_hash();
+            assert!(!hashes.contains(&hash), "{:?} hashes like others", options);
+            hashes.push(hash);
+        }
+    }
+
+    /// The text of each line of `describe()`.
+    fn texts(options: &GameOptions) -> Vec<String> {
+        options
+            .describe()
+            .into_iter()
+            .map(|line| line.text)
+            .collect()
+    }
+
+    #[test]
+    fn american_rules_are_described() {
+        let american = GameOptions {
+            flying_kings: false,
+            promotion_ends_capture: true,
+            ..GameOptions::new()
+        };
+        assert_eq!(
+            texts(&american),
+            [
+                "The board has 8 by 8 squares.",
+                "A piece that can capture must capture.",
+                "When there are several captures, any of them can be chosen.",
+                "Kings move one square at a time.",
+                "Men only move and capture forwards.",
+                "A man reaching the last row becomes a king, and the move ends.",
+                "A player who loses the connection forfeits, unless they are back within 30 \
+                 seconds.",
+                "Every game is played on its own.",
+            ]
+        );
+    }
+
+    #[test]
+    fn international_rules_are_described() {
+        let international = GameOptions {
+            board_size: 10,
+            longest_capture: true,
+            men_capture_backwards: true,
+            abandonment_policy: AbandonmentPolicy::PauseForever,
+            match_length: 3,
+            ..GameOptions::new()
+        };
+        assert_eq!(
+            texts(&international),
+            [
+                "The board has 10 by 10 squares.",
+                "A piece that can capture must capture.",
+                "When there are several captures, the one taking the most pieces must be \
+                 chosen.",
+                "Kings move and capture any distance along a diagonal.",
+                "Men can capture backwards.",
+                "A man passing the last row while capturing carries on as a man, and only becomes a \
+                 king if the move ends there.",
+                "A player who loses the connection can come back at any time, and the game waits.",
+                "The games are a match of 3. Whoever wins the most of them wins the match.",
+            ]
+        );
+    }
+
+    #[test]
+    fn every_option_is_described() {
+        // Every option is in the fields of a match
+        let options = GameOptions {
+            match_length: 3,
+            ..GameOptions::new()
+        };
+        let described: Vec<&str> = options.describe().iter().map(|line| line.option).collect();
+        let fields: Vec<&str> = options.fields().iter().map(|(name, _)| *name).collect();
+        assert_eq!(described, fields);
+
+        // Changing an option changes its own line, and no other
+        let new = GameOptions::new();
+        let (new_lines, new_fields) = (new.describe(), new.fields());
+        for options in changed_options() {
+            let changed_lines: Vec<&str> = options
+                .describe()
+                .iter()
+                .zip(&new_lines)
+                .filter(|(line, new_line)| line != new_line)
+                .map(|(line, _)| line.option)
+                .collect();
+            let changed_fields: Vec<&str> = options
+                .fields()
+                .into_iter()
+                .filter(|field| !new_fields.contains(field))
+                .map(|(name, _)| name)
+                .collect();
+            assert_eq!(changed_fields.len(), 1, "{:?}", options);
+            assert_eq!(changed_lines, changed_fields, "{:?}", options);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! The pieces on the board. These are plain Rust types, so the rules and the network can be used
+//! without the GUI. With the `gui` feature they convert to and from the types of the same name
+//! generated from `ui/piece.slint`.
+
+#[cfg(feature = "gui")]
+use super::ui;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PieceColor {
+    #[default]
+    White,
+    Black,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PieceData {
+    pub is_active: bool,
+    pub color: PieceColor,
+    pub is_king: bool,
+}
+
+impl PieceColor {
+    /// Get the opposite color
+    pub const fn get_opposite(&self) -> Self {
+        match self {
+            Self::White => Self::Black,
+            Self::Black => Self::White,
+        }
+    }
+
+    /// Get the color that makes move number `move_number`. Moves are counted from 0, and White
+    /// makes the first move.
+    pub const fn side_to_move(move_number: u16) -> Self {
+        if move_number.is_multiple_of(2) {
+            Self::White
+        } else {
+            Self::Black
+        }
+    }
+}
+
+impl PieceData {
+    #[cfg_attr(not(feature = "gui"), allow(dead_code))]
+    pub(super) const fn const_default() -> Self {
+        PieceData {
+            is_king: false,
+            is_active: false,
+            color: PieceColor::White,
+        }
+    }
+}
+
+#[cfg(feature = "gui")]
+impl From<ui::PieceColor> for PieceColor {
+    fn from(color: ui::PieceColor) -> Self {
+        match color {
+            ui::PieceColor::White => PieceColor::White,
+            ui::PieceColor::Black => PieceColor::Black,
+        }
+    }
+}
+
+#[cfg(feature = "gui")]
+impl From<PieceColor> for ui::PieceColor {
+    fn from(color: PieceColor) -> Self {
+        match color {
+            PieceColor::White => ui::PieceColor::White,
+            PieceColor::Black => ui::PieceColor::Black,
+        }
+    }
+}
+
+#[cfg(feature = "gui")]
+impl From<ui::PieceData> for PieceData {
+    fn from(piece: ui::PieceData) -> Self {
+        PieceData {
+            is_active: piece.is_active,
+            color: piece.color.into(),
+            is_king: piece.is_king,
+        }
+    }
+}
+
+#[cfg(feature = "gui")]
+impl From<PieceData> for ui::PieceData {
+    fn from(piece: PieceData) -> Self {
+        ui::PieceData {
+            is_active: piece.is_active,
+            color: piece.color.into(),
+            is_king: piece.is_king,
+        }
+    }
+}
@@ -0,0 +1,331 @@
+//! Piece sets: Images of the pieces and the squares, which replace the built-in drawing.
+//!
+//! A piece set is a directory inside `piece_sets/`, with a `manifest.ron` and the images:
+//!
+//! ```text
+//! piece_sets/wood/
+//!     manifest.ron     (name: "Wood", author: "...", tile_size: 64)
+//!     white_man.png
+//!     white_king.png
+//!     black_man.png
+//!     black_king.png
+//!     square.png
+//! ```
+//!
+//! Every image must be `tile_size` pixels wide and high. A piece set that can't be loaded falls
+//! back to the built-in one, so a broken piece set never stops the game.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+use slint::{ComponentHandle, Image, Rgba8Pixel, SharedPixelBuffer, Weak};
+
+use super::{GameWindow, PieceSetImages};
+
+/// The directory the piece sets are found in.
+const PIECE_SETS_DIR: &str = "piece_sets";
+/// The manifest inside each piece set.
+const MANIFEST_FILE: &str = "manifest.ron";
+/// The largest `tile_size` a manifest may have.
+const MAX_TILE_SIZE: u32 = 1024;
+/// The name of the pieces and squares drawn by the game itself.
+pub const BUILT_IN: &str = "Built-in";
+
+/// The images of a piece set, in the order `show_decoded()` sets them.
+const IMAGE_FILES: [&str; 5] = [
+    "white_man.png",
+    "white_king.png",
+    "black_man.png",
+    "black_king.png",
+    "square.png",
+];
+
+/// What a piece set is, from its `manifest.ron`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Manifest {
+    /// The name shown in the start window. It must be unique among the piece sets.
+    pub name: String,
+    pub author: String,
+    /// The width and height of every image, in pixels.
+    pub tile_size: u32,
+}
+
+impl Manifest {
+    /// Parse and validate a manifest.
+    pub fn parse(source: &str) -> anyhow::Result<Self> {
+        let manifest: Self = ron::from_str(source)?;
+        if manifest.name.trim().is_empty() {
+            bail!("the name is empty");
+        }
+        if manifest.name == BUILT_IN {
+            bail!("the name {:?} is taken by the built-in piece set", BUILT_IN);
+        }
+        if !(1..=MAX_TILE_SIZE).contains(&manifest.tile_size) {
+            bail!(
+                "the tile size {} isn't between 1 and {}",
+                manifest.tile_size,
+                MAX_TILE_SIZE
+            );
+        }
+        Ok(manifest)
+    }
+}
+
+/// A piece set found on disk.
+#[derive(Clone, Debug)]
+struct PieceSet {
+    manifest: Manifest,
+    dir: PathBuf,
+}
+
+impl PieceSet {
+    /// Read the piece set in `dir`. Every image must exist, but they aren't decoded yet.
+    fn open(dir: &Path) -> anyhow::Result<Self> {
+        let manifest = Manifest::parse(&fs::read_to_string(dir.join(MANIFEST_FILE))?)?;
+        if let Some(missing) = IMAGE_FILES.iter().find(|file| !dir.join(file).is_file()) {
+            bail!("{} is missing", missing);
+        }
+        Ok(Self {
+            manifest,
+            dir: dir.to_owned(),
+        })
+    }
+
+    /// Decode the images. The pixel buffers can be sent to the UI thread, unlike `Image`.
+    fn decode(&self) -> anyhow::Result<Vec<SharedPixelBuffer<Rgba8Pixel>>> {
+        let size = self.manifest.tile_size;
+        IMAGE_FILES
+            .iter()
+            .map(|file| {
+                let image = image::open(self.dir.join(file))
+                    .map_err(|e| anyhow!("{}: {}", file, e))?
+                    .into_rgba8();
+                if image.dimensions() != (size, size) {
+                    bail!(
+                        "{} is {}x{}, not {}x{}",
+                        file,
+                        image.width(),
+                        image.height(),
+                        size,
+                        size
+                    );
+                }
+                Ok(SharedPixelBuffer::clone_from_slice(
+                    image.as_raw(),
+                    size,
+                    size,
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Finds the piece sets, and shows the picked one in the window.
+#[derive(Clone, Debug, Default)]
+pub struct PieceSetManager {
+    piece_sets: Vec<PieceSet>,
+}
+
+impl PieceSetManager {
+    /// Find the piece sets in `piece_sets/`. Invalid piece sets are skipped.
+    pub fn scan() -> Self {
+        Self::scan_dir(Path::new(PIECE_SETS_DIR))
+    }
+
+    /// Find the piece sets in `dir`, which is `piece_sets/` outside of tests.
+    fn scan_dir(dir: &Path) -> Self {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return Self::default(),
+        };
+
+        let mut piece_sets: Vec<PieceSet> = vec![];
+        for dir in entries.flatten().map(|entry| entry.path()) {
+            if !dir.is_dir() {
+                continue;
+            }
+            match PieceSet::open(&dir) {
+                Ok(piece_set)
+                    if piece_sets
+                        .iter()
+                        .any(|other| other.manifest.name == piece_set.manifest.name) =>
+                {
+                    println!("Skipping piece set {:?}: Its name is already used", dir);
+                }
+                Ok(piece_set) => piece_sets.push(piece_set),
+                Err(e) => println!("Skipping piece set {:?}: {}", dir, e),
+            }
+        }
+        piece_sets.sort_by(|a, b| a.manifest.name.cmp(&b.manifest.name));
+        Self { piece_sets }
+    }
+
+    /// The names of the piece sets that can be picked, with the built-in one first.
+    pub fn names(&self) -> Vec<String> {
+        std::iter::once(BUILT_IN.to_owned())
+            .chain(self.piece_sets.iter().map(|set| set.manifest.name.clone()))
+            .collect()
+    }
+
+    fn find(&self, name: &str) -> Option<&PieceSet> {
+        self.piece_sets.iter().find(|set| set.manifest.name == name)
+    }
+
+    /// Show the piece set named `name` in `window`. The images are decoded on another thread, and
+    /// shown when they are ready. If the piece set can't be loaded, the built-in one is shown.
+    /// Only the images change, so this can be done in the middle of a game.
+    pub fn select(&self, name: &str, window: &GameWindow) {
+        let Some(piece_set) = self.find(name).cloned() else {
+            if name != BUILT_IN {
+                println!("No piece set named {:?}, using the built-in one", name);
+            }
+            show_built_in(window);
+            return;
+        };
+
+        let handle_weak = window.as_weak();
+        thread::spawn(move || {
+            let decoded = piece_set.decode();
+            slint::invoke_from_event_loop(move || show_decoded(&handle_weak, &piece_set, decoded))
+                .unwrap();
+        });
+    }
+}
+
+fn show_built_in(window: &GameWindow) {
+    window.global::<PieceSetImages>().set_loaded(false);
+}
+
+fn show_decoded(
+    handle_weak: &Weak<GameWindow>,
+    piece_set: &PieceSet,
+    decoded: anyhow::Result<Vec<SharedPixelBuffer<Rgba8Pixel>>>,
+) {
+    let Some(window) = handle_weak.upgrade() else {
+        return;
+    };
+    let images: Vec<Image> = match decoded {
+        Ok(buffers) => buffers.into_iter().map(Image::from_rgba8).collect(),
+        Err(e) => {
+            println!(
+                "Couldn't load piece set {:?}, using the built-in one: {}",
+                piece_set.manifest.name, e
+            );
+            show_built_in(&window);
+            return;
+        }
+    };
+
+    let global = window.global::<PieceSetImages>();
+    let [white_man, white_king, black_man, black_king, square]: [Image; 5] =
+        images.try_into().unwrap();
+    global.set_white_man(white_man);
+    global.set_white_king(white_king);
+    global.set_black_man(black_man);
+    global.set_black_king(black_king);
+    global.set_square(square);
+    global.set_loaded(true);
+    println!(
+        "Using piece set {:?} by {}",
+        piece_set.manifest.name, piece_set.manifest.author
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use super::*;
+    use crate::game::migrations::tests::temp_dir;
+
+    /// Write a piece set named `name` to `dir`, with every image `image_size` pixels wide and high,
+    /// and a manifest saying they are `tile_size`.
+    fn write_piece_set(dir: &Path, name: &str, tile_size: u32, image_size: u32) {
+        fs::create_dir_all(dir).unwrap();
+        let manifest = format!(
+            "(name: {:?}, author: \"Tester\", tile_size: {})",
+            name, tile_size
+        );
+        fs::write(dir.join(MANIFEST_FILE), manifest).unwrap();
+        for file in IMAGE_FILES {
+            RgbaImage::new(image_size, image_size)
+                .save(dir.join(file))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn manifest_is_parsed_and_checked() {
+        let manifest = Manifest::parse("(name: \"Wood\", author: \"Ann\", tile_size: 64)").unwrap();
+        assert_eq!(
+            manifest,
+            Manifest {
+                name: "Wood".to_owned(),
+                author: "Ann".to_owned(),
+                tile_size: 64,
+            }
+        );
+
+        for source in [
+            "(name: \"  \", author: \"Ann\", tile_size: 64)",
+            "(name: \"Built-in\", author: \"Ann\", tile_size: 64)",
+            "(name: \"Wood\", author: \"Ann\", tile_size: 0)",
+            "(name: \"Wood\", author: \"Ann\", tile_size: 1025)",
+            "(name: \"Wood\", author: \"Ann\")",
+            "(name: \"Wood\", author: \"Ann\", tile_size: -1)",
+            "not a manifest",
+        ] {
+            assert!(Manifest::parse(source).is_err(), "{:?} was taken", source);
+        }
+    }
+
+    #[test]
+    fn broken_piece_sets_are_skipped() {
+        let dir = temp_dir("piece_sets");
+        write_piece_set(&dir.join("wood"), "Wood", 2, 2);
+        write_piece_set(&dir.join("marble"), "Marble", 2, 2);
+        // The same name again, so only one of them is kept
+        write_piece_set(&dir.join("wood_copy"), "Wood", 2, 2);
+        write_piece_set(&dir.join("no_square"), "No square", 2, 2);
+        fs::remove_file(dir.join("no_square").join("square.png")).unwrap();
+        write_piece_set(&dir.join("bad_manifest"), "Bad", 0, 2);
+        fs::write(dir.join("stray.txt"), "not a piece set").unwrap();
+
+        let manager = PieceSetManager::scan_dir(&dir);
+        assert_eq!(manager.names(), [BUILT_IN, "Marble", "Wood"]);
+        assert!(manager.find("No square").is_none());
+        assert!(manager.find(BUILT_IN).is_none());
+
+        // Without the directory there is only the built-in one
+        let missing = PieceSetManager::scan_dir(&dir.join("missing"));
+        assert_eq!(missing.names(), [BUILT_IN]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn images_of_the_wrong_size_fall_back() {
+        let dir = temp_dir("piece_set_images");
+        write_piece_set(&dir, "Wood", 2, 2);
+        let piece_set = PieceSet::open(&dir).unwrap();
+        let buffers = piece_set.decode().unwrap();
+        assert_eq!(buffers.len(), IMAGE_FILES.len());
+        assert!(buffers.iter().all(|buffer| buffer.width() == 2));
+
+        // The manifest says otherwise, so the set can't be shown
+        write_piece_set(&dir, "Wood", 3, 2);
+        let e = PieceSet::open(&dir).unwrap().decode().unwrap_err();
+        assert_eq!(e.to_string(), "white_man.png is 2x2, not 3x3");
+
+        // Nor can an image that isn't one
+        write_piece_set(&dir, "Wood", 2, 2);
+        fs::write(dir.join("square.png"), "not an image").unwrap();
+        let e = PieceSet::open(&dir).unwrap().decode().unwrap_err();
+        assert!(e.to_string().starts_with("square.png: "), "{}", e);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
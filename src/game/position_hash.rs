@@ -0,0 +1,212 @@
+//! The one hash of a game position. Anything comparing positions between the peers, or over time,
+//! must use `position_hash()`, so two hashes of the same position always match.
+//!
+//! The hash is 64 bit FNV-1a over these 43 bytes:
+//!
+//! | Bytes    | Content                                                            |
+//! |----------|--------------------------------------------------------------------|
+//! | `0..32`  | The squares seen from White's side, one byte each. See below.      |
+//! | `32`     | The side to move: `0` for White, `1` for Black.                    |
+//! | `33..35` | The number of moves made, as a big endian `u16`.                   |
+//! | `35..43` | `GameOptions::options_hash()` of the rules, as a big endian `u64`. |
+//!
+//! A square is `0` when empty. Otherwise bit 0 is set for White, bit 1 for Black and bit 2 for a
+//! king. This is the same as a square in a `Resync` packet.
+
+use super::{options::GameOptions, PieceColor, PieceData};
+
+/// The amount of bytes hashed.
+pub const HASHED_LEN: usize = 43;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+const SQUARE_WHITE: u8 = 0b001;
+const SQUARE_BLACK: u8 = 0b010;
+const SQUARE_KING: u8 = 0b100;
+
+/// Hash a position. See the module documentation for how.
+///
+/// ## Params
+/// * `pieces` - The 32 squares of the board, seen from White's side.
+/// * `side_to_move` - The color whose turn it is.
+/// * `move_number` - The number of moves made in the game.
+/// * `options` - The rules the game is played with.
+pub fn position_hash(
+    pieces: &[PieceData],
+    side_to_move: PieceColor,
+    move_number: u16,
+    options: &GameOptions,
+) -> u64 {
+    canonical_bytes(pieces, side_to_move, move_number, options)
+        .iter()
+        .fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+        })
+}
+
+/// The bytes `position_hash()` hashes. See the module documentation for the layout.
+pub fn canonical_bytes(
+    pieces: &[PieceData],
+    side_to_move: PieceColor,
+    move_number: u16,
+    options: &GameOptions,
+) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HASHED_LEN);
+    bytes.extend(pieces.iter().map(square_byte));
+    bytes.push(match side_to_move {
+        PieceColor::White => 0,
+        PieceColor::Black => 1,
+    });
+    bytes.extend_from_slice(&move_number.to_be_bytes());
+    bytes.extend_from_slice(&options.options_hash().to_be_bytes());
+    bytes
+}
+
+fn square_byte(piece: &PieceData) -> u8 {
+    if !piece.is_active {
+        return 0;
+    }
+    let color = match piece.color {
+        PieceColor::White => SQUARE_WHITE,
+        PieceColor::Black => SQUARE_BLACK,
+    };
+    if piece.is_king {
+        color | SQUARE_KING
+    } else {
+        color
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The board a game starts with, seen from White's side.
+    fn start() -> Vec<PieceData> {
+        (0..32)
+            .map(|i| PieceData {
+                color: if i < 12 {
+                    PieceColor::Black
+                } else {
+                    PieceColor::White
+                },
+                is_active: !(12..20).contains(&i),
+                is_king: false,
+            })
+            .collect()
+    }
+
+    /// `start()` with a white king on square 14 and a black king on square 17.
+    fn kings() -> Vec<PieceData> {
+        let mut pieces = start();
+        for (square, color) in [(14, PieceColor::White), (17, PieceColor::Black)] {
+            pieces[square] = PieceData {
+                color,
+                is_active: true,
+                is_king: true,
+            };
+        }
+        pieces
+    }
+
+    /// Every state a square can be in.
+    fn square_states() -> Vec<PieceData> {
+        let mut states = vec![PieceData::default()];
+        for color in [PieceColor::White, PieceColor::Black] {
+            for is_king in [false, true] {
+                states.push(PieceData {
+                    color,
+                    is_active: true,
+                    is_king,
+                });
+            }
+        }
+        states
+    }
+
+    #[test]
+    fn canonical_bytes_follow_the_layout() {
+        let options = GameOptions::new();
+        let bytes = canonical_bytes(&kings(), PieceColor::Black, 0x1234, &options);
+        assert_eq!(bytes.len(), HASHED_LEN);
+        assert_eq!(bytes[..12], [SQUARE_BLACK; 12]);
+        assert_eq!(
+            bytes[12..20],
+            [
+                0,
+                0,
+                SQUARE_WHITE | SQUARE_KING,
+                0,
+                0,
+                SQUARE_BLACK | SQUARE_KING,
+                0,
+                0
+            ]
+        );
+        assert_eq!(bytes[20..32], [SQUARE_WHITE; 12]);
+        assert_eq!(bytes[32..35], [1, 0x12, 0x34]);
+        assert_eq!(bytes[35..], options.options_hash().to_be_bytes());
+    }
+
+    #[test]
+    fn golden_hashes() {
+        let options = GameOptions::new();
+        let empty = vec![PieceData::default(); 32];
+        let cases = [
+            (start(), PieceColor::White, 0, 0xb2a6_1793_09e4_a91f),
+            (kings(), PieceColor::Black, 17, 0xa932_ce66_0719_7dae),
+            (empty, PieceColor::Black, u16::MAX, 0x2983_15dd_2dbd_62fe),
+        ];
+        for (pieces, side_to_move, move_number, expected) in cases {
+            let hash = position_hash(&pieces, side_to_move, move_number, &options);
+            assert_eq!(hash, expected, "{:#018x}", hash);
+        }
+    }
+
+    #[test]
+    fn every_square_change_changes_the_hash() {
+        let options = GameOptions::new();
+        for base in [start(), kings()] {
+            let mut hashes = vec![];
+            let hash = position_hash(&base, PieceColor::White, 4, &options);
+            for square in 0..base.len() {
+                for state in square_states() {
+                    let mut changed = base.clone();
+                    changed[square] = state;
+                    let changed_hash = position_hash(&changed, PieceColor::White, 4, &options);
+                    if square_byte(&changed[square]) == square_byte(&base[square]) {
+                        assert_eq!(changed_hash, hash);
+                    } else {
+                        assert_ne!(changed_hash, hash, "square {}", square);
+                        hashes.push(changed_hash);
+                    }
+                }
+            }
+            // No two of the changed boards collide either
+            let count = hashes.len();
+            hashes.sort_unstable();
+            hashes.dedup();
+            assert_eq!(hashes.len(), count);
+        }
+    }
+
+    #[test]
+    fn everything_else_changes_the_hash() {
+        let options = GameOptions::new();
+        let hash = position_hash(&start(), PieceColor::White, 4, &options);
+        assert_ne!(
+            position_hash(&start(), PieceColor::Black, 4, &options),
+            hash
+        );
+        assert_ne!(
+            position_hash(&start(), PieceColor::White, 5, &options),
+            hash
+        );
+        let flying = GameOptions {
+            flying_kings: !options.flying_kings,
+            ..options
+        };
+        assert_ne!(position_hash(&start(), PieceColor::White, 4, &flying), hash);
+    }
+}
@@ -0,0 +1,201 @@
+//! The player's profile, which is made the first time the game starts. Until it exists, the game
+//! starts with the onboarding instead of the start window.
+//!
+//! The profile is stored as RON in `profile.ron` in the working directory.
+
+use serde::{Deserialize, Serialize};
+
+use super::migrations::{to_ron, Format};
+use crate::i18n::{get_language, Language};
+
+/// The file the profile is stored in.
+const PROFILE_PATH: &str = "profile.ron";
+
+/// The stored profile. See `migrations`.
+const FORMAT: Format = Format {
+    path: PROFILE_PATH,
+    migrations: &[add_piece_set, add_language, add_anonymous],
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Profile {
+    schema_version: u32,
+    /// The username picked in the onboarding, filled in on the start window.
+    pub username: String,
+    /// The name of the picked piece set, or `None` for the built-in one. See `piece_set`.
+    pub piece_set: Option<String>,
+    /// The language of the messages, picked in the start window. See `i18n`.
+    pub language: Language,
+    /// If the player plays anonymously, picked in the start window. See
+    /// `interface::set_anonymous()`.
+    pub anonymous: bool,
+}
+
+impl Profile {
+    /// A profile with the built-in piece set and the current language.
+    pub fn new(username: String) -> Self {
+        Self {
+            schema_version: FORMAT.current_version(),
+            username,
+            piece_set: None,
+            language: get_language(),
+            anonymous: false,
+        }
+    }
+
+    /// Load the profile, if the onboarding is done. A file that can't be read is treated as no
+    /// profile, so the onboarding is shown again.
+    pub fn load() -> Option<Self> {
+        FORMAT.load().unwrap_or_else(|e| {
+            println!("Ignoring {}: {}", PROFILE_PATH, e);
+            None
+        })
+    }
+
+    /// Store the profile, which marks the onboarding as done.
+    pub fn save(&self) -> anyhow::Result<()> {
+        FORMAT.save(self)
+    }
+}
+
+/// Version 0 to 1: Profiles made before piece sets have no `piece_set`, which is the built-in one.
+fn add_piece_set(source: &str) -> anyhow::Result<String> {
+    #[derive(Deserialize)]
+    struct ProfileV0 {
+        username: String,
+        #[serde(default)]
+        piece_set: Option<String>,
+    }
+
+    let old: ProfileV0 = ron::from_str(source)?;
+    to_ron(&ProfileV1 {
+        schema_version: 1,
+        username: old.username,
+        piece_set: old.piece_set,
+    })
+}
+
+/// The profile before it had a language.
+#[derive(Serialize, Deserialize)]
+struct ProfileV1 {
+    schema_version: u32,
+    username: String,
+    piece_set: Option<String>,
+}
+
+/// Version 1 to 2: Profiles made before the language could be picked are in English.
+fn add_language(source: &str) -> anyhow::Result<String> {
+    let old: ProfileV1 = ron::from_str(source)?;
+    to_ron(&ProfileV2 {
+        schema_version: 2,
+        username: old.username,
+        piece_set: old.piece_set,
+        language: Language::English,
+    })
+}
+
+/// The profile before playing anonymously was remembered.
+#[derive(Serialize, Deserialize)]
+struct ProfileV2 {
+    schema_version: u32,
+    username: String,
+    piece_set: Option<String>,
+    language: Language,
+}
+
+/// Version 2 to 3: Profiles made before playing anonymously was remembered play with their name.
+fn add_anonymous(source: &str) -> anyhow::Result<String> {
+    let old: ProfileV2 = ron::from_str(source)?;
+    to_ron(&Profile {
+        schema_version: 3,
+        username: old.username,
+        piece_set: old.piece_set,
+        language: old.language,
+        anonymous: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::migrations::tests::load_fixture;
+
+    /// Run every migration from `version` on `source`.
+    fn migrate(source: &str, version: usize) -> Profile {
+        let mut source = source.to_owned();
+        for migration in &FORMAT.migrations[version..] {
+            source = migration(&source).unwrap();
+        }
+        ron::from_str(&source).unwrap()
+    }
+
+    #[test]
+    fn old_profiles_are_in_english() {
+        let v0 = migrate(r#"(username: "Bob")"#, 0);
+        let v1 = migrate(
+            r#"(schema_version: 1, username: "Bob", piece_set: Some("wood"))"#,
+            1,
+        );
+        for (profile, piece_set) in [(v0, None), (v1, Some("wood".to_owned()))] {
+            assert_eq!(profile.schema_version, FORMAT.current_version());
+            assert_eq!(profile.username, "Bob");
+            assert_eq!(profile.piece_set, piece_set);
+            assert_eq!(profile.language, Language::English);
+            assert!(!profile.anonymous);
+        }
+    }
+
+    #[test]
+    fn old_profiles_play_with_their_name() {
+        let v2 = migrate(
+            r#"(schema_version: 2, username: "Bob", piece_set: None, language: Danish)"#,
+            2,
+        );
+        assert_eq!(v2.schema_version, FORMAT.current_version());
+        assert_eq!(v2.username, "Bob");
+        assert_eq!(v2.language, Language::Danish);
+        assert!(!v2.anonymous);
+    }
+
+    #[test]
+    fn stored_profiles_of_every_version_are_migrated() {
+        for (version, piece_set, language) in [
+            (0, None, Language::English),
+            (1, Some("wood"), Language::English),
+            (2, Some("wood"), Language::Danish),
+        ] {
+            let fixture = format!("profile.v{}.ron", version);
+            let loaded = load_fixture::<Profile>(&fixture, FORMAT.migrations);
+            let profile = loaded.value;
+            assert_eq!(
+                profile.schema_version,
+                FORMAT.current_version(),
+                "{}",
+                fixture
+            );
+            assert_eq!(profile.username, "Bob", "{}", fixture);
+            assert_eq!(profile.piece_set.as_deref(), piece_set, "{}", fixture);
+            assert_eq!(profile.language, language, "{}", fixture);
+            assert!(!profile.anonymous, "{}", fixture);
+            assert_eq!(loaded.stored, to_ron(&profile).unwrap());
+            assert_eq!(loaded.backup, Some(format!("stored.ron.v{}.bak", version)));
+        }
+    }
+
+    #[test]
+    fn language_is_stored() {
+        let mut profile = Profile::new("Bob".to_owned());
+        profile.language = Language::Danish;
+        let stored: Profile = ron::from_str(&to_ron(&profile).unwrap()).unwrap();
+        assert_eq!(stored.language, Language::Danish);
+    }
+
+    #[test]
+    fn anonymous_is_stored() {
+        let mut profile = Profile::new("Bob".to_owned());
+        assert!(!profile.anonymous);
+        profile.anonymous = true;
+        let stored: Profile = ron::from_str(&to_ron(&profile).unwrap()).unwrap();
+        assert!(stored.anonymous);
+    }
+}
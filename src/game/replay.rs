@@ -0,0 +1,146 @@
+//! Replays scripted board input on a headless board, to catch regressions in the click handling.
+//!
+//! A script is a RON file like:
+//! ```ron
+//! (
+//!     player: White,
+//!     inputs: [Click(21), Click(17), Remote(9, 13)],
+//! )
+//! ```
+//! `Click(index)` is a click on a square, which may be outside the board like the clicks from the
+//! window, and `Remote(from, to)` is a move made by the other player. Squares are indices on the
+//! board as the player sees it. A script can also set the
+//! starting `position` as a FEN string, and `my_turn` if it isn't White's turn to begin with.
+//!
+//! After the inputs, the board, the marked squares, the selected square and the turn are written
+//! to a snapshot, which is compared to the script's golden file by the `replay` binary.
+
+use anyhow::anyhow;
+use serde::Deserialize;
+
+use super::{
+    board::{set_board_move, Board},
+    fen::from_fen,
+    square, PieceColor,
+};
+
+/// The color the player plays in a script.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum Player {
+    White,
+    Black,
+}
+
+impl From<Player> for PieceColor {
+    fn from(player: Player) -> Self {
+        match player {
+            Player::White => PieceColor::White,
+            Player::Black => PieceColor::Black,
+        }
+    }
+}
+
+/// A single input to the board.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum Input {
+    /// The player clicks the square with this index. An index off the board is a click outside
+    /// it.
+    Click(i32),
+    /// The other player moves the piece on the first index to the second.
+    Remote(usize, usize),
+}
+
+/// A sequence of inputs, and the game they are made in.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Script {
+    pub player: Player,
+    /// The starting position as a FEN string. The normal starting setup is used if it's `None`.
+    #[serde(default)]
+    pub position: Option<String>,
+    /// If the player starts with the turn. Defaults to true when playing White.
+    #[serde(default)]
+    pub my_turn: Option<bool>,
+    pub inputs: Vec<Input>,
+}
+
+impl Script {
+    /// Parses a script from RON.
+    pub fn parse(source: &str) -> anyhow::Result<Self> {
+        Ok(ron::from_str(source)?)
+    }
+
+    /// Runs the script on a headless board, and returns the snapshot of the end state.
+    pub fn run(&self) -> anyhow::Result<String> {
+        let player_color: PieceColor = self.player.into();
+        let mut board = Board::new_headless();
+
+        match &self.position {
+            Some(fen) => {
+                let (mut pieces, _) = from_fen(fen)?;
+                // FEN is seen from White's side, and the player is always at the bottom
+                square::orient(&mut pieces, player_color);
+                board.load_position(pieces, player_color);
+            }
+            None => board.start_new_game(player_color),
+        }
+
+        let mut my_turn = self.my_turn.unwrap_or(player_color == PieceColor::White);
+
+        // Does the same as `Context::on_board_clicked()` and `Context::wait_for_opponent()`
+        for (step, input) in self.inputs.iter().enumerate() {
+            match *input {
+                Input::Click(index) => {
+                    let Some(index) = Board::square_index(index) else {
+                        board.clear_selection();
+                        continue;
+                    };
+                    if !my_turn {
+                        continue;
+                    }
+                    if let Some(mov) = board.find_move_to(index) {
+                        set_board_move(&mov);
+                        board.move_piece();
+                        my_turn = false;
+                    }
+                    board.select_square(index);
+                }
+                Input::Remote(from, to) => {
+                    check_index(step, from)?;
+                    check_index(step, to)?;
+                    let mov = board
+                        .get_legal_moves_piece(from)
+                        .and_then(|(moves, _)| moves.into_iter().find(|mov| mov.end == to))
+                        .ok_or(anyhow!(
+                            "Step {}: Remote move {}-{} isn't legal",
+                            step,
+                            from,
+                            to
+                        ))?;
+                    set_board_move(&mov);
+                    board.move_piece();
+                    my_turn = true;
+                }
+            }
+        }
+
+        let side_to_move = if my_turn {
+            player_color
+        } else {
+            player_color.get_opposite()
+        };
+        Ok(format!(
+            "board: {}\nmarked: {:?}\nselected: {}\nmy turn: {}\n",
+            board.to_fen(side_to_move),
+            board.marked_squares(),
+            board.selected_square,
+            my_turn
+        ))
+    }
+}
+
+fn check_index(step: usize, index: usize) -> anyhow::Result<()> {
+    if index >= 32 {
+        return Err(anyhow!("Step {}: Square {} is off the board", step, index));
+    }
+    Ok(())
+}
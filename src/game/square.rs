@@ -0,0 +1,222 @@
+//! Square indexing: Where each playable square is, and how a square is seen from either side.
+//!
+//! Only the dark squares are playable, so a row of the board holds half of its squares. They are
+//! indexed from the top left, row by row. The first square of even rows is in column 0, and the
+//! first square of odd rows is in column 1, so every playable square has `row + col` even.
+//!
+//! The canonical orientation has White at the bottom, like FEN and the move notation. The board on
+//! screen has the player at the bottom, so it's seen from Black's side when the player is Black.
+//! Turning the board around maps the square at `index` to `mirror(index)`.
+
+use super::PieceColor;
+
+/// The size of a board, which all the square math is based on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BoardGeometry {
+    size: usize,
+}
+
+impl BoardGeometry {
+    /// The 8 by 8 board of American checkers, with 32 playable squares.
+    pub const CHECKERS: Self = Self::new(8);
+    /// The 10 by 10 board of international draughts, with 50 playable squares.
+    pub const INTERNATIONAL: Self = Self::new(10);
+
+    /// A board with `size` squares along each side. The size must be even, so the bottom left
+    /// square is playable for both players.
+    pub const fn new(size: usize) -> Self {
+        assert!(size.is_multiple_of(2), "The board size must be even");
+        Self { size }
+    }
+
+    /// The amount of squares along each side of the board.
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The amount of playable squares in a row.
+    pub const fn row_width(&self) -> usize {
+        self.size / 2
+    }
+
+    /// The amount of playable squares on the board.
+    pub const fn squares(&self) -> usize {
+        self.size * self.row_width()
+    }
+
+    /// The square at `index` seen from the other side of the board.
+    pub const fn mirror(&self, index: usize) -> usize {
+        self.squares() - 1 - index
+    }
+
+    /// The row of the square at `index`, counted from the top.
+    pub const fn row(&self, index: usize) -> usize {
+        index / self.row_width()
+    }
+
+    /// The column of the square at `index`, counted from the left on the full board.
+    pub const fn col(&self, index: usize) -> usize {
+        // The playable squares of every other row are moved one column to the right
+        index % self.row_width() * 2 + self.row(index) % 2
+    }
+
+    /// The row and column of the square at `index`.
+    pub const fn to_rowcol(&self, index: usize) -> (usize, usize) {
+        (self.row(index), self.col(index))
+    }
+
+    /// The index of the square at `row` and `col`, if it's a playable square on the board. Takes
+    /// signed values, so a step off the board gives `None`.
+    pub const fn from_rowcol(&self, row: i32, col: i32) -> Option<usize> {
+        let size = self.size as i32;
+        if row < 0 || row >= size || col < 0 || col >= size || (row + col) % 2 != 0 {
+            return None;
+        }
+        Some(row as usize * self.row_width() + col as usize / 2)
+    }
+
+    /// The square `d_row` rows and `d_col` columns from the square at `index`, if it's a playable
+    /// square on the board.
+    pub const fn neighbor(&self, index: usize, d_row: i32, d_col: i32) -> Option<usize> {
+        let (row, col) = self.to_rowcol(index);
+        self.from_rowcol(row as i32 + d_row, col as i32 + d_col)
+    }
+
+    /// The canonical index of the square at `index`, on a board seen with `bottom` at the bottom.
+    pub const fn to_canonical(&self, index: usize, bottom: PieceColor) -> usize {
+        match bottom {
+            PieceColor::White => index,
+            PieceColor::Black => self.mirror(index),
+        }
+    }
+
+    /// The index of the square at the canonical `index`, on a board seen with `bottom` at the
+    /// bottom. This is the inverse of `to_canonical()`.
+    pub const fn to_view(&self, index: usize, bottom: PieceColor) -> usize {
+        // Turning the board around twice gives the same board, so it's the same mapping
+        self.to_canonical(index, bottom)
+    }
+}
+
+/// Turn a whole board between the canonical orientation and the view with `bottom` at the bottom.
+/// The squares are reversed, which moves each of them to `mirror()` of its index.
+///
+/// ## Params
+/// * `squares` - Every playable square of the board, e.g. the pieces.
+/// * `bottom` - The color at the bottom of the view.
+pub fn orient<T>(squares: &mut [T], bottom: PieceColor) {
+    if bottom == PieceColor::Black {
+        squares.reverse();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::Move;
+
+    const GEOMETRIES: [BoardGeometry; 2] = [BoardGeometry::CHECKERS, BoardGeometry::INTERNATIONAL];
+
+    #[test]
+    fn mirror_turns_the_board_around() {
+        for geometry in GEOMETRIES {
+            let last = geometry.size() - 1;
+            let mut mirrored = vec![];
+            for index in 0..geometry.squares() {
+                let mirror = geometry.mirror(index);
+                assert_eq!(geometry.mirror(mirror), index);
+                let (row, col) = geometry.to_rowcol(index);
+                assert_eq!(geometry.to_rowcol(mirror), (last - row, last - col));
+                mirrored.push(mirror);
+            }
+            mirrored.sort();
+            assert!(mirrored.into_iter().eq(0..geometry.squares()));
+        }
+    }
+
+    #[test]
+    fn rowcol_round_trips() {
+        for geometry in GEOMETRIES {
+            for index in 0..geometry.squares() {
+                let (row, col) = geometry.to_rowcol(index);
+                assert_eq!((row + col) % 2, 0, "{} isn't playable", index);
+                assert_eq!(geometry.from_rowcol(row as i32, col as i32), Some(index));
+            }
+
+            // Every other square is light, or off the board
+            let size = geometry.size() as i32;
+            let playable = (-1..=size)
+                .flat_map(|row| (-1..=size).map(move |col| (row, col)))
+                .filter(|(row, col)| geometry.from_rowcol(*row, *col).is_some())
+                .count();
+            assert_eq!(playable, geometry.squares());
+        }
+    }
+
+    #[test]
+    fn view_is_the_inverse_of_canonical() {
+        for geometry in GEOMETRIES {
+            for index in 0..geometry.squares() {
+                assert_eq!(geometry.to_canonical(index, PieceColor::White), index);
+                for bottom in [PieceColor::White, PieceColor::Black] {
+                    let canonical = geometry.to_canonical(index, bottom);
+                    assert_eq!(geometry.to_view(canonical, bottom), index);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_step_back_returns_to_the_square() {
+        for geometry in GEOMETRIES {
+            for index in 0..geometry.squares() {
+                for (d_row, d_col) in [(-1, -1), (-1, 1), (1, -1), (1, 1), (2, 2), (-2, 2)] {
+                    if let Some(neighbor) = geometry.neighbor(index, d_row, d_col) {
+                        assert_eq!(geometry.neighbor(neighbor, -d_row, -d_col), Some(index));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn orient_moves_each_square_to_its_mirror() {
+        for geometry in GEOMETRIES {
+            let canonical: Vec<usize> = (0..geometry.squares()).collect();
+            let mut squares = canonical.clone();
+            orient(&mut squares, PieceColor::White);
+            assert_eq!(squares, canonical);
+
+            orient(&mut squares, PieceColor::Black);
+            for (index, square) in squares.iter().enumerate() {
+                assert_eq!(*square, geometry.mirror(index));
+            }
+            orient(&mut squares, PieceColor::Black);
+            assert_eq!(squares, canonical);
+        }
+    }
+
+    #[test]
+    fn reversing_a_move_twice_gives_the_move() {
+        for geometry in GEOMETRIES {
+            for index in 0..geometry.squares() {
+                let piece = (index + 1) % geometry.squares();
+                for (captured, promoted) in [(None, false), (Some(vec![piece]), true)] {
+                    let mov = Move {
+                        index,
+                        end: geometry.mirror(index),
+                        captured,
+                        promoted,
+                    };
+                    let reversed = mov.reverse(geometry);
+                    assert_eq!(reversed.index, geometry.mirror(index));
+                    assert_eq!(reversed.end, index);
+                    let mirrored = mov.captured.as_ref().map(|_| vec![geometry.mirror(piece)]);
+                    assert_eq!(reversed.captured, mirrored);
+                    assert_eq!(reversed.promoted, promoted);
+                    assert_eq!(reversed.reverse(geometry), mov);
+                }
+            }
+        }
+    }
+}
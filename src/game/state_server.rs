@@ -0,0 +1,332 @@
+//! A small read-only HTTP server on localhost, serving the state of the current game as JSON. It's
+//! meant for streaming overlays.
+//!
+//! `GET /` or `GET /state` returns:
+//! ```json
+//! {"version":3,"fen":"B:W21,22:B1,2","moves":["22-18","11-15"],"white":"Alice","black":"Bob"}
+//! ```
+//! `version` counts up every time the state changes, and is also sent as the `ETag` header. Clients
+//! polling the server can send it back in an `If-None-Match` header, and get an empty
+//! `304 Not Modified` response if nothing has changed since.
+
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use super::{Move, PieceColor};
+
+/// The largest request the server reads. Requests are only ever a request line and a few headers.
+const MAX_REQUEST_LEN: usize = 4096;
+
+#[derive(Default)]
+struct GameState {
+    version: u64,
+    fen: String,
+    moves: Vec<String>,
+    white: String,
+    black: String,
+}
+
+lazy_static! {
+    static ref STATE: Mutex<GameState> = Mutex::new(GameState::default());
+}
+
+/// Clear the state for a new game.
+///
+/// ## Params
+/// * `fen` - The starting position.
+pub fn new_game(fen: String) {
+    let mut state = STATE.lock().unwrap();
+    let version = state.version + 1;
+    *state = GameState {
+        version,
+        fen,
+        ..Default::default()
+    };
+}
+
+/// Add a move to the state.
+///
+/// ## Params
+/// * `mov` - The move, with indices as seen from White's side.
+/// * `fen` - The position after the move.
+pub fn push_move(mov: &Move, fen: String) {
+    let separator = if mov.captured.is_some() { 'x' } else { '-' };
+    let mut state = STATE.lock().unwrap();
+    state
+        .moves
+        .push(format!("{}{}{}", mov.index + 1, separator, mov.end + 1));
+    state.fen = fen;
+    state.version += 1;
+}
+
+/// Set the names of the players, if they have changed.
+///
+/// ## Params
+/// * `my_color` - The color of the local player.
+/// * `my_name` - The name of the local player.
+/// * `other_name` - The name of the other player.
+pub fn set_names(my_color: PieceColor, my_name: String, other_name: String) {
+    let (white, black) = match my_color {
+        PieceColor::White => (my_name, other_name),
+        PieceColor::Black => (other_name, my_name),
+    };
+    let mut state = STATE.lock().unwrap();
+    if state.white != white || state.black != black {
+        state.white = white;
+        state.black = black;
+        state.version += 1;
+    }
+}
+
+/// Serve the state on `127.0.0.1:<port>` until an error occurs.
+pub async fn serve(port: u16) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    println!("State server listening on 127.0.0.1:{}", port);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream).await {
+                println!("State server request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream) -> anyhow::Result<()> {
+    let mut buffer = vec![0; MAX_REQUEST_LEN];
+    let mut len = 0;
+    while !buffer[..len].windows(4).any(|window| window == b"\r\n\r\n") {
+        if len == MAX_REQUEST_LEN {
+            return write_response(&mut stream, "413 Payload Too Large", None, "").await;
+        }
+        let read = stream.read(&mut buffer[len..]).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        len += read;
+    }
+
+    let request = String::from_utf8_lossy(&buffer[..len]);
+    let mut lines = request.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (method, path) = (request_line.next(), request_line.next());
+    if method != Some("GET") {
+        return write_response(&mut stream, "405 Method Not Allowed", None, "").await;
+    }
+    if !matches!(path, Some("/") | Some("/state")) {
+        return write_response(&mut stream, "404 Not Found", None, "").await;
+    }
+
+    let if_none_match = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("If-None-Match")
+            .then(|| value.trim().to_owned())
+    });
+
+    let (etag, body) = {
+        let state = STATE.lock().unwrap();
+        (format!("\"{}\"", state.version), to_json(&state))
+    };
+
+    if if_none_match.as_deref() == Some(etag.as_str()) {
+        return write_response(&mut stream, "304 Not Modified", Some(&etag), "").await;
+    }
+    write_response(&mut stream, "200 OK", Some(&etag), &body).await
+}
+
+async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    etag: Option<&str>,
+    body: &str,
+) -> anyhow::Result<()> {
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    if let Some(etag) = etag {
+        response.push_str(&format!("ETag: {}\r\n", etag));
+    }
+    response.push_str(&format!(
+        "Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    ));
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+fn to_json(state: &GameState) -> String {
+    let moves: Vec<String> = state.moves.iter().map(|mov| json_string(mov)).collect();
+    format!(
+        "{{\"version\":{},\"fen\":{},\"moves\":[{}],\"white\":{},\"black\":{}}}",
+        state.version,
+        json_string(&state.fen),
+        moves.join(","),
+        json_string(&state.white),
+        json_string(&state.black)
+    )
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::net::lock_global_state;
+
+    /// The state, as a client reads it.
+    fn state_json() -> Value {
+        serde_json::from_str(&to_json(&STATE.lock().unwrap())).unwrap()
+    }
+
+    /// Send `request` to a connection handled by the server, and get the response.
+    fn send(request: &str) -> String {
+        Runtime::new().unwrap().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+            handle_connection(server).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        })
+    }
+
+    #[test]
+    fn game_is_served_as_json() {
+        let _state = lock_global_state();
+        new_game("W:W21,22:B1,2".to_owned());
+        let started = state_json()["version"].as_u64().unwrap();
+        set_names(
+            PieceColor::Black,
+            "Bob".to_owned(),
+            "Alice \"A\"\n".to_owned(),
+        );
+        push_move(
+            &Move {
+                index: 21,
+                end: 17,
+                promoted: false,
+                captured: None,
+            },
+            "B:W18,21:B1,2".to_owned(),
+        );
+        push_move(
+            &Move {
+                index: 5,
+                end: 14,
+                promoted: false,
+                captured: Some(vec![9]),
+            },
+            "W:W21:B1,15".to_owned(),
+        );
+
+        assert_eq!(
+            state_json(),
+            json!({
+                "version": started + 3,
+                "fen": "W:W21:B1,15",
+                "moves": ["22-18", "6x15"],
+                "white": "Alice \"A\"\n",
+                "black": "Bob",
+            })
+        );
+
+        // The same names again change nothing
+        set_names(
+            PieceColor::White,
+            "Alice \"A\"\n".to_owned(),
+            "Bob".to_owned(),
+        );
+        assert_eq!(state_json()["version"], started + 3);
+        new_game("B:W21,22:B1,2".to_owned());
+        assert_eq!(
+            state_json(),
+            json!({
+                "version": started + 4,
+                "fen": "B:W21,22:B1,2",
+                "moves": [],
+                "white": "",
+                "black": "",
+            })
+        );
+    }
+
+    #[test]
+    fn unchanged_state_is_not_sent_again() {
+        let _state = lock_global_state();
+        new_game("W:W21,22:B1,2".to_owned());
+        let etag = format!("\"{}\"", STATE.lock().unwrap().version);
+
+        let response = send("GET /state HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains(&format!("ETag: {}\r\n", etag)));
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        assert_eq!(serde_json::from_str::<Value>(body).unwrap(), state_json());
+
+        let request = format!("GET / HTTP/1.1\r\nif-none-match: {}\r\n\r\n", etag);
+        let response = send(&request);
+        assert!(
+            response.starts_with("HTTP/1.1 304 Not Modified\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("\r\n\r\n"));
+
+        // Once the state changes, the old tag gets the new state
+        push_move(
+            &Move {
+                index: 21,
+                end: 17,
+                promoted: false,
+                captured: None,
+            },
+            "B:W18,22:B1,2".to_owned(),
+        );
+        let response = send(&request);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\"moves\":[\"22-18\"]"));
+    }
+
+    #[test]
+    fn other_requests_are_refused() {
+        let response = send("POST /state HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        let response = send("GET /moves HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        // A request that fills the buffer without ending, and is all read, so the connection isn't
+        // reset when the server closes it
+        let line = "GET / HTTP/1.1\r\n";
+        let response = send(&format!(
+            "{}{}",
+            line,
+            "X".repeat(MAX_REQUEST_LEN - line.len())
+        ));
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    }
+}
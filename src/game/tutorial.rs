@@ -0,0 +1,217 @@
+use anyhow::anyhow;
+use serde::Deserialize;
+
+use super::{board::Board, fen::from_fen, Move, PieceColor, PieceData};
+
+/// The scenarios of the tutorial, as RON.
+const SCENARIOS: &str = include_str!("../../tutorial/scenarios.ron");
+
+/// A single lesson of the tutorial: a position, and the move the player has to find in it.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Scenario {
+    /// The name of the lesson.
+    pub title: String,
+    /// The position of the lesson as a FEN string. The player plays White.
+    pub position: String,
+    /// The move the player has to make, as a pair of square numbers (from, to).
+    pub expected: (usize, usize),
+    /// What the player is asked to do.
+    pub instruction: String,
+    /// Shown when the player makes another move than the expected one.
+    pub explanation: String,
+}
+
+impl Scenario {
+    /// Returns the pieces of the scenario's position.
+    pub fn pieces(&self) -> anyhow::Result<Vec<PieceData>> {
+        let (pieces, side_to_move) = from_fen(&self.position)?;
+        if side_to_move != PieceColor::White {
+            return Err(anyhow!("The player always plays White in the tutorial"));
+        }
+        Ok(pieces)
+    }
+
+    /// Returns true if `mov` is the move the player has to make.
+    pub fn is_expected(&self, mov: &Move) -> bool {
+        mov.index + 1 == self.expected.0 && mov.end + 1 == self.expected.1
+    }
+
+    /// Checks that the position can be parsed, and that the expected move is legal in it.
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut board = Board::default();
+        board.load_position(self.pieces()?, PieceColor::White);
+
+        let is_legal = board
+            .get_legal_moves()
+            .is_some_and(|moves| moves.iter().any(|mov| self.is_expected(mov)));
+        if !is_legal {
+            return Err(anyhow!(
+                "The expected move {}-{} isn't legal",
+                self.expected.0,
+                self.expected.1
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// What a click on the board does in the tutorial.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TutorialClick {
+    /// The clicked square is selected, since the selected piece can't move there.
+    Select,
+    /// The selected piece can move to the clicked square, but that isn't the expected move. The
+    /// explanation of the scenario is shown, and the square is selected.
+    Wrong(String),
+    /// The expected move, which is played. The tutorial has gone on to the next scenario.
+    Correct(Move),
+}
+
+/// Keeps track of the scenarios of the tutorial, and how far the player is.
+pub struct Tutorial {
+    scenarios: Vec<Scenario>,
+    current: usize,
+}
+
+impl Tutorial {
+    /// Loads the scenarios embedded in the game. Scenarios that aren't valid are left out.
+    pub fn load() -> Self {
+        let scenarios: Vec<Scenario> = match ron::from_str(SCENARIOS) {
+            Ok(scenarios) => scenarios,
+            Err(e) => {
+                println!("Failed to parse the tutorial scenarios: {}", e);
+                vec![]
+            }
+        };
+
+        let scenarios = scenarios
+            .into_iter()
+            .filter(|scenario| match scenario.validate() {
+                Ok(_) => true,
+                Err(e) => {
+                    println!("Tutorial scenario \"{}\" is invalid: {}", scenario.title, e);
+                    false
+                }
+            })
+            .collect();
+
+        Self {
+            scenarios,
+            current: 0,
+        }
+    }
+
+    /// The scenario the player is at. Returns `None` when the tutorial is done.
+    pub fn current(&self) -> Option<&Scenario> {
+        self.scenarios.get(self.current)
+    }
+
+    /// Go to the next scenario, and return it. Returns `None` when the tutorial is done.
+    pub fn advance(&mut self) -> Option<&Scenario> {
+        self.current += 1;
+        self.current()
+    }
+
+    /// Returns the number of the current scenario, counting from 1, and the amount of scenarios.
+    pub fn progress(&self) -> (usize, usize) {
+        (self.current + 1, self.scenarios.len())
+    }
+
+    /// Handles a click on the square `index` of `board`, which has the position of the current
+    /// scenario. Only the expected move is let through, and then the tutorial goes on to the next
+    /// scenario.
+    pub fn click(&mut self, board: &Board, index: usize) -> TutorialClick {
+        let selected_piece = board.selected();
+        let mov = board.get_legal_moves().and_then(|moves| {
+            moves
+                .into_iter()
+                .find(|mov| Some(mov.index) == selected_piece && mov.end == index)
+        });
+
+        match (mov, self.current()) {
+            (Some(mov), Some(scenario)) if scenario.is_expected(&mov) => {
+                self.advance();
+                TutorialClick::Correct(mov)
+            }
+            (Some(_), Some(scenario)) => TutorialClick::Wrong(scenario.explanation.clone()),
+            _ => TutorialClick::Select,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{game::board::set_board_move, net::lock_global_state};
+
+    #[test]
+    fn every_shipped_scenario_is_valid() {
+        let scenarios: Vec<Scenario> = ron::from_str(SCENARIOS).unwrap();
+        assert!(!scenarios.is_empty());
+        for scenario in &scenarios {
+            if let Err(e) = scenario.validate() {
+                panic!("Scenario \"{}\" is invalid: {}", scenario.title, e);
+            }
+        }
+        // So none are left out
+        assert_eq!(Tutorial::load().progress(), (1, scenarios.len()));
+    }
+
+    #[test]
+    fn invalid_scenarios_are_refused() {
+        let scenario = |position: &str, expected| Scenario {
+            title: "Broken".to_owned(),
+            position: position.to_owned(),
+            expected,
+            instruction: String::new(),
+            explanation: String::new(),
+        };
+        // The capture is forced
+        assert!(scenario("W:W22,29:B18", (29, 25)).validate().is_err());
+        assert!(scenario("W:W22,29:B18", (22, 13)).validate().is_ok());
+        assert!(scenario("B:W22:B18", (22, 13)).validate().is_err());
+        assert!(scenario("W:W22:B18:", (22, 13)).validate().is_err());
+    }
+
+    #[test]
+    fn tutorial_is_played_to_the_end() {
+        let _state = lock_global_state();
+        let mut tutorial = Tutorial::load();
+        let (_, count) = tutorial.progress();
+        let mut board = Board::new_headless();
+
+        while let Some(scenario) = tutorial.current().cloned() {
+            let (number, _) = tutorial.progress();
+            board.load_position(scenario.pieces().unwrap(), PieceColor::White);
+            board.clear_selection();
+
+            // Another legal move is explained, and the scenario goes on
+            let wrong = board
+                .get_legal_moves()
+                .unwrap()
+                .into_iter()
+                .find(|mov| !scenario.is_expected(mov));
+            if let Some(wrong) = wrong {
+                assert_eq!(tutorial.click(&board, wrong.index), TutorialClick::Select);
+                board.select_square(wrong.index);
+                assert_eq!(
+                    tutorial.click(&board, wrong.end),
+                    TutorialClick::Wrong(scenario.explanation.clone())
+                );
+                assert_eq!(tutorial.progress().0, number, "{}", scenario.title);
+            }
+
+            let (from, to) = (scenario.expected.0 - 1, scenario.expected.1 - 1);
+            assert_eq!(tutorial.click(&board, from), TutorialClick::Select);
+            board.select_square(from);
+            let TutorialClick::Correct(mov) = tutorial.click(&board, to) else {
+                panic!("The expected move of \"{}\" wasn't taken", scenario.title);
+            };
+            set_board_move(&mov);
+            board.move_piece();
+            assert!(board.piece_is_player(to), "{}", scenario.title);
+            assert_eq!(tutorial.progress().0, number + 1);
+        }
+        assert_eq!(tutorial.progress(), (count + 1, count));
+    }
+}
@@ -0,0 +1,478 @@
+//! The moves of a game as a tree, for analyzing it after it ended. The moves of the game are the
+//! main line, and every other move tried in the analysis starts a variation at the position it
+//! was played from. Variations can branch again, be promoted over the line they branch from, and
+//! be deleted, but the moves of the game always stay the main line.
+//!
+//! The tree is a copy of the game, so nothing done in it changes the game or is sent to the other
+//! player. It can be written as PDN, with the variations in parentheses.
+
+use super::{notation::move_notation, Move};
+
+/// A position in a `MoveHistory`. The ids of deleted positions aren't reused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+/// The position the game started from.
+const ROOT: NodeId = NodeId(0);
+
+/// A position in the tree, and the move that led to it.
+#[derive(Clone, Debug)]
+struct Node {
+    /// The move from the parent, with indices as seen from White's side. `None` for the start.
+    mov: Option<Move>,
+    /// The board after the move, as a FEN string.
+    fen: String,
+    /// The number of moves from the start.
+    ply: u16,
+    parent: Option<NodeId>,
+    /// The moves played from this position. The first one goes on with the line this position is
+    /// on, and the others are its variations.
+    children: Vec<NodeId>,
+    /// If the move was played in the game, rather than in the analysis.
+    is_game: bool,
+}
+
+/// The moves of a game and the variations tried on them, with the position shown on the board.
+#[derive(Clone, Debug)]
+pub struct MoveHistory {
+    /// Deleted positions are `None`, so the ids of the others stay valid.
+    nodes: Vec<Option<Node>>,
+    current: NodeId,
+}
+
+impl MoveHistory {
+    /// A game that started from `start_fen` and made `moves`, each with the board after it. The
+    /// start of the game is shown.
+    pub fn from_game(start_fen: String, moves: impl IntoIterator<Item = (Move, String)>) -> Self {
+        let mut history = Self {
+            nodes: vec![Some(Node {
+                mov: None,
+                fen: start_fen,
+                ply: 0,
+                parent: None,
+                children: vec![],
+                is_game: true,
+            })],
+            current: ROOT,
+        };
+        let mut last = ROOT;
+        for (mov, fen) in moves {
+            last = history.add_child(last, mov, fen, true);
+        }
+        history
+    }
+
+    /// The position shown.
+    pub fn current(&self) -> NodeId {
+        self.current
+    }
+
+    /// The board at `id`, as a FEN string.
+    pub fn fen(&self, id: NodeId) -> Option<&str> {
+        self.node(id).map(|node| node.fen.as_str())
+    }
+
+    /// The move that led to `id`, which is `None` for the start of the game.
+    pub fn mov(&self, id: NodeId) -> Option<&Move> {
+        self.node(id)?.mov.as_ref()
+    }
+
+    /// If the move to `id` was played in the game.
+    pub fn is_game(&self, id: NodeId) -> bool {
+        self.node(id).is_some_and(|node| node.is_game)
+    }
+
+    /// The moves played from `id`, the one going on with its line first.
+    pub fn children(&self, id: NodeId) -> &[NodeId] {
+        self.node(id).map_or(&[], |node| node.children.as_slice())
+    }
+
+    /// Play `mov` from the position shown, and show the board after it, `fen`. A move that was
+    /// already played from there is followed instead of added again. Other moves start a new
+    /// variation, or go on with the line if nothing was played from there yet.
+    pub fn play(&mut self, mov: Move, fen: String) -> NodeId {
+        let known = self
+            .children(self.current)
+            .iter()
+            .find(|child| self.mov(**child) == Some(&mov))
+            .copied();
+        self.current = match known {
+            Some(known) => known,
+            None => self.add_child(self.current, mov, fen, false),
+        };
+        self.current
+    }
+
+    /// Show the position before the one shown. Returns false at the start of the game.
+    pub fn back(&mut self) -> bool {
+        match self.node(self.current).and_then(|node| node.parent) {
+            Some(parent) => {
+                self.current = parent;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Show the next position of the line that is shown. Returns false at its end.
+    pub fn forward(&mut self) -> bool {
+        match self.children(self.current).first() {
+            Some(&next) => {
+                self.current = next;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Show the position `id`. Returns false if it isn't in the tree.
+    pub fn go_to(&mut self, id: NodeId) -> bool {
+        let is_known = self.node(id).is_some();
+        if is_known {
+            self.current = id;
+        }
+        is_known
+    }
+
+    /// The positions of the line through `id`, without the start: The moves leading to it, and
+    /// the line going on after it. This is the line shown in the move list.
+    pub fn line_through(&self, id: NodeId) -> Vec<NodeId> {
+        let mut line = vec![];
+        let mut node = Some(id);
+        while let Some(on_line) = node.filter(|node| *node != ROOT) {
+            line.push(on_line);
+            node = self.node(on_line).and_then(|node| node.parent);
+        }
+        line.reverse();
+
+        let mut last = id;
+        while let Some(&next) = self.children(last).first() {
+            line.push(next);
+            last = next;
+        }
+        line
+    }
+
+    /// Make the variation `id` is on the line it branches from, and that line its first
+    /// variation. Variations that branch from other variations move up one level at a time.
+    /// Returns false if `id` is on the main line, or its variation branches from a move of the
+    /// game, which stays the main line.
+    pub fn promote(&mut self, id: NodeId) -> bool {
+        let mut node = id;
+        while let Some(parent) = self.node(node).and_then(|node| node.parent) {
+            let children = self.children(parent);
+            let position = children.iter().position(|child| *child == node);
+            match position {
+                Some(0) | None => node = parent,
+                Some(_) if self.is_game(children[0]) => return false,
+                Some(position) => {
+                    let children = &mut self.node_mut(parent).children;
+                    let variation = children.remove(position);
+                    children.insert(0, variation);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Delete the move to `id` and everything played after it. If the position shown is deleted,
+    /// the one before `id` is shown. Returns false for the start and the moves of the game, which
+    /// can't be deleted.
+    pub fn delete(&mut self, id: NodeId) -> bool {
+        let Some(parent) = self
+            .node(id)
+            .filter(|node| !node.is_game)
+            .and_then(|node| node.parent)
+        else {
+            return false;
+        };
+        self.node_mut(parent).children.retain(|child| *child != id);
+
+        let mut deleted = vec![id];
+        while let Some(NodeId(index)) = deleted.pop() {
+            if let Some(node) = self.nodes[index].take() {
+                deleted.extend(node.children);
+            }
+        }
+        if self.node(self.current).is_none() {
+            self.current = parent;
+        }
+        true
+    }
+
+    /// Every move from the start, in PDN movetext. Variations are in parentheses after the move
+    /// they replace, e.g. `"1. 22-18 (1. 21-17) 11-15"`.
+    pub fn movetext(&self) -> String {
+        self.line_text(ROOT, false).join(" ")
+    }
+
+    /// The tree as a PDN game, with `tags` and `result`, e.g. `"1-0"`. The start position is given
+    /// in a `FEN` tag.
+    pub fn to_pdn(&self, tags: &[(&str, &str)], result: &str) -> String {
+        let start = self.fen(ROOT).unwrap_or_default();
+        let mut pdn: String = tags
+            .iter()
+            .chain(&[("Result", result), ("FEN", start)])
+            .map(|(name, value)| format!("[{} \"{}\"]\n", name, value.replace('"', "'")))
+            .collect();
+        let movetext = self.movetext();
+        pdn.push('\n');
+        if !movetext.is_empty() {
+            pdn.push_str(&movetext);
+            pdn.push(' ');
+        }
+        pdn.push_str(result);
+        pdn.push('\n');
+        pdn
+    }
+
+    /// The moves of the line going on from `from`, with the variations of each move. The first
+    /// move is numbered as if a variation came before it if `is_numbered`.
+    fn line_text(&self, from: NodeId, mut is_numbered: bool) -> Vec<String> {
+        let mut text = vec![];
+        let mut node = from;
+        while let Some((&next, variations)) = self.children(node).split_first() {
+            text.push(self.move_text(next, is_numbered));
+            for &variation in variations {
+                let mut line = vec![self.move_text(variation, true)];
+                line.extend(self.line_text(variation, false));
+                text.push(format!("({})", line.join(" ")));
+            }
+            is_numbered = !variations.is_empty();
+            node = next;
+        }
+        text
+    }
+
+    /// The move to `id` in PDN, numbered if it's the first player's move or `is_numbered`.
+    fn move_text(&self, id: NodeId, is_numbered: bool) -> String {
+        let Some(node) = self.node(id) else {
+            return String::new();
+        };
+        let notation = node.mov.as_ref().map(move_notation).unwrap_or_default();
+        // The first player moves on the even plies, counted from 0
+        let ply = node.ply.saturating_sub(1);
+        let number = ply / 2 + 1;
+        match (ply % 2 == 0, is_numbered) {
+            (true, _) => format!("{}. {}", number, notation),
+            (false, true) => format!("{}... {}", number, notation),
+            (false, false) => notation,
+        }
+    }
+
+    fn add_child(&mut self, parent: NodeId, mov: Move, fen: String, is_game: bool) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        let ply = self.node(parent).map_or(0, |node| node.ply) + 1;
+        self.nodes.push(Some(Node {
+            mov: Some(mov),
+            fen,
+            ply,
+            parent: Some(parent),
+            children: vec![],
+            is_game,
+        }));
+        self.node_mut(parent).children.push(id);
+        id
+    }
+
+    fn node(&self, NodeId(index): NodeId) -> Option<&Node> {
+        self.nodes.get(index)?.as_ref()
+    }
+
+    /// The node `id`, which must be in the tree.
+    fn node_mut(&mut self, NodeId(index): NodeId) -> &mut Node {
+        self.nodes[index]
+            .as_mut()
+            .expect("The node was deleted from the tree")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A move without captures, with the FEN after it named after the move.
+    fn step(start: usize, end: usize) -> (Move, String) {
+        let mov = Move {
+            index: start - 1,
+            end: end - 1,
+            promoted: false,
+            captured: None,
+        };
+        (mov, format!("after {}-{}", start, end))
+    }
+
+    /// A game of `22-18 11-15 18-11`.
+    fn game() -> MoveHistory {
+        MoveHistory::from_game(
+            "W:start".to_string(),
+            [step(22, 18), step(11, 15), step(18, 11)],
+        )
+    }
+
+    fn play(history: &mut MoveHistory, start: usize, end: usize) -> NodeId {
+        let (mov, fen) = step(start, end);
+        history.play(mov, fen)
+    }
+
+    #[test]
+    fn variations_branch_from_the_game() {
+        let mut history = game();
+        assert_eq!(history.current(), ROOT);
+        assert_eq!(history.fen(ROOT), Some("W:start"));
+        assert!(history.forward());
+        let first = history.current();
+        assert!(history.is_game(first));
+
+        // Another reply to the first move starts a variation, and going on from it extends it
+        let reply = play(&mut history, 9, 13);
+        assert!(!history.is_game(reply));
+        let deeper = play(&mut history, 18, 14);
+        assert_eq!(history.children(first).len(), 2);
+        assert_eq!(history.children(first)[1], reply);
+        assert_eq!(history.children(reply), [deeper]);
+        assert_eq!(history.fen(deeper), Some("after 18-14"));
+
+        // Playing the game's move again follows it instead of adding a copy
+        assert!(history.go_to(first));
+        let again = play(&mut history, 11, 15);
+        assert_eq!(again, history.children(first)[0]);
+        assert!(history.is_game(again));
+        assert_eq!(history.children(first).len(), 2);
+
+        // The move list shows the line through the position shown
+        let line = history.line_through(deeper);
+        assert_eq!(line, [first, reply, deeper]);
+        let game_line = history.line_through(first);
+        assert_eq!(game_line.len(), 3);
+        assert!(game_line.iter().all(|id| history.is_game(*id)));
+
+        assert!(history.go_to(reply));
+        assert!(history.back());
+        assert_eq!(history.current(), first);
+        assert!(history.back());
+        assert!(!history.back());
+        assert_eq!(history.current(), ROOT);
+    }
+
+    #[test]
+    fn promoting_moves_a_variation_up() {
+        let mut history = game();
+        history.go_to(history.line_through(ROOT)[2]);
+        // Two lines after the end of the game, and a line branching from the second one
+        let first = play(&mut history, 22, 17);
+        history.back();
+        let second = play(&mut history, 21, 17);
+        let second_main = play(&mut history, 9, 14);
+        history.back();
+        let nested = play(&mut history, 10, 14);
+        let nested_next = play(&mut history, 17, 10);
+
+        // A variation of a move of the game can't replace it
+        let last_game_move = history.line_through(ROOT)[2];
+        history.go_to(history.line_through(ROOT)[0]);
+        let reply = play(&mut history, 9, 13);
+        assert!(!history.promote(reply));
+        assert!(history.is_game(history.children(history.line_through(ROOT)[0])[0]));
+
+        // The nested variation first becomes the line of its own variation
+        assert!(history.promote(nested_next));
+        assert_eq!(history.children(second), [nested, second_main]);
+        // and then that variation becomes the line after the game
+        assert!(history.promote(nested_next));
+        assert_eq!(history.children(last_game_move), [second, first]);
+        assert!(!history.promote(nested_next));
+        assert_eq!(
+            history.line_through(ROOT)[3..],
+            [second, nested, nested_next]
+        );
+    }
+
+    #[test]
+    fn deleting_removes_a_branch() {
+        let mut history = game();
+        let game_line = history.line_through(ROOT);
+        history.go_to(game_line[0]);
+        let reply = play(&mut history, 9, 13);
+        let deeper = play(&mut history, 18, 14);
+
+        // The position shown goes back to where the branch started
+        assert!(history.delete(reply));
+        assert_eq!(history.current(), game_line[0]);
+        assert_eq!(history.children(game_line[0]), [game_line[1]]);
+        assert_eq!(history.fen(deeper), None);
+        assert!(!history.go_to(deeper));
+        assert!(!history.delete(reply));
+
+        // The game itself stays
+        assert!(!history.delete(game_line[1]));
+        assert!(!history.delete(ROOT));
+        assert_eq!(history.line_through(ROOT), game_line);
+
+        // The ids of deleted positions aren't reused
+        let again = play(&mut history, 9, 13);
+        assert_ne!(again, reply);
+    }
+
+    #[test]
+    fn pdn_puts_variations_in_parentheses() {
+        let mut history = game();
+        let game_line = history.line_through(ROOT);
+        history.go_to(game_line[0]);
+        play(&mut history, 9, 13);
+        play(&mut history, 18, 14);
+        history.go_to(ROOT);
+        play(&mut history, 21, 17);
+
+        assert_eq!(
+            history.movetext(),
+            "1. 22-18 (1. 21-17) 1... 11-15 (1... 9-13 2. 18-14) 2. 18-11"
+        );
+        assert_eq!(
+            history.to_pdn(&[("White", "Alice"), ("Black", "Bob \"B\"")], "1-0"),
+            "[White \"Alice\"]\n[Black \"Bob 'B'\"]\n[Result \"1-0\"]\n[FEN \"W:start\"]\n\n\
+             1. 22-18 (1. 21-17) 1... 11-15 (1... 9-13 2. 18-14) 2. 18-11 1-0\n"
+        );
+
+        let empty = MoveHistory::from_game("W:start".to_string(), []);
+        assert_eq!(empty.movetext(), "");
+        assert!(empty.to_pdn(&[], "*").ends_with("\n\n*\n"));
+    }
+
+    #[test]
+    fn pdn_writes_moves_like_the_move_list() {
+        // A capture, and a capture that promotes, which the move list writes with their jumps
+        let capture = Move {
+            index: 17,
+            end: 10,
+            promoted: false,
+            captured: Some(vec![14]),
+        };
+        let promotion = Move {
+            index: 10,
+            end: 1,
+            promoted: true,
+            captured: Some(vec![6]),
+        };
+        let moves = [
+            step(22, 18),
+            step(11, 15),
+            (capture.clone(), "after 18x11".to_string()),
+            step(8, 15),
+            (promotion.clone(), "after 11x2".to_string()),
+        ];
+        let history = MoveHistory::from_game("W:start".to_string(), moves);
+
+        assert_eq!(move_notation(&capture), "18x11");
+        assert_eq!(move_notation(&promotion), "11x2K");
+        assert_eq!(
+            history.movetext(),
+            format!(
+                "1. 22-18 11-15 2. {} 8-15 3. {}",
+                move_notation(&capture),
+                move_notation(&promotion)
+            )
+        );
+    }
+}
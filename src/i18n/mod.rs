@@ -0,0 +1,373 @@
+use std::{collections::HashMap, fmt::Display, sync::Mutex};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+/// The key of every user-facing message produced on the Rust side of the game.
+/// Each key must have an entry in every catalog inside `lang/`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+pub enum MessageKey {
+    /// Shown instead of the other players name, if they didn't send one.
+    NoUsername,
+    /// The name the host uses, if its user didn't set one.
+    DefaultHostUsername,
+    /// An error response from the other peer. `{0}` is the error kind.
+    ErrorResponse,
+    /// The other peer responded with the wrong kind of packet.
+    WrongResponsePacket,
+    /// The other peer sent a request, where a response was expected.
+    RequestInsteadOfResponse,
+    /// The title of a tutorial scenario. `{0}` is its number, `{1}` the amount of scenarios and
+    /// `{2}` its name.
+    TutorialTitle,
+    /// Shown when the player makes the expected move in a tutorial scenario.
+    TutorialCorrect,
+    /// The peer a host tried to join is also hosting, so the host joins it as client.
+    BothHosting,
+    /// The peer a host tried to join isn't hosting.
+    ProbeNotHosting,
+    /// The peer a host tried to join didn't answer.
+    ProbeNoAnswer,
+    /// The join code couldn't be decoded.
+    JoinCodeInvalid,
+    /// The join code points to an address that can't be a host. `{0}` is the address.
+    JoinTargetInvalid,
+    /// The join code points to an address outside the local network, and has to be confirmed.
+    /// `{0}` is the address.
+    JoinTargetPublic,
+    /// The host didn't answer an attempt to join it. `{0}` is the seconds until the next attempt,
+    /// `{1}` its number, and `{2}` the amount of attempts.
+    ConnectRetrying,
+    /// The host didn't answer any attempt to join it. `{0}` is the amount of attempts.
+    ConnectTimedOut,
+    /// Shown while the coin flip deciding the colors is revealed.
+    CoinFlipping,
+    /// The coin flip made us White.
+    CoinFlipWhite,
+    /// The coin flip made us Black.
+    CoinFlipBlack,
+    /// The host's coin flip didn't match its commitment, so it may have cheated.
+    CoinFlipMismatch,
+    /// The peers play with different rules, so the game was cancelled.
+    OptionsMismatch,
+    /// The host never answered the check that both peers play with the same rules.
+    OptionsUnconfirmed,
+    /// The other player left the game.
+    OpponentLeft,
+    /// The other player refused our move as not legal, so it was taken back.
+    MoveRefused,
+    /// The host refused our move, since it wasn't our turn there. `{0}` is the move the host is at.
+    MoveNotYourTurn,
+    /// The other player refused our move, since it isn't legal on their board.
+    MoveIllegal,
+    /// A move couldn't be sent, since too much is waiting to be sent to the other player, and was
+    /// taken back.
+    MoveNotSent,
+    /// The other player offered a draw, shown above the buttons answering it.
+    DrawOffered,
+    /// Our draw offer was sent, and is waiting for an answer.
+    DrawOfferSent,
+    /// The other player declined our draw offer, so the game goes on.
+    DrawDeclined,
+    /// A draw offer was accepted, so the game ended in a draw.
+    DrawAgreed,
+    /// A draw can only be offered on our own turn.
+    DrawOfferNotYourTurn,
+    /// Our draw offer is still waiting for an answer, also after a reconnect.
+    DrawOfferPending,
+    /// The other player asks for a pause, shown next to the buttons answering it.
+    PauseAsked,
+    /// The other player asks to go on with the paused game, shown next to the buttons answering
+    /// it.
+    ResumeAsked,
+    /// We asked for a pause, and wait for the answer.
+    PauseRequestSent,
+    /// We asked to go on with the paused game, and wait for the answer.
+    ResumeRequestSent,
+    /// The game is paused. Only the chat works until both players agree to go on.
+    GamePaused,
+    /// The other player declined our request, so the game stays as it was.
+    PauseDeclined,
+    /// We won, since the other player surrendered.
+    GameWonSurrender,
+    /// We lost, since we surrendered.
+    GameLostSurrender,
+    /// We won, since the other player has no legal move left.
+    GameWonNoMoves,
+    /// We lost, since we have no legal move left.
+    GameLostNoMoves,
+    /// We won, since the other player didn't come back within the grace period.
+    GameWonTimeout,
+    /// We lost, since we didn't come back within the grace period.
+    GameLostTimeout,
+    /// The other player offered a rematch, shown above the buttons answering it.
+    RematchOffered,
+    /// Our rematch offer was sent, and is waiting for an answer.
+    RematchOfferSent,
+    /// The other player declined our rematch offer.
+    RematchDeclined,
+    /// The other player's game hadn't ended, so it refused our rematch offer.
+    RematchNotOver,
+    /// A rematch started, where we play White.
+    RematchWhite,
+    /// A rematch started, where we play Black.
+    RematchBlack,
+    /// The other peer runs a version of the game that speaks another protocol, so both must update.
+    ProtocolMismatch,
+    /// The ping to the other player. `{0}` is the smoothed ping in milliseconds.
+    Ping,
+    /// The ping to the other player, with spikes. `{0}` is the smoothed ping and `{1}` the 95th
+    /// percentile, in milliseconds.
+    PingSpike,
+    /// Both boards had the same hash after move `{0}`.
+    BoardInSync,
+    /// The boards had different hashes after move `{0}`, so they are resynced.
+    BoardDesynced,
+    /// A chat message. `{0}` is the username of the sender, and `{1}` the text.
+    ChatLine,
+    /// The other player is typing a chat message. `{0}` is their username.
+    OtherTyping,
+    /// The score of a match that isn't over. `{0}` is the amount of games in it, `{1}` the games
+    /// we won and `{2}` the games the other player won.
+    MatchScore,
+    /// We won the match. The arguments are those of `MatchScore`.
+    MatchWon,
+    /// The other player won the match. The arguments are those of `MatchScore`.
+    MatchLost,
+    /// The match ended with as many games won by each player. The arguments are those of
+    /// `MatchScore`.
+    MatchTied,
+    /// We resigned the match. The arguments are those of `MatchScore`.
+    MatchResignedByUs,
+    /// The other player resigned the match. The arguments are those of `MatchScore`.
+    MatchResignedByThem,
+    /// The analyzed game was copied as PDN.
+    PdnCopied,
+    /// The analyzed game couldn't be copied, so its PDN was printed instead.
+    PdnNotCopied,
+    /// The size of the board. `{0}` is the amount of squares along a side.
+    RuleBoardSize,
+    /// Capturing is mandatory.
+    RuleCaptureMandatory,
+    /// Capturing is optional.
+    RuleCaptureOptional,
+    /// The longest capture must be chosen.
+    RuleLongestCapture,
+    /// Any capture can be chosen.
+    RuleAnyCapture,
+    /// Kings move any distance.
+    RuleFlyingKings,
+    /// Kings move one square.
+    RuleShortKings,
+    /// Men capture backwards.
+    RuleMenCaptureBackwards,
+    /// Men only go forwards.
+    RuleMenCaptureForwards,
+    /// Promotion ends the move.
+    RulePromotionEndsCapture,
+    /// A man passing the last row keeps capturing as a man.
+    RulePromotionContinuesCapture,
+    /// A player who loses the connection forfeits. `{0}` is the seconds they have to come back.
+    RuleForfeitAfter,
+    /// The game waits for a player who loses the connection.
+    RulePauseForever,
+    /// Every game stands on its own.
+    RuleSingleGame,
+    /// The games are played as a match. `{0}` is the amount of games in it.
+    RuleMatchLength,
+    /// The username is empty.
+    UsernameEmpty,
+    /// The username doesn't fit in a connect request.
+    UsernameTooLong,
+    /// The username has a control character, like a newline.
+    UsernameControlCharacter,
+    /// The connectivity check found our address on the local network. `{0}` is the address.
+    DiagnosticsLocalIp,
+    /// The connectivity check found no local network.
+    DiagnosticsNoLocalIp,
+    /// Our address isn't on a local network. `{0}` is the address.
+    DiagnosticsNotPrivate,
+    /// The connectivity check found a port to host on. `{0}` is the port.
+    DiagnosticsPort,
+    /// The connectivity check found no port to host on.
+    DiagnosticsNoPort,
+    /// Every connectivity check passed.
+    DiagnosticsOk,
+    /// A connectivity check failed, but the player can go on.
+    DiagnosticsFailed,
+    /// How one player hosts and the other joins, shown the first time the game starts.
+    OnboardingHostOrJoin,
+    /// The host's board differs from ours after a reconnect, shown above a preview of it.
+    ResyncPreview,
+    /// The other player runs another major version of the game. {0} is theirs, {1} is ours.
+    VersionMismatch,
+}
+
+/// The languages the game ships a message catalog for. The picked one is stored in the profile.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Language {
+    #[default]
+    English,
+    Danish,
+}
+
+impl Language {
+    /// Returns an array to iterate over all enum values
+    pub const fn values() -> &'static [Language; 2] {
+        &[Language::English, Language::Danish]
+    }
+
+    /// The name of the language in the language itself, shown where it's picked.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::English => "English",
+            Self::Danish => "Dansk",
+        }
+    }
+
+    /// The language called `name`, as given by `name()`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::values()
+            .iter()
+            .copied()
+            .find(|lang| lang.name() == name)
+    }
+
+    /// The embedded TOML source of the languages message catalog
+    const fn catalog_source(&self) -> &'static str {
+        match self {
+            Self::English => include_str!("../../lang/en.toml"),
+            Self::Danish => include_str!("../../lang/da.toml"),
+        }
+    }
+}
+
+type Catalog = HashMap<MessageKey, String>;
+
+lazy_static! {
+    /// The parsed catalogs of every language. A catalog that fails to parse is left empty, so its
+    /// messages fall back to English.
+    static ref CATALOGS: HashMap<Language, Catalog> = Language::values()
+        .iter()
+        .map(|lang| {
+            let catalog = toml::from_str(lang.catalog_source()).unwrap_or_else(|e| {
+                println!("Failed to parse the {:?} message catalog: {}", lang, e);
+                HashMap::new()
+            });
+            (*lang, catalog)
+        })
+        .collect();
+}
+
+static LANGUAGE: Mutex<Language> = Mutex::new(Language::English);
+
+/// Gets the language used by `tr()`.
+pub fn get_language() -> Language {
+    *LANGUAGE.lock().unwrap()
+}
+
+/// Sets the language used by `tr()`.
+pub fn set_language(language: Language) {
+    *LANGUAGE.lock().unwrap() = language;
+}
+
+/// Translate a message into the current language.
+/// Every `{n}` in the message is replaced by the n'th element of `args`. If the current language
+/// doesn't have the message, the English one is used.
+///
+/// ## Params
+/// * `key` - The message to translate.
+/// * `args` - The values that gets interpolated into the message.
+pub fn tr(key: MessageKey, args: &[&dyn Display]) -> String {
+    let message = CATALOGS
+        .get(&get_language())
+        .and_then(|catalog| catalog.get(&key))
+        .or_else(|| CATALOGS.get(&Language::English)?.get(&key));
+
+    let mut message = match message {
+        Some(message) => message.clone(),
+        None => return format!("{:?}", key),
+    };
+
+    for (i, arg) in args.iter().enumerate() {
+        message = message.replace(&format!("{{{}}}", i), &arg.to_string());
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    /// The name of every `MessageKey`, read from its definition above.
+    fn key_names() -> BTreeSet<String> {
+        let source = include_str!("mod.rs");
+        let start = source.find("pub enum MessageKey {").unwrap();
+        source[start..]
+            .lines()
+            .skip(1)
+            .take_while(|line| *line != "}")
+            .map(str::trim)
+            .filter(|line| !line.starts_with("///"))
+            .map(|line| line.trim_end_matches(',').to_owned())
+            .collect()
+    }
+
+    /// The `{n}` placeholders of a message.
+    fn placeholders(message: &str) -> BTreeSet<usize> {
+        message
+            .split('{')
+            .skip(1)
+            .filter_map(|part| part.split_once('}')?.0.parse().ok())
+            .collect()
+    }
+
+    #[test]
+    fn every_key_is_in_every_catalog() {
+        let keys = key_names();
+        assert!(keys.contains("NoUsername") && keys.contains("VersionMismatch"));
+        for lang in Language::values() {
+            let catalog: HashMap<String, String> = toml::from_str(lang.catalog_source()).unwrap();
+            let names: BTreeSet<String> = catalog.keys().cloned().collect();
+            assert_eq!(names, keys, "the {:?} catalog", lang);
+            // Every name was read as its key
+            assert_eq!(CATALOGS[lang].len(), keys.len(), "the {:?} catalog", lang);
+        }
+    }
+
+    #[test]
+    fn every_catalog_takes_the_same_args() {
+        for (key, english) in &CATALOGS[&Language::English] {
+            for lang in Language::values() {
+                // A missing message fails `every_key_is_in_every_catalog`
+                let Some(message) = CATALOGS[lang].get(key) else {
+                    continue;
+                };
+                assert_eq!(
+                    placeholders(message),
+                    placeholders(english),
+                    "{:?} in the {:?} catalog",
+                    key,
+                    lang
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn args_are_interpolated() {
+        assert_eq!(
+            tr(MessageKey::TutorialTitle, &[&2, &10, &"Kings"]),
+            "Tutorial 2 / 10: Kings"
+        );
+        assert_eq!(
+            tr(MessageKey::ConnectRetrying, &[&3, &1, &5]),
+            "The host didn't answer. Trying again in 3 s (attempt 1 of 5)..."
+        );
+        // Missing args are left as they are, and extra ones are ignored
+        assert_eq!(tr(MessageKey::Ping, &[]), "Ping: {0} ms");
+        assert_eq!(tr(MessageKey::TutorialCorrect, &[&1]), "Correct!");
+    }
+}
@@ -1,2 +1,3 @@
 pub mod game;
+pub mod i18n;
 pub mod net;
@@ -0,0 +1,154 @@
+//! The locks of `status` and `p2p::queue`. They follow one rule: A task holds at most one of them
+//! at a time, and awaits nothing but the lock itself while holding it. So there is no order to
+//! take them in, and no two tasks can wait on each other. A callback is never run while one is
+//! held, since it could take another.
+//!
+//! Debug builds check the rule. Since a guard is never held across an await, it's dropped on the
+//! thread that took it, before the thread polls any other task. Each thread remembers the lock it
+//! holds, and panics when:
+//! - it takes a lock while it holds one, even the same one, which would never be released, or
+//! - it drops a guard of a lock it doesn't hold, which means the guard was held across an await,
+//!   and the task went on on another thread.
+//!
+//! Release builds check nothing, so a `CheckedMutex` is only a `tokio::sync::Mutex`.
+
+use std::{
+    cell::Cell,
+    ops::{Deref, DerefMut},
+    thread,
+};
+
+use tokio::sync::{Mutex, MutexGuard};
+
+thread_local! {
+    /// The name of the lock this thread holds, in debug builds.
+    static HELD: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+/// A `tokio::sync::Mutex` that follows the locking rule of the module.
+pub struct CheckedMutex<T> {
+    /// The name of the lock in the panics, e.g. `status.board`.
+    name: &'static str,
+    mutex: Mutex<T>,
+}
+
+impl<T> CheckedMutex<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Self {
+            name,
+            mutex: Mutex::const_new(value),
+        }
+    }
+
+    /// Wait for the lock. Panics in debug builds if this thread holds another checked lock, or this
+    /// one, which would never be released.
+    pub async fn lock(&self) -> CheckedGuard<'_, T> {
+        self.check_none_held();
+        let guard = self.mutex.lock().await;
+        self.check_none_held();
+        if cfg!(debug_assertions) {
+            HELD.with(|held| held.set(Some(self.name)));
+        }
+        CheckedGuard {
+            name: self.name,
+            guard,
+        }
+    }
+
+    fn check_none_held(&self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        if let Some(held) = HELD.with(Cell::get) {
+            panic!(
+                "Took the lock of {} while this thread holds {}",
+                self.name, held
+            );
+        }
+    }
+}
+
+/// The guard of a `CheckedMutex`. The lock is released when it's dropped.
+pub struct CheckedGuard<'a, T> {
+    name: &'static str,
+    guard: MutexGuard<'a, T>,
+}
+
+impl<T> Deref for CheckedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for CheckedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for CheckedGuard<'_, T> {
+    fn drop(&mut self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let held = HELD.with(Cell::take);
+        // A second panic while unwinding would abort, and hide the first one
+        if held != Some(self.name) && !thread::panicking() {
+            panic!("The lock of {} was held across an await", self.name);
+        }
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use futures::executor;
+
+    use super::*;
+
+    static FIRST: CheckedMutex<u8> = CheckedMutex::new("first", 0);
+    static SECOND: CheckedMutex<u8> = CheckedMutex::new("second", 0);
+
+    #[test]
+    fn locks_taken_one_at_a_time_are_fine() {
+        executor::block_on(async {
+            *FIRST.lock().await += 1;
+            *SECOND.lock().await += 1;
+            let first = FIRST.lock().await;
+            drop(first);
+            let _second = SECOND.lock().await;
+        });
+        assert_eq!(HELD.with(Cell::get), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Took the lock of second while this thread holds first")]
+    fn second_lock_panics() {
+        executor::block_on(async {
+            let _first = FIRST.lock().await;
+            let _second = SECOND.lock().await;
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Took the lock of first while this thread holds first")]
+    fn same_lock_twice_panics_instead_of_deadlocking() {
+        executor::block_on(async {
+            let _first = FIRST.lock().await;
+            let _again = FIRST.lock().await;
+        });
+    }
+
+    #[test]
+    fn guard_moved_to_another_thread_panics() {
+        let guard = executor::block_on(SECOND.lock());
+        let dropped = thread::spawn(move || drop(guard)).join();
+        let panic = dropped.expect_err("The guard was dropped on another thread");
+        assert_eq!(
+            panic.downcast_ref::<String>().map(String::as_str),
+            Some("The lock of second was held across an await")
+        );
+        HELD.with(|held| held.set(None));
+    }
+}
@@ -1,40 +1,109 @@
 use std::{
-    net::{IpAddr, SocketAddr},
-    sync::Arc,
-    time::Duration,
+    fmt::Write,
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::anyhow;
 use chrono::Utc;
 use futures::executor;
-use tokio::sync::Mutex;
+use thiserror::Error;
 
 use crate::{
-    game::{GameAction, PieceColor},
+    game::{self, history, GameAction, PieceColor, PieceData},
+    i18n::{tr, MessageKey},
     net::{
-        net_utils::{get_available_port, get_local_ip, hex_decode_ip, hex_encode_ip},
+        net_utils::{
+            classify_target, get_available_port, get_local_ip, hex_decode_ip, hex_encode_ip,
+            ToPacket,
+        },
         p2p::{
+            anomaly::{self, NetConfig},
+            capture::get_capture,
+            coin_flip::{self, Commitment},
+            desync,
             net_loop::{client_network_loop, host_network_loop},
+            peer_info::PeerInfo,
+            presence,
+            probe::{probe_peer, ProbeAnswer},
             queue::{
-                check_for_response, get_outgoing_queue_len, new_transaction_id,
-                pop_incoming_gameaction, push_outgoing_queue,
+                check_for_response, clear_gameaction_sequences, get_outgoing_queue_len,
+                get_transaction_table_len, is_host, new_sequence, pending_requests,
+                pop_incoming_chat, pop_incoming_gameaction, push_outgoing_queue, return_sequence,
+                Completion, TimedOut,
             },
-            P2pPacket, P2pRequest, P2pRequestPacket, P2pResponse, P2pResponsePacket,
+            resync::fetch_host_board,
+            runtime,
+            session::Session,
+            simulate, taken_moves,
+            throttle::Throttled,
+            vectors::{self, TestVector},
+            watchdog::stop_network_loop,
+            wire, normalize_username, NoteKind, P2pError, P2pPacket, P2pRequest,
+            P2pRequestPacket, P2pResponse, P2pResponsePacket,
         },
-        status,
+        session_log, status,
     },
 };
 
+pub use super::net_utils::TargetClass;
+pub use super::p2p::backoff::RetryPolicy;
+pub use super::p2p::pause::PauseState;
+pub use super::p2p::queue::{
+    outgoing_capacity, set_outgoing_capacity, NoTransactionId, QueueFull, MAX_OUTGOING,
+};
+pub use super::p2p::simulate::{Latency, NetworkSimulation, SimulationStats};
+pub use super::p2p::{
+    GameOverReason, ProtocolMismatch, UsernameError, MAX_CHAT_LEN, MAX_USERNAME_LEN,
+};
+pub use super::status::{
+    get_game_options, ping_micros, ping_millis, set_game_options, BoardSync, ConnectionStats,
+    GameResult, HostBoard, NetworkStats, OptionsState, PingLoss,
+};
+
+// The board types are only in `game`, since the older copies in `checkers_game` and `game_data`
+// were removed. This fails to build if the interface takes or hands out the types of another copy
+// again, or if the board of a resync no longer fits the board of the game.
+const _: () = {
+    let _: fn() -> Option<game::PieceColor> = get_my_color;
+    let _: fn() -> Option<game::GameAction> = get_next_game_action;
+    let _: fn(Vec<game::PieceData>) = publish_board;
+    assert!(wire::BOARD_LEN == game::square::BoardGeometry::CHECKERS.squares());
+};
+
+/// Bind the socket of the network loop. It's bound in the network runtime, so its IO isn't held
+/// up by whatever runs on the main runtime.
+fn bind_network_socket(port: u16) -> tokio::net::UdpSocket {
+    let _guard = runtime::enter();
+    executor::block_on(tokio::net::UdpSocket::bind(("0.0.0.0", port))).unwrap()
+}
+
 /// Start the host network peer on a LAN connection.
 /// Returns the join code for the client
 pub fn start_lan_host() -> String {
-    let port = executor::block_on(get_available_port()).unwrap();
-    let socket = executor::block_on(tokio::net::UdpSocket::bind(("0.0.0.0", port))).unwrap();
+    start_host(get_local_ip().unwrap())
+}
 
-    let local_ip = get_local_ip().unwrap();
+/// Start the host network peer, with a join code for `127.0.0.1`. Only a client on this computer
+/// can join, e.g. in a test.
+/// Returns the join code for the client
+pub fn start_loopback_host() -> String {
+    start_host(Ipv4Addr::LOCALHOST)
+}
+
+/// Start the host network peer, with a join code for `ip`.
+fn start_host(ip: Ipv4Addr) -> String {
+    let port = executor::block_on(get_available_port()).unwrap();
+    let socket = bind_network_socket(port);
 
-    let encoded_ip = hex_encode_ip(SocketAddr::new(IpAddr::V4(local_ip), port)).unwrap();
+    let encoded_ip = hex_encode_ip(SocketAddr::new(IpAddr::V4(ip), port)).unwrap();
     executor::block_on(status::set_join_code(&encoded_ip));
+    executor::block_on(status::set_coin_nonce(coin_flip::new_nonce()));
+    executor::block_on(status::set_options_state(OptionsState::Pending));
 
     executor::block_on(status::set_connection_status(
         status::ConnectionStatus::PendingConnection,
@@ -48,7 +117,7 @@ pub fn start_lan_host() -> String {
 /// Start the client network peer on a LAN connection.
 pub fn start_lan_client() {
     let port = executor::block_on(get_available_port()).unwrap();
-    let socket = executor::block_on(tokio::net::UdpSocket::bind(("0.0.0.0", port))).unwrap();
+    let socket = bind_network_socket(port);
 
     executor::block_on(status::set_connection_status(
         status::ConnectionStatus::PendingConnection,
@@ -64,35 +133,53 @@ pub fn start_lan_client() {
 ///
 /// ## Params
 /// * `join_code` - The join code sent by the host.
-/// * `username` - The clients username.
-pub fn send_join_request(join_code: &str, username: &str) -> u16 {
-    let join_request = P2pRequest::new(
-        status::CONNECT_SESSION_ID,
-        executor::block_on(new_transaction_id()),
-        P2pRequestPacket::Connect {
-            join_code: join_code.to_owned(),
-            username: username.to_owned(),
-        },
-    );
-    let host_addr = hex_decode_ip(join_code).unwrap();
+/// * `username` - The clients username. It isn't sent when playing anonymously.
+/// * `nonce` - The clients nonce for the coin flip. See `coin_flip`.
+/// * `timeout` - How long the answer of the host is kept waited for. After it, the request is
+///   forgotten, and `check_for_connection_resp()` never gets its answer.
+pub fn send_join_request(
+    join_code: &str,
+    username: &str,
+    nonce: u64,
+    timeout: Duration,
+) -> anyhow::Result<u16> {
+    let host_addr = hex_decode_ip(join_code)?;
     println!("Asking to join Host at {:?}", host_addr);
 
+    // The host makes a handle for us, once the session has an ID
+    let username = match executor::block_on(status::is_anonymous()) {
+        true => "",
+        false => normalize_username(username)?,
+    };
+    let preference = executor::block_on(status::get_color_preference());
+    let packet = P2pRequestPacket::connect(join_code, username, nonce, preference)?;
+
     println!("Pushing to queue");
 
-    executor::block_on(push_outgoing_queue(
-        P2pPacket::Request(join_request.clone()),
-        None,
-    ))
+    executor::block_on(async {
+        Session::connect_request(packet)
+            .await
+            .timeout(timeout)
+            .send()
+            .await
+    })
 }
 
 /// Check if the connection request sent with `send_join_request()` has gotten an response.
 /// If a packet has been recieved, and if that packet is a correct response, the function will
 /// return the clients assigned piece color, as well as the hosts username.
+/// The color must match the coin flip, or be the color we wish to play, and the host's nonce must
+/// match its commitment. Otherwise an error is returned. A host running a version of the game we
+/// can't talk to gives a `ProtocolMismatch`.
 ///
 /// ## Params
 /// * `transaction_id` - The id of the join request
+/// * `nonce` - The clients nonce for the coin flip, sent in the join request.
+/// * `commitment` - The hosts commitment to its nonce, from its answer to our probe.
 pub fn check_for_connection_resp(
     transaction_id: u16,
+    nonce: u64,
+    commitment: &Commitment,
 ) -> Option<anyhow::Result<(PieceColor, String)>> {
     println!("Checking for resp");
     match executor::block_on(check_for_response(transaction_id)) {
@@ -101,66 +188,524 @@ pub fn check_for_connection_resp(
                 P2pResponsePacket::Connect {
                     client_color,
                     host_username,
+                    host_nonce,
+                    peer_info,
                 } => {
                     println!("Got resp");
+                    let preference = executor::block_on(status::get_color_preference());
+                    if !coin_flip::is_fair(commitment, nonce, host_nonce, preference, client_color)
+                    {
+                        println!("The hosts coin flip doesn't match its commitment");
+                        return Some(Err(anyhow!(tr(MessageKey::CoinFlipMismatch, &[]))));
+                    }
+                    executor::block_on(status::set_my_color(client_color));
                     executor::block_on(status::set_connection_status(
                         status::ConnectionStatus::connected(),
                     ));
                     println!("Set connection status");
                     executor::block_on(status::set_session_id(resp.session_id));
+                    executor::block_on(status::set_move_number(0));
+                    executor::block_on(taken_moves::clear());
+                    executor::block_on(clear_gameaction_sequences());
+                    executor::block_on(desync::clear());
+                    executor::block_on(status::set_game_finished(false));
                     println!("Set session id");
+                    // The host's username is shown like our own, so it must obey the same rules
+                    let host_username = match normalize_username(&host_username) {
+                        Ok(host_username) => host_username.to_owned(),
+                        Err(e) => {
+                            println!("Can't use the hosts username {:?}: {}", host_username, e);
+                            tr(MessageKey::DefaultHostUsername, &[])
+                        }
+                    };
                     executor::block_on(status::set_other_username(&host_username));
+                    executor::block_on(status::set_other_peer_info(peer_info));
+                    executor::block_on(status::set_other_left(false));
                     println!("Set username");
                     Some(Ok((client_color, host_username)))
                 }
-                P2pResponsePacket::Error { kind } => {
-                    Some(Err(anyhow!("Got Error response: {:?}", kind)))
+                P2pResponsePacket::Error {
+                    kind: P2pError::ProtocolMismatch,
+                } => Some(Err(ProtocolMismatch.into())),
+                P2pResponsePacket::Error { kind } => Some(Err(anyhow!(tr(
+                    MessageKey::ErrorResponse,
+                    &[&format!("{:?}", kind)]
+                )))),
+                P2pResponsePacket::RetryLater { retry_after_ms, .. } => Some(Err(Throttled {
+                    retry_after: Duration::from_millis(retry_after_ms.into()),
                 }
-                _ => Some(Err(anyhow!("Got wrong response Packet"))),
+                .into())),
+                _ => Some(Err(anyhow!(tr(MessageKey::WrongResponsePacket, &[])))),
             },
-            _ => Some(Err(anyhow!("Got request packet instead of response"))),
+            _ => Some(Err(anyhow!(tr(MessageKey::RequestInsteadOfResponse, &[])))),
         },
-        None => {
-            println!("Got no resp :(");
-            None
-        }
+        None => None,
     }
 }
 
-/// A blocking function which sends a join request to the host, and waits for a response. The
-/// function is in a loop, so if a packet goes lost, it will send a new one after 5 seconds.
+/// How long a join request waits for the answer of the host, before the attempt counts as failed.
+/// An answer from across the internet can take longer than the `REQUEST_TIMEOUT_MS` of a ping.
+const JOIN_TIMEOUT_MS: u64 = 5_000;
+const CONNECTION_TICK_MS: u64 = 500;
+
+/// The attempts of `connect_to_host_loop()` to join a host: 5 attempts, 1, 2, 4 and 8 seconds
+/// apart.
+pub const JOIN_RETRY: RetryPolicy = RetryPolicy {
+    initial: Duration::from_secs(1),
+    multiplier: 2.0,
+    max_interval: Duration::from_secs(8),
+    max_attempts: 5,
+};
+
+/// How far `connect_to_host_loop()` has come, after an attempt to join failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectProgress {
+    /// The attempt that failed, counted from 1.
+    pub attempt: u32,
+    /// The amount of attempts made before giving up.
+    pub max_attempts: u32,
+    /// How long until the next attempt.
+    pub retry_in: Duration,
+}
+
+/// The error when `connect_to_host_loop()` gives up on joining the host.
+#[derive(Debug, Error)]
+pub enum ConnectError {
+    /// The host didn't answer any of the attempts.
+    #[error("{}", tr(MessageKey::ConnectTimedOut, &[attempts]))]
+    Timeout { attempts: u32 },
+}
+
+/// A blocking function which tries to join the host, and waits for a response. An attempt the
+/// host doesn't answer, e.g. because a packet got lost, is made again after the delay from
+/// `policy`. Gives up with a `ConnectError::Timeout` once the attempts run out.
+/// The host is probed first, to get its commitment for the coin flip deciding the colors.
+/// Gives up with a `ProtocolMismatch` if the host runs a version of the game we can't talk to.
 ///
 /// ## Params
 /// * `join_code` - The join code sent by the host.
 /// * `username` - The clients username.
+/// * `policy` - How many times, and how far apart, to try. See `JOIN_RETRY`.
+/// * `on_progress` - Called after each failed attempt, before waiting for the next one.
 pub fn connect_to_host_loop(
     join_code: &str,
     username: &str,
+    policy: RetryPolicy,
+    mut on_progress: impl FnMut(ConnectProgress),
 ) -> anyhow::Result<(PieceColor, String)> {
+    // A join code that can't be decoded, or that points to where no host can be, is refused
+    // before anything is set up for it
+    let host_addr = hex_decode_ip(join_code)?;
+    if classify_target(host_addr, None).is_invalid() {
+        return Err(anyhow!(tr(MessageKey::JoinTargetInvalid, &[&host_addr])));
+    }
     executor::block_on(status::set_join_code(join_code));
-    let host_addr = hex_decode_ip(join_code).unwrap();
     executor::block_on(status::set_other_addr(host_addr));
-    set_my_username(username);
+    set_my_username(username)?;
     println!("Starting to connect...");
-    let mut connection_tick = tokio::time::interval(Duration::from_millis(500));
+    let nonce = coin_flip::new_nonce();
+    let mut commitment = None;
+    let mut attempt = 0;
     loop {
-        let join_id = send_join_request(join_code, username);
+        attempt += 1;
+        if let Some(joined) = try_to_join(join_code, username, nonce, &mut commitment)? {
+            confirm_options()?;
+            return Ok(joined);
+        }
 
-        let time = Utc::now();
-        println!("Request sent at {:?}", time.to_string());
-        print!(
-            "Queue len: {}",
-            executor::block_on(get_outgoing_queue_len())
+        let Some(retry_in) = policy.delay(attempt) else {
+            println!("Giving up on the host after {} attempts", attempt);
+            return Err(ConnectError::Timeout { attempts: attempt }.into());
+        };
+        println!(
+            "Attempt {} of {} to join the host failed, trying again in {:?}",
+            attempt, policy.max_attempts, retry_in
         );
-        println!("!!!");
+        on_progress(ConnectProgress {
+            attempt,
+            max_attempts: policy.max_attempts,
+            retry_in,
+        });
+        thread::sleep(retry_in);
+    }
+}
+
+/// Make one attempt to join the host: probe it for its commitment if we don't have it yet, and
+/// send a join request. Returns `None` if the host didn't answer in time, or asked us to wait.
+///
+/// ## Params
+/// * `join_code` - The join code sent by the host.
+/// * `username` - The clients username.
+/// * `nonce` - The clients nonce for the coin flip.
+/// * `commitment` - The hosts commitment, kept for the next attempts once it has answered a probe.
+fn try_to_join(
+    join_code: &str,
+    username: &str,
+    nonce: u64,
+    commitment: &mut Option<Commitment>,
+) -> anyhow::Result<Option<(PieceColor, String)>> {
+    let host_addr = hex_decode_ip(join_code)?;
+    let commitment = match *commitment {
+        Some(commitment) => commitment,
+        None => {
+            let probe = probe_peer(host_addr, Duration::from_millis(PROBE_TIMEOUT_MS));
+            match executor::block_on(probe) {
+                Ok(ProbeAnswer {
+                    hosting: true,
+                    commitment: Some(answer),
+                }) => *commitment.insert(answer),
+                Ok(answer) => {
+                    println!("The host didn't commit to a coin flip: {:?}", answer);
+                    return Ok(None);
+                }
+                Err(e) if e.is::<ProtocolMismatch>() => return Err(e),
+                Err(e) => {
+                    println!("Probing the host failed: {}", e);
+                    wait_if_throttled(&e);
+                    return Ok(None);
+                }
+            }
+        }
+    };
+
+    let timeout = Duration::from_millis(JOIN_TIMEOUT_MS);
+    let join_id = send_join_request(join_code, username, nonce, timeout)?;
+
+    let time = Utc::now();
+    println!("Request sent at {:?}", time.to_string());
+
+    let mut connection_tick = tokio::time::interval(Duration::from_millis(CONNECTION_TICK_MS));
+    for _ in 0..JOIN_TIMEOUT_MS / CONNECTION_TICK_MS {
+        executor::block_on(connection_tick.tick());
+        match check_for_connection_resp(join_id, nonce, &commitment) {
+            Some(Err(e)) if wait_if_throttled(&e) => return Ok(None),
+            Some(resp) => return resp.map(Some),
+            None => (),
+        }
+    }
+    Ok(None)
+}
+
+/// If `e` is the host asking us to try again later, wait as long as it asked for.
+/// Returns true if it was, so the request can be sent again.
+fn wait_if_throttled(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<Throttled>() {
+        Some(throttled) => {
+            println!("{}", throttled);
+            thread::sleep(throttled.retry_after);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The error when the host plays with other `GameOptions` than we do. The game isn't started, and
+/// the peers disconnect.
+#[derive(Debug, Error)]
+#[error("{}", tr(MessageKey::OptionsMismatch, &[]))]
+pub struct OptionsMismatch;
 
-        for _ in 0..10 {
-            executor::block_on(connection_tick.tick());
-            if let Some(resp) = check_for_connection_resp(join_id) {
-                return resp;
+/// How many times the client sends `OptionsAck`, before giving up.
+const OPTIONS_ACK_TRIES: usize = 3;
+const OPTIONS_ACK_TIMEOUT_MS: u64 = 1_000;
+
+/// Confirm to the host that we play with the same `GameOptions`, after connecting. The game may
+/// only start if this succeeds. If the host has other options, we disconnect, and an
+/// `OptionsMismatch` is returned.
+fn confirm_options() -> anyhow::Result<()> {
+    let options_hash = get_game_options().options_hash();
+    for _ in 0..OPTIONS_ACK_TRIES {
+        let response = executor::block_on(async {
+            Session::request(P2pRequestPacket::OptionsAck { options_hash })
+                .await
+                .send_and_wait(Duration::from_millis(OPTIONS_ACK_TIMEOUT_MS))
+                .await
+        });
+        match response {
+            Ok(P2pResponse {
+                packet: P2pResponsePacket::Acknowledge,
+                ..
+            }) => {
+                executor::block_on(status::set_options_state(OptionsState::Agreed));
+                return Ok(());
+            }
+            Ok(P2pResponse {
+                packet:
+                    P2pResponsePacket::Error {
+                        kind: P2pError::OptionsMismatch,
+                    },
+                ..
+            }) => {
+                println!("The host plays with other options. Disconnecting.");
+                executor::block_on(async {
+                    status::set_options_state(OptionsState::Mismatch).await;
+                    status::set_connection_status(status::ConnectionStatus::Disconnected).await;
+                    status::remove_other_addr().await;
+                    status::remove_other_username().await;
+                    status::remove_other_peer_info().await;
+                    status::set_session_id(status::CONNECT_SESSION_ID).await;
+                });
+                return Err(OptionsMismatch.into());
+            }
+            Ok(resp) => {
+                println!(
+                    "Expected an acknowledge of the options, got {:?}",
+                    resp.packet
+                );
+                return Err(anyhow!(tr(MessageKey::WrongResponsePacket, &[])));
             }
+            Err(e) => println!("No answer to OptionsAck: {}", e),
         }
     }
+    Err(anyhow!(tr(MessageKey::OptionsUnconfirmed, &[])))
+}
+
+/// If the peers have agreed on the `GameOptions`, so the game can start. The host waits for this
+/// before starting the game.
+pub fn get_options_state() -> OptionsState {
+    executor::block_on(status::get_options_state())
+}
+
+/// Decode the address of the host from a join code.
+///
+/// ## Params
+/// * `join_code` - The join code sent by the host.
+pub fn decode_join_code(join_code: &str) -> anyhow::Result<SocketAddr> {
+    hex_decode_ip(join_code)
+}
+
+/// Decode a join code, and tell what kind of address it points to. See `classify_target()`.
+///
+/// ## Params
+/// * `join_code` - The join code sent by the host.
+/// * `own_join_code` - Our own join code, if we are hosting.
+pub fn classify_join_code(
+    join_code: &str,
+    own_join_code: Option<&str>,
+) -> anyhow::Result<(SocketAddr, TargetClass)> {
+    let addr = hex_decode_ip(join_code)?;
+    let own = own_join_code.and_then(|own| hex_decode_ip(own).ok());
+    let class = classify_target(addr, own);
+    println!("Join code {} points to {} ({:?})", join_code, addr, class);
+    Ok((addr, class))
+}
+
+/// Check that `username` can be used. It must have something besides whitespace, no control
+/// characters, and be at most `MAX_USERNAME_LEN` bytes once trimmed. The host refuses a client
+/// with any other username.
+pub fn validate_username(username: &str) -> Result<(), UsernameError> {
+    normalize_username(username).map(|_| ())
+}
+
+/// What `run_diagnostics()` found out about playing over LAN.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Diagnostics {
+    /// Our address on the local network, if we have one.
+    pub local_ip: Option<Ipv4Addr>,
+    /// A free port to host on, if there is one.
+    pub port: Option<u16>,
+}
+
+impl Diagnostics {
+    /// If `local_ip` is on a local network, where other players can reach it.
+    pub fn is_private(&self) -> bool {
+        self.local_ip.is_some_and(|ip| {
+            classify_target(SocketAddr::new(IpAddr::V4(ip), 1), None) == TargetClass::Private
+        })
+    }
+
+    /// If LAN play should work.
+    pub fn is_ok(&self) -> bool {
+        self.is_private() && self.port.is_some()
+    }
+
+    /// A line for the user for each check, and a verdict at the end.
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = vec![match self.local_ip {
+            Some(ip) if self.is_private() => tr(MessageKey::DiagnosticsLocalIp, &[&ip]),
+            Some(ip) => tr(MessageKey::DiagnosticsNotPrivate, &[&ip]),
+            None => tr(MessageKey::DiagnosticsNoLocalIp, &[]),
+        }];
+        lines.push(match self.port {
+            Some(port) => tr(MessageKey::DiagnosticsPort, &[&port]),
+            None => tr(MessageKey::DiagnosticsNoPort, &[]),
+        });
+        lines.push(match self.is_ok() {
+            true => tr(MessageKey::DiagnosticsOk, &[]),
+            false => tr(MessageKey::DiagnosticsFailed, &[]),
+        });
+        lines
+    }
+}
+
+/// Check if this computer can play over LAN: If it has an address on a local network, and a free
+/// port to host on. Nothing is sent. This blocks while the ports are tried.
+pub fn run_diagnostics() -> Diagnostics {
+    let local_ip = get_local_ip()
+        .inspect_err(|e| println!("Diagnostics: No local IP: {}", e))
+        .ok();
+    let port = {
+        let _guard = runtime::enter();
+        executor::block_on(get_available_port())
+            .inspect_err(|e| println!("Diagnostics: No free port: {}", e))
+            .ok()
+    };
+    let diagnostics = Diagnostics { local_ip, port };
+    println!("Diagnostics: {:?}", diagnostics);
+    diagnostics
+}
+
+/// How long to wait for an answer to a probe.
+const PROBE_TIMEOUT_MS: u64 = 1_000;
+
+/// Ask the peer with the join code `join_code` if it's hosting a game. Returns an error if it
+/// doesn't answer.
+///
+/// ## Params
+/// * `join_code` - The join code of the peer.
+pub fn probe(join_code: &str) -> anyhow::Result<bool> {
+    let addr = hex_decode_ip(join_code)?;
+    let answer = executor::block_on(probe_peer(addr, Duration::from_millis(PROBE_TIMEOUT_MS)))?;
+    Ok(answer.hosting)
+}
+
+/// Get the color we play, once the coin flip is done.
+pub fn get_my_color() -> Option<PieceColor> {
+    executor::block_on(status::get_my_color())
+}
+
+/// The payload sizes `probe_path()` is normally called with.
+pub const PATH_PROBE_SIZES: [usize; 4] = [64, 256, 512, 1024];
+
+/// How long to wait for the pong to each ping of a path probe.
+const PATH_PROBE_TIMEOUT_MS: u64 = 1_000;
+
+/// The result of `probe_path()`.
+#[derive(Clone, Debug)]
+pub struct PathReport {
+    /// Each payload size, and if its ping came back with the payload intact.
+    pub results: Vec<(usize, bool)>,
+    /// The largest payload size that came back, if any did.
+    pub largest: Option<usize>,
+}
+
+/// Check which packet sizes get to the other user and back, since some networks drop larger UDP
+/// packets. A ping is sent with a random payload of each size, which the other user echoes. Each
+/// ping has its own timeout, so a dropped size doesn't affect the others.
+/// The largest size that came back is saved, and shown in the debug bundle.
+///
+/// ## Params
+/// * `sizes` - The payload sizes to try, e.g. `PATH_PROBE_SIZES`.
+pub fn probe_path(sizes: &[usize]) -> PathReport {
+    let results: Vec<(usize, bool)> = sizes
+        .iter()
+        .map(|&size| (size, executor::block_on(ping_with_payload(size))))
+        .collect();
+    let largest = results
+        .iter()
+        .filter(|(_, echoed)| *echoed)
+        .map(|(size, _)| *size)
+        .max();
+
+    executor::block_on(status::set_path_mtu(largest));
+    PathReport { results, largest }
+}
+
+/// Ping the other user with a random payload of `size` bytes. Returns if it was echoed back.
+async fn ping_with_payload(size: usize) -> bool {
+    let payload: Vec<u8> = (0..size).map(|_| rand::random()).collect();
+    let packet = match P2pRequestPacket::ping_with_payload(payload.clone()) {
+        Ok(packet) => packet,
+        Err(e) => {
+            println!("Can't ping with {} bytes: {}", size, e);
+            return false;
+        }
+    };
+
+    match Session::request(packet)
+        .await
+        .send_and_wait(Duration::from_millis(PATH_PROBE_TIMEOUT_MS))
+        .await
+    {
+        Ok(P2pResponse {
+            packet: P2pResponsePacket::Pong { payload: echo },
+            ..
+        }) => echo == payload,
+        Ok(resp) => {
+            println!("Expected a pong, got {:?}", resp.packet);
+            false
+        }
+        Err(e) => {
+            println!("No pong to a {} byte ping: {}", size, e);
+            false
+        }
+    }
+}
+
+/// Stop hosting, and join the game with the join code `join_code` as a client instead. This is
+/// used when both users clicked host. The anonymous setting is kept.
+/// Like `connect_to_host_loop()`, this blocks until the host answers, or the attempts run out.
+///
+/// ## Params
+/// * `join_code` - The join code sent by the other host.
+/// * `username` - The users username.
+/// * `policy` - How many times, and how far apart, to try joining.
+/// * `on_progress` - Called after each failed attempt to join.
+pub fn join_as_client_from_host(
+    join_code: &str,
+    username: &str,
+    policy: RetryPolicy,
+    on_progress: impl FnMut(ConnectProgress),
+) -> anyhow::Result<(PieceColor, String)> {
+    println!("Stopping the host, to join {} as client", join_code);
+    executor::block_on(async {
+        stop_network_loop().await;
+        status::set_connection_status(status::ConnectionStatus::Disconnected).await;
+        status::remove_other_addr().await;
+        status::remove_other_username().await;
+        status::remove_other_peer_info().await;
+        status::remove_coin_nonce().await;
+        status::set_session_id(status::CONNECT_SESSION_ID).await;
+    });
+
+    start_lan_client();
+    connect_to_host_loop(join_code, username, policy, on_progress)
+}
+
+/// How many times `disconnect()` sends `Disconnect`, before leaving without an answer.
+const DISCONNECT_TRIES: usize = 2;
+const DISCONNECT_TIMEOUT_MS: u64 = 250;
+
+/// Leave the game, e.g. when the window is closed. The other user is told, so it doesn't have to
+/// wait for the connection to time out, and the network loop is stopped. If the other user doesn't
+/// answer, we leave anyway, and it finds out when our pings stop.
+pub fn disconnect() {
+    executor::block_on(async {
+        if status::get_other_addr().await.is_some() {
+            for _ in 0..DISCONNECT_TRIES {
+                let response = Session::request(P2pRequestPacket::Disconnect)
+                    .await
+                    .send_and_wait(Duration::from_millis(DISCONNECT_TIMEOUT_MS))
+                    .await;
+                match response {
+                    Ok(_) => break,
+                    Err(e) => println!("The other user didn't answer our disconnect: {}", e),
+                }
+            }
+        }
+        stop_network_loop().await;
+        status::set_connection_status(status::ConnectionStatus::Disconnected).await;
+        status::remove_other_addr().await;
+        status::remove_other_username().await;
+        status::remove_other_peer_info().await;
+        status::remove_coin_nonce().await;
+        status::set_session_id(status::CONNECT_SESSION_ID).await;
+    });
+}
+
+/// A message to show the user if the other user has left the game, and `None` if it hasn't.
+pub fn opponent_left_message() -> Option<String> {
+    executor::block_on(status::get_other_left()).then(|| tr(MessageKey::OpponentLeft, &[]))
 }
 
 /// Get the next game action from the other user.
@@ -168,16 +713,310 @@ pub fn get_next_game_action() -> Option<GameAction> {
     executor::block_on(pop_incoming_gameaction())
 }
 
+/// How long to wait for the other user to acknowledge the end of the game, before telling it again.
+const GAME_OVER_TIMEOUT_MS: u64 = 1_000;
+/// How many times the end of the game is told, before the other user is given up on.
+const GAME_OVER_ATTEMPTS: usize = 5;
+
+/// Tell the other user the game has ended. It's sent again until the other user acknowledges it,
+/// so it isn't lost with a packet. Blocks until then, and returns an error if it never was.
+///
+/// ## Params
+/// * `winner` - The color of the player who won, or `None` if the game ended in a draw.
+/// * `reason` - Why the game ended.
+pub fn send_game_over(winner: Option<PieceColor>, reason: GameOverReason) -> anyhow::Result<()> {
+    executor::block_on(async {
+        status::set_game_finished(true).await;
+        for attempt in 1..=GAME_OVER_ATTEMPTS {
+            match Session::request(P2pRequestPacket::game_over(winner, reason))
+                .await
+                .send_and_wait(Duration::from_millis(GAME_OVER_TIMEOUT_MS))
+                .await
+            {
+                Ok(P2pResponse {
+                    packet: P2pResponsePacket::Acknowledge,
+                    ..
+                }) => return Ok(()),
+                Ok(resp) => {
+                    return Err(anyhow!(
+                        "The end of the game wasn't taken: {:?}",
+                        resp.packet
+                    ))
+                }
+                Err(e) => println!(
+                    "The end of the game wasn't acknowledged, attempt {}: {}",
+                    attempt, e
+                ),
+            }
+        }
+        Err(anyhow!(
+            "The other user never acknowledged the end of the game"
+        ))
+    })
+}
+
+/// Take how the game ended, if the other user has told it, or lost the game by not coming back in
+/// time, since the last call.
+pub fn take_game_result() -> Option<GameResult> {
+    executor::block_on(status::take_game_result())
+}
+
+/// The error `offer_rematch()` gives its closure, when the other user's game hasn't ended yet.
+#[derive(Debug, Error)]
+#[error("{}", tr(MessageKey::RematchNotOver, &[]))]
+pub struct GameInProgress;
+
+/// A rematch offered by the other user, waiting for an answer. See `answer_rematch()`.
+#[derive(Debug)]
+pub struct RematchOffer {
+    request: P2pRequest,
+}
+
+/// Reset the state of the session for a new game, where we play `color`.
+async fn start_rematch(color: PieceColor) {
+    // The colors are swapped instead of flipped, but each game still gets its own nonce
+    if is_host() {
+        status::set_coin_nonce(coin_flip::new_nonce()).await;
+    }
+    status::set_my_color(color).await;
+    status::set_move_number(0).await;
+    taken_moves::clear().await;
+    desync::clear().await;
+    status::set_game_finished(false).await;
+    println!("Starting a rematch as {:?}", color);
+}
+
+/// Offer the other user a rematch, after the game has ended. The session is kept, and the players
+/// swap colors in the new game. The function is not blocking the thread until it gets an answer,
+/// which can take as long as the other user takes to decide.
+///
+/// ## Params
+/// * `on_answer` - The closure that will be called with the answer: The color we play in the new
+///   game, or `None` if the other user declined. If their game hasn't ended, the error is a
+///   `GameInProgress`. The new game has to be started on the board with `Board::start_new_game`.
+///   If the outgoing queue is full, it's called right away with a `QueueFull`, and if every
+///   transaction ID is waiting for a response, with a `NoTransactionId`.
+pub fn offer_rematch<F>(on_answer: F)
+where
+    F: FnMut(anyhow::Result<Option<PieceColor>>) + Send + Sync + 'static,
+{
+    // Shared, since the closure is called here if the offer can't be queued
+    let on_answer = Arc::new(Mutex::new(on_answer));
+    let on_queue_full = on_answer.clone();
+    let callback = move |resp: Result<P2pResponse, TimedOut>| {
+        let mut on_answer = on_answer.lock().unwrap();
+        take_rematch_answer(resp, &mut *on_answer)
+    };
+
+    let sent = executor::block_on(async {
+        Session::request(P2pRequestPacket::RematchOffer)
+            .await
+            .on_response(callback)
+            .send()
+            .await
+    });
+    if let Err(e) = sent {
+        println!("The rematch offer wasn't sent: {}", e);
+        (on_queue_full.lock().unwrap())(Err(e));
+    }
+}
+
+/// Hand the answer to a rematch offer to the closure of `offer_rematch()`.
+fn take_rematch_answer(
+    resp: Result<P2pResponse, TimedOut>,
+    on_answer: &mut impl FnMut(anyhow::Result<Option<PieceColor>>),
+) {
+    match resp.map(|resp| resp.packet) {
+        Ok(P2pResponsePacket::RematchAnswer {
+            offerer_color: color,
+        }) => {
+            executor::block_on(start_rematch(color));
+            on_answer(Ok(Some(color)));
+        }
+        Ok(P2pResponsePacket::RematchDecline) => on_answer(Ok(None)),
+        Ok(P2pResponsePacket::Error {
+            kind: P2pError::GameInProgress,
+        }) => on_answer(Err(GameInProgress.into())),
+        Ok(P2pResponsePacket::Error { kind }) => on_answer(Err(anyhow!(tr(
+            MessageKey::ErrorResponse,
+            &[&format!("{:?}", kind)]
+        )))),
+        Ok(packet) => {
+            println!("Expected an answer to the rematch, got {:?}", packet);
+            on_answer(Err(anyhow!(tr(MessageKey::WrongResponsePacket, &[]))));
+        }
+        Err(e) => on_answer(Err(e.into())),
+    }
+}
+
+/// Take the rematch the other user offered since the last call, if they did. It must be answered
+/// with `answer_rematch()`, since the other user waits for the answer.
+pub fn take_rematch_offer() -> Option<RematchOffer> {
+    executor::block_on(status::take_rematch_offer()).map(|request| RematchOffer { request })
+}
+
+/// Answer a rematch offered by the other user. An accepted rematch starts a new game in the same
+/// session, where the players swap colors. Returns the color we play in the new game, or `None`
+/// if it was declined. The new game has to be started on the board with `Board::start_new_game`.
+///
+/// ## Params
+/// * `offer` - The offer from `take_rematch_offer()`.
+/// * `accept` - If the rematch is accepted.
+pub fn answer_rematch(offer: RematchOffer, accept: bool) -> Option<PieceColor> {
+    executor::block_on(async {
+        // A rematch can't swap colors we never got
+        let color = match status::get_my_color().await {
+            Some(color) if accept => Some(color.get_opposite()),
+            _ => None,
+        };
+        if let Some(color) = color {
+            start_rematch(color).await;
+        }
+
+        // The other user plays the color we had
+        let packet = match color {
+            Some(color) => P2pResponsePacket::RematchAnswer {
+                offerer_color: color.get_opposite(),
+            },
+            None => P2pResponsePacket::RematchDecline,
+        };
+        let response = Session::respond_to(&offer.request, packet).await;
+        let pushed = push_outgoing_queue(P2pPacket::Response(response), Completion::Keep, None);
+        if let Err(e) = pushed.await {
+            println!("The answer to the rematch wasn't sent: {}", e);
+        }
+        color
+    })
+}
+
+/// Resign the rest of the match, which the other user wins. It can be sent during a game, which
+/// has to be ended with `send_game_over()` first, or after one. See `game::match_state`.
+///
+/// ## Params
+/// * `on_response` - The closure that will be called when the other user took it, like the one of
+///   `send_game_action()`.
+pub fn resign_match<F>(on_response: F)
+where
+    F: FnMut(anyhow::Result<()>) + Send + Sync + 'static,
+{
+    send_game_action(GameAction::ResignMatch, on_response)
+}
+
+/// If the game is paused, and the request about it that waits for an answer. Either user asks with
+/// a `GameAction::PauseRequest` or `GameAction::ResumeRequest`, and the other answers with a
+/// `GameAction::PauseResponse`, all sent with `send_game_action()`.
+pub fn get_pause() -> PauseState {
+    executor::block_on(status::get_pause())
+}
+
+/// Take if the other user resigned the match since the last call.
+pub fn take_match_resigned() -> bool {
+    executor::block_on(status::take_match_resigned())
+}
+
+/// Send a chat message to the other user. The message isn't sent again if it's lost.
+/// Returns a `PacketError::DataError` if it's longer than `MAX_CHAT_LEN` bytes, a `QueueFull` if
+/// the outgoing queue is full, and a `NoTransactionId` if every transaction ID is waiting.
+///
+/// ## Params
+/// * `message` - The text of the message.
+pub fn send_chat_message(message: &str) -> anyhow::Result<()> {
+    let packet = P2pRequestPacket::chat(message)?;
+    // The next message is told to be typed right away
+    presence::reset_typing_throttle();
+    executor::block_on(async {
+        Session::request(packet)
+            .await
+            .on_response(|resp| {
+                if let Ok(P2pResponsePacket::Error { kind }) = resp.map(|resp| resp.packet) {
+                    println!("The chat message wasn't taken: {:?}", kind);
+                }
+            })
+            .send()
+            .await
+    })?;
+    Ok(())
+}
+
+/// Get the next chat message from the other user, as the username of the sender and the text.
+pub fn get_next_chat_message() -> Option<(String, String)> {
+    executor::block_on(pop_incoming_chat())
+}
+
+/// Tell the other user that this user is typing a chat message. Call it on every edit of the
+/// message; at most one note is sent every `presence::TYPING_INTERVAL`, and a lost one isn't sent
+/// again.
+pub fn notify_typing() {
+    executor::block_on(async {
+        if status::get_other_addr().await.is_none() || !presence::try_send_typing(Instant::now()) {
+            return;
+        }
+        let packet = P2pRequestPacket::StatusNote {
+            kind: NoteKind::Typing,
+        };
+        if let Err(e) = Session::request(packet).await.send().await {
+            println!("The typing note wasn't sent: {}", e);
+        }
+    })
+}
+
+/// The text telling that the other user is typing a chat message, or an empty string if they
+/// aren't.
+pub fn typing_text() -> String {
+    if !presence::is_other_typing(Instant::now()) {
+        return String::new();
+    }
+    let username =
+        executor::block_on(status::get_other_username()).unwrap_or(tr(MessageKey::NoUsername, &[]));
+    tr(MessageKey::OtherTyping, &[&username])
+}
+
+/// The text telling that our draw offer is waiting for an answer, or an empty string if there is
+/// none. It's kept after a reconnect, since the host sends its open offer when resyncing.
+pub fn draw_offer_text() -> String {
+    executor::block_on(async {
+        let offerer = status::get_draw_offer().await;
+        match status::get_my_color().await {
+            Some(color) if offerer == Some(color) => tr(MessageKey::DrawOfferPending, &[]),
+            _ => String::new(),
+        }
+    })
+}
+
+/// The error `send_game_action()` gives its closure, when the host rejected a move because it
+/// wasn't this users turn. The host is always right about the turn, so the move has to be taken
+/// back.
+#[derive(Debug, Error)]
+#[error("{}", tr(MessageKey::MoveNotYourTurn, &[move_number]))]
+pub struct NotYourTurn {
+    /// The number of moves the host has made in the game.
+    pub move_number: u16,
+    /// The color whose turn it is on the host.
+    pub side_to_move: PieceColor,
+}
+
+/// The error `send_game_action()` gives its closure, when the other peer refused a move that
+/// isn't legal. It never reached the other board, so it has to be taken back.
+#[derive(Debug, Error)]
+#[error("{}", tr(MessageKey::MoveIllegal, &[]))]
+pub struct InvalidMove;
+
 /// Send a game action to the other user.
 /// The function is not blocking the thread until it gets a response.
+/// A `GameAction::MovePiece` counts as the next move in the game.
 ///
 /// ## Params:
 /// * `action` - The game action you want to send, is of type `GameAction`
 /// * `on_response` - The closure that will be called when the `GameAction` request gets a
-/// response.
+///   response. If the host rejected a move, the error is a `NotYourTurn`. The action is sent
+///   again while no response comes, and the error is a `TimedOut` if none ever did. If the
+///   outgoing queue is full, the action isn't sent, and the closure is called right away with a
+///   `QueueFull`, or a `NoTransactionId` if every transaction ID is waiting. A move after move
+///   number `u16::MAX` can't be numbered, so it isn't sent either, and the closure gets an
+///   `InvalidMove`.
 ///
 /// ## Examples:
-/// ```
+/// ```ignore
 /// let action = GameAction::Surrender;
 ///
 /// let callback = |res: anyhow::Result<()>| {
@@ -189,27 +1028,143 @@ pub fn get_next_game_action() -> Option<GameAction> {
 ///
 /// send_game_action(action, callback);
 /// ```
-pub fn send_game_action<F>(action: GameAction, mut on_response: F)
+pub fn send_game_action<F>(action: GameAction, on_response: F)
 where
     F: FnMut(anyhow::Result<()>) + Send + Sync + 'static,
 {
-    let closure = Arc::new(Mutex::new(move |resp: P2pResponse| {
-        if let P2pResponsePacket::Error { kind: _ } = resp.packet {
-            on_response(Err(anyhow::anyhow!("Recieved error")));
+    // Shared, since the closure is called here if the action can't be queued
+    let on_response = Arc::new(Mutex::new(on_response));
+    let on_unsent = on_response.clone();
+    let callback = move |resp: Result<P2pResponse, TimedOut>| {
+        let mut on_response = on_response.lock().unwrap();
+        take_game_action_response(resp, &mut *on_response)
+    };
+
+    let sent = executor::block_on(async {
+        let move_number = status::get_move_number().await;
+        let is_move = matches!(action, GameAction::MovePiece(_));
+        if is_move {
+            // The move number comes from the host, which could have sent the last one there is
+            let next = move_number.checked_add(1).ok_or(InvalidMove)?;
+            status::set_move_number(next).await;
+        }
+
+        let sequence = new_sequence();
+        let packet = P2pRequestPacket::game_action(action.clone(), move_number, sequence);
+        let sent = Session::request(packet)
+            .await
+            .on_response(callback)
+            .send()
+            .await;
+        if sent.is_ok() {
+            status::track_draw_offer(&action, true).await;
+            status::track_pause(&action, true).await;
         } else {
-            on_response(Ok(()));
+            // The action never left, so the move and the sequence number are free again
+            return_sequence(sequence);
+            if is_move {
+                status::set_move_number(move_number).await;
+            }
         }
-    }));
+        sent
+    });
+    if let Err(e) = sent {
+        println!("The game action wasn't sent: {}", e);
+        (on_unsent.lock().unwrap())(Err(e));
+    }
+}
 
-    let request = P2pRequest {
-        session_id: executor::block_on(status::get_session_id()),
-        transaction_id: executor::block_on(new_transaction_id()),
-        packet: P2pRequestPacket::game_action(action),
-    };
-    executor::block_on(push_outgoing_queue(
-        P2pPacket::Request(request),
-        Some(closure),
-    ));
+/// Hand the response to a game action to the closure of `send_game_action()`.
+fn take_game_action_response(
+    resp: Result<P2pResponse, TimedOut>,
+    on_response: &mut impl FnMut(anyhow::Result<()>),
+) {
+    match resp.map(|resp| resp.packet) {
+        Ok(P2pResponsePacket::Error {
+            kind: P2pError::InvalidMove,
+        }) => {
+            on_response(Err(InvalidMove.into()));
+        }
+        Ok(P2pResponsePacket::Error { kind }) => on_response(Err(anyhow!(tr(
+            MessageKey::ErrorResponse,
+            &[&format!("{:?}", kind)]
+        )))),
+        Ok(P2pResponsePacket::Rejected {
+            kind: _,
+            move_number,
+            side_to_move,
+        }) => {
+            on_response(Err(NotYourTurn {
+                move_number,
+                side_to_move,
+            }
+            .into()));
+        }
+        Ok(_) => on_response(Ok(())),
+        Err(e) => on_response(Err(e.into())),
+    }
+}
+
+/// Get the number of moves made in the game.
+pub fn get_move_number() -> u16 {
+    executor::block_on(status::get_move_number())
+}
+
+/// Set the number of moves made in the game, e.g. after taking back a rejected move.
+pub fn set_move_number(move_number: u16) {
+    executor::block_on(status::set_move_number(move_number))
+}
+
+/// Publish our board, as seen from White's side, so the host can send it to a client that asks to
+/// resync.
+pub fn publish_board(board: Vec<PieceData>) {
+    executor::block_on(status::set_board(board));
+}
+
+/// Send the other user the hash of our board after a move, so both can tell if the boards still
+/// agree. The client resyncs by itself if they don't, like after a reconnect. See `desync`.
+///
+/// ## Params
+/// * `board` - Our board, seen from White's side.
+/// * `move_count` - The amount of moves applied to the board.
+pub fn send_board_hash(board: &[PieceData], move_count: u16) {
+    executor::block_on(desync::send_board_hash(board, move_count))
+}
+
+/// The last comparison of our board with the other user's, if any boards were compared in this
+/// game.
+pub fn get_board_sync() -> Option<BoardSync> {
+    executor::block_on(status::get_board_sync())
+}
+
+/// The last comparison of the boards to show the user. Empty if none were compared.
+pub fn board_sync_text() -> String {
+    match get_board_sync() {
+        Some(BoardSync::InSync { move_count }) => tr(MessageKey::BoardInSync, &[&move_count]),
+        Some(BoardSync::Desynced { move_count, .. }) => {
+            tr(MessageKey::BoardDesynced, &[&move_count])
+        }
+        None => String::new(),
+    }
+}
+
+/// Take the hosts board and turn, if the client has resynced after a reconnect, or after its board
+/// differed from the hosts.
+pub fn take_resync_board() -> Option<HostBoard> {
+    executor::block_on(status::take_resync_board())
+}
+
+/// Ask the host for its board and turn now, instead of after the next reconnect. Blocks until the
+/// host answers, and leaves the answer for `take_resync_board()`, like a resync after a reconnect.
+/// Only the client can ask.
+pub fn request_resync() -> anyhow::Result<()> {
+    executor::block_on(async {
+        let board = fetch_host_board().await?;
+        status::set_draw_offer(board.draw_offer).await;
+        status::set_pause(board.pause).await;
+        status::set_resync_board(board).await;
+        Ok(())
+    })
 }
 
 /// Check if there is an established connection between the host and client.
@@ -217,12 +1172,602 @@ pub fn is_connected() -> bool {
     executor::block_on(status::get_connection_status()).is_connected()
 }
 
+/// Check if the connection was lost, and is being tried again. The game goes on once it's back.
+pub fn is_reconnecting() -> bool {
+    executor::block_on(status::get_connection_status()).is_reconnecting()
+}
+
 /// Gets the other users username.
 pub fn get_other_username() -> Option<String> {
     executor::block_on(status::get_other_username())
 }
 
-/// Sets your username.
-pub fn set_my_username(name: &str) {
-    executor::block_on(status::set_my_username(name))
+/// Sets your username, with the whitespace around it trimmed. This is the name shown to you, but
+/// when playing anonymously, the other user gets a generated handle instead. Returns an error, and
+/// keeps the old username, if it can't be used. See `validate_username()`.
+pub fn set_my_username(name: &str) -> Result<(), UsernameError> {
+    let name = normalize_username(name)?;
+    executor::block_on(status::set_my_username(name));
+    Ok(())
+}
+
+/// Set the color you wish to play, or `None` to leave it to the coin flip. You only get it if the
+/// other user wishes to play the other color. Must be set before hosting or joining.
+pub fn set_color_preference(preference: Option<PieceColor>) {
+    executor::block_on(status::set_color_preference(preference))
+}
+
+/// Set if you play anonymously. When anonymous, your username is never sent to the other user,
+/// who sees a generated handle like "Player-7f3a" instead.
+pub fn set_anonymous(anonymous: bool) {
+    executor::block_on(status::set_anonymous(anonymous))
+}
+
+/// Turn protocol anomalies into visible errors, instead of tolerating them. This is on by default
+/// in debug builds. See `p2p::anomaly`.
+pub fn set_strict_protocol(strict_protocol: bool) {
+    executor::block_on(anomaly::set_config(NetConfig { strict_protocol }))
+}
+
+/// Get the last protocol anomaly that was turned into an error in strict mode, if any. The error
+/// is cleared, so it's only returned once.
+pub fn take_protocol_error() -> Option<String> {
+    executor::block_on(async {
+        let error = anomaly::get_error().await;
+        anomaly::clear_error().await;
+        error
+    })
+}
+
+/// Write a debug bundle to the directory at `path`, for attaching to bug reports. The directory is
+/// created if it doesn't exist. The bundle holds:
+/// * `connection.txt` - The state of the connection. Usernames are left out.
+/// * `packets.log` - Summaries of the last packets sent and recieved.
+/// * `history.log` - The last moves of the game, and the board after each.
+/// * `board.fen` - The board, if `board_fen` is given.
+///
+/// ## Params
+/// * `path` - The directory to write the bundle to.
+/// * `board_fen` - The current board as a FEN string.
+pub fn export_debug_bundle(path: &Path, board_fen: Option<&str>) -> anyhow::Result<()> {
+    fs::create_dir_all(path)?;
+
+    let connection = executor::block_on(async {
+        let mut connection = String::new();
+        writeln!(connection, "exported: {}", Utc::now())?;
+        writeln!(
+            connection,
+            "status: {:?}",
+            status::get_connection_status().await
+        )?;
+        writeln!(
+            connection,
+            "session id: {:#06x}",
+            status::get_session_id().await
+        )?;
+        writeln!(connection, "join code: {:?}", status::get_join_code().await)?;
+        writeln!(
+            connection,
+            "other address: {:?}",
+            status::get_other_addr().await
+        )?;
+        writeln!(connection, "this peer: {}", PeerInfo::local())?;
+        if let Some(info) = status::get_other_peer_info().await {
+            writeln!(connection, "other peer: {}", info)?;
+        }
+        writeln!(
+            connection,
+            "largest ping payload: {:?}",
+            status::get_path_mtu().await
+        )?;
+        writeln!(
+            connection,
+            "task restarts: {}",
+            status::get_task_restarts().await
+        )?;
+        writeln!(
+            connection,
+            "socket rebinds: {}",
+            status::get_socket_rebinds().await
+        )?;
+        writeln!(
+            connection,
+            "throttled requests: {}",
+            status::get_throttled_requests().await
+        )?;
+        writeln!(
+            connection,
+            "corrupt packets: {}",
+            status::get_corrupt_packets().await
+        )?;
+        writeln!(
+            connection,
+            "outgoing queue: {} of {}",
+            get_outgoing_queue_len().await,
+            outgoing_capacity()
+        )?;
+        writeln!(
+            connection,
+            "transaction table: {}",
+            get_transaction_table_len().await
+        )?;
+        for request in pending_requests().await {
+            let answered = if request.answered { " (answered)" } else { "" };
+            writeln!(
+                connection,
+                "pending request: {}{}",
+                request.transaction_id, answered
+            )?;
+        }
+        writeln!(
+            connection,
+            "strict protocol: {}",
+            anomaly::get_config().await.strict_protocol
+        )?;
+        let ping_loss = status::get_ping_loss().await;
+        writeln!(
+            connection,
+            "lost pings: {} of {}",
+            ping_loss.lost, ping_loss.sent
+        )?;
+        writeln!(
+            connection,
+            "traffic: {}",
+            status::get_connection_stats().await
+        )?;
+        if let Some(stats) = simulate::stats() {
+            writeln!(connection, "simulated network: {}", stats)?;
+        }
+        for (anomaly, count) in anomaly::get_counts().await {
+            writeln!(connection, "tolerated {:?}: {}", anomaly, count)?;
+        }
+        anyhow::Ok(connection)
+    })?;
+    fs::write(path.join("connection.txt"), connection)?;
+
+    let mut packets = executor::block_on(get_capture()).join("\n");
+    packets.push('\n');
+    fs::write(path.join("packets.log"), packets)?;
+
+    let mut moves = String::new();
+    for entry in history::history_ring() {
+        writeln!(moves, "{}", entry)?;
+    }
+    fs::write(path.join("history.log"), moves)?;
+
+    if let Some(fen) = board_fen {
+        fs::write(path.join("board.fen"), fen)?;
+    }
+
+    println!("Wrote debug bundle to {:?}", path);
+    Ok(())
+}
+
+/// Write a line to the log of the current session, e.g. a move. See `session_log`.
+pub fn log_to_session(line: impl Into<String>) {
+    session_log::log(line);
+}
+
+/// Wait until the session log is written to the disk, for at most `timeout`. Called before the
+/// game exits, and when it panics.
+pub fn flush_session_log(timeout: Duration) {
+    session_log::flush(timeout);
+}
+
+/// Generate the protocol test vectors as JSON. See `vectors`.
+pub fn generate_protocol_vectors() -> anyhow::Result<String> {
+    let vectors = vectors::generate();
+    Ok(serde_json::to_string_pretty(&vectors)?)
+}
+
+/// Check the protocol test vectors in `source`, which is JSON. Returns the amount of vectors, and
+/// the failures as the name of the vector and why it failed.
+pub fn verify_protocol_vectors(source: &str) -> anyhow::Result<(usize, Vec<(String, String)>)> {
+    let vectors: Vec<TestVector> = serde_json::from_str(source)?;
+    let failures = vectors::verify(&vectors)
+        .into_iter()
+        .map(|(name, e)| (name, e.to_string()))
+        .collect();
+    Ok((vectors.len(), failures))
+}
+
+/// The packets of the protocol vectors, for `benches/encode.rs` to encode. The packets aren't
+/// public, so the benchmark only gets their bytes.
+#[doc(hidden)]
+pub struct SamplePackets(Vec<P2pPacket>);
+
+#[doc(hidden)]
+pub fn sample_packets() -> SamplePackets {
+    SamplePackets(vectors::packets())
+}
+
+impl SamplePackets {
+    /// Encode each packet into a new buffer, with `ToPacket::to_packet()`. Returns the amount of
+    /// bytes.
+    pub fn encode_each(&self) -> usize {
+        self.0.iter().map(|packet| packet.to_packet().len()).sum()
+    }
+
+    /// Encode each packet into `buf`, which is cleared in between, like `send_p2p_packet()` reuses
+    /// its buffer. Returns the amount of bytes.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) -> usize {
+        self.0
+            .iter()
+            .map(|packet| {
+                buf.clear();
+                packet.write_packet(buf);
+                buf.len()
+            })
+            .sum()
+    }
+}
+
+/// Get the statistics of the last pings to the other peer, or `None` if none has come back on
+/// this connection.
+pub fn get_network_stats() -> Option<NetworkStats> {
+    executor::block_on(status::get_network_stats())
+}
+
+/// A warning to show the user if the other player runs another major version of the game, which
+/// may not work as expected even though the protocol matches. `None` if the versions agree, or
+/// there is no other player.
+pub fn version_warning() -> Option<String> {
+    let other = executor::block_on(status::get_other_peer_info())?;
+    let local = PeerInfo::local();
+    (!local.is_compatible_with(&other))
+        .then(|| tr(MessageKey::VersionMismatch, &[&other.version, &local.version]))
+}
+
+/// The smoothed round trip of the last pings, or `None` if no ping has come back on this
+/// connection. See `NetworkStats::average`.
+pub fn get_ping_average() -> Option<Duration> {
+    executor::block_on(status::get_ping_average())
+}
+
+/// The jitter of the last pings, or `None` if no ping has come back on this connection.
+pub fn get_ping_jitter() -> Option<Duration> {
+    executor::block_on(status::get_ping_jitter())
+}
+
+/// The round trip of the newest ping, or `None` if no ping has come back on this connection.
+pub fn get_ping_last() -> Option<Duration> {
+    executor::block_on(status::get_ping_last())
+}
+
+/// The ping to show the user: The smoothed ping, and the 95th percentile if it's a spike. Empty if
+/// there are no pings.
+pub fn ping_text() -> String {
+    let millis = |round_trip| ping_millis(ping_micros(round_trip));
+    match get_network_stats() {
+        Some(stats) if stats.has_spike() => tr(
+            MessageKey::PingSpike,
+            &[&millis(stats.average), &millis(stats.p95)],
+        ),
+        Some(stats) => tr(MessageKey::Ping, &[&millis(stats.average)]),
+        None => String::new(),
+    }
+}
+
+/// Simulate a bad network for the packets we send, e.g. to reproduce flakiness. A simulation that
+/// doesn't change anything turns it off. See `simulate`.
+pub fn set_network_simulation(simulation: NetworkSimulation) {
+    if simulation.is_active() {
+        println!("Simulating a bad network: {:?}", simulation);
+    }
+    simulate::set(simulation);
+}
+
+/// What the simulated network has done to the packets we sent, or `None` if it's off.
+pub fn get_simulation_stats() -> Option<SimulationStats> {
+    simulate::stats()
+}
+
+/// The counters of the traffic with the other peer in this session, e.g. for a debug overlay.
+/// They start over when a new session starts.
+pub fn get_connection_stats() -> ConnectionStats {
+    executor::block_on(status::get_connection_stats())
+}
+
+/// How many times the socket was rebound, since it had stopped receiving while reconnecting.
+pub fn get_socket_rebinds() -> u32 {
+    executor::block_on(status::get_socket_rebinds())
+}
+
+/// How many of the pings to the other peer were lost. Next to `get_simulation_stats()` it shows the loss
+/// the simulation caused.
+pub fn get_ping_loss() -> PingLoss {
+    executor::block_on(status::get_ping_loss())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{net_utils::FromPacket, p2p::queue};
+
+    /// What `take_game_action_response()` hands the closure for the response `packet`.
+    fn take(packet: P2pResponsePacket) -> anyhow::Result<()> {
+        let resp = P2pResponse {
+            session_id: 0x1a2b,
+            transaction_id: 0x0001,
+            packet,
+        };
+        let mut taken = None;
+        take_game_action_response(Ok(resp), &mut |result| taken = Some(result));
+        taken.unwrap()
+    }
+
+    #[test]
+    fn game_action_rejections_are_told_apart() {
+        assert!(take(P2pResponsePacket::Acknowledge).is_ok());
+
+        let e = take(P2pResponsePacket::error(P2pError::InvalidMove)).unwrap_err();
+        assert!(e.is::<InvalidMove>());
+
+        let e = take(P2pResponsePacket::Rejected {
+            kind: P2pError::NotYourTurn,
+            move_number: 7,
+            side_to_move: PieceColor::Black,
+        })
+        .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(NotYourTurn {
+                move_number: 7,
+                side_to_move: PieceColor::Black,
+            })
+        ));
+
+        let e = take(P2pResponsePacket::error(P2pError::Throttled)).unwrap_err();
+        assert!(!e.is::<InvalidMove>() && !e.is::<NotYourTurn>());
+    }
+
+    #[test]
+    fn game_action_timeout_is_told_apart() {
+        let timed_out = TimedOut {
+            transaction_id: 0x0001,
+            sends: 3,
+        };
+        let mut taken = None;
+        take_game_action_response(Err(timed_out), &mut |result| taken = Some(result));
+        assert_eq!(taken.unwrap().unwrap_err().downcast_ref(), Some(&timed_out));
+    }
+
+    /// What `take_rematch_answer()` hands the closure for the response `packet`.
+    fn take_rematch(packet: P2pResponsePacket) -> anyhow::Result<Option<PieceColor>> {
+        let resp = P2pResponse {
+            session_id: 0x1a2b,
+            transaction_id: 0x0001,
+            packet,
+        };
+        let mut taken = None;
+        take_rematch_answer(Ok(resp), &mut |result| taken = Some(result));
+        taken.unwrap()
+    }
+
+    /// Where a game that has ended leaves the session, with us playing `color`.
+    async fn finish_game(color: PieceColor) {
+        status::set_my_color(color).await;
+        status::set_move_number(12).await;
+        status::set_game_finished(true).await;
+    }
+
+    /// If a new game has been started in the session, where we play `color`.
+    async fn assert_rematch_started(color: PieceColor) {
+        assert_eq!(status::get_my_color().await, Some(color));
+        assert_eq!(status::get_move_number().await, 0);
+        assert!(!status::is_game_finished().await);
+    }
+
+    #[test]
+    fn rematch_answers_are_told_apart() {
+        let _state = crate::net::p2p::lock_global_state();
+        executor::block_on(finish_game(PieceColor::Black));
+        let declined = take_rematch(P2pResponsePacket::RematchDecline).unwrap();
+        assert_eq!(declined, None);
+        assert!(executor::block_on(status::is_game_finished()));
+
+        let e = take_rematch(P2pResponsePacket::error(P2pError::GameInProgress)).unwrap_err();
+        assert!(e.is::<GameInProgress>(), "{}", e);
+        let e = take_rematch(P2pResponsePacket::error(P2pError::InvalidSessionId)).unwrap_err();
+        assert!(!e.is::<GameInProgress>(), "{}", e);
+        assert!(take_rematch(P2pResponsePacket::Acknowledge).is_err());
+        assert!(executor::block_on(status::is_game_finished()));
+
+        // An accepted rematch swaps the colors, and starts the new game
+        let accepted = take_rematch(P2pResponsePacket::RematchAnswer {
+            offerer_color: PieceColor::White,
+        });
+        assert_eq!(accepted.unwrap(), Some(PieceColor::White));
+        executor::block_on(assert_rematch_started(PieceColor::White));
+    }
+
+    /// Answer a rematch offered with the transaction `transaction_id`, and return the response
+    /// that was queued for it.
+    fn answer_rematch_offer(
+        transaction_id: u16,
+        accept: bool,
+    ) -> (Option<PieceColor>, P2pResponse) {
+        let request = P2pRequest {
+            session_id: 0x1a2b,
+            transaction_id,
+            packet: P2pRequestPacket::RematchOffer,
+        };
+        let color = answer_rematch(RematchOffer { request }, accept);
+        let Some((P2pPacket::Response(response), _)) =
+            executor::block_on(queue::pop_outgoing_queue())
+        else {
+            panic!("The answer wasn't queued");
+        };
+        (color, response)
+    }
+
+    #[test]
+    fn offered_rematch_is_answered() {
+        let _state = crate::net::p2p::lock_global_state();
+        executor::block_on(finish_game(PieceColor::Black));
+        let (color, response) = answer_rematch_offer(0x0031, false);
+        assert_eq!(color, None);
+        assert_eq!(response.transaction_id, 0x0031);
+        assert_eq!(response.packet, P2pResponsePacket::RematchDecline);
+        assert_eq!(
+            executor::block_on(status::get_my_color()),
+            Some(PieceColor::Black)
+        );
+        assert!(executor::block_on(status::is_game_finished()));
+
+        // The player who offered it gets the color we had
+        let (color, response) = answer_rematch_offer(0x0032, true);
+        assert_eq!(color, Some(PieceColor::White));
+        assert_eq!(response.transaction_id, 0x0032);
+        assert_eq!(
+            response.packet,
+            P2pResponsePacket::RematchAnswer {
+                offerer_color: PieceColor::Black,
+            }
+        );
+        executor::block_on(assert_rematch_started(PieceColor::White));
+    }
+
+    #[test]
+    fn path_probe_finds_the_largest_echoed_payload() {
+        let _state = crate::net::p2p::lock_global_state();
+        let probe = thread::spawn(|| {
+            let _runtime = runtime::enter();
+            probe_path(&PATH_PROBE_SIZES)
+        });
+
+        // The other user echoes the small pings, mangles the 512 byte one, and never gets the
+        // largest, like a network dropping large packets
+        while !probe.is_finished() {
+            let Some((P2pPacket::Request(req), _)) =
+                executor::block_on(queue::pop_outgoing_queue())
+            else {
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            };
+            let P2pRequestPacket::Ping { mut payload } = req.packet else {
+                panic!("Expected a ping, got {:?}", req.packet);
+            };
+            match payload.len() {
+                1024 => continue,
+                512 => payload[0] ^= 0xff,
+                _ => {}
+            }
+            executor::block_on(queue::set_response(P2pResponse {
+                session_id: req.session_id,
+                transaction_id: req.transaction_id,
+                packet: P2pResponsePacket::Pong { payload },
+            }));
+        }
+
+        let report = probe.join().unwrap();
+        assert_eq!(
+            report.results,
+            [(64, true), (256, true), (512, false), (1024, false)]
+        );
+        assert_eq!(report.largest, Some(256));
+        assert_eq!(executor::block_on(status::get_path_mtu()), Some(256));
+        executor::block_on(status::set_path_mtu(None));
+    }
+
+    #[test]
+    fn diagnostics_need_a_private_address_and_a_port() {
+        let ip = Ipv4Addr::new(192, 168, 1, 20);
+        let ok = Diagnostics {
+            local_ip: Some(ip),
+            port: Some(6000),
+        };
+        assert!(ok.is_ok());
+        assert_eq!(
+            ok.summary(),
+            [
+                tr(MessageKey::DiagnosticsLocalIp, &[&ip]),
+                tr(MessageKey::DiagnosticsPort, &[&6000]),
+                tr(MessageKey::DiagnosticsOk, &[]),
+            ]
+        );
+
+        let public = Ipv4Addr::new(8, 8, 8, 8);
+        let failed = [
+            Diagnostics {
+                local_ip: Some(public),
+                ..ok
+            },
+            Diagnostics {
+                local_ip: None,
+                ..ok
+            },
+            Diagnostics { port: None, ..ok },
+        ];
+        for diagnostics in failed {
+            assert!(!diagnostics.is_ok(), "{:?} is fine", diagnostics);
+            assert_eq!(
+                diagnostics.summary().last(),
+                Some(&tr(MessageKey::DiagnosticsFailed, &[]))
+            );
+        }
+        assert_eq!(
+            failed[0].summary()[0],
+            tr(MessageKey::DiagnosticsNotPrivate, &[&public])
+        );
+    }
+
+    #[test]
+    fn host_of_another_version_is_a_protocol_mismatch() {
+        let _state = crate::net::p2p::lock_global_state();
+        let transaction_id = 0x0007;
+        let request = P2pRequest {
+            session_id: wire::CONNECT_SESSION_ID,
+            transaction_id,
+            packet: P2pRequestPacket::connect("7f0000011f90", "Bob", 7, None).unwrap(),
+        };
+        executor::block_on(async {
+            push_outgoing_queue(request.into(), Completion::Keep, None)
+                .await
+                .unwrap();
+            queue::pop_outgoing_queue().await.unwrap();
+        });
+
+        // The host's refusal, in the header of its own version
+        let refusal = P2pResponse {
+            session_id: wire::CONNECT_SESSION_ID,
+            transaction_id,
+            packet: P2pResponsePacket::error(P2pError::ProtocolMismatch),
+        };
+        let mut bytes = P2pPacket::from(refusal).to_packet();
+        bytes[1] = wire::PROTOCOL_VERSION + 1;
+        let Ok(P2pPacket::Response(refusal)) = P2pPacket::from_packet(bytes) else {
+            panic!("The refusal wasn't read");
+        };
+        executor::block_on(queue::set_response(refusal));
+
+        let commitment = coin_flip::commit(coin_flip::new_nonce());
+        let result = check_for_connection_resp(transaction_id, 7, &commitment);
+        let e = result.expect("The refusal wasn't taken").unwrap_err();
+        assert!(e.is::<ProtocolMismatch>(), "{}", e);
+        let pending = executor::block_on(queue::check_transaction_id(transaction_id));
+        assert!(!pending, "The refusal was left in the table");
+    }
+
+    #[test]
+    fn invalid_join_code_is_an_error() {
+        let _state = crate::net::p2p::lock_global_state();
+        for code in ["", "not a code", "c0a8000a1b", "c0a8000a1b58ff"] {
+            assert!(send_join_request(code, "Bob", 7, Duration::from_secs(1)).is_err());
+            let result = connect_to_host_loop(code, "Bob", JOIN_RETRY, |_| {});
+            assert!(result.is_err(), "{:?} was joined", code);
+        }
+        // Nor are addresses no host can be at
+        for code in [
+            "000000001770",
+            "ffffffff1770",
+            "e00000011770",
+            "c0a800010000",
+        ] {
+            let result = connect_to_host_loop(code, "Bob", JOIN_RETRY, |_| {});
+            assert!(result.is_err(), "{:?} was joined", code);
+        }
+        // Nothing was set up for them
+        assert_eq!(executor::block_on(status::get_other_addr()), None);
+    }
 }
@@ -1,4 +1,11 @@
 pub mod interface;
+mod checked_lock;
 mod net_utils;
 mod p2p;
+mod session_log;
 mod status;
+
+/// Also held by the tests of the game window, whose board move is global as well, and by the tests
+/// of the move history and the state server, which the game window updates.
+#[cfg(test)]
+pub(crate) use p2p::lock_global_state;
@@ -1,4 +1,7 @@
-use std::{hint, net::{IpAddr, Ipv4Addr, SocketAddr}};
+use std::{
+    hint,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
 
 use anyhow::anyhow;
 use local_ip_address::local_ip;
@@ -7,7 +10,19 @@ use thiserror::Error;
 /// Turn the data into bytes ready to be sent over the network. The packet is in BE (Big Endian)
 /// order.
 pub trait ToPacket {
-    fn to_packet(&self) -> Vec<u8>;
+    /// Append the packet to `buf`, so the packet can be written into a reused buffer.
+    fn write_packet(&self, buf: &mut Vec<u8>);
+
+    fn to_packet(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.write_packet(&mut buf);
+        buf
+    }
+
+    /// The amount of bytes the data takes up as a packet.
+    fn encoded_len(&self) -> usize {
+        self.to_packet().len()
+    }
 }
 /// Turn BE (Big  Endian) bytes into data.
 pub trait FromPacket {
@@ -30,6 +45,10 @@ pub enum PacketError {
     Empty,
     #[error("Data error. Reason: {reason:?}")]
     DataError { reason: String },
+    #[error("Packet is too large. Max size is {max} bytes, got {size} bytes")]
+    TooLarge { size: usize, max: usize },
+    #[error("Checksum mismatch. The packet sums to {computed:08x}, but came with {got:08x}")]
+    ChecksumMismatch { computed: u32, got: u32 },
 }
 impl PacketError {
     pub fn invalid_length(expected: usize, got: usize) -> Self {
@@ -40,8 +59,51 @@ impl PacketError {
             reason: reason.to_string(),
         }
     }
+    pub fn too_large(size: usize, max: usize) -> Self {
+        Self::TooLarge { size, max }
+    }
+}
+
+/// Append `s` to `buf` as a length-prefixed string: Two BE bytes with the length, and then that
+/// many bytes of UTF-8. A string longer than `u16::MAX` bytes is cut off, but such a packet is too
+/// large to send anyway.
+pub fn write_str(buf: &mut Vec<u8>, s: &str) {
+    let s = truncate(s, u16::MAX as usize);
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Read a string written by `write_str()` from the start of `bytes`. Returns the string and the
+/// amount of bytes it took. A string that is cut off gives a `PacketError::DataError`.
+///
+/// ## Params
+/// * `bytes` - The bytes starting with the string.
+/// * `what` - What the string is, for the error.
+pub fn read_str(bytes: &[u8], what: &str) -> anyhow::Result<(String, usize)> {
+    let [high, low, ..] = *bytes else {
+        let reason = format!("The length of the {} is cut off", what);
+        return Err(PacketError::data_error(&reason).into());
+    };
+    let end = 2 + u16::from_be_bytes([high, low]) as usize;
+    let s = bytes
+        .get(2..end)
+        .ok_or_else(|| PacketError::data_error(&format!("The {} is cut off", what)))?;
+    let s = String::from_utf8(s.to_vec()).map_err(|_| {
+        PacketError::data_error(&format!("Invalid UFT8 encoded values for {}", what))
+    })?;
+    Ok((s, end))
 }
 
+/// The longest start of `s` that is at most `max` bytes, without splitting a character.
+pub fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum NetworkError {
     #[error("Couldn't find an available port in range 6000..=7000")]
@@ -52,6 +114,7 @@ pub enum NetworkError {
     SendError { details: String },
     #[error("Error occured while recieving data: {details:?}")]
     RecieveError { details: String },
+    #[allow(dead_code)]
     #[error("Response Type Error: Got wrong data type in return")]
     ResponseTypeError,
 }
@@ -84,13 +147,14 @@ pub fn get_local_ip() -> anyhow::Result<Ipv4Addr> {
         .filter(|netifas| matches!(netifas.1, IpAddr::V4(_)))
         .find(|x| x.0.to_lowercase().trim() == "hamachi");
 
-        if let Some(netifas) = hamachi_netifas {
-            print!("Found Hamachi IP!!");
-            return match netifas.1 {
-                IpAddr::V4(ip) => Ok(ip),
-                _ => unsafe { hint::unreachable_unchecked(); }
-            }
-        }
+    if let Some(netifas) = hamachi_netifas {
+        return match netifas.1 {
+            IpAddr::V4(ip) => Ok(ip),
+            _ => unsafe {
+                hint::unreachable_unchecked();
+            },
+        };
+    }
 
     if let Ok(IpAddr::V4(ip)) = local_ip() {
         Ok(ip)
@@ -112,6 +176,63 @@ pub fn hex_encode_ip(addr: SocketAddr) -> anyhow::Result<String> {
     }
 }
 
+/// What kind of address a join code points to. See `classify_target()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TargetClass {
+    /// An address on the local network, or this computer.
+    Private,
+    /// An address on the internet. The join code was probably made on another network.
+    Public,
+    /// `0.0.0.0`, or port 0.
+    Unspecified,
+    /// The broadcast address.
+    Broadcast,
+    /// A multicast address.
+    Multicast,
+    /// Our own hosting address.
+    Own,
+}
+
+impl TargetClass {
+    /// If a host can never be at the address.
+    pub fn is_invalid(&self) -> bool {
+        !matches!(self, Self::Private | Self::Public)
+    }
+}
+
+/// Hamachi gives out addresses in `25.0.0.0/8`, which are routable, but are used like a LAN.
+const HAMACHI_FIRST_OCTET: u8 = 25;
+
+/// Tells what kind of address `target` is, so the join flow can refuse addresses that can't be a
+/// host, and warn about addresses that aren't on the local network.
+///
+/// ## Params
+/// * `target` - The address decoded from a join code.
+/// * `own` - Our own hosting address, if we are hosting.
+pub fn classify_target(target: SocketAddr, own: Option<SocketAddr>) -> TargetClass {
+    let IpAddr::V4(ip) = target.ip() else {
+        return TargetClass::Public;
+    };
+
+    if ip.is_unspecified() || target.port() == 0 {
+        TargetClass::Unspecified
+    } else if ip.is_broadcast() {
+        TargetClass::Broadcast
+    } else if ip.is_multicast() {
+        TargetClass::Multicast
+    } else if Some(target) == own {
+        TargetClass::Own
+    } else if ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.octets()[0] == HAMACHI_FIRST_OCTET
+    {
+        TargetClass::Private
+    } else {
+        TargetClass::Public
+    }
+}
+
 pub fn hex_decode_ip(data: &str) -> anyhow::Result<SocketAddr> {
     let bytes = match hex::decode(data) {
         Ok(bytes) => bytes,
@@ -127,3 +248,71 @@ pub fn hex_decode_ip(data: &str) -> anyhow::Result<SocketAddr> {
 
     Ok(SocketAddr::new(IpAddr::V4(ip.into()), port))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(target: &str) -> TargetClass {
+        classify_target(target.parse().unwrap(), None)
+    }
+
+    #[test]
+    fn local_addresses_are_private() {
+        for target in [
+            "127.0.0.1:6000",
+            "10.0.0.7:6000",
+            "172.16.4.2:6000",
+            "192.168.0.10:6000",
+            "169.254.3.3:6000",
+        ] {
+            assert_eq!(classify(target), TargetClass::Private, "{}", target);
+        }
+    }
+
+    #[test]
+    fn hamachi_addresses_are_private() {
+        assert_eq!(classify("25.0.0.1:6000"), TargetClass::Private);
+        assert_eq!(classify("25.255.255.254:6000"), TargetClass::Private);
+        assert_eq!(classify("26.0.0.1:6000"), TargetClass::Public);
+    }
+
+    #[test]
+    fn internet_addresses_are_public() {
+        for target in ["8.8.8.8:6000", "172.32.0.1:6000", "192.169.0.1:6000"] {
+            assert_eq!(classify(target), TargetClass::Public, "{}", target);
+        }
+    }
+
+    #[test]
+    fn addresses_no_host_can_be_at_are_invalid() {
+        let cases = [
+            ("0.0.0.0:6000", TargetClass::Unspecified),
+            ("192.168.0.10:0", TargetClass::Unspecified),
+            ("255.255.255.255:6000", TargetClass::Broadcast),
+            ("224.0.0.1:6000", TargetClass::Multicast),
+            ("239.255.255.250:6000", TargetClass::Multicast),
+        ];
+        for (target, class) in cases {
+            assert_eq!(classify(target), class, "{}", target);
+            assert!(class.is_invalid());
+        }
+
+        let own = "192.168.0.10:6000".parse().unwrap();
+        assert_eq!(classify_target(own, Some(own)), TargetClass::Own);
+        // Another port on our own computer can be another host
+        let other_port = "192.168.0.10:6001".parse().unwrap();
+        assert_eq!(classify_target(other_port, Some(own)), TargetClass::Private);
+    }
+
+    #[test]
+    fn join_codes_round_trip() {
+        let addr = "192.168.0.10:6000".parse().unwrap();
+        let code = hex_encode_ip(addr).unwrap();
+        assert_eq!(code, "c0a8000a1770");
+        assert_eq!(hex_decode_ip(&code).unwrap(), addr);
+        for code in ["", "c0a8000a17", "c0a8000a177000", "zz a8000a1770"] {
+            assert!(hex_decode_ip(code).is_err(), "{:?} was decoded", code);
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! What the network loops do about protocol anomalies: packets from unknown peers, responses to
+//! transactions that aren't waited for, packets that can't be decoded and packets from another
+//! session.
+//!
+//! Normally they are tolerated and counted, since UDP loses, duplicates and delays packets. With
+//! `NetConfig::strict_protocol`, which is on in debug builds, they become a visible error instead,
+//! and the full bytes of the packet are saved in the packet capture. Every site in the loops goes
+//! through `report()`, so the two modes only differ in `AnomalyPolicy`.
+
+use std::net::SocketAddr;
+
+use tokio::sync::Mutex;
+
+use crate::net::session_log;
+
+use super::capture;
+
+/// A protocol anomaly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anomaly {
+    /// A packet from another address than the other peer's.
+    UnknownPeer,
+    /// A response to a transaction that isn't waited for, e.g. a late or duplicated pong.
+    UnexpectedResponse,
+    /// A packet that couldn't be decoded.
+    DecodeFailure,
+    /// A packet with another session ID than the current session's.
+    SessionMismatch,
+}
+
+impl Anomaly {
+    /// Returns an array to iterate over all enum values
+    pub const fn values() -> &'static [Anomaly; 4] {
+        &[
+            Anomaly::UnknownPeer,
+            Anomaly::UnexpectedResponse,
+            Anomaly::DecodeFailure,
+            Anomaly::SessionMismatch,
+        ]
+    }
+
+    const fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Settings for the network.
+#[derive(Clone, Copy, Debug)]
+pub struct NetConfig {
+    /// Turn protocol anomalies into visible errors, instead of tolerating them.
+    pub strict_protocol: bool,
+}
+
+impl NetConfig {
+    /// The default settings. `strict_protocol` is only on in debug builds.
+    pub const fn new() -> Self {
+        Self {
+            strict_protocol: cfg!(debug_assertions),
+        }
+    }
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What to do about an anomaly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Count it, and carry on.
+    Tolerate,
+    /// Show the error to the user, and save the packet.
+    Error(String),
+}
+
+/// Decides what to do about anomalies, from the `NetConfig`.
+#[derive(Clone, Copy, Debug)]
+pub struct AnomalyPolicy {
+    strict: bool,
+}
+
+impl AnomalyPolicy {
+    pub const fn new(config: &NetConfig) -> Self {
+        Self {
+            strict: config.strict_protocol,
+        }
+    }
+
+    /// Decide what to do about `anomaly`.
+    ///
+    /// ## Params
+    /// * `anomaly` - The anomaly.
+    /// * `addr` - The address the packet came from.
+    pub fn judge(&self, anomaly: Anomaly, addr: SocketAddr) -> Verdict {
+        if self.strict {
+            Verdict::Error(format!("Protocol anomaly: {:?} from {:?}", anomaly, addr))
+        } else {
+            Verdict::Tolerate
+        }
+    }
+}
+
+struct AnomalyState {
+    config: NetConfig,
+    counts: [u32; 4],
+    error: Option<String>,
+}
+
+static STATE: Mutex<AnomalyState> = Mutex::const_new(AnomalyState {
+    config: NetConfig::new(),
+    counts: [0; 4],
+    error: None,
+});
+
+/// Report an anomaly from the network loops, and handle it as the `AnomalyPolicy` says.
+///
+/// ## Params
+/// * `anomaly` - The anomaly.
+/// * `addr` - The address the packet came from.
+/// * `bytes` - The full bytes of the packet.
+pub async fn report(anomaly: Anomaly, addr: SocketAddr, bytes: &[u8]) {
+    let error = {
+        let mut state = STATE.lock().await;
+        match AnomalyPolicy::new(&state.config).judge(anomaly, addr) {
+            Verdict::Tolerate => {
+                state.counts[anomaly.index()] += 1;
+                session_log::log(format!("warning: tolerated {:?} from {:?}", anomaly, addr));
+                return;
+            }
+            Verdict::Error(error) => {
+                state.error = Some(error.clone());
+                error
+            }
+        }
+    };
+    println!("{}", error);
+    session_log::log(format!("error: {}", error));
+    // The capture is only locked after the state is released, so the two are never held together
+    capture::record_bytes(&error, bytes).await;
+}
+
+/// Get the network settings.
+pub async fn get_config() -> NetConfig {
+    STATE.lock().await.config
+}
+
+/// Set the network settings.
+pub async fn set_config(config: NetConfig) {
+    STATE.lock().await.config = config;
+}
+
+/// Get how many times each anomaly has been tolerated.
+pub async fn get_counts() -> Vec<(Anomaly, u32)> {
+    let state = STATE.lock().await;
+    Anomaly::values()
+        .iter()
+        .map(|anomaly| (*anomaly, state.counts[anomaly.index()]))
+        .collect()
+}
+
+/// Get the last anomaly that was turned into an error, if any.
+pub async fn get_error() -> Option<String> {
+    STATE.lock().await.error.clone()
+}
+
+/// Clear the error, e.g. after it has been shown.
+pub async fn clear_error() {
+    STATE.lock().await.error = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, sync::Arc};
+
+    use futures::executor;
+
+    use super::*;
+    use crate::net::p2p::{
+        communicate::{recieve_p2p_packet, MAX_PACKET_SIZE},
+        lock_global_state, runtime,
+    };
+
+    /// What a run of anomalies left behind.
+    #[derive(Debug)]
+    struct Outcome {
+        /// How many of each anomaly were tolerated during the run.
+        tolerated: Vec<(Anomaly, u32)>,
+        error: Option<String>,
+        /// The packets saved in the capture during the run.
+        saved: usize,
+    }
+
+    /// The anomalies of a bad connection: Every kind, from the other peer and from a stranger.
+    /// The bytes are marked, so their saved copies can be told from other packets.
+    fn script() -> Vec<(Anomaly, SocketAddr, Vec<u8>)> {
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let stranger: SocketAddr = "10.0.0.9:4000".parse().unwrap();
+        vec![
+            (Anomaly::UnexpectedResponse, peer, vec![0xa7, 0x68, 0x80]),
+            (Anomaly::UnexpectedResponse, peer, vec![0xa7, 0x68, 0x81]),
+            (Anomaly::DecodeFailure, peer, vec![0xa7, 0x68, 0x82]),
+            (Anomaly::SessionMismatch, peer, vec![0xa7, 0x68, 0x83]),
+            (Anomaly::UnknownPeer, stranger, vec![0xa7, 0x68, 0x84]),
+        ]
+    }
+
+    /// Count the saved packets whose bytes start like the packets of `script()`.
+    async fn saved_script_packets() -> usize {
+        capture::get_capture()
+            .await
+            .iter()
+            .filter(|line| line.contains("bytes=a768"))
+            .count()
+    }
+
+    /// Run `anomalies` with `strict_protocol`, and see what they left behind.
+    async fn run(strict_protocol: bool, anomalies: impl Future<Output = ()>) -> Outcome {
+        set_config(NetConfig { strict_protocol }).await;
+        clear_error().await;
+        let counts_before = get_counts().await;
+        let saved_before = saved_script_packets().await;
+
+        anomalies.await;
+
+        let tolerated = get_counts()
+            .await
+            .into_iter()
+            .zip(counts_before)
+            .map(|((anomaly, after), (_, before))| (anomaly, after - before))
+            .collect();
+        let outcome = Outcome {
+            tolerated,
+            error: get_error().await,
+            saved: saved_script_packets().await - saved_before,
+        };
+        clear_error().await;
+        set_config(NetConfig::new()).await;
+        outcome
+    }
+
+    async fn run_script() {
+        for (anomaly, addr, bytes) in script() {
+            report(anomaly, addr, &bytes).await;
+        }
+    }
+
+    #[test]
+    fn lenient_mode_counts_anomalies_and_carries_on() {
+        let _state = lock_global_state();
+        let outcome = executor::block_on(run(false, run_script()));
+        assert_eq!(
+            outcome.tolerated,
+            [
+                (Anomaly::UnknownPeer, 1),
+                (Anomaly::UnexpectedResponse, 2),
+                (Anomaly::DecodeFailure, 1),
+                (Anomaly::SessionMismatch, 1),
+            ]
+        );
+        assert_eq!(outcome.error, None);
+        assert_eq!(outcome.saved, 0);
+    }
+
+    #[test]
+    fn strict_mode_shows_anomalies_and_saves_the_packets() {
+        let _state = lock_global_state();
+        let outcome = executor::block_on(run(true, run_script()));
+        assert!(outcome.tolerated.iter().all(|(_, count)| *count == 0));
+        // The last one is shown
+        assert_eq!(
+            outcome.error.as_deref(),
+            Some("Protocol anomaly: UnknownPeer from 10.0.0.9:4000")
+        );
+        assert_eq!(outcome.saved, script().len());
+    }
+
+    #[test]
+    fn oversized_packet_is_judged_by_the_mode() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        for strict_protocol in [false, true] {
+            let outcome = executor::block_on(run(strict_protocol, async {
+                let socket = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+                let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let mut oversized = vec![0xa7, 0x68];
+                oversized.resize(MAX_PACKET_SIZE + 1, 0);
+                peer.send_to(&oversized, socket.local_addr().unwrap())
+                    .await
+                    .unwrap();
+                assert!(recieve_p2p_packet(&socket).await.is_err());
+            }));
+
+            let decode_failures = outcome.tolerated[Anomaly::DecodeFailure.index()].1;
+            if strict_protocol {
+                assert_eq!(decode_failures, 0);
+                assert!(outcome.error.unwrap().contains("DecodeFailure"));
+                assert_eq!(outcome.saved, 1);
+            } else {
+                assert_eq!(decode_failures, 1);
+                assert_eq!(outcome.error, None);
+                assert_eq!(outcome.saved, 0);
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! The responses the host sent to the last requests of the client, so a request that comes again
+//! gets the same answer instead of being handled twice.
+//!
+//! UDP can deliver a packet twice, and game actions are sent again while they have no response. A
+//! request is known by its session- and transaction ID. When it comes again, the response it got
+//! is sent again, even if handling it now would give another answer, e.g. a move rejected the
+//! first time that would fit now. The client only takes the first response it gets anyway.
+//!
+//! Only the `MAX_ANSWERED` requests used last are remembered. A duplicate older than them falls
+//! through to the checks of each request, like `taken_moves` for moves.
+
+use std::collections::VecDeque;
+
+use super::{P2pRequest, P2pResponse};
+
+/// The amount of responses remembered. A request is sent again for a few seconds at most, in
+/// which the client sends far fewer requests than this.
+pub const MAX_ANSWERED: usize = 32;
+
+/// The session- and transaction ID of a request.
+type RequestKey = (u16, u16);
+
+/// The last responses sent, the one used last at the back.
+pub struct AnsweredRequests {
+    responses: VecDeque<(RequestKey, P2pResponse)>,
+}
+
+impl AnsweredRequests {
+    pub const fn new() -> Self {
+        Self {
+            responses: VecDeque::new(),
+        }
+    }
+
+    fn key(req: &P2pRequest) -> RequestKey {
+        (req.session_id, req.transaction_id)
+    }
+
+    /// Get the response `req` got before, if it's a duplicate.
+    pub fn get(&mut self, req: &P2pRequest) -> Option<P2pResponse> {
+        let key = Self::key(req);
+        let index = self.responses.iter().position(|(k, _)| *k == key)?;
+        // Used again, so it's the last one to be forgotten
+        let entry = self.responses.remove(index)?;
+        let response = entry.1.clone();
+        self.responses.push_back(entry);
+        Some(response)
+    }
+
+    /// Remember the `response` sent to `req`. The response used the longest ago is forgotten,
+    /// if there are `MAX_ANSWERED` already.
+    pub fn add(&mut self, req: &P2pRequest, response: P2pResponse) {
+        let key = Self::key(req);
+        self.responses.retain(|(k, _)| *k != key);
+        if self.responses.len() == MAX_ANSWERED {
+            self.responses.pop_front();
+        }
+        self.responses.push_back((key, response));
+    }
+
+    /// Forget the responses, when a new client joins.
+    pub fn clear(&mut self) {
+        self.responses.clear();
+    }
+
+    /// The amount of responses remembered.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.responses.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.responses.is_empty()
+    }
+}
+
+impl Default for AnsweredRequests {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::p2p::{P2pRequestPacket, P2pResponsePacket};
+
+    fn ping(transaction_id: u16) -> (P2pRequest, P2pResponse) {
+        let req = P2pRequest::new(0x1a2b, transaction_id, P2pRequestPacket::ping());
+        let response = P2pResponse::new(0x1a2b, transaction_id, P2pResponsePacket::Acknowledge);
+        (req, response)
+    }
+
+    #[test]
+    fn only_the_responses_used_last_are_kept() {
+        let mut answered = AnsweredRequests::new();
+        let (first, response) = ping(0x0001);
+        answered.add(&first, response.clone());
+        for transaction_id in 0x0100..0x0100 + MAX_ANSWERED as u16 - 1 {
+            let (req, response) = ping(transaction_id);
+            answered.add(&req, response);
+        }
+        // Used again, so the oldest of the others is forgotten instead
+        assert_eq!(answered.get(&first), Some(response.clone()));
+        let (newest, newest_response) = ping(0x0200);
+        answered.add(&newest, newest_response);
+        assert_eq!(answered.len(), MAX_ANSWERED);
+        assert_eq!(answered.get(&first), Some(response));
+        assert!(answered.get(&ping(0x0100).0).is_none());
+
+        // A new client joins
+        answered.clear();
+        assert!(answered.is_empty());
+    }
+}
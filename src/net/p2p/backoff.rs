@@ -0,0 +1,284 @@
+//! The time between pings while the client is reconnecting. A host that is really gone shouldn't
+//! be pinged at the full rate, so the delay doubles with every failed ping, from
+//! `BACKOFF_BASE_MS` up to `BACKOFF_CAP_MS`. Each delay is moved randomly by up to `JITTER`, so
+//! peers that lost the connection at the same time don't ping in lockstep.
+//!
+//! How long the client keeps trying is up to the `AbandonmentPolicy` of the game, however many
+//! pings that was, so the time until `Disconnected` doesn't depend on the delays.
+//!
+//! Something that gives up after a number of attempts instead, like joining a host, is spaced out
+//! by a `RetryPolicy`.
+
+use std::time::{Duration, Instant};
+
+/// The delay after the first failed ping.
+pub const BACKOFF_BASE_MS: u64 = 250;
+/// The longest delay between two pings.
+pub const BACKOFF_CAP_MS: u64 = 5_000;
+/// The most a delay is moved by jitter, as a fraction of the delay.
+pub const JITTER: f64 = 0.2;
+
+/// How far apart, and how many times, something is tried before giving up. The delay after the
+/// first failed attempt is `initial`, and each delay after it is `multiplier` times the one before,
+/// up to `max_interval`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The delay after the first failed attempt.
+    pub initial: Duration,
+    /// How many times longer each delay is than the one before.
+    pub multiplier: f64,
+    /// The longest delay between two attempts.
+    pub max_interval: Duration,
+    /// The most attempts, including the first.
+    pub max_attempts: u32,
+}
+
+impl RetryPolicy {
+    /// The delay before the next attempt, or `None` if it's time to give up.
+    ///
+    /// ## Params
+    /// * `attempt` - The attempt that failed, counted from 1.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        // An infinite delay from a large exponent is capped like any other
+        let delay = self.initial.as_secs_f64() * self.multiplier.powi(exponent);
+        Some(Duration::from_secs_f64(
+            delay.min(self.max_interval.as_secs_f64()),
+        ))
+    }
+}
+
+/// The delay before the next ping, without jitter.
+///
+/// ## Params
+/// * `tries` - The amount of failed pings since the connection was lost.
+pub const fn backoff_ms(tries: u8) -> u64 {
+    // The cap is reached long before the shift could overflow
+    let tries = if tries > 16 { 16 } else { tries };
+    let delay = BACKOFF_BASE_MS << tries;
+    if delay > BACKOFF_CAP_MS {
+        BACKOFF_CAP_MS
+    } else {
+        delay
+    }
+}
+
+/// Move `delay_ms` by `offset` times `JITTER` of it.
+///
+/// ## Params
+/// * `delay_ms` - The delay from `backoff_ms()`.
+/// * `offset` - A number from -1 to 1.
+pub fn jittered_ms(delay_ms: u64, offset: f64) -> u64 {
+    let offset = offset.clamp(-1.0, 1.0);
+    (delay_ms as f64 * (1.0 + offset * JITTER)).round() as u64
+}
+
+/// The delay before the next ping, with random jitter.
+///
+/// ## Params
+/// * `tries` - The amount of failed pings since the connection was lost.
+pub fn backoff_delay(tries: u8) -> Duration {
+    let offset = rand::random::<f64>() * 2.0 - 1.0;
+    Duration::from_millis(jittered_ms(backoff_ms(tries), offset))
+}
+
+/// How long the connection has been lost, counted from the first failed ping, however many pings
+/// were sent since. This is what the `AbandonmentPolicy` is measured against.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Outage {
+    lost_at: Option<Instant>,
+}
+
+impl Outage {
+    /// The connection was lost at `now`.
+    pub fn start(&mut self, now: Instant) {
+        self.lost_at = Some(now);
+    }
+
+    /// The connection is back.
+    pub fn end(&mut self) {
+        self.lost_at = None;
+    }
+
+    /// Count the outage from `now` instead, if there is one. The time asleep doesn't count against
+    /// the host.
+    pub fn restart(&mut self, now: Instant) {
+        if self.lost_at.is_some() {
+            self.start(now);
+        }
+    }
+
+    /// The time since the connection was lost, starting the outage at `now` if there is none.
+    pub fn gone_for(&mut self, now: Instant) -> Duration {
+        now.saturating_duration_since(*self.lost_at.get_or_insert(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{game::options::AbandonmentPolicy, net::p2p::net_loop::REQUEST_TIMEOUT_MS};
+
+    /// Fail pings the way the client does while reconnecting, on a clock that only moves by the
+    /// delays and the timeouts, and return how long the connection was lost when it gave up, or
+    /// `None` if it was still trying after `max_pings`.
+    ///
+    /// ## Params
+    /// * `offset` - The jitter of every delay, from -1 to 1.
+    fn give_up_after(policy: AbandonmentPolicy, offset: f64, max_pings: u32) -> Option<Duration> {
+        let timeout = Duration::from_millis(REQUEST_TIMEOUT_MS as u64);
+        let mut now = Instant::now();
+        let mut outage = Outage::default();
+        // The first ping that times out starts the outage
+        now += timeout;
+        outage.start(now);
+        let mut tries = 0u8;
+        for _ in 0..max_pings {
+            now += Duration::from_millis(jittered_ms(backoff_ms(tries), offset)) + timeout;
+            let gone_for = outage.gone_for(now);
+            if policy.has_forfeited(gone_for) {
+                return Some(gone_for);
+            }
+            tries = tries.saturating_add(1);
+        }
+        None
+    }
+
+    #[test]
+    fn budget_is_kept_whatever_the_delays() {
+        let timeout = Duration::from_millis(REQUEST_TIMEOUT_MS as u64);
+        let longest_ping = Duration::from_millis(jittered_ms(BACKOFF_CAP_MS, 1.0)) + timeout;
+        for grace_secs in [0, 1, 5, 30, 120] {
+            let grace_period = Duration::from_secs(grace_secs);
+            let policy = AbandonmentPolicy::ForfeitAfter(grace_period);
+            for offset in [-1.0, 0.0, 1.0] {
+                let gone_for = give_up_after(policy, offset, 1_000).expect("Never gave up");
+                assert!(
+                    grace_period <= gone_for && gone_for <= grace_period + longest_ping,
+                    "Gave up after {:?} of {:?}",
+                    gone_for,
+                    grace_period
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn paused_game_never_gives_up() {
+        assert_eq!(
+            give_up_after(AbandonmentPolicy::PauseForever, 0.0, 10_000),
+            None
+        );
+    }
+
+    #[test]
+    fn outage_is_counted_from_the_first_failed_ping() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut outage = Outage::default();
+
+        // Without an outage, the first failed ping starts one
+        assert_eq!(outage.gone_for(at(3)), Duration::ZERO);
+        assert_eq!(outage.gone_for(at(10)), Duration::from_secs(7));
+
+        // Waking up starts it over, and a pong ends it
+        outage.restart(at(12));
+        assert_eq!(outage.gone_for(at(15)), Duration::from_secs(3));
+        outage.end();
+        assert_eq!(outage.gone_for(at(20)), Duration::ZERO);
+
+        // Waking up while connected doesn't start one
+        outage.end();
+        outage.restart(at(21));
+        assert_eq!(outage.lost_at, None);
+
+        // A clock that seems to go back doesn't panic
+        outage.start(at(30));
+        assert_eq!(outage.gone_for(at(25)), Duration::ZERO);
+    }
+
+    /// The delays after each failed attempt of `policy`, up to the first `None` and one past it.
+    fn schedule(policy: RetryPolicy) -> Vec<Option<Duration>> {
+        (1..=policy.max_attempts + 1)
+            .map(|attempt| policy.delay(attempt))
+            .collect()
+    }
+
+    fn ms(ms: u64) -> Option<Duration> {
+        Some(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn join_retry_schedule() {
+        use crate::net::interface::JOIN_RETRY;
+
+        let secs = |s| Some(Duration::from_secs(s));
+        assert_eq!(
+            schedule(JOIN_RETRY),
+            [secs(1), secs(2), secs(4), secs(8), None, None]
+        );
+    }
+
+    #[test]
+    fn delays_are_capped() {
+        let policy = RetryPolicy {
+            initial: Duration::from_millis(10),
+            multiplier: 3.0,
+            max_interval: Duration::from_millis(50),
+            max_attempts: 5,
+        };
+        assert_eq!(
+            schedule(policy),
+            [ms(10), ms(30), ms(50), ms(50), None, None]
+        );
+
+        // A delay too large for a float stays at the cap instead of panicking
+        let policy = RetryPolicy {
+            max_attempts: u32::MAX,
+            ..policy
+        };
+        assert_eq!(policy.delay(u32::MAX - 1), ms(50));
+    }
+
+    #[test]
+    fn constant_and_single_attempt_policies() {
+        let constant = RetryPolicy {
+            initial: Duration::from_millis(20),
+            multiplier: 1.0,
+            max_interval: Duration::from_secs(1),
+            max_attempts: 3,
+        };
+        assert_eq!(schedule(constant), [ms(20), ms(20), None, None]);
+
+        let once = RetryPolicy {
+            max_attempts: 1,
+            ..constant
+        };
+        assert_eq!(schedule(once), [None, None]);
+    }
+
+    #[test]
+    fn ping_backoff_doubles_up_to_the_cap() {
+        let delays: Vec<u64> = (0..7).map(backoff_ms).collect();
+        assert_eq!(delays, [250, 500, 1_000, 2_000, 4_000, 5_000, 5_000]);
+        assert_eq!(backoff_ms(u8::MAX), BACKOFF_CAP_MS);
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        assert_eq!(jittered_ms(1_000, 0.0), 1_000);
+        assert_eq!(jittered_ms(1_000, -1.0), 800);
+        assert_eq!(jittered_ms(1_000, 1.0), 1_200);
+        assert_eq!(jittered_ms(1_000, 5.0), 1_200);
+        assert_eq!(jittered_ms(1_000, -5.0), 800);
+
+        for tries in 0..20 {
+            let delay = backoff_delay(tries).as_millis() as u64;
+            let base = backoff_ms(tries);
+            assert!(jittered_ms(base, -1.0) <= delay && delay <= jittered_ms(base, 1.0));
+        }
+    }
+}
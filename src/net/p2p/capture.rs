@@ -0,0 +1,97 @@
+use std::{collections::VecDeque, net::SocketAddr};
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+use tokio::sync::Mutex;
+
+use super::{P2pPacket, P2pRequestPacket, P2pResponsePacket};
+
+/// How many packet summaries are kept.
+const CAPTURE_LEN: usize = 256;
+
+lazy_static! {
+    /// The summaries of the last `CAPTURE_LEN` packets sent and recieved. This is always on, so
+    /// there is something to look at when the game stops responding.
+    static ref CAPTURE: Mutex<VecDeque<String>> = Mutex::const_new(VecDeque::new());
+}
+
+/// Which way a packet went.
+#[derive(Clone, Copy, Debug)]
+pub enum Direction {
+    Sent,
+    Recieved,
+}
+
+/// Save a summary of a packet. Usernames aren't included in the summary.
+///
+/// ## Params
+/// * `direction` - If the packet was sent or recieved.
+/// * `packet` - The packet.
+/// * `addr` - The address the packet was sent to, or recieved from.
+pub async fn record(direction: Direction, packet: &P2pPacket, addr: SocketAddr) {
+    let arrow = match direction {
+        Direction::Sent => "->",
+        Direction::Recieved => "<-",
+    };
+    let summary = format!("{} {} {:?} {}", Utc::now(), arrow, addr, summarize(packet));
+    push(summary).await;
+}
+
+/// Save a note with the full bytes of a packet, usernames included. Used for protocol anomalies in
+/// strict mode.
+///
+/// ## Params
+/// * `note` - What happened.
+/// * `bytes` - The bytes of the packet.
+pub async fn record_bytes(note: &str, bytes: &[u8]) {
+    let summary = format!("{} !! {} bytes={}", Utc::now(), note, hex::encode(bytes));
+    push(summary).await;
+}
+
+async fn push(summary: String) {
+    let mut capture = CAPTURE.lock().await;
+    if capture.len() >= CAPTURE_LEN {
+        capture.pop_front();
+    }
+    capture.push_back(summary);
+}
+
+/// Get the saved packet summaries, oldest first.
+pub async fn get_capture() -> Vec<String> {
+    CAPTURE.lock().await.iter().cloned().collect()
+}
+
+fn summarize(packet: &P2pPacket) -> String {
+    match packet {
+        P2pPacket::Request(req) => {
+            let packet = match &req.packet {
+                P2pRequestPacket::Connect { join_code, .. } => {
+                    format!("Connect {{ join_code: {:?}, .. }}", join_code)
+                }
+                P2pRequestPacket::Ping { payload } => {
+                    format!("Ping {{ payload: {} bytes }}", payload.len())
+                }
+                packet => format!("{:?}", packet),
+            };
+            format!(
+                "Request session={:#06x} transaction={:#06x} {}",
+                req.session_id, req.transaction_id, packet
+            )
+        }
+        P2pPacket::Response(resp) => {
+            let packet = match &resp.packet {
+                P2pResponsePacket::Connect { client_color, .. } => {
+                    format!("Connect {{ client_color: {:?}, .. }}", client_color)
+                }
+                P2pResponsePacket::Pong { payload } => {
+                    format!("Pong {{ payload: {} bytes }}", payload.len())
+                }
+                packet => format!("{:?}", packet),
+            };
+            format!(
+                "Response session={:#06x} transaction={:#06x} {}",
+                resp.session_id, resp.transaction_id, packet
+            )
+        }
+    }
+}
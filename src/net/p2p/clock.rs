@@ -0,0 +1,122 @@
+//! Tells when the computer has been asleep, from the gaps between iterations of a loop.
+//!
+//! All durations in the network loop are measured with `Instant`, which never goes backwards.
+//! But on some platforms it keeps counting while the computer is suspended, so after a resume
+//! every timer looks like it ran out at once. A peer that was pinged a second before the suspend
+//! shouldn't forfeit because our own computer slept for two hours, so the loops that time out
+//! the other player check for a gap first, and start their timers over when they find one.
+//!
+//! Wall-clock time (`chrono`) is only used for the timestamps in logs and captures, never to
+//! measure a duration, so an NTP step can't make one negative.
+
+use std::time::{Duration, Instant};
+
+/// A loop of the network loop that goes this long between two iterations has been paused by the
+/// computer sleeping. The slowest loop is a reconnecting client, which waits for the longest
+/// backoff and a request timeout, well below this.
+pub const MAX_LOOP_GAP: Duration = Duration::from_secs(15);
+
+/// Measures the time between iterations of a loop.
+pub struct JumpDetector {
+    last_check: Instant,
+}
+
+impl JumpDetector {
+    pub fn new() -> Self {
+        Self {
+            last_check: Instant::now(),
+        }
+    }
+
+    /// Call once per iteration of the loop. Returns how long it has been since the last call, if
+    /// that was longer than `MAX_LOOP_GAP`.
+    pub fn check(&mut self) -> Option<Duration> {
+        self.check_at(Instant::now())
+    }
+
+    /// `check()`, at the time `now`.
+    pub fn check_at(&mut self, now: Instant) -> Option<Duration> {
+        let gap = now.saturating_duration_since(self.last_check);
+        self.last_check = now;
+        (gap > MAX_LOOP_GAP).then_some(gap)
+    }
+}
+
+impl Default for JumpDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        game::options::AbandonmentPolicy,
+        net::p2p::{
+            backoff::{backoff_ms, Outage},
+            net_loop::REQUEST_TIMEOUT_MS,
+        },
+    };
+
+    const HOURS_ASLEEP: u64 = 2;
+
+    #[test]
+    fn only_a_gap_longer_than_the_max_is_a_jump() {
+        let start = Instant::now();
+        let mut jumps = JumpDetector { last_check: start };
+        let mut now = start;
+        for _ in 0..10 {
+            now += Duration::from_secs(1);
+            assert_eq!(jumps.check_at(now), None);
+        }
+        now += MAX_LOOP_GAP;
+        assert_eq!(jumps.check_at(now), None);
+
+        let asleep = Duration::from_secs(HOURS_ASLEEP * 60 * 60);
+        now += asleep;
+        assert_eq!(jumps.check_at(now), Some(asleep));
+        // The loop goes on as before after waking up
+        now += Duration::from_secs(1);
+        assert_eq!(jumps.check_at(now), None);
+
+        // A time before the last check is no gap, not a negative one
+        assert_eq!(jumps.check_at(now - Duration::from_secs(5)), None);
+    }
+
+    #[test]
+    fn sleeping_while_reconnecting_doesnt_forfeit() {
+        let grace_period = Duration::from_secs(30);
+        let policy = AbandonmentPolicy::ForfeitAfter(grace_period);
+        let start = Instant::now();
+        let mut jumps = JumpDetector { last_check: start };
+        let mut outage = Outage::default();
+        outage.start(start);
+
+        // A few pings fail, like `client_ping_host()`, and then the computer sleeps
+        let mut now = start;
+        let timeout = Duration::from_millis(REQUEST_TIMEOUT_MS as u64);
+        for tries in 0..3 {
+            now += Duration::from_millis(backoff_ms(tries)) + timeout;
+            assert_eq!(jumps.check_at(now), None);
+        }
+        let before_sleep = outage.gone_for(now);
+        now += Duration::from_secs(HOURS_ASLEEP * 60 * 60);
+
+        // The client wakes up, finds the gap and starts the grace period over
+        assert!(jumps.check_at(now).is_some());
+        outage.restart(now);
+        now += timeout;
+        let gone_for = outage.gone_for(now);
+        assert!(gone_for < before_sleep);
+        assert!(!policy.has_forfeited(gone_for));
+
+        // The grace period still ends, if the host stays gone after the wake up
+        let woke_up = now - timeout;
+        while now < woke_up + grace_period {
+            now += Duration::from_millis(backoff_ms(u8::MAX)) + timeout;
+            assert_eq!(jumps.check_at(now), None);
+        }
+        assert!(policy.has_forfeited(outage.gone_for(now)));
+    }
+}
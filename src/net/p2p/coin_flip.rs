@@ -0,0 +1,178 @@
+//! The coin flip deciding who plays White, so the host can't just pick its color.
+//!
+//! It's a commit-reveal between the two peers:
+//! 1. The host picks a random nonce when it starts hosting, and sends a commitment to it (the
+//!    SHA-256 hash of the nonce) in its answer to the clients `Probe`.
+//! 2. The client picks its own nonce, and sends it in `Connect`.
+//! 3. The host reveals its nonce in the `Connect` response, along with the clients color.
+//! 4. The client checks that the nonce matches the commitment, and that the color is derived
+//!    correctly from both nonces.
+//!
+//! Since the host committed to its nonce before it saw the clients, it can't choose the outcome.
+//! The host uses a nonce for one `Connect` only, and picks a new one after revealing it, so a
+//! client that joins later can't know it in advance.
+//!
+//! If both players picked a color, and they picked different ones, they get them instead. The
+//! client only accepts that if it got the color it asked for, so the host can't use it to cheat.
+
+use sha2::{Digest, Sha256};
+
+use crate::game::PieceColor;
+
+/// The length of a commitment in bytes.
+pub const COMMITMENT_LEN: usize = 32;
+
+/// A commitment to a nonce.
+pub type Commitment = [u8; COMMITMENT_LEN];
+
+/// Pick a random nonce for the coin flip.
+pub fn new_nonce() -> u64 {
+    rand::random()
+}
+
+/// Get the commitment to `nonce`.
+pub fn commit(nonce: u64) -> Commitment {
+    Sha256::digest(nonce.to_be_bytes()).into()
+}
+
+/// Check that `nonce` is the nonce `commitment` was made from.
+pub fn verify(commitment: &Commitment, nonce: u64) -> bool {
+    commit(nonce) == *commitment
+}
+
+/// Get the clients color from the nonces of both peers. The client plays White if the lowest bit
+/// of the nonces XOR'ed together is 0.
+///
+/// ## Params
+/// * `client_nonce` - The nonce the client sent in `Connect`.
+/// * `host_nonce` - The nonce the host revealed in the `Connect` response.
+pub const fn client_color(client_nonce: u64, host_nonce: u64) -> PieceColor {
+    if (client_nonce ^ host_nonce) & 1 == 0 {
+        PieceColor::White
+    } else {
+        PieceColor::Black
+    }
+}
+
+/// Get the clients color, as the host decides it. The players get the colors they picked, if both
+/// picked one and they're different. Otherwise it's decided by the coin flip.
+///
+/// ## Params
+/// * `client_nonce` - The nonce the client sent in `Connect`.
+/// * `host_nonce` - The nonce the host committed to.
+/// * `client_preference` - The color the client sent in `Connect`, if any.
+/// * `host_preference` - The color the host wishes to play, if any.
+pub const fn assign_client_color(
+    client_nonce: u64,
+    host_nonce: u64,
+    client_preference: Option<PieceColor>,
+    host_preference: Option<PieceColor>,
+) -> PieceColor {
+    match (client_preference, host_preference) {
+        (Some(PieceColor::White), Some(PieceColor::Black)) => PieceColor::White,
+        (Some(PieceColor::Black), Some(PieceColor::White)) => PieceColor::Black,
+        _ => client_color(client_nonce, host_nonce),
+    }
+}
+
+/// Check the hosts answer to `Connect` on the client. The nonce must match the commitment, and the
+/// color must be the result of the coin flip, or the color the client asked for.
+///
+/// ## Params
+/// * `commitment` - The hosts commitment, from its answer to the probe.
+/// * `client_nonce` - The nonce the client sent in `Connect`.
+/// * `host_nonce` - The nonce the host revealed.
+/// * `client_preference` - The color the client sent in `Connect`, if any.
+/// * `client_color` - The color the host gave the client.
+pub fn is_fair(
+    commitment: &Commitment,
+    client_nonce: u64,
+    host_nonce: u64,
+    client_preference: Option<PieceColor>,
+    client_color: PieceColor,
+) -> bool {
+    verify(commitment, host_nonce)
+        && (client_color == self::client_color(client_nonce, host_nonce)
+            || client_preference == Some(client_color))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commitment_only_matches_its_nonce() {
+        for nonce in [0, 1, 42, u64::MAX] {
+            let commitment = commit(nonce);
+            assert!(verify(&commitment, nonce));
+            assert!(!verify(&commitment, nonce ^ 1));
+            assert!(!verify(&commitment, nonce.wrapping_add(1 << 32)));
+        }
+        assert_ne!(commit(1), commit(2));
+        assert_eq!(commit(7), commit(7));
+    }
+
+    #[test]
+    fn lowest_bits_decide_the_color() {
+        assert_eq!(client_color(0, 0), PieceColor::White);
+        assert_eq!(client_color(1, 1), PieceColor::White);
+        assert_eq!(client_color(0, 1), PieceColor::Black);
+        assert_eq!(client_color(1, 0), PieceColor::Black);
+        // The higher bits don't matter
+        assert_eq!(client_color(u64::MAX - 1, 2), PieceColor::White);
+        assert_eq!(client_color(u64::MAX, 2), PieceColor::Black);
+    }
+
+    #[test]
+    fn each_color_is_won_about_half_the_time() {
+        let host_nonce = new_nonce();
+        let white = (0..1000)
+            .filter(|_| client_color(new_nonce(), host_nonce) == PieceColor::White)
+            .count();
+        assert!((400..600).contains(&white), "{}", white);
+    }
+
+    #[test]
+    fn different_preferences_replace_the_coin_flip() {
+        let (white, black) = (Some(PieceColor::White), Some(PieceColor::Black));
+        for (client_nonce, host_nonce) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+            let flip = client_color(client_nonce, host_nonce);
+            let assign = |client, host| assign_client_color(client_nonce, host_nonce, client, host);
+            assert_eq!(assign(white, black), PieceColor::White);
+            assert_eq!(assign(black, white), PieceColor::Black);
+            // Both players wish the same color, or one has no wish
+            assert_eq!(assign(white, white), flip);
+            assert_eq!(assign(black, black), flip);
+            assert_eq!(assign(white, None), flip);
+            assert_eq!(assign(None, black), flip);
+            assert_eq!(assign(None, None), flip);
+        }
+    }
+
+    #[test]
+    fn client_only_accepts_a_fair_answer() {
+        let (client_nonce, host_nonce) = (new_nonce(), new_nonce());
+        let commitment = commit(host_nonce);
+        let flip = client_color(client_nonce, host_nonce);
+        let other = flip.get_opposite();
+        let fair =
+            |preference, color| is_fair(&commitment, client_nonce, host_nonce, preference, color);
+
+        assert!(fair(None, flip));
+        assert!(!fair(None, other));
+        // The host may give the client the color it asked for
+        assert!(fair(Some(other), other));
+        assert!(!fair(Some(flip), other));
+        // A nonce the host didn't commit to is never fair
+        let wrong_nonce = host_nonce ^ 1;
+        let flip = client_color(client_nonce, wrong_nonce);
+        assert!(!is_fair(&commitment, client_nonce, wrong_nonce, None, flip));
+        assert!(!is_fair(
+            &commitment,
+            client_nonce,
+            wrong_nonce,
+            Some(flip),
+            flip
+        ));
+    }
+}
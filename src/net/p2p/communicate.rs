@@ -1,51 +1,438 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{cell::RefCell, net::SocketAddr, panic, sync::Arc, time::Instant};
 
-use crate::net::net_utils::{FromPacket, NetworkError, ToPacket};
+use thiserror::Error;
+use tokio::sync::Mutex;
 
-use super::P2pPacket;
+use crate::net::{
+    net_utils::{FromPacket, NetworkError, PacketError, ToPacket},
+    session_log,
+    status::{add_corrupt_packet, add_recieved_packet, add_sent_packet},
+};
 
-/// Send a packet to the other machine over a P2P UDP protocol.
+use super::{
+    anomaly::{report, Anomaly},
+    capture::{self, Direction},
+    fragment::{self, Reassembly},
+    simulate, wire, ForeignVersion, P2pPacket,
+};
+
+/// The largest packet that can be sent or recieved. This keeps a packet inside a single datagram
+/// on a normal 1500 byte MTU, with room to spare for the IP and UDP headers.
+pub const MAX_PACKET_SIZE: usize = 1400;
+
+thread_local! {
+    /// A buffer packets are written into before they are sent, so sending doesn't allocate.
+    static SEND_BUFFER: RefCell<Vec<u8>> = RefCell::new(Vec::with_capacity(MAX_PACKET_SIZE));
+}
+
+/// The fragments recieved of packets too large for one datagram, until the rest of them come.
+static REASSEMBLY: Mutex<Reassembly> = Mutex::const_new(Reassembly::new());
+
+/// Append the CRC32 checksum of `bytes` to it, making a datagram ready to send.
+pub fn append_checksum(bytes: &mut Vec<u8>) {
+    let checksum = crc32fast::hash(bytes);
+    bytes.extend_from_slice(&checksum.to_be_bytes());
+}
+
+/// Check the checksum at the end of a recieved datagram. Returns the packet in front of it, or a
+/// `PacketError::ChecksumMismatch` if the datagram was changed on the way, or isn't ours.
+/// A datagram with no packet in front of its checksum is a `PacketError::InvalidLength`, since
+/// the checksum of nothing is 0, and four zero bytes would pass.
+pub fn strip_checksum(datagram: &[u8]) -> Result<&[u8], PacketError> {
+    if datagram.len() <= wire::CHECKSUM_LEN {
+        return Err(PacketError::invalid_length(
+            wire::CHECKSUM_LEN + 1,
+            datagram.len(),
+        ));
+    }
+    let (packet, checksum) = datagram.split_at(datagram.len() - wire::CHECKSUM_LEN);
+    let got = u32::from_be_bytes(checksum.try_into().unwrap());
+    let computed = crc32fast::hash(packet);
+    if computed != got {
+        return Err(PacketError::ChecksumMismatch { computed, got });
+    }
+    Ok(packet)
+}
+
+/// Send a packet to the other machine over a P2P UDP protocol, followed by its checksum.
+/// A packet too large for one datagram is split into fragments, see `fragment`. Returns a
+/// `PacketError::TooLarge` if the packet is bigger than `fragment::MAX_MESSAGE_SIZE`.
 /// # Example:
-/// ```
+/// ```ignore
 /// let socket = tokio::net::UdpSocket::bind(("0.0.0.0", 1000)).await?;
 ///
 /// let to_address = SocketAddr::new(IpAddr::from_str("0.0.0.0")?, 2000));
 ///
-/// let request = P2pRequest::new(0, P2pRequestPacket::Ping);
+/// let request = P2pRequest::new(0, P2pRequestPacket::ping());
 ///
 /// send_p2p_packet::<P2pRequest>(socket, request, to_address)?;
-/// ```
-pub async fn send_p2p_packet<T: ToPacket>(
+/// ```ignore
+pub async fn send_p2p_packet<T: ToPacket + Into<P2pPacket>>(
+    socket: &Arc<tokio::net::UdpSocket>,
+    packet: T,
+    to: SocketAddr,
+) -> anyhow::Result<usize> {
+    // The buffer is taken out while sending, since the task can move to another thread at an
+    // await, and put back in the slot of the thread it ends on.
+    let mut bytes = SEND_BUFFER.with(|buffer| std::mem::take(&mut *buffer.borrow_mut()));
+    bytes.clear();
+    packet.write_packet(&mut bytes);
+
+    let result = if bytes.len() + wire::CHECKSUM_LEN > MAX_PACKET_SIZE {
+        send_fragments(socket, packet, &bytes, to).await
+    } else {
+        append_checksum(&mut bytes);
+        capture::record(Direction::Sent, &packet.into(), to).await;
+        send_datagram(socket, &bytes, to).await
+    };
+    SEND_BUFFER.with(|buffer| *buffer.borrow_mut() = bytes);
+    result
+}
+
+/// Send `bytes`, the encoded `packet`, as fragments. Returns the amount of bytes sent in all of
+/// them.
+async fn send_fragments<T: Into<P2pPacket>>(
     socket: &Arc<tokio::net::UdpSocket>,
     packet: T,
+    bytes: &[u8],
     to: SocketAddr,
 ) -> anyhow::Result<usize> {
-    match socket.send_to(packet.to_packet().as_slice(), to).await {
-        Ok(bytes) => Ok(bytes),
+    let fragments = fragment::split(bytes)?;
+    capture::record(Direction::Sent, &packet.into(), to).await;
+
+    let mut sent = 0;
+    for fragment in &fragments {
+        sent += send_datagram(socket, fragment, to).await?;
+    }
+    Ok(sent)
+}
+
+/// Send one datagram, and count it in the `ConnectionStats`.
+async fn send_datagram(
+    socket: &Arc<tokio::net::UdpSocket>,
+    bytes: &[u8],
+    to: SocketAddr,
+) -> anyhow::Result<usize> {
+    if bytes.len() > MAX_PACKET_SIZE {
+        return Err(PacketError::too_large(bytes.len(), MAX_PACKET_SIZE).into());
+    }
+
+    // A simulated bad network decides when the packet is sent, and how many times
+    if let Some(delays) = simulate::next_packet() {
+        for delay in delays {
+            let (socket, bytes) = (socket.clone(), bytes.to_vec());
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                if let Err(e) = socket.send_to(&bytes, to).await {
+                    session_log::log(format!("error: failed to send a simulated packet: {}", e));
+                }
+            });
+        }
+        add_sent_packet(bytes.len()).await;
+        return Ok(bytes.len());
+    }
+
+    match socket.send_to(bytes, to).await {
+        Ok(bytes) => {
+            add_sent_packet(bytes).await;
+            Ok(bytes)
+        }
         Err(e) => Err(NetworkError::send_error(&e.to_string()).into()),
     }
 }
 
+/// A packet from a peer speaking another protocol version, which was recieved but can't be read.
+#[derive(Clone, Copy, Debug, Error)]
+#[error("{header} (from {from})")]
+pub struct ForeignPacket {
+    /// The header of the packet.
+    pub header: ForeignVersion,
+    /// The address the packet came from.
+    pub from: SocketAddr,
+}
+
 /// Recieve a packet from the other machine over a P2P UDP protocol.
 /// Returns a tuple of the data struct, and the `SocketAddr` that you got the data from.
+/// Datagrams bigger than `MAX_PACKET_SIZE` are rejected with a `PacketError::TooLarge`, datagrams
+/// with a wrong checksum with a `PacketError::ChecksumMismatch`, and packets from another protocol
+/// version with a `ForeignPacket`. Every datagram is counted in the `ConnectionStats`, and datagrams
+/// with a wrong checksum on their own.
+/// The fragments of a packet too large for one datagram are kept until the last one comes, and
+/// the packet they make up is returned then.
 /// # Example:
-/// ```
+/// ```ignore
 /// let socket = tokio::net::UdpSocket::bind(("0.0.0.0", 8080)).await?;
 ///
 /// let (response, addr) = recieve_p2p_packet::<P2pResponse>(socket)?;
-/// ```
+/// ```ignore
 pub async fn recieve_p2p_packet(
     socket: &Arc<tokio::net::UdpSocket>,
 ) -> anyhow::Result<(P2pPacket, SocketAddr)> {
-    let mut buffer = vec![0; 1024];
-    match socket.recv_from(&mut buffer).await {
-        Ok((len, addr)) => {
-            buffer.resize(len, 0);
-            let response = P2pPacket::from_packet(buffer.to_vec())?;
-            Ok((response, addr))
+    // One byte more than the max size, so an oversized packet can't pass as a truncated one
+    let mut buffer = vec![0; MAX_PACKET_SIZE + 1];
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buffer).await {
+            Ok(recieved) => recieved,
+            Err(e) => return Err(NetworkError::recieve_error(&e.to_string()).into()),
+        };
+        add_recieved_packet(len).await;
+        if len > MAX_PACKET_SIZE {
+            report(Anomaly::DecodeFailure, addr, &buffer).await;
+            return Err(PacketError::too_large(len, MAX_PACKET_SIZE).into());
         }
-        Err(e) => {
-            return Err(NetworkError::recieve_error(&e.to_string()).into());
+        let datagram = &buffer[..len];
+        // A damaged packet could decode to something else, so it's dropped before decoding
+        let packet = match strip_checksum(datagram) {
+            Ok(packet) => packet,
+            Err(e) => {
+                add_corrupt_packet().await;
+                return Err(e.into());
+            }
+        };
+        let packet = if fragment::is_fragment(packet) {
+            match REASSEMBLY.lock().await.add(packet, addr, Instant::now()) {
+                Ok(Some(packet)) => packet,
+                // Wait for the rest of the fragments
+                Ok(None) => continue,
+                Err(e) => {
+                    report(Anomaly::DecodeFailure, addr, datagram).await;
+                    return Err(e.into());
+                }
+            }
+        } else {
+            packet.to_vec()
+        };
+
+        // A decoder that panics has a bug, but the packet came from outside, so it's dropped like
+        // any other packet we can't read. The task stays up, and can't be made to restart
+        let decoded = panic::catch_unwind(|| P2pPacket::from_packet(packet))
+            .unwrap_or_else(|_| Err(PacketError::data_error("The decoder panicked").into()));
+        let response = match decoded {
+            Ok(response) => response,
+            // Not a broken packet, but one we can't read. The sender may be told so
+            Err(e) => match e.downcast::<ForeignVersion>() {
+                Ok(header) => return Err(ForeignPacket { header, from: addr }.into()),
+                Err(e) => {
+                    report(Anomaly::DecodeFailure, addr, datagram).await;
+                    return Err(e);
+                }
+            },
+        };
+        capture::record(Direction::Recieved, &response, addr).await;
+        return Ok((response, addr));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor;
+
+    use super::*;
+    use crate::net::{
+        p2p::{
+            fragment::MAX_MESSAGE_SIZE, lock_global_state, runtime, P2pRequest, P2pRequestPacket,
+            P2pResponse, P2pResponsePacket,
+        },
+        status::{get_connection_stats, reset_connection_stats, ConnectionStats},
+    };
+
+    /// A datagram carrying a game action, as it's sent.
+    fn datagram() -> Vec<u8> {
+        let packet = P2pRequestPacket::game_action(crate::game::GameAction::OfferDraw, 3, 1);
+        let mut bytes = P2pRequest::new(0x1234, 0x0042, packet).to_packet();
+        append_checksum(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn intact_datagram_passes() {
+        let datagram = datagram();
+        let packet = strip_checksum(&datagram).unwrap();
+        assert_eq!(packet, &datagram[..datagram.len() - wire::CHECKSUM_LEN]);
+        assert!(P2pPacket::from_packet(packet.to_vec()).is_ok());
+    }
+
+    #[test]
+    fn every_flipped_bit_is_rejected() {
+        let datagram = datagram();
+        for bit in 0..datagram.len() * 8 {
+            let mut flipped = datagram.clone();
+            flipped[bit / 8] ^= 1 << (bit % 8);
+            assert!(
+                matches!(
+                    strip_checksum(&flipped),
+                    Err(PacketError::ChecksumMismatch { .. })
+                ),
+                "flipping bit {} wasn't noticed",
+                bit
+            );
         }
     }
+
+    #[test]
+    fn truncated_datagram_is_rejected() {
+        let datagram = datagram();
+        for len in 0..datagram.len() {
+            assert!(
+                strip_checksum(&datagram[..len]).is_err(),
+                "the first {} bytes passed",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn checksum_without_packet_is_rejected() {
+        // The CRC32 of nothing is 0
+        assert!(matches!(
+            strip_checksum(&[0, 0, 0, 0]),
+            Err(PacketError::InvalidLength { .. })
+        ));
+        assert!(strip_checksum(&[]).is_err());
+    }
+
+    /// A ping request of `len` bytes in all.
+    fn ping_of_len(len: usize) -> P2pRequest {
+        let empty = P2pRequest::new(0x1234, 0x0042, P2pRequestPacket::Ping { payload: vec![] });
+        let payload = (0..len - empty.encoded_len()).map(|i| i as u8).collect();
+        P2pRequest::new(0x1234, 0x0042, P2pRequestPacket::Ping { payload })
+    }
+
+    #[test]
+    fn packets_around_the_datagram_size() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        executor::block_on(async {
+            let from = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let to = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let addr = to.local_addr().unwrap();
+
+            // Fills a datagram to the last byte
+            let fits = ping_of_len(MAX_PACKET_SIZE - wire::CHECKSUM_LEN);
+            let sent = send_p2p_packet(&from, fits.clone(), addr).await.unwrap();
+            assert_eq!(sent, MAX_PACKET_SIZE);
+            let (recieved, _) = recieve_p2p_packet(&to).await.unwrap();
+            assert_eq!(recieved, fits.into());
+
+            // A byte more is split in two, and put back together
+            let split = ping_of_len(MAX_PACKET_SIZE - wire::CHECKSUM_LEN + 1);
+            let sent = send_p2p_packet(&from, split.clone(), addr).await.unwrap();
+            assert!(sent > MAX_PACKET_SIZE);
+            let (recieved, _) = recieve_p2p_packet(&to).await.unwrap();
+            assert_eq!(recieved, split.into());
+        });
+    }
+
+    #[test]
+    fn packets_around_the_message_size() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        executor::block_on(async {
+            let socket = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let addr = socket.local_addr().unwrap();
+
+            let largest = ping_of_len(MAX_MESSAGE_SIZE);
+            send_p2p_packet(&socket, largest.clone(), addr)
+                .await
+                .unwrap();
+            let (recieved, _) = recieve_p2p_packet(&socket).await.unwrap();
+            assert_eq!(recieved, largest.into());
+
+            let too_large = ping_of_len(MAX_MESSAGE_SIZE + 1);
+            let e = send_p2p_packet(&socket, too_large, addr).await.unwrap_err();
+            assert!(matches!(
+                e.downcast_ref(),
+                Some(PacketError::TooLarge { size, max: MAX_MESSAGE_SIZE })
+                    if *size == MAX_MESSAGE_SIZE + 1
+            ));
+        });
+    }
+
+    #[test]
+    fn ten_kilobytes_come_back_byte_for_byte() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        executor::block_on(async {
+            let from = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let to = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let payload: Vec<u8> = (0..10 * 1024).map(|i| (i % 251) as u8).collect();
+            let packet = P2pRequestPacket::Ping {
+                payload: payload.clone(),
+            };
+            let ping = P2pRequest::new(0x1234, 0x0042, packet);
+            let sent = send_p2p_packet(&from, ping.clone(), to.local_addr().unwrap())
+                .await
+                .unwrap();
+            assert!(sent > payload.len());
+
+            let (recieved, addr) = recieve_p2p_packet(&to).await.unwrap();
+            assert_eq!(addr, from.local_addr().unwrap());
+            let P2pPacket::Request(P2pRequest {
+                packet: P2pRequestPacket::Ping { payload: recieved },
+                ..
+            }) = recieved
+            else {
+                panic!("expected the ping, got {:?}", recieved);
+            };
+            assert_eq!(recieved, payload);
+        });
+    }
+
+    #[test]
+    fn every_datagram_is_counted_both_ways() {
+        const CYCLES: u32 = 3;
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        executor::block_on(async {
+            reset_connection_stats().await;
+            let a = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let b = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+
+            let mut bytes = 0;
+            for id in 0..CYCLES as u16 {
+                let ping = P2pRequest::new(0x1234, 0x0500 + id, P2pRequestPacket::ping());
+                bytes += send_p2p_packet(&a, ping, b_addr).await.unwrap();
+                recieve_p2p_packet(&b).await.unwrap();
+                let pong = P2pResponsePacket::Pong { payload: vec![] };
+                let pong = P2pResponse::new(0x1234, 0x0500 + id, pong);
+                bytes += send_p2p_packet(&b, pong, a_addr).await.unwrap();
+                recieve_p2p_packet(&a).await.unwrap();
+            }
+            let stats = get_connection_stats().await;
+            assert_eq!(
+                (stats.packets_sent, stats.packets_recieved),
+                (2 * CYCLES, 2 * CYCLES)
+            );
+            assert_eq!(
+                (stats.bytes_sent, stats.bytes_recieved),
+                (bytes as u64, bytes as u64)
+            );
+
+            // A packet in two fragments is two datagrams each way
+            let split = ping_of_len(MAX_PACKET_SIZE - wire::CHECKSUM_LEN + 1);
+            bytes += send_p2p_packet(&a, split, b_addr).await.unwrap();
+            recieve_p2p_packet(&b).await.unwrap();
+            // A damaged datagram is recieved, even if it's dropped
+            let mut damaged = datagram();
+            damaged[0] ^= 1;
+            a.send_to(&damaged, b_addr).await.unwrap();
+            assert!(recieve_p2p_packet(&b).await.is_err());
+
+            let stats = get_connection_stats().await;
+            assert_eq!(
+                stats,
+                ConnectionStats {
+                    packets_sent: 2 * CYCLES + 2,
+                    packets_recieved: 2 * CYCLES + 3,
+                    bytes_sent: bytes as u64,
+                    bytes_recieved: (bytes + damaged.len()) as u64,
+                    ..ConnectionStats::default()
+                }
+            );
+
+            reset_connection_stats().await;
+            assert_eq!(get_connection_stats().await, ConnectionStats::default());
+        });
+    }
 }
@@ -0,0 +1,307 @@
+//! Desync detection, without sending whole boards. After applying a move, each peer sends the
+//! other the `position_hash()` of its board in a `P2pRequestPacket::BoardHash`, with the amount of
+//! moves applied.
+//!
+//! The hashes are compared once both peers have one for the same move count, since the hash of
+//! the other peer can come before we have applied the move ourselves. A hash for a move count that
+//! was already hashed replaces the old one, e.g. after a move was taken back and replayed, and is
+//! compared again. A copy of a hash that came before isn't, since it would hide the comparisons made
+//! since. The result of the last comparison is kept in `status`, for showing it.
+//!
+//! A move the host rejected was hashed before it was taken back, so the boards can look different
+//! for a moment. The hash of the replayed board puts that right.
+//!
+//! When the hashes differ, the client asks the host for its board, like after a reconnect, and
+//! the game shows it in the resync preview. The host decides the order of the moves, so it only
+//! waits for the client to do so.
+
+use std::collections::VecDeque;
+
+use tokio::sync::Mutex;
+
+use crate::{
+    game::{position_hash::position_hash, PieceColor, PieceData},
+    net::status::{get_game_options, remove_board_sync, set_board_sync, BoardSync},
+};
+
+use super::{queue, resync, runtime, session::Session, P2pRequestPacket, P2pResponsePacket};
+
+/// The amount of move counts kept for each peer. Only the last few moves can still be waiting for
+/// the hash of the other peer.
+const MAX_HASHES: usize = 8;
+
+static HASHES: Mutex<HashLog> = Mutex::const_new(HashLog::new());
+
+/// The latest board hashes of both peers, each as the move count and the hash, the latest at the
+/// back.
+pub struct HashLog {
+    ours: VecDeque<(u16, u64)>,
+    theirs: VecDeque<(u16, u64)>,
+}
+
+impl HashLog {
+    pub const fn new() -> Self {
+        Self {
+            ours: VecDeque::new(),
+            theirs: VecDeque::new(),
+        }
+    }
+
+    /// Add the hash of our board after `move_count` moves. Returns how it compares to the hash of
+    /// the other peer, if it has sent one for the same move count. Our hashes after `move_count`
+    /// are dropped, since the moves after it were taken back.
+    pub fn add_ours(&mut self, move_count: u16, hash: u64) -> Option<BoardSync> {
+        self.ours.retain(|(count, _)| *count < move_count);
+        insert(&mut self.ours, move_count, hash);
+        let theirs = find(&self.theirs, move_count)?;
+        Some(BoardSync::compare(move_count, hash, theirs))
+    }
+
+    /// Add the hash of the board of the other peer after `move_count` moves. Returns how it
+    /// compares to our hash, if we have one for the same move count. A hash that came before is
+    /// only compared the first time, so a late copy can't hide a newer comparison.
+    pub fn add_theirs(&mut self, move_count: u16, hash: u64) -> Option<BoardSync> {
+        if find(&self.theirs, move_count) == Some(hash) {
+            return None;
+        }
+        insert(&mut self.theirs, move_count, hash);
+        let ours = find(&self.ours, move_count)?;
+        Some(BoardSync::compare(move_count, ours, hash))
+    }
+}
+
+impl Default for HashLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn insert(hashes: &mut VecDeque<(u16, u64)>, move_count: u16, hash: u64) {
+    hashes.retain(|(count, _)| *count != move_count);
+    if hashes.len() == MAX_HASHES {
+        hashes.pop_front();
+    }
+    hashes.push_back((move_count, hash));
+}
+
+fn find(hashes: &VecDeque<(u16, u64)>, move_count: u16) -> Option<u64> {
+    hashes
+        .iter()
+        .find(|(count, _)| *count == move_count)
+        .map(|(_, hash)| *hash)
+}
+
+/// The hash of a board sent in a `P2pRequestPacket::BoardHash`.
+///
+/// ## Params
+/// * `board` - The squares of the board, seen from White's side.
+/// * `move_count` - The amount of moves applied to the board.
+pub fn board_hash(board: &[PieceData], move_count: u16) -> u64 {
+    let side_to_move = PieceColor::side_to_move(move_count);
+    position_hash(board, side_to_move, move_count, &get_game_options())
+}
+
+/// Hash our board after `move_count` moves, compare it to the other peer's, and send it to them.
+/// The hash isn't sent again if it's lost, or if the outgoing queue is full, since the next move
+/// sends a new one.
+///
+/// ## Params
+/// * `board` - Our board, seen from White's side.
+/// * `move_count` - The amount of moves applied to the board.
+pub async fn send_board_hash(board: &[PieceData], move_count: u16) {
+    let hash = board_hash(board, move_count);
+    let sync = HASHES.lock().await.add_ours(move_count, hash);
+    if let Some(sync) = sync {
+        take_comparison(sync).await;
+    }
+
+    let sent = Session::request(P2pRequestPacket::BoardHash { hash, move_count })
+        .await
+        .on_response(|resp| {
+            if let Ok(P2pResponsePacket::Error { kind }) = resp.map(|resp| resp.packet) {
+                println!("The board hash wasn't taken: {:?}", kind);
+            }
+        })
+        .send()
+        .await;
+    if let Err(e) = sent {
+        println!("The board hash wasn't sent: {}", e);
+    }
+}
+
+/// Compare the board hash from the other peer to ours.
+pub async fn take_board_hash(hash: u64, move_count: u16) -> P2pResponsePacket {
+    let sync = HASHES.lock().await.add_theirs(move_count, hash);
+    if let Some(sync) = sync {
+        take_comparison(sync).await;
+    }
+    P2pResponsePacket::Acknowledge
+}
+
+/// Forget the hashes of both peers, for a new game.
+pub async fn clear() {
+    *HASHES.lock().await = HashLog::new();
+    remove_board_sync().await;
+}
+
+/// Keep the result of a comparison, and ask the host for its board if the client's board differs.
+async fn take_comparison(sync: BoardSync) {
+    set_board_sync(sync).await;
+    let BoardSync::Desynced {
+        move_count,
+        ours,
+        theirs,
+    } = sync
+    else {
+        return;
+    };
+    println!(
+        "The boards differ after move {}: {:016x} here, {:016x} on the other peer",
+        move_count, ours, theirs
+    );
+    // Both peers find out, but only the client takes the other board
+    if !queue::is_host() {
+        // Spawned, since the answer is recieved by the loop that may be calling this
+        runtime::spawn(resync::request_resync());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::executor;
+
+    use super::*;
+    use crate::net::{
+        p2p::{lock_global_state, wire, P2pPacket},
+        status::{get_board_sync, take_resync_board},
+    };
+
+    /// The board at the start of a game, seen from White's side.
+    fn start_board() -> Vec<PieceData> {
+        (0..wire::BOARD_LEN)
+            .map(|i| PieceData {
+                color: if i < 12 {
+                    PieceColor::Black
+                } else {
+                    PieceColor::White
+                },
+                is_active: !(12..20).contains(&i),
+                is_king: false,
+            })
+            .collect()
+    }
+
+    /// The start board, with a piece missing.
+    fn corrupted_board() -> Vec<PieceData> {
+        let mut board = start_board();
+        board[22] = PieceData::default();
+        board
+    }
+
+    #[test]
+    fn hashes_are_compared_once_both_peers_have_one() {
+        let board = start_board();
+        let mut log = HashLog::new();
+        // The other peer's hash comes before we have applied the move
+        assert_eq!(log.add_theirs(20, board_hash(&board, 20)), None);
+        assert_eq!(log.add_ours(19, board_hash(&board, 19)), None);
+        let hash = board_hash(&board, 20);
+        assert_eq!(
+            log.add_ours(20, hash),
+            Some(BoardSync::InSync { move_count: 20 })
+        );
+
+        let ours = board_hash(&corrupted_board(), 20);
+        assert_ne!(ours, hash);
+        assert_eq!(
+            log.add_ours(20, ours),
+            Some(BoardSync::Desynced {
+                move_count: 20,
+                ours,
+                theirs: hash,
+            })
+        );
+        // A late copy of their hash doesn't hide it
+        assert_eq!(log.add_theirs(20, hash), None);
+        assert_eq!(
+            log.add_ours(20, hash),
+            Some(BoardSync::InSync { move_count: 20 })
+        );
+
+        // Our hashes after a move that was taken back are dropped
+        log.add_ours(21, ours);
+        log.add_ours(19, board_hash(&board, 19));
+        assert_eq!(log.add_theirs(21, ours), None);
+    }
+
+    /// Wait for `future`, which the spawned resync finishes, or fail the test.
+    async fn within_a_second<T>(future: impl std::future::Future<Output = T>) -> T {
+        tokio::time::timeout(Duration::from_secs(1), future)
+            .await
+            .expect("the resync didn't get this far")
+    }
+
+    #[test]
+    fn corrupted_board_asks_the_host_for_its_board() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        executor::block_on(async {
+            queue::set_is_host(false);
+            clear().await;
+            while queue::pop_outgoing_queue().await.is_some() {}
+
+            send_board_hash(&corrupted_board(), 4).await;
+            let Some((P2pPacket::Request(sent), _)) = queue::pop_outgoing_queue().await else {
+                panic!("the board hash wasn't sent");
+            };
+            assert!(matches!(
+                sent.packet,
+                P2pRequestPacket::BoardHash { move_count: 4, .. }
+            ));
+            take_board_hash(board_hash(&start_board(), 4), 4).await;
+            assert!(matches!(
+                get_board_sync().await,
+                Some(BoardSync::Desynced { move_count: 4, .. })
+            ));
+
+            // The client asks the host for its board, and takes the answer
+            let resync = within_a_second(async {
+                loop {
+                    match queue::pop_outgoing_queue().await {
+                        Some((P2pPacket::Request(req), _))
+                            if req.packet == P2pRequestPacket::Resync =>
+                        {
+                            break req
+                        }
+                        Some(_) => {}
+                        None => tokio::time::sleep(Duration::from_millis(10)).await,
+                    }
+                }
+            })
+            .await;
+            let board = start_board();
+            let packet = P2pResponsePacket::Resync {
+                board: board.clone().try_into().unwrap(),
+                move_number: 4,
+                side_to_move: PieceColor::side_to_move(4),
+                draw_offer: None,
+                pause: Default::default(),
+            };
+            queue::set_response(Session::respond_to(&resync, packet).await).await;
+            let taken = within_a_second(async {
+                loop {
+                    match take_resync_board().await {
+                        Some(taken) => break taken,
+                        None => tokio::time::sleep(Duration::from_millis(10)).await,
+                    }
+                }
+            })
+            .await;
+            assert_eq!(taken.board, board);
+            assert_eq!(taken.move_number, 4);
+            clear().await;
+        });
+    }
+}
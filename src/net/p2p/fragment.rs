@@ -0,0 +1,287 @@
+//! Fragmentation of packets too large for one datagram.
+//!
+//! A packet bigger than `MAX_PACKET_SIZE` with its checksum is split into fragments, each sent in a
+//! datagram of its own with the kind `wire::kind::FRAGMENT`:
+//! `[kind][version][message ID u16][index][count][part of the packet]`, followed by the checksum of
+//! the datagram. Every packet split gets a new message ID, so a resent packet is a new message.
+//!
+//! The reciever keeps the fragments of each message until it has all of them, and then reads the
+//! packet they make up like one that came in a single datagram. A message still missing fragments
+//! after `REASSEMBLY_TIMEOUT` is dropped like a lost packet, so it's up to the retries of the
+//! request to send it again. Only the `MAX_MESSAGES` messages started last are kept, so spoofed
+//! fragments can't make the peer run out of memory.
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::atomic::{AtomicU16, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::net::net_utils::PacketError;
+
+use super::{
+    communicate::{append_checksum, MAX_PACKET_SIZE},
+    wire,
+};
+
+/// The most bytes of a packet sent in one fragment.
+pub const FRAGMENT_PAYLOAD: usize =
+    MAX_PACKET_SIZE - wire::FRAGMENT_HEADER_LEN - wire::CHECKSUM_LEN;
+/// The largest packet that can be sent, split into `wire::MAX_FRAGMENTS` fragments.
+pub const MAX_MESSAGE_SIZE: usize = FRAGMENT_PAYLOAD * wire::MAX_FRAGMENTS;
+
+/// How long the fragments of a message are kept, waiting for the rest of them.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(2);
+/// The amount of messages being put back together at once.
+const MAX_MESSAGES: usize = 16;
+
+/// The ID of the next message split into fragments.
+static NEXT_MESSAGE_ID: AtomicU16 = AtomicU16::new(0);
+
+/// Split `packet` into fragments. Returns the datagrams to send, each with its checksum, or a
+/// `PacketError::TooLarge` if the packet is bigger than `MAX_MESSAGE_SIZE`.
+///
+/// ## Params
+/// * `packet` - The encoded packet, without a checksum.
+pub fn split(packet: &[u8]) -> Result<Vec<Vec<u8>>, PacketError> {
+    if packet.len() > MAX_MESSAGE_SIZE {
+        return Err(PacketError::too_large(packet.len(), MAX_MESSAGE_SIZE));
+    }
+    let message_id = NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed);
+    let count = packet.len().div_ceil(FRAGMENT_PAYLOAD);
+
+    let fragments = packet
+        .chunks(FRAGMENT_PAYLOAD)
+        .enumerate()
+        .map(|(index, part)| {
+            let mut datagram = Vec::with_capacity(MAX_PACKET_SIZE);
+            datagram.push(wire::kind::FRAGMENT);
+            datagram.push(wire::PROTOCOL_VERSION);
+            datagram.extend_from_slice(&message_id.to_be_bytes());
+            datagram.push(index as u8);
+            datagram.push(count as u8);
+            datagram.extend_from_slice(part);
+            append_checksum(&mut datagram);
+            datagram
+        })
+        .collect();
+    Ok(fragments)
+}
+
+/// Returns if the packet of a recieved datagram is a fragment, which must be put together with the
+/// rest of its message before it can be read.
+pub fn is_fragment(packet: &[u8]) -> bool {
+    packet.first() == Some(&wire::kind::FRAGMENT)
+}
+
+/// The header of a fragment.
+struct Header {
+    message_id: u16,
+    index: usize,
+    count: usize,
+}
+
+impl Header {
+    /// Read the header of `fragment`. Returns an error if it can't be from a message we sent.
+    fn read(fragment: &[u8]) -> Result<Self, PacketError> {
+        // A fragment carries at least one byte of the packet
+        if fragment.len() <= wire::FRAGMENT_HEADER_LEN {
+            return Err(PacketError::invalid_length(
+                wire::FRAGMENT_HEADER_LEN + 1,
+                fragment.len(),
+            ));
+        }
+        if fragment[1] != wire::PROTOCOL_VERSION {
+            let reason = format!(
+                "The fragment is from protocol version {}, but we speak version {}",
+                fragment[1],
+                wire::PROTOCOL_VERSION
+            );
+            return Err(PacketError::data_error(&reason));
+        }
+
+        let header = Self {
+            message_id: u16::from_be_bytes([fragment[2], fragment[3]]),
+            index: fragment[4] as usize,
+            count: fragment[5] as usize,
+        };
+        if header.count == 0 || header.count > wire::MAX_FRAGMENTS {
+            let reason = format!(
+                "The message has {} fragments, but can have 1 to {}",
+                header.count,
+                wire::MAX_FRAGMENTS
+            );
+            return Err(PacketError::data_error(&reason));
+        }
+        if header.index >= header.count {
+            let reason = format!(
+                "The fragment has index {}, but the message has {} fragments",
+                header.index, header.count
+            );
+            return Err(PacketError::data_error(&reason));
+        }
+        Ok(header)
+    }
+}
+
+/// The fragments recieved of a message.
+struct Message {
+    from: SocketAddr,
+    message_id: u16,
+    parts: Vec<Option<Vec<u8>>>,
+    started: Instant,
+}
+
+impl Message {
+    fn missing(&self) -> usize {
+        self.parts.iter().filter(|part| part.is_none()).count()
+    }
+}
+
+/// The messages being put back together, the one started last at the back.
+pub struct Reassembly {
+    messages: VecDeque<Message>,
+}
+
+impl Reassembly {
+    pub const fn new() -> Self {
+        Self {
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Add a recieved fragment to its message. Returns the packet of the message once it has every
+    /// fragment, or `None` while some are missing. A fragment that was already recieved is ignored.
+    ///
+    /// ## Params
+    /// * `fragment` - The packet of the datagram, after its checksum was checked and removed.
+    /// * `from` - The address the fragment came from.
+    /// * `now` - The time the fragment came.
+    pub fn add(
+        &mut self,
+        fragment: &[u8],
+        from: SocketAddr,
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, PacketError> {
+        let header = Header::read(fragment)?;
+        self.drop_expired(now);
+
+        let position = self
+            .messages
+            .iter()
+            .position(|message| message.from == from && message.message_id == header.message_id);
+        let mut message = match position {
+            Some(i) => self.messages.remove(i).unwrap(),
+            None => {
+                if self.messages.len() == MAX_MESSAGES {
+                    self.messages.pop_front();
+                }
+                Message {
+                    from,
+                    message_id: header.message_id,
+                    parts: vec![None; header.count],
+                    started: now,
+                }
+            }
+        };
+        // The message is dropped, since one of the two fragments is wrong
+        if message.parts.len() != header.count {
+            let reason = format!(
+                "The fragment says its message has {} fragments, but an earlier one said {}",
+                header.count,
+                message.parts.len()
+            );
+            return Err(PacketError::data_error(&reason));
+        }
+
+        message.parts[header.index]
+            .get_or_insert_with(|| fragment[wire::FRAGMENT_HEADER_LEN..].to_vec());
+        if message.missing() > 0 {
+            self.messages.push_back(message);
+            return Ok(None);
+        }
+        let packet = message.parts.into_iter().flatten().flatten().collect();
+        Ok(Some(packet))
+    }
+
+    /// Drop the messages that have waited too long for their missing fragments.
+    fn drop_expired(&mut self, now: Instant) {
+        self.messages.retain(|message| {
+            let expired = now.saturating_duration_since(message.started) >= REASSEMBLY_TIMEOUT;
+            if expired {
+                println!(
+                    "Dropped message {} from {}, which was missing {} of its {} fragments",
+                    message.message_id,
+                    message.from,
+                    message.missing(),
+                    message.parts.len()
+                );
+            }
+            !expired
+        });
+    }
+}
+
+impl Default for Reassembly {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::p2p::communicate::strip_checksum;
+
+    /// A packet of `len` bytes, whose bytes differ from their neighbours.
+    fn packet(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn fragments_are_put_together_in_any_order() {
+        let packet = packet(10 * 1024);
+        let fragments = split(&packet).unwrap();
+        assert_eq!(fragments.len(), packet.len().div_ceil(FRAGMENT_PAYLOAD));
+        assert!(fragments.iter().all(|d| d.len() <= MAX_PACKET_SIZE));
+
+        // In the reverse order, with the last one recieved twice
+        let from = "192.168.0.1:6000".parse().unwrap();
+        let now = Instant::now();
+        let mut reassembly = Reassembly::new();
+        let mut recieved = vec![&fragments[fragments.len() - 1]];
+        recieved.extend(fragments.iter().rev());
+        let (last, rest) = recieved.split_last().unwrap();
+        for datagram in rest {
+            let fragment = strip_checksum(datagram).unwrap();
+            assert_eq!(reassembly.add(fragment, from, now).unwrap(), None);
+        }
+        let fragment = strip_checksum(last).unwrap();
+        assert_eq!(reassembly.add(fragment, from, now).unwrap(), Some(packet));
+    }
+
+    #[test]
+    fn message_missing_a_fragment_is_dropped_after_the_timeout() {
+        let fragments = split(&packet(3 * FRAGMENT_PAYLOAD)).unwrap();
+        let from = "192.168.0.1:6000".parse().unwrap();
+        let now = Instant::now();
+        let mut reassembly = Reassembly::new();
+        for datagram in &fragments[1..] {
+            let fragment = strip_checksum(datagram).unwrap();
+            reassembly.add(fragment, from, now).unwrap();
+        }
+        let first = strip_checksum(&fragments[0]).unwrap();
+        let late = reassembly.add(first, from, now + REASSEMBLY_TIMEOUT);
+        assert_eq!(late.unwrap(), None);
+    }
+
+    #[test]
+    fn too_large_packet_is_refused() {
+        assert!(split(&packet(MAX_MESSAGE_SIZE)).is_ok());
+        assert!(matches!(
+            split(&packet(MAX_MESSAGE_SIZE + 1)),
+            Err(PacketError::TooLarge { .. })
+        ));
+    }
+}
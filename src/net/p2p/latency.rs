@@ -0,0 +1,40 @@
+//! Times the round trip of pings from when they are actually sent, not from when they are queued.
+//! A ping can wait in the outgoing queue behind other packets, and that time isn't latency of the
+//! network.
+//!
+//! The outgoing task marks every request when it's sent, and the incoming task takes the mark
+//! when the response arrives. The round trips of pongs go into the ping statistics in `status`.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+/// How long a mark is kept for a response that never arrives.
+const MARK_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    /// When each request waiting for a response was sent, by transaction ID.
+    static ref SENT_AT: Mutex<HashMap<u16, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// Mark that the request with `transaction_id` was just sent.
+pub fn mark_sent(transaction_id: u16) {
+    let mut sent_at = SENT_AT.lock().unwrap();
+    let now = Instant::now();
+    sent_at.retain(|_, sent| now.duration_since(*sent) < MARK_TIMEOUT);
+    sent_at.insert(transaction_id, now);
+}
+
+/// The time since the request with `transaction_id` was sent, when its response has just arrived.
+/// Returns `None` if the request wasn't marked.
+pub fn take_round_trip(transaction_id: u16) -> Option<Duration> {
+    SENT_AT
+        .lock()
+        .unwrap()
+        .remove(&transaction_id)
+        .map(|sent| sent.elapsed())
+}
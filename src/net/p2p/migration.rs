@@ -0,0 +1,231 @@
+use std::{net::SocketAddr, sync::Arc, time::Instant};
+
+use tokio::net::UdpSocket;
+
+use crate::net::status::{get_other_addr, get_session_id, set_other_addr, CONNECT_SESSION_ID};
+
+use super::{
+    communicate::send_p2p_packet, session::Session, P2pRequest, P2pRequestPacket, P2pResponse,
+    P2pResponsePacket,
+};
+
+/// How long the host waits before challenging another address.
+const MIGRATION_COOLDOWN_MS: u128 = 2_000;
+/// How long a challenge can be answered.
+const CHALLENGE_TIMEOUT_MS: u128 = 2_000;
+
+struct PendingChallenge {
+    addr: SocketAddr,
+    token: u32,
+    sent: Instant,
+}
+
+/// Lets the client move to a new address in the middle of a game, e.g. when it switches from
+/// Wi-Fi to Ethernet. Requests in the current session from an unknown address are answered with a
+/// `P2pRequestPacket::Challenge`, and the client's address is only changed once the new address
+/// echoes the token back. This way a stranger can't take over the game by guessing the session ID.
+#[derive(Default)]
+pub struct AddressMigration {
+    pending: Option<PendingChallenge>,
+    last_challenge: Option<Instant>,
+}
+
+impl AddressMigration {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle a request from an address that isn't the client's.
+    /// If the request is in the current session, the address is sent a challenge.
+    ///
+    /// ## Params
+    /// * `socket` - The hosts socket.
+    /// * `req` - The request from the unknown address.
+    /// * `addr` - The address the request came from.
+    pub async fn challenge(&mut self, socket: &Arc<UdpSocket>, req: &P2pRequest, addr: SocketAddr) {
+        let session_id = get_session_id().await;
+        if session_id == CONNECT_SESSION_ID || req.session_id != session_id {
+            println!("Ignored request from unknown address {:?}", addr);
+            return;
+        }
+
+        if self
+            .last_challenge
+            .is_some_and(|last| last.elapsed().as_millis() < MIGRATION_COOLDOWN_MS)
+        {
+            return;
+        }
+
+        let token = rand::random::<u32>();
+        let challenge = match Session::request(P2pRequestPacket::Challenge { token })
+            .await
+            .into_packet()
+        {
+            Ok(challenge) => challenge,
+            Err(e) => {
+                println!("Can't challenge {:?}: {}", addr, e);
+                return;
+            }
+        };
+        if let Err(e) = send_p2p_packet(socket, challenge, addr).await {
+            println!("Failed to challenge {:?}: {}", addr, e);
+            return;
+        }
+
+        println!("Challenging {:?}, which claims to be the client", addr);
+        self.pending = Some(PendingChallenge {
+            addr,
+            token,
+            sent: Instant::now(),
+        });
+        self.last_challenge = Some(Instant::now());
+    }
+
+    /// Handle a response from an address that isn't the client's.
+    /// Returns true if it answered the pending challenge, in which case it's now the client's
+    /// address.
+    ///
+    /// ## Params
+    /// * `resp` - The response from the unknown address.
+    /// * `addr` - The address the response came from.
+    pub async fn verify(&mut self, resp: &P2pResponse, addr: SocketAddr) -> bool {
+        let token = match resp.packet {
+            P2pResponsePacket::ChallengeEcho { token } => token,
+            _ => return false,
+        };
+        let pending = match &self.pending {
+            Some(pending) => pending,
+            None => return false,
+        };
+        if pending.addr != addr
+            || pending.token != token
+            || pending.sent.elapsed().as_millis() > CHALLENGE_TIMEOUT_MS
+        {
+            println!("Wrong challenge echo from {:?}", addr);
+            return false;
+        }
+
+        println!(
+            "Client moved from {:?} to {:?}",
+            get_other_addr().await,
+            addr
+        );
+        set_other_addr(addr).await;
+        self.pending = None;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::executor;
+
+    use super::*;
+    use crate::net::{
+        p2p::{communicate::recieve_p2p_packet, lock_global_state, runtime, P2pPacket},
+        status::{remove_other_addr, set_session_id},
+    };
+
+    const SESSION_ID: u16 = 0x5a5a;
+
+    /// Two sockets, the hosts and the clients new one, with the game in `SESSION_ID` and the
+    /// client at an address it has left.
+    async fn setup() -> (Arc<UdpSocket>, Arc<UdpSocket>, SocketAddr) {
+        set_session_id(SESSION_ID).await;
+        set_other_addr("127.0.0.1:9".parse().unwrap()).await;
+        let host = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = client.local_addr().unwrap();
+        (host, client, addr)
+    }
+
+    async fn teardown() {
+        set_session_id(CONNECT_SESSION_ID).await;
+        remove_other_addr().await;
+    }
+
+    /// The challenge `socket` was sent, if any.
+    async fn recieve_challenge(socket: &Arc<UdpSocket>) -> Option<P2pRequest> {
+        let recieved = tokio::time::timeout(Duration::from_millis(200), recieve_p2p_packet(socket));
+        match recieved.await {
+            Ok(Ok((P2pPacket::Request(req), _))) => Some(req),
+            _ => None,
+        }
+    }
+
+    /// The clients echo of `challenge`, with `token`.
+    fn echo(challenge: &P2pRequest, token: u32) -> P2pResponse {
+        let packet = P2pResponsePacket::ChallengeEcho { token };
+        P2pResponse::new(challenge.session_id, challenge.transaction_id, packet)
+    }
+
+    #[test]
+    fn client_moves_after_echoing_the_challenge() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        executor::block_on(async {
+            let (host, client, addr) = setup().await;
+            let mut migration = AddressMigration::new();
+
+            let req = P2pRequest::new(SESSION_ID, 0x0010, P2pRequestPacket::ping());
+            migration.challenge(&host, &req, addr).await;
+            let challenge = recieve_challenge(&client).await.unwrap();
+            let P2pRequestPacket::Challenge { token } = challenge.packet else {
+                panic!("expected a challenge, got {:?}", challenge.packet);
+            };
+
+            // Only the right token from the challenged address counts
+            let stranger = "127.0.0.1:10".parse().unwrap();
+            assert!(!migration.verify(&echo(&challenge, token), stranger).await);
+            assert!(!migration.verify(&echo(&challenge, !token), addr).await);
+            assert_ne!(get_other_addr().await, Some(addr));
+
+            assert!(migration.verify(&echo(&challenge, token), addr).await);
+            assert_eq!(get_other_addr().await, Some(addr));
+            // The challenge is used up
+            assert!(!migration.verify(&echo(&challenge, token), addr).await);
+            teardown().await;
+        });
+    }
+
+    #[test]
+    fn spoofed_session_id_is_not_challenged() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        executor::block_on(async {
+            let (host, client, addr) = setup().await;
+            let mut migration = AddressMigration::new();
+
+            for session_id in [SESSION_ID ^ 1, CONNECT_SESSION_ID] {
+                let req = P2pRequest::new(session_id, 0x0010, P2pRequestPacket::ping());
+                migration.challenge(&host, &req, addr).await;
+                assert!(recieve_challenge(&client).await.is_none());
+            }
+            // Without a challenge, no echo is taken
+            let fake =
+                P2pRequest::new(SESSION_ID, 0x0010, P2pRequestPacket::Challenge { token: 1 });
+            assert!(!migration.verify(&echo(&fake, 1), addr).await);
+            assert_ne!(get_other_addr().await, Some(addr));
+            teardown().await;
+        });
+    }
+
+    #[test]
+    fn challenges_are_rate_limited() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        executor::block_on(async {
+            let (host, client, addr) = setup().await;
+            let mut migration = AddressMigration::new();
+
+            let req = P2pRequest::new(SESSION_ID, 0x0010, P2pRequestPacket::ping());
+            migration.challenge(&host, &req, addr).await;
+            assert!(recieve_challenge(&client).await.is_some());
+            migration.challenge(&host, &req, addr).await;
+            assert!(recieve_challenge(&client).await.is_none());
+            teardown().await;
+        });
+    }
+}
@@ -1,19 +1,140 @@
+pub mod anomaly;
+pub mod answered;
+pub mod backoff;
+pub mod capture;
+pub mod clock;
+pub mod coin_flip;
 pub mod communicate;
+pub mod desync;
+pub mod fragment;
+pub mod latency;
+pub mod migration;
 pub mod net_loop;
+pub mod pause;
+pub mod peer_info;
+pub mod presence;
+pub mod probe;
 pub mod queue;
+pub mod resync;
+pub mod runtime;
+pub mod sequence;
+pub mod session;
+pub mod simulate;
+pub mod socket;
+pub mod taken_moves;
+pub mod throttle;
+pub mod vectors;
+pub mod watchdog;
+pub mod wire;
 
 use anyhow::anyhow;
+use thiserror::Error;
 
-use super::net_utils::{FromPacket, PacketError, ToByte, ToPacket};
+use super::net_utils::{read_str, write_str, FromPacket, PacketError, ToByte, ToPacket};
 
-use crate::game::{GameAction, Move, PieceColor, PieceData};
+use coin_flip::{Commitment, COMMITMENT_LEN};
+use fragment::MAX_MESSAGE_SIZE;
+use pause::PauseState;
+use peer_info::PeerInfo;
 
-#[derive(Clone, Debug)]
+use crate::{
+    game::{GameAction, PieceColor, PieceData},
+    i18n::{tr, MessageKey},
+    net::status::get_game_options,
+};
+
+use wire::HEADER_LEN;
+
+/// Held by the tests that use the network state of the process, like the queue and `status`, so
+/// they don't run at the same time.
+#[cfg(test)]
+pub(crate) static GLOBAL_STATE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Take `GLOBAL_STATE`, even after a test holding it failed.
+#[cfg(test)]
+pub(crate) fn lock_global_state() -> std::sync::MutexGuard<'static, ()> {
+    GLOBAL_STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Returns a `PacketError::TooLarge` if a request or response carrying `packet` would be bigger
+/// than `MAX_MESSAGE_SIZE`, the most that can be sent in fragments.
+fn check_packet_size<T: ToPacket>(packet: &T) -> anyhow::Result<()> {
+    let size = HEADER_LEN + packet.encoded_len();
+    if size > MAX_MESSAGE_SIZE {
+        return Err(PacketError::too_large(size, MAX_MESSAGE_SIZE).into());
+    }
+    Ok(())
+}
+
+/// A packet from a peer speaking another `wire::PROTOCOL_VERSION`. Only its header can be read,
+/// since that is the same in every version.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+#[error(
+    "The packet is from protocol version {version}, but we speak version {}",
+    wire::PROTOCOL_VERSION
+)]
+pub struct ForeignVersion {
+    /// The protocol version of the packet.
+    pub version: u8,
+    /// If the packet is a request, and not a response.
+    pub is_request: bool,
+    /// The session ID in the header of the packet.
+    pub session_id: u16,
+    /// The transaction ID in the header of the packet.
+    pub transaction_id: u16,
+}
+
+/// The error when the other peer speaks another `wire::PROTOCOL_VERSION`, so the peers can't play
+/// together until both are updated.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+#[error("{}", tr(MessageKey::ProtocolMismatch, &[]))]
+pub struct ProtocolMismatch;
+
+/// Read the header in front of a request or response.
+/// Returns the session ID and the transaction ID, or a `ForeignVersion` if the packet is from
+/// another protocol version.
+///
+/// ## Params
+/// * `packet` - The whole packet.
+/// * `kind` - The kind the packet must be, from `wire::kind`.
+fn read_header(packet: &[u8], kind: u8) -> anyhow::Result<(u16, u16)> {
+    if packet.len() < HEADER_LEN + 1 {
+        return Err(PacketError::invalid_length(HEADER_LEN + 1, packet.len()).into());
+    }
+    if packet[0] != kind {
+        return Err(PacketError::InavlidType.into());
+    }
+    let session_id = u16::from_be_bytes(packet[2..4].try_into().unwrap());
+    let transaction_id = u16::from_be_bytes(packet[4..6].try_into().unwrap());
+    if packet[1] != wire::PROTOCOL_VERSION {
+        return Err(ForeignVersion {
+            version: packet[1],
+            is_request: kind == wire::kind::REQUEST,
+            session_id,
+            transaction_id,
+        }
+        .into());
+    }
+    Ok((session_id, transaction_id))
+}
+
+/// If `body`, the packet after the header, is a `P2pError::ProtocolMismatch` error response.
+fn is_protocol_mismatch(body: &[u8]) -> bool {
+    matches!(
+        P2pResponsePacket::from_packet(body.to_vec()),
+        Ok(P2pResponsePacket::Error {
+            kind: P2pError::ProtocolMismatch
+        })
+    )
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum P2pPacket {
     Request(P2pRequest),
     Response(P2pResponse),
 }
 
+#[allow(dead_code)]
 impl P2pPacket {
     pub fn is_request(&self) -> bool {
         matches!(self, Self::Request(_))
@@ -23,23 +144,38 @@ impl P2pPacket {
     }
 }
 
+impl From<P2pRequest> for P2pPacket {
+    fn from(req: P2pRequest) -> Self {
+        Self::Request(req)
+    }
+}
+
+impl From<P2pResponse> for P2pPacket {
+    fn from(resp: P2pResponse) -> Self {
+        Self::Response(resp)
+    }
+}
+
 impl ToPacket for P2pPacket {
-    fn to_packet(&self) -> Vec<u8> {
+    fn write_packet(&self, buf: &mut Vec<u8>) {
         match self {
-            Self::Request(req) => req.to_packet(),
-            Self::Response(resp) => resp.to_packet(),
+            Self::Request(req) => req.write_packet(buf),
+            Self::Response(resp) => resp.write_packet(buf),
         }
     }
 }
 
 impl FromPacket for P2pPacket {
     fn from_packet(packet: Vec<u8>) -> anyhow::Result<Self> {
-        match packet[0] {
-            0 => match P2pRequest::from_packet(packet) {
+        let Some(&kind) = packet.first() else {
+            return Err(PacketError::Empty.into());
+        };
+        match kind {
+            wire::kind::REQUEST => match P2pRequest::from_packet(packet) {
                 Ok(req) => Ok(Self::Request(req)),
                 Err(e) => Err(e),
             },
-            1 => match P2pResponse::from_packet(packet) {
+            wire::kind::RESPONSE => match P2pResponse::from_packet(packet) {
                 Ok(resp) => Ok(Self::Response(resp)),
                 Err(e) => Err(e),
             },
@@ -50,7 +186,7 @@ impl FromPacket for P2pPacket {
 
 /// A request for P2P (Peer to Peer) connection. This moves mostly from client to host, but the
 /// host will send requests to the client, when it makes an update to the board.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct P2pRequest {
     /// The sessions ID set by the host. Is set to 0 if it is the first time the client is talking
     /// with the host.
@@ -63,7 +199,8 @@ pub struct P2pRequest {
 
 impl P2pRequest {
     /// Create a new `P2pRequest` from the sessions ID and the packet.
-    pub fn new(session_id: u16, transaction_id: u16, packet: P2pRequestPacket) -> Self {
+    /// Outside of this module, requests are created through `Session`.
+    fn new(session_id: u16, transaction_id: u16, packet: P2pRequestPacket) -> Self {
         Self {
             session_id,
             transaction_id,
@@ -73,28 +210,19 @@ impl P2pRequest {
 }
 
 impl ToPacket for P2pRequest {
-    fn to_packet(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        bytes.append(&mut 0u8.to_be_bytes().to_vec());
-        bytes.append(&mut self.session_id.to_be_bytes().to_vec());
-        bytes.append(&mut self.transaction_id.to_be_bytes().to_vec());
-        bytes.append(&mut self.packet.to_packet());
-
-        bytes
+    fn write_packet(&self, buf: &mut Vec<u8>) {
+        buf.push(wire::kind::REQUEST);
+        buf.push(wire::PROTOCOL_VERSION);
+        buf.extend_from_slice(&self.session_id.to_be_bytes());
+        buf.extend_from_slice(&self.transaction_id.to_be_bytes());
+        self.packet.write_packet(buf);
     }
 }
 
 impl FromPacket for P2pRequest {
     fn from_packet(packet: Vec<u8>) -> anyhow::Result<Self> {
-        if packet.len() < 6 {
-            return Err(PacketError::invalid_length(6, packet.len()).into());
-        }
-        if packet[0] != 0 {
-            return Err(PacketError::InavlidType.into());
-        }
-        let session_id = u16::from_be_bytes(packet[1..3].try_into().unwrap());
-        let transaction_id = u16::from_be_bytes(packet[3..5].try_into().unwrap());
-        let packet = P2pRequestPacket::from_packet(packet[5..].to_vec())?;
+        let (session_id, transaction_id) = read_header(&packet, wire::kind::REQUEST)?;
+        let packet = P2pRequestPacket::from_packet(packet[HEADER_LEN..].to_vec())?;
 
         Ok(Self {
             session_id,
@@ -105,10 +233,14 @@ impl FromPacket for P2pRequest {
 }
 
 /// The different types of packets you can send as a request to the other peer.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum P2pRequestPacket {
     /// Ping the other peer, to uphold the connection. This must be done often.
-    Ping,
+    Ping {
+        /// Opaque bytes the other peer echoes back in `P2pResponsePacket::Pong`. Used to check
+        /// which packet sizes get through. Normally empty.
+        payload: Vec<u8>,
+    },
     /// Request to connect to the host. `join_code` is the HEX encoded IP and port of the host,
     /// which is the same as the join code if working over LAN. 'username' is the username the
     /// client wishes to use.
@@ -116,57 +248,271 @@ pub enum P2pRequestPacket {
         /// The games join code. Calculated by HEX encoding the hosts IP and PORT. When on LAN, its
         /// the code given to the client by the host.
         join_code: String,
-        /// The clients username. Set by the clients user.
+        /// The clients username. Set by the clients user, or empty if they play anonymously, in
+        /// which case the host calls them by `status::anonymous_handle()`.
         username: String,
+        /// The clients nonce for the coin flip deciding the colors. See `coin_flip`.
+        nonce: u64,
+        /// The clients version and platform. See `peer_info`.
+        peer_info: PeerInfo,
+        /// The color the client wishes to play, if any. It's only given if the host wishes the
+        /// other color. See `coin_flip::assign_client_color()`.
+        preference: Option<PieceColor>,
     },
     /// Ask the host for a copy of the correct board, so the client can resync theirs.
     Resync,
     /// Perform a game action
-    GameAction { action: GameAction },
+    GameAction {
+        action: GameAction,
+        /// The number of moves made in the game before this action. Moves are counted from 0,
+        /// and White makes the even numbered moves.
+        move_number: u16,
+        /// The place of the action among the game actions the sender sent in this session, so
+        /// they're taken in order. See `sequence`.
+        sequence: u16,
+    },
+    /// Sent by the host to a new address claiming to be the client. The client proves it's at
+    /// that address by echoing the token back in `P2pResponsePacket::ChallengeEcho`.
+    Challenge {
+        /// A random token, only known by the host and the address it was sent to.
+        token: u32,
+    },
+    /// Ask a peer if it is hosting a game. Used before joining, to find out if both users clicked
+    /// host.
+    Probe,
+    /// Sent by the client after connecting, to confirm it plays with the same `GameOptions` as
+    /// the host. The host answers `Acknowledge` if the hashes match, and otherwise an
+    /// `OptionsMismatch` error, after which the client is dropped.
+    OptionsAck {
+        /// The clients `GameOptions::options_hash()`.
+        options_hash: u64,
+    },
+    /// A chat message from the other player. Answered with `Acknowledge`.
+    Chat {
+        /// The text of the message, at most `MAX_CHAT_LEN` bytes of UTF-8.
+        message: String,
+    },
+    /// The other peer is leaving the game, e.g. because its window was closed. Answered with
+    /// `Acknowledge`, after which the peers forget each other. Only taken from the other peer in
+    /// the current session.
+    Disconnect,
+    /// The game has ended, sent by the peer that found out first. Answered with `Acknowledge`.
+    /// Only taken from the other peer in the current session.
+    GameOver {
+        /// The color of the player who won, or `None` if the game ended in a draw.
+        winner: Option<PieceColor>,
+        /// Why the game ended.
+        reason: GameOverReason,
+    },
+    /// Offer the other player a new game in the same session, after the last one has ended. It's
+    /// answered with `RematchAnswer` or `RematchDecline` once the other player has decided, or with
+    /// a `GameInProgress` error if their game hasn't ended. Only taken from the other peer in the
+    /// current session.
+    RematchOffer,
+    /// The hash of the board after applying a move, sent by both peers. Answered with
+    /// `Acknowledge`. See `desync`.
+    BoardHash {
+        /// The `desync::board_hash()` of the board.
+        hash: u64,
+        /// The amount of moves applied to the board.
+        move_count: u16,
+    },
+    /// A note about the player that only matters for a moment, like that they're typing a chat
+    /// message. Answered with `Acknowledge`, but never sent again if it's lost, since the next one
+    /// follows soon. See `presence`.
+    StatusNote {
+        /// What the note tells.
+        kind: NoteKind,
+    },
 }
 
 impl P2pRequestPacket {
     /// Request to connect to the host. `join_code` is the HEX encoded IP and port of the host,
     /// which is the same as the join code if working over LAN. 'username' is the username the
-    /// client wishes to use.
-    pub fn connect(join_code: &str, username: &str) -> Self {
-        Self::Connect {
+    /// client wishes to use, `nonce` its part of the coin flip, and `preference` the color it
+    /// wishes to play.
+    /// Returns an error if the packet would be too large to send.
+    pub fn connect(
+        join_code: &str,
+        username: &str,
+        nonce: u64,
+        preference: Option<PieceColor>,
+    ) -> anyhow::Result<Self> {
+        let packet = Self::Connect {
             join_code: join_code.to_owned(),
             username: username.to_owned(),
-        }
+            nonce,
+            peer_info: PeerInfo::local(),
+            preference,
+        };
+        check_packet_size(&packet)?;
+        Ok(packet)
+    }
+    /// Ping the other peer, to uphold the connection.
+    pub fn ping() -> Self {
+        Self::Ping { payload: vec![] }
+    }
+    /// Ping the other peer with `payload`, which it echoes back.
+    /// Returns an error if the packet would be too large to send.
+    pub fn ping_with_payload(payload: Vec<u8>) -> anyhow::Result<Self> {
+        let packet = Self::Ping { payload };
+        check_packet_size(&packet)?;
+        Ok(packet)
     }
     /// Perform a game action
-    pub fn game_action(action: GameAction) -> Self {
-        Self::GameAction { action }
+    pub fn game_action(action: GameAction, move_number: u16, sequence: u16) -> Self {
+        Self::GameAction {
+            action,
+            move_number,
+            sequence,
+        }
+    }
+    /// Tell the other player the game has ended, won by `winner` or drawn.
+    pub fn game_over(winner: Option<PieceColor>, reason: GameOverReason) -> Self {
+        Self::GameOver { winner, reason }
+    }
+    /// Send a chat message to the other player.
+    /// Returns a `PacketError::DataError` if the message is longer than `MAX_CHAT_LEN` bytes.
+    pub fn chat(message: &str) -> anyhow::Result<Self> {
+        check_chat_len(message)?;
+        Ok(Self::Chat {
+            message: message.to_owned(),
+        })
+    }
+    /// If the request is sent again when its response doesn't come. See `queue::resend_expired`.
+    /// Only game actions are, since a lost one is gone from the game for good. A lost ping is
+    /// followed by the next one anyway, and counts towards the ping loss.
+    pub fn is_resent(&self) -> bool {
+        matches!(self, Self::GameAction { .. })
+    }
+}
+
+/// The longest chat message, in bytes of UTF-8.
+pub const MAX_CHAT_LEN: usize = 256;
+
+/// Returns a `PacketError::DataError` if `message` is too long for a chat message.
+fn check_chat_len(message: &str) -> Result<(), PacketError> {
+    if message.len() > MAX_CHAT_LEN {
+        let reason = format!(
+            "The chat message is {} bytes, but can be at most {}",
+            message.len(),
+            MAX_CHAT_LEN
+        );
+        return Err(PacketError::data_error(&reason));
+    }
+    Ok(())
+}
+
+/// The longest username, in bytes of UTF-8, after trimming. It's counted in bytes, since that's
+/// what it takes up in a packet.
+pub const MAX_USERNAME_LEN: usize = 32;
+
+/// Why a username can't be used. See `normalize_username()`.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+pub enum UsernameError {
+    #[error("{}", tr(MessageKey::UsernameEmpty, &[]))]
+    Empty,
+    #[error("{}", tr(MessageKey::UsernameTooLong, &[]))]
+    TooLong,
+    #[error("{}", tr(MessageKey::UsernameControlCharacter, &[]))]
+    ControlCharacter,
+}
+
+/// Trim the whitespace around `username`, and check that what's left can be used. It must not be
+/// empty, have no control characters, and be at most `MAX_USERNAME_LEN` bytes. A name that's too
+/// long is refused, not cut off, so a character is never split.
+pub fn normalize_username(username: &str) -> Result<&str, UsernameError> {
+    let username = username.trim();
+    if username.is_empty() {
+        Err(UsernameError::Empty)
+    } else if username.chars().any(char::is_control) {
+        Err(UsernameError::ControlCharacter)
+    } else if username.len() > MAX_USERNAME_LEN {
+        Err(UsernameError::TooLong)
+    } else {
+        Ok(username)
     }
 }
 
 impl ToPacket for P2pRequestPacket {
-    fn to_packet(&self) -> Vec<u8> {
-        let mut bytes = vec![];
+    fn write_packet(&self, buf: &mut Vec<u8>) {
         match self {
-            Self::Ping => {
-                bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
+            Self::Ping { payload } => {
+                buf.push(self.to_u8()); // Packet type code
+
+                buf.extend_from_slice(payload);
             }
             Self::Connect {
                 join_code,
                 username,
+                nonce,
+                peer_info,
+                preference,
             } => {
-                bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
+                buf.push(self.to_u8()); // Packet type code
 
-                bytes.append(&mut join_code.as_bytes().to_vec());
-                bytes.append(&mut username.as_bytes().to_vec());
+                write_str(buf, join_code);
+                buf.extend_from_slice(&nonce.to_be_bytes());
+                peer_info.write(buf);
+                write_str(buf, username);
+                buf.push(preference.map_or(wire::color::NONE, |color| color.to_u8()));
             }
             Self::Resync => {
-                bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
+                buf.push(self.to_u8()); // Packet type code
+            }
+            Self::GameAction {
+                action,
+                move_number,
+                sequence,
+            } => {
+                buf.push(self.to_u8()); // Packet type code
+
+                buf.extend_from_slice(&move_number.to_be_bytes());
+                buf.extend_from_slice(&sequence.to_be_bytes());
+                action.write_packet(buf);
+            }
+            Self::Challenge { token } => {
+                buf.push(self.to_u8()); // Packet type code
+
+                buf.extend_from_slice(&token.to_be_bytes());
+            }
+            Self::Probe => {
+                buf.push(self.to_u8()); // Packet type code
+            }
+            Self::OptionsAck { options_hash } => {
+                buf.push(self.to_u8()); // Packet type code
+
+                buf.extend_from_slice(&options_hash.to_be_bytes());
+            }
+            Self::Chat { message } => {
+                buf.push(self.to_u8()); // Packet type code
+
+                write_str(buf, message);
             }
-            Self::GameAction { action } => {
-                bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
+            Self::Disconnect => {
+                buf.push(self.to_u8()); // Packet type code
+            }
+            Self::GameOver { winner, reason } => {
+                buf.push(self.to_u8()); // Packet type code
+
+                buf.push(winner.map_or(wire::color::NONE, |color| color.to_u8()));
+                buf.push(reason.to_u8());
+            }
+            Self::RematchOffer => {
+                buf.push(self.to_u8()); // Packet type code
+            }
+            Self::BoardHash { hash, move_count } => {
+                buf.push(self.to_u8()); // Packet type code
 
-                bytes.append(&mut action.to_packet());
+                buf.extend_from_slice(&hash.to_be_bytes());
+                buf.extend_from_slice(&move_count.to_be_bytes());
+            }
+            Self::StatusNote { kind } => {
+                buf.push(self.to_u8()); // Packet type code
+
+                buf.push(kind.to_u8());
             }
         }
-        bytes
     }
 }
 
@@ -176,47 +522,103 @@ impl FromPacket for P2pRequestPacket {
             return Err(PacketError::Empty.into());
         }
         match packet[0] {
-            // Ping
-            1 => Ok(Self::Ping),
-            // Connect
-            2 => {
-                if packet.len() < 14 {
-                    return Err(PacketError::invalid_length(14, packet.len()).into());
-                }
-                let join_code = match String::from_utf8(packet[1..13].to_vec()) {
-                    Ok(string) => string,
-                    Err(_) => {
-                        return Err(PacketError::data_error(
-                            "Invalid UFT8 encoded values for username",
-                        )
-                        .into())
-                    }
-                };
-                let username = match String::from_utf8(packet[13..].to_vec()) {
-                    Ok(string) => string,
-                    Err(_) => {
-                        return Err(PacketError::data_error(
-                            "Invalid UFT8 encoded values for username",
-                        )
-                        .into())
-                    }
+            wire::request::PING => Ok(Self::Ping {
+                payload: packet[1..].to_vec(),
+            }),
+            wire::request::CONNECT => {
+                // Anything after the preference is from a newer version, and is skipped
+                let (join_code, join_code_len) = read_str(&packet[1..], "join code")?;
+                let nonce_start = 1 + join_code_len;
+                let nonce = packet
+                    .get(nonce_start..nonce_start + 8)
+                    .ok_or_else(|| PacketError::data_error("The nonce is cut off"))?;
+                let nonce = u64::from_be_bytes(nonce.try_into().unwrap());
+                let info_start = nonce_start + 8;
+                let (peer_info, info_len) = PeerInfo::read(&packet[info_start..])?;
+                let username_start = info_start + info_len;
+                let (username, username_len) = read_str(&packet[username_start..], "username")?;
+                // A client too old to send a preference has none
+                let preference = match packet.get(username_start + username_len).copied() {
+                    None | Some(wire::color::NONE) => None,
+                    Some(color) => Some(PieceColor::try_from(color)?),
                 };
 
                 Ok(Self::Connect {
                     join_code,
                     username,
+                    nonce,
+                    peer_info,
+                    preference,
+                })
+            }
+            wire::request::RESYNC => Ok(Self::Resync),
+            wire::request::GAME_ACTION => {
+                if packet.len() < 6 {
+                    return Err(PacketError::invalid_length(6, packet.len()).into());
+                }
+                let move_number = u16::from_be_bytes(packet[1..3].try_into().unwrap());
+                let sequence = u16::from_be_bytes(packet[3..5].try_into().unwrap());
+                let action = GameAction::from_packet(packet[5..].to_vec())?;
+
+                Ok(Self::GameAction {
+                    action,
+                    move_number,
+                    sequence,
                 })
             }
-            // Resync
-            3 => Ok(Self::Resync),
-            // Game Action
-            4 => {
-                if packet.len() < 2 {
+            wire::request::CHALLENGE => {
+                if packet.len() != 5 {
+                    return Err(PacketError::invalid_length(5, packet.len()).into());
+                }
+                let token = u32::from_be_bytes(packet[1..5].try_into().unwrap());
+
+                Ok(Self::Challenge { token })
+            }
+            wire::request::PROBE => Ok(Self::Probe),
+            wire::request::OPTIONS_ACK => {
+                if packet.len() != 9 {
+                    return Err(PacketError::invalid_length(9, packet.len()).into());
+                }
+                let options_hash = u64::from_be_bytes(packet[1..9].try_into().unwrap());
+
+                Ok(Self::OptionsAck { options_hash })
+            }
+            wire::request::CHAT => {
+                let (message, _) = read_str(&packet[1..], "chat message")?;
+                check_chat_len(&message)?;
+
+                Ok(Self::Chat { message })
+            }
+            wire::request::DISCONNECT => Ok(Self::Disconnect),
+            wire::request::GAME_OVER => {
+                if packet.len() != 3 {
+                    return Err(PacketError::invalid_length(3, packet.len()).into());
+                }
+                let winner = match packet[1] {
+                    wire::color::NONE => None,
+                    color => Some(PieceColor::try_from(color)?),
+                };
+                let reason = GameOverReason::try_from(packet[2])?;
+
+                Ok(Self::GameOver { winner, reason })
+            }
+            wire::request::REMATCH_OFFER => Ok(Self::RematchOffer),
+            wire::request::BOARD_HASH => {
+                if packet.len() != 11 {
+                    return Err(PacketError::invalid_length(11, packet.len()).into());
+                }
+                let hash = u64::from_be_bytes(packet[1..9].try_into().unwrap());
+                let move_count = u16::from_be_bytes(packet[9..11].try_into().unwrap());
+
+                Ok(Self::BoardHash { hash, move_count })
+            }
+            wire::request::STATUS_NOTE => {
+                if packet.len() != 2 {
                     return Err(PacketError::invalid_length(2, packet.len()).into());
                 }
-                let action = GameAction::from_packet(packet[1..].to_vec()).unwrap();
+                let kind = NoteKind::try_from(packet[1])?;
 
-                Ok(Self::GameAction { action })
+                Ok(Self::StatusNote { kind })
             }
             _ => Err(
                 PacketError::data_error(&format!("Not valid packet type: {}", packet[0])).into(),
@@ -228,19 +630,41 @@ impl FromPacket for P2pRequestPacket {
 impl ToByte for P2pRequestPacket {
     fn to_u8(&self) -> u8 {
         match self {
-            Self::Ping => 1,
+            Self::Ping { payload: _ } => wire::request::PING,
             Self::Connect {
                 join_code: _,
                 username: _,
-            } => 2,
-            Self::Resync => 3,
-            Self::GameAction { action: _ } => 4,
+                nonce: _,
+                peer_info: _,
+                preference: _,
+            } => wire::request::CONNECT,
+            Self::Resync => wire::request::RESYNC,
+            Self::GameAction {
+                action: _,
+                move_number: _,
+                sequence: _,
+            } => wire::request::GAME_ACTION,
+            Self::Challenge { token: _ } => wire::request::CHALLENGE,
+            Self::Probe => wire::request::PROBE,
+            Self::OptionsAck { options_hash: _ } => wire::request::OPTIONS_ACK,
+            Self::Chat { message: _ } => wire::request::CHAT,
+            Self::Disconnect => wire::request::DISCONNECT,
+            Self::GameOver {
+                winner: _,
+                reason: _,
+            } => wire::request::GAME_OVER,
+            Self::RematchOffer => wire::request::REMATCH_OFFER,
+            Self::BoardHash {
+                hash: _,
+                move_count: _,
+            } => wire::request::BOARD_HASH,
+            Self::StatusNote { kind: _ } => wire::request::STATUS_NOTE,
         }
     }
 }
 
 /// A response to the `P2pResonse` struct.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct P2pResponse {
     /// The sessions ID set randomly by the host.
     pub session_id: u16,
@@ -252,7 +676,8 @@ pub struct P2pResponse {
 
 impl P2pResponse {
     /// Create a new `P2pResponse` from the sessions ID and the packet.
-    pub fn new(session_id: u16, transaction_id: u16, packet: P2pResponsePacket) -> Self {
+    /// Outside of this module, responses are created through `Session`.
+    fn new(session_id: u16, transaction_id: u16, packet: P2pResponsePacket) -> Self {
         Self {
             session_id,
             transaction_id,
@@ -262,29 +687,29 @@ impl P2pResponse {
 }
 
 impl ToPacket for P2pResponse {
-    fn to_packet(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        bytes.append(&mut 1u8.to_be_bytes().to_vec());
-        bytes.append(&mut self.session_id.to_be_bytes().to_vec());
-        bytes.append(&mut self.transaction_id.to_be_bytes().to_vec());
-        bytes.append(&mut self.packet.to_packet());
-        bytes
+    fn write_packet(&self, buf: &mut Vec<u8>) {
+        buf.push(wire::kind::RESPONSE);
+        buf.push(wire::PROTOCOL_VERSION);
+        buf.extend_from_slice(&self.session_id.to_be_bytes());
+        buf.extend_from_slice(&self.transaction_id.to_be_bytes());
+        self.packet.write_packet(buf);
     }
 }
 
 impl FromPacket for P2pResponse {
     fn from_packet(packet: Vec<u8>) -> anyhow::Result<Self> {
-        if packet.len() < 6 {
-            return Err(PacketError::invalid_length(6, packet.len()).into());
-        }
-
-        if packet[0] != 1 {
-            return Err(PacketError::InavlidType.into());
-        }
-
-        let session_id = u16::from_be_bytes(packet[1..3].try_into().unwrap());
-        let transaction_id = u16::from_be_bytes(packet[3..5].try_into().unwrap());
-        let packet = P2pResponsePacket::from_packet(packet[5..].to_vec())?;
+        let (session_id, transaction_id) = match read_header(&packet, wire::kind::RESPONSE) {
+            Ok(ids) => ids,
+            // A peer of another version can still tell us that it is, since the error response
+            // for it is the same in every version
+            Err(e) => match e.downcast_ref::<ForeignVersion>() {
+                Some(foreign) if is_protocol_mismatch(&packet[HEADER_LEN..]) => {
+                    (foreign.session_id, foreign.transaction_id)
+                }
+                _ => return Err(e),
+            },
+        };
+        let packet = P2pResponsePacket::from_packet(packet[HEADER_LEN..].to_vec())?;
 
         Ok(Self {
             session_id,
@@ -303,21 +728,77 @@ pub enum P2pResponsePacket {
         kind: P2pError,
     },
     /// The reponse to `P2pRequestPacket::Ping`.
-    Pong,
+    Pong {
+        /// The payload of the ping, echoed verbatim.
+        payload: Vec<u8>,
+    },
     /// Response to `P2pRequestPacket::Connect`.
     Connect {
         /// The board color that the client will be assigned to.
         client_color: PieceColor,
         /// The hosts username, set by the Hosts user.
         host_username: String,
+        /// The hosts nonce for the coin flip, revealed. See `coin_flip`.
+        host_nonce: u64,
+        /// The hosts version and platform. See `peer_info`.
+        peer_info: PeerInfo,
     },
-    /// A response to `P2pRequestPacket::Resync`, features the hosts version of the game board.
+    /// A response to `P2pRequestPacket::Resync`, features the hosts version of the game board and
+    /// its turn.
     Resync {
         /// The hosts version of the game board, which the client will copy.
-        board: Vec<PieceData>,
+        board: [PieceData; wire::BOARD_LEN],
+        /// The number of moves the host has made in the game.
+        move_number: u16,
+        /// The color whose turn it is on the host.
+        side_to_move: PieceColor,
+        /// The color of the player whose draw offer is waiting for an answer on the host, if any.
+        /// A client that reconnected while an offer was open still shows it.
+        draw_offer: Option<PieceColor>,
+        /// If the game is paused on the host, and its open request about it. A client that
+        /// reconnected during a pause lands in it.
+        pause: PauseState,
     },
     /// A simple acknowledge.
     Acknowledge,
+    /// Response to `P2pRequestPacket::Challenge`, with the token it was sent.
+    ChallengeEcho {
+        /// The token from the challenge.
+        token: u32,
+    },
+    /// Response to `P2pRequestPacket::Probe`.
+    ProbeResponse {
+        /// If the peer is hosting a game.
+        hosting: bool,
+        /// The hosts commitment to its coin flip nonce, if it's hosting. See `coin_flip`.
+        commitment: Option<Commitment>,
+    },
+    /// A game action was rejected by the host. Carries the hosts turn, so the client can get back
+    /// in sync.
+    Rejected {
+        /// Why the action was rejected.
+        kind: P2pError,
+        /// The number of moves the host has made in the game.
+        move_number: u16,
+        /// The color whose turn it is on the host.
+        side_to_move: PieceColor,
+    },
+    /// A request was refused for now, and may be sent again later. See `throttle`.
+    RetryLater {
+        /// Why the request was refused.
+        kind: P2pError,
+        /// How long to wait before sending the request again, in milliseconds.
+        retry_after_ms: u16,
+    },
+    /// Response to `P2pRequestPacket::RematchOffer`, when it was accepted. The players swap colors
+    /// in the new game.
+    RematchAnswer {
+        /// The color the player who offered the rematch plays in the new game.
+        offerer_color: PieceColor,
+    },
+    /// Response to `P2pRequestPacket::RematchOffer`, when it was declined. In a match, it ends the
+    /// match on its score.
+    RematchDecline,
 }
 
 impl P2pResponsePacket {
@@ -326,52 +807,135 @@ impl P2pResponsePacket {
         Self::Error { kind }
     }
     /// Response to `P2pRequestPacket::Connect`.
-    pub fn connect(client_color: PieceColor, host_username: String) -> Self {
-        Self::Connect {
+    /// Returns an error if the packet would be too large to send.
+    pub fn connect(
+        client_color: PieceColor,
+        host_username: String,
+        host_nonce: u64,
+    ) -> anyhow::Result<Self> {
+        let packet = Self::Connect {
             client_color,
             host_username,
-        }
+            host_nonce,
+            peer_info: PeerInfo::local(),
+        };
+        check_packet_size(&packet)?;
+        Ok(packet)
     }
-    /// A response to `P2pRequestPacket::Resync`, features the hosts version of the game board.
-    pub fn resync(board: Vec<PieceData>) -> Self {
-        Self::Resync { board }
+    /// A response to `P2pRequestPacket::Resync`, features the hosts version of the game board,
+    /// its turn, its open draw offer and its pause.
+    pub fn resync(
+        board: [PieceData; wire::BOARD_LEN],
+        move_number: u16,
+        side_to_move: PieceColor,
+        draw_offer: Option<PieceColor>,
+        pause: PauseState,
+    ) -> Self {
+        Self::Resync {
+            board,
+            move_number,
+            side_to_move,
+            draw_offer,
+            pause,
+        }
     }
 }
 
 impl ToPacket for P2pResponsePacket {
-    fn to_packet(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-
+    fn write_packet(&self, buf: &mut Vec<u8>) {
         match self {
             Self::Error { kind } => {
-                bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
-                bytes.append(&mut kind.to_u8().to_be_bytes().to_vec());
+                buf.push(self.to_u8()); // Packet type code
+                buf.push(kind.to_u8());
             }
-            Self::Pong => {
-                bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
+            Self::Pong { payload } => {
+                buf.push(self.to_u8()); // Packet type code
+
+                buf.extend_from_slice(payload);
             }
             Self::Connect {
                 client_color,
                 host_username,
+                host_nonce,
+                peer_info,
             } => {
-                bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
+                buf.push(self.to_u8()); // Packet type code
 
-                bytes.append(&mut client_color.to_u8().to_be_bytes().to_vec());
-                bytes.append(&mut host_username.as_bytes().to_vec());
+                buf.push(client_color.to_u8());
+                buf.extend_from_slice(&host_nonce.to_be_bytes());
+                peer_info.write(buf);
+                write_str(buf, host_username);
             }
-            Self::Resync { board } => {
-                bytes.append(&mut self.to_u8().to_be_bytes().to_vec()); // Packet type code
-
+            Self::Resync {
+                board,
+                move_number,
+                side_to_move,
+                draw_offer,
+                pause,
+            } => {
+                buf.push(self.to_u8()); // Packet type code
+
+                buf.extend_from_slice(&move_number.to_be_bytes());
+                buf.push(side_to_move.to_u8());
+                buf.push(draw_offer.map_or(wire::color::NONE, |color| color.to_u8()));
+                buf.push(pause.paused as u8);
+                buf.push(
+                    pause
+                        .request
+                        .map_or(wire::color::NONE, |color| color.to_u8()),
+                );
                 for tile in board {
-                    bytes.append(&mut vec![tile.to_u8()]);
+                    buf.push(tile.to_u8());
                 }
             }
             Self::Acknowledge => {
-                bytes.append(&mut self.to_u8().to_be_bytes().to_vec());
+                buf.push(self.to_u8());
             }
-        }
+            Self::ChallengeEcho { token } => {
+                buf.push(self.to_u8()); // Packet type code
 
-        bytes
+                buf.extend_from_slice(&token.to_be_bytes());
+            }
+            Self::ProbeResponse {
+                hosting,
+                commitment,
+            } => {
+                buf.push(self.to_u8()); // Packet type code
+
+                buf.push(*hosting as u8);
+                if let Some(commitment) = commitment {
+                    buf.extend_from_slice(commitment);
+                }
+            }
+            Self::Rejected {
+                kind,
+                move_number,
+                side_to_move,
+            } => {
+                buf.push(self.to_u8()); // Packet type code
+
+                buf.push(kind.to_u8());
+                buf.extend_from_slice(&move_number.to_be_bytes());
+                buf.push(side_to_move.to_u8());
+            }
+            Self::RetryLater {
+                kind,
+                retry_after_ms,
+            } => {
+                buf.push(self.to_u8()); // Packet type code
+
+                buf.push(kind.to_u8());
+                buf.extend_from_slice(&retry_after_ms.to_be_bytes());
+            }
+            Self::RematchAnswer { offerer_color } => {
+                buf.push(self.to_u8()); // Packet type code
+
+                buf.push(offerer_color.to_u8());
+            }
+            Self::RematchDecline => {
+                buf.push(self.to_u8()); // Packet type code
+            }
+        }
     }
 }
 
@@ -382,8 +946,7 @@ impl FromPacket for P2pResponsePacket {
         }
 
         match packet[0] {
-            // Error
-            0 => {
+            wire::response::ERROR => {
                 if packet.len() != 2 {
                     return Err(PacketError::invalid_length(2, packet.len()).into());
                 }
@@ -397,12 +960,12 @@ impl FromPacket for P2pResponsePacket {
 
                 Ok(Self::Error { kind })
             }
-            // Pong
-            1 => Ok(Self::Pong),
-            // Connect
-            2 => {
-                if packet.len() < 3 {
-                    return Err(PacketError::invalid_length(2, packet.len()).into());
+            wire::response::PONG => Ok(Self::Pong {
+                payload: packet[1..].to_vec(),
+            }),
+            wire::response::CONNECT => {
+                if packet.len() < 10 {
+                    return Err(PacketError::invalid_length(10, packet.len()).into());
                 }
 
                 let client_color = match PieceColor::try_from(packet[1]) {
@@ -410,39 +973,126 @@ impl FromPacket for P2pResponsePacket {
                     Err(e) => return Err(PacketError::data_error(&e.to_string()).into()),
                 };
 
-                let host_username = match String::from_utf8(packet[2..].to_vec()) {
-                    Ok(string) => string,
-                    Err(_) => {
-                        return Err(PacketError::data_error(
-                            "Invalid UFT8 encoded values for username",
-                        )
-                        .into())
-                    }
-                };
+                let host_nonce = u64::from_be_bytes(packet[2..10].try_into().unwrap());
+                let (peer_info, info_len) = PeerInfo::read(&packet[10..])?;
+                // Anything after the username is from a newer version, and is skipped
+                let (host_username, _) = read_str(&packet[10 + info_len..], "username")?;
 
                 Ok(Self::Connect {
                     client_color,
                     host_username,
+                    host_nonce,
+                    peer_info,
                 })
             }
-            // Resync
-            3 => {
-                if packet.len() < 65 {
-                    return Err(PacketError::invalid_length(65, packet.len()).into());
+            wire::response::RESYNC => {
+                if packet.len() != wire::BOARD_LEN + 7 {
+                    return Err(
+                        PacketError::invalid_length(wire::BOARD_LEN + 7, packet.len()).into(),
+                    );
                 }
 
+                let move_number = u16::from_be_bytes(packet[1..3].try_into().unwrap());
+                let side_to_move = match PieceColor::try_from(packet[3]) {
+                    Ok(color) => color,
+                    Err(e) => return Err(PacketError::data_error(&e.to_string()).into()),
+                };
+                let optional_color = |byte| match byte {
+                    wire::color::NONE => Ok(None),
+                    color => match PieceColor::try_from(color) {
+                        Ok(color) => Ok(Some(color)),
+                        Err(e) => Err(PacketError::data_error(&e.to_string())),
+                    },
+                };
+                let draw_offer = optional_color(packet[4])?;
+                let pause = PauseState {
+                    paused: packet[5] != 0,
+                    request: optional_color(packet[6])?,
+                };
                 let mut board = vec![];
-                for byte in packet[1..].to_vec() {
+                for byte in packet[7..].iter().copied() {
                     match PieceData::try_from(byte) {
                         Ok(piece) => board.push(piece),
                         Err(e) => return Err(PacketError::data_error(&e.to_string()).into()),
                     }
                 }
 
-                Ok(Self::Resync { board })
+                Ok(Self::Resync {
+                    // The length was checked above
+                    board: board.try_into().unwrap(),
+                    move_number,
+                    side_to_move,
+                    draw_offer,
+                    pause,
+                })
             }
-            // Ok
-            4 => Ok(Self::Acknowledge),
+            wire::response::ACKNOWLEDGE => Ok(Self::Acknowledge),
+            wire::response::CHALLENGE_ECHO => {
+                if packet.len() != 5 {
+                    return Err(PacketError::invalid_length(5, packet.len()).into());
+                }
+                let token = u32::from_be_bytes(packet[1..5].try_into().unwrap());
+
+                Ok(Self::ChallengeEcho { token })
+            }
+            wire::response::PROBE_RESPONSE => {
+                let commitment = match packet.len() {
+                    2 => None,
+                    len if len == 2 + COMMITMENT_LEN => Some(packet[2..].try_into().unwrap()),
+                    len => return Err(PacketError::invalid_length(2, len).into()),
+                };
+
+                Ok(Self::ProbeResponse {
+                    hosting: packet[1] != 0,
+                    commitment,
+                })
+            }
+            wire::response::REJECTED => {
+                if packet.len() != 5 {
+                    return Err(PacketError::invalid_length(5, packet.len()).into());
+                }
+
+                let kind = match P2pError::try_from(packet[1]) {
+                    Ok(kind) => kind,
+                    Err(e) => return Err(PacketError::data_error(&e.to_string()).into()),
+                };
+                let move_number = u16::from_be_bytes(packet[2..4].try_into().unwrap());
+                let side_to_move = match PieceColor::try_from(packet[4]) {
+                    Ok(color) => color,
+                    Err(e) => return Err(PacketError::data_error(&e.to_string()).into()),
+                };
+
+                Ok(Self::Rejected {
+                    kind,
+                    move_number,
+                    side_to_move,
+                })
+            }
+            wire::response::RETRY_LATER => {
+                if packet.len() != 4 {
+                    return Err(PacketError::invalid_length(4, packet.len()).into());
+                }
+
+                let kind = match P2pError::try_from(packet[1]) {
+                    Ok(kind) => kind,
+                    Err(e) => return Err(PacketError::data_error(&e.to_string()).into()),
+                };
+                let retry_after_ms = u16::from_be_bytes(packet[2..4].try_into().unwrap());
+
+                Ok(Self::RetryLater {
+                    kind,
+                    retry_after_ms,
+                })
+            }
+            wire::response::REMATCH_ANSWER => {
+                if packet.len() != 2 {
+                    return Err(PacketError::invalid_length(2, packet.len()).into());
+                }
+                let offerer_color = PieceColor::try_from(packet[1])?;
+
+                Ok(Self::RematchAnswer { offerer_color })
+            }
+            wire::response::REMATCH_DECLINE => Ok(Self::RematchDecline),
             _ => Err(
                 PacketError::data_error(&format!("Not valid packet type: {}", packet[0])).into(),
             ),
@@ -453,33 +1103,53 @@ impl FromPacket for P2pResponsePacket {
 impl ToByte for P2pResponsePacket {
     fn to_u8(&self) -> u8 {
         match self {
-            Self::Error { kind: _ } => 0,
-            Self::Pong => 1,
+            Self::Error { kind: _ } => wire::response::ERROR,
+            Self::Pong { payload: _ } => wire::response::PONG,
             Self::Connect {
                 client_color: _,
                 host_username: _,
-            } => 2,
-            Self::Resync { board: _ } => 3,
-            Self::Acknowledge => 4,
+                host_nonce: _,
+                peer_info: _,
+            } => wire::response::CONNECT,
+            Self::Resync { .. } => wire::response::RESYNC,
+            Self::Acknowledge => wire::response::ACKNOWLEDGE,
+            Self::ChallengeEcho { token: _ } => wire::response::CHALLENGE_ECHO,
+            Self::ProbeResponse {
+                hosting: _,
+                commitment: _,
+            } => wire::response::PROBE_RESPONSE,
+            Self::Rejected {
+                kind: _,
+                move_number: _,
+                side_to_move: _,
+            } => wire::response::REJECTED,
+            Self::RetryLater {
+                kind: _,
+                retry_after_ms: _,
+            } => wire::response::RETRY_LATER,
+            Self::RematchAnswer { offerer_color: _ } => wire::response::REMATCH_ANSWER,
+            Self::RematchDecline => wire::response::REMATCH_DECLINE,
         }
     }
 }
 
 impl ToPacket for GameAction {
-    fn to_packet(&self) -> Vec<u8> {
-        let mut bytes = self.to_u8().to_be_bytes().to_vec();
+    fn write_packet(&self, buf: &mut Vec<u8>) {
+        buf.push(self.to_u8());
         if let Self::MovePiece(move_action) = self {
-            bytes.push(move_action.index as u8);
-            bytes.push(move_action.end as u8);
-            bytes.push(move_action.promoted as u8);
+            buf.push(move_action.index as u8);
+            buf.push(move_action.end as u8);
+            buf.push(move_action.promoted as u8);
 
             if let Some(captured) = &move_action.captured {
                 for piece in captured {
-                    bytes.push(*piece as u8);
+                    buf.push(*piece as u8);
                 }
             }
         }
-        bytes
+        if let Self::DrawResponse(accepted) | Self::PauseResponse(accepted) = self {
+            buf.push(*accepted as u8);
+        }
     }
 }
 
@@ -488,9 +1158,9 @@ impl FromPacket for GameAction {
         if packet.is_empty() {
             return Err(PacketError::invalid_length(1, 0).into());
         }
-        match Self::from(packet[0]) {
-            Self::MovePiece(_) => {
-                if packet.len() < 3 {
+        match packet[0] {
+            wire::action::MOVE_PIECE => {
+                if packet.len() < 4 {
                     return Err(PacketError::invalid_length(4, packet.len()).into());
                 }
                 let index = packet[1] as usize;
@@ -506,79 +1176,184 @@ impl FromPacket for GameAction {
                     }
                 }
 
-                Ok(Self::move_piece(index, end, captured, promoted))
+                let action = Self::move_piece(index, end, captured, promoted);
+                if let Self::MovePiece(mov) = &action {
+                    // Remote indices are checked here, so they never reach the board unchecked
+                    if let Err(e) = mov.check_squares(get_game_options().squares()) {
+                        return Err(PacketError::data_error(&e.to_string()).into());
+                    }
+                }
+                Ok(action)
             }
-            Self::Surrender => {
+            wire::action::SURRENDER => {
                 if packet.len() != 1 {
                     return Err(PacketError::invalid_length(1, packet.len()).into());
                 }
                 Ok(Self::Surrender)
             }
-            Self::Stalemate => {
+            wire::action::OFFER_DRAW => {
+                if packet.len() != 1 {
+                    return Err(PacketError::invalid_length(1, packet.len()).into());
+                }
+                Ok(Self::OfferDraw)
+            }
+            wire::action::DRAW_RESPONSE => {
+                if packet.len() != 2 {
+                    return Err(PacketError::invalid_length(2, packet.len()).into());
+                }
+                Ok(Self::DrawResponse(packet[1] != 0))
+            }
+            wire::action::RESIGN_MATCH => {
+                if packet.len() != 1 {
+                    return Err(PacketError::invalid_length(1, packet.len()).into());
+                }
+                Ok(Self::ResignMatch)
+            }
+            wire::action::PAUSE_REQUEST => {
                 if packet.len() != 1 {
                     return Err(PacketError::invalid_length(1, packet.len()).into());
                 }
-                Ok(Self::Stalemate)
+                Ok(Self::PauseRequest)
             }
+            wire::action::RESUME_REQUEST => {
+                if packet.len() != 1 {
+                    return Err(PacketError::invalid_length(1, packet.len()).into());
+                }
+                Ok(Self::ResumeRequest)
+            }
+            wire::action::PAUSE_RESPONSE => {
+                if packet.len() != 2 {
+                    return Err(PacketError::invalid_length(2, packet.len()).into());
+                }
+                Ok(Self::PauseResponse(packet[1] != 0))
+            }
+            _ => Err(PacketError::data_error(&format!(
+                "Not valid game action type: {}",
+                packet[0]
+            ))
+            .into()),
+        }
+    }
+}
+
+impl ToByte for GameAction {
+    fn to_u8(&self) -> u8 {
+        match self {
+            Self::MovePiece(_) => wire::action::MOVE_PIECE,
+            Self::OfferDraw => wire::action::OFFER_DRAW,
+            Self::DrawResponse(_) => wire::action::DRAW_RESPONSE,
+            Self::Surrender => wire::action::SURRENDER,
+            Self::ResignMatch => wire::action::RESIGN_MATCH,
+            Self::PauseRequest => wire::action::PAUSE_REQUEST,
+            Self::ResumeRequest => wire::action::RESUME_REQUEST,
+            Self::PauseResponse(_) => wire::action::PAUSE_RESPONSE,
         }
     }
 }
 
-impl From<u8> for GameAction {
-    fn from(value: u8) -> Self {
+/// Why a game ended, sent in a `P2pRequestPacket::GameOver`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum GameOverReason {
+    /// The loser gave up.
+    Surrender = wire::game_over::SURRENDER,
+    /// The loser has no legal move left, e.g. because all of its pieces were captured.
+    NoMoves = wire::game_over::NO_MOVES,
+    /// A draw offer was accepted. The game has no winner.
+    DrawAccepted = wire::game_over::DRAW_ACCEPTED,
+    /// The loser lost the connection, and didn't come back within the grace period of the
+    /// `AbandonmentPolicy`.
+    Timeout = wire::game_over::TIMEOUT,
+}
+
+impl ToByte for GameOverReason {
+    fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
+impl TryFrom<u8> for GameOverReason {
+    type Error = anyhow::Error;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0 => Self::MovePiece(Move {
-                index: 0,
-                end: 0,
-                captured: None,
-                promoted: false,
-            }),
-            1 => Self::Stalemate,
-            2 => Self::Surrender,
-            _ => {
-                panic!("Not valid Gameaction value in 'From' cast")
-            }
+            wire::game_over::SURRENDER => Ok(Self::Surrender),
+            wire::game_over::NO_MOVES => Ok(Self::NoMoves),
+            wire::game_over::DRAW_ACCEPTED => Ok(Self::DrawAccepted),
+            wire::game_over::TIMEOUT => Ok(Self::Timeout),
+            _ => Err(anyhow!("Not a valid game over reason: {}", value)),
         }
     }
 }
 
-impl ToByte for GameAction {
+/// What a `P2pRequestPacket::StatusNote` tells about the player who sent it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum NoteKind {
+    /// The player is typing a chat message.
+    Typing = wire::note::TYPING,
+}
+
+impl ToByte for NoteKind {
     fn to_u8(&self) -> u8 {
-        match self {
-            Self::MovePiece(_) => 0,
-            Self::Stalemate => 1,
-            Self::Surrender => 2,
+        *self as u8
+    }
+}
+
+impl TryFrom<u8> for NoteKind {
+    type Error = anyhow::Error;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            wire::note::TYPING => Ok(Self::Typing),
+            _ => Err(anyhow!("Not a valid status note: {}", value)),
         }
     }
 }
 
 /// The error used by `P2pResponsePacket`
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
 pub enum P2pError {
     /// This errorkind is caused by the client having an outdated, or invalid board. An example of
     /// when this error is thrown, is when the clients wants to move a piece to an invalid
     /// position.
-    InvalidBoard,
+    InvalidBoard = wire::error::INVALID_BOARD,
     /// This errorkind is caused by the client sending a package with a wrong Join code.
-    InvalidJoinCode,
+    InvalidJoinCode = wire::error::INVALID_JOIN_CODE,
     /// This errorkind is caused by tge client sending a package with an invalid session Id.
-    InvalidSessionId,
+    InvalidSessionId = wire::error::INVALID_SESSION_ID,
     /// This errorkind is caused by the client attempting jo join a game that is already full.
-    FullGameSession,
+    FullGameSession = wire::error::FULL_GAME_SESSION,
     /// THis errorkind is caused by data flowing the wrong direction. E.g. when a Host tries to
     /// send a `P2pRequest::Connect` to the client.
-    WrongDirection,
+    WrongDirection = wire::error::WRONG_DIRECTION,
+    /// This errorkind is caused by a peer making a move when it isn't its turn, or from an
+    /// outdated move number.
+    NotYourTurn = wire::error::NOT_YOUR_TURN,
+    /// This errorkind is caused by the client playing with other `GameOptions` than the host.
+    OptionsMismatch = wire::error::OPTIONS_MISMATCH,
+    /// This errorkind is caused by a peer sending too many requests, which the host doesn't
+    /// answer for a while. See `throttle`.
+    Throttled = wire::error::THROTTLED,
+    /// This errorkind is caused by a peer speaking another `wire::PROTOCOL_VERSION`, which means
+    /// it runs another version of the game. It is sent with the version of the sender.
+    ProtocolMismatch = wire::error::PROTOCOL_MISMATCH,
+    /// This errorkind is caused by a peer making a move that isn't legal, e.g. one that leaves the
+    /// board or promotes a piece that didn't reach the last row.
+    InvalidMove = wire::error::INVALID_MOVE,
+    /// This errorkind is caused by a client joining with a username that can't be used. See
+    /// `normalize_username()`.
+    InvalidUsername = wire::error::INVALID_USERNAME,
+    /// This errorkind is caused by a peer offering a rematch while the game of the other peer
+    /// hasn't ended.
+    GameInProgress = wire::error::GAME_IN_PROGRESS,
+    /// This errorkind is caused by a client joining a host that has no nonce committed for the
+    /// coin flip, so the colors can't be decided. See `coin_flip`.
+    NoCommitment = wire::error::NO_COMMITMENT,
 }
 
 impl ToByte for P2pError {
     fn to_u8(&self) -> u8 {
-        match self {
-            Self::InvalidBoard => 0,
-            Self::InvalidJoinCode => 1,
-            Self::InvalidSessionId => 2,
-            Self::FullGameSession => 3,
-            Self::WrongDirection => 4,
-        }
+        *self as u8
     }
 }
 
@@ -586,15 +1361,20 @@ impl TryFrom<u8> for P2pError {
     type Error = anyhow::Error;
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0 => Ok(Self::InvalidBoard),
-            1 => Ok(Self::InvalidJoinCode),
-            2 => Ok(Self::InvalidSessionId),
-            3 => Ok(Self::FullGameSession),
-            4 => Ok(Self::WrongDirection),
-            _ => Err(anyhow!(
-                "Can only take values in range 0..=4 for P2p Error, got {}",
-                value
-            )),
+            wire::error::INVALID_BOARD => Ok(Self::InvalidBoard),
+            wire::error::INVALID_JOIN_CODE => Ok(Self::InvalidJoinCode),
+            wire::error::INVALID_SESSION_ID => Ok(Self::InvalidSessionId),
+            wire::error::FULL_GAME_SESSION => Ok(Self::FullGameSession),
+            wire::error::WRONG_DIRECTION => Ok(Self::WrongDirection),
+            wire::error::NOT_YOUR_TURN => Ok(Self::NotYourTurn),
+            wire::error::OPTIONS_MISMATCH => Ok(Self::OptionsMismatch),
+            wire::error::THROTTLED => Ok(Self::Throttled),
+            wire::error::PROTOCOL_MISMATCH => Ok(Self::ProtocolMismatch),
+            wire::error::INVALID_MOVE => Ok(Self::InvalidMove),
+            wire::error::INVALID_USERNAME => Ok(Self::InvalidUsername),
+            wire::error::GAME_IN_PROGRESS => Ok(Self::GameInProgress),
+            wire::error::NO_COMMITMENT => Ok(Self::NoCommitment),
+            _ => Err(anyhow!("Not a valid P2p Error code: {}", value)),
         }
     }
 }
@@ -603,8 +1383,8 @@ impl TryFrom<u8> for P2pError {
 impl ToByte for PieceColor {
     fn to_u8(&self) -> u8 {
         match self {
-            Self::White => 1,
-            Self::Black => 2,
+            Self::White => wire::color::WHITE,
+            Self::Black => wire::color::BLACK,
         }
     }
 }
@@ -613,8 +1393,8 @@ impl TryFrom<u8> for PieceColor {
     type Error = anyhow::Error;
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            1 => Ok(Self::White),
-            2 => Ok(Self::Black),
+            wire::color::WHITE => Ok(Self::White),
+            wire::color::BLACK => Ok(Self::Black),
             _ => Err(anyhow!(
                 "Can only take 1 or 2 for Piece Color, got {}",
                 value
@@ -622,26 +1402,27 @@ impl TryFrom<u8> for PieceColor {
         }
     }
 }
-/// THIS IS A TEMP STRUCT
+/// A square of the board as a byte. An empty square is always `wire::piece::EMPTY`, whatever its
+/// color and king flag are, so it decodes to `PieceData::default()`.
 impl ToByte for PieceData {
     fn to_u8(&self) -> u8 {
-        let mut byte: u8 = 0;
+        let mut byte: u8 = wire::piece::EMPTY;
 
-        if self.is_active {
+        if !self.is_active {
             return byte;
         }
 
         match self.color {
             PieceColor::White => {
-                byte |= 0b001;
+                byte |= wire::piece::WHITE;
             }
             PieceColor::Black => {
-                byte |= 0b010;
+                byte |= wire::piece::BLACK;
             }
         }
 
         if self.is_king {
-            byte |= 0b100;
+            byte |= wire::piece::KING;
         }
 
         byte
@@ -651,26 +1432,25 @@ impl ToByte for PieceData {
 impl TryFrom<u8> for PieceData {
     type Error = anyhow::Error;
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        if value == 0 {
-            let piece = Self {
-                color: PieceColor::White,
-                is_active: false,
-                is_king: false,
-            };
-            return Ok(piece);
+        if value == wire::piece::EMPTY {
+            return Ok(Self::default());
         }
 
-        if (value & 0b11).count_ones() != 1 {
-            return Err(anyhow!("Got byte in wrong format"));
+        // Every other byte must be exactly one color, maybe with the king bit. Anything else
+        // wouldn't encode back to the same byte
+        if value & !wire::piece::PIECE_MASK != 0
+            || (value & wire::piece::COLOR_MASK).count_ones() != 1
+        {
+            return Err(anyhow!("Not a valid piece: {:#05b}", value));
         }
 
-        let color = if value & 0b01 == 1 {
+        let color = if value & wire::piece::WHITE != 0 {
             PieceColor::White
         } else {
             PieceColor::Black
         };
 
-        let is_king = value & 0b100 >= 0b100;
+        let is_king = value & wire::piece::KING != 0;
 
         let piece = Self {
             color,
@@ -680,3 +1460,942 @@ impl TryFrom<u8> for PieceData {
         Ok(piece)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{game::options::GameOptions, net::status::set_game_options};
+    use peer_info::Platform;
+
+    /// The encoded packets of the protocol vectors, one of each kind of packet.
+    fn encoded_packets() -> Vec<Vec<u8>> {
+        vectors::generate()
+            .iter()
+            .map(|vector| hex::decode(&vector.bytes).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn empty_packet_is_refused() {
+        let e = P2pPacket::from_packet(vec![]).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(PacketError::Empty)));
+        assert!(P2pRequestPacket::from_packet(vec![]).is_err());
+        assert!(P2pResponsePacket::from_packet(vec![]).is_err());
+        assert!(GameAction::from_packet(vec![]).is_err());
+    }
+
+    #[test]
+    fn truncated_packets_never_panic() {
+        for bytes in encoded_packets() {
+            for len in 0..bytes.len() {
+                let result = P2pPacket::from_packet(bytes[..len].to_vec());
+                if len <= HEADER_LEN {
+                    assert!(
+                        result.is_err(),
+                        "the header {:02x?} was read",
+                        &bytes[..len]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn short_inputs_never_panic() {
+        let inputs = (0..=u8::MAX)
+            .map(|a| vec![a])
+            .chain((0..=u16::MAX).map(|ab| ab.to_be_bytes().to_vec()));
+        for input in inputs {
+            let _ = P2pPacket::from_packet(input.clone());
+            let _ = P2pRequestPacket::from_packet(input.clone());
+            let _ = P2pResponsePacket::from_packet(input.clone());
+            let _ = GameAction::from_packet(input);
+        }
+    }
+
+    #[test]
+    fn short_bodies_never_panic() {
+        // Every kind of body, cut off after its kind byte
+        for bytes in encoded_packets() {
+            let body = &bytes[HEADER_LEN..];
+            for len in 1..body.len() {
+                let _ = P2pRequestPacket::from_packet(body[..len].to_vec());
+                let _ = P2pResponsePacket::from_packet(body[..len].to_vec());
+            }
+        }
+    }
+
+    #[test]
+    fn move_piece_round_trip() {
+        let moves = [
+            (9, 13, None, false),
+            (22, 13, Some(vec![17]), false),
+            (1, 19, Some(vec![5, 14]), false),
+            (6, 1, None, true),
+            (31, 27, None, false),
+        ];
+        for (index, end, captured, promoted) in moves {
+            let action = GameAction::move_piece(index, end, captured, promoted);
+            let bytes = action.to_packet();
+            // The start square is written first, and must be read first
+            assert_eq!(
+                bytes[..3],
+                [wire::action::MOVE_PIECE, index as u8, end as u8]
+            );
+            assert_eq!(GameAction::from_packet(bytes).unwrap(), action);
+
+            let packet = P2pRequestPacket::game_action(action, 7, 3);
+            let request = P2pPacket::from(P2pRequest::new(0x1a2b, 0x0001, packet));
+            assert_eq!(
+                P2pPacket::from_packet(request.to_packet()).unwrap(),
+                request
+            );
+        }
+    }
+
+    /// If the move `bytes` is refused as a `PacketError::DataError`.
+    fn is_refused(bytes: &[u8]) -> bool {
+        let mut packet = vec![wire::action::MOVE_PIECE];
+        packet.extend_from_slice(bytes);
+        match GameAction::from_packet(packet) {
+            Ok(_) => false,
+            Err(e) => matches!(e.downcast_ref(), Some(PacketError::DataError { .. })),
+        }
+    }
+
+    #[test]
+    fn moves_off_the_board_are_refused() {
+        assert!(!is_refused(&[9, 13, 0]));
+        assert!(!is_refused(&[9, 18, 0, 13]));
+        assert!(is_refused(&[200, 13, 0]));
+        assert!(is_refused(&[9, 200, 0]));
+        assert!(is_refused(&[9, 18, 0, 200]));
+        assert!(is_refused(&[32, 13, 0]));
+        // Square 31 is the last one
+        assert!(!is_refused(&[31, 27, 0]));
+    }
+
+    #[test]
+    fn moves_that_repeat_a_square_are_refused() {
+        assert!(is_refused(&[9, 9, 0]));
+        assert!(is_refused(&[1, 19, 0, 5, 5]));
+        assert!(!is_refused(&[1, 19, 0, 5, 14]));
+    }
+
+    #[test]
+    fn every_piece_round_trips() {
+        let mut states = vec![PieceData::default()];
+        for color in [PieceColor::White, PieceColor::Black] {
+            for is_king in [false, true] {
+                states.push(PieceData {
+                    is_active: true,
+                    color,
+                    is_king,
+                });
+            }
+        }
+        let bytes: Vec<u8> = states.iter().map(|state| state.to_u8()).collect();
+        assert_eq!(bytes, [0b000, 0b001, 0b101, 0b010, 0b110]);
+        for (state, byte) in states.iter().zip(bytes) {
+            assert_eq!(PieceData::try_from(byte).unwrap(), *state);
+        }
+
+        // An empty square is empty, whatever else it says
+        let empty = PieceData {
+            is_active: false,
+            color: PieceColor::Black,
+            is_king: true,
+        };
+        assert_eq!(empty.to_u8(), wire::piece::EMPTY);
+    }
+
+    #[test]
+    fn invalid_piece_bytes_are_refused() {
+        for byte in [0b011, 0b111, 0b100, 0b1000, 0b1001, u8::MAX] {
+            assert!(PieceData::try_from(byte).is_err(), "{:#05b} was read", byte);
+        }
+        let pieces = (0..=u8::MAX)
+            .filter(|byte| PieceData::try_from(*byte).is_ok())
+            .count();
+        assert_eq!(pieces, 5);
+    }
+
+    /// A Resync response body for a board of `squares` empty squares.
+    fn resync_body(squares: usize) -> Vec<u8> {
+        let mut body = vec![
+            wire::response::RESYNC,
+            0,
+            8,
+            wire::color::WHITE,
+            wire::color::NONE,
+            0,
+            wire::color::NONE,
+        ];
+        body.resize(body.len() + squares, wire::piece::EMPTY);
+        body
+    }
+
+    #[test]
+    fn resync_board_must_be_whole() {
+        let exact = P2pResponsePacket::from_packet(resync_body(wire::BOARD_LEN)).unwrap();
+        assert_eq!(
+            exact,
+            P2pResponsePacket::resync(
+                std::array::from_fn(|_| PieceData::default()),
+                8,
+                PieceColor::White,
+                None,
+                PauseState::default()
+            )
+        );
+        assert_eq!(exact.to_packet(), resync_body(wire::BOARD_LEN));
+
+        for squares in [0, 1, wire::BOARD_LEN - 1, wire::BOARD_LEN + 1, 64] {
+            let e = P2pResponsePacket::from_packet(resync_body(squares)).unwrap_err();
+            let expected = wire::BOARD_LEN + 7;
+            assert!(
+                matches!(
+                    e.downcast_ref(),
+                    Some(&PacketError::InvalidLength { expected: e, got })
+                        if e == expected && got == squares + 7
+                ),
+                "a board of {} squares gave {}",
+                squares,
+                e
+            );
+        }
+        // Cut off inside the move number, the turn, the draw offer and the pause
+        for len in 1..7 {
+            assert!(P2pResponsePacket::from_packet(resync_body(0)[..len].to_vec()).is_err());
+        }
+    }
+
+    #[test]
+    fn resync_move_number_crosses_the_byte_boundary() {
+        let numbers = [0, 1, 254, 255, 256, 257, 511, 512, u16::MAX - 1, u16::MAX];
+        let mut read = vec![];
+        for move_number in numbers {
+            let side_to_move = PieceColor::side_to_move(move_number);
+            let packet = P2pResponsePacket::resync(
+                std::array::from_fn(|_| PieceData::default()),
+                move_number,
+                side_to_move,
+                None,
+                PauseState::default(),
+            );
+            let bytes = packet.to_packet();
+            assert_eq!(bytes[1..3], move_number.to_be_bytes());
+            let decoded = P2pResponsePacket::from_packet(bytes).unwrap();
+            assert_eq!(decoded, packet);
+            let P2pResponsePacket::Resync {
+                move_number,
+                side_to_move: read_side,
+                ..
+            } = decoded
+            else {
+                unreachable!();
+            };
+            assert_eq!(read_side, side_to_move, "after move {}", move_number);
+            read.push(move_number);
+        }
+        // Past 255 the count goes on in the high byte, instead of wrapping around to 0
+        assert_eq!(read, numbers);
+        assert!(read.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn connect_round_trip() {
+        let long = "a".repeat(1000);
+        for username in ["", "Bob", "Søren ♟ 名前", &long] {
+            let join_code = "c0a8000a1b58";
+            let request = P2pRequestPacket::Connect {
+                join_code: join_code.to_owned(),
+                username: username.to_owned(),
+                nonce: u64::MAX - 1,
+                peer_info: PeerInfo::local(),
+                preference: Some(PieceColor::White),
+            };
+            let bytes = request.to_packet();
+            assert_eq!(
+                P2pRequestPacket::from_packet(bytes.clone()).unwrap(),
+                request
+            );
+            // The strings can't run into each other, whatever they hold
+            assert_eq!(bytes[1..3], (join_code.len() as u16).to_be_bytes());
+            assert_eq!(bytes[3..3 + join_code.len()], *join_code.as_bytes());
+
+            let response = P2pResponsePacket::Connect {
+                client_color: PieceColor::Black,
+                host_username: username.to_owned(),
+                host_nonce: 42,
+                peer_info: PeerInfo::local(),
+            };
+            assert_eq!(
+                P2pResponsePacket::from_packet(response.to_packet()).unwrap(),
+                response
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_connect_is_refused() {
+        let bytes = P2pRequestPacket::connect("c0a8000a1b58", "Søren", 7, Some(PieceColor::Black))
+            .unwrap()
+            .to_packet();
+        // Without the preference, it's a connect from a client too old to send one
+        let P2pRequestPacket::Connect { preference, .. } =
+            P2pRequestPacket::from_packet(bytes[..bytes.len() - 1].to_vec()).unwrap()
+        else {
+            panic!("Not a connect request");
+        };
+        assert_eq!(preference, None);
+        for len in 1..bytes.len() - 1 {
+            let e = P2pRequestPacket::from_packet(bytes[..len].to_vec()).unwrap_err();
+            assert!(
+                matches!(e.downcast_ref(), Some(PacketError::DataError { .. })),
+                "{} of {} bytes gave {}",
+                len,
+                bytes.len(),
+                e
+            );
+        }
+
+        // The fixed fields are checked for length first, and then the name
+        let bytes = P2pResponsePacket::connect(PieceColor::White, "Søren".to_owned(), 7)
+            .unwrap()
+            .to_packet();
+        for len in 1..bytes.len() {
+            let e = P2pResponsePacket::from_packet(bytes[..len].to_vec()).unwrap_err();
+            assert!(
+                matches!(
+                    e.downcast_ref(),
+                    Some(PacketError::DataError { .. } | PacketError::InvalidLength { .. })
+                ),
+                "{} of {} bytes gave {}",
+                len,
+                bytes.len(),
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn usernames_around_the_length_limit() {
+        let at_limit = "a".repeat(MAX_USERNAME_LEN);
+        let over_limit = "a".repeat(MAX_USERNAME_LEN + 1);
+        // The last character of each of these ends on, or crosses, the limit
+        let two_bytes_at_limit = format!("{}ø", "a".repeat(MAX_USERNAME_LEN - 2));
+        let two_bytes_across_limit = format!("{}ø", "a".repeat(MAX_USERNAME_LEN - 1));
+        let cases = [
+            (at_limit.as_str(), Ok(at_limit.as_str())),
+            (&format!("  {}\t", at_limit), Ok(at_limit.as_str())),
+            (&over_limit, Err(UsernameError::TooLong)),
+            (&two_bytes_at_limit, Ok(two_bytes_at_limit.as_str())),
+            (&two_bytes_across_limit, Err(UsernameError::TooLong)),
+            (&"ø".repeat(MAX_USERNAME_LEN / 2), Ok("øøøøøøøøøøøøøøøø")),
+            (&format!("{}ø", "♟".repeat(10)), Ok("♟♟♟♟♟♟♟♟♟♟ø")),
+            (&"♟".repeat(11), Err(UsernameError::TooLong)),
+            (" \t ", Err(UsernameError::Empty)),
+            ("Søren\nHansen", Err(UsernameError::ControlCharacter)),
+        ];
+        for (username, expected) in cases {
+            assert_eq!(normalize_username(username), expected, "{:?}", username);
+        }
+    }
+
+    #[test]
+    fn username_cut_inside_a_character_is_refused() {
+        let username = format!("{}ø", "a".repeat(MAX_USERNAME_LEN - 1));
+        let request = P2pRequestPacket::Connect {
+            join_code: "c0a8000a1b58".to_owned(),
+            username: username.clone(),
+            nonce: 7,
+            peer_info: PeerInfo::local(),
+            preference: None,
+        };
+        let mut bytes = request.to_packet();
+        // Without the preference, the name is at the end
+        bytes.pop();
+        // Cut off the last byte of the name, in the middle of the "ø"
+        let len_at = bytes.len() - username.len() - 2;
+        bytes[len_at..len_at + 2].copy_from_slice(&(MAX_USERNAME_LEN as u16).to_be_bytes());
+        bytes.pop();
+        let e = P2pRequestPacket::from_packet(bytes).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(PacketError::DataError { .. })
+        ));
+    }
+
+    #[test]
+    fn chat_messages_around_the_length_limit() {
+        // The last character of each of these ends on, or crosses, the limit
+        let two_bytes_at_limit = format!("{}ø", "a".repeat(MAX_CHAT_LEN - 2));
+        let two_bytes_across_limit = format!("{}ø", "a".repeat(MAX_CHAT_LEN - 1));
+        let taken = [
+            "",
+            "Good game! ♟",
+            &"a".repeat(MAX_CHAT_LEN),
+            &two_bytes_at_limit,
+        ];
+        for message in taken {
+            let request = P2pRequestPacket::chat(message).unwrap();
+            let bytes = request.to_packet();
+            assert_eq!(bytes[0], wire::request::CHAT);
+            assert_eq!(P2pRequestPacket::from_packet(bytes).unwrap(), request);
+        }
+
+        for message in [&"a".repeat(MAX_CHAT_LEN + 1), &two_bytes_across_limit] {
+            let e = P2pRequestPacket::chat(message).unwrap_err();
+            assert!(matches!(
+                e.downcast_ref(),
+                Some(PacketError::DataError { .. })
+            ));
+            // Nor is one taken from the wire
+            let request = P2pRequestPacket::Chat {
+                message: message.clone(),
+            };
+            let e = P2pRequestPacket::from_packet(request.to_packet()).unwrap_err();
+            assert!(matches!(
+                e.downcast_ref(),
+                Some(PacketError::DataError { .. })
+            ));
+        }
+    }
+
+    /// The versions a peer can speak, other than ours.
+    fn other_versions() -> [u8; 4] {
+        let version = wire::PROTOCOL_VERSION;
+        [0, version - 1, version + 1, u8::MAX]
+    }
+
+    #[test]
+    fn packets_of_another_version_are_refused() {
+        let request = P2pRequest::new(0x1a2b, 0x0007, P2pRequestPacket::ping());
+        let response =
+            P2pResponse::new(0x1a2b, 0x0007, P2pResponsePacket::Pong { payload: vec![1] });
+        let packets = [(P2pPacket::from(request), true), (response.into(), false)];
+        for (packet, is_request) in packets {
+            let mut bytes = packet.to_packet();
+            assert_eq!(bytes[1], wire::PROTOCOL_VERSION);
+            for version in other_versions() {
+                bytes[1] = version;
+                let e = P2pPacket::from_packet(bytes.clone()).unwrap_err();
+                assert_eq!(
+                    e.downcast_ref(),
+                    Some(&ForeignVersion {
+                        version,
+                        is_request,
+                        session_id: 0x1a2b,
+                        transaction_id: 0x0007,
+                    })
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn protocol_mismatch_is_read_from_every_version() {
+        let response = P2pResponse::new(
+            0x1a2b,
+            0x0007,
+            P2pResponsePacket::error(P2pError::ProtocolMismatch),
+        );
+        let mut bytes = P2pPacket::from(response.clone()).to_packet();
+        // The layout can never change, since every version must read it
+        assert_eq!(
+            bytes,
+            [
+                wire::kind::RESPONSE,
+                wire::PROTOCOL_VERSION,
+                0x1a,
+                0x2b,
+                0x00,
+                0x07,
+                wire::response::ERROR,
+                8
+            ]
+        );
+        for version in other_versions() {
+            bytes[1] = version;
+            let read = P2pPacket::from_packet(bytes.clone()).unwrap();
+            assert_eq!(read, P2pPacket::Response(response.clone()));
+        }
+
+        // Any other error from another version is still refused
+        let mut bytes = P2pPacket::from(P2pResponse::new(
+            0x1a2b,
+            0x0007,
+            P2pResponsePacket::error(P2pError::InvalidMove),
+        ))
+        .to_packet();
+        bytes[1] = wire::PROTOCOL_VERSION + 1;
+        let e = P2pPacket::from_packet(bytes).unwrap_err();
+        assert!(e.is::<ForeignVersion>());
+    }
+
+    #[test]
+    fn error_codes_are_stable() {
+        // The codes are written out, since a code can never change once it's been sent
+        let errors = [
+            (P2pError::InvalidBoard, 0),
+            (P2pError::InvalidJoinCode, 1),
+            (P2pError::InvalidSessionId, 2),
+            (P2pError::FullGameSession, 3),
+            (P2pError::WrongDirection, 4),
+            (P2pError::NotYourTurn, 5),
+            (P2pError::OptionsMismatch, 6),
+            (P2pError::Throttled, 7),
+            (P2pError::ProtocolMismatch, 8),
+            (P2pError::InvalidMove, 9),
+            (P2pError::InvalidUsername, 10),
+            (P2pError::GameInProgress, 11),
+            (P2pError::NoCommitment, 12),
+        ];
+        for (kind, code) in errors {
+            assert_eq!(kind.to_u8(), code, "{:?}", kind);
+            assert_eq!(P2pError::try_from(code).unwrap(), kind);
+
+            let response = P2pResponsePacket::error(kind);
+            let bytes = response.to_packet();
+            assert_eq!(bytes, [wire::response::ERROR, code]);
+            assert_eq!(P2pResponsePacket::from_packet(bytes).unwrap(), response);
+        }
+
+        for code in errors.len() as u8..=u8::MAX {
+            assert!(P2pError::try_from(code).is_err(), "{} was read", code);
+            let bytes = vec![wire::response::ERROR, code];
+            let e = P2pResponsePacket::from_packet(bytes).unwrap_err();
+            assert!(matches!(
+                e.downcast_ref(),
+                Some(PacketError::DataError { .. })
+            ));
+        }
+    }
+
+    /// Every code in `wire::request`, which must be the kinds of `P2pRequestPacket`.
+    const REQUEST_CODES: [u8; 13] = [
+        wire::request::PING,
+        wire::request::CONNECT,
+        wire::request::RESYNC,
+        wire::request::GAME_ACTION,
+        wire::request::CHALLENGE,
+        wire::request::PROBE,
+        wire::request::OPTIONS_ACK,
+        wire::request::CHAT,
+        wire::request::DISCONNECT,
+        wire::request::GAME_OVER,
+        wire::request::REMATCH_OFFER,
+        wire::request::BOARD_HASH,
+        wire::request::STATUS_NOTE,
+    ];
+
+    /// Every code in `wire::response`, which must be the kinds of `P2pResponsePacket`.
+    const RESPONSE_CODES: [u8; 11] = [
+        wire::response::ERROR,
+        wire::response::PONG,
+        wire::response::CONNECT,
+        wire::response::RESYNC,
+        wire::response::ACKNOWLEDGE,
+        wire::response::CHALLENGE_ECHO,
+        wire::response::PROBE_RESPONSE,
+        wire::response::REJECTED,
+        wire::response::RETRY_LATER,
+        wire::response::REMATCH_ANSWER,
+        wire::response::REMATCH_DECLINE,
+    ];
+
+    /// Every code in `wire::action`, which must be the kinds of `GameAction`.
+    const ACTION_CODES: [u8; 8] = [
+        wire::action::MOVE_PIECE,
+        wire::action::OFFER_DRAW,
+        wire::action::SURRENDER,
+        wire::action::DRAW_RESPONSE,
+        wire::action::RESIGN_MATCH,
+        wire::action::PAUSE_REQUEST,
+        wire::action::RESUME_REQUEST,
+        wire::action::PAUSE_RESPONSE,
+    ];
+
+    /// Check that `codes` are the codes of the variants of `T`: Each code is read as a variant
+    /// that writes it again, and no other byte is read.
+    fn assert_codes_round_trip<T>(codes: &[u8])
+    where
+        T: TryFrom<u8> + ToByte + PartialEq + std::fmt::Debug,
+    {
+        for &code in codes {
+            let Ok(value) = T::try_from(code) else {
+                panic!("{} wasn't read", code);
+            };
+            assert_eq!(value.to_u8(), code, "{:?}", value);
+        }
+        let values: Vec<T> = decodable();
+        assert_eq!(values.len(), codes.len(), "{:?} aren't the codes", codes);
+        for value in values {
+            assert_eq!(T::try_from(value.to_u8()).ok(), Some(value));
+        }
+    }
+
+    #[test]
+    fn wire_codes_are_unique_and_round_trip() {
+        let namespaces: [(&str, &[u8]); 10] = [
+            (
+                "kind",
+                &[
+                    wire::kind::REQUEST,
+                    wire::kind::RESPONSE,
+                    wire::kind::FRAGMENT,
+                ],
+            ),
+            ("request", &REQUEST_CODES),
+            ("response", &RESPONSE_CODES),
+            ("action", &ACTION_CODES),
+            (
+                "error",
+                &[
+                    wire::error::INVALID_BOARD,
+                    wire::error::INVALID_JOIN_CODE,
+                    wire::error::INVALID_SESSION_ID,
+                    wire::error::FULL_GAME_SESSION,
+                    wire::error::WRONG_DIRECTION,
+                    wire::error::NOT_YOUR_TURN,
+                    wire::error::OPTIONS_MISMATCH,
+                    wire::error::THROTTLED,
+                    wire::error::PROTOCOL_MISMATCH,
+                    wire::error::INVALID_MOVE,
+                    wire::error::INVALID_USERNAME,
+                    wire::error::GAME_IN_PROGRESS,
+                    wire::error::NO_COMMITMENT,
+                ],
+            ),
+            (
+                "color",
+                &[wire::color::NONE, wire::color::WHITE, wire::color::BLACK],
+            ),
+            (
+                "game_over",
+                &[
+                    wire::game_over::SURRENDER,
+                    wire::game_over::NO_MOVES,
+                    wire::game_over::DRAW_ACCEPTED,
+                    wire::game_over::TIMEOUT,
+                ],
+            ),
+            ("note", &[wire::note::TYPING]),
+            (
+                "platform",
+                &[
+                    wire::platform::UNKNOWN,
+                    wire::platform::WINDOWS,
+                    wire::platform::LINUX,
+                    wire::platform::MACOS,
+                ],
+            ),
+            (
+                "piece",
+                &[
+                    wire::piece::EMPTY,
+                    wire::piece::WHITE,
+                    wire::piece::BLACK,
+                    wire::piece::KING,
+                ],
+            ),
+        ];
+        for (name, codes) in namespaces {
+            let unique: HashSet<&u8> = codes.iter().collect();
+            assert_eq!(unique.len(), codes.len(), "wire::{} repeats a code", name);
+        }
+        let codes = |name| namespaces.iter().find(|(n, _)| *n == name).unwrap().1;
+
+        assert_codes_round_trip::<P2pError>(codes("error"));
+        assert_codes_round_trip::<GameOverReason>(codes("game_over"));
+        assert_codes_round_trip::<NoteKind>(codes("note"));
+        // No color is written for a missing one, and isn't a color itself
+        assert_codes_round_trip::<PieceColor>(&[wire::color::WHITE, wire::color::BLACK]);
+        assert!(PieceColor::try_from(wire::color::NONE).is_err());
+
+        // The platform is only written as part of the peer info
+        let platforms = [
+            Platform::Unknown,
+            Platform::Windows,
+            Platform::Linux,
+            Platform::MacOs,
+        ];
+        for (platform, &code) in platforms.into_iter().zip(codes("platform")) {
+            let info = PeerInfo {
+                version: "1.0.0".to_owned(),
+                platform,
+            };
+            let mut bytes = vec![];
+            info.write(&mut bytes);
+            assert_eq!(bytes[0], code, "{:?}", platform);
+            assert_eq!(PeerInfo::read(&bytes).unwrap().0, info);
+        }
+    }
+
+    #[test]
+    fn remote_moves_are_checked_against_the_game_options() {
+        let _state = lock_global_state();
+        // Square 40 is on a 10 by 10 board, but not on the 8 by 8 one
+        let mov = GameAction::move_piece(40, 35, None, false);
+        let packet = mov.to_packet();
+        assert!(GameAction::from_packet(packet.clone()).is_err());
+
+        set_game_options(GameOptions {
+            board_size: 10,
+            ..GameOptions::new()
+        });
+        let decoded = GameAction::from_packet(packet);
+        set_game_options(GameOptions::new());
+        assert_eq!(decoded.unwrap(), mov);
+    }
+
+    /// How many packets of each kind `random_packets_round_trip` makes.
+    const RANDOM_ROUNDS: usize = 5_000;
+
+    /// Every value some byte decodes to, e.g. every `P2pError`.
+    fn decodable<T: TryFrom<u8>>() -> Vec<T> {
+        (0..=u8::MAX)
+            .filter_map(|byte| T::try_from(byte).ok())
+            .collect()
+    }
+
+    /// Makes random packets of every kind, which can be decoded again.
+    struct RandomPackets {
+        rng: StdRng,
+        colors: Vec<PieceColor>,
+        errors: Vec<P2pError>,
+        reasons: Vec<GameOverReason>,
+        notes: Vec<NoteKind>,
+        pieces: Vec<PieceData>,
+    }
+
+    impl RandomPackets {
+        fn new(seed: u64) -> Self {
+            Self {
+                rng: StdRng::seed_from_u64(seed),
+                colors: decodable(),
+                errors: decodable(),
+                reasons: decodable(),
+                notes: decodable(),
+                pieces: decodable(),
+            }
+        }
+
+        fn pick<T: Clone>(&mut self, values: impl Fn(&Self) -> &[T]) -> T {
+            let i = self.rng.gen_range(0..values(self).len());
+            values(self)[i].clone()
+        }
+
+        fn color(&mut self) -> PieceColor {
+            self.pick(|this| &this.colors)
+        }
+
+        fn optional_color(&mut self) -> Option<PieceColor> {
+            match self.rng.gen() {
+                true => Some(self.color()),
+                false => None,
+            }
+        }
+
+        fn bytes(&mut self, max_len: usize) -> Vec<u8> {
+            let len = self.rng.gen_range(0..=max_len);
+            (0..len).map(|_| self.rng.gen()).collect()
+        }
+
+        /// A string of at most `max_len` bytes, with characters of every UTF-8 length.
+        fn string(&mut self, max_len: usize) -> String {
+            let len = self.rng.gen_range(0..=max_len);
+            let mut s = String::new();
+            let chars = ['a', 'Z', '7', ' ', 'æ', 'ø', '♛', '🙂'];
+            while let Some(&c) = chars.choose(&mut self.rng) {
+                if s.len() + c.len_utf8() > len {
+                    return s;
+                }
+                s.push(c);
+            }
+            s
+        }
+
+        /// A move on the 8 by 8 board. Its squares are all different, like a decoded move's must
+        /// be.
+        fn mov(&mut self) -> GameAction {
+            let mut squares: Vec<usize> = (0..GameOptions::new().squares()).collect();
+            squares.shuffle(&mut self.rng);
+            let captures = self.rng.gen_range(0..=4);
+            let captured = (captures > 0).then(|| squares[2..2 + captures].to_vec());
+            GameAction::move_piece(squares[0], squares[1], captured, self.rng.gen())
+        }
+
+        fn action(&mut self) -> GameAction {
+            match self.rng.gen_range(0..8) {
+                0 => self.mov(),
+                1 => GameAction::OfferDraw,
+                2 => GameAction::DrawResponse(self.rng.gen()),
+                3 => GameAction::Surrender,
+                4 => GameAction::ResignMatch,
+                5 => GameAction::PauseRequest,
+                6 => GameAction::ResumeRequest,
+                _ => GameAction::PauseResponse(self.rng.gen()),
+            }
+        }
+
+        fn peer_info(&mut self) -> PeerInfo {
+            let platforms = [
+                Platform::Unknown,
+                Platform::Windows,
+                Platform::Linux,
+                Platform::MacOs,
+            ];
+            PeerInfo {
+                version: self.string(16),
+                platform: *platforms.choose(&mut self.rng).unwrap(),
+            }
+        }
+
+        fn request(&mut self) -> P2pRequestPacket {
+            match self.rng.gen_range(0..13) {
+                0 => P2pRequestPacket::Ping {
+                    payload: self.bytes(64),
+                },
+                1 => P2pRequestPacket::Connect {
+                    join_code: self.string(12),
+                    username: self.string(MAX_USERNAME_LEN),
+                    nonce: self.rng.gen(),
+                    peer_info: self.peer_info(),
+                    preference: self.optional_color(),
+                },
+                2 => P2pRequestPacket::Resync,
+                3 => {
+                    let action = self.action();
+                    P2pRequestPacket::game_action(action, self.rng.gen(), self.rng.gen())
+                }
+                4 => P2pRequestPacket::Challenge {
+                    token: self.rng.gen(),
+                },
+                5 => P2pRequestPacket::Probe,
+                6 => P2pRequestPacket::OptionsAck {
+                    options_hash: self.rng.gen(),
+                },
+                7 => P2pRequestPacket::Chat {
+                    message: self.string(MAX_CHAT_LEN),
+                },
+                8 => P2pRequestPacket::Disconnect,
+                9 => {
+                    let winner = self.optional_color();
+                    P2pRequestPacket::game_over(winner, self.pick(|this| &this.reasons))
+                }
+                10 => P2pRequestPacket::RematchOffer,
+                11 => P2pRequestPacket::BoardHash {
+                    hash: self.rng.gen(),
+                    move_count: self.rng.gen(),
+                },
+                _ => P2pRequestPacket::StatusNote {
+                    kind: self.pick(|this| &this.notes),
+                },
+            }
+        }
+
+        fn response(&mut self) -> P2pResponsePacket {
+            match self.rng.gen_range(0..11) {
+                0 => P2pResponsePacket::error(self.pick(|this| &this.errors)),
+                1 => P2pResponsePacket::Pong {
+                    payload: self.bytes(64),
+                },
+                2 => P2pResponsePacket::Connect {
+                    client_color: self.color(),
+                    host_username: self.string(MAX_USERNAME_LEN),
+                    host_nonce: self.rng.gen(),
+                    peer_info: self.peer_info(),
+                },
+                3 => P2pResponsePacket::resync(
+                    std::array::from_fn(|_| self.pick(|this| &this.pieces)),
+                    self.rng.gen(),
+                    self.color(),
+                    self.optional_color(),
+                    PauseState {
+                        paused: self.rng.gen(),
+                        request: self.optional_color(),
+                    },
+                ),
+                4 => P2pResponsePacket::Acknowledge,
+                5 => P2pResponsePacket::ChallengeEcho {
+                    token: self.rng.gen(),
+                },
+                6 => P2pResponsePacket::ProbeResponse {
+                    hosting: self.rng.gen(),
+                    commitment: self.rng.gen::<bool>().then(|| self.rng.gen()),
+                },
+                7 => P2pResponsePacket::Rejected {
+                    kind: self.pick(|this| &this.errors),
+                    move_number: self.rng.gen(),
+                    side_to_move: self.color(),
+                },
+                8 => P2pResponsePacket::RetryLater {
+                    kind: self.pick(|this| &this.errors),
+                    retry_after_ms: self.rng.gen(),
+                },
+                9 => P2pResponsePacket::RematchAnswer {
+                    offerer_color: self.color(),
+                },
+                _ => P2pResponsePacket::RematchDecline,
+            }
+        }
+    }
+
+    /// Check that `value` is decoded from its own bytes, and that `write_packet()` appends the
+    /// same bytes as `to_packet()` returns, after whatever is in the buffer already.
+    fn assert_round_trip<T>(value: &T)
+    where
+        T: ToPacket + FromPacket + PartialEq + std::fmt::Debug,
+    {
+        let bytes = value.to_packet();
+        assert_eq!(value.encoded_len(), bytes.len());
+        let mut buf = vec![0xaa; 3];
+        value.write_packet(&mut buf);
+        assert_eq!(buf[..3], [0xaa; 3], "{:?} overwrote the buffer", value);
+        assert_eq!(buf[3..], bytes, "{:?} was written differently", value);
+
+        match T::from_packet(bytes) {
+            Ok(decoded) => assert_eq!(&decoded, value),
+            Err(e) => panic!("{:?} wasn't decoded: {}", value, e),
+        }
+    }
+
+    #[test]
+    fn random_packets_round_trip() {
+        // A move is decoded against the game options in `status`
+        let _state = lock_global_state();
+        let mut random = RandomPackets::new(689);
+        let (mut requests, mut responses, mut actions) =
+            (HashSet::new(), HashSet::new(), HashSet::new());
+
+        for _ in 0..RANDOM_ROUNDS {
+            let action = random.action();
+            assert_round_trip(&action);
+            let request = random.request();
+            assert_round_trip(&request);
+            let response = random.response();
+            assert_round_trip(&response);
+
+            requests.insert(request.to_u8());
+            responses.insert(response.to_u8());
+            actions.insert(action.to_u8());
+
+            // With the header, as they are sent
+            let (session_id, transaction_id) = random.rng.gen();
+            let request = P2pPacket::from(P2pRequest::new(session_id, transaction_id, request));
+            assert_round_trip(&request);
+            let response = P2pPacket::from(P2pResponse::new(session_id, transaction_id, response));
+            assert_round_trip(&response);
+        }
+        // Every kind of packet was made, with the codes in `wire`
+        assert_eq!(requests, HashSet::from(REQUEST_CODES));
+        assert_eq!(responses, HashSet::from(RESPONSE_CODES));
+        assert_eq!(actions, HashSet::from(ACTION_CODES));
+    }
+}
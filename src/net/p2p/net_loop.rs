@@ -1,160 +1,748 @@
 use std::{
+    net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
 
+use tokio::time::MissedTickBehavior;
+
 use crate::{
-    game::GameAction,
+    game::{GameAction, Move},
+    i18n::{tr, MessageKey},
     net::{
+        net_utils::ToPacket,
         p2p::{
-            communicate::{recieve_p2p_packet, send_p2p_packet},
-            queue::{self, get_incoming_gameaction_len, push_incoming_gameaction},
-            P2pError, P2pPacket, P2pRequest, P2pRequestPacket, P2pResponse, P2pResponsePacket,
-            PieceColor,
+            communicate::{recieve_p2p_packet, send_p2p_packet, ForeignPacket},
+            queue::{
+                self, get_incoming_gameaction_len, is_duplicate_gameaction, push_incoming_chat,
+                push_incoming_gameaction, refuse_incoming_gameaction, Completion,
+            },
+            normalize_username, wire, GameOverReason, NoteKind, P2pError, P2pPacket, P2pRequest,
+            P2pRequestPacket, P2pResponse, P2pResponsePacket, PieceColor,
         },
+        session_log,
         status::{
-            get_connection_status, get_join_code, get_my_username, get_other_addr, get_session_id,
-            remove_other_addr, remove_other_username, set_connection_ping, set_connection_status,
-            set_other_addr, set_other_username, set_reconnect_tries, set_session_id,
-            ConnectionStatus, CONNECT_SESSION_ID,
+            add_ping, add_round_trip, add_socket_rebind, add_throttled_request, anonymous_handle,
+            get_board, get_coin_nonce, get_color_preference, get_connection_status, get_draw_offer,
+            get_game_options, get_join_code, get_move_number, get_my_color, get_network_stats,
+            get_other_addr, get_other_username, get_pause, get_session_id, get_wire_username,
+            is_game_finished, new_session_id, ping_micros, ping_millis, remove_other_addr,
+            remove_other_peer_info, remove_other_username, set_coin_nonce, set_connection_status,
+            set_game_finished, set_game_result, set_match_resigned, set_move_number, set_my_color,
+            set_options_state, set_other_addr, set_other_left, set_other_peer_info,
+            set_other_username, set_reconnect_tries, set_rematch_offer, set_session_id,
+            track_draw_offer, track_pause, watch_other_addr, ConnectionStatus, GameResult,
+            OptionsState, CONNECT_SESSION_ID,
         },
     },
 };
 
-use super::queue::{new_transaction_id, push_outgoing_queue, wait_for_response};
+use super::{
+    anomaly::{report, Anomaly},
+    answered::AnsweredRequests,
+    backoff,
+    clock::JumpDetector,
+    coin_flip, desync, latency,
+    migration::AddressMigration,
+    peer_info::PeerInfo,
+    presence,
+    resync::client_resync_scheduler,
+    session::Session,
+    socket::SharedSocket,
+    taken_moves,
+    throttle::{Refusal, Throttle},
+    watchdog::{Heartbeat, Supervisor},
+};
 
 pub const REQUEST_TIMEOUT_MS: u128 = 500;
-const DISCONNECT_TIME_MS: u128 = 5_000;
-const RECONNECT_TRIES: u32 = 10;
+/// The longest sleep between two heartbeats, while backing off.
+const HEARTBEAT_SLEEP_MS: u64 = 1_000;
+/// The longest the outgoing loops wait for a packet to send, or for the other peer, before they
+/// look for requests to send again.
+const OUTGOING_WAIT_MS: u64 = 100;
+/// How often the host pings the client.
+const HOST_PING_INTERVAL_MS: u64 = 1_000;
 
 /// The async network loop for the host.
 /// The loop goes though the following points:
 ///     - Check for incoming messages and respond accordingly.
 ///     - If connected with the client:
 ///         - Send the next item in the Outgoing queue to the host.
+///         - Ping the client, to measure the ping.
 pub fn host_network_loop(socket: tokio::net::UdpSocket) {
+    queue::set_is_host(true);
     let socket = Arc::new(socket);
+    let mut supervisor = Supervisor::new();
     // Handle outgoing queue
-    tokio::spawn({
-        println!("Starting Host Handle outgoing queue");
-        let new_sock = socket.clone();
-        async move {
-            loop {
-                let client_addr = match get_other_addr().await {
-                    Some(addr) => addr,
-                    None => continue,
-                };
-                if let Some((data, id)) = queue::pop_outgoing_queue().await {
-                    println!("Sending Packet with ID {}... ({:?})", id, data);
-                    send_p2p_packet(&new_sock, data, client_addr).await.unwrap();
-                }
-            }
-        }
+    supervisor.spawn("Host Handle outgoing queue", {
+        let socket = socket.clone();
+        move |heartbeat| host_handle_outgoing(socket.clone(), heartbeat)
     });
     // Handle incoming responses
-    tokio::spawn({
-        println!("Starting Host handle incoming responses");
-        let new_sock = socket.clone();
-        async move {
-            let mut time_since_ping = Instant::now();
-            loop {
-                if time_since_ping.elapsed().as_millis() >= DISCONNECT_TIME_MS
-                    && get_other_addr().await.is_some()
-                {
+    supervisor.spawn("Host handle incoming responses", {
+        let socket = socket.clone();
+        move |heartbeat| host_handle_incoming(socket.clone(), heartbeat)
+    });
+    // Ping client
+    supervisor.spawn("Host Ping Client", host_ping_client);
+    supervisor.start();
+}
+
+async fn host_handle_outgoing(socket: Arc<tokio::net::UdpSocket>, heartbeat: Arc<Heartbeat>) {
+    let mut addrs = watch_other_addr();
+    loop {
+        heartbeat.bump();
+        // Without the other peer, a resend would only pile up in the queue
+        let has_addr = addrs.borrow().is_some();
+        if has_addr {
+            queue::resend_expired(Instant::now()).await;
+        }
+        queue::expire_transactions(Instant::now(), queue::TRANSACTION_TTL).await;
+        let wait = Duration::from_millis(OUTGOING_WAIT_MS);
+        if let Some((data, id, client_addr)) = queue::next_outgoing(&mut addrs, wait).await {
+            send_outgoing(&socket, data, id, client_addr).await;
+        }
+    }
+}
+
+/// Send a packet taken from the outgoing queue. A packet that can't be sent, e.g. because it's too
+/// large, or the socket stopped working after a sleep, is logged and dropped instead of taking the
+/// task down. A request is given up on, so whoever waits for its response finds out.
+async fn send_outgoing(
+    socket: &Arc<tokio::net::UdpSocket>,
+    data: P2pPacket,
+    id: u16,
+    to: SocketAddr,
+) {
+    println!("Sending Packet with ID {}... ({:?})", id, data);
+    let is_request = data.is_request();
+    match send_p2p_packet(socket, data, to).await {
+        Ok(_) if is_request => latency::mark_sent(id),
+        Ok(_) => {}
+        Err(e) => {
+            println!("Failed to send packet with ID {}: {}", id, e);
+            if is_request {
+                queue::fail_transaction(id).await;
+            }
+        }
+    }
+}
+
+/// Ping the client, so the host has a ping to show as well. The pongs are timed like the client's,
+/// by the outgoing and incoming tasks. Only the round trips and the lost pings are counted: The
+/// client is dropped on the timer of `host_handle_incoming()`, which runs on the requests of the
+/// client alone, so a lost ping here isn't held against it twice.
+async fn host_ping_client(heartbeat: Arc<Heartbeat>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(HOST_PING_INTERVAL_MS));
+    // The pings missed while asleep aren't sent all at once after waking up
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        heartbeat.bump();
+        if !get_connection_status().await.is_connected() || get_other_addr().await.is_none() {
+            continue;
+        }
+
+        let pong = Session::request(P2pRequestPacket::ping())
+            .await
+            .send_and_wait(Duration::from_millis(REQUEST_TIMEOUT_MS as u64))
+            .await;
+        add_ping(pong.is_err()).await;
+        if let Err(e) = pong {
+            println!("The client didn't answer a ping: {}", e);
+        }
+    }
+}
+
+async fn host_handle_incoming(socket: Arc<tokio::net::UdpSocket>, heartbeat: Arc<Heartbeat>) {
+    let mut time_since_ping = Instant::now();
+    let mut migration = AddressMigration::new();
+    let mut jumps = JumpDetector::new();
+    let mut throttle = Throttle::new();
+    let mut answered = AnsweredRequests::new();
+    loop {
+        heartbeat.bump();
+        // After the computer slept, the client hasn't had a chance to ping us, so it gets a new
+        // grace period instead of forfeiting right away
+        if let Some(gap) = jumps.check() {
+            println!(
+                "Woke up after {} s, waiting for the client again",
+                gap.as_secs()
+            );
+            time_since_ping = Instant::now();
+        }
+        // The client is only dropped once it has been gone for the grace period of the
+        // abandonment policy, so it has the same time to come back as it spends reconnecting
+        if get_game_options()
+            .abandonment_policy
+            .has_forfeited(time_since_ping.elapsed())
+            && get_other_addr().await.is_some()
+        {
+            println!(
+                "Client at {:?} disconnected, and forfeited the game!",
+                get_other_addr().await.unwrap()
+            );
+            drop_client().await;
+            other_peer_forfeited().await;
+        }
+        // Get incoming
+        let timeout_result = tokio::time::timeout(
+            Duration::from_millis(REQUEST_TIMEOUT_MS as u64),
+            recieve_p2p_packet(&socket),
+        )
+        .await;
+
+        let (incoming_packet, addr) = match timeout_result {
+            Ok(packet_result) => match packet_result {
+                Ok(packet) => packet,
+                Err(e) => {
+                    if let Some(foreign) = e.downcast_ref::<ForeignPacket>() {
+                        refuse_foreign(&socket, &mut throttle, foreign).await;
+                    }
+                    continue;
+                }
+            },
+            Err(_) => continue,
+        };
+
+        // Packets from another address than the client's may be the client, after its address
+        // changed. Only new clients can connect without proving it.
+        let is_stranger = get_other_addr().await.is_some_and(|other| other != addr);
+
+        if let P2pPacket::Request(req) = incoming_packet {
+            if let P2pRequestPacket::Disconnect = req.packet {
+                if is_from_other_peer(&req, addr).await {
+                    let response = Session::respond_to(&req, P2pResponsePacket::Acknowledge).await;
+                    // The response is sent directly, since the client is forgotten before the
+                    // queue would get to it
+                    if let Err(e) = send_p2p_packet(&socket, response, addr).await {
+                        println!("Failed to acknowledge the disconnect of {:?}: {}", addr, e);
+                    }
+                    println!("The client at {:?} left the game", addr);
+                    other_peer_left().await;
+                }
+                continue;
+            }
+            // Anyone can send these, so each address may only send a few at a time
+            if matches!(
+                req.packet,
+                P2pRequestPacket::Connect { .. } | P2pRequestPacket::Probe
+            ) {
+                if let Err(refusal) = throttle.check(addr.ip(), Instant::now()) {
+                    refuse_throttled(&socket, &req, addr, refusal).await;
+                    continue;
+                }
+            }
+            // Probes are answered directly, since they don't come from the client. A host with a
+            // client doesn't answer them, so it isn't found by others.
+            if let P2pRequestPacket::Probe = req.packet {
+                if get_other_addr().await.is_none() {
+                    let packet = host_handle_request(req.clone(), addr).await;
+                    let response = Session::respond_to(&req, packet).await;
+                    if let Err(e) = send_p2p_packet(&socket, response, addr).await {
+                        println!("Failed to answer probe from {:?}: {}", addr, e);
+                    }
+                }
+                continue;
+            }
+            let is_connect = matches!(req.packet, P2pRequestPacket::Connect { .. });
+            if is_stranger && !is_connect {
+                migration.challenge(&socket, &req, addr).await;
+                continue;
+            }
+            if !is_connect && req.session_id != get_session_id().await {
+                report(Anomaly::SessionMismatch, addr, &req.to_packet()).await;
+            }
+            // A request that comes again gets the response it got, without being handled twice
+            if get_other_addr().await == Some(addr) {
+                if let Some(response) = answered.get(&req) {
+                    println!(
+                        "Transaction {} from the client arrived again, sending the same response",
+                        req.transaction_id
+                    );
+                    push_response(response).await;
+                    time_since_ping = Instant::now();
+                    continue;
+                }
+            }
+            if let P2pRequestPacket::RematchOffer = req.packet {
+                take_rematch_offer(req, addr).await;
+                time_since_ping = Instant::now();
+                continue;
+            }
+            let packet = host_handle_request(req.clone(), addr).await;
+            // A refused connect is answered directly, since the queue sends to the client, which
+            // the sender didn't become
+            if is_connect && matches!(packet, P2pResponsePacket::Error { .. }) {
+                let response = Session::respond_to(&req, packet).await;
+                if let Err(e) = send_p2p_packet(&socket, response, addr).await {
+                    println!("Failed to refuse the join attempt from {:?}: {}", addr, e);
+                }
+                continue;
+            }
+            let is_mismatch = matches!(
+                packet,
+                P2pResponsePacket::Error {
+                    kind: P2pError::OptionsMismatch
+                }
+            );
+            let response = Session::respond_to(&req, packet).await;
+            if is_mismatch {
+                // The error is sent directly, since the client is dropped before the queue
+                // would get to it
+                if let Err(e) = send_p2p_packet(&socket, response, addr).await {
                     println!(
-                        "Client at {:?} disconnected!",
-                        get_other_addr().await.unwrap()
+                        "Failed to tell {:?} about the options mismatch: {}",
+                        addr, e
                     );
-                    remove_other_addr().await;
-                    remove_other_username().await;
-                    set_session_id(CONNECT_SESSION_ID).await;
                 }
-                // Get incoming
-                let timeout_result = tokio::time::timeout(
-                    Duration::from_millis(REQUEST_TIMEOUT_MS as u64),
-                    recieve_p2p_packet(&new_sock),
-                )
-                .await;
-
-                let (incoming_packet, addr) = match timeout_result {
-                    Ok(packet_result) => match packet_result {
-                        Ok(packet) => packet,
-                        Err(_) => continue,
-                    },
-                    Err(_) => continue,
+                drop_client().await;
+                continue;
+            }
+            // The responses to the last client can't be duplicates for a new one
+            if is_connect {
+                answered.clear();
+            }
+            answered.add(&req, response.clone());
+            push_response(response).await;
+            time_since_ping = Instant::now();
+        } else if let P2pPacket::Response(resp) = incoming_packet {
+            if is_stranger {
+                if migration.verify(&resp, addr).await {
+                    time_since_ping = Instant::now();
+                }
+                continue;
+            }
+            // A pong is timed, but doesn't reset `time_since_ping`, which only counts the
+            // client's own requests
+            let round_trip = latency::take_round_trip(resp.transaction_id);
+            if let (Some(round_trip), P2pResponsePacket::Pong { .. }) = (round_trip, &resp.packet) {
+                add_round_trip(round_trip).await;
+            }
+            if !queue::check_transaction_id(resp.transaction_id).await {
+                report(Anomaly::UnexpectedResponse, addr, &resp.to_packet()).await;
+                continue;
+            }
+            queue::set_response(resp).await;
+        }
+    }
+}
+
+/// Tell the sender of a request from another protocol version that we can't talk, with a
+/// `P2pError::ProtocolMismatch` every version can read. The answers count against the `throttle`
+/// like connects, but a refused one is just dropped, since the sender can't read `RetryLater`.
+async fn refuse_foreign(
+    socket: &Arc<tokio::net::UdpSocket>,
+    throttle: &mut Throttle,
+    foreign: &ForeignPacket,
+) {
+    let (header, addr) = (foreign.header, foreign.from);
+    // Answering a response could make two peers answer each other forever
+    if !header.is_request || throttle.check(addr.ip(), Instant::now()).is_err() {
+        return;
+    }
+    let line = format!(
+        "Refusing a request from {}, which speaks protocol version {} (we speak {})",
+        addr.ip(),
+        header.version,
+        wire::PROTOCOL_VERSION
+    );
+    println!("{}", line);
+    session_log::log(line);
+
+    let response = P2pResponse::new(
+        header.session_id,
+        header.transaction_id,
+        P2pResponsePacket::error(P2pError::ProtocolMismatch),
+    );
+    if let Err(e) = send_p2p_packet(socket, response, addr).await {
+        println!(
+            "Failed to tell {:?} about the protocol mismatch: {}",
+            addr, e
+        );
+    }
+}
+
+/// Answer a request the `throttle` refused, with when to try again. The answer is sent directly,
+/// since the request may not come from the client.
+async fn refuse_throttled(
+    socket: &Arc<tokio::net::UdpSocket>,
+    req: &P2pRequest,
+    addr: SocketAddr,
+    refusal: Refusal,
+) {
+    let throttled = add_throttled_request().await;
+    let retry_after_ms = u16::try_from(refusal.retry_after.as_millis()).unwrap_or(u16::MAX);
+    // Only the start of a run of refusals is logged, so a flood doesn't flood the log too
+    if refusal.is_first {
+        let request = match req.packet {
+            P2pRequestPacket::Probe => "probes",
+            _ => "connects",
+        };
+        let line = format!(
+            "Throttling {} from {}, which may try again in {} ms ({} refused so far)",
+            request,
+            addr.ip(),
+            retry_after_ms,
+            throttled
+        );
+        println!("{}", line);
+        session_log::log(line);
+    }
+
+    let packet = P2pResponsePacket::RetryLater {
+        kind: P2pError::Throttled,
+        retry_after_ms,
+    };
+    let response = Session::respond_to(req, packet).await;
+    if let Err(e) = send_p2p_packet(socket, response, addr).await {
+        println!("Failed to tell {:?} to try again later: {}", addr, e);
+    }
+}
+
+/// Forget the client, so a new one can join.
+async fn drop_client() {
+    remove_other_addr().await;
+    remove_other_username().await;
+    remove_other_peer_info().await;
+    set_session_id(CONNECT_SESSION_ID).await;
+}
+
+/// If `req` is from the other peer, in the current session. Anything else can't end the game.
+async fn is_from_other_peer(req: &P2pRequest, addr: SocketAddr) -> bool {
+    get_other_addr().await == Some(addr) && req.session_id == get_session_id().await
+}
+
+/// The other peer sent a `P2pRequestPacket::Disconnect`. Forget it, and let the game know it left.
+async fn other_peer_left() {
+    drop_client().await;
+    set_connection_status(ConnectionStatus::Disconnected).await;
+    set_other_left(true).await;
+}
+
+/// The other peer didn't come back within the grace period, so we won the game. It can't be told,
+/// so only the game is.
+async fn other_peer_forfeited() {
+    set_game_finished(true).await;
+    if let Some(color) = get_my_color().await {
+        set_game_result(GameResult {
+            winner: Some(color),
+            reason: GameOverReason::Timeout,
+        })
+        .await;
+    }
+}
+
+/// Keep how the game ended as told by the other peer, for the game to show.
+async fn take_game_over(winner: Option<PieceColor>, reason: GameOverReason) -> P2pResponsePacket {
+    println!("The game is over: {:?}, won by {:?}", reason, winner);
+    set_game_result(GameResult { winner, reason }).await;
+    set_game_finished(true).await;
+    P2pResponsePacket::Acknowledge
+}
+
+/// Keep a rematch offered by the other peer, for the game to answer with
+/// `interface::answer_rematch()` once the player has decided. It's refused right away if it isn't
+/// from the other peer in the current session, or if our game hasn't ended.
+async fn take_rematch_offer(req: P2pRequest, addr: SocketAddr) {
+    let refusal = if !is_from_other_peer(&req, addr).await {
+        P2pError::InvalidSessionId
+    } else if !is_game_finished().await {
+        println!("Refused a rematch, the game isn't over");
+        P2pError::GameInProgress
+    } else {
+        println!("The other player offers a rematch");
+        set_rematch_offer(req).await;
+        return;
+    };
+    let response = Session::respond_to(&req, P2pResponsePacket::error(refusal)).await;
+    push_response(response).await;
+}
+
+/// Push a response to the outgoing queue. If the queue is full, the response is dropped, and the
+/// client gets it when it sends the request again.
+async fn push_response(response: P2pResponse) {
+    let transaction_id = response.transaction_id;
+    let pushed =
+        queue::push_outgoing_queue(P2pPacket::Response(response), Completion::Keep, None).await;
+    if let Err(e) = pushed {
+        println!(
+            "Dropped the response to transaction {}: {}",
+            transaction_id, e
+        );
+    }
+}
+
+/// Handle a request sent to the host, and get the packet to respond with.
+async fn host_handle_request(req: P2pRequest, addr: SocketAddr) -> P2pResponsePacket {
+    match req.packet {
+        P2pRequestPacket::Ping { payload } => P2pResponsePacket::Pong { payload },
+        P2pRequestPacket::Connect {
+            join_code,
+            username,
+            nonce,
+            peer_info,
+            preference,
+        } => {
+            // A client playing anonymously sends no username, and gets a handle from the session
+            let anonymous = username.is_empty();
+            if get_other_addr().await.is_some() {
+                println!("Failed join attempt from {:?} - Game session full.", addr);
+                P2pResponsePacket::error(P2pError::FullGameSession)
+            } else if join_code != get_join_code().await.unwrap() {
+                println!("Failed join attempt from {:?} - Wrong join code.", addr);
+                P2pResponsePacket::error(P2pError::InvalidJoinCode)
+            } else if req.session_id != CONNECT_SESSION_ID {
+                println!("Failed join attempt from {:?} - Wrong session code.", addr);
+                P2pResponsePacket::error(P2pError::InvalidSessionId)
+            } else if !anonymous && normalize_username(&username).is_err() {
+                let e = normalize_username(&username).unwrap_err();
+                println!("Failed join attempt from {:?} - {}", addr, e);
+                P2pResponsePacket::error(P2pError::InvalidUsername)
+            } else if get_coin_nonce().await.is_none() {
+                println!("Failed join attempt from {:?} - No coin flip nonce.", addr);
+                P2pResponsePacket::error(P2pError::NoCommitment)
+            } else {
+                // The host committed to its nonce when it answered the clients probe. It's
+                // revealed now, so the next client gets a new one
+                let host_nonce = get_coin_nonce().await.unwrap();
+                set_coin_nonce(coin_flip::new_nonce()).await;
+                let client_color = coin_flip::assign_client_color(
+                    nonce,
+                    host_nonce,
+                    preference,
+                    get_color_preference().await,
+                );
+                set_my_color(client_color.get_opposite()).await;
+
+                set_session_id(new_session_id()).await;
+                // Checked above
+                let username = match anonymous {
+                    true => anonymous_handle(get_session_id().await, false),
+                    false => normalize_username(&username).unwrap().to_owned(),
                 };
+                println!("{} at {:?} Joined the game!", username, addr);
 
-                if let P2pPacket::Request(req) = incoming_packet {
-                    let packet = match req.packet {
-                        P2pRequestPacket::Ping => P2pResponsePacket::Pong,
-                        P2pRequestPacket::Connect {
-                            join_code,
-                            username,
-                        } => {
-                            if get_other_addr().await.is_some() {
-                                println!(
-                                    "Failed join attempt from {:?} - Game session full.",
-                                    addr
-                                );
-                                P2pResponsePacket::error(P2pError::FullGameSession)
-                            } else if join_code != get_join_code().await.unwrap() {
-                                println!("Failed join attempt from {:?} - Wrong join code.", addr);
-                                P2pResponsePacket::error(P2pError::InvalidJoinCode)
-                            } else if req.session_id != CONNECT_SESSION_ID {
-                                println!(
-                                    "Failed join attempt from {:?} - Wrong session code.",
-                                    addr
-                                );
-                                P2pResponsePacket::error(P2pError::InvalidSessionId)
-                            } else {
-                                println!("{} at {:?} Joined the game!", username, addr);
-
-                                set_session_id(rand::random::<u16>()).await;
-                                set_connection_status(ConnectionStatus::connected()).await;
-                                set_other_addr(addr).await;
-                                set_other_username(&username).await;
-                                let username = get_my_username().await.unwrap_or("HOST".to_owned());
-
-                                P2pResponsePacket::Connect {
-                                    client_color: PieceColor::White,
-                                    host_username: username,
-                                }
-                            }
-                        }
-                        P2pRequestPacket::Resync => P2pResponsePacket::resync(vec![]),
-                        P2pRequestPacket::GameAction { action } => {
-                            match action {
-                                GameAction::Surrender => {
-                                    // TODO: Verify Surrender
-                                    push_incoming_gameaction(action).await;
-                                    P2pResponsePacket::Acknowledge
-                                }
-                                GameAction::Stalemate => {
-                                    // TODO: Verify Stalemate
-                                    push_incoming_gameaction(action).await;
-                                    P2pResponsePacket::Acknowledge
-                                }
-                                GameAction::MovePiece(_) => {
-                                    // TODO: Verify move
-                                    push_incoming_gameaction(action).await;
-                                    P2pResponsePacket::Acknowledge
-                                }
-                            }
-                        }
-                    };
-                    let session_id = get_session_id().await;
-                    let response = P2pResponse::new(session_id, req.transaction_id, packet);
-                    queue::push_outgoing_queue(P2pPacket::Response(response), None).await;
-                    time_since_ping = Instant::now();
-                } else if let P2pPacket::Response(resp) = incoming_packet {
-                    if !queue::check_transaction_id(resp.transaction_id).await {
-                        continue;
+                set_move_number(0).await;
+                taken_moves::clear().await;
+                queue::clear_gameaction_sequences().await;
+                desync::clear().await;
+                set_game_finished(false).await;
+                set_options_state(OptionsState::Pending).await;
+                set_connection_status(ConnectionStatus::connected()).await;
+                set_other_addr(addr).await;
+                set_other_username(&username).await;
+                set_other_peer_info(peer_info).await;
+                set_other_left(false).await;
+                let username = get_wire_username(true)
+                    .await
+                    .unwrap_or(tr(MessageKey::DefaultHostUsername, &[]));
+
+                P2pResponsePacket::connect(client_color, username, host_nonce).unwrap_or_else(|e| {
+                    println!("Can't send the hosts username: {}", e);
+                    P2pResponsePacket::Connect {
+                        client_color,
+                        host_username: tr(MessageKey::DefaultHostUsername, &[]),
+                        host_nonce,
+                        peer_info: PeerInfo::local(),
                     }
-                    queue::set_response(resp.transaction_id, Some(P2pPacket::Response(resp))).await;
-                }
+                })
             }
         }
-    });
+        // A board of another size can't be sent, e.g. from a game with other rules
+        P2pRequestPacket::Resync => match get_board().await.map(<[_; wire::BOARD_LEN]>::try_from) {
+            Some(Ok(board)) => {
+                // The host decides the order of the moves, so its turn is the one the client takes
+                let move_number = get_move_number().await;
+                let side_to_move = PieceColor::side_to_move(move_number);
+                let draw_offer = get_draw_offer().await;
+                let pause = get_pause().await;
+                P2pResponsePacket::resync(board, move_number, side_to_move, draw_offer, pause)
+            }
+            _ => P2pResponsePacket::error(P2pError::InvalidBoard),
+        },
+        P2pRequestPacket::Probe => P2pResponsePacket::ProbeResponse {
+            hosting: true,
+            commitment: get_coin_nonce().await.map(coin_flip::commit),
+        },
+        P2pRequestPacket::Challenge { token: _ } => {
+            P2pResponsePacket::error(P2pError::WrongDirection)
+        }
+        P2pRequestPacket::OptionsAck { options_hash } => {
+            let ours = get_game_options().options_hash();
+            if options_hash == ours {
+                set_options_state(OptionsState::Agreed).await;
+                P2pResponsePacket::Acknowledge
+            } else {
+                println!(
+                    "Dropping the client at {:?} - Its options hash {:016x} isn't ours {:016x}",
+                    addr, options_hash, ours
+                );
+                set_options_state(OptionsState::Mismatch).await;
+                set_connection_status(ConnectionStatus::PendingConnection).await;
+                P2pResponsePacket::error(P2pError::OptionsMismatch)
+            }
+        }
+        // Answered by the loop, since the client is forgotten right after
+        P2pRequestPacket::Disconnect => P2pResponsePacket::Acknowledge,
+        // Only the client can chat, and a new client has to connect first
+        P2pRequestPacket::Chat { message: _ } if get_other_addr().await.is_none() => {
+            P2pResponsePacket::error(P2pError::InvalidSessionId)
+        }
+        P2pRequestPacket::Chat { message } => take_chat(message).await,
+        P2pRequestPacket::GameOver { .. } if get_other_addr().await.is_none() => {
+            P2pResponsePacket::error(P2pError::InvalidSessionId)
+        }
+        P2pRequestPacket::GameOver { winner, reason } => take_game_over(winner, reason).await,
+        // Answered by the loop, once the player has decided
+        P2pRequestPacket::RematchOffer => P2pResponsePacket::error(P2pError::GameInProgress),
+        P2pRequestPacket::BoardHash { .. } if get_other_addr().await.is_none() => {
+            P2pResponsePacket::error(P2pError::InvalidSessionId)
+        }
+        P2pRequestPacket::BoardHash { hash, move_count } => {
+            desync::take_board_hash(hash, move_count).await
+        }
+        P2pRequestPacket::StatusNote { .. } if get_other_addr().await.is_none() => {
+            P2pResponsePacket::error(P2pError::InvalidSessionId)
+        }
+        P2pRequestPacket::StatusNote { kind } => take_status_note(kind),
+        P2pRequestPacket::GameAction {
+            action,
+            move_number,
+            sequence,
+        } => {
+            if is_duplicate_gameaction(sequence).await {
+                println!(
+                    "Game action {} from the client arrived again, it was already taken",
+                    sequence
+                );
+                return P2pResponsePacket::Acknowledge;
+            }
+            let taken = host_take_action(action, move_number).await;
+            take_in_order(sequence, taken).await
+        }
+    }
+}
+
+/// Handle a game action from the client. Returns the packet to respond with, and the action if
+/// it was taken.
+async fn host_take_action(
+    action: GameAction,
+    move_number: u16,
+) -> (P2pResponsePacket, Option<GameAction>) {
+    let client_color = get_my_color()
+        .await
+        .map_or(PieceColor::Black, |color| color.get_opposite());
+    match action {
+        GameAction::Surrender => {
+            // TODO: Verify Surrender
+            (P2pResponsePacket::Acknowledge, Some(action))
+        }
+        GameAction::OfferDraw | GameAction::DrawResponse(_) => {
+            // The game window answers an offer, and ends the game on an accepted one
+            track_draw_offer(&action, false).await;
+            (P2pResponsePacket::Acknowledge, Some(action))
+        }
+        GameAction::ResignMatch => {
+            // The game window takes it like the end of a game, since it can come after one
+            println!("The other player resigned the match");
+            track_draw_offer(&action, false).await;
+            set_match_resigned(true).await;
+            (P2pResponsePacket::Acknowledge, None)
+        }
+        GameAction::PauseRequest | GameAction::ResumeRequest | GameAction::PauseResponse(_) => {
+            // The game window follows the pause from the status, since either player can ask for
+            // one at any time, not only while waiting for a move
+            track_pause(&action, false).await;
+            (P2pResponsePacket::Acknowledge, None)
+        }
+        GameAction::MovePiece(mov)
+            if !is_on_board(&mov) || !is_promotion_valid(&mov, client_color) =>
+        {
+            (P2pResponsePacket::error(P2pError::InvalidMove), None)
+        }
+        GameAction::MovePiece(ref mov) => {
+            if taken_moves::contains(move_number, mov).await {
+                println!(
+                    "Move {} from the client arrived again, it was already taken",
+                    move_number
+                );
+                return (P2pResponsePacket::Acknowledge, None);
+            }
+            // The host decides the order of the moves. A move from the client is only
+            // taken if it's the clients turn, and it was made on the hosts latest move.
+            let current = get_move_number().await;
+            let side_to_move = PieceColor::side_to_move(current);
+            let next = current.checked_add(1);
+            if move_number != current || side_to_move != client_color || next.is_none() {
+                println!(
+                    "Rejected move {} from the client, the host is at move {}",
+                    move_number, current
+                );
+                let rejection = P2pResponsePacket::Rejected {
+                    kind: P2pError::NotYourTurn,
+                    move_number: current,
+                    side_to_move,
+                };
+                return (rejection, None);
+            }
+            // Checked above
+            set_move_number(next.unwrap()).await;
+            taken_moves::record(move_number, mov.clone()).await;
+
+            // TODO: Verify move
+            (P2pResponsePacket::Acknowledge, Some(action))
+        }
+    }
+}
+
+/// Queue a game action from the other peer, to be taken after the actions it sent before it.
+/// Returns the packet to respond with.
+///
+/// ## Params
+/// * `sequence` - The sequence number of the action.
+/// * `taken` - The packet to respond with, and the action if it was taken.
+async fn take_in_order(
+    sequence: u16,
+    (packet, action): (P2pResponsePacket, Option<GameAction>),
+) -> P2pResponsePacket {
+    match action {
+        Some(action) => {
+            push_incoming_gameaction(sequence, action).await;
+            session_log::log(format!(
+                "queued action {}, {} waiting",
+                sequence,
+                get_incoming_gameaction_len().await
+            ));
+        }
+        // The actions after it don't wait for it
+        None => refuse_incoming_gameaction(sequence).await,
+    }
+    packet
+}
+
+/// Check the squares of a move from the other peer, before it's taken. The decoder has checked
+/// them already, but the board must never get a move that doesn't fit.
+fn is_on_board(mov: &Move) -> bool {
+    match mov.check_squares(get_game_options().squares()) {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Rejected a move that doesn't fit on the board: {}", e);
+            false
+        }
+    }
+}
+
+/// Check that a move from the other peer, playing `sender`, only claims a promotion on the last
+/// row. A move is sent as seen from the sender's side, so the sender's pieces start at the bottom.
+fn is_promotion_valid(mov: &Move, sender: PieceColor) -> bool {
+    match mov.check_promotion(&get_game_options(), sender, sender) {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Rejected a move with a forged promotion: {}", e);
+            false
+        }
+    }
 }
 
 /// The async network loop for the client.
@@ -167,161 +755,1258 @@ pub fn host_network_loop(socket: tokio::net::UdpSocket) {
 /// When entering, it requires the open  UdpSocket, as well as how many pings pr. second the client
 /// should send.
 pub fn client_network_loop(socket: tokio::net::UdpSocket, pings: usize) {
-    let socket = Arc::new(socket);
+    queue::set_is_host(false);
+    // The client can rebind its socket after waking from sleep, see `socket`
+    let socket = Arc::new(SharedSocket::new(socket));
+    let mut supervisor = Supervisor::new();
     // Ping host
-    tokio::spawn({
-        println!("Starting Client Ping Host");
-        let mut interval = tokio::time::interval(Duration::from_millis((1000 / pings) as u64));
-        async move {
-            loop {
-                interval.tick().await;
-
-                let connection_status = get_connection_status().await;
-                if !connection_status.is_connected() && !connection_status.is_reconnecting() {
-                    continue;
-                }
-                if get_other_addr().await.is_none() {
-                    continue;
-                }
+    supervisor.spawn("Client Ping Host", {
+        let socket = socket.clone();
+        move |heartbeat| client_ping_host(pings, socket.clone(), heartbeat)
+    });
+    // Handle outgoing queue
+    supervisor.spawn("Client Handle outgoing queue", {
+        let socket = socket.clone();
+        move |heartbeat| client_handle_outgoing(socket.clone(), heartbeat)
+    });
+    // Handle incoming responses
+    supervisor.spawn("Client Handle incoming responses", {
+        let socket = socket.clone();
+        move |heartbeat| client_handle_incoming(socket.clone(), heartbeat)
+    });
+    // Resync the board after reconnecting
+    supervisor.spawn("Client Resync scheduler", client_resync_scheduler);
+    supervisor.start();
+}
 
-                let time = Instant::now();
+/// Sleep for `delay`, while keeping the heartbeat going, since a backoff can be as long as the
+/// watchdog waits for a stalled task.
+async fn sleep_with_heartbeat(delay: Duration, heartbeat: &Heartbeat) {
+    let end = Instant::now() + delay;
+    while let Some(left) = end.checked_duration_since(Instant::now()) {
+        heartbeat.bump();
+        tokio::time::sleep(left.min(Duration::from_millis(HEARTBEAT_SLEEP_MS))).await;
+    }
+}
 
-                let session_id = get_session_id().await;
+async fn client_ping_host(pings: usize, socket: Arc<SharedSocket>, heartbeat: Arc<Heartbeat>) {
+    let mut interval = tokio::time::interval(Duration::from_millis((1000 / pings) as u64));
+    // The pings missed while asleep aren't sent all at once after waking up
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // When the connection was lost, while reconnecting
+    let mut outage = backoff::Outage::default();
+    // The socket is only rebound once every time the connection is lost
+    let mut has_rebound = false;
+    let mut jumps = JumpDetector::new();
+    loop {
+        // While reconnecting, the pings back off instead of keeping the normal rate
+        match get_connection_status().await {
+            ConnectionStatus::Reconnecting { tries } => {
+                sleep_with_heartbeat(backoff::backoff_delay(tries), &heartbeat).await;
+            }
+            _ => {
+                interval.tick().await;
+            }
+        }
+        heartbeat.bump();
+        // The time asleep doesn't count against the host, so reconnecting starts over
+        if let Some(gap) = jumps.check() {
+            println!(
+                "Woke up after {} s, reconnecting from the start",
+                gap.as_secs()
+            );
+            outage.restart(Instant::now());
+            has_rebound = false;
+        }
 
-                let ping_id = new_transaction_id().await;
-                let ping = P2pRequest::new(session_id, ping_id, P2pRequestPacket::Ping);
+        let connection_status = get_connection_status().await;
+        if !connection_status.is_connected() && !connection_status.is_reconnecting() {
+            continue;
+        }
+        if get_other_addr().await.is_none() {
+            continue;
+        }
 
-                push_outgoing_queue(P2pPacket::Request(ping), None).await;
+        let ping = Session::request(P2pRequestPacket::ping()).await;
 
-                match tokio::time::timeout(
-                    Duration::from_millis(REQUEST_TIMEOUT_MS as u64),
-                    wait_for_response(ping_id),
-                )
-                .await
-                {
-                    Ok(data) => {
-                        if let P2pPacket::Response(pong) = data {
-                            if pong.packet != P2pResponsePacket::Pong {
-                                println!("Got wrong packet, expected pong, got: ");
-                                dbg!(&pong);
-                            }
-                            let elapsed_ms = time.elapsed().as_millis();
-                            println!("ping: {} ms", elapsed_ms);
-                            if get_connection_status().await.is_reconnecting() {
-                                set_connection_status(ConnectionStatus::connected()).await;
-                            }
-                            set_connection_ping(elapsed_ms).await;
-                        }
+        match ping
+            .send_and_wait(Duration::from_millis(REQUEST_TIMEOUT_MS as u64))
+            .await
+        {
+            Ok(pong) => {
+                add_ping(false).await;
+                if !matches!(pong.packet, P2pResponsePacket::Pong { payload: _ }) {
+                    println!("Got wrong packet, expected pong, got: {:#?}", pong);
+                }
+                if get_connection_status().await.is_reconnecting() {
+                    set_connection_status(ConnectionStatus::connected()).await;
+                }
+                outage.end();
+                has_rebound = false;
+                // The round trip is timed by the incoming task, from when the ping was sent
+                if let Some(stats) = get_network_stats().await {
+                    let millis = |round_trip| ping_millis(ping_micros(round_trip));
+                    println!(
+                        "ping: {} ms (jitter {} ms, 95th percentile {} ms)",
+                        millis(stats.average),
+                        millis(stats.jitter),
+                        millis(stats.p95)
+                    );
+                }
+            }
+            Err(e) => {
+                add_ping(true).await;
+                if let ConnectionStatus::Reconnecting { tries } = get_connection_status().await {
+                    let gone_for = outage.gone_for(Instant::now());
+                    let policy = get_game_options().abandonment_policy;
+                    match policy.grace_period() {
+                        Some(grace_period) => println!(
+                            "Trying to reconnect... ({} / {} ms)",
+                            gone_for.as_millis(),
+                            grace_period.as_millis()
+                        ),
+                        None => println!(
+                            "Trying to reconnect... ({} ms, waiting forever)",
+                            gone_for.as_millis()
+                        ),
                     }
-                    Err(e) => {
-                        if let ConnectionStatus::Reconnecting { tries } =
-                            get_connection_status().await
-                        {
-                            println!("Trying to reconnect... ({} / {})", tries, RECONNECT_TRIES);
-                            if tries >= RECONNECT_TRIES as u8 {
-                                set_connection_status(ConnectionStatus::Disconnected).await;
-                                remove_other_addr().await;
-                                remove_other_username().await;
-                                println!("Disconnected from host");
-                            } else {
-                                set_reconnect_tries(tries + 1).await;
+                    if policy.has_forfeited(gone_for) {
+                        set_connection_status(ConnectionStatus::Disconnected).await;
+                        remove_other_addr().await;
+                        remove_other_username().await;
+                        remove_other_peer_info().await;
+                        outage.end();
+                        println!("Disconnected from host, the game is forfeited");
+                        other_peer_forfeited().await;
+                    } else {
+                        set_reconnect_tries(tries.saturating_add(1)).await;
+                    }
+
+                    // The pings are sent, but nothing comes back. After a sleep the socket may
+                    // have stopped receiving, so a new one is tried
+                    if !has_rebound && socket.is_silent() {
+                        has_rebound = true;
+                        match socket.rebind().await {
+                            Ok(()) => {
+                                add_socket_rebind().await;
                             }
-                        } else {
-                            println!("Ping request time out: {}", e.to_string());
-                            set_connection_status(ConnectionStatus::reconnecting()).await;
+                            Err(e) => println!("Failed to rebind the socket: {}", e),
                         }
                     }
+                } else {
+                    println!("Ping request time out: {}", e);
+                    outage.start(Instant::now());
+                    set_connection_status(ConnectionStatus::reconnecting()).await;
                 }
             }
         }
-    });
-    // Handle outgoing queue
-    tokio::spawn({
-        println!("Starting Client Handle outgoing queue");
-        let new_sock = socket.clone();
-        async move {
-            loop {
-                let host_addr = match get_other_addr().await.clone() {
-                    Some(addr) => addr,
-                    None => {
-                        tokio::time::sleep(Duration::from_millis(50)).await;
-                        continue;
+    }
+}
+
+async fn client_handle_outgoing(socket: Arc<SharedSocket>, heartbeat: Arc<Heartbeat>) {
+    let mut addrs = watch_other_addr();
+    loop {
+        heartbeat.bump();
+        // Without the other peer, a resend would only pile up in the queue
+        let has_addr = addrs.borrow().is_some();
+        if has_addr {
+            queue::resend_expired(Instant::now()).await;
+        }
+        queue::expire_transactions(Instant::now(), queue::TRANSACTION_TTL).await;
+        let wait = Duration::from_millis(OUTGOING_WAIT_MS);
+        if let Some((data, id, host_addr)) = queue::next_outgoing(&mut addrs, wait).await {
+            send_outgoing(&socket.get(), data, id, host_addr).await;
+        }
+    }
+}
+
+async fn client_handle_incoming(socket: Arc<SharedSocket>, heartbeat: Arc<Heartbeat>) {
+    loop {
+        heartbeat.bump();
+        let timeout_result = tokio::time::timeout(
+            Duration::from_millis(REQUEST_TIMEOUT_MS as u64),
+            recieve_p2p_packet(&socket.get()),
+        )
+        .await;
+
+        let (incoming_packet, addr) = match timeout_result {
+            Ok(Ok(packet)) => packet,
+            _ => continue,
+        };
+        socket.mark_received();
+        // The host is forgotten once it has left, and anything after that is from a stranger
+        if get_other_addr().await != Some(addr) {
+            report(Anomaly::UnknownPeer, addr, &incoming_packet.to_packet()).await;
+            continue;
+        }
+        if let P2pPacket::Request(req) = incoming_packet {
+            if let P2pRequestPacket::Disconnect = req.packet {
+                if is_from_other_peer(&req, addr).await {
+                    let response = Session::respond_to(&req, P2pResponsePacket::Acknowledge).await;
+                    if let Err(e) = send_p2p_packet(&socket.get(), response, addr).await {
+                        println!("Failed to acknowledge the disconnect of the host: {}", e);
                     }
-                };
-                if let Some((data, id)) = queue::pop_outgoing_queue().await {
-                    println!("Sending Packet with ID {}... ({:?})", id, data);
-                    send_p2p_packet(&new_sock, data, host_addr).await.unwrap();
-                } else {
-                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    println!("The host left the game");
+                    other_peer_left().await;
                 }
+                continue;
+            }
+            if req.session_id != get_session_id().await {
+                report(Anomaly::SessionMismatch, addr, &req.to_packet()).await;
+            }
+            if let P2pRequestPacket::RematchOffer = req.packet {
+                take_rematch_offer(req, addr).await;
+                continue;
             }
+            let packet = client_handle_request(req.clone()).await;
+            let response = Session::respond_to(&req, packet).await;
+            // The host sends the request again, if the response never leaves
+            if let Err(e) = send_p2p_packet(&socket.get(), response, addr).await {
+                println!("Failed to respond to the host: {}", e);
+            }
+        } else if let P2pPacket::Response(resp) = incoming_packet {
+            let round_trip = latency::take_round_trip(resp.transaction_id);
+            if let (Some(round_trip), P2pResponsePacket::Pong { .. }) = (round_trip, &resp.packet) {
+                add_round_trip(round_trip).await;
+            }
+            // A response to no request in the table can't be handed to anyone, so it's reported
+            // and dropped, like on the host
+            if !queue::check_transaction_id(resp.transaction_id).await {
+                report(Anomaly::UnexpectedResponse, addr, &resp.to_packet()).await;
+                continue;
+            }
+            queue::set_response(resp).await;
         }
-    });
-    // Handle incoming responses
-    tokio::spawn({
-        println!("Starting Client Handle incoming responses");
-        let new_sock = socket.clone();
-        async move {
-            loop {
-                let timeout_result = tokio::time::timeout(
-                    Duration::from_millis(REQUEST_TIMEOUT_MS as u64),
-                    recieve_p2p_packet(&new_sock),
-                )
-                .await;
-
-                let (incoming_packet, addr) = match timeout_result {
-                    Ok(Ok(packet)) => packet,
-                    _ => continue,
+    }
+}
+
+/// Handle a request sent to the client, and get the packet to respond with.
+async fn client_handle_request(req: P2pRequest) -> P2pResponsePacket {
+    match req.packet {
+        P2pRequestPacket::Ping { payload } => P2pResponsePacket::Pong { payload },
+        P2pRequestPacket::Challenge { token } => P2pResponsePacket::ChallengeEcho { token },
+        P2pRequestPacket::Probe => P2pResponsePacket::ProbeResponse {
+            hosting: false,
+            commitment: None,
+        },
+        P2pRequestPacket::Chat { message } => take_chat(message).await,
+        P2pRequestPacket::GameOver { winner, reason } => take_game_over(winner, reason).await,
+        P2pRequestPacket::BoardHash { hash, move_count } => {
+            desync::take_board_hash(hash, move_count).await
+        }
+        P2pRequestPacket::StatusNote { kind } => take_status_note(kind),
+        P2pRequestPacket::GameAction {
+            action,
+            move_number,
+            sequence,
+        } => {
+            if is_duplicate_gameaction(sequence).await {
+                println!(
+                    "Game action {} from the host arrived again, it was already taken",
+                    sequence
+                );
+                return P2pResponsePacket::Acknowledge;
+            }
+            let taken = client_take_action(action, move_number).await;
+            take_in_order(sequence, taken).await
+        }
+        _ => P2pResponsePacket::error(P2pError::WrongDirection),
+    }
+}
+
+/// Queue a chat message from the other peer, for the game to show with its username.
+async fn take_chat(message: String) -> P2pResponsePacket {
+    let sender = get_other_username()
+        .await
+        .unwrap_or(tr(MessageKey::NoUsername, &[]));
+    push_incoming_chat(sender, message).await;
+    presence::clear_other_typing();
+    P2pResponsePacket::Acknowledge
+}
+
+/// Show a note about the other player for a while. See `presence`.
+fn take_status_note(kind: NoteKind) -> P2pResponsePacket {
+    presence::take_note(kind, Instant::now());
+    P2pResponsePacket::Acknowledge
+}
+
+/// Handle a game action from the host. Returns the packet to respond with, and the action if it
+/// was taken.
+async fn client_take_action(
+    action: GameAction,
+    move_number: u16,
+) -> (P2pResponsePacket, Option<GameAction>) {
+    let host_color = get_my_color()
+        .await
+        .map_or(PieceColor::White, |color| color.get_opposite());
+    match action {
+        GameAction::Surrender => {
+            // TODO: Verify Surrender
+            (P2pResponsePacket::Acknowledge, Some(action))
+        }
+        GameAction::OfferDraw | GameAction::DrawResponse(_) => {
+            // The game window answers an offer, and ends the game on an accepted one
+            track_draw_offer(&action, false).await;
+            (P2pResponsePacket::Acknowledge, Some(action))
+        }
+        GameAction::ResignMatch => {
+            // The game window takes it like the end of a game, since it can come after one
+            println!("The other player resigned the match");
+            track_draw_offer(&action, false).await;
+            set_match_resigned(true).await;
+            (P2pResponsePacket::Acknowledge, None)
+        }
+        GameAction::PauseRequest | GameAction::ResumeRequest | GameAction::PauseResponse(_) => {
+            // The game window follows the pause from the status, since either player can ask for
+            // one at any time, not only while waiting for a move
+            track_pause(&action, false).await;
+            (P2pResponsePacket::Acknowledge, None)
+        }
+        GameAction::MovePiece(mov)
+            if !is_on_board(&mov) || !is_promotion_valid(&mov, host_color) =>
+        {
+            (P2pResponsePacket::error(P2pError::InvalidMove), None)
+        }
+        GameAction::MovePiece(ref mov) => {
+            if taken_moves::contains(move_number, mov).await {
+                println!(
+                    "Move {} from the host arrived again, it was already taken",
+                    move_number
+                );
+                return (P2pResponsePacket::Acknowledge, None);
+            }
+            // There is no move after the last move number
+            let Some(next) = move_number.checked_add(1) else {
+                return (P2pResponsePacket::error(P2pError::InvalidMove), None);
+            };
+            // The hosts moves are always taken, since the host decides the order
+            set_move_number(next).await;
+            taken_moves::record(move_number, mov.clone()).await;
+
+            // TODO: Verify move
+            (P2pResponsePacket::Acknowledge, Some(action))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use futures::executor;
+
+    use super::*;
+    use crate::{
+        game::{options::GameOptions, PieceData},
+        net::{
+            interface,
+            net_utils::FromPacket,
+            p2p::{
+                communicate::{strip_checksum, MAX_PACKET_SIZE},
+                fragment::MAX_MESSAGE_SIZE,
+                lock_global_state,
+                pause::PauseState,
+                peer_info::Platform,
+                queue::TimedOut,
+                runtime, ForeignVersion,
+            },
+            status::{
+                get_options_state, get_other_peer_info, remove_coin_nonce, set_anonymous,
+                set_board, set_color_preference, set_join_code, set_my_username, take_game_result,
+            },
+        },
+    };
+
+    #[test]
+    fn unsendable_request_is_given_up() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        let answer: Arc<Mutex<Option<Result<P2pResponse, TimedOut>>>> = Arc::default();
+        executor::block_on(async {
+            let socket = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            // Too large even for fragments, so sending it fails
+            let packet = P2pRequestPacket::Ping {
+                payload: vec![0; MAX_MESSAGE_SIZE],
+            };
+            let request = P2pRequest::new(0x1a2b, 0x0600, packet);
+            let completion = Completion::Callback(Box::new({
+                let answer = answer.clone();
+                move |resp| *answer.lock().unwrap() = Some(resp)
+            }));
+            queue::push_outgoing_queue(request.into(), completion, None)
+                .await
+                .unwrap();
+            let (data, id) = queue::pop_outgoing_queue().await.unwrap();
+
+            send_outgoing(&socket, data, id, socket.local_addr().unwrap()).await;
+            assert!(!queue::check_transaction_id(0x0600).await);
+        });
+        assert!(matches!(
+            *answer.lock().unwrap(),
+            Some(Err(TimedOut {
+                transaction_id: 0x0600,
+                ..
+            }))
+        ));
+    }
+
+    /// A socket that loses the first datagrams sent through it, like a bad connection. The rest
+    /// are sent over a real socket on 127.0.0.1.
+    struct LossySocket {
+        socket: Arc<tokio::net::UdpSocket>,
+        /// The amount of datagrams lost before the first one gets through.
+        lose_first: usize,
+        sends: usize,
+    }
+
+    impl LossySocket {
+        async fn bind(lose_first: usize) -> Self {
+            let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            Self {
+                socket: Arc::new(socket),
+                lose_first,
+                sends: 0,
+            }
+        }
+
+        /// Send every packet in the outgoing queue, like the outgoing loop. Returns the
+        /// transaction IDs of the packets sent, lost or not.
+        async fn send_queued(&mut self, to: SocketAddr) -> Vec<u16> {
+            let mut sent = vec![];
+            while let Some((data, id)) = queue::pop_outgoing_queue().await {
+                self.sends += 1;
+                if self.sends > self.lose_first {
+                    send_outgoing(&self.socket, data, id, to).await;
+                }
+                sent.push(id);
+            }
+            sent
+        }
+    }
+
+    /// Acknowledge the next request that arrives at `peer`, and take the acknowledgement on
+    /// `socket`, like the network loops do.
+    async fn acknowledge_next(peer: &Arc<tokio::net::UdpSocket>, socket: &LossySocket) {
+        let recieve =
+            |socket| tokio::time::timeout(Duration::from_secs(1), recieve_p2p_packet(socket));
+        let (P2pPacket::Request(req), addr) = recieve(peer).await.unwrap().unwrap() else {
+            panic!("expected a request");
+        };
+        let ack = Session::respond_to(&req, P2pResponsePacket::Acknowledge).await;
+        send_p2p_packet(peer, ack, addr).await.unwrap();
+        let (P2pPacket::Response(resp), _) = recieve(&socket.socket).await.unwrap().unwrap() else {
+            panic!("expected a response");
+        };
+        queue::set_response(resp).await;
+    }
+
+    type Answer = Arc<Mutex<Option<Result<P2pResponse, TimedOut>>>>;
+
+    /// Push a game action, whose response is kept in the returned answer.
+    async fn push_game_action(transaction_id: u16) -> Answer {
+        let answer = Answer::default();
+        let packet = P2pRequestPacket::game_action(GameAction::OfferDraw, 3, transaction_id);
+        let completion = Completion::Callback(Box::new({
+            let answer = answer.clone();
+            move |resp| *answer.lock().unwrap() = Some(resp)
+        }));
+        let request = P2pRequest::new(0x1a2b, transaction_id, packet);
+        queue::push_outgoing_queue(request.into(), completion, None)
+            .await
+            .unwrap();
+        answer
+    }
+
+    #[test]
+    fn lost_game_action_is_resent_until_answered() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        executor::block_on(async {
+            let mut socket = LossySocket::bind(1).await;
+            let peer = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let to = peer.local_addr().unwrap();
+            let answer = push_game_action(0x0700).await;
+
+            // The first send is lost, so nothing comes back
+            assert_eq!(socket.send_queued(to).await, [0x0700]);
+            assert!(answer.lock().unwrap().is_none());
+            queue::resend_expired(Instant::now() + queue::RESEND_TIMEOUT).await;
+            assert_eq!(socket.send_queued(to).await, [0x0700]);
+            acknowledge_next(&peer, &socket).await;
+            assert!(matches!(*answer.lock().unwrap(), Some(Ok(_))));
+
+            // Answered, so it isn't sent again
+            queue::resend_expired(Instant::now() + queue::RESEND_TIMEOUT).await;
+            assert!(socket.send_queued(to).await.is_empty());
+        });
+    }
+
+    #[test]
+    fn lost_ping_is_not_resent() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        executor::block_on(async {
+            let mut socket = LossySocket::bind(1).await;
+            let to = "127.0.0.1:9".parse().unwrap();
+            let ping = P2pRequest::new(0x1a2b, 0x0701, P2pRequestPacket::ping());
+            queue::push_outgoing_queue(ping.into(), Completion::Keep, None)
+                .await
+                .unwrap();
+
+            assert_eq!(socket.send_queued(to).await, [0x0701]);
+            queue::resend_expired(Instant::now() + queue::RESEND_TIMEOUT).await;
+            assert!(socket.send_queued(to).await.is_empty());
+            queue::forget_transaction(0x0701).await;
+        });
+    }
+
+    #[test]
+    fn game_action_gives_up_when_every_send_is_lost() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        executor::block_on(async {
+            let mut socket = LossySocket::bind(usize::MAX).await;
+            let to = "127.0.0.1:9".parse().unwrap();
+            let answer = push_game_action(0x0702).await;
+
+            assert_eq!(socket.send_queued(to).await, [0x0702]);
+            for _ in 0..queue::MAX_RESENDS {
+                queue::resend_expired(Instant::now() + queue::RESEND_TIMEOUT).await;
+                assert_eq!(socket.send_queued(to).await, [0x0702]);
+            }
+            queue::resend_expired(Instant::now() + queue::RESEND_TIMEOUT).await;
+            assert!(socket.send_queued(to).await.is_empty());
+            assert_eq!(
+                *answer.lock().unwrap(),
+                Some(Err(TimedOut {
+                    transaction_id: 0x0702,
+                    sends: queue::MAX_RESENDS + 1,
+                }))
+            );
+        });
+    }
+
+    #[test]
+    fn move_after_the_last_move_number_is_refused() {
+        let action = GameAction::move_piece(9, 13, None, false);
+        let (packet, taken) = executor::block_on(client_take_action(action, u16::MAX));
+        assert_eq!(packet, P2pResponsePacket::error(P2pError::InvalidMove));
+        assert!(taken.is_none());
+    }
+
+    #[test]
+    fn promotion_is_checked_from_the_senders_side() {
+        let _state = lock_global_state();
+        for sender in [PieceColor::White, PieceColor::Black] {
+            let onto_last_row = Move {
+                index: 5,
+                end: 1,
+                captured: None,
+                promoted: true,
+            };
+            assert!(is_promotion_valid(&onto_last_row, sender), "{:?}", sender);
+            let forged = Move {
+                index: 9,
+                end: 5,
+                captured: None,
+                promoted: true,
+            };
+            assert!(!is_promotion_valid(&forged, sender), "{:?}", sender);
+            // Nor a promotion on the row the sender started on
+            let backwards = Move {
+                index: 28,
+                end: 32,
+                captured: None,
+                promoted: true,
+            };
+            assert!(!is_promotion_valid(&backwards, sender), "{:?}", sender);
+        }
+    }
+
+    /// Send `action` from the client to the host over the wire, as move 1.
+    async fn send_to_host(action: GameAction) -> P2pResponsePacket {
+        let req = P2pRequest::new(0x1a2b, 0x0001, P2pRequestPacket::game_action(action, 1, 0));
+        let Ok(P2pPacket::Request(req)) = P2pPacket::from_packet(P2pPacket::from(req).to_packet())
+        else {
+            panic!("the move wasn't read");
+        };
+        let mut answered = AnsweredRequests::new();
+        host_answer(&mut answered, &req).await.packet
+    }
+
+    #[test]
+    fn forged_promotion_over_the_wire_is_refused() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            for host in [PieceColor::White, PieceColor::Black] {
+                start_client_turn().await;
+                set_my_color(host).await;
+                // The client moves first when the host plays black
+                let client_turn = if host == PieceColor::White { 1 } else { 0 };
+                set_move_number(client_turn).await;
+
+                let forged = GameAction::move_piece(9, 5, None, true);
+                let packet = send_to_host(forged.clone()).await;
+                assert_eq!(packet, P2pResponsePacket::error(P2pError::InvalidMove));
+                assert_eq!(get_incoming_gameaction_len().await, 0, "{:?}", host);
+                let (packet, taken) = client_take_action(forged, 1).await;
+                assert_eq!(packet, P2pResponsePacket::error(P2pError::InvalidMove));
+                assert!(taken.is_none());
+
+                // A real promotion is taken by either side
+                let promotion = GameAction::move_piece(5, 1, None, true);
+                taken_moves::clear().await;
+                let (_, taken) = client_take_action(promotion.clone(), 1).await;
+                assert_eq!(taken, Some(promotion.clone()));
+                taken_moves::clear().await;
+                set_move_number(client_turn).await;
+                let (packet, taken) = host_take_action(promotion.clone(), client_turn).await;
+                assert_eq!(packet, P2pResponsePacket::Acknowledge, "{:?}", host);
+                assert_eq!(taken, Some(promotion));
+            }
+            queue::clear_gameaction_sequences().await;
+        });
+    }
+
+    #[test]
+    fn request_of_another_version_is_refused_with_a_protocol_mismatch() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        executor::block_on(async {
+            let socket = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let from = peer.local_addr().unwrap();
+            let mut throttle = Throttle::new();
+            let foreign = |is_request| ForeignPacket {
+                header: ForeignVersion {
+                    version: wire::PROTOCOL_VERSION + 1,
+                    is_request,
+                    session_id: 0x1a2b,
+                    transaction_id: 0x0007,
+                },
+                from,
+            };
+
+            // A response is never answered, or two peers could answer each other forever
+            refuse_foreign(&socket, &mut throttle, &foreign(false)).await;
+            refuse_foreign(&socket, &mut throttle, &foreign(true)).await;
+            let mut buf = [0; MAX_PACKET_SIZE];
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), peer.recv_from(&mut buf))
+                .await
+                .expect("The request wasn't refused")
+                .unwrap();
+            let packet = strip_checksum(&buf[..len]).unwrap();
+            let refusal = P2pResponse::new(
+                0x1a2b,
+                0x0007,
+                P2pResponsePacket::error(P2pError::ProtocolMismatch),
+            );
+            assert_eq!(packet, P2pPacket::from(refusal).to_packet());
+            assert!(peer.try_recv_from(&mut buf).is_err(), "Answered twice");
+        });
+    }
+
+    /// Answer `req` from the client like the host loop does. A request that comes again gets the
+    /// response it got before, without being handled twice.
+    async fn host_answer(answered: &mut AnsweredRequests, req: &P2pRequest) -> P2pResponse {
+        if let Some(response) = answered.get(req) {
+            return response;
+        }
+        let packet = host_handle_request(req.clone(), "127.0.0.1:1".parse().unwrap()).await;
+        let response = Session::respond_to(req, packet).await;
+        answered.add(req, response.clone());
+        response
+    }
+
+    /// Start a game where the host plays white, and it's the clients turn at move 1.
+    async fn start_client_turn() {
+        taken_moves::clear().await;
+        queue::clear_gameaction_sequences().await;
+        set_my_color(PieceColor::White).await;
+        set_move_number(1).await;
+    }
+
+    #[test]
+    fn clients_move_made_at_once_with_the_hosts_is_rejected() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            // The host plays White. Both thought it was their turn at move 2, and the host's move
+            // was made first
+            start_client_turn().await;
+            set_move_number(3).await;
+            let theirs = GameAction::move_piece(22, 18, None, false);
+            let (packet, taken) = host_take_action(theirs.clone(), 2).await;
+            let rejection = |move_number, side_to_move| P2pResponsePacket::Rejected {
+                kind: P2pError::NotYourTurn,
+                move_number,
+                side_to_move,
+            };
+            assert_eq!(packet, rejection(3, PieceColor::Black));
+            assert!(taken.is_none());
+            assert_eq!(get_move_number().await, 3);
+
+            // Nor is a move at the host's move number taken, when it's the host's turn
+            set_move_number(2).await;
+            let (packet, taken) = host_take_action(theirs.clone(), 2).await;
+            assert_eq!(packet, rejection(2, PieceColor::White));
+            assert!(taken.is_none());
+
+            // The client takes its turn from the rejection, and moves again at the host's number
+            set_move_number(3).await;
+            let (packet, taken) = host_take_action(theirs.clone(), 3).await;
+            assert_eq!(packet, P2pResponsePacket::Acknowledge);
+            assert_eq!(taken, Some(theirs));
+            assert_eq!(get_move_number().await, 4);
+            taken_moves::clear().await;
+        });
+    }
+
+    #[test]
+    fn duplicate_move_request_is_queued_once() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            start_client_turn().await;
+            let action = GameAction::move_piece(22, 18, None, false);
+            let packet = P2pRequestPacket::game_action(action, 1, 0);
+            let req = P2pRequest::new(0x1a2b, 0x0001, packet);
+            let mut answered = AnsweredRequests::new();
+
+            let first = host_answer(&mut answered, &req).await;
+            let second = host_answer(&mut answered, &req).await;
+            assert_eq!(first.packet, P2pResponsePacket::Acknowledge);
+            assert_eq!(second, first);
+            assert_eq!(get_incoming_gameaction_len().await, 1);
+
+            // The same transaction in another session is another request
+            let other = P2pRequest::new(0x1a2c, 0x0001, req.packet.clone());
+            assert!(answered.get(&other).is_none());
+            queue::clear_gameaction_sequences().await;
+        });
+    }
+
+    #[test]
+    fn move_sent_again_after_its_response_is_forgotten_is_taken_once() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            start_client_turn().await;
+            let action = GameAction::move_piece(22, 18, None, false);
+            let req = P2pRequest::new(
+                0x1a2b,
+                0x0001,
+                P2pRequestPacket::game_action(action.clone(), 1, 0),
+            );
+            let mut answered = AnsweredRequests::new();
+            host_answer(&mut answered, &req).await;
+            assert_eq!(get_move_number().await, 2);
+
+            // The response was evicted from the cache when the request is sent again
+            answered.clear();
+            let again = host_answer(&mut answered, &req).await;
+            assert_eq!(again.packet, P2pResponsePacket::Acknowledge);
+            assert_eq!(get_incoming_gameaction_len().await, 1);
+            assert_eq!(get_move_number().await, 2);
+
+            // Even with a new sequence number, the move isn't played again
+            let (packet, taken) = host_take_action(action.clone(), 1).await;
+            assert_eq!(packet, P2pResponsePacket::Acknowledge);
+            assert!(taken.is_none());
+            assert_eq!(get_move_number().await, 2);
+
+            // Nor on the client, whose move number isn't set back
+            taken_moves::clear().await;
+            set_move_number(4).await;
+            let (_, taken) = client_take_action(action.clone(), 4).await;
+            assert_eq!(taken, Some(action.clone()));
+            assert_eq!(get_move_number().await, 5);
+            let (packet, taken) = client_take_action(action, 4).await;
+            assert_eq!(packet, P2pResponsePacket::Acknowledge);
+            assert!(taken.is_none());
+            assert_eq!(get_move_number().await, 5);
+            queue::clear_gameaction_sequences().await;
+        });
+    }
+
+    #[test]
+    fn chat_is_queued_with_its_sender() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            while queue::pop_incoming_chat().await.is_some() {}
+            set_other_username("Søren").await;
+            let packet = take_chat("Good game! ♟".to_owned()).await;
+            assert_eq!(packet, P2pResponsePacket::Acknowledge);
+            assert_eq!(
+                queue::pop_incoming_chat().await,
+                Some(("Søren".to_owned(), "Good game! ♟".to_owned()))
+            );
+
+            // A sender without a name gets the placeholder
+            remove_other_username().await;
+            take_chat("Hi".to_owned()).await;
+            let no_username = tr(MessageKey::NoUsername, &[]);
+            assert_eq!(
+                queue::pop_incoming_chat().await,
+                Some((no_username, "Hi".to_owned()))
+            );
+            assert_eq!(queue::pop_incoming_chat().await, None);
+        });
+    }
+
+    #[test]
+    fn resynced_board_keeps_its_kings() {
+        let _state = lock_global_state();
+        let mut board: Vec<PieceData> = (0..wire::BOARD_LEN)
+            .map(|i| PieceData {
+                color: if i < 12 {
+                    PieceColor::Black
+                } else {
+                    PieceColor::White
+                },
+                is_active: !(12..20).contains(&i),
+                is_king: false,
+            })
+            .collect();
+        for (square, color) in [(14, PieceColor::White), (17, PieceColor::Black)] {
+            board[square] = PieceData {
+                color,
+                is_active: true,
+                is_king: true,
+            };
+        }
+
+        let packet = executor::block_on(async {
+            set_board(board.clone()).await;
+            set_move_number(5).await;
+            resync_from_host().await
+        });
+        let P2pResponsePacket::Resync {
+            board: resynced,
+            move_number,
+            side_to_move,
+            draw_offer,
+            pause,
+        } = packet
+        else {
+            panic!("expected the hosts board, got {:?}", packet);
+        };
+        assert_eq!(resynced.to_vec(), board);
+        assert_eq!(move_number, 5);
+        assert_eq!(side_to_move, PieceColor::side_to_move(5));
+        assert_eq!(draw_offer, None);
+        assert_eq!(pause, PauseState::default());
+    }
+
+    #[test]
+    fn resynced_turn_goes_on_past_a_byte() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            set_board(vec![PieceData::default(); wire::BOARD_LEN]).await;
+            let mut turns = vec![];
+            for current in [254, 255, 256, 257] {
+                set_move_number(current).await;
+                let P2pResponsePacket::Resync {
+                    move_number,
+                    side_to_move,
+                    ..
+                } = resync_from_host().await
+                else {
+                    panic!("expected the hosts board");
                 };
-                if addr != get_other_addr().await.unwrap() {
-                    continue;
+                assert_eq!(move_number, current);
+                turns.push(side_to_move);
+            }
+            let (white, black) = (PieceColor::White, PieceColor::Black);
+            assert_eq!(turns, [white, black, white, black]);
+            set_move_number(0).await;
+        });
+    }
+
+    /// Ask the host to resync, as a client that just reconnected, and read its answer off the
+    /// wire.
+    async fn resync_from_host() -> P2pResponsePacket {
+        let req = P2pRequest::new(0x1a2b, 0x0001, P2pRequestPacket::Resync);
+        let packet = host_handle_request(req, "127.0.0.1:1".parse().unwrap()).await;
+        let response = P2pPacket::from(P2pResponse::new(0x1a2b, 0x0001, packet));
+        let Ok(P2pPacket::Response(response)) = P2pPacket::from_packet(response.to_packet()) else {
+            panic!("the resync response wasn't read");
+        };
+        response.packet
+    }
+
+    /// The open draw offer in the hosts answer to a resync.
+    async fn resynced_draw_offer() -> Option<PieceColor> {
+        match resync_from_host().await {
+            P2pResponsePacket::Resync { draw_offer, .. } => draw_offer,
+            packet => panic!("expected the hosts board, got {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn open_draw_offer_survives_a_reconnect() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            set_board(vec![PieceData::default(); wire::BOARD_LEN]).await;
+            set_my_color(PieceColor::White).await;
+            set_game_finished(false).await;
+
+            // The client offers a draw, and reconnects before the host answers
+            let (_, taken) = host_take_action(GameAction::OfferDraw, 3).await;
+            assert_eq!(taken, Some(GameAction::OfferDraw));
+            assert_eq!(resynced_draw_offer().await, Some(PieceColor::Black));
+            // Still open after another reconnect, until it's answered
+            assert_eq!(resynced_draw_offer().await, Some(PieceColor::Black));
+            track_draw_offer(&GameAction::DrawResponse(false), true).await;
+            assert_eq!(resynced_draw_offer().await, None);
+
+            // The hosts own offer, which ends with the game
+            track_draw_offer(&GameAction::OfferDraw, true).await;
+            assert_eq!(resynced_draw_offer().await, Some(PieceColor::White));
+            set_game_finished(true).await;
+            assert_eq!(resynced_draw_offer().await, None);
+
+            set_game_finished(false).await;
+        });
+    }
+
+    /// The pause in the hosts answer to a resync.
+    async fn resynced_pause() -> PauseState {
+        match resync_from_host().await {
+            P2pResponsePacket::Resync { pause, .. } => pause,
+            packet => panic!("expected the hosts board, got {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn pause_requests_are_answered() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            set_my_color(PieceColor::White).await;
+            set_game_finished(false).await;
+
+            // The client asks for a pause, which the host declines
+            let (packet, taken) = host_take_action(GameAction::PauseRequest, 3).await;
+            assert_eq!(packet, P2pResponsePacket::Acknowledge);
+            assert_eq!(taken, None);
+            assert_eq!(
+                get_pause().await,
+                PauseState {
+                    paused: false,
+                    request: Some(PieceColor::Black),
                 }
-                if let P2pPacket::Request(req) = incoming_packet {
-                    let packet = match req.packet {
-                        P2pRequestPacket::Ping => P2pResponsePacket::Pong,
-                        P2pRequestPacket::GameAction { action } => {
-                            match action {
-                                GameAction::Surrender => {
-                                    // TODO: Verify Surrender
-                                    push_incoming_gameaction(action).await;
-                                    println!(
-                                        "Incoming action len: {}",
-                                        get_incoming_gameaction_len().await
-                                    );
-                                    P2pResponsePacket::Acknowledge
-                                }
-                                GameAction::Stalemate => {
-                                    // TODO: Verify stalemate
-                                    push_incoming_gameaction(action).await;
-                                    println!(
-                                        "Incoming action len: {}",
-                                        get_incoming_gameaction_len().await
-                                    );
-                                    P2pResponsePacket::Acknowledge
-                                }
-                                GameAction::MovePiece(_) => {
-                                    // TODO: Verify move
-                                    push_incoming_gameaction(action).await;
-                                    println!(
-                                        "Incoming action len: {}",
-                                        get_incoming_gameaction_len().await
-                                    );
-                                    P2pResponsePacket::Acknowledge
-                                }
-                            }
-                        }
-                        _ => P2pResponsePacket::error(P2pError::WrongDirection),
+            );
+            track_pause(&GameAction::PauseResponse(false), true).await;
+            assert_eq!(get_pause().await, PauseState::default());
+
+            // It asks again, and the host accepts
+            host_take_action(GameAction::PauseRequest, 3).await;
+            track_pause(&GameAction::PauseResponse(true), true).await;
+            assert!(get_pause().await.paused);
+
+            // The host asks to go on, which the client accepts
+            track_pause(&GameAction::ResumeRequest, true).await;
+            assert_eq!(get_pause().await.request, Some(PieceColor::White));
+            let (packet, _) = host_take_action(GameAction::PauseResponse(true), 3).await;
+            assert_eq!(packet, P2pResponsePacket::Acknowledge);
+            assert_eq!(get_pause().await, PauseState::default());
+        });
+    }
+
+    #[test]
+    fn reconnect_lands_in_the_pause() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            set_board(vec![PieceData::default(); wire::BOARD_LEN]).await;
+            set_my_color(PieceColor::White).await;
+            set_game_finished(false).await;
+
+            // The game is paused, and the client reconnects
+            host_take_action(GameAction::PauseRequest, 3).await;
+            track_pause(&GameAction::PauseResponse(true), true).await;
+            let paused = PauseState {
+                paused: true,
+                request: None,
+            };
+            assert_eq!(resynced_pause().await, paused);
+
+            // Its request to go on is still open after another reconnect
+            host_take_action(GameAction::ResumeRequest, 3).await;
+            assert_eq!(
+                resynced_pause().await,
+                PauseState {
+                    paused: true,
+                    request: Some(PieceColor::Black),
+                }
+            );
+
+            // A client that doesn't come back during the pause still forfeits
+            other_peer_forfeited().await;
+            assert_eq!(
+                take_game_result().await,
+                Some(GameResult {
+                    winner: Some(PieceColor::White),
+                    reason: GameOverReason::Timeout,
+                })
+            );
+            assert_eq!(resynced_pause().await, PauseState::default());
+
+            set_game_finished(false).await;
+        });
+    }
+
+    /// Offer a rematch from `addr`, and return the refusal that was queued for it, if any.
+    async fn offer_rematch_from(addr: SocketAddr) -> Option<P2pResponsePacket> {
+        let req = P2pRequest::new(0x1a2b, 0x0041, P2pRequestPacket::RematchOffer);
+        take_rematch_offer(req, addr).await;
+        match queue::pop_outgoing_queue().await {
+            Some((P2pPacket::Response(response), _)) => Some(response.packet),
+            Some((packet, _)) => panic!("expected a response, got {:?}", packet),
+            None => None,
+        }
+    }
+
+    #[test]
+    fn rematch_is_only_taken_after_the_game() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            let other: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            set_other_addr(other).await;
+            set_session_id(0x1a2b).await;
+            set_game_finished(false).await;
+
+            assert_eq!(
+                offer_rematch_from(other).await,
+                Some(P2pResponsePacket::error(P2pError::GameInProgress))
+            );
+            assert!(crate::net::status::take_rematch_offer().await.is_none());
+
+            // Nor from anyone but the other peer, even after the game
+            set_game_finished(true).await;
+            assert_eq!(
+                offer_rematch_from("127.0.0.1:2".parse().unwrap()).await,
+                Some(P2pResponsePacket::error(P2pError::InvalidSessionId))
+            );
+            assert!(crate::net::status::take_rematch_offer().await.is_none());
+
+            // It's kept for the game to answer
+            assert_eq!(offer_rematch_from(other).await, None);
+            let offer = crate::net::status::take_rematch_offer()
+                .await
+                .expect("No offer was kept");
+            assert_eq!(offer.packet, P2pRequestPacket::RematchOffer);
+
+            drop_client().await;
+            set_game_finished(false).await;
+        });
+    }
+
+    /// Ask the host to join its game as a new client, and forget the client again.
+    async fn join_host(nonce: u64, preference: Option<PieceColor>) -> P2pResponsePacket {
+        join_host_as("Bob", nonce, preference).await
+    }
+
+    /// Ask to join the host as a new client with `username`, and forget the client again.
+    async fn join_host_as(
+        username: &str,
+        nonce: u64,
+        preference: Option<PieceColor>,
+    ) -> P2pResponsePacket {
+        let join_code = "7f0000011f90";
+        set_join_code(join_code).await;
+        remove_other_addr().await;
+        let packet = P2pRequestPacket::connect(join_code, username, nonce, preference).unwrap();
+        let req = P2pRequest::new(CONNECT_SESSION_ID, 1, packet);
+        let packet = host_handle_request(req, "127.0.0.1:1".parse().unwrap()).await;
+        remove_other_addr().await;
+        packet
+    }
+
+    #[test]
+    fn each_join_reveals_a_new_nonce() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            set_color_preference(None).await;
+            let mut revealed = vec![];
+            for _ in 0..5 {
+                let commitment = coin_flip::commit(get_coin_nonce().await.unwrap());
+                let nonce = coin_flip::new_nonce();
+                let P2pResponsePacket::Connect {
+                    client_color,
+                    host_nonce,
+                    ..
+                } = join_host(nonce, None).await
+                else {
+                    panic!("The join was refused");
+                };
+                assert!(coin_flip::is_fair(
+                    &commitment,
+                    nonce,
+                    host_nonce,
+                    None,
+                    client_color
+                ));
+                assert_eq!(get_my_color().await, Some(client_color.get_opposite()));
+                assert!(!revealed.contains(&host_nonce));
+                revealed.push(host_nonce);
+            }
+        });
+    }
+
+    #[test]
+    fn join_without_a_nonce_is_refused() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            remove_coin_nonce().await;
+            let packet = join_host(0, None).await;
+            assert_eq!(packet, P2pResponsePacket::error(P2pError::NoCommitment));
+            set_coin_nonce(coin_flip::new_nonce()).await;
+        });
+    }
+
+    #[test]
+    fn different_preferences_pick_the_colors() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            for (client, host) in [
+                (PieceColor::White, PieceColor::Black),
+                (PieceColor::Black, PieceColor::White),
+            ] {
+                set_color_preference(Some(host)).await;
+                // Whatever the nonces are
+                for nonce in 0..4 {
+                    set_coin_nonce(coin_flip::new_nonce()).await;
+                    let packet = join_host(nonce, Some(client)).await;
+                    let P2pResponsePacket::Connect { client_color, .. } = packet else {
+                        panic!("The join was refused");
                     };
-                    let response = P2pResponse::new(req.session_id, req.transaction_id, packet);
-                    send_p2p_packet(&new_sock, response, addr).await.unwrap();
-                    println!("Sent package");
-                } else if let P2pPacket::Response(resp) = incoming_packet {
-                    // if !queue::check_transaction_id(resp.transaction_id).await {
-                    //     continue;
-                    // }
-                    queue::set_response(resp.transaction_id, Some(P2pPacket::Response(resp))).await;
+                    assert_eq!(client_color, client);
+                    assert_eq!(get_my_color().await, Some(host));
                 }
             }
-        }
-    });
+            // The same wish is left to the coin flip
+            set_color_preference(Some(PieceColor::White)).await;
+            let host_nonce = get_coin_nonce().await.unwrap();
+            let packet = join_host(host_nonce, Some(PieceColor::White)).await;
+            let P2pResponsePacket::Connect { client_color, .. } = packet else {
+                panic!("The join was refused");
+            };
+            assert_eq!(
+                client_color,
+                coin_flip::client_color(host_nonce, host_nonce)
+            );
+            set_color_preference(None).await;
+        });
+    }
+
+    #[test]
+    fn anonymous_peers_are_called_by_a_handle_of_the_session() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            set_coin_nonce(coin_flip::new_nonce()).await;
+            set_color_preference(None).await;
+            set_my_username("Alice").await;
+            set_anonymous(true).await;
+            let P2pResponsePacket::Connect { host_username, .. } = join_host_as("", 0, None).await
+            else {
+                panic!("The anonymous join was refused");
+            };
+            let session_id = get_session_id().await;
+            assert_eq!(host_username, anonymous_handle(session_id, true));
+            let client_handle = anonymous_handle(session_id, false);
+            assert_eq!(get_other_username().await, Some(client_handle));
+
+            // Peers with a name are called by it
+            set_anonymous(false).await;
+            let P2pResponsePacket::Connect { host_username, .. } = join_host(0, None).await else {
+                panic!("The join was refused");
+            };
+            assert_eq!(host_username, "Alice");
+            assert_eq!(get_other_username().await.as_deref(), Some("Bob"));
+
+            // A name of only whitespace is no name, not a wish to be anonymous
+            let packet = join_host_as("  ", 0, None).await;
+            assert_eq!(packet, P2pResponsePacket::error(P2pError::InvalidUsername));
+        });
+    }
+
+    #[test]
+    fn options_of_another_hash_are_refused() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            let options_ack = |options: GameOptions| {
+                let packet = P2pRequestPacket::OptionsAck {
+                    options_hash: options.options_hash(),
+                };
+                let req = P2pRequest::new(0x1a2b, 1, packet);
+                host_handle_request(req, "127.0.0.1:1".parse().unwrap())
+            };
+
+            // A client playing on a larger board
+            let theirs = GameOptions {
+                board_size: 10,
+                ..get_game_options()
+            };
+            let packet = options_ack(theirs).await;
+            assert_eq!(packet, P2pResponsePacket::error(P2pError::OptionsMismatch));
+            assert_eq!(get_options_state().await, OptionsState::Mismatch);
+            assert!(matches!(
+                get_connection_status().await,
+                ConnectionStatus::PendingConnection
+            ));
+
+            let packet = options_ack(get_game_options()).await;
+            assert_eq!(packet, P2pResponsePacket::Acknowledge);
+            assert_eq!(get_options_state().await, OptionsState::Agreed);
+        });
+    }
+
+    /// Join the host as a client running `peer_info`, over the wire. Returns the hosts info, and
+    /// forgets the client again.
+    async fn join_host_running(peer_info: &PeerInfo) -> PeerInfo {
+        let join_code = "7f0000011f90";
+        set_join_code(join_code).await;
+        set_coin_nonce(coin_flip::new_nonce()).await;
+        remove_other_addr().await;
+        let packet = P2pRequestPacket::Connect {
+            join_code: join_code.to_owned(),
+            username: "Bob".to_owned(),
+            nonce: 7,
+            peer_info: peer_info.clone(),
+            preference: None,
+        };
+        let req = P2pPacket::from(P2pRequest::new(CONNECT_SESSION_ID, 1, packet));
+        let Ok(P2pPacket::Request(req)) = P2pPacket::from_packet(req.to_packet()) else {
+            panic!("the connect request wasn't read");
+        };
+        let packet = host_handle_request(req, "127.0.0.1:1".parse().unwrap()).await;
+        remove_other_addr().await;
+        let P2pResponsePacket::Connect { peer_info, .. } = packet else {
+            panic!("Expected a connect response, got {:?}", packet);
+        };
+        peer_info
+    }
+
+    #[test]
+    fn connect_exchanges_the_peer_info() {
+        let _state = lock_global_state();
+        let newer = PeerInfo {
+            version: "99.0.0".to_owned(),
+            platform: Platform::MacOs,
+        };
+        let hosts = executor::block_on(join_host_running(&newer));
+        assert_eq!(hosts, PeerInfo::local());
+        assert_eq!(executor::block_on(get_other_peer_info()), Some(newer));
+        let warning = interface::version_warning().expect("No warning of another major version");
+        assert!(warning.contains("99.0.0"), "{}", warning);
+
+        // Only another major version is warned about
+        let patched = PeerInfo {
+            version: format!("{}.99", env!("CARGO_PKG_VERSION")),
+            platform: Platform::Unknown,
+        };
+        executor::block_on(join_host_running(&patched));
+        assert_eq!(executor::block_on(get_other_peer_info()), Some(patched));
+        assert_eq!(interface::version_warning(), None);
+
+        executor::block_on(remove_other_peer_info());
+        assert_eq!(interface::version_warning(), None);
+    }
 }
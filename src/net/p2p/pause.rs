@@ -0,0 +1,157 @@
+//! Pausing a game. Either player can ask for a pause with a `GameAction::PauseRequest` at any time
+//! during the game, and the other player answers it with a `GameAction::PauseResponse`. A paused
+//! game goes on once a `GameAction::ResumeRequest` is accepted the same way. While the game is
+//! paused, only chat works.
+//!
+//! A pause doesn't stop the connection: the pings go on, and a player who loses the connection
+//! during a pause still forfeits by the `AbandonmentPolicy`, so a pause can't be used to leave a
+//! game without losing it. The host sends its pause in the `Resync` response, so a client that
+//! reconnects lands in it.
+
+use crate::game::{GameAction, PieceColor};
+
+/// If the game is paused, and the request about it that waits for an answer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PauseState {
+    /// If the game is paused.
+    pub paused: bool,
+    /// The color of the player whose request waits for an answer, if any. It asks for a pause
+    /// while the game isn't paused, and for the game to go on while it is.
+    pub request: Option<PieceColor>,
+}
+
+impl PauseState {
+    /// The state after `by` sent `action`. A request while the other player's request of the same
+    /// kind is open accepts it, since both players want the same. Other requests while one is open
+    /// change nothing, and neither does an answer to a request that isn't open, or to our own.
+    pub fn after(self, action: &GameAction, by: PieceColor) -> Self {
+        let wanted = match action {
+            GameAction::PauseRequest => true,
+            GameAction::ResumeRequest => false,
+            GameAction::PauseResponse(accepted) => {
+                return match self.request {
+                    Some(requester) if requester != by => Self {
+                        paused: self.paused != *accepted,
+                        request: None,
+                    },
+                    _ => self,
+                };
+            }
+            _ => return self,
+        };
+        if self.paused == wanted {
+            return self;
+        }
+        match self.request {
+            None => Self {
+                request: Some(by),
+                ..self
+            },
+            Some(requester) if requester != by => Self {
+                paused: wanted,
+                request: None,
+            },
+            Some(_) => self,
+        }
+    }
+
+    /// If `color` has to answer a request of the other player.
+    pub fn is_asked(&self, color: PieceColor) -> bool {
+        self.request == Some(color.get_opposite())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAYING: PauseState = PauseState {
+        paused: false,
+        request: None,
+    };
+    const PAUSED: PauseState = PauseState {
+        paused: true,
+        request: None,
+    };
+
+    #[test]
+    fn requests_wait_for_an_answer() {
+        let requested = PLAYING.after(&GameAction::PauseRequest, PieceColor::White);
+        assert_eq!(
+            requested,
+            PauseState {
+                paused: false,
+                request: Some(PieceColor::White),
+            }
+        );
+        assert!(requested.is_asked(PieceColor::Black));
+        assert!(!requested.is_asked(PieceColor::White));
+
+        // Only the other player answers it
+        assert_eq!(
+            requested.after(&GameAction::PauseResponse(true), PieceColor::White),
+            requested
+        );
+        assert_eq!(
+            requested.after(&GameAction::PauseResponse(false), PieceColor::Black),
+            PLAYING
+        );
+        assert_eq!(
+            requested.after(&GameAction::PauseResponse(true), PieceColor::Black),
+            PAUSED
+        );
+
+        // Going on is asked for the same way
+        let resume = PAUSED.after(&GameAction::ResumeRequest, PieceColor::Black);
+        assert_eq!(resume.request, Some(PieceColor::Black));
+        assert_eq!(
+            resume.after(&GameAction::PauseResponse(true), PieceColor::White),
+            PLAYING
+        );
+        assert_eq!(
+            resume.after(&GameAction::PauseResponse(false), PieceColor::White),
+            PAUSED
+        );
+    }
+
+    #[test]
+    fn requests_that_dont_fit_change_nothing() {
+        // A paused game can't be paused again, and a game that goes on can't be resumed
+        assert_eq!(
+            PAUSED.after(&GameAction::PauseRequest, PieceColor::White),
+            PAUSED
+        );
+        assert_eq!(
+            PLAYING.after(&GameAction::ResumeRequest, PieceColor::White),
+            PLAYING
+        );
+        assert_eq!(
+            PLAYING.after(&GameAction::PauseResponse(true), PieceColor::White),
+            PLAYING
+        );
+        assert_eq!(
+            PLAYING.after(&GameAction::OfferDraw, PieceColor::White),
+            PLAYING
+        );
+
+        let requested = PLAYING.after(&GameAction::PauseRequest, PieceColor::White);
+        assert_eq!(
+            requested.after(&GameAction::PauseRequest, PieceColor::White),
+            requested
+        );
+    }
+
+    #[test]
+    fn crossing_requests_agree() {
+        let requested = PLAYING.after(&GameAction::PauseRequest, PieceColor::White);
+        assert_eq!(
+            requested.after(&GameAction::PauseRequest, PieceColor::Black),
+            PAUSED
+        );
+        let resume = PAUSED.after(&GameAction::ResumeRequest, PieceColor::White);
+        assert_eq!(
+            resume.after(&GameAction::ResumeRequest, PieceColor::Black),
+            PLAYING
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! The version of the game and the platform of each peer, exchanged in `P2pRequestPacket::Connect`
+//! and its response. They are only kept in memory, the session log and the debug bundle, so a bug
+//! report shows what both sides ran. Nothing is sent anywhere but to the other peer.
+//!
+//! On the wire, the info is the platform byte from `wire::platform`, followed by the version as a
+//! length-prefixed string: One byte with the length, and then that many bytes of UTF-8.
+
+use std::fmt::Display;
+
+use crate::net::net_utils::{truncate, PacketError};
+
+use super::wire;
+
+/// The longest version a length-prefixed string can hold.
+pub const MAX_VERSION_LEN: usize = u8::MAX as usize;
+
+/// The operating system a peer runs on. Only the family is sent, never the release.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Platform {
+    Unknown,
+    Windows,
+    Linux,
+    MacOs,
+}
+
+impl Platform {
+    /// The platform this game was built for.
+    pub const fn local() -> Self {
+        if cfg!(target_os = "windows") {
+            Self::Windows
+        } else if cfg!(target_os = "linux") {
+            Self::Linux
+        } else if cfg!(target_os = "macos") {
+            Self::MacOs
+        } else {
+            Self::Unknown
+        }
+    }
+
+    const fn to_u8(self) -> u8 {
+        match self {
+            Self::Unknown => wire::platform::UNKNOWN,
+            Self::Windows => wire::platform::WINDOWS,
+            Self::Linux => wire::platform::LINUX,
+            Self::MacOs => wire::platform::MACOS,
+        }
+    }
+
+    /// A platform code from a newer version is read as `Unknown`, since it's only informational.
+    const fn from_u8(byte: u8) -> Self {
+        match byte {
+            wire::platform::WINDOWS => Self::Windows,
+            wire::platform::LINUX => Self::Linux,
+            wire::platform::MACOS => Self::MacOs,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// What a peer runs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// The version of the game, e.g. `0.1.0`.
+    pub version: String,
+    pub platform: Platform,
+}
+
+impl PeerInfo {
+    /// The info of this peer.
+    pub fn local() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            platform: Platform::local(),
+        }
+    }
+
+    /// The amount of bytes the info takes on the wire.
+    pub fn encoded_len(&self) -> usize {
+        2 + truncate(&self.version, MAX_VERSION_LEN).len()
+    }
+
+    /// Append the info to `buf`. A version longer than `MAX_VERSION_LEN` bytes is cut off.
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        let version = truncate(&self.version, MAX_VERSION_LEN);
+        buf.push(self.platform.to_u8());
+        buf.push(version.len() as u8);
+        buf.extend_from_slice(version.as_bytes());
+    }
+
+    /// Read the info from the start of `bytes`. Returns the info and the amount of bytes it took.
+    /// Info that is cut off gives a `PacketError::DataError`.
+    pub fn read(bytes: &[u8]) -> anyhow::Result<(Self, usize)> {
+        let [platform, len, ..] = *bytes else {
+            return Err(PacketError::data_error("The peer info is cut off").into());
+        };
+        let end = 2 + len as usize;
+        let version = bytes
+            .get(2..end)
+            .ok_or_else(|| PacketError::data_error("The version is cut off"))?;
+        let version = String::from_utf8(version.to_vec())
+            .map_err(|_| PacketError::data_error("Invalid UFT8 encoded values for version"))?;
+
+        let info = Self {
+            version,
+            platform: Platform::from_u8(platform),
+        };
+        Ok((info, end))
+    }
+
+    /// Returns false if the other peer's major version differs from ours, so the game may not
+    /// work as expected even though the protocol matches. A version that can't be parsed is
+    /// assumed to be compatible.
+    pub fn is_compatible_with(&self, other: &PeerInfo) -> bool {
+        match (major_version(&self.version), major_version(&other.version)) {
+            (Some(mine), Some(theirs)) => mine == theirs,
+            _ => true,
+        }
+    }
+}
+
+impl Display for PeerInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "version {} on {:?}", self.version, self.platform)
+    }
+}
+
+/// The major version of a version like `1.2.3`, or `None` if it doesn't start with a number.
+fn major_version(version: &str) -> Option<u64> {
+    version.split('.').next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(version: &str, platform: Platform) -> PeerInfo {
+        PeerInfo {
+            version: version.to_owned(),
+            platform,
+        }
+    }
+
+    #[test]
+    fn info_round_trips() {
+        let longest = "9".repeat(MAX_VERSION_LEN);
+        for platform in [
+            Platform::Unknown,
+            Platform::Windows,
+            Platform::Linux,
+            Platform::MacOs,
+        ] {
+            for version in ["", "0.1.0", "1.2.3-beta.4+øre", &longest] {
+                let sent = info(version, platform);
+                let mut buf = vec![];
+                sent.write(&mut buf);
+                assert_eq!(buf.len(), sent.encoded_len());
+                // Whatever comes after the info is left for the rest of the packet
+                buf.extend_from_slice(b"rest");
+                let (read, len) = PeerInfo::read(&buf).unwrap();
+                assert_eq!(read, sent);
+                assert_eq!(&buf[len..], b"rest");
+            }
+        }
+    }
+
+    #[test]
+    fn long_version_is_cut_off_between_characters() {
+        // Each 'ø' takes two bytes, so the longest version ends in the middle of one
+        let sent = info(&"ø".repeat(MAX_VERSION_LEN), Platform::Linux);
+        let mut buf = vec![];
+        sent.write(&mut buf);
+        assert_eq!(buf.len(), sent.encoded_len());
+        let (read, _) = PeerInfo::read(&buf).unwrap();
+        assert_eq!(read.version, "ø".repeat(MAX_VERSION_LEN / 2));
+    }
+
+    #[test]
+    fn cut_off_info_is_refused() {
+        let mut buf = vec![];
+        info("0.1.0", Platform::Windows).write(&mut buf);
+        for len in 0..buf.len() {
+            assert!(
+                PeerInfo::read(&buf[..len]).is_err(),
+                "{} bytes were read",
+                len
+            );
+        }
+        let not_utf8 = [wire::platform::LINUX, 2, 0xc3, 0x28];
+        assert!(PeerInfo::read(&not_utf8).is_err());
+    }
+
+    #[test]
+    fn unknown_platform_reads_as_unknown() {
+        let (read, _) = PeerInfo::read(&[0xfe, 1, b'1']).unwrap();
+        assert_eq!(read, info("1", Platform::Unknown));
+    }
+
+    #[test]
+    fn only_another_major_version_is_incompatible() {
+        let local = info("1.4.2", Platform::Linux);
+        for (version, compatible) in [
+            ("1.4.2", true),
+            ("1.0.0", true),
+            ("1.99.0-beta", true),
+            ("1", true),
+            ("0.9.9", false),
+            ("2.0.0", false),
+            ("10.4.2", false),
+            // Versions that can't be read don't warn
+            ("", true),
+            ("v2.0.0", true),
+            ("nightly", true),
+        ] {
+            let other = info(version, Platform::Windows);
+            assert_eq!(local.is_compatible_with(&other), compatible, "{}", version);
+            assert_eq!(other.is_compatible_with(&local), compatible, "{}", version);
+        }
+    }
+}
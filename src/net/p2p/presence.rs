@@ -0,0 +1,152 @@
+//! Notes about the other player that only matter for a moment, like that they're typing a chat
+//! message. They're sent as `P2pRequestPacket::StatusNote`, and never sent again when lost, since
+//! the next one follows soon anyway.
+//!
+//! The sender throttles the notes, so typing a long message doesn't send one for every key. The
+//! receiver shows a note until it hasn't been refreshed for a while, so the note can't stay on
+//! screen when the other player stopped typing, or the connection went down. Every function takes
+//! the time, so the tests can step it.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::NoteKind;
+
+/// The least time between two typing notes sent by us.
+pub const TYPING_INTERVAL: Duration = Duration::from_secs(2);
+/// How long the other player is shown as typing after their last typing note.
+pub const TYPING_EXPIRY: Duration = Duration::from_secs(5);
+
+/// Limits the typing notes we send to one every `TYPING_INTERVAL`.
+#[derive(Debug, Default)]
+pub struct TypingThrottle {
+    last_sent: Option<Instant>,
+}
+
+impl TypingThrottle {
+    pub const fn new() -> Self {
+        Self { last_sent: None }
+    }
+
+    /// If a typing note may be sent at `now`. If it may, it's counted as sent.
+    pub fn try_send(&mut self, now: Instant) -> bool {
+        let is_due = self
+            .last_sent
+            .is_none_or(|sent| now.saturating_duration_since(sent) >= TYPING_INTERVAL);
+        if is_due {
+            self.last_sent = Some(now);
+        }
+        is_due
+    }
+
+    /// Let the next note be sent right away, e.g. after a message was sent, so typing the next
+    /// one shows at once.
+    pub fn reset(&mut self) {
+        self.last_sent = None;
+    }
+}
+
+/// If the other player is typing, from their typing notes.
+#[derive(Debug, Default)]
+pub struct TypingIndicator {
+    refreshed: Option<Instant>,
+}
+
+impl TypingIndicator {
+    pub const fn new() -> Self {
+        Self { refreshed: None }
+    }
+
+    /// A typing note arrived at `now`.
+    pub fn refresh(&mut self, now: Instant) {
+        self.refreshed = Some(now);
+    }
+
+    /// If the other player is shown as typing at `now`.
+    pub fn is_shown(&self, now: Instant) -> bool {
+        self.refreshed
+            .is_some_and(|refreshed| now.saturating_duration_since(refreshed) < TYPING_EXPIRY)
+    }
+
+    /// The other player is done typing, e.g. because their message arrived.
+    pub fn clear(&mut self) {
+        self.refreshed = None;
+    }
+}
+
+/// The typing notes we send.
+static THROTTLE: Mutex<TypingThrottle> = Mutex::new(TypingThrottle::new());
+/// The typing notes the other peer sends.
+static INDICATOR: Mutex<TypingIndicator> = Mutex::new(TypingIndicator::new());
+
+/// If a typing note may be sent at `now`. See `TypingThrottle::try_send()`.
+pub fn try_send_typing(now: Instant) -> bool {
+    THROTTLE.lock().unwrap().try_send(now)
+}
+
+/// Let the next typing note be sent right away, after we sent a chat message.
+pub fn reset_typing_throttle() {
+    THROTTLE.lock().unwrap().reset()
+}
+
+/// Take a note from the other peer, which arrived at `now`.
+pub fn take_note(kind: NoteKind, now: Instant) {
+    match kind {
+        NoteKind::Typing => INDICATOR.lock().unwrap().refresh(now),
+    }
+}
+
+/// If the other player is typing at `now`.
+pub fn is_other_typing(now: Instant) -> bool {
+    INDICATOR.lock().unwrap().is_shown(now)
+}
+
+/// The other player is done typing, since their chat message arrived.
+pub fn clear_other_typing() {
+    INDICATOR.lock().unwrap().clear()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typing_notes_are_throttled() {
+        let start = Instant::now();
+        let mut throttle = TypingThrottle::new();
+        assert!(throttle.try_send(start));
+        // Every key while typing asks, but only one note goes out every interval
+        let sent: Vec<u64> = (1..=50)
+            .map(|tenth| tenth * 100)
+            .filter(|&ms| throttle.try_send(start + Duration::from_millis(ms)))
+            .collect();
+        assert_eq!(sent, [2_000, 4_000]);
+
+        throttle.reset();
+        assert!(throttle.try_send(start + Duration::from_millis(4_100)));
+        assert!(!throttle.try_send(start + Duration::from_millis(4_200)));
+    }
+
+    #[test]
+    fn typing_expires_without_a_refresh() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut indicator = TypingIndicator::new();
+        assert!(!indicator.is_shown(start));
+
+        indicator.refresh(start);
+        assert!(indicator.is_shown(at(4_999)));
+        assert!(!indicator.is_shown(at(5_000)));
+
+        // Each note keeps it shown for another expiry
+        indicator.refresh(at(4_000));
+        assert!(indicator.is_shown(at(8_999)));
+        assert!(!indicator.is_shown(at(9_000)));
+
+        indicator.refresh(at(9_000));
+        indicator.clear();
+        assert!(!indicator.is_shown(at(9_001)));
+    }
+}
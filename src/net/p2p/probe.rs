@@ -0,0 +1,80 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+
+use super::{
+    coin_flip::Commitment,
+    communicate::{recieve_p2p_packet, send_p2p_packet},
+    session::Session,
+    throttle::Throttled,
+    P2pError, P2pPacket, P2pRequestPacket, P2pResponsePacket, ProtocolMismatch,
+};
+
+/// The answer to a probe.
+#[derive(Clone, Copy, Debug)]
+pub struct ProbeAnswer {
+    /// If the peer is hosting a game.
+    pub hosting: bool,
+    /// The hosts commitment to its coin flip nonce. See `coin_flip`.
+    pub commitment: Option<Commitment>,
+}
+
+/// Ask the peer at `addr` if it's hosting a game. The probe is sent from its own socket, so it
+/// works while a network loop is running.
+/// Returns an error if the peer doesn't answer within `timeout`, `Throttled` if the peer asks to
+/// try again later, or `ProtocolMismatch` if the peer runs a version of the game we can't talk to.
+///
+/// ## Params
+/// * `addr` - The address of the peer.
+/// * `timeout` - How long to wait for an answer.
+pub async fn probe_peer(addr: SocketAddr, timeout: Duration) -> anyhow::Result<ProbeAnswer> {
+    let socket = Arc::new(tokio::net::UdpSocket::bind(("0.0.0.0", 0)).await?);
+
+    let probe = Session::connect_request(P2pRequestPacket::Probe)
+        .await
+        .into_packet()?;
+    let transaction_id = match &probe {
+        P2pPacket::Request(req) => req.transaction_id,
+        P2pPacket::Response(resp) => resp.transaction_id,
+    };
+    send_p2p_packet(&socket, probe, addr).await?;
+
+    let answer = async {
+        loop {
+            let (packet, from) = match recieve_p2p_packet(&socket).await {
+                Ok(packet) => packet,
+                Err(_) => continue,
+            };
+            if from != addr {
+                continue;
+            }
+            if let P2pPacket::Response(resp) = packet {
+                if resp.transaction_id != transaction_id {
+                    continue;
+                }
+                return match resp.packet {
+                    P2pResponsePacket::ProbeResponse {
+                        hosting,
+                        commitment,
+                    } => Ok(ProbeAnswer {
+                        hosting,
+                        commitment,
+                    }),
+                    P2pResponsePacket::RetryLater { retry_after_ms, .. } => Err(Throttled {
+                        retry_after: Duration::from_millis(retry_after_ms.into()),
+                    }
+                    .into()),
+                    P2pResponsePacket::Error {
+                        kind: P2pError::ProtocolMismatch,
+                    } => Err(ProtocolMismatch.into()),
+                    packet => Err(anyhow!("Expected a probe response, got {:?}", packet)),
+                };
+            }
+        }
+    };
+
+    match tokio::time::timeout(timeout, answer).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("No answer to probe from {:?}", addr)),
+    }
+}
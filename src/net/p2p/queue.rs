@@ -1,102 +1,512 @@
+//! The queues between the game and the network loop, and the table of requests waiting for
+//! their responses.
+//!
+//! Locking: No function holds more than one of the locks of this module at a time, and none
+//! awaits anything while holding one, other than taking the lock itself. A `Completion` is taken
+//! out of the table and run after the lock is released, since a callback can call back into the
+//! queue, e.g. to send the next game action. The one exception is the receiving end of the
+//! outgoing queue, which `next_outgoing()` holds while it waits for a packet. Only the outgoing
+//! loop takes it, so nothing waits on it. Debug builds check the rest, see `net::checked_lock`.
+//!
+//! The outgoing queue is a channel, so the outgoing loop sleeps until there is something to send,
+//! instead of checking the queue over and over.
+//!
+//! Capacity: Nothing is sent while there is no other peer, so the queue holds at most
+//! `outgoing_capacity()` packets. A push to a full queue gives a `QueueFull`, instead of piling up
+//! packets that would flood the other peer once it's back. Pings are refused once the queue is
+//! three quarters full, so the room left is kept for the packets that matter. Resends don't count
+//! against the capacity, since their requests were let in already.
+//!
+//! Resends: A request that `P2pRequestPacket::is_resent()` is pushed to the back of the outgoing
+//! queue again, when its response hasn't come `RESEND_TIMEOUT` after it was sent. After
+//! `MAX_RESENDS` resends it's taken out of the table, and its completion gets a `TimedOut`. The
+//! other peer can get the request more than once, so it must be safe to take it again.
+//!
+//! Expiry: A request whose response never comes, e.g. a lost ping, would stay in the table for the
+//! rest of the session. `expire_transactions()` takes out the requests older than a time to live,
+//! or than the timeout they were pushed with, and their completions get a `TimedOut` like after
+//! the resends.
+
 use std::{
     collections::{HashMap, VecDeque},
-    sync::Arc,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering},
+    time::{Duration, Instant},
 };
 
 use lazy_static::lazy_static;
-use tokio::sync::Mutex;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
+
+use crate::{
+    game::GameAction,
+    net::{
+        checked_lock::CheckedMutex,
+        status::{add_retried_request, add_timed_out_response},
+    },
+};
 
-use crate::game::GameAction;
+use super::{
+    net_loop::REQUEST_TIMEOUT_MS, sequence::ActionOrder, P2pPacket, P2pRequest, P2pRequestPacket,
+    P2pResponse,
+};
 
-use super::{P2pPacket, P2pResponse};
+/// How long a request that is resent waits for its response, before it's sent again.
+pub const RESEND_TIMEOUT: Duration = Duration::from_millis(REQUEST_TIMEOUT_MS as u64);
+/// The most times a request is sent again, after the first time.
+pub const MAX_RESENDS: u8 = 3;
+/// How long a request stays in the table without its response being taken. It's long, since the
+/// answer to a rematch offer waits for the other player to decide.
+pub const TRANSACTION_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The most packets in the outgoing queue, unless `set_outgoing_capacity()` says otherwise.
+pub const MAX_OUTGOING: usize = 256;
+
+/// The error when a packet is pushed to a full outgoing queue. The packet isn't sent, and a
+/// request never gets a response.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+#[error("The outgoing queue is full, with {len} packets waiting to be sent")]
+pub struct QueueFull {
+    pub len: usize,
+}
+
+/// The error when every transaction ID of our half is waiting for a response. The request isn't
+/// sent, since its response couldn't be told apart from the response to another request.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+#[error("Every transaction ID is waiting for a response")]
+pub struct NoTransactionId;
+
+/// The error the completion of a request gets, when it was resent `MAX_RESENDS` times without
+/// getting a response.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+#[error("Transaction {transaction_id} got no response, after it was sent {sends} times")]
+pub struct TimedOut {
+    pub transaction_id: u16,
+    /// The times the request was sent, including the first.
+    pub sends: u8,
+}
+
+/// A callback that runs when a request gets its response, or with a `TimedOut` if a request that
+/// is resent never got one.
+pub type ResponseCallback = Box<dyn FnOnce(Result<P2pResponse, TimedOut>) + Send>;
+
+/// What is done with the response to a request, when it arrives. Each is done at most once, and
+/// dropping one never blocks.
+pub enum Completion {
+    /// The response is kept in the table, until `check_for_response()` takes it.
+    Keep,
+    /// The response is sent on the channel. It's dropped if nothing listens anymore.
+    Send(oneshot::Sender<P2pResponse>),
+    /// The callback is run with the response.
+    Callback(ResponseCallback),
+}
+
+/// A request we have sent, in the transaction table.
+struct Transaction {
+    /// The response, if it has arrived and the completion is `Completion::Keep`.
+    response: Option<P2pPacket>,
+    completion: Completion,
+    /// The request, if it's sent again when its response doesn't come.
+    resend: Option<Resend>,
+    /// When the request was pushed to the outgoing queue.
+    created: Instant,
+    /// When the request gives up on its response, if it was pushed with a timeout.
+    deadline: Option<Instant>,
+}
+
+impl Transaction {
+    /// The times the request was sent, including the first.
+    fn sends(&self) -> u8 {
+        self.resend.as_ref().map_or(1, |resend| resend.resends + 1)
+    }
+}
+
+/// A request that is sent again when its response doesn't come.
+struct Resend {
+    request: P2pRequest,
+    /// When it was last sent, or `None` while it's waiting in the outgoing queue.
+    sent: Option<Instant>,
+    /// The times it was sent again.
+    resends: u8,
+}
+
+/// A request in the transaction table, for diagnostics.
+#[derive(Clone, Copy, Debug)]
+pub struct PendingRequest {
+    pub transaction_id: u16,
+    /// If the response has arrived, but hasn't been taken yet.
+    pub answered: bool,
+}
+
+/// The top bit of a transaction ID is set if the host made the request, and clear if the client
+/// did. Each side only hands out IDs from its own half, so the ID of a request never collides with
+/// one the other side is waiting on.
+pub const HOST_ID_BIT: u16 = 0x8000;
+
+/// If this peer is the host, which decides the half its transaction IDs are from.
+static IS_HOST: AtomicBool = AtomicBool::new(false);
+/// The next transaction ID, without the `HOST_ID_BIT`. It counts up, so an ID is only used again
+/// after every other ID of the half, long after a late response to it could arrive.
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+/// The sequence number of the next game action we send in this session. See `sequence`.
+static NEXT_SEQUENCE: AtomicU16 = AtomicU16::new(0);
 
 lazy_static! {
-    static ref TRANSACTION_TABLE: Mutex<
-        HashMap<
-            u16,
-            (
-                Option<P2pPacket>,
-                Option<Arc<Mutex<dyn FnMut(P2pResponse) + Send + Sync>>>
-            ),
-        >,
-    > = Mutex::const_new(HashMap::new());
+    /// The requests we have sent, by transaction ID. Responses we send aren't in it.
+    static ref TRANSACTION_TABLE: CheckedMutex<HashMap<u16, Transaction>> =
+        CheckedMutex::new("queue.transaction_table", HashMap::new());
 }
 
+/// A packet in the outgoing queue, with its transaction ID.
+type Outgoing = (P2pPacket, u16);
+type OutgoingReceiver = Mutex<mpsc::UnboundedReceiver<Outgoing>>;
+
 lazy_static! {
-    /// Queue for outgoing packets. Follows First in First out principle.
-    /// Each item in the queue is a tuple of two items: The outgoing packet, and a closure that runs when
-    /// the outgoing packet has gotten a response.
-    /// If the packet isn't meant to get a response, set the closure to `None`.
-    static ref OUTGOING_QUEUE: Mutex<VecDeque<(P2pPacket, u16)>> =
-        Mutex::const_new(VecDeque::new());
+    /// Queue for outgoing packets. Follows First in First out principle. The receiving end is
+    /// taken by the outgoing loop.
+    static ref OUTGOING_QUEUE: (mpsc::UnboundedSender<Outgoing>, OutgoingReceiver) = {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (sender, Mutex::const_new(receiver))
+    };
 }
+/// The amount of packets in `OUTGOING_QUEUE`. It's counted on the side, since the receiver is
+/// held while the outgoing loop waits.
+static OUTGOING_LEN: AtomicUsize = AtomicUsize::new(0);
+/// The most packets in `OUTGOING_QUEUE`.
+static OUTGOING_CAPACITY: AtomicUsize = AtomicUsize::new(MAX_OUTGOING);
+
+/// The `GameActions` sent from the other user, in the order they were sent.
+static INCOMING_ACTIONS: CheckedMutex<ActionOrder> =
+    CheckedMutex::new("queue.incoming_actions", ActionOrder::new());
+
+/// The most chat messages kept in `INCOMING_CHAT`. When it's full, the oldest message is dropped,
+/// so a peer sending messages nobody reads can't make us run out of memory.
+const MAX_INCOMING_CHAT: usize = 64;
 
 lazy_static! {
-    /// A list which holds all `GameActions` send from the other user.
-    static ref INCOMING_ACTIONS: Mutex<VecDeque<GameAction>> =
-        Mutex::const_new(VecDeque::new());
+    /// The chat messages from the other user, that haven't been shown yet. Each item is a tuple
+    /// of the senders username and the text.
+    static ref INCOMING_CHAT: CheckedMutex<VecDeque<(String, String)>> =
+        CheckedMutex::new("queue.incoming_chat", VecDeque::new());
 }
 
+/// Push a packet to the outgoing queue. Returns its transaction ID, or a `QueueFull` if there is
+/// no room for it.
+///
+/// ## Params
+/// * `data` - The packet.
+/// * `completion` - What to do with the response, if the packet is a request.
+/// * `timeout` - How long a request waits for its response, before its completion gets a
+///   `TimedOut`. With `None` it waits through its resends, or up to `TRANSACTION_TTL`.
 pub async fn push_outgoing_queue(
     data: P2pPacket,
-    closure: Option<Arc<Mutex<(dyn FnMut(P2pResponse) + Send + Sync + 'static)>>>,
-) -> u16 {
+    completion: Completion,
+    timeout: Option<Duration>,
+) -> Result<u16, QueueFull> {
     let transaction_id = match &data {
         P2pPacket::Request(req) => req.transaction_id,
         P2pPacket::Response(resp) => resp.transaction_id,
     };
-    OUTGOING_QUEUE
-        .lock()
-        .await
-        .push_back((data, transaction_id));
+    let capacity = OUTGOING_CAPACITY.load(Ordering::Relaxed);
+    let limit = match &data {
+        P2pPacket::Request(P2pRequest {
+            packet: P2pRequestPacket::Ping { .. },
+            ..
+        }) => capacity - capacity / 4,
+        _ => capacity,
+    };
+    // The room is taken before the transaction is added, so a refused request leaves no trace
+    OUTGOING_LEN
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| {
+            (len < limit).then_some(len + 1)
+        })
+        .map_err(|len| QueueFull { len })?;
+    // The transaction must be in the table before the packet can be sent, or a fast response is
+    // dropped by `set_response`. A response has the ID of the other side's request, so it isn't
+    // added, where it could be taken for a response to our own request.
+    if let P2pPacket::Request(req) = &data {
+        let resend = req.packet.is_resent().then(|| Resend {
+            request: req.clone(),
+            sent: None,
+            resends: 0,
+        });
+        let created = Instant::now();
+        TRANSACTION_TABLE.lock().await.insert(
+            transaction_id,
+            Transaction {
+                response: None,
+                completion,
+                resend,
+                created,
+                deadline: timeout.map(|timeout| created + timeout),
+            },
+        );
+    }
 
-    TRANSACTION_TABLE
-        .lock()
-        .await
-        .insert(transaction_id, (None, closure));
-    transaction_id
+    // The receiver is in a static, so it's never dropped
+    let _ = OUTGOING_QUEUE.0.send((data, transaction_id));
+    Ok(transaction_id)
+}
+
+/// Put a packet at the back of the outgoing queue, whether there is room or not.
+fn send_outgoing(data: P2pPacket, transaction_id: u16) {
+    OUTGOING_LEN.fetch_add(1, Ordering::Relaxed);
+    let _ = OUTGOING_QUEUE.0.send((data, transaction_id));
 }
 
-/// Pops and returns the next item in the outgoing network queue.
+/// Set the most packets in the outgoing queue. Packets already in it stay, even if there are more
+/// of them.
+pub fn set_outgoing_capacity(capacity: usize) {
+    OUTGOING_CAPACITY.store(capacity, Ordering::Relaxed);
+}
+
+/// The most packets in the outgoing queue.
+pub fn outgoing_capacity() -> usize {
+    OUTGOING_CAPACITY.load(Ordering::Relaxed)
+}
+
+/// Pops and returns the next item in the outgoing network queue, without waiting for one. A
+/// request that is resent waits for its response from now on, since it's about to be sent.
+#[cfg(test)]
 pub async fn pop_outgoing_queue() -> Option<(P2pPacket, u16)> {
-    OUTGOING_QUEUE.lock().await.pop_front()
+    let (data, transaction_id) = OUTGOING_QUEUE.1.lock().await.try_recv().ok()?;
+    Some(take_outgoing(data, transaction_id).await)
 }
 
-pub async fn get_outgoing_queue_len() -> usize {
-    OUTGOING_QUEUE.lock().await.len()
-}
-
-/// Sets the response to a request inside the transaction table.
-/// If the transaction has a closure, this will run that closure, and then remove the request and
-/// its response.
-pub async fn set_response(transaction_id: u16, response: Option<P2pPacket>) {
-    let table = &mut TRANSACTION_TABLE.lock().await;
-    if let Some((_, closure)) = table.get(&transaction_id) {
-        if let Some(closure) = closure {
-            if let Some(P2pPacket::Response(resp)) = response.clone() {
-                closure.lock().await(resp);
+/// Wait for the next packet to send, and the address of the other peer to send it to. While
+/// there is no other peer, the queue is left alone. Returns `None` if nothing can be sent within
+/// `wait`, so the outgoing loop can do its other work.
+///
+/// ## Params
+/// * `addrs` - The address of the other peer, from `status::watch_other_addr()`.
+/// * `wait` - The longest time to wait.
+pub async fn next_outgoing(
+    addrs: &mut watch::Receiver<Option<SocketAddr>>,
+    wait: Duration,
+) -> Option<(P2pPacket, u16, SocketAddr)> {
+    let deadline = tokio::time::Instant::now() + wait;
+    let addr = match tokio::time::timeout_at(deadline, addrs.wait_for(Option::is_some)).await {
+        Ok(Ok(addr)) => (*addr)?,
+        _ => return None,
+    };
+    let (data, transaction_id) = {
+        let mut receiver = OUTGOING_QUEUE.1.lock().await;
+        tokio::time::timeout_at(deadline, receiver.recv())
+            .await
+            .ok()??
+    };
+    let (data, transaction_id) = take_outgoing(data, transaction_id).await;
+    Some((data, transaction_id, addr))
+}
+
+/// Count a packet taken out of the outgoing queue. A request that is resent waits for its
+/// response from now on, since it's about to be sent.
+async fn take_outgoing(data: P2pPacket, transaction_id: u16) -> (P2pPacket, u16) {
+    OUTGOING_LEN.fetch_sub(1, Ordering::Relaxed);
+    if data.is_request() {
+        let mut table = TRANSACTION_TABLE.lock().await;
+        let resend = table
+            .get_mut(&transaction_id)
+            .and_then(|transaction| transaction.resend.as_mut());
+        if let Some(resend) = resend {
+            resend.sent = Some(Instant::now());
+        }
+    }
+    (data, transaction_id)
+}
+
+/// Send the requests that have waited `RESEND_TIMEOUT` for their response again, by pushing them
+/// to the back of the outgoing queue. A request that was already sent again `MAX_RESENDS` times is
+/// taken out of the table instead, and its completion gets a `TimedOut`.
+///
+/// ## Params
+/// * `now` - The time to measure the wait until.
+pub async fn resend_expired(now: Instant) {
+    let mut resends = vec![];
+    let expired: Vec<(u16, Transaction)> = {
+        let mut table = TRANSACTION_TABLE.lock().await;
+        let mut expired_ids = vec![];
+        for (transaction_id, transaction) in table.iter_mut() {
+            let Some(resend) = transaction.resend.as_mut() else {
+                continue;
+            };
+            let waited = match resend.sent {
+                Some(sent) => now.saturating_duration_since(sent),
+                None => continue,
+            };
+            if transaction.response.is_some() || waited < RESEND_TIMEOUT {
+                continue;
             }
-            table.remove(&transaction_id);
-        } else {
-            table.insert(transaction_id, (response, None));
+            if resend.resends < MAX_RESENDS {
+                resend.resends += 1;
+                resend.sent = None;
+                resends.push(resend.request.clone());
+            } else {
+                expired_ids.push(*transaction_id);
+            }
+        }
+        expired_ids
+            .into_iter()
+            .filter_map(|transaction_id| {
+                let transaction = table.remove(&transaction_id)?;
+                Some((transaction_id, transaction))
+            })
+            .collect()
+    };
+
+    for req in resends {
+        println!(
+            "No response to transaction {}, sending it again",
+            req.transaction_id
+        );
+        let transaction_id = req.transaction_id;
+        send_outgoing(P2pPacket::Request(req), transaction_id);
+        add_retried_request().await;
+    }
+    for (transaction_id, transaction) in expired {
+        println!(
+            "Transaction {} got no response, giving up on it",
+            transaction_id
+        );
+        time_out(transaction_id, transaction).await;
+    }
+}
+
+/// Take the requests that have been in the table for `ttl`, or are past the timeout they were
+/// pushed with, out of it, with their response if it wasn't taken. Their completions get a
+/// `TimedOut`.
+///
+/// ## Params
+/// * `now` - The time to measure the age of the requests at.
+/// * `ttl` - How old a request may get, normally `TRANSACTION_TTL`.
+pub async fn expire_transactions(now: Instant, ttl: Duration) {
+    let expired: Vec<(u16, Transaction)> = {
+        let mut table = TRANSACTION_TABLE.lock().await;
+        let expired_ids: Vec<u16> = table
+            .iter()
+            .filter(|(_, transaction)| {
+                now.saturating_duration_since(transaction.created) >= ttl
+                    || transaction.deadline.is_some_and(|deadline| now >= deadline)
+            })
+            .map(|(transaction_id, _)| *transaction_id)
+            .collect();
+        expired_ids
+            .into_iter()
+            .filter_map(|transaction_id| {
+                let transaction = table.remove(&transaction_id)?;
+                Some((transaction_id, transaction))
+            })
+            .collect()
+    };
+
+    for (transaction_id, transaction) in expired {
+        let waited = now.saturating_duration_since(transaction.created);
+        println!(
+            "Transaction {} got no response in {} ms, forgetting it",
+            transaction_id,
+            waited.as_millis()
+        );
+        time_out(transaction_id, transaction).await;
+    }
+}
+
+/// Give the completion of a transaction that was taken out of the table a `TimedOut`. It's counted
+/// in the `ConnectionStats`, unless its response came, and was never taken.
+async fn time_out(transaction_id: u16, transaction: Transaction) {
+    if transaction.response.is_none() {
+        add_timed_out_response().await;
+    }
+    give_up(transaction_id, transaction);
+}
+
+/// Take a request that couldn't be sent out of the table, e.g. because it's too large, or the
+/// socket stopped working. No response can come, so its completion gets a `TimedOut` right away,
+/// instead of after its resends. Does nothing for a transaction that isn't in the table.
+pub async fn fail_transaction(transaction_id: u16) {
+    let transaction = TRANSACTION_TABLE.lock().await.remove(&transaction_id);
+    if let Some(transaction) = transaction {
+        give_up(transaction_id, transaction);
+    }
+}
+
+/// Give the completion of a transaction that was taken out of the table a `TimedOut`.
+fn give_up(transaction_id: u16, transaction: Transaction) {
+    let sends = transaction.sends();
+    // A channel is closed by dropping its sender, so the receiver is woken up
+    if let Completion::Callback(callback) = transaction.completion {
+        callback(Err(TimedOut {
+            transaction_id,
+            sends,
+        }));
+    }
+}
+
+pub async fn get_outgoing_queue_len() -> usize {
+    OUTGOING_LEN.load(Ordering::Relaxed)
+}
+
+/// The amount of requests in the transaction table, answered or not.
+pub async fn get_transaction_table_len() -> usize {
+    TRANSACTION_TABLE.lock().await.len()
+}
+
+/// Completes the request that `response` answers, as its `Completion` says. A response to a
+/// request that isn't in the table is dropped.
+pub async fn set_response(response: P2pResponse) {
+    let completion = {
+        let mut table = TRANSACTION_TABLE.lock().await;
+        let Some(transaction) = table.get_mut(&response.transaction_id) else {
+            return;
         };
+        if let Completion::Keep = transaction.completion {
+            transaction.response = Some(P2pPacket::Response(response));
+            return;
+        }
+        table.remove(&response.transaction_id)
+    };
+    match completion.map(|transaction| transaction.completion) {
+        Some(Completion::Send(sender)) => {
+            // The receiver is gone if the request timed out
+            let _ = sender.send(response);
+        }
+        Some(Completion::Callback(callback)) => callback(Ok(response)),
+        _ => {}
     }
 }
 
-pub async fn new_transaction_id() -> u16 {
-    let mut transaction_id;
-    loop {
-        transaction_id = rand::random::<u16>();
-        if TRANSACTION_TABLE
-            .lock()
-            .await
-            .get(&transaction_id)
-            .is_none()
-        {
-            break;
+/// Set which half of the transaction IDs this peer hands out. See `HOST_ID_BIT`.
+pub fn set_is_host(is_host: bool) {
+    IS_HOST.store(is_host, Ordering::Relaxed);
+}
+
+/// If this peer is the host.
+pub fn is_host() -> bool {
+    IS_HOST.load(Ordering::Relaxed)
+}
+
+/// The next transaction ID of our half. After the last ID of the half, it wraps around to the
+/// first. An ID that is still waiting for its response after a full wrap around is skipped.
+/// Returns a `NoTransactionId` if every ID of the half is waiting.
+pub async fn new_transaction_id() -> Result<u16, NoTransactionId> {
+    let half = if IS_HOST.load(Ordering::Relaxed) {
+        HOST_ID_BIT
+    } else {
+        0
+    };
+    let table = TRANSACTION_TABLE.lock().await;
+    // Each ID of the half is tried once
+    for _ in 0..HOST_ID_BIT {
+        let transaction_id = half | (NEXT_ID.fetch_add(1, Ordering::Relaxed) & !HOST_ID_BIT);
+        if !table.contains_key(&transaction_id) {
+            return Ok(transaction_id);
         }
     }
-    transaction_id
+    Err(NoTransactionId)
+}
+
+/// Stop waiting for the response to a transaction. If the response comes later, it's unexpected.
+pub async fn forget_transaction(transaction_id: u16) {
+    TRANSACTION_TABLE.lock().await.remove(&transaction_id);
 }
 
 pub async fn check_transaction_id(transaction_id: u16) -> bool {
@@ -107,55 +517,576 @@ pub async fn check_transaction_id(transaction_id: u16) -> bool {
         .is_some()
 }
 
+/// Take the response of the transaction out of the table, if it has arrived.
 pub async fn check_for_response(transaction_id: u16) -> Option<P2pPacket> {
-    let response = TRANSACTION_TABLE
+    let mut table = TRANSACTION_TABLE.lock().await;
+    match table.get(&transaction_id) {
+        Some(Transaction {
+            response: Some(_), ..
+        }) => table.remove(&transaction_id)?.response,
+        _ => None,
+    }
+}
+
+/// The requests waiting in the transaction table.
+pub async fn pending_requests() -> Vec<PendingRequest> {
+    TRANSACTION_TABLE
         .lock()
         .await
-        .clone()
-        .get(&transaction_id)
-        .unwrap_or(&(None, None))
-        .clone();
+        .iter()
+        .map(|(transaction_id, transaction)| PendingRequest {
+            transaction_id: *transaction_id,
+            answered: transaction.response.is_some(),
+        })
+        .collect()
+}
 
-    if response.0.is_some() {
-        TRANSACTION_TABLE.lock().await.remove(&transaction_id);
-    }
-    response.0
+/// The sequence number for the next game action we send.
+pub fn new_sequence() -> u16 {
+    NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed)
 }
 
-/// Wait for the transaction ID to get a response
-pub async fn wait_for_response(transaction_id: u16) -> P2pPacket {
-    loop {
-        let response = TRANSACTION_TABLE
-            .lock()
-            .await
-            .clone()
-            .get(&transaction_id)
-            .unwrap_or(&(None, None))
-            .clone();
-
-        if let Some(resp) = response.0 {
-            TRANSACTION_TABLE.lock().await.remove(&transaction_id);
-            return resp.clone();
-        }
-    }
+/// Hand out `sequence` again, if it's the last one `new_sequence()` gave, since the game action
+/// that got it was never sent. Otherwise the other peer skips it after the `GAP_TIMEOUT`.
+pub fn return_sequence(sequence: u16) {
+    let next = sequence.wrapping_add(1);
+    let _ = NEXT_SEQUENCE.compare_exchange(next, sequence, Ordering::Relaxed, Ordering::Relaxed);
 }
 
-pub async fn get_transaction_table() -> HashMap<
-    u16,
-    (
-        Option<P2pPacket>,
-        Option<Arc<Mutex<dyn FnMut(P2pResponse) + Send + Sync>>>,
-    ),
-> {
-    TRANSACTION_TABLE.lock().await.clone()
+/// Returns if the game action with `sequence` from the other user was taken already.
+pub async fn is_duplicate_gameaction(sequence: u16) -> bool {
+    INCOMING_ACTIONS.lock().await.is_duplicate(sequence)
+}
+
+/// Queue a game action from the other user, to be popped after the actions sent before it.
+pub async fn push_incoming_gameaction(sequence: u16, action: GameAction) {
+    INCOMING_ACTIONS
+        .lock()
+        .await
+        .add(sequence, Some(action), Instant::now());
 }
 
-pub async fn push_incoming_gameaction(action: GameAction) {
-    INCOMING_ACTIONS.lock().await.push_back(action);
+/// Let the game actions after a refused one from the other user be popped without it.
+pub async fn refuse_incoming_gameaction(sequence: u16) {
+    INCOMING_ACTIONS
+        .lock()
+        .await
+        .add(sequence, None, Instant::now());
 }
+
 pub async fn pop_incoming_gameaction() -> Option<GameAction> {
-    INCOMING_ACTIONS.lock().await.pop_front()
+    INCOMING_ACTIONS.lock().await.pop(Instant::now())
 }
 pub async fn get_incoming_gameaction_len() -> usize {
     INCOMING_ACTIONS.lock().await.len()
 }
+
+/// Start counting the game actions of both users from 0, for a new session.
+pub async fn clear_gameaction_sequences() {
+    NEXT_SEQUENCE.store(0, Ordering::Relaxed);
+    *INCOMING_ACTIONS.lock().await = ActionOrder::new();
+}
+
+pub async fn push_incoming_chat(sender: String, message: String) {
+    let mut chat = INCOMING_CHAT.lock().await;
+    if chat.len() == MAX_INCOMING_CHAT {
+        chat.pop_front();
+    }
+    chat.push_back((sender, message));
+}
+pub async fn pop_incoming_chat() -> Option<(String, String)> {
+    INCOMING_CHAT.lock().await.pop_front()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex as StdMutex};
+
+    use futures::executor;
+
+    use super::*;
+    use crate::net::{
+        p2p::{lock_global_state, runtime, session::Session, P2pResponsePacket},
+        status::{
+            get_connection_stats, remove_other_addr, reset_connection_stats, set_other_addr,
+            watch_other_addr,
+        },
+    };
+
+    /// Empty the outgoing queue and the transaction table, so the next test starts clean.
+    async fn clear() {
+        while pop_outgoing_queue().await.is_some() {}
+        TRANSACTION_TABLE.lock().await.clear();
+    }
+
+    /// The responses delivered to each request, by the payload of its ping.
+    type Delivered = Arc<StdMutex<Vec<(u8, Vec<u8>)>>>;
+
+    /// Push a ping with `transaction_id`, whose response is added to `delivered` as `(label, the
+    /// payload of the pong)`.
+    async fn push_ping(transaction_id: u16, label: u8, delivered: &Delivered) {
+        let packet = P2pRequestPacket::Ping {
+            payload: vec![label],
+        };
+        let delivered = delivered.clone();
+        let completion = Completion::Callback(Box::new(move |resp| {
+            let P2pResponsePacket::Pong { payload } = resp.unwrap().packet else {
+                panic!("expected a pong");
+            };
+            delivered.lock().unwrap().push((label, payload));
+        }));
+        let request = P2pRequest::new(0x1a2b, transaction_id, packet);
+        push_outgoing_queue(request.into(), completion, None)
+            .await
+            .unwrap();
+    }
+
+    /// The pong the other peer answers `transaction_id` with.
+    fn pong(transaction_id: u16, payload: u8) -> P2pResponse {
+        let packet = P2pResponsePacket::Pong {
+            payload: vec![payload],
+        };
+        P2pResponse::new(0x1a2b, transaction_id, packet)
+    }
+
+    #[test]
+    fn transaction_ids_wrap_around_without_misdelivery() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            clear().await;
+            let delivered = Delivered::default();
+            set_is_host(false);
+
+            // The first ID of the half is still waiting when the counter wraps around
+            NEXT_ID.store(0, Ordering::Relaxed);
+            let waiting = new_transaction_id().await.unwrap();
+            assert_eq!(waiting, 0x0000);
+            push_ping(waiting, 1, &delivered).await;
+
+            NEXT_ID.store(0x7ffe, Ordering::Relaxed);
+            let mut ids = vec![];
+            for label in 2..5 {
+                let id = new_transaction_id().await.unwrap();
+                push_ping(id, label, &delivered).await;
+                ids.push(id);
+            }
+            assert_eq!(ids, [0x7ffe, 0x7fff, 0x0001]);
+
+            // Each response reaches the request with its ID, and only that one
+            for (id, payload) in [(0x0001, 4), (0x0000, 1), (0x7fff, 3), (0x7ffe, 2)] {
+                set_response(pong(id, payload)).await;
+            }
+            // A late response to an answered request, or one of the others half, goes nowhere
+            set_response(pong(0x0001, 9)).await;
+            set_response(pong(HOST_ID_BIT | 0x0002, 9)).await;
+
+            let delivered = delivered.lock().unwrap().clone();
+            assert_eq!(
+                delivered,
+                [(4, vec![4]), (1, vec![1]), (3, vec![3]), (2, vec![2])]
+            );
+            assert_eq!(get_transaction_table_len().await, 0);
+            clear().await;
+        });
+    }
+
+    #[test]
+    fn host_ids_stay_in_their_half() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            clear().await;
+            set_is_host(true);
+            NEXT_ID.store(0xfffe, Ordering::Relaxed);
+            let mut ids = vec![];
+            for _ in 0..4 {
+                ids.push(new_transaction_id().await.unwrap());
+            }
+            assert_eq!(ids, [0xfffe, 0xffff, 0x8000, 0x8001]);
+
+            set_is_host(false);
+            NEXT_ID.store(0xffff, Ordering::Relaxed);
+            assert_eq!(new_transaction_id().await, Ok(0x7fff));
+            assert_eq!(new_transaction_id().await, Ok(0x0000));
+        });
+    }
+
+    #[test]
+    fn request_is_refused_when_every_id_is_waiting() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            clear().await;
+            set_is_host(false);
+            {
+                let mut table = TRANSACTION_TABLE.lock().await;
+                for transaction_id in 0..HOST_ID_BIT {
+                    let transaction = Transaction {
+                        response: None,
+                        completion: Completion::Keep,
+                        resend: None,
+                        created: Instant::now(),
+                        deadline: None,
+                    };
+                    table.insert(transaction_id, transaction);
+                }
+            }
+            assert_eq!(new_transaction_id().await, Err(NoTransactionId));
+            let e = Session::request(P2pRequestPacket::ping())
+                .await
+                .send()
+                .await
+                .unwrap_err();
+            assert!(e.is::<NoTransactionId>());
+            assert!(pop_outgoing_queue().await.is_none());
+
+            // The hosts half is free, and so is an ID once its request is done
+            set_is_host(true);
+            assert!(new_transaction_id().await.is_ok());
+            set_is_host(false);
+            forget_transaction(0x1234).await;
+            assert_eq!(new_transaction_id().await, Ok(0x1234));
+            clear().await;
+        });
+    }
+
+    #[test]
+    fn queue_is_parked_until_there_is_an_address() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        executor::block_on(async {
+            clear().await;
+            remove_other_addr().await;
+            let delivered = Delivered::default();
+            for label in 0..3 {
+                push_ping(0x0100 + label as u16, label, &delivered).await;
+            }
+
+            let mut addrs = watch_other_addr();
+            let parked = next_outgoing(&mut addrs, Duration::from_millis(200)).await;
+            assert!(parked.is_none());
+            assert_eq!(get_outgoing_queue_len().await, 3);
+
+            let addr: SocketAddr = "127.0.0.1:9".parse().unwrap();
+            let appears = tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                set_other_addr(addr).await;
+                Instant::now()
+            });
+            let (_, transaction_id, to) = next_outgoing(&mut addrs, Duration::from_secs(10))
+                .await
+                .unwrap();
+            let flushed = Instant::now();
+            assert_eq!((transaction_id, to), (0x0100, addr));
+            assert!(flushed.duration_since(appears.await.unwrap()) < Duration::from_millis(100));
+
+            // The rest go right away, in order
+            for id in [0x0101, 0x0102] {
+                let next = next_outgoing(&mut addrs, Duration::ZERO).await.unwrap();
+                assert_eq!(next.1, id);
+            }
+            remove_other_addr().await;
+            clear().await;
+        });
+    }
+
+    #[test]
+    fn full_queue_refuses_pings_first_and_flushes_in_order() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        executor::block_on(async {
+            clear().await;
+            remove_other_addr().await;
+            set_outgoing_capacity(8);
+            let push = |transaction_id: u16, packet: P2pRequestPacket| async move {
+                let request = P2pRequest::new(0x1a2b, transaction_id, packet);
+                push_outgoing_queue(request.into(), Completion::Keep, None).await
+            };
+            let ping = || P2pRequestPacket::Ping { payload: vec![] };
+            let action =
+                |sequence| P2pRequestPacket::game_action(GameAction::OfferDraw, 3, sequence);
+
+            // Pings fill the queue up to three quarters of it
+            for id in 0x0200..0x0206 {
+                assert_eq!(push(id, ping()).await, Ok(id));
+            }
+            assert_eq!(push(0x0206, ping()).await, Err(QueueFull { len: 6 }));
+
+            // The rest of the room is kept for the game actions
+            assert_eq!(push(0x0207, action(1)).await, Ok(0x0207));
+            assert_eq!(push(0x0208, action(2)).await, Ok(0x0208));
+            assert_eq!(push(0x0209, action(3)).await, Err(QueueFull { len: 8 }));
+
+            // A refused request leaves no trace in the table
+            assert_eq!(get_outgoing_queue_len().await, 8);
+            assert_eq!(get_transaction_table_len().await, 8);
+            assert!(!check_transaction_id(0x0206).await);
+            assert!(!check_transaction_id(0x0209).await);
+
+            // Once the other peer is back, everything let in goes out in the order it was pushed
+            let addr: SocketAddr = "127.0.0.1:9".parse().unwrap();
+            set_other_addr(addr).await;
+            let mut addrs = watch_other_addr();
+            let mut flushed = vec![];
+            while let Some((_, transaction_id, _)) = next_outgoing(&mut addrs, Duration::ZERO).await
+            {
+                flushed.push(transaction_id);
+            }
+            let expected: Vec<u16> = (0x0200..0x0206).chain([0x0207, 0x0208]).collect();
+            assert_eq!(flushed, expected);
+
+            // And there is room again
+            assert_eq!(push(0x020a, action(3)).await, Ok(0x020a));
+
+            set_outgoing_capacity(MAX_OUTGOING);
+            remove_other_addr().await;
+            clear().await;
+        });
+    }
+
+    #[test]
+    fn old_transactions_expire() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            clear().await;
+            let ping =
+                |transaction_id| P2pRequest::new(0x1a2b, transaction_id, P2pRequestPacket::ping());
+            let answer: Arc<StdMutex<Option<Result<P2pResponse, TimedOut>>>> = Arc::default();
+            let callback = Completion::Callback(Box::new({
+                let answer = answer.clone();
+                move |resp| *answer.lock().unwrap() = Some(resp)
+            }));
+            push_outgoing_queue(ping(0x0200).into(), callback, None)
+                .await
+                .unwrap();
+            let (sender, mut receiver) = oneshot::channel();
+            push_outgoing_queue(ping(0x0201).into(), Completion::Send(sender), None)
+                .await
+                .unwrap();
+            push_outgoing_queue(ping(0x0202).into(), Completion::Keep, None)
+                .await
+                .unwrap();
+            while pop_outgoing_queue().await.is_some() {}
+            // Answered, but never taken
+            set_response(pong(0x0202, 1)).await;
+
+            expire_transactions(Instant::now(), TRANSACTION_TTL).await;
+            assert_eq!(get_transaction_table_len().await, 3);
+            expire_transactions(Instant::now() + TRANSACTION_TTL, TRANSACTION_TTL).await;
+            assert_eq!(get_transaction_table_len().await, 0);
+
+            let expected = TimedOut {
+                transaction_id: 0x0200,
+                sends: 1,
+            };
+            assert_eq!(*answer.lock().unwrap(), Some(Err(expected)));
+            assert_eq!(
+                receiver.try_recv(),
+                Err(oneshot::error::TryRecvError::Closed)
+            );
+        });
+    }
+
+    #[test]
+    fn resends_and_timeouts_are_counted() {
+        let _state = lock_global_state();
+        executor::block_on(async {
+            clear().await;
+            reset_connection_stats().await;
+            let packet = P2pRequestPacket::game_action(GameAction::OfferDraw, 3, 1);
+            let request = P2pRequest::new(0x1a2b, 0x0300, packet);
+            push_outgoing_queue(request.into(), Completion::Keep, None)
+                .await
+                .unwrap();
+
+            // Sent, and sent again each time its response doesn't come, until it gives up
+            for resends in 1..=MAX_RESENDS {
+                assert!(pop_outgoing_queue().await.is_some());
+                resend_expired(Instant::now() + RESEND_TIMEOUT).await;
+                assert_eq!(
+                    get_connection_stats().await.requests_retried,
+                    u32::from(resends)
+                );
+            }
+            assert!(pop_outgoing_queue().await.is_some());
+            resend_expired(Instant::now() + RESEND_TIMEOUT).await;
+            assert!(!check_transaction_id(0x0300).await);
+
+            // Of two pings past their deadline, only the one without a response timed out
+            let ping = P2pRequest::new(0x1a2b, 0x0301, P2pRequestPacket::ping());
+            push_outgoing_queue(ping.into(), Completion::Keep, Some(Duration::ZERO))
+                .await
+                .unwrap();
+            let ping = P2pRequest::new(0x1a2b, 0x0302, P2pRequestPacket::ping());
+            push_outgoing_queue(ping.into(), Completion::Keep, Some(Duration::ZERO))
+                .await
+                .unwrap();
+            set_response(pong(0x0302, 1)).await;
+            expire_transactions(Instant::now(), TRANSACTION_TTL).await;
+
+            let stats = get_connection_stats().await;
+            assert_eq!(stats.requests_retried, u32::from(MAX_RESENDS));
+            assert_eq!(stats.responses_timed_out, 2);
+            assert_eq!(get_transaction_table_len().await, 0);
+
+            reset_connection_stats().await;
+            clear().await;
+        });
+    }
+
+    #[test]
+    fn callback_can_call_back_into_the_queue() {
+        let _state = lock_global_state();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            clear().await;
+            let transaction_id = new_transaction_id().await.unwrap();
+            let answered = Arc::new(AtomicBool::new(false));
+            let completion = Completion::Callback(Box::new({
+                let answered = answered.clone();
+                move |resp| {
+                    resp.unwrap();
+                    // Like sending the next game action, which blocks on the queue
+                    assert!(!executor::block_on(check_transaction_id(transaction_id)));
+                    executor::block_on(new_transaction_id()).unwrap();
+                    answered.store(true, Ordering::Relaxed);
+                }
+            }));
+            let request = P2pRequest::new(0x1a2b, transaction_id, P2pRequestPacket::ping());
+            push_outgoing_queue(request.into(), completion, None)
+                .await
+                .unwrap();
+            set_response(pong(transaction_id, 0)).await;
+            assert!(answered.load(Ordering::Relaxed));
+            clear().await;
+        });
+    }
+
+    /// Push a ping, and wait for its response as `completion_kind` says, while another task sets
+    /// the response. A callback takes the table lock itself, like a callback sending the next game
+    /// action would.
+    async fn ping_and_wait(completion_kind: usize, completed: Arc<AtomicUsize>) {
+        let transaction_id = new_transaction_id().await.unwrap();
+        let (sender, receiver) = oneshot::channel();
+        let completion = match completion_kind {
+            0 => Completion::Keep,
+            1 => Completion::Send(sender),
+            _ => Completion::Callback(Box::new(move |resp| {
+                completed.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(async move {
+                    assert!(!check_transaction_id(transaction_id).await);
+                    let _ = sender.send(resp.unwrap());
+                });
+            })),
+        };
+        let request = P2pRequest::new(0x1a2b, transaction_id, P2pRequestPacket::ping());
+        // Each task has one packet in the queue at a time, so it never fills up
+        push_outgoing_queue(request.into(), completion, None)
+            .await
+            .unwrap();
+        while pop_outgoing_queue().await.is_none() {
+            tokio::task::yield_now().await;
+        }
+        tokio::spawn(set_response(pong(transaction_id, 0)));
+
+        if completion_kind == 0 {
+            while check_for_response(transaction_id).await.is_none() {
+                tokio::task::yield_now().await;
+            }
+        } else {
+            receiver.await.unwrap();
+        }
+    }
+
+    #[test]
+    fn concurrent_requests_all_complete() {
+        const TASKS: usize = 48;
+        const ROUNDS: usize = 60;
+        let _state = lock_global_state();
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(4)
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            clear().await;
+            let completed = Arc::new(AtomicUsize::new(0));
+            let tasks: Vec<_> = (0..TASKS)
+                .map(|task| {
+                    let completed = completed.clone();
+                    tokio::spawn(async move {
+                        for round in 0..ROUNDS {
+                            ping_and_wait((task + round) % 3, completed.clone()).await;
+                        }
+                    })
+                })
+                .collect();
+            let all = futures::future::try_join_all(tasks);
+            tokio::time::timeout(Duration::from_secs(20), all)
+                .await
+                .expect("The requests didn't complete, they may be deadlocked")
+                .unwrap();
+
+            // A third of the requests have a callback, which ran once each
+            assert_eq!(completed.load(Ordering::Relaxed), TASKS * ROUNDS / 3);
+            assert_eq!(get_transaction_table_len().await, 0);
+            assert_eq!(get_outgoing_queue_len().await, 0);
+        });
+    }
+
+    #[test]
+    fn callbacks_fire_exactly_once_under_concurrent_responses() {
+        const REQUESTS: usize = 64;
+        const RESPONSES: usize = 8;
+        let _state = lock_global_state();
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(4)
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            clear().await;
+            let mut calls = Vec::new();
+            for _ in 0..REQUESTS {
+                let transaction_id = new_transaction_id().await.unwrap();
+                let called = Arc::new(AtomicUsize::new(0));
+                let completion = Completion::Callback(Box::new({
+                    let called = called.clone();
+                    move |_| {
+                        called.fetch_add(1, Ordering::Relaxed);
+                    }
+                }));
+                let request = P2pRequest::new(0x1a2b, transaction_id, P2pRequestPacket::ping());
+                push_outgoing_queue(request.into(), completion, None)
+                    .await
+                    .unwrap();
+                calls.push((transaction_id, called));
+            }
+            while pop_outgoing_queue().await.is_some() {}
+
+            // The same response comes many times at once, e.g. after resends, while the request
+            // also fails and expires
+            let mut tasks = Vec::new();
+            for &(transaction_id, _) in &calls {
+                for _ in 0..RESPONSES {
+                    tasks.push(tokio::spawn(set_response(pong(transaction_id, 0))));
+                }
+                tasks.push(tokio::spawn(fail_transaction(transaction_id)));
+            }
+            tasks.push(tokio::spawn(expire_transactions(
+                Instant::now() + Duration::from_secs(3600),
+                Duration::ZERO,
+            )));
+            futures::future::try_join_all(tasks).await.unwrap();
+
+            for (transaction_id, called) in calls {
+                assert_eq!(
+                    called.load(Ordering::Relaxed),
+                    1,
+                    "The callback of {:#06x} wasn't called once",
+                    transaction_id
+                );
+            }
+            assert_eq!(get_transaction_table_len().await, 0);
+        });
+    }
+}
@@ -0,0 +1,201 @@
+//! Resyncs the clients board with the hosts after a reconnect. Moves made while the connection
+//! was down may never have reached the other peer, so the boards can differ.
+//!
+//! The scheduler is its own task, listening for changes of the connection status. Every time the
+//! status goes from `Reconnecting` back to `Connected`, a resync is scheduled `RESYNC_SETTLE_MS`
+//! later. Another reconnect within that time pushes the resync back, so a flaky connection only
+//! gives one resync once it has settled.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::net::status::{
+    set_draw_offer, set_pause, set_resync_board, watch_connection_status, ConnectionStatus,
+    HostBoard,
+};
+
+use super::{session::Session, watchdog::Heartbeat, P2pRequestPacket, P2pResponsePacket};
+
+/// How long the connection has to be back, before the resync is sent.
+const RESYNC_SETTLE_MS: u64 = 1_000;
+/// How often the scheduler wakes up, when the status doesn't change.
+const RESYNC_POLL_MS: u64 = 250;
+const RESYNC_TIMEOUT_MS: u64 = 1_000;
+
+/// The task scheduling a resync after every reconnect. Runs on the client.
+pub async fn client_resync_scheduler(heartbeat: Arc<Heartbeat>) {
+    let mut statuses = watch_connection_status();
+    let mut schedule = ResyncSchedule::new(*statuses.borrow());
+
+    loop {
+        heartbeat.bump();
+        // The status sender is static, so the channel is never closed
+        let _ =
+            tokio::time::timeout(Duration::from_millis(RESYNC_POLL_MS), statuses.changed()).await;
+
+        let status = *statuses.borrow_and_update();
+        if schedule.update(status, Instant::now()) {
+            println!("Reconnected to the host");
+            request_resync().await;
+        }
+    }
+}
+
+/// When the next resync is due, from the connection statuses the scheduler has seen.
+struct ResyncSchedule {
+    was_reconnecting: bool,
+    resync_at: Option<Instant>,
+}
+
+impl ResyncSchedule {
+    fn new(status: ConnectionStatus) -> Self {
+        Self {
+            was_reconnecting: status.is_reconnecting(),
+            resync_at: None,
+        }
+    }
+
+    /// Take the status seen at `now`. Returns true if the resync is due, and then schedules no
+    /// other until the next reconnect.
+    fn update(&mut self, status: ConnectionStatus, now: Instant) -> bool {
+        if self.was_reconnecting && status.is_connected() {
+            self.resync_at = Some(now + Duration::from_millis(RESYNC_SETTLE_MS));
+        }
+        self.was_reconnecting = status.is_reconnecting();
+
+        let is_due = self.resync_at.is_some_and(|at| at <= now);
+        if is_due && status.is_connected() {
+            self.resync_at = None;
+            return true;
+        }
+        false
+    }
+}
+
+/// Ask the host for its board and turn. They are left for the game to take, with
+/// `interface::take_resync_board()`. The hosts open draw offer and pause are taken right away, since
+/// the host is always right about them.
+pub async fn request_resync() {
+    println!("Asking the host for its board");
+    match fetch_host_board().await {
+        Ok(board) => {
+            set_draw_offer(board.draw_offer).await;
+            set_pause(board.pause).await;
+            set_resync_board(board).await;
+        }
+        Err(e) => println!("The host didn't send its board: {}", e),
+    }
+}
+
+/// Ask the host for its board and turn, and wait for the answer.
+pub async fn fetch_host_board() -> anyhow::Result<HostBoard> {
+    let response = Session::request(P2pRequestPacket::Resync)
+        .await
+        .send_and_wait(Duration::from_millis(RESYNC_TIMEOUT_MS))
+        .await?;
+
+    match response.packet {
+        P2pResponsePacket::Resync {
+            board,
+            move_number,
+            side_to_move,
+            draw_offer,
+            pause,
+        } => Ok(HostBoard {
+            board: board.to_vec(),
+            move_number,
+            side_to_move,
+            draw_offer,
+            pause,
+        }),
+        packet => Err(anyhow::anyhow!(
+            "Expected the hosts board, got {:?}",
+            packet
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `schedule` the statuses seen at the milliseconds after `start`, and return when the
+    /// resyncs were due.
+    fn resyncs(
+        schedule: &mut ResyncSchedule,
+        start: Instant,
+        seen: &[(u64, ConnectionStatus)],
+    ) -> Vec<u64> {
+        seen.iter()
+            .filter(|(ms, status)| schedule.update(*status, start + Duration::from_millis(*ms)))
+            .map(|(ms, _)| *ms)
+            .collect()
+    }
+
+    const CONNECTED: ConnectionStatus = ConnectionStatus::Connected { ping: 20 };
+    const RECONNECTING: ConnectionStatus = ConnectionStatus::Reconnecting { tries: 1 };
+
+    #[test]
+    fn reconnect_gives_one_resync_once_it_has_settled() {
+        let mut schedule = ResyncSchedule::new(CONNECTED);
+        let seen = [
+            (0, RECONNECTING),
+            (3_000, CONNECTED),
+            (3_999, CONNECTED),
+            (4_000, CONNECTED),
+            (4_250, CONNECTED),
+            (9_000, CONNECTED),
+        ];
+        assert_eq!(resyncs(&mut schedule, Instant::now(), &seen), [4_000]);
+    }
+
+    #[test]
+    fn rapid_reconnects_give_one_resync() {
+        let mut schedule = ResyncSchedule::new(CONNECTED);
+        let seen = [
+            (0, RECONNECTING),
+            (100, CONNECTED),
+            (600, RECONNECTING),
+            (700, CONNECTED),
+            (1_200, RECONNECTING),
+            (1_300, CONNECTED),
+            // A second after the first reconnect, but the last one pushed it back
+            (2_100, CONNECTED),
+            (2_300, CONNECTED),
+            (5_000, CONNECTED),
+        ];
+        assert_eq!(resyncs(&mut schedule, Instant::now(), &seen), [2_300]);
+    }
+
+    #[test]
+    fn resync_waits_until_the_connection_is_back() {
+        let mut schedule = ResyncSchedule::new(RECONNECTING);
+        let seen = [
+            (0, CONNECTED),
+            (500, RECONNECTING),
+            // Due, but there is no connection to resync over
+            (1_000, RECONNECTING),
+            (2_000, RECONNECTING),
+            (2_500, CONNECTED),
+            (3_500, CONNECTED),
+        ];
+        assert_eq!(resyncs(&mut schedule, Instant::now(), &seen), [3_500]);
+    }
+
+    #[test]
+    fn first_connection_gives_no_resync() {
+        let mut schedule = ResyncSchedule::new(ConnectionStatus::Disconnected);
+        let seen = [
+            (0, ConnectionStatus::PendingConnection),
+            (500, ConnectionStatus::connected()),
+            (2_000, CONNECTED),
+            (9_000, ConnectionStatus::Disconnected),
+            (9_500, ConnectionStatus::PendingConnection),
+            (10_000, CONNECTED),
+            (12_000, CONNECTED),
+        ];
+        assert!(resyncs(&mut schedule, Instant::now(), &seen).is_empty());
+    }
+}
@@ -0,0 +1,53 @@
+//! The tokio runtime the network loop runs on.
+//!
+//! ## Threading model
+//! * The Slint event loop runs on the main thread. It calls into `interface`, which blocks on
+//!   short async calls with `executor::block_on`, and is updated from other threads with
+//!   `invoke_from_event_loop`.
+//! * The main tokio runtime, from `#[tokio::main]`, runs everything the UI spawns, like waiting
+//!   for the opponent, the coin flip and the state server.
+//! * The network runtime runs the network loop tasks and their watchdog: The pings, the replies to
+//!   incoming packets and the resends of the outgoing queue. These have deadlines, and a missed
+//!   ping shows up as a spurious `Reconnecting`, so they get their own worker threads that nothing
+//!   else is spawned on. The network sockets are registered with this runtime as well, so their IO
+//!   doesn't wait on a busy main runtime either.
+//!
+//! The two runtimes only share state through `status`, `queue` and `Session`, which all use
+//! runtime independent locks and channels.
+
+use std::future::Future;
+
+use lazy_static::lazy_static;
+use tokio::{
+    runtime::{Builder, EnterGuard, Runtime},
+    task::JoinHandle,
+};
+
+/// The amount of worker threads of the network runtime. The host and client loops have at most
+/// five tasks, which are mostly waiting.
+const NET_WORKER_THREADS: usize = 2;
+
+lazy_static! {
+    static ref NET_RUNTIME: Runtime = Builder::new_multi_thread()
+        .worker_threads(NET_WORKER_THREADS)
+        .thread_name("network")
+        .enable_all()
+        .build()
+        .expect("Failed to start the network runtime");
+}
+
+/// Spawn a task on the network runtime. Only for the network loop, anything else should be spawned
+/// on the main runtime.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    NET_RUNTIME.spawn(future)
+}
+
+/// Enter the network runtime, until the guard is dropped. Sockets created while it's entered are
+/// driven by the network runtime.
+pub fn enter() -> EnterGuard<'static> {
+    NET_RUNTIME.enter()
+}
@@ -0,0 +1,265 @@
+//! The order of the game actions from the other peer.
+//!
+//! UDP can deliver packets in another order than they were sent, e.g. a move and the surrender
+//! right after it. Every `P2pRequestPacket::GameAction` carries a sequence number, counted up by
+//! the sender from 0 in each session, and the reciever hands the actions to the game in that
+//! order. An action that comes before an earlier one waits for it.
+//!
+//! An action the reciever refused, e.g. a move that wasn't the clients turn, still takes up its
+//! sequence number, so the actions after it don't wait for it. If it comes again, it's handled
+//! again, since the sender must get the refusal. Any other action that comes again is a duplicate,
+//! and isn't handed to the game a second time.
+//!
+//! The sender gives up on an action after its resends, so an action that is still missing after
+//! `GAP_TIMEOUT` is skipped, and the actions after it are handed on.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::game::GameAction;
+
+use super::queue::{MAX_RESENDS, RESEND_TIMEOUT};
+
+/// How long the actions after a missing one wait for it, before it's skipped. It's a bit longer
+/// than the sender keeps sending it again.
+pub const GAP_TIMEOUT: Duration = RESEND_TIMEOUT.saturating_mul(MAX_RESENDS as u32 + 2);
+/// The most sequence numbers that are waited for at once. An action further ahead skips the
+/// missing actions it would wait on.
+const MAX_WAITING: usize = 64;
+/// The amount of refused sequence numbers remembered, so they're handled again.
+const MAX_REFUSED: usize = 8;
+
+/// A sequence number that has come.
+enum Slot {
+    Action(GameAction),
+    Refused,
+}
+
+/// The game actions from the other peer, put back in the order they were sent.
+pub struct ActionOrder {
+    /// The sequence number of the next action to hand on.
+    next: u16,
+    /// The sequence numbers from `next` on, as `None` while they haven't come yet.
+    waiting: VecDeque<Option<Slot>>,
+    /// The last refused sequence numbers.
+    refused: VecDeque<u16>,
+    /// Since when the actions have waited for the missing action at `next`.
+    gap_since: Option<Instant>,
+}
+
+impl ActionOrder {
+    pub const fn new() -> Self {
+        Self {
+            next: 0,
+            waiting: VecDeque::new(),
+            refused: VecDeque::new(),
+            gap_since: None,
+        }
+    }
+
+    /// How far `sequence` is after the next action to hand on, or `None` if it's before it. The
+    /// numbers wrap around, so half of them count as before.
+    fn offset(&self, sequence: u16) -> Option<usize> {
+        let offset = sequence.wrapping_sub(self.next);
+        (offset < 0x8000).then_some(offset as usize)
+    }
+
+    /// Returns if the action with `sequence` has come before, and must not be taken again. A
+    /// refused action isn't a duplicate, so it's refused again.
+    pub fn is_duplicate(&self, sequence: u16) -> bool {
+        if self.refused.contains(&sequence) {
+            return false;
+        }
+        match self.offset(sequence) {
+            Some(offset) => matches!(self.waiting.get(offset), Some(Some(_))),
+            None => true,
+        }
+    }
+
+    /// Add the action with `sequence`, to hand it on in order. A duplicate is ignored.
+    ///
+    /// ## Params
+    /// * `sequence` - The sequence number of the action.
+    /// * `action` - The action, or `None` if it was refused.
+    /// * `now` - The time the action came.
+    pub fn add(&mut self, sequence: u16, action: Option<GameAction>, now: Instant) {
+        if action.is_none() && !self.refused.contains(&sequence) {
+            if self.refused.len() == MAX_REFUSED {
+                self.refused.pop_front();
+            }
+            self.refused.push_back(sequence);
+        }
+        if self.is_duplicate(sequence) {
+            return;
+        }
+        let Some(mut offset) = self.offset(sequence) else {
+            return;
+        };
+        while offset >= MAX_WAITING {
+            self.skip_next(now);
+            offset -= 1;
+        }
+
+        if self.waiting.len() <= offset {
+            self.waiting.resize_with(offset + 1, || None);
+        }
+        self.waiting[offset] = Some(match action {
+            Some(action) => Slot::Action(action),
+            None => Slot::Refused,
+        });
+        if offset > 0 && self.gap_since.is_none() {
+            self.gap_since = Some(now);
+        }
+    }
+
+    /// Take the next action in order, if it has come. The missing action the others are waiting
+    /// on is skipped, once they have waited `GAP_TIMEOUT`.
+    ///
+    /// ## Params
+    /// * `now` - The time to measure the wait until.
+    pub fn pop(&mut self, now: Instant) -> Option<GameAction> {
+        loop {
+            match self.waiting.front() {
+                Some(Some(_)) => {}
+                Some(None) => {
+                    let waited = now.saturating_duration_since(self.gap_since?);
+                    if waited < GAP_TIMEOUT {
+                        return None;
+                    }
+                    println!("Game action {} never came, skipping it", self.next);
+                }
+                None => return None,
+            }
+            if let Some(Slot::Action(action)) = self.skip_next(now) {
+                return Some(action);
+            }
+        }
+    }
+
+    /// The amount of actions that have come, and haven't been taken.
+    pub fn len(&self) -> usize {
+        self.waiting
+            .iter()
+            .filter(|slot| matches!(slot, Some(Slot::Action(_))))
+            .count()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Move on to the next sequence number. Returns what came with the current one.
+    fn skip_next(&mut self, now: Instant) -> Option<Slot> {
+        let slot = self.waiting.pop_front().flatten();
+        self.next = self.next.wrapping_add(1);
+        // The actions after a gap wait for it from the time it's the next one
+        self.gap_since = self.waiting.iter().any(Option::is_some).then_some(now);
+        slot
+    }
+}
+
+impl Default for ActionOrder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+    use super::*;
+
+    /// A move told apart from the others by `n`.
+    fn action(n: u16) -> GameAction {
+        GameAction::move_piece(n as usize % 32, (n as usize + 1) % 32, None, false)
+    }
+
+    /// Take every action that can be taken now.
+    fn pop_all(order: &mut ActionOrder, now: Instant) -> Vec<GameAction> {
+        std::iter::from_fn(|| order.pop(now)).collect()
+    }
+
+    #[test]
+    fn shuffled_actions_come_out_in_order() {
+        let now = Instant::now();
+        let expected: Vec<GameAction> = (0..20).map(action).collect();
+        for seed in 0..100 {
+            let mut sequences: Vec<u16> = (0..20).chain([3, 7, 7, 19]).collect();
+            sequences.shuffle(&mut StdRng::seed_from_u64(seed));
+
+            let mut order = ActionOrder::new();
+            let mut taken = vec![];
+            for sequence in sequences {
+                order.add(sequence, Some(action(sequence)), now);
+                taken.extend(pop_all(&mut order, now));
+            }
+            assert_eq!(taken, expected, "seed {}", seed);
+            assert!(order.is_empty());
+        }
+    }
+
+    #[test]
+    fn duplicates_are_not_taken_again() {
+        let now = Instant::now();
+        let mut order = ActionOrder::new();
+        order.add(1, Some(action(1)), now);
+        assert!(order.is_duplicate(1));
+        assert!(!order.is_duplicate(0));
+        order.add(0, Some(action(0)), now);
+        assert_eq!(pop_all(&mut order, now), [action(0), action(1)]);
+
+        for sequence in [0, 1] {
+            assert!(order.is_duplicate(sequence));
+            order.add(sequence, Some(action(sequence)), now);
+        }
+        assert!(order.pop(now).is_none());
+    }
+
+    #[test]
+    fn refused_actions_hold_nothing_up() {
+        let now = Instant::now();
+        let mut order = ActionOrder::new();
+        order.add(2, Some(action(2)), now);
+        order.add(1, None, now);
+        order.add(0, Some(action(0)), now);
+        assert_eq!(pop_all(&mut order, now), [action(0), action(2)]);
+        // A refused action that comes again is handled again
+        assert!(!order.is_duplicate(1));
+    }
+
+    #[test]
+    fn missing_action_is_skipped_after_the_gap_timeout() {
+        let now = Instant::now();
+        let mut order = ActionOrder::new();
+        order.add(1, Some(action(1)), now);
+        order.add(2, Some(action(2)), now);
+        assert!(order
+            .pop(now + GAP_TIMEOUT - Duration::from_millis(1))
+            .is_none());
+        assert_eq!(
+            pop_all(&mut order, now + GAP_TIMEOUT),
+            [action(1), action(2)]
+        );
+        // It's too late for it now
+        assert!(order.is_duplicate(0));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let now = Instant::now();
+        let mut order = ActionOrder {
+            next: u16::MAX - 1,
+            ..ActionOrder::new()
+        };
+        for sequence in [1, u16::MAX, 0, u16::MAX - 1] {
+            order.add(sequence, Some(action(sequence)), now);
+        }
+        let expected = [u16::MAX - 1, u16::MAX, 0, 1].map(action);
+        assert_eq!(pop_all(&mut order, now), expected);
+        assert!(order.is_duplicate(u16::MAX));
+    }
+}
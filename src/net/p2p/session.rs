@@ -0,0 +1,297 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use tokio::sync::oneshot;
+
+use crate::net::status::{add_timed_out_response, get_session_id, CONNECT_SESSION_ID};
+
+use super::{
+    queue::{
+        forget_transaction, new_transaction_id, push_outgoing_queue, Completion, NoTransactionId,
+        TimedOut,
+    },
+    P2pPacket, P2pRequest, P2pRequestPacket, P2pResponse, P2pResponsePacket,
+};
+
+/// Builds the requests and responses of the current session, so their session- and transaction
+/// IDs are always filled in.
+pub struct Session;
+
+impl Session {
+    /// Create a request in the current session, with a fresh transaction ID. If every ID is
+    /// waiting for a response, the request can't be sent, and gives a `NoTransactionId`.
+    pub async fn request(packet: P2pRequestPacket) -> OutgoingRequest {
+        let session_id = get_session_id().await;
+        Self::request_in(session_id, packet).await
+    }
+
+    /// Create a request with the `CONNECT_SESSION_ID`, with a fresh transaction ID.
+    /// This is used by the client before it has joined a session.
+    pub async fn connect_request(packet: P2pRequestPacket) -> OutgoingRequest {
+        Self::request_in(CONNECT_SESSION_ID, packet).await
+    }
+
+    async fn request_in(session_id: u16, packet: P2pRequestPacket) -> OutgoingRequest {
+        let request = new_transaction_id()
+            .await
+            .map(|transaction_id| P2pRequest::new(session_id, transaction_id, packet));
+        OutgoingRequest {
+            request,
+            completion: Completion::Keep,
+            timeout: None,
+        }
+    }
+
+    /// Create the response to `req`. The response gets the transaction ID of the request, and
+    /// its session ID, unless the request was sent with the `CONNECT_SESSION_ID`. In that case the
+    /// current session ID is used, so a joining client learns the ID of its new session.
+    pub async fn respond_to(req: &P2pRequest, packet: P2pResponsePacket) -> P2pResponse {
+        let session_id = if req.session_id == CONNECT_SESSION_ID {
+            get_session_id().await
+        } else {
+            req.session_id
+        };
+        P2pResponse::new(session_id, req.transaction_id, packet)
+    }
+}
+
+/// A request that is ready to be pushed to the outgoing queue.
+pub struct OutgoingRequest {
+    request: Result<P2pRequest, NoTransactionId>,
+    completion: Completion,
+    timeout: Option<Duration>,
+}
+
+impl OutgoingRequest {
+    /// Set the callback that runs when the request gets a response. It runs once, on the task
+    /// that received the response. If the request is resent, and never got a response, it gets a
+    /// `TimedOut` instead.
+    ///
+    /// The task runs on a worker of the network runtime, so the callback should spawn what it
+    /// awaits, instead of blocking on it: A task next in line for a lock of `status` or `queue`
+    /// can be waiting on the same worker.
+    pub fn on_response<F>(mut self, callback: F) -> Self
+    where
+        F: FnOnce(Result<P2pResponse, TimedOut>) + Send + 'static,
+    {
+        self.completion = Completion::Callback(Box::new(callback));
+        self
+    }
+
+    /// Give up on the response after `timeout`, instead of the default of the queue. The callback
+    /// set by `on_response()` gets a `TimedOut` then, and a response kept in the table is gone.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Get the request as a packet, for sending it directly on a socket instead of through the
+    /// outgoing queue. Its response won't be waited for.
+    pub fn into_packet(self) -> Result<P2pPacket, NoTransactionId> {
+        self.request.map(P2pPacket::Request)
+    }
+
+    /// Push the request to the outgoing queue. Returns the transaction ID of the request, a
+    /// `QueueFull` if there is no room for it, or a `NoTransactionId` if it got no ID. Unless a
+    /// callback is set, the response is kept until `check_for_response()` takes it. The callback
+    /// isn't run for a request that wasn't queued.
+    pub async fn send(self) -> anyhow::Result<u16> {
+        let transaction_id = push_outgoing_queue(
+            P2pPacket::Request(self.request?),
+            self.completion,
+            self.timeout,
+        )
+        .await?;
+        Ok(transaction_id)
+    }
+
+    /// Push the request to the outgoing queue, and wait for its response. A full queue gives a
+    /// `QueueFull`, and a request without an ID a `NoTransactionId`.
+    /// The callback set by `on_response()` isn't used, since the response is returned instead, and
+    /// neither is the timeout set by `timeout()`.
+    ///
+    /// ## Params
+    /// * `timeout` - How long to wait for the response, before returning an error.
+    pub async fn send_and_wait(self, timeout: Duration) -> anyhow::Result<P2pResponse> {
+        let (sender, receiver) = oneshot::channel();
+        let transaction_id = push_outgoing_queue(
+            P2pPacket::Request(self.request?),
+            Completion::Send(sender),
+            None,
+        )
+        .await?;
+
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(resp)) => Ok(resp),
+            Ok(Err(_)) => Err(anyhow!("Transaction {} was dropped", transaction_id)),
+            Err(_) => {
+                // The ID isn't handed out again until the counter wraps around, so a late
+                // response can't be delivered to another request
+                forget_transaction(transaction_id).await;
+                add_timed_out_response().await;
+                Err(anyhow!("Transaction {} timed out", transaction_id))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+        time::Instant,
+    };
+
+    use futures::{
+        executor,
+        future::{join_all, Future},
+        task::{waker, ArcWake},
+    };
+
+    use super::*;
+    use crate::net::p2p::{
+        lock_global_state,
+        queue::{
+            expire_transactions, forget_transaction, get_transaction_table_len, pop_outgoing_queue,
+            set_response, TimedOut, TRANSACTION_TTL,
+        },
+        runtime,
+    };
+
+    /// Push a ping with `label` as its payload, and wait for its response.
+    async fn ping(label: u8) -> anyhow::Result<P2pResponse> {
+        let packet = P2pRequestPacket::Ping {
+            payload: vec![label],
+        };
+        Session::request(packet)
+            .await
+            .send_and_wait(Duration::from_secs(10))
+            .await
+    }
+
+    /// Answer every ping in the outgoing queue with a pong of its payload, the last pushed first.
+    async fn answer_pings() -> usize {
+        let mut pings = vec![];
+        while let Some((P2pPacket::Request(req), _)) = pop_outgoing_queue().await {
+            pings.push(req);
+        }
+        for req in pings.iter().rev() {
+            let P2pRequestPacket::Ping { payload } = &req.packet else {
+                panic!("expected a ping, got {:?}", req.packet);
+            };
+            let pong = P2pResponsePacket::Pong {
+                payload: payload.clone(),
+            };
+            set_response(Session::respond_to(req, pong).await).await;
+        }
+        pings.len()
+    }
+
+    #[test]
+    fn concurrent_waiters_get_their_own_response() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        let waiters: Vec<_> = (0..16).map(|label| runtime::spawn(ping(label))).collect();
+        executor::block_on(async {
+            let mut answered = 0;
+            while answered < waiters.len() {
+                answered += answer_pings().await;
+                tokio::task::yield_now().await;
+            }
+            for (label, waiter) in join_all(waiters).await.into_iter().enumerate() {
+                let resp = waiter.unwrap().unwrap();
+                assert_eq!(
+                    resp.packet,
+                    P2pResponsePacket::Pong {
+                        payload: vec![label as u8]
+                    }
+                );
+            }
+        });
+    }
+
+    /// Counts the times it's woken.
+    #[derive(Default)]
+    struct WakeCount(AtomicUsize);
+
+    impl ArcWake for WakeCount {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn waiter_sleeps_until_its_response() {
+        let _state = lock_global_state();
+        let _runtime = runtime::enter();
+        let wakes = Arc::new(WakeCount::default());
+        let waker = waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut waiter = Box::pin(ping(1));
+        assert!(waiter.as_mut().poll(&mut cx).is_pending());
+        // A busy loop would wake itself to check again
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(wakes.0.load(Ordering::Relaxed), 0);
+
+        assert_eq!(executor::block_on(answer_pings()), 1);
+        assert_eq!(wakes.0.load(Ordering::Relaxed), 1);
+        let Poll::Ready(resp) = waiter.as_mut().poll(&mut cx) else {
+            panic!("the response didn't finish the wait");
+        };
+        assert_eq!(
+            resp.unwrap().packet,
+            P2pResponsePacket::Pong { payload: vec![1] }
+        );
+    }
+
+    #[test]
+    fn request_with_a_short_timeout_fails_fast() {
+        type Answer = Arc<std::sync::Mutex<Option<Result<P2pResponse, TimedOut>>>>;
+        let keep = |answer: &Answer| {
+            let answer = answer.clone();
+            move |resp| *answer.lock().unwrap() = Some(resp)
+        };
+        let _state = lock_global_state();
+        executor::block_on(async {
+            let timed = Answer::default();
+            let timed_id = Session::request(P2pRequestPacket::ping())
+                .await
+                .timeout(Duration::from_millis(50))
+                .on_response(keep(&timed))
+                .send()
+                .await
+                .unwrap();
+            let default = Answer::default();
+            let default_id = Session::request(P2pRequestPacket::ping())
+                .await
+                .on_response(keep(&default))
+                .send()
+                .await
+                .unwrap();
+            // The other peer never answers
+            while pop_outgoing_queue().await.is_some() {}
+
+            expire_transactions(Instant::now(), TRANSACTION_TTL).await;
+            assert!(timed.lock().unwrap().is_none());
+            std::thread::sleep(Duration::from_millis(60));
+            expire_transactions(Instant::now(), TRANSACTION_TTL).await;
+            assert_eq!(
+                *timed.lock().unwrap(),
+                Some(Err(TimedOut {
+                    transaction_id: timed_id,
+                    sends: 1,
+                }))
+            );
+
+            // The ping with the default keeps waiting
+            assert_eq!(get_transaction_table_len().await, 1);
+            assert!(default.lock().unwrap().is_none());
+            forget_transaction(default_id).await;
+        });
+    }
+}
@@ -0,0 +1,291 @@
+//! A simulated bad network, for reproducing the flakiness users report. When it's on, every packet
+//! that is sent can be dropped, delayed, duplicated or held back so later packets overtake it. It
+//! sits in front of the real socket, so it also works against a real peer on the LAN.
+//!
+//! Only sent packets are touched, so each peer simulates its own direction. The faults come from a
+//! seeded RNG, so a run with the same seed makes the same decisions for the same packets.
+
+use std::{fmt, str::FromStr, sync::Mutex, time::Duration};
+
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// How much longer than the normal latency a reordered packet is held back.
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// A latency, with random jitter on top of it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Latency {
+    pub base: Duration,
+    /// The most the latency of a packet differs from `base`, in either direction.
+    pub jitter: Duration,
+}
+
+impl FromStr for Latency {
+    type Err = anyhow::Error;
+
+    /// Parse a latency in milliseconds, like `80`, `80±20` or `80+-20`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (base, jitter) = match s.split_once('±').or_else(|| s.split_once("+-")) {
+            Some((base, jitter)) => (base, Some(jitter)),
+            None => (s, None),
+        };
+        let millis = |ms: &str| {
+            ms.trim()
+                .parse::<u64>()
+                .map(Duration::from_millis)
+                .map_err(|_| anyhow!("expected milliseconds like 80 or 80±20, got {:?}", s))
+        };
+        Ok(Self {
+            base: millis(base)?,
+            jitter: jitter.map(millis).transpose()?.unwrap_or_default(),
+        })
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}±{} ms",
+            self.base.as_millis(),
+            self.jitter.as_millis()
+        )
+    }
+}
+
+/// The faults to inject. The chances are in percent, from 0 to 100.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NetworkSimulation {
+    /// The chance a packet is dropped.
+    pub loss: u8,
+    pub latency: Latency,
+    /// The chance a packet is sent twice.
+    pub duplicate: u8,
+    /// The chance a packet is held back, so the packets after it arrive first.
+    pub reorder: u8,
+    /// The seed of the RNG deciding the faults. A random seed is used if `None`.
+    pub seed: Option<u64>,
+}
+
+impl NetworkSimulation {
+    /// If the simulation changes anything.
+    pub fn is_active(&self) -> bool {
+        self.loss > 0
+            || self.latency != Latency::default()
+            || self.duplicate > 0
+            || self.reorder > 0
+    }
+}
+
+/// What the simulation has done to the packets it was given.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SimulationStats {
+    pub packets: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+impl fmt::Display for SimulationStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} packets, {} dropped, {} duplicated, {} reordered",
+            self.packets, self.dropped, self.duplicated, self.reordered
+        )
+    }
+}
+
+/// Decides the fate of each packet.
+pub struct Simulator {
+    config: NetworkSimulation,
+    rng: StdRng,
+    stats: SimulationStats,
+}
+
+impl Simulator {
+    pub fn new(config: NetworkSimulation) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            config,
+            rng,
+            stats: SimulationStats::default(),
+        }
+    }
+
+    fn chance(&mut self, percent: u8) -> bool {
+        self.rng.gen_bool(f64::from(percent.min(100)) / 100.0)
+    }
+
+    fn delay(&mut self) -> Duration {
+        let Latency { base, jitter } = self.config.latency;
+        let jitter = jitter.as_secs_f64() * (self.rng.gen::<f64>() * 2.0 - 1.0);
+        Duration::from_secs_f64((base.as_secs_f64() + jitter).max(0.0))
+    }
+
+    /// The fate of the next packet: How long to wait before sending each copy of it. Empty if
+    /// the packet is dropped.
+    pub fn next_packet(&mut self) -> Vec<Duration> {
+        self.stats.packets += 1;
+        if self.chance(self.config.loss) {
+            self.stats.dropped += 1;
+            return vec![];
+        }
+
+        let mut delay = self.delay();
+        if self.chance(self.config.reorder) {
+            self.stats.reordered += 1;
+            delay += self.config.latency.base + REORDER_DELAY;
+        }
+        let mut delays = vec![delay];
+        if self.chance(self.config.duplicate) {
+            self.stats.duplicated += 1;
+            delays.push(self.delay());
+        }
+        delays
+    }
+
+    pub fn stats(&self) -> SimulationStats {
+        self.stats
+    }
+}
+
+lazy_static! {
+    /// The simulation of the sent packets, if it's on.
+    static ref SIMULATOR: Mutex<Option<Simulator>> = Mutex::new(None);
+}
+
+/// Turn the simulation on with `config`, or off if it doesn't change anything. The stats start
+/// over.
+pub fn set(config: NetworkSimulation) {
+    *SIMULATOR.lock().unwrap() = config.is_active().then(|| Simulator::new(config));
+}
+
+/// The fate of the next packet that is sent, or `None` if the simulation is off. See
+/// `Simulator::next_packet()`.
+pub fn next_packet() -> Option<Vec<Duration>> {
+    SIMULATOR
+        .lock()
+        .unwrap()
+        .as_mut()
+        .map(Simulator::next_packet)
+}
+
+/// What the simulation has done so far, or `None` if it's off.
+pub fn stats() -> Option<SimulationStats> {
+    SIMULATOR.lock().unwrap().as_ref().map(Simulator::stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKETS: u64 = 100_000;
+
+    fn bad_network(seed: u64) -> NetworkSimulation {
+        NetworkSimulation {
+            loss: 10,
+            latency: Latency {
+                base: Duration::from_millis(80),
+                jitter: Duration::from_millis(20),
+            },
+            duplicate: 5,
+            reorder: 20,
+            seed: Some(seed),
+        }
+    }
+
+    /// Assert that `count` of `of` is within half a percentage point of `percent`.
+    fn assert_rate(what: &str, count: u64, of: u64, percent: u8) {
+        let rate = count as f64 / of as f64 * 100.0;
+        assert!(
+            (rate - f64::from(percent)).abs() < 0.5,
+            "{} {:.2}% of the packets, expected {}%",
+            what,
+            rate,
+            percent
+        );
+    }
+
+    #[test]
+    fn faults_come_at_the_configured_rates() {
+        let config = bad_network(710);
+        let mut simulator = Simulator::new(config);
+        let (min, max) = (Duration::from_millis(60), Duration::from_millis(100));
+        let held_back = config.latency.base + REORDER_DELAY;
+        let mut sent = 0;
+        for _ in 0..PACKETS {
+            let delays = simulator.next_packet();
+            if let Some((first, copies)) = delays.split_first() {
+                sent += 1;
+                assert!(copies.len() <= 1, "{} copies", delays.len());
+                assert!(
+                    (min..=max).contains(first)
+                        || (min + held_back..=max + held_back).contains(first),
+                    "{:?}",
+                    first
+                );
+                for copy in copies {
+                    assert!((min..=max).contains(copy), "{:?}", copy);
+                }
+            }
+        }
+
+        let stats = simulator.stats();
+        assert_eq!(stats.packets, PACKETS);
+        assert_eq!(stats.dropped, PACKETS - sent);
+        assert_rate("dropped", stats.dropped, PACKETS, config.loss);
+        // Only the packets that aren't dropped can be held back or duplicated
+        assert_rate("reordered", stats.reordered, sent, config.reorder);
+        assert_rate("duplicated", stats.duplicated, sent, config.duplicate);
+    }
+
+    #[test]
+    fn same_seed_makes_the_same_decisions() {
+        let mut first = Simulator::new(bad_network(7));
+        let mut again = Simulator::new(bad_network(7));
+        let mut other = Simulator::new(bad_network(8));
+        let mut differs = false;
+        for _ in 0..1000 {
+            let fate = first.next_packet();
+            assert_eq!(fate, again.next_packet());
+            differs |= fate != other.next_packet();
+        }
+        assert_eq!(first.stats(), again.stats());
+        assert!(differs, "another seed made the same decisions");
+    }
+
+    #[test]
+    fn certain_faults_always_happen() {
+        let mut lossy = Simulator::new(NetworkSimulation {
+            loss: 100,
+            seed: Some(1),
+            ..Default::default()
+        });
+        let mut doubled = Simulator::new(NetworkSimulation {
+            duplicate: 100,
+            seed: Some(1),
+            ..Default::default()
+        });
+        for _ in 0..1000 {
+            assert!(lossy.next_packet().is_empty());
+            assert_eq!(doubled.next_packet(), [Duration::ZERO; 2]);
+        }
+    }
+
+    #[test]
+    fn simulation_without_faults_is_off() {
+        let _state = crate::net::p2p::lock_global_state();
+        set(NetworkSimulation {
+            seed: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(next_packet(), None);
+        assert_eq!(stats(), None);
+    }
+}
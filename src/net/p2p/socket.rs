@@ -0,0 +1,60 @@
+//! The socket of the network loop, behind a handle it can be swapped in.
+//!
+//! After a laptop wakes from sleep, a bound UDP socket can stop receiving on some platforms, while
+//! sends still look fine. The client notices when it hasn't received anything for
+//! `REBIND_AFTER_MS` while reconnecting, and binds a new socket on a fresh port. The loops get the
+//! socket from the handle every time they use it, so they pick up the new one right away. The host
+//! sees the client's requests come from a new port, and moves the client there through
+//! `AddressMigration`.
+
+use std::{
+    sync::{Arc, RwLock},
+    time::Instant,
+};
+
+use tokio::net::UdpSocket;
+
+/// How long the client can go without receiving anything while reconnecting, before the socket is
+/// rebound.
+pub const REBIND_AFTER_MS: u128 = 3_000;
+
+/// A socket that can be swapped for a new one, while the loops are using it.
+pub struct SharedSocket {
+    socket: RwLock<Arc<UdpSocket>>,
+    last_received: RwLock<Instant>,
+}
+
+impl SharedSocket {
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket: RwLock::new(Arc::new(socket)),
+            last_received: RwLock::new(Instant::now()),
+        }
+    }
+
+    /// The current socket. It shouldn't be kept for longer than one send or receive, so a swap
+    /// is seen.
+    pub fn get(&self) -> Arc<UdpSocket> {
+        self.socket.read().unwrap().clone()
+    }
+
+    /// Note that a packet was received, so the socket still works.
+    pub fn mark_received(&self) {
+        *self.last_received.write().unwrap() = Instant::now();
+    }
+
+    /// If nothing has been received for long enough, that the socket may have stopped working.
+    pub fn is_silent(&self) -> bool {
+        self.last_received.read().unwrap().elapsed().as_millis() >= REBIND_AFTER_MS
+    }
+
+    /// Bind a new socket on a fresh port, and swap it in. The old socket is closed once the loops
+    /// are done with it.
+    pub async fn rebind(&self) -> std::io::Result<()> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+        println!("Rebound the socket to {:?}", socket.local_addr()?);
+        *self.socket.write().unwrap() = Arc::new(socket);
+        self.mark_received();
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+//! The last moves taken from the other peer, so each move is played exactly once.
+//!
+//! UDP can deliver a packet twice, and a duplicate has a new chance to arrive every time a
+//! request is sent again. A move that arrives again is recognised by its move number and its
+//! squares, and is acknowledged again without being pushed to the incoming actions a second time.
+//! The acknowledgement carries nothing, so it's made again instead of being stored.
+//!
+//! The moves are forgotten when a new session starts, since the move numbers start over.
+
+use std::collections::VecDeque;
+
+use tokio::sync::Mutex;
+
+use crate::game::Move;
+
+/// The amount of moves remembered. A duplicate older than this would have to arrive after more
+/// than this many moves of the game, long after the request it belongs to timed out.
+const MAX_TAKEN_MOVES: usize = 8;
+
+static TAKEN_MOVES: Mutex<VecDeque<(u16, Move)>> = Mutex::const_new(VecDeque::new());
+
+/// Remember that the move with `move_number` was taken.
+pub async fn record(move_number: u16, mov: Move) {
+    let mut taken = TAKEN_MOVES.lock().await;
+    if taken.len() == MAX_TAKEN_MOVES {
+        taken.pop_front();
+    }
+    taken.push_back((move_number, mov));
+}
+
+/// Returns true if the same move with `move_number` has already been taken.
+pub async fn contains(move_number: u16, mov: &Move) -> bool {
+    TAKEN_MOVES
+        .lock()
+        .await
+        .iter()
+        .any(|(number, taken)| *number == move_number && taken == mov)
+}
+
+/// Forget the moves, when a new session starts.
+pub async fn clear() {
+    TAKEN_MOVES.lock().await.clear();
+}
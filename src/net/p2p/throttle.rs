@@ -0,0 +1,194 @@
+//! Rate limiting of the requests anyone can send the host: `P2pRequestPacket::Connect` and
+//! `P2pRequestPacket::Probe`.
+//!
+//! Each one makes the host compare a join code, log, and answer, so a buggy client sending them in
+//! a loop keeps the host busy. Every source address gets a token bucket holding `MAX_ATTEMPTS`
+//! attempts, which refills over `WINDOW`. A request from an address with an empty bucket is
+//! answered with `P2pResponsePacket::RetryLater`, telling when the next attempt is let through.
+//!
+//! Addresses are counted by IP, so a client can't get a new bucket by using a new port. Only the
+//! `MAX_ADDRESSES` addresses seen last are remembered, so spoofed source addresses can't make the
+//! host run out of memory. They can push out the bucket of a real address, which just gets a full
+//! bucket the next time.
+
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+/// The attempts an address can make at once.
+const MAX_ATTEMPTS: f64 = 5.0;
+/// The time it takes an empty bucket to fill up again.
+const WINDOW: Duration = Duration::from_secs(10);
+/// The amount of addresses with a bucket.
+const MAX_ADDRESSES: usize = 64;
+
+/// The error when the host refused a request with `P2pError::Throttled`.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+#[error("The host is busy, and asks to try again in {} ms", retry_after.as_millis())]
+pub struct Throttled {
+    /// How long to wait before trying again.
+    pub retry_after: Duration,
+}
+
+/// A request that wasn't let through.
+#[derive(Clone, Copy, Debug)]
+pub struct Refusal {
+    /// How long until the next attempt is let through.
+    pub retry_after: Duration,
+    /// If the address was let through before this, so this is the start of a run of refusals.
+    pub is_first: bool,
+}
+
+struct Bucket {
+    attempts: f64,
+    updated: Instant,
+    refusing: bool,
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Self {
+            attempts: MAX_ATTEMPTS,
+            updated: now,
+            refusing: false,
+        }
+    }
+
+    /// Take an attempt from the bucket, after refilling it with the attempts gained since the last
+    /// time.
+    fn take(&mut self, now: Instant) -> Result<(), Refusal> {
+        let rate = MAX_ATTEMPTS / WINDOW.as_secs_f64();
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.attempts = (self.attempts + elapsed * rate).min(MAX_ATTEMPTS);
+        self.updated = now;
+
+        if self.attempts >= 1.0 {
+            self.attempts -= 1.0;
+            self.refusing = false;
+            return Ok(());
+        }
+
+        let refusal = Refusal {
+            retry_after: Duration::from_secs_f64((1.0 - self.attempts) / rate),
+            is_first: !self.refusing,
+        };
+        self.refusing = true;
+        Err(refusal)
+    }
+}
+
+/// The buckets of the addresses seen last, the most recent at the back.
+pub struct Throttle {
+    buckets: VecDeque<(IpAddr, Bucket)>,
+}
+
+impl Throttle {
+    pub fn new() -> Self {
+        Self {
+            buckets: VecDeque::new(),
+        }
+    }
+
+    /// Take an attempt for `addr`. Returns a `Refusal` if it has made too many.
+    ///
+    /// ## Params
+    /// * `addr` - The IP address the request came from.
+    /// * `now` - The time the request came.
+    pub fn check(&mut self, addr: IpAddr, now: Instant) -> Result<(), Refusal> {
+        let mut bucket = match self.buckets.iter().position(|(ip, _)| *ip == addr) {
+            Some(i) => self.buckets.remove(i).unwrap().1,
+            None => {
+                if self.buckets.len() == MAX_ADDRESSES {
+                    self.buckets.pop_front();
+                }
+                Bucket::new(now)
+            }
+        };
+        let result = bucket.take(now);
+        self.buckets.push_back((addr, bucket));
+        result
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 168, 0, last))
+    }
+
+    #[test]
+    fn throttle_trips_and_recovers() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut throttle = Throttle::new();
+        for _ in 0..5 {
+            assert!(throttle.check(ip(1), start).is_ok());
+        }
+
+        // An attempt comes back every 2 s
+        let refusal = throttle.check(ip(1), start).unwrap_err();
+        assert_eq!(refusal.retry_after, Duration::from_secs(2));
+        assert!(refusal.is_first);
+        let refusal = throttle.check(ip(1), at(1_999)).unwrap_err();
+        assert_eq!(refusal.retry_after, Duration::from_millis(1));
+        assert!(!refusal.is_first);
+
+        assert!(throttle.check(ip(1), at(2_000)).is_ok());
+        // A new run of refusals starts once one was let through
+        assert!(throttle.check(ip(1), at(2_000)).unwrap_err().is_first);
+
+        // Waiting the whole window fills the bucket, but no more
+        let later = at(60_000);
+        for _ in 0..5 {
+            assert!(throttle.check(ip(1), later).is_ok());
+        }
+        assert!(throttle.check(ip(1), later).is_err());
+    }
+
+    #[test]
+    fn addresses_have_buckets_of_their_own() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new();
+        for _ in 0..5 {
+            assert!(throttle.check(ip(1), now).is_ok());
+        }
+        assert!(throttle.check(ip(1), now).is_err());
+        assert!(throttle.check(ip(2), now).is_ok());
+    }
+
+    #[test]
+    fn oldest_address_is_forgotten() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new();
+        for _ in 0..5 {
+            assert!(throttle.check(ip(0), now).is_ok());
+        }
+        for last in 1..MAX_ADDRESSES as u8 {
+            assert!(throttle.check(ip(last), now).is_ok());
+        }
+        // Still remembered with the addresses seen since
+        assert!(throttle.check(ip(0), now).is_err());
+
+        // Seen before the others again, and then pushed out by a new address
+        for last in 1..MAX_ADDRESSES as u8 {
+            assert!(throttle.check(ip(last), now).is_ok());
+        }
+        assert!(throttle.check(ip(MAX_ADDRESSES as u8), now).is_ok());
+        // So it gets a full bucket
+        assert!(throttle.check(ip(0), now).is_ok());
+    }
+}
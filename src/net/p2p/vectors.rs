@@ -0,0 +1,730 @@
+//! Protocol test vectors: Named packets with their exact bytes, generated from the encoders. Other
+//! implementations of the protocol can check themselves against them, and here every vector must
+//! decode and encode to the same bytes again.
+//!
+//! The bytes are the packet without the CRC32 checksum behind it in the datagram, which is the
+//! same for every packet. Each vector is also checked to be dropped with any single bit flipped.
+//!
+//! The vectors are stored in `protocol/vectors.json`, and written with
+//! `cargo run --bin vectors -- gen-vectors`. A change of the wire format shows up as a change of
+//! that file, so it can't go unnoticed in review. `cargo test` checks them, in `tests/vectors.rs`.
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    game::{GameAction, PieceColor, PieceData},
+    net::net_utils::{FromPacket, PacketError, ToPacket},
+};
+
+use super::{
+    coin_flip::COMMITMENT_LEN,
+    communicate::{append_checksum, strip_checksum, MAX_PACKET_SIZE},
+    pause::PauseState,
+    peer_info::{PeerInfo, Platform, MAX_VERSION_LEN},
+    wire, ForeignVersion, GameOverReason, NoteKind, P2pError, P2pPacket, P2pRequest,
+    P2pRequestPacket, P2pResponse, P2pResponsePacket, MAX_CHAT_LEN,
+};
+
+/// The session and transaction ID of every vector, except for connecting.
+const SESSION_ID: u16 = 0x1a2b;
+const TRANSACTION_ID: u16 = 0x0001;
+/// The sequence number of every game action.
+const SEQUENCE: u16 = 0x0003;
+/// The join code of 192.168.0.1:6000.
+const JOIN_CODE: &str = "c0a800011770";
+
+/// The version and platform in every connect packet, so the vectors don't change with the version
+/// of the game.
+fn peer_info() -> PeerInfo {
+    PeerInfo {
+        version: "0.1.0".to_owned(),
+        platform: Platform::Linux,
+    }
+}
+
+/// A packet and its bytes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TestVector {
+    /// A unique name of the case.
+    pub name: String,
+    /// What the packet is.
+    pub description: String,
+    /// The bytes of the packet, hex encoded.
+    pub bytes: String,
+}
+
+/// A vector with the packet it was generated from, which its bytes must decode to.
+struct Case {
+    vector: TestVector,
+    packet: P2pPacket,
+}
+
+fn case(name: &str, description: &str, packet: P2pPacket) -> Case {
+    Case {
+        vector: TestVector {
+            name: name.to_owned(),
+            description: description.to_owned(),
+            bytes: hex::encode(packet.to_packet()),
+        },
+        packet,
+    }
+}
+
+fn request(packet: P2pRequestPacket) -> P2pPacket {
+    P2pRequest::new(SESSION_ID, TRANSACTION_ID, packet).into()
+}
+
+fn response(packet: P2pResponsePacket) -> P2pPacket {
+    P2pResponse::new(SESSION_ID, TRANSACTION_ID, packet).into()
+}
+
+fn move_piece(index: usize, end: usize, captured: Option<Vec<usize>>, promoted: bool) -> P2pPacket {
+    request(P2pRequestPacket::game_action(
+        GameAction::move_piece(index, end, captured, promoted),
+        7,
+        SEQUENCE,
+    ))
+}
+
+/// The board of a new game, with a white king on square 15 and a black king on square 18.
+fn board() -> [PieceData; wire::BOARD_LEN] {
+    let mut board: [PieceData; wire::BOARD_LEN] = std::array::from_fn(|i| PieceData {
+        color: if i < 12 {
+            PieceColor::Black
+        } else {
+            PieceColor::White
+        },
+        is_active: !(12..20).contains(&i),
+        is_king: false,
+    });
+    board[14] = PieceData {
+        color: PieceColor::White,
+        is_active: true,
+        is_king: true,
+    };
+    board[17] = PieceData {
+        color: PieceColor::Black,
+        is_active: true,
+        is_king: true,
+    };
+    board
+}
+
+/// Every `P2pError`, found by trying every byte as an error code.
+fn errors() -> Vec<P2pError> {
+    (0..=u8::MAX)
+        .filter_map(|code| P2pError::try_from(code).ok())
+        .collect()
+}
+
+/// The name of an error in snake case, e.g. `invalid_board`.
+fn error_name(error: P2pError) -> String {
+    let mut name = String::new();
+    for c in format!("{:?}", error).chars() {
+        if c.is_uppercase() && !name.is_empty() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
+/// Generate the test vectors from the encoders.
+pub fn generate() -> Vec<TestVector> {
+    cases().into_iter().map(|case| case.vector).collect()
+}
+
+/// The packets the test vectors are generated from, with at least one of each kind.
+pub fn packets() -> Vec<P2pPacket> {
+    cases().into_iter().map(|case| case.packet).collect()
+}
+
+fn cases() -> Vec<Case> {
+    // The longest username that fits in a connect request, before the preference. Each string has
+    // a 2 byte length
+    let max_username = "a".repeat(
+        MAX_PACKET_SIZE
+            - wire::HEADER_LEN
+            - 1
+            - (2 + JOIN_CODE.len())
+            - 8
+            - peer_info().encoded_len()
+            - 2
+            - 1
+            - wire::CHECKSUM_LEN,
+    );
+    // The most pieces a capture can take on the board
+    let long_capture: Vec<usize> = (5..27).step_by(2).collect();
+    let commitment: Vec<u8> = (0..COMMITMENT_LEN as u8).collect();
+
+    let mut vectors = vec![
+        case(
+            "ping",
+            "A ping without a payload",
+            request(P2pRequestPacket::ping()),
+        ),
+        case(
+            "ping_payload",
+            "A ping with a 16 byte payload",
+            request(P2pRequestPacket::Ping {
+                payload: (0..16).collect(),
+            }),
+        ),
+        case(
+            "connect",
+            "A connect request, before the client has a session",
+            P2pRequest::new(
+                wire::CONNECT_SESSION_ID,
+                TRANSACTION_ID,
+                P2pRequestPacket::Connect {
+                    join_code: JOIN_CODE.to_owned(),
+                    username: "player".to_owned(),
+                    nonce: 0x0102_0304_0506_0708,
+                    peer_info: peer_info(),
+                    preference: None,
+                },
+            )
+            .into(),
+        ),
+        case(
+            "connect_max_username",
+            "A connect request with the longest username that fits in a packet",
+            P2pRequest::new(
+                wire::CONNECT_SESSION_ID,
+                TRANSACTION_ID,
+                P2pRequestPacket::Connect {
+                    join_code: JOIN_CODE.to_owned(),
+                    username: max_username.clone(),
+                    nonce: u64::MAX,
+                    peer_info: peer_info(),
+                    preference: None,
+                },
+            )
+            .into(),
+        ),
+        case(
+            "connect_unicode_username",
+            "A connect request with a username outside of ASCII",
+            P2pRequest::new(
+                wire::CONNECT_SESSION_ID,
+                TRANSACTION_ID,
+                P2pRequestPacket::Connect {
+                    join_code: JOIN_CODE.to_owned(),
+                    username: "Søren ♟".to_owned(),
+                    nonce: 0,
+                    peer_info: peer_info(),
+                    preference: None,
+                },
+            )
+            .into(),
+        ),
+        case(
+            "connect_empty_username",
+            "A connect request without a username, from a client playing anonymously",
+            P2pRequest::new(
+                wire::CONNECT_SESSION_ID,
+                TRANSACTION_ID,
+                P2pRequestPacket::Connect {
+                    join_code: JOIN_CODE.to_owned(),
+                    username: String::new(),
+                    nonce: 0,
+                    peer_info: peer_info(),
+                    preference: None,
+                },
+            )
+            .into(),
+        ),
+        case(
+            "connect_preference",
+            "A connect request from a client that wishes to play Black",
+            P2pRequest::new(
+                wire::CONNECT_SESSION_ID,
+                TRANSACTION_ID,
+                P2pRequestPacket::Connect {
+                    join_code: JOIN_CODE.to_owned(),
+                    username: "player".to_owned(),
+                    nonce: 0,
+                    peer_info: peer_info(),
+                    preference: Some(PieceColor::Black),
+                },
+            )
+            .into(),
+        ),
+        case(
+            "connect_long_version",
+            "A connect request from a peer with the longest version that can be sent, on an \
+             unknown platform",
+            P2pRequest::new(
+                wire::CONNECT_SESSION_ID,
+                TRANSACTION_ID,
+                P2pRequestPacket::Connect {
+                    join_code: JOIN_CODE.to_owned(),
+                    username: "player".to_owned(),
+                    nonce: 0,
+                    peer_info: PeerInfo {
+                        version: format!("1.0.0-{}", "x".repeat(MAX_VERSION_LEN - 6)),
+                        platform: Platform::Unknown,
+                    },
+                    preference: None,
+                },
+            )
+            .into(),
+        ),
+        case(
+            "resync",
+            "A request for the hosts board",
+            request(P2pRequestPacket::Resync),
+        ),
+        case(
+            "move",
+            "Move 7, from index 21 to 17",
+            move_piece(21, 17, None, false),
+        ),
+        case(
+            "move_capture",
+            "Move 7, from index 21 to 12, capturing the piece on 17",
+            move_piece(21, 12, Some(vec![17]), false),
+        ),
+        case(
+            "move_promotion",
+            "Move 7, from index 4 to 0, promoting the piece",
+            move_piece(4, 0, None, true),
+        ),
+        case(
+            "move_capture_11",
+            "Move 7, capturing 11 pieces",
+            move_piece(31, 0, Some(long_capture), true),
+        ),
+        case(
+            "offer_draw",
+            "Move 7 offers a draw",
+            request(P2pRequestPacket::game_action(
+                GameAction::OfferDraw,
+                7,
+                SEQUENCE,
+            )),
+        ),
+        case(
+            "draw_accepted",
+            "Move 7 accepts a draw offer",
+            request(P2pRequestPacket::game_action(
+                GameAction::DrawResponse(true),
+                7,
+                SEQUENCE,
+            )),
+        ),
+        case(
+            "draw_declined",
+            "Move 7 declines a draw offer",
+            request(P2pRequestPacket::game_action(
+                GameAction::DrawResponse(false),
+                7,
+                SEQUENCE,
+            )),
+        ),
+        case(
+            "surrender",
+            "Move 7 surrenders",
+            request(P2pRequestPacket::game_action(
+                GameAction::Surrender,
+                7,
+                SEQUENCE,
+            )),
+        ),
+        case(
+            "resign_match",
+            "Move 7 resigns the rest of the match",
+            request(P2pRequestPacket::game_action(
+                GameAction::ResignMatch,
+                7,
+                SEQUENCE,
+            )),
+        ),
+        case(
+            "pause_request",
+            "Move 7 asks for a pause",
+            request(P2pRequestPacket::game_action(
+                GameAction::PauseRequest,
+                7,
+                SEQUENCE,
+            )),
+        ),
+        case(
+            "resume_request",
+            "Move 7 asks to go on with the paused game",
+            request(P2pRequestPacket::game_action(
+                GameAction::ResumeRequest,
+                7,
+                SEQUENCE,
+            )),
+        ),
+        case(
+            "pause_accepted",
+            "Move 7 accepts a pause",
+            request(P2pRequestPacket::game_action(
+                GameAction::PauseResponse(true),
+                7,
+                SEQUENCE,
+            )),
+        ),
+        case(
+            "challenge",
+            "An address migration challenge",
+            request(P2pRequestPacket::Challenge { token: 0xdead_beef }),
+        ),
+        case(
+            "probe",
+            "A probe for a host",
+            request(P2pRequestPacket::Probe),
+        ),
+        case(
+            "options_ack",
+            "The clients options hash",
+            request(P2pRequestPacket::OptionsAck {
+                options_hash: 0x0123_4567_89ab_cdef,
+            }),
+        ),
+        case(
+            "chat",
+            "A chat message with a character outside ASCII",
+            request(P2pRequestPacket::Chat {
+                message: "Good game ♟".to_owned(),
+            }),
+        ),
+        case(
+            "chat_max",
+            "The longest chat message",
+            request(P2pRequestPacket::Chat {
+                message: "a".repeat(MAX_CHAT_LEN),
+            }),
+        ),
+        case(
+            "disconnect",
+            "The other peer leaving the game",
+            request(P2pRequestPacket::Disconnect),
+        ),
+        case(
+            "game_over_surrender",
+            "White surrenders, so Black wins",
+            request(P2pRequestPacket::game_over(
+                Some(PieceColor::Black),
+                GameOverReason::Surrender,
+            )),
+        ),
+        case(
+            "game_over_no_moves",
+            "Black has no legal move left, so White wins",
+            request(P2pRequestPacket::game_over(
+                Some(PieceColor::White),
+                GameOverReason::NoMoves,
+            )),
+        ),
+        case(
+            "game_over_draw",
+            "A draw offer was accepted, so the game has no winner",
+            request(P2pRequestPacket::game_over(
+                None,
+                GameOverReason::DrawAccepted,
+            )),
+        ),
+        case(
+            "game_over_timeout",
+            "White didn't come back in time, so Black wins",
+            request(P2pRequestPacket::game_over(
+                Some(PieceColor::Black),
+                GameOverReason::Timeout,
+            )),
+        ),
+        case(
+            "rematch_offer",
+            "A rematch offered after the game has ended",
+            request(P2pRequestPacket::RematchOffer),
+        ),
+        case(
+            "board_hash",
+            "The hash of the board after 20 moves",
+            request(P2pRequestPacket::BoardHash {
+                hash: 0x0123_4567_89ab_cdef,
+                move_count: 20,
+            }),
+        ),
+        case(
+            "status_note_typing",
+            "The player is typing a chat message",
+            request(P2pRequestPacket::StatusNote {
+                kind: NoteKind::Typing,
+            }),
+        ),
+        case(
+            "pong",
+            "A pong without a payload",
+            response(P2pResponsePacket::Pong { payload: vec![] }),
+        ),
+        case(
+            "pong_payload",
+            "A pong echoing a 16 byte payload",
+            response(P2pResponsePacket::Pong {
+                payload: (0..16).collect(),
+            }),
+        ),
+        case(
+            "connect_response",
+            "The host accepts the client, which plays Black",
+            response(P2pResponsePacket::Connect {
+                client_color: PieceColor::Black,
+                host_username: "host".to_owned(),
+                host_nonce: 0x0807_0605_0403_0201,
+                peer_info: peer_info(),
+            }),
+        ),
+        case(
+            "connect_response_max_username",
+            "The host accepts the client, with the longest username that fits in a packet",
+            response(P2pResponsePacket::Connect {
+                client_color: PieceColor::White,
+                host_username: "a".repeat(
+                    MAX_PACKET_SIZE
+                        - wire::HEADER_LEN
+                        - 1
+                        - 1
+                        - 8
+                        - peer_info().encoded_len()
+                        - 2
+                        - wire::CHECKSUM_LEN,
+                ),
+                host_nonce: u64::MAX,
+                peer_info: peer_info(),
+            }),
+        ),
+        case(
+            "resync_response",
+            "The hosts board at move 8 with White to move: A new game, with a white king on square 15 and a black king on square 18",
+            response(P2pResponsePacket::resync(
+                board(),
+                8,
+                PieceColor::White,
+                None,
+                PauseState::default(),
+            )),
+        ),
+        case(
+            "resync_response_255",
+            "The hosts board at move 255 with Black to move, the last move number fitting in a byte",
+            response(P2pResponsePacket::resync(
+                board(),
+                255,
+                PieceColor::Black,
+                None,
+                PauseState::default(),
+            )),
+        ),
+        case(
+            "resync_response_256",
+            "The hosts board at move 256 with White to move, the first move number over a byte",
+            response(P2pResponsePacket::resync(
+                board(),
+                256,
+                PieceColor::White,
+                None,
+                PauseState::default(),
+            )),
+        ),
+        case(
+            "resync_response_draw_offer",
+            "The hosts board at move 9 with Black to move, while White's draw offer waits for an answer",
+            response(P2pResponsePacket::resync(
+                board(),
+                9,
+                PieceColor::Black,
+                Some(PieceColor::White),
+                PauseState::default(),
+            )),
+        ),
+        case(
+            "resync_response_paused",
+            "The hosts board at move 9 with Black to move, in a pause Black asked to end",
+            response(P2pResponsePacket::resync(
+                board(),
+                9,
+                PieceColor::Black,
+                None,
+                PauseState {
+                    paused: true,
+                    request: Some(PieceColor::Black),
+                },
+            )),
+        ),
+        case(
+            "acknowledge",
+            "An acknowledgement",
+            response(P2pResponsePacket::Acknowledge),
+        ),
+        case(
+            "challenge_echo",
+            "The answer to a challenge",
+            response(P2pResponsePacket::ChallengeEcho { token: 0xdead_beef }),
+        ),
+        case(
+            "probe_response",
+            "The answer to a probe, from a peer that isn't hosting",
+            response(P2pResponsePacket::ProbeResponse {
+                hosting: false,
+                commitment: None,
+            }),
+        ),
+        case(
+            "probe_response_hosting",
+            "The answer to a probe, from a host with its coin flip commitment",
+            response(P2pResponsePacket::ProbeResponse {
+                hosting: true,
+                commitment: Some(commitment.try_into().unwrap()),
+            }),
+        ),
+        case(
+            "rejected",
+            "A move rejected, since the host is at move 8 with White to move",
+            response(P2pResponsePacket::Rejected {
+                kind: P2pError::NotYourTurn,
+                move_number: 8,
+                side_to_move: PieceColor::White,
+            }),
+        ),
+        case(
+            "retry_later",
+            "A connect refused for sending too many, which may be sent again in 2 seconds",
+            response(P2pResponsePacket::RetryLater {
+                kind: P2pError::Throttled,
+                retry_after_ms: 2_000,
+            }),
+        ),
+        case(
+            "rematch_accepted",
+            "A rematch accepted, where the player who offered it plays White",
+            response(P2pResponsePacket::RematchAnswer {
+                offerer_color: PieceColor::White,
+            }),
+        ),
+        case(
+            "rematch_declined",
+            "A rematch declined",
+            response(P2pResponsePacket::RematchDecline),
+        ),
+    ];
+
+    for error in errors() {
+        vectors.push(case(
+            &format!("error_{}", error_name(error)),
+            &format!("An error response with {:?}", error),
+            response(P2pResponsePacket::error(error)),
+        ));
+    }
+    vectors
+}
+
+/// Check the vectors. Every vector must decode and encode to its own bytes, and must match the
+/// vector generated now, which must decode to the packet it was generated from. Returns the
+/// failures, as the name of the vector and why it failed.
+pub fn verify(vectors: &[TestVector]) -> Vec<(String, anyhow::Error)> {
+    let cases = cases();
+    let generated: Vec<&TestVector> = cases.iter().map(|case| &case.vector).collect();
+    let mut failures = vec![];
+
+    // A packet can decode to another packet that encodes to the same bytes, like an empty
+    // capture list read back as no capture list, so the decoded packet is compared too
+    for case in &cases {
+        if let Err(e) = decodes_to_packet(case) {
+            failures.push((case.vector.name.clone(), e));
+        }
+    }
+
+    for vector in vectors {
+        if let Err(e) = round_trip(vector) {
+            failures.push((vector.name.clone(), e));
+        }
+        match generated.iter().find(|case| case.name == vector.name) {
+            Some(case) if case.bytes != vector.bytes => failures.push((
+                vector.name.clone(),
+                anyhow!("the encoder now gives {}", case.bytes),
+            )),
+            Some(_) => {}
+            None => failures.push((vector.name.clone(), anyhow!("not generated anymore"))),
+        }
+    }
+
+    for case in &generated {
+        if !vectors.iter().any(|vector| vector.name == case.name) {
+            failures.push((case.name.clone(), anyhow!("missing from the vectors")));
+        }
+    }
+    failures
+}
+
+/// Check every byte as a square of a board. The empty square and the 4 kinds of piece must encode
+/// to a byte that decodes to them again, and every other byte must be refused.
+fn decodes_to_packet(case: &Case) -> anyhow::Result<()> {
+    let packet = P2pPacket::from_packet(hex::decode(&case.vector.bytes)?)?;
+    if packet != case.packet {
+        return Err(anyhow!("decodes to {:?}", packet));
+    }
+    Ok(())
+}
+
+fn round_trip(vector: &TestVector) -> anyhow::Result<()> {
+    let bytes = hex::decode(&vector.bytes)?;
+    if bytes.is_empty() {
+        return Err(anyhow!("no bytes"));
+    }
+    let packet = P2pPacket::from_packet(bytes.clone())?;
+    let encoded = packet.to_packet();
+    if encoded != bytes {
+        return Err(anyhow!("encodes to {}", hex::encode(encoded)));
+    }
+    with_flipped_bits(&bytes)?;
+    from_other_version(&packet, bytes)
+}
+
+/// Check that the datagram carrying the vector is dropped with a `PacketError::ChecksumMismatch`,
+/// whichever single bit of it is flipped. That includes the bits of the checksum itself.
+fn with_flipped_bits(bytes: &[u8]) -> anyhow::Result<()> {
+    let mut datagram = bytes.to_vec();
+    append_checksum(&mut datagram);
+    if strip_checksum(&datagram)? != bytes {
+        return Err(anyhow!("isn't the same after its checksum is removed"));
+    }
+    for bit in 0..datagram.len() * 8 {
+        datagram[bit / 8] ^= 1 << (bit % 8);
+        if !matches!(
+            strip_checksum(&datagram),
+            Err(PacketError::ChecksumMismatch { .. })
+        ) {
+            return Err(anyhow!("is let through with bit {} flipped", bit));
+        }
+        datagram[bit / 8] ^= 1 << (bit % 8);
+    }
+    Ok(())
+}
+
+/// Check that the vector is refused as a `ForeignVersion` when it comes from the next protocol
+/// version, except for the `P2pError::ProtocolMismatch` error response, which must still be read.
+fn from_other_version(packet: &P2pPacket, mut bytes: Vec<u8>) -> anyhow::Result<()> {
+    bytes[1] = wire::PROTOCOL_VERSION.wrapping_add(1);
+    let is_mismatch = matches!(
+        packet,
+        P2pPacket::Response(P2pResponse {
+            packet: P2pResponsePacket::Error {
+                kind: P2pError::ProtocolMismatch
+            },
+            ..
+        })
+    );
+    match P2pPacket::from_packet(bytes) {
+        Ok(_) if is_mismatch => Ok(()),
+        Ok(_) => Err(anyhow!("is read from another protocol version")),
+        Err(e) if is_mismatch => Err(e.context("isn't read from another protocol version")),
+        Err(e) => match e.downcast_ref::<ForeignVersion>() {
+            Some(_) => Ok(()),
+            None => Err(e.context("isn't refused as another protocol version")),
+        },
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+
+use super::{clock::JumpDetector, runtime};
+use crate::net::status::{
+    add_task_restart, remove_other_addr, remove_other_peer_info, remove_other_username,
+    set_connection_status, set_session_id, ConnectionStatus, CONNECT_SESSION_ID,
+};
+
+/// How long a task can go without bumping its heartbeat, before it's seen as stalled.
+const STALL_TIME_MS: u64 = 5_000;
+/// How often the watchdog checks up on the supervised tasks.
+const WATCHDOG_INTERVAL_MS: u64 = 500;
+/// How many times the watchdog restarts tasks within `RESTART_WINDOW`, before it gives up on the
+/// connection. A task that keeps failing right after it's restarted hits this quickly, while one
+/// that fails now and then is restarted every time.
+pub const MAX_TASK_RESTARTS: usize = 5;
+/// How far back the restarts are counted against `MAX_TASK_RESTARTS`.
+pub const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// The point in time all heartbeats are measured from.
+    static ref EPOCH: Instant = Instant::now();
+}
+
+/// Counts up every time the network loop is stopped. A watchdog stops its tasks when this
+/// changes from what it was when the watchdog started.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Stop the running network loop, and wait until the watchdog has stopped its tasks.
+pub async fn stop_network_loop() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_millis(WATCHDOG_INTERVAL_MS * 2)).await;
+}
+
+/// A timestamp a supervised task bumps every time it goes through its loop, to tell the watchdog
+/// that it is still alive.
+#[derive(Default)]
+pub struct Heartbeat(AtomicU64);
+
+impl Heartbeat {
+    /// Tell the watchdog that the task is still alive.
+    pub fn bump(&self) {
+        self.0.store(now_ms(), Ordering::Relaxed);
+    }
+
+    fn is_stale(&self) -> bool {
+        now_ms().saturating_sub(self.0.load(Ordering::Relaxed)) >= STALL_TIME_MS
+    }
+}
+
+fn now_ms() -> u64 {
+    EPOCH.elapsed().as_millis() as u64
+}
+
+/// The times of the restarts within the last `RESTART_WINDOW`, the latest at the back.
+#[derive(Default)]
+pub struct RestartWindow {
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a restart at `now`. Returns the amount of restarts within the window, this one
+    /// included.
+    pub fn add(&mut self, now: Instant) -> usize {
+        while self
+            .restarts
+            .front()
+            .is_some_and(|restart| now.saturating_duration_since(*restart) >= RESTART_WINDOW)
+        {
+            self.restarts.pop_front();
+        }
+        self.restarts.push_back(now);
+        self.restarts.len()
+    }
+}
+
+type TaskFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+type TaskBuilder = Box<dyn Fn(Arc<Heartbeat>) -> TaskFuture + Send + Sync>;
+
+struct SupervisedTask {
+    name: &'static str,
+    heartbeat: Arc<Heartbeat>,
+    build: TaskBuilder,
+    handle: JoinHandle<()>,
+}
+
+impl SupervisedTask {
+    fn spawn(name: &'static str, build: TaskBuilder) -> Self {
+        let heartbeat = Arc::new(Heartbeat::default());
+        heartbeat.bump();
+        let handle = runtime::spawn(build(heartbeat.clone()));
+
+        Self {
+            name,
+            heartbeat,
+            build,
+            handle,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.handle.abort();
+        self.heartbeat.bump();
+        self.handle = runtime::spawn((self.build)(self.heartbeat.clone()));
+    }
+}
+
+/// Keeps the network loop tasks running. The tasks and the watchdog run on the network runtime,
+/// see `runtime`.
+/// Every task is built from a closure, so when a task panics or stops bumping its `Heartbeat`, the
+/// watchdog can build and spawn it again with the same shared socket and state.
+#[derive(Default)]
+pub struct Supervisor {
+    tasks: Vec<SupervisedTask>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spawn a task that the watchdog will look after.
+    ///
+    /// ## Params
+    /// * `name` - The name used when logging about the task.
+    /// * `build` - Builds the task. It is called again every time the task has to be restarted.
+    pub fn spawn<F, Fut>(&mut self, name: &'static str, build: F)
+    where
+        F: Fn(Arc<Heartbeat>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        println!("Starting {}", name);
+        let build: TaskBuilder = Box::new(move |heartbeat| Box::pin(build(heartbeat)));
+        self.tasks.push(SupervisedTask::spawn(name, build));
+    }
+
+    /// Start the watchdog task, which checks up on all the spawned tasks.
+    /// If the tasks together have been restarted more than `MAX_TASK_RESTARTS` times within
+    /// `RESTART_WINDOW`, the watchdog stops them all and sets the connection as disconnected.
+    /// The tasks are also stopped when `stop_network_loop()` is called.
+    pub fn start(mut self) {
+        let generation = GENERATION.load(Ordering::SeqCst);
+        runtime::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(WATCHDOG_INTERVAL_MS));
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut jumps = JumpDetector::new();
+            let mut window = RestartWindow::new();
+            loop {
+                interval.tick().await;
+
+                // Every heartbeat is old after the computer slept, but the tasks haven't stalled,
+                // so they get the time to bump it again instead of all being restarted
+                if jumps.check().is_some() {
+                    for task in &self.tasks {
+                        task.heartbeat.bump();
+                    }
+                    continue;
+                }
+
+                if GENERATION.load(Ordering::SeqCst) != generation {
+                    println!("Stopping the network loop");
+                    for task in &self.tasks {
+                        task.handle.abort();
+                    }
+                    return;
+                }
+
+                for task in &mut self.tasks {
+                    if task.handle.is_finished() {
+                        match (&mut task.handle).await {
+                            Err(e) if e.is_panic() => {
+                                let payload = e.into_panic();
+                                let reason = payload
+                                    .downcast_ref::<&str>()
+                                    .map(|s| s.to_string())
+                                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                                    .unwrap_or("Unknown panic payload".to_owned());
+                                println!("Task \"{}\" panicked: {}", task.name, reason);
+                            }
+                            _ => println!("Task \"{}\" stopped", task.name),
+                        }
+                    } else if task.heartbeat.is_stale() {
+                        println!("Task \"{}\" stalled", task.name);
+                    } else {
+                        continue;
+                    }
+
+                    add_task_restart().await;
+                    let restarts = window.add(Instant::now());
+                    if restarts > MAX_TASK_RESTARTS {
+                        println!(
+                            "Restarted network tasks {} times in {} s, giving up on the connection",
+                            MAX_TASK_RESTARTS,
+                            RESTART_WINDOW.as_secs()
+                        );
+                        for task in &self.tasks {
+                            task.handle.abort();
+                        }
+                        set_connection_status(ConnectionStatus::Disconnected).await;
+                        remove_other_addr().await;
+                        remove_other_username().await;
+                        remove_other_peer_info().await;
+                        set_session_id(CONNECT_SESSION_ID).await;
+                        return;
+                    }
+
+                    println!(
+                        "Restarting task \"{}\" ({} / {} in {} s)",
+                        task.name,
+                        restarts,
+                        MAX_TASK_RESTARTS,
+                        RESTART_WINDOW.as_secs()
+                    );
+                    task.restart();
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU32;
+
+    use super::*;
+
+    #[test]
+    fn restarts_in_a_burst_give_up() {
+        let mut window = RestartWindow::new();
+        let now = Instant::now();
+        for i in 1..=MAX_TASK_RESTARTS {
+            assert_eq!(window.add(now + Duration::from_millis(i as u64 * 1_200)), i);
+        }
+        assert!(window.add(now + Duration::from_secs(8)) > MAX_TASK_RESTARTS);
+    }
+
+    #[test]
+    fn restarts_far_apart_never_give_up() {
+        let mut window = RestartWindow::new();
+        let now = Instant::now();
+        let apart = RESTART_WINDOW / MAX_TASK_RESTARTS as u32;
+        for i in 0..100 {
+            assert!(window.add(now + apart * i) <= MAX_TASK_RESTARTS);
+        }
+    }
+
+    #[test]
+    fn restarts_are_forgotten_after_the_window() {
+        let mut window = RestartWindow::new();
+        let now = Instant::now();
+        for _ in 0..MAX_TASK_RESTARTS {
+            window.add(now);
+        }
+        assert_eq!(window.add(now + RESTART_WINDOW), 1);
+    }
+
+    #[test]
+    fn panicked_task_is_restarted() {
+        let runs = Arc::new(AtomicU32::new(0));
+        let mut supervisor = Supervisor::new();
+        supervisor.spawn("Test task", {
+            let runs = runs.clone();
+            move |heartbeat| {
+                let runs = runs.clone();
+                async move {
+                    // The first run panics, like a task hitting a bug
+                    if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                        panic!("injected panic");
+                    }
+                    loop {
+                        heartbeat.bump();
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                    }
+                }
+            }
+        });
+        supervisor.start();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while runs.load(Ordering::SeqCst) < 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        // The restarted task is healthy, so it isn't restarted again
+        std::thread::sleep(Duration::from_millis(WATCHDOG_INTERVAL_MS * 3));
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        GENERATION.fetch_add(1, Ordering::SeqCst);
+    }
+}
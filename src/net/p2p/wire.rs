@@ -0,0 +1,143 @@
+//! Every number that is sent over the wire. Both the encoding and the decoding of packets use
+//! these, so they can't drift apart. Each module is its own namespace, where every value is unique.
+
+/// The session ID used by a client that hasn't joined a session yet.
+pub const CONNECT_SESSION_ID: u16 = 0x15f4;
+
+/// The version of the protocol, sent in the header of every packet. Bump it on every change of the
+/// wire format, so peers of different versions refuse each other instead of misreading packets.
+///
+/// The header, the checksum, and the `P2pError::ProtocolMismatch` error response telling a peer it
+/// speaks another version, must stay the same in every version, so the peers can still tell each
+/// other.
+pub const PROTOCOL_VERSION: u8 = 17;
+
+/// The size of the header in front of the packet in every `P2pRequest` and `P2pResponse`: The
+/// kind byte, the protocol version, the session ID and the transaction ID.
+pub const HEADER_LEN: usize = 6;
+
+/// The size of the CRC32 checksum after the packet in every datagram, as 4 BE bytes. It's added
+/// when sending and removed when recieving, so the packets themselves don't include it.
+pub const CHECKSUM_LEN: usize = 4;
+
+/// The size of the header in front of the part of a packet in every fragment: The kind byte, the
+/// protocol version, the message ID, the index of the fragment and the amount of fragments.
+pub const FRAGMENT_HEADER_LEN: usize = 6;
+
+/// The most fragments a packet can be split into, since the amount is sent in one byte. It also
+/// bounds the memory taken by a packet that is being put back together.
+pub const MAX_FRAGMENTS: usize = 16;
+
+/// The amount of squares in the board sent in a `P2pResponsePacket::Resync`, one byte each.
+pub const BOARD_LEN: usize = 32;
+
+/// The first byte of every datagram, telling if it's a request, a response, or a fragment of a
+/// packet too large for one datagram.
+pub mod kind {
+    pub const REQUEST: u8 = 0;
+    pub const RESPONSE: u8 = 1;
+    pub const FRAGMENT: u8 = 2;
+}
+
+/// The type codes of `P2pRequestPacket`.
+pub mod request {
+    pub const PING: u8 = 1;
+    pub const CONNECT: u8 = 2;
+    pub const RESYNC: u8 = 3;
+    pub const GAME_ACTION: u8 = 4;
+    pub const CHALLENGE: u8 = 5;
+    pub const PROBE: u8 = 6;
+    pub const OPTIONS_ACK: u8 = 7;
+    pub const CHAT: u8 = 8;
+    pub const DISCONNECT: u8 = 9;
+    pub const GAME_OVER: u8 = 10;
+    pub const REMATCH_OFFER: u8 = 11;
+    pub const BOARD_HASH: u8 = 12;
+    pub const STATUS_NOTE: u8 = 13;
+}
+
+/// The type codes of `P2pResponsePacket`.
+pub mod response {
+    pub const ERROR: u8 = 0;
+    pub const PONG: u8 = 1;
+    pub const CONNECT: u8 = 2;
+    pub const RESYNC: u8 = 3;
+    pub const ACKNOWLEDGE: u8 = 4;
+    pub const CHALLENGE_ECHO: u8 = 5;
+    pub const PROBE_RESPONSE: u8 = 6;
+    pub const REJECTED: u8 = 7;
+    pub const RETRY_LATER: u8 = 8;
+    pub const REMATCH_ANSWER: u8 = 9;
+    pub const REMATCH_DECLINE: u8 = 10;
+}
+
+/// The type codes of `GameAction`.
+pub mod action {
+    pub const MOVE_PIECE: u8 = 0;
+    pub const OFFER_DRAW: u8 = 1;
+    pub const SURRENDER: u8 = 2;
+    pub const DRAW_RESPONSE: u8 = 3;
+    pub const RESIGN_MATCH: u8 = 4;
+    pub const PAUSE_REQUEST: u8 = 5;
+    pub const RESUME_REQUEST: u8 = 6;
+    pub const PAUSE_RESPONSE: u8 = 7;
+}
+
+/// The codes of `P2pError`.
+pub mod error {
+    pub const INVALID_BOARD: u8 = 0;
+    pub const INVALID_JOIN_CODE: u8 = 1;
+    pub const INVALID_SESSION_ID: u8 = 2;
+    pub const FULL_GAME_SESSION: u8 = 3;
+    pub const WRONG_DIRECTION: u8 = 4;
+    pub const NOT_YOUR_TURN: u8 = 5;
+    pub const OPTIONS_MISMATCH: u8 = 6;
+    pub const THROTTLED: u8 = 7;
+    pub const PROTOCOL_MISMATCH: u8 = 8;
+    pub const INVALID_MOVE: u8 = 9;
+    pub const INVALID_USERNAME: u8 = 10;
+    pub const GAME_IN_PROGRESS: u8 = 11;
+    pub const NO_COMMITMENT: u8 = 12;
+}
+
+/// The codes of `PieceColor`.
+pub mod color {
+    /// No color, e.g. the winner of a game that ended in a draw.
+    pub const NONE: u8 = 0;
+    pub const WHITE: u8 = 1;
+    pub const BLACK: u8 = 2;
+}
+
+/// The codes of `GameOverReason`.
+pub mod game_over {
+    pub const SURRENDER: u8 = 0;
+    pub const NO_MOVES: u8 = 1;
+    pub const DRAW_ACCEPTED: u8 = 2;
+    pub const TIMEOUT: u8 = 3;
+}
+
+/// The codes of `NoteKind`.
+pub mod note {
+    pub const TYPING: u8 = 0;
+}
+
+/// The codes of `peer_info::Platform`. An unknown code is read as `UNKNOWN`.
+pub mod platform {
+    pub const UNKNOWN: u8 = 0;
+    pub const WINDOWS: u8 = 1;
+    pub const LINUX: u8 = 2;
+    pub const MACOS: u8 = 3;
+}
+
+/// The bits of a `PieceData` byte.
+pub mod piece {
+    /// An empty square. It has no color and is never a king.
+    pub const EMPTY: u8 = 0;
+    pub const WHITE: u8 = 0b001;
+    pub const BLACK: u8 = 0b010;
+    pub const KING: u8 = 0b100;
+    /// The bits holding the color.
+    pub const COLOR_MASK: u8 = WHITE | BLACK;
+    /// Every bit a piece can have set. A byte with any other bit set isn't a piece.
+    pub const PIECE_MASK: u8 = COLOR_MASK | KING;
+}
@@ -0,0 +1,246 @@
+//! A log file per session, so a bug report about a game can come with the log of that game only.
+//!
+//! A log is opened when the session ID is set, and closed when it's reset. The file is named by
+//! the time and the session ID, e.g. `20240501_193000_session_1a2b.log`, in `SESSION_LOG_DIR`. Only
+//! the last `MAX_SESSION_LOGS` logs are kept.
+//!
+//! Logging never blocks on the disk: Lines are sent over a channel to a writer thread, which owns
+//! the file. `flush()` waits for the writer to catch up, and is called when the game exits and
+//! when it panics.
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use chrono::Local;
+use lazy_static::lazy_static;
+
+/// The directory the session logs are written to, in the working directory.
+const SESSION_LOG_DIR: &str = "session_logs";
+/// The amount of session logs kept. The oldest are deleted when a new one is opened.
+const MAX_SESSION_LOGS: usize = 20;
+
+enum LogMessage {
+    Open(u16),
+    Line(String),
+    Close,
+    Flush(mpsc::Sender<()>),
+}
+
+lazy_static! {
+    static ref SENDER: Mutex<mpsc::Sender<LogMessage>> = Mutex::new(start_writer());
+}
+
+fn start_writer() -> mpsc::Sender<LogMessage> {
+    start_writer_in(PathBuf::from(SESSION_LOG_DIR))
+}
+
+/// Start a writer thread, which writes the logs to `dir`.
+fn start_writer_in(dir: PathBuf) -> mpsc::Sender<LogMessage> {
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name("session log".to_owned())
+        .spawn(move || write_logs(receiver, &dir))
+        .expect("Failed to start the session log writer");
+    sender
+}
+
+fn send(message: LogMessage) {
+    // The writer only stops if it panicked, and then there is nothing to log to
+    let _ = SENDER.lock().unwrap().send(message);
+}
+
+/// Open the log of a new session. The log of the last session is closed.
+///
+/// ## Params
+/// * `session_id` - The ID of the new session.
+pub fn open(session_id: u16) {
+    // The tests set session IDs too, but shouldn't leave logs behind
+    if cfg!(test) {
+        return;
+    }
+    send(LogMessage::Open(session_id));
+}
+
+/// Close the log of the session.
+pub fn close() {
+    send(LogMessage::Close);
+}
+
+/// Write a line to the log of the session, with the time. Nothing is written between sessions.
+pub fn log(line: impl Into<String>) {
+    send(LogMessage::Line(line.into()));
+}
+
+/// Wait until everything logged so far is written to the disk, for at most `timeout`.
+pub fn flush(timeout: Duration) {
+    let (done, wait) = mpsc::channel();
+    send(LogMessage::Flush(done));
+    let _ = wait.recv_timeout(timeout);
+}
+
+/// The writer thread. Owns the log file of the current session, in `dir`.
+fn write_logs(receiver: mpsc::Receiver<LogMessage>, dir: &Path) {
+    let mut file: Option<BufWriter<File>> = None;
+
+    for message in receiver {
+        let result = match message {
+            LogMessage::Open(session_id) => {
+                if let Some(mut file) = file.take() {
+                    let _ = file.flush();
+                }
+                open_file(dir, session_id).map(|opened| file = Some(opened))
+            }
+            LogMessage::Line(line) => match &mut file {
+                Some(file) => writeln!(file, "{} {}", Local::now().format("%H:%M:%S%.3f"), line),
+                None => Ok(()),
+            },
+            LogMessage::Close => match file.take() {
+                Some(mut file) => file.flush(),
+                None => Ok(()),
+            },
+            LogMessage::Flush(done) => {
+                let result = file.as_mut().map_or(Ok(()), |file| file.flush());
+                let _ = done.send(());
+                result
+            }
+        };
+
+        if let Err(e) = result {
+            println!("Failed to write the session log: {}", e);
+        }
+    }
+}
+
+fn open_file(dir: &Path, session_id: u16) -> std::io::Result<BufWriter<File>> {
+    fs::create_dir_all(dir)?;
+
+    let name = format!(
+        "{}_session_{:04x}.log",
+        Local::now().format("%Y%m%d_%H%M%S"),
+        session_id
+    );
+    let file = File::create(dir.join(name))?;
+    remove_old_logs(dir)?;
+
+    let mut file = BufWriter::new(file);
+    writeln!(file, "session: {:#06x}", session_id)?;
+    Ok(file)
+}
+
+/// Delete the oldest logs, so only `MAX_SESSION_LOGS` are left. The names start with the time, so
+/// they sort oldest first.
+fn remove_old_logs(dir: &Path) -> std::io::Result<()> {
+    let mut logs: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .collect();
+    logs.sort();
+
+    let excess = logs.len().saturating_sub(MAX_SESSION_LOGS);
+    for path in &logs[..excess] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// An empty directory for the test `name`, which isn't used by other tests.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("the_checker_mater-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The names of the logs in `dir`, oldest first.
+    fn log_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Send `messages` to a writer of its own in `dir`, and wait until they are written.
+    fn write(dir: &Path, messages: Vec<LogMessage>) {
+        let sender = start_writer_in(dir.to_owned());
+        for message in messages {
+            sender.send(message).unwrap();
+        }
+        let (done, wait) = mpsc::channel();
+        sender.send(LogMessage::Flush(done)).unwrap();
+        wait.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn each_session_gets_a_log_of_its_own() {
+        let dir = temp_dir("session_logs");
+        write(
+            &dir,
+            vec![
+                LogMessage::Line("before any session".to_owned()),
+                LogMessage::Open(0x1a2b),
+                LogMessage::Line("connected".to_owned()),
+                LogMessage::Line("move 22-18".to_owned()),
+                // The next session closes the log of the first one
+                LogMessage::Open(0x1a2c),
+                LogMessage::Line("reconnected".to_owned()),
+                LogMessage::Close,
+                LogMessage::Line("after the session".to_owned()),
+            ],
+        );
+
+        let names = log_names(&dir);
+        assert_eq!(names.len(), 2, "{:?}", names);
+        assert!(names[0].ends_with("_session_1a2b.log"), "{:?}", names);
+        assert!(names[1].ends_with("_session_1a2c.log"), "{:?}", names);
+        let lines = |name: &str| -> Vec<String> {
+            let log = fs::read_to_string(dir.join(name)).unwrap();
+            let mut lines = log.lines();
+            let header = lines.next().unwrap().to_owned();
+            // Without the time
+            let logged = lines.map(|line| line.split_once(' ').unwrap().1.to_owned());
+            [header].into_iter().chain(logged).collect()
+        };
+        assert_eq!(
+            lines(&names[0]),
+            ["session: 0x1a2b", "connected", "move 22-18"]
+        );
+        assert_eq!(lines(&names[1]), ["session: 0x1a2c", "reconnected"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_the_last_logs_are_kept() {
+        let dir = temp_dir("session_log_rotation");
+        let old: Vec<String> = (0..MAX_SESSION_LOGS)
+            .map(|day| format!("200001{:02}_120000_session_0001.log", day + 1))
+            .collect();
+        for name in &old {
+            fs::write(dir.join(name), "session: 0x0001\n").unwrap();
+        }
+        // Only logs are rotated
+        fs::write(dir.join("notes.txt"), "").unwrap();
+
+        write(&dir, vec![LogMessage::Open(0x1a2b), LogMessage::Close]);
+
+        let names = log_names(&dir);
+        assert_eq!(names.len(), MAX_SESSION_LOGS + 1, "{:?}", names);
+        assert_eq!(names[..MAX_SESSION_LOGS - 1], old[1..]);
+        assert!(names[MAX_SESSION_LOGS - 1].ends_with("_session_1a2b.log"));
+        assert_eq!(names[MAX_SESSION_LOGS], "notes.txt");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
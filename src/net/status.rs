@@ -1,15 +1,27 @@
-use std::net::SocketAddr;
+use std::{collections::VecDeque, fmt, net::SocketAddr, sync::RwLock, time::Duration};
 
-use tokio::sync::Mutex;
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
 
-pub const CONNECT_SESSION_ID: u16 = 0x15f4;
+use crate::game::{options::GameOptions, GameAction, PieceColor, PieceData};
 
+use super::{
+    checked_lock::CheckedMutex,
+    p2p::{pause::PauseState, peer_info::PeerInfo, GameOverReason, P2pRequest},
+    session_log,
+};
+
+pub use super::p2p::wire::CONNECT_SESSION_ID;
+
+/// The `ping` of `Connected` is the smoothed round trip in microseconds, the `average` of the
+/// `NetworkStats`. See `ping_micros()`.
 #[derive(Clone, Copy, Debug)]
 pub enum ConnectionStatus {
     Disconnected,
     PendingConnection,
     Reconnecting { tries: u8 },
-    Connected { ping: u128 },
+    Connected { ping: u32 },
 }
 
 impl ConnectionStatus {
@@ -27,6 +39,7 @@ impl ConnectionStatus {
     pub fn is_reconnecting(&self) -> bool {
         matches!(self, Self::Reconnecting { tries: _ })
     }
+    #[allow(dead_code)]
     pub fn can_send(&self) -> bool {
         match self {
             Self::Disconnected => false,
@@ -36,22 +49,311 @@ impl ConnectionStatus {
         }
     }
 }
+
+/// If the peers have agreed on the `GameOptions`. See `P2pRequestPacket::OptionsAck`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OptionsState {
+    /// Not connected, or waiting for the clients `OptionsAck`.
+    Pending,
+    /// Both peers play with the same options, so the game can start.
+    Agreed,
+    /// The peers play with different options, so the client was dropped.
+    Mismatch,
+}
+
+/// The amount of round trips the ping statistics are taken over.
+const PING_WINDOW: usize = 20;
+/// How many times the median the 95th percentile must be, before it's shown as a spike.
+const SPIKE_FACTOR: u32 = 2;
+/// How much of the way to each new round trip the average moves, like the smoothed round trip of
+/// TCP.
+const AVERAGE_GAIN: f64 = 1.0 / 8.0;
+/// How much of the way to the deviation of each new round trip the jitter moves.
+const JITTER_GAIN: f64 = 1.0 / 4.0;
+/// The most deviations above the average a round trip counts as in the average. The deviation is
+/// at least a quarter of the average, so a steady ping can still change.
+const OUTLIER_DEVIATIONS: f64 = 4.0;
+
+/// Statistics of the round trips of the last `PING_WINDOW` pings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetworkStats {
+    pub median: Duration,
+    pub p95: Duration,
+    /// The smoothed round trip, an exponential moving average from the oldest round trip to the
+    /// newest. A single slow round trip only moves it a little, but a lasting change shows within
+    /// a few pings. This is the ping shown to the user.
+    pub average: Duration,
+    /// The smoothed deviation of the round trips from the `average`.
+    pub jitter: Duration,
+    /// The newest round trip.
+    pub last: Duration,
+    /// The amount of round trips the statistics are taken over.
+    pub samples: usize,
+}
+
+impl NetworkStats {
+    /// The statistics of `round_trips`, the oldest first, or `None` if there are none.
+    pub(crate) fn new(round_trips: &VecDeque<Duration>) -> Option<Self> {
+        let mut sorted: Vec<Duration> = round_trips.iter().copied().collect();
+        sorted.sort();
+        // Nearest rank percentiles
+        let percentile = |p: usize| sorted[(sorted.len() * p).div_ceil(100).max(1) - 1];
+        let (average, jitter) = smooth(round_trips)?;
+        Some(Self {
+            median: percentile(50),
+            p95: percentile(95),
+            average,
+            jitter,
+            last: *round_trips.back()?,
+            samples: sorted.len(),
+        })
+    }
+
+    /// If some pings took much longer than usual.
+    pub fn has_spike(&self) -> bool {
+        self.p95 > self.median * SPIKE_FACTOR
+    }
+}
+
+/// The average and the jitter of `round_trips`, the oldest first. The average starts at the first
+/// round trip, and the jitter at zero. Each round trip after it counts as at most
+/// `OUTLIER_DEVIATIONS` deviations above the average, while the jitter takes its full deviation.
+fn smooth(round_trips: &VecDeque<Duration>) -> Option<(Duration, Duration)> {
+    let mut samples = round_trips.iter().map(Duration::as_secs_f64);
+    let mut average = samples.next()?;
+    let mut jitter: f64 = 0.0;
+    for sample in samples {
+        let cap = average + OUTLIER_DEVIATIONS * jitter.max(average / 4.0);
+        let deviation = (sample - average).abs();
+        average += AVERAGE_GAIN * (sample.min(cap) - average);
+        jitter += JITTER_GAIN * (deviation - jitter);
+    }
+    Some((
+        Duration::from_secs_f64(average),
+        Duration::from_secs_f64(jitter),
+    ))
+}
+
+/// How many pings we have sent, and how many of them got no pong in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PingLoss {
+    pub sent: u32,
+    pub lost: u32,
+}
+
+impl PingLoss {
+    /// The share of the pings that were lost, in percent.
+    pub fn percent(&self) -> f64 {
+        match self.sent {
+            0 => 0.0,
+            sent => f64::from(self.lost) * 100.0 / f64::from(sent),
+        }
+    }
+}
+
+/// Counters of the traffic with the other peer in this session. They start over when a new
+/// session starts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// The datagrams we sent, so a packet sent in fragments counts once for each.
+    pub packets_sent: u32,
+    /// The datagrams we recieved, including the ones dropped as damaged or unreadable.
+    pub packets_recieved: u32,
+    pub bytes_sent: u64,
+    pub bytes_recieved: u64,
+    /// The times a request was sent again, since its response didn't come in time.
+    pub requests_retried: u32,
+    /// The requests that gave up on their response.
+    pub responses_timed_out: u32,
+    /// The pings sent, and how many of them were lost.
+    pub ping_loss: PingLoss,
+}
+
+impl ConnectionStats {
+    /// The estimated packet loss in percent, from the pings without a pong in time. A lost ping
+    /// is one of two packets lost, so this is an upper bound of the loss in either direction.
+    pub fn loss_percent(&self) -> f64 {
+        self.ping_loss.percent()
+    }
+}
+
+impl fmt::Display for ConnectionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} packets ({} bytes) sent, {} packets ({} bytes) recieved, {} retried, {} timed out, \
+             {:.1}% loss",
+            self.packets_sent,
+            self.bytes_sent,
+            self.packets_recieved,
+            self.bytes_recieved,
+            self.requests_retried,
+            self.responses_timed_out,
+            self.loss_percent()
+        )
+    }
+}
+
+/// The hosts board and turn, as sent in its answer to a resync. See `P2pResponsePacket::Resync`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostBoard {
+    /// The hosts board, as seen from White's side.
+    pub board: Vec<PieceData>,
+    /// The number of moves the host has made in the game.
+    pub move_number: u16,
+    /// The color whose turn it is on the host.
+    pub side_to_move: PieceColor,
+    /// The color of the player whose draw offer is waiting for an answer on the host, if any.
+    pub draw_offer: Option<PieceColor>,
+    /// If the game is paused on the host, and its open request about it.
+    pub pause: PauseState,
+}
+
+/// How a game ended, as told by the other peer in a `P2pRequestPacket::GameOver`, or found out
+/// by the network loop itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GameResult {
+    /// The color of the player who won, or `None` if the game ended in a draw.
+    pub winner: Option<PieceColor>,
+    /// Why the game ended.
+    pub reason: GameOverReason,
+}
+
+/// The last comparison of our board with the other peer's, from the hashes both sent after the
+/// same move. See `desync`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BoardSync {
+    /// Both boards had the same hash after `move_count` moves.
+    InSync { move_count: u16 },
+    /// The boards had different hashes after `move_count` moves.
+    Desynced {
+        move_count: u16,
+        /// The hash of our board.
+        ours: u64,
+        /// The hash of the other peer's board.
+        theirs: u64,
+    },
+}
+
+impl BoardSync {
+    /// Compare our hash to the other peer's, after `move_count` moves.
+    pub fn compare(move_count: u16, ours: u64, theirs: u64) -> Self {
+        if ours == theirs {
+            Self::InSync { move_count }
+        } else {
+            Self::Desynced {
+                move_count,
+                ours,
+                theirs,
+            }
+        }
+    }
+
+    pub fn is_in_sync(&self) -> bool {
+        matches!(self, Self::InSync { .. })
+    }
+}
+
+/// A round trip in whole microseconds, rounded to the nearest. This is the unit of a ping anywhere
+/// it's sent or shown; the statistics keep the full `Duration`. Saturates at `u32::MAX`, which is
+/// about 71 minutes.
+pub fn ping_micros(round_trip: Duration) -> u32 {
+    let micros = round_trip.as_nanos().saturating_add(500) / 1_000;
+    u32::try_from(micros).unwrap_or(u32::MAX)
+}
+
+/// A ping in microseconds as whole milliseconds, rounded to the nearest, for showing it.
+pub fn ping_millis(micros: u32) -> u32 {
+    micros / 1_000 + u32::from(micros % 1_000 >= 500)
+}
+
+/// Every field has its own lock. No function holds two of them at once, or awaits anything else
+/// while holding one, so they can be taken in any order. Debug builds check it, see
+/// `checked_lock`.
 pub struct ConnectionData {
-    status: Mutex<ConnectionStatus>,
-    other_addr: Mutex<Option<SocketAddr>>,
-    other_username: Mutex<Option<String>>,
-    my_username: Mutex<Option<String>>,
-    join_code: Mutex<Option<String>>,
-    session_id: Mutex<u16>,
+    status: CheckedMutex<ConnectionStatus>,
+    other_addr: CheckedMutex<Option<SocketAddr>>,
+    other_username: CheckedMutex<Option<String>>,
+    other_peer_info: CheckedMutex<Option<PeerInfo>>,
+    other_left: CheckedMutex<bool>,
+    my_username: CheckedMutex<Option<String>>,
+    anonymous: CheckedMutex<bool>,
+    join_code: CheckedMutex<Option<String>>,
+    session_id: CheckedMutex<u16>,
+    move_number: CheckedMutex<u16>,
+    coin_nonce: CheckedMutex<Option<u64>>,
+    color_preference: CheckedMutex<Option<PieceColor>>,
+    my_color: CheckedMutex<Option<PieceColor>>,
+    options_state: CheckedMutex<OptionsState>,
+    board: CheckedMutex<Option<Vec<PieceData>>>,
+    resync_board: CheckedMutex<Option<HostBoard>>,
+    game_result: CheckedMutex<Option<GameResult>>,
+    game_finished: CheckedMutex<bool>,
+    draw_offer: CheckedMutex<Option<PieceColor>>,
+    pause: CheckedMutex<PauseState>,
+    match_resigned: CheckedMutex<bool>,
+    rematch_offer: CheckedMutex<Option<P2pRequest>>,
+    board_sync: CheckedMutex<Option<BoardSync>>,
+    path_mtu: CheckedMutex<Option<usize>>,
+    task_restarts: CheckedMutex<u32>,
+    socket_rebinds: CheckedMutex<u32>,
+    throttled_requests: CheckedMutex<u32>,
+    corrupt_packets: CheckedMutex<u32>,
+    ping_loss: CheckedMutex<PingLoss>,
+    round_trips: CheckedMutex<VecDeque<Duration>>,
+    /// The counters of `ConnectionStats`, without the `ping_loss`, which is kept on its own.
+    connection_stats: CheckedMutex<ConnectionStats>,
 }
 
 static CONNECTION_DATA: ConnectionData = ConnectionData {
-    status: Mutex::const_new(ConnectionStatus::Disconnected),
-    other_addr: Mutex::const_new(None),
-    other_username: Mutex::const_new(None),
-    my_username: Mutex::const_new(None),
-    join_code: Mutex::const_new(None),
-    session_id: Mutex::const_new(CONNECT_SESSION_ID),
+    status: CheckedMutex::new("status.status", ConnectionStatus::Disconnected),
+    other_addr: CheckedMutex::new("status.other_addr", None),
+    other_username: CheckedMutex::new("status.other_username", None),
+    other_peer_info: CheckedMutex::new("status.other_peer_info", None),
+    other_left: CheckedMutex::new("status.other_left", false),
+    my_username: CheckedMutex::new("status.my_username", None),
+    anonymous: CheckedMutex::new("status.anonymous", false),
+    join_code: CheckedMutex::new("status.join_code", None),
+    session_id: CheckedMutex::new("status.session_id", CONNECT_SESSION_ID),
+    move_number: CheckedMutex::new("status.move_number", 0),
+    coin_nonce: CheckedMutex::new("status.coin_nonce", None),
+    color_preference: CheckedMutex::new("status.color_preference", None),
+    my_color: CheckedMutex::new("status.my_color", None),
+    options_state: CheckedMutex::new("status.options_state", OptionsState::Pending),
+    board: CheckedMutex::new("status.board", None),
+    resync_board: CheckedMutex::new("status.resync_board", None),
+    game_result: CheckedMutex::new("status.game_result", None),
+    game_finished: CheckedMutex::new("status.game_finished", false),
+    draw_offer: CheckedMutex::new("status.draw_offer", None),
+    pause: CheckedMutex::new(
+        "status.pause",
+        PauseState {
+            paused: false,
+            request: None,
+        },
+    ),
+    match_resigned: CheckedMutex::new("status.match_resigned", false),
+    rematch_offer: CheckedMutex::new("status.rematch_offer", None),
+    board_sync: CheckedMutex::new("status.board_sync", None),
+    path_mtu: CheckedMutex::new("status.path_mtu", None),
+    task_restarts: CheckedMutex::new("status.task_restarts", 0),
+    socket_rebinds: CheckedMutex::new("status.socket_rebinds", 0),
+    throttled_requests: CheckedMutex::new("status.throttled_requests", 0),
+    corrupt_packets: CheckedMutex::new("status.corrupt_packets", 0),
+    ping_loss: CheckedMutex::new("status.ping_loss", PingLoss { sent: 0, lost: 0 }),
+    round_trips: CheckedMutex::new("status.round_trips", VecDeque::new()),
+    connection_stats: CheckedMutex::new(
+        "status.connection_stats",
+        ConnectionStats {
+            packets_sent: 0,
+            packets_recieved: 0,
+            bytes_sent: 0,
+            bytes_recieved: 0,
+            requests_retried: 0,
+            responses_timed_out: 0,
+            ping_loss: PingLoss { sent: 0, lost: 0 },
+        },
+    ),
 };
 
 pub async fn get_other_addr() -> Option<SocketAddr> {
@@ -59,11 +361,24 @@ pub async fn get_other_addr() -> Option<SocketAddr> {
 }
 
 pub async fn set_other_addr(addr: SocketAddr) {
-    *CONNECTION_DATA.other_addr.lock().await = Some(addr)
+    *CONNECTION_DATA.other_addr.lock().await = Some(addr);
+    ADDR_WATCH.send_replace(Some(addr));
 }
 
 pub async fn remove_other_addr() {
-    *CONNECTION_DATA.other_addr.lock().await = None
+    *CONNECTION_DATA.other_addr.lock().await = None;
+    ADDR_WATCH.send_replace(None);
+}
+
+lazy_static! {
+    /// Tells the tasks listening with `watch_other_addr()` when the address of the other peer is
+    /// set or removed.
+    static ref ADDR_WATCH: watch::Sender<Option<SocketAddr>> = watch::channel(None).0;
+}
+
+/// Listen for changes of the address of the other peer.
+pub fn watch_other_addr() -> watch::Receiver<Option<SocketAddr>> {
+    ADDR_WATCH.subscribe()
 }
 
 pub async fn get_other_username() -> Option<String> {
@@ -74,6 +389,42 @@ pub async fn set_other_username(name: &str) {
     *CONNECTION_DATA.other_username.lock().await = Some(name.to_owned())
 }
 
+/// The version and platform of the other peer, from when it connected. See `p2p::peer_info`.
+pub async fn get_other_peer_info() -> Option<PeerInfo> {
+    CONNECTION_DATA.other_peer_info.lock().await.clone()
+}
+
+/// Keep the version and platform of the other peer, and write them to the session log. A peer with
+/// another major version gets a warning in the log too.
+pub async fn set_other_peer_info(info: PeerInfo) {
+    session_log::log(format!("other peer: {}", info));
+    if !PeerInfo::local().is_compatible_with(&info) {
+        println!(
+            "The other peer runs {}, and this is {}. The game may not work as expected",
+            info,
+            PeerInfo::local()
+        );
+        session_log::log(format!(
+            "warning: the other peer runs another major version than {}",
+            env!("CARGO_PKG_VERSION")
+        ));
+    }
+    *CONNECTION_DATA.other_peer_info.lock().await = Some(info)
+}
+
+pub async fn remove_other_peer_info() {
+    *CONNECTION_DATA.other_peer_info.lock().await = None
+}
+
+/// If the other peer left the game with a `P2pRequestPacket::Disconnect`, since we last connected.
+pub async fn get_other_left() -> bool {
+    *CONNECTION_DATA.other_left.lock().await
+}
+
+pub async fn set_other_left(other_left: bool) {
+    *CONNECTION_DATA.other_left.lock().await = other_left
+}
+
 pub async fn get_my_username() -> Option<String> {
     CONNECTION_DATA.my_username.lock().await.clone()
 }
@@ -82,6 +433,42 @@ pub async fn set_my_username(name: &str) {
     *CONNECTION_DATA.my_username.lock().await = Some(name.to_owned())
 }
 
+pub async fn is_anonymous() -> bool {
+    *CONNECTION_DATA.anonymous.lock().await
+}
+
+pub async fn set_anonymous(anonymous: bool) {
+    *CONNECTION_DATA.anonymous.lock().await = anonymous
+}
+
+/// The username to send to the other user. When playing anonymously, this is a generated handle
+/// instead of the users own username.
+///
+/// ## Params
+/// * `is_host` - If this peer is the host.
+pub async fn get_wire_username(is_host: bool) -> Option<String> {
+    if !is_anonymous().await {
+        return get_my_username().await;
+    }
+    Some(anonymous_handle(get_session_id().await, is_host))
+}
+
+/// Generate a handle like "Player-7f3a" for an anonymous user, from the first bytes of the SHA-256
+/// hash of the session ID. It's the same for the whole session, so a client that reconnects from
+/// another address keeps it. The client doesn't know the session ID when it joins, so it sends no
+/// username, and the host makes the handle for it.
+///
+/// ## Params
+/// * `session_id` - The ID of the session. See `new_session_id()`.
+/// * `is_host` - If the handle is for the host.
+pub fn anonymous_handle(session_id: u16, is_host: bool) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(session_id.to_be_bytes());
+    hasher.update([is_host as u8]);
+    let hash = hasher.finalize();
+    format!("Player-{:02x}{:02x}", hash[0], hash[1])
+}
+
 pub async fn remove_other_username() {
     *CONNECTION_DATA.other_username.lock().await = None
 }
@@ -91,17 +478,42 @@ pub async fn get_connection_status() -> ConnectionStatus {
 }
 
 pub async fn set_connection_status(status: ConnectionStatus) {
-    *CONNECTION_DATA.status.lock().await = status
+    let old_status = std::mem::replace(&mut *CONNECTION_DATA.status.lock().await, status);
+    if std::mem::discriminant(&old_status) != std::mem::discriminant(&status) {
+        session_log::log(format!("status: {:?}", status));
+    }
+    // The round trips of an old connection say nothing about the next
+    if matches!(
+        status,
+        ConnectionStatus::Disconnected | ConnectionStatus::PendingConnection
+    ) {
+        CONNECTION_DATA.round_trips.lock().await.clear();
+    }
+    STATUS_WATCH.send_replace(status);
+}
+
+lazy_static! {
+    /// Tells the tasks listening with `watch_connection_status()` when the status is set. Changes
+    /// of the ping and the reconnect tries aren't sent.
+    static ref STATUS_WATCH: watch::Sender<ConnectionStatus> =
+        watch::channel(ConnectionStatus::Disconnected).0;
+}
+
+/// Listen for changes of the connection status.
+pub fn watch_connection_status() -> watch::Receiver<ConnectionStatus> {
+    STATUS_WATCH.subscribe()
 }
 
-pub async fn get_connection_ping() -> Option<u128> {
+#[allow(dead_code)]
+pub async fn get_connection_ping() -> Option<u32> {
     match *CONNECTION_DATA.status.lock().await {
         ConnectionStatus::Connected { ping } => Some(ping),
         _ => None,
     }
 }
 
-pub async fn set_connection_ping(new_ping: u128) {
+/// Set the ping of a connected status, in microseconds. See `ping_micros()`.
+pub async fn set_connection_ping(new_ping: u32) {
     if let ConnectionStatus::Connected { ping } = &mut *CONNECTION_DATA.status.lock().await {
         *ping = new_ping;
     }
@@ -124,6 +536,553 @@ pub async fn get_session_id() -> u16 {
     *CONNECTION_DATA.session_id.lock().await
 }
 
+/// Pick the ID of a new session. It's never the `CONNECT_SESSION_ID`, which would make the session
+/// look like one not joined yet, nor 0, which a peer that never set its ID could have.
+pub fn new_session_id() -> u16 {
+    session_id_from(rand::random)
+}
+
+/// The first ID from `draw` that can be a session ID. See `new_session_id()`.
+fn session_id_from(mut draw: impl FnMut() -> u16) -> u16 {
+    loop {
+        let session_id = draw();
+        if session_id != CONNECT_SESSION_ID && session_id != 0 {
+            return session_id;
+        }
+    }
+}
+
+/// Set the session ID. A new session gets its own log, see `session_log`, and its own
+/// `ConnectionStats`. The stats of the last session are kept until then.
 pub async fn set_session_id(session_id: u16) {
-    *CONNECTION_DATA.session_id.lock().await = session_id
+    let old_session_id =
+        std::mem::replace(&mut *CONNECTION_DATA.session_id.lock().await, session_id);
+    if old_session_id == session_id {
+        return;
+    }
+    match session_id {
+        CONNECT_SESSION_ID => session_log::close(),
+        session_id => {
+            session_log::open(session_id);
+            reset_connection_stats().await;
+        }
+    }
+}
+
+/// The number of moves made in the game, which is also the number of the next move.
+pub async fn get_move_number() -> u16 {
+    *CONNECTION_DATA.move_number.lock().await
+}
+
+pub async fn set_move_number(move_number: u16) {
+    *CONNECTION_DATA.move_number.lock().await = move_number
+}
+
+/// The hosts nonce for the coin flip. See `p2p::coin_flip`.
+pub async fn get_coin_nonce() -> Option<u64> {
+    *CONNECTION_DATA.coin_nonce.lock().await
+}
+
+pub async fn set_coin_nonce(nonce: u64) {
+    *CONNECTION_DATA.coin_nonce.lock().await = Some(nonce)
+}
+
+pub async fn remove_coin_nonce() {
+    *CONNECTION_DATA.coin_nonce.lock().await = None
+}
+
+/// The color the user wishes to play, if any. See `p2p::coin_flip::assign_client_color()`.
+pub async fn get_color_preference() -> Option<PieceColor> {
+    *CONNECTION_DATA.color_preference.lock().await
+}
+
+pub async fn set_color_preference(preference: Option<PieceColor>) {
+    *CONNECTION_DATA.color_preference.lock().await = preference
+}
+
+/// The color we play, once the coin flip is done.
+pub async fn get_my_color() -> Option<PieceColor> {
+    *CONNECTION_DATA.my_color.lock().await
+}
+
+pub async fn set_my_color(color: PieceColor) {
+    *CONNECTION_DATA.my_color.lock().await = Some(color)
+}
+
+pub async fn get_options_state() -> OptionsState {
+    *CONNECTION_DATA.options_state.lock().await
+}
+
+pub async fn set_options_state(state: OptionsState) {
+    *CONNECTION_DATA.options_state.lock().await = state
+}
+
+/// The `GameOptions` we play with. The client confirms them with the host, so once the
+/// `OptionsState` is `Agreed` they are the options of the game. They're kept outside of the
+/// `ConnectionData`, since moves are checked against them where nothing can be awaited, like when
+/// a packet is decoded.
+static GAME_OPTIONS: RwLock<GameOptions> = RwLock::new(GameOptions::new());
+
+pub fn get_game_options() -> GameOptions {
+    *GAME_OPTIONS.read().unwrap()
+}
+
+/// Set the `GameOptions` we play with. Set them before connecting, since the other peer only
+/// plays with the same options.
+pub fn set_game_options(options: GameOptions) {
+    *GAME_OPTIONS.write().unwrap() = options
+}
+
+/// Our board as seen from White's side, as last published by the game. The host sends it when
+/// the client asks to resync.
+pub async fn get_board() -> Option<Vec<PieceData>> {
+    CONNECTION_DATA.board.lock().await.clone()
+}
+
+pub async fn set_board(board: Vec<PieceData>) {
+    *CONNECTION_DATA.board.lock().await = Some(board)
+}
+
+/// Take the hosts board and turn from the last resync, if it hasn't been taken.
+pub async fn take_resync_board() -> Option<HostBoard> {
+    CONNECTION_DATA.resync_board.lock().await.take()
+}
+
+pub async fn set_resync_board(board: HostBoard) {
+    *CONNECTION_DATA.resync_board.lock().await = Some(board)
+}
+
+/// Take how the game ended, if it ended since the last time.
+pub async fn take_game_result() -> Option<GameResult> {
+    CONNECTION_DATA.game_result.lock().await.take()
+}
+
+pub async fn set_game_result(result: GameResult) {
+    *CONNECTION_DATA.game_result.lock().await = Some(result)
+}
+
+/// If the game in this session has ended, so a rematch can be offered. Unlike the game result, it
+/// stays set until a new game starts.
+pub async fn is_game_finished() -> bool {
+    *CONNECTION_DATA.game_finished.lock().await
+}
+
+/// Set if the game has ended. An open draw offer and the pause are dropped either way, since they
+/// belong to the game that ended, or to the one before the new game.
+pub async fn set_game_finished(game_finished: bool) {
+    *CONNECTION_DATA.game_finished.lock().await = game_finished;
+    set_draw_offer(None).await;
+    set_pause(PauseState::default()).await;
+}
+
+/// The color of the player whose draw offer is waiting for an answer, if any. The host sends it
+/// in its answer to a resync, so an offer made before a reconnect is still shown after it.
+pub async fn get_draw_offer() -> Option<PieceColor> {
+    *CONNECTION_DATA.draw_offer.lock().await
+}
+
+pub async fn set_draw_offer(offerer: Option<PieceColor>) {
+    *CONNECTION_DATA.draw_offer.lock().await = offerer
+}
+
+/// Keep track of the open draw offer, after a game action was sent to or taken from the other
+/// peer. An offer stays open until it's answered.
+///
+/// ## Params
+/// * `action` - The game action.
+/// * `is_mine` - If we sent the action.
+pub async fn track_draw_offer(action: &GameAction, is_mine: bool) {
+    let offerer = match action {
+        GameAction::OfferDraw => {
+            get_my_color()
+                .await
+                .map(|color| if is_mine { color } else { color.get_opposite() })
+        }
+        GameAction::DrawResponse(_) | GameAction::Surrender | GameAction::ResignMatch => None,
+        GameAction::MovePiece(_)
+        | GameAction::PauseRequest
+        | GameAction::ResumeRequest
+        | GameAction::PauseResponse(_) => return,
+    };
+    set_draw_offer(offerer).await;
+}
+
+/// If the game is paused, and the request about it that waits for an answer. The host sends it in
+/// its answer to a resync, so a client that reconnects during a pause lands in it.
+pub async fn get_pause() -> PauseState {
+    *CONNECTION_DATA.pause.lock().await
+}
+
+pub async fn set_pause(pause: PauseState) {
+    *CONNECTION_DATA.pause.lock().await = pause
+}
+
+/// Keep track of the pause, after a game action was sent to or taken from the other peer. See
+/// `PauseState::after()`.
+///
+/// ## Params
+/// * `action` - The game action.
+/// * `is_mine` - If we sent the action.
+pub async fn track_pause(action: &GameAction, is_mine: bool) {
+    let Some(color) = get_my_color().await else {
+        return;
+    };
+    let by = if is_mine { color } else { color.get_opposite() };
+    let mut pause = CONNECTION_DATA.pause.lock().await;
+    *pause = pause.after(action, by);
+}
+
+/// Take if the other peer resigned the match with a `GameAction::ResignMatch`, since the last
+/// time.
+pub async fn take_match_resigned() -> bool {
+    std::mem::take(&mut *CONNECTION_DATA.match_resigned.lock().await)
+}
+
+pub async fn set_match_resigned(match_resigned: bool) {
+    *CONNECTION_DATA.match_resigned.lock().await = match_resigned
+}
+
+/// Take the rematch offered by the other peer, if it hasn't been taken. It's the request, since
+/// it's answered once the player has decided.
+pub async fn take_rematch_offer() -> Option<P2pRequest> {
+    CONNECTION_DATA.rematch_offer.lock().await.take()
+}
+
+pub async fn set_rematch_offer(request: P2pRequest) {
+    *CONNECTION_DATA.rematch_offer.lock().await = Some(request)
+}
+
+/// The last comparison of our board with the other peer's, if any hashes were compared in this
+/// game.
+pub async fn get_board_sync() -> Option<BoardSync> {
+    *CONNECTION_DATA.board_sync.lock().await
+}
+
+pub async fn set_board_sync(board_sync: BoardSync) {
+    *CONNECTION_DATA.board_sync.lock().await = Some(board_sync)
+}
+
+pub async fn remove_board_sync() {
+    *CONNECTION_DATA.board_sync.lock().await = None
+}
+
+/// The largest ping payload that got to the other peer and back, if the path has been probed.
+pub async fn get_path_mtu() -> Option<usize> {
+    *CONNECTION_DATA.path_mtu.lock().await
+}
+
+pub async fn set_path_mtu(path_mtu: Option<usize>) {
+    *CONNECTION_DATA.path_mtu.lock().await = path_mtu
+}
+
+pub async fn get_task_restarts() -> u32 {
+    *CONNECTION_DATA.task_restarts.lock().await
+}
+
+/// Count a restart of a network task. Returns the total amount of restarts.
+pub async fn add_task_restart() -> u32 {
+    let mut restarts = CONNECTION_DATA.task_restarts.lock().await;
+    *restarts += 1;
+    *restarts
+}
+
+pub async fn get_socket_rebinds() -> u32 {
+    *CONNECTION_DATA.socket_rebinds.lock().await
+}
+
+/// Count a rebind of the network socket. Returns the total amount of rebinds.
+pub async fn add_socket_rebind() -> u32 {
+    let mut rebinds = CONNECTION_DATA.socket_rebinds.lock().await;
+    *rebinds += 1;
+    *rebinds
+}
+
+pub async fn get_throttled_requests() -> u32 {
+    *CONNECTION_DATA.throttled_requests.lock().await
+}
+
+/// Count a request refused by the `throttle`. Returns the total amount of refused requests.
+pub async fn add_throttled_request() -> u32 {
+    let mut throttled = CONNECTION_DATA.throttled_requests.lock().await;
+    *throttled = throttled.saturating_add(1);
+    *throttled
+}
+
+pub async fn get_corrupt_packets() -> u32 {
+    *CONNECTION_DATA.corrupt_packets.lock().await
+}
+
+/// Count a packet dropped for a wrong checksum. Returns the total amount of dropped packets.
+pub async fn add_corrupt_packet() -> u32 {
+    let mut corrupt = CONNECTION_DATA.corrupt_packets.lock().await;
+    *corrupt = corrupt.saturating_add(1);
+    *corrupt
+}
+
+pub async fn get_ping_loss() -> PingLoss {
+    *CONNECTION_DATA.ping_loss.lock().await
+}
+
+/// Count a ping, and if it was lost.
+pub async fn add_ping(lost: bool) {
+    let mut ping_loss = CONNECTION_DATA.ping_loss.lock().await;
+    ping_loss.sent = ping_loss.sent.saturating_add(1);
+    ping_loss.lost = ping_loss.lost.saturating_add(u32::from(lost));
+}
+
+/// The counters of the traffic with the other peer in this session.
+pub async fn get_connection_stats() -> ConnectionStats {
+    let ping_loss = get_ping_loss().await;
+    ConnectionStats {
+        ping_loss,
+        ..*CONNECTION_DATA.connection_stats.lock().await
+    }
+}
+
+/// Start the `ConnectionStats` and the ping loss over, for a new session.
+pub async fn reset_connection_stats() {
+    *CONNECTION_DATA.connection_stats.lock().await = ConnectionStats::default();
+    *CONNECTION_DATA.ping_loss.lock().await = PingLoss::default();
+}
+
+/// Count a datagram of `bytes` bytes we sent.
+pub async fn add_sent_packet(bytes: usize) {
+    let mut stats = CONNECTION_DATA.connection_stats.lock().await;
+    stats.packets_sent = stats.packets_sent.saturating_add(1);
+    stats.bytes_sent = stats.bytes_sent.saturating_add(bytes as u64);
+}
+
+/// Count a datagram of `bytes` bytes we recieved.
+pub async fn add_recieved_packet(bytes: usize) {
+    let mut stats = CONNECTION_DATA.connection_stats.lock().await;
+    stats.packets_recieved = stats.packets_recieved.saturating_add(1);
+    stats.bytes_recieved = stats.bytes_recieved.saturating_add(bytes as u64);
+}
+
+/// Count a request sent again.
+pub async fn add_retried_request() {
+    let mut stats = CONNECTION_DATA.connection_stats.lock().await;
+    stats.requests_retried = stats.requests_retried.saturating_add(1);
+}
+
+/// Count a request that gave up on its response.
+pub async fn add_timed_out_response() {
+    let mut stats = CONNECTION_DATA.connection_stats.lock().await;
+    stats.responses_timed_out = stats.responses_timed_out.saturating_add(1);
+}
+
+/// Add the round trip of a ping to the statistics, and set the ping of the connection to the new
+/// average. Returns the new statistics.
+pub async fn add_round_trip(round_trip: Duration) -> NetworkStats {
+    let stats = {
+        let mut round_trips = CONNECTION_DATA.round_trips.lock().await;
+        if round_trips.len() == PING_WINDOW {
+            round_trips.pop_front();
+        }
+        round_trips.push_back(round_trip);
+        // There is at least one round trip
+        NetworkStats::new(&round_trips).unwrap()
+    };
+    set_connection_ping(ping_micros(stats.average)).await;
+    stats
+}
+
+/// Get the statistics of the last pings, or `None` if no ping has come back on this connection.
+pub async fn get_network_stats() -> Option<NetworkStats> {
+    NetworkStats::new(&*CONNECTION_DATA.round_trips.lock().await)
+}
+
+/// The smoothed round trip of the last pings. See `NetworkStats::average`.
+pub async fn get_ping_average() -> Option<Duration> {
+    get_network_stats().await.map(|stats| stats.average)
+}
+
+/// The jitter of the last pings. See `NetworkStats::jitter`.
+pub async fn get_ping_jitter() -> Option<Duration> {
+    get_network_stats().await.map(|stats| stats.jitter)
+}
+
+/// The round trip of the newest ping.
+pub async fn get_ping_last() -> Option<Duration> {
+    CONNECTION_DATA.round_trips.lock().await.back().copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::p2p::normalize_username;
+
+    #[test]
+    fn anonymous_handle_is_stable_for_the_session() {
+        let handle = anonymous_handle(0x1234, false);
+        assert_eq!(handle, anonymous_handle(0x1234, false));
+        // The first bytes of the SHA-256 hash of [0x12, 0x34, 0]
+        assert_eq!(handle, "Player-a693");
+        assert_ne!(handle, anonymous_handle(0x1234, true));
+
+        let handles: std::collections::HashSet<_> = (1..=1000)
+            .map(|session_id| anonymous_handle(session_id, true))
+            .collect();
+        assert!(handles.len() > 980, "{}", handles.len());
+        for handle in handles {
+            let hex = handle.strip_prefix("Player-").unwrap();
+            assert_eq!(hex.len(), 4);
+            assert!(hex
+                .chars()
+                .all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+            assert_eq!(normalize_username(&handle), Ok(handle.as_str()));
+        }
+    }
+
+    #[test]
+    fn session_id_is_never_the_connect_id_or_zero() {
+        // The draws that can't be used are skipped, however many come in a row
+        let mut draws = [CONNECT_SESSION_ID, 0, CONNECT_SESSION_ID, 0, 1, 2].into_iter();
+        assert_eq!(session_id_from(|| draws.next().unwrap()), 1);
+
+        // Every ID there is, with the sentinel among them
+        let mut draws = 0..=u16::MAX;
+        let ids: Vec<_> = (0..u16::MAX - 1)
+            .map(|_| session_id_from(|| draws.next().unwrap()))
+            .collect();
+        assert!(draws.next().is_none());
+        assert!(!ids.contains(&CONNECT_SESSION_ID));
+        assert!(!ids.contains(&0));
+        assert_eq!(ids.len(), u16::MAX as usize - 1);
+
+        for _ in 0..10_000 {
+            let session_id = new_session_id();
+            assert_ne!(session_id, CONNECT_SESSION_ID);
+            assert_ne!(session_id, 0);
+        }
+    }
+
+    #[test]
+    fn ping_micros_rounds_to_the_nearest() {
+        assert_eq!(ping_micros(Duration::ZERO), 0);
+        assert_eq!(ping_micros(Duration::from_nanos(499)), 0);
+        assert_eq!(ping_micros(Duration::from_nanos(500)), 1);
+        assert_eq!(ping_micros(Duration::from_nanos(1_499)), 1);
+        assert_eq!(ping_micros(Duration::from_micros(42_000)), 42_000);
+        assert_eq!(ping_micros(Duration::from_nanos(42_000_501)), 42_001);
+    }
+
+    #[test]
+    fn ping_micros_saturates() {
+        let max = Duration::from_micros(u32::MAX as u64);
+        assert_eq!(ping_micros(max), u32::MAX);
+        // Would round up past the max
+        assert_eq!(ping_micros(max + Duration::from_nanos(500)), u32::MAX);
+        assert_eq!(ping_micros(max + Duration::from_micros(1)), u32::MAX);
+        assert_eq!(ping_micros(Duration::from_secs(72 * 60)), u32::MAX);
+        assert_eq!(ping_micros(Duration::MAX), u32::MAX);
+        // About 71 minutes still fit
+        assert!(ping_micros(Duration::from_secs(71 * 60)) < u32::MAX);
+    }
+
+    #[test]
+    fn ping_millis_rounds_to_the_nearest() {
+        assert_eq!(ping_millis(0), 0);
+        assert_eq!(ping_millis(499), 0);
+        assert_eq!(ping_millis(500), 1);
+        assert_eq!(ping_millis(1_499), 1);
+        assert_eq!(ping_millis(1_500), 2);
+        assert_eq!(ping_millis(u32::MAX), 4_294_967);
+    }
+
+    /// The statistics of round trips of `millis` milliseconds, the oldest first.
+    fn stats_of(millis: &[u64]) -> Option<NetworkStats> {
+        let round_trips = millis.iter().map(|&ms| Duration::from_millis(ms)).collect();
+        NetworkStats::new(&round_trips)
+    }
+
+    /// Assert that `actual` is within a microsecond of `expected` milliseconds, since the averages
+    /// are calculated with floats.
+    fn assert_near(actual: Duration, expected_ms: f64) {
+        let expected = Duration::from_secs_f64(expected_ms / 1_000.0);
+        let off = actual.abs_diff(expected);
+        assert!(
+            off < Duration::from_micros(1),
+            "{actual:?} isn't {expected:?}"
+        );
+    }
+
+    #[test]
+    fn average_and_jitter_follow_their_gains() {
+        assert_eq!(stats_of(&[]), None);
+
+        let steady = stats_of(&[100; 5]).unwrap();
+        assert_near(steady.average, 100.0);
+        assert_near(steady.jitter, 0.0);
+
+        // An eighth of the way to the new round trip, and a quarter of the way to its deviation
+        let stats = stats_of(&[100, 180]).unwrap();
+        assert_near(stats.average, 110.0);
+        assert_near(stats.jitter, 20.0);
+        assert_eq!(stats.last, Duration::from_millis(180));
+
+        let stats = stats_of(&[100, 180, 110]).unwrap();
+        assert_near(stats.average, 110.0);
+        assert_near(stats.jitter, 15.0);
+    }
+
+    #[test]
+    fn percentiles_are_nearest_rank() {
+        let millis: Vec<u64> = (1..=20).collect();
+        let stats = stats_of(&millis).unwrap();
+        assert_eq!(stats.median, Duration::from_millis(10));
+        assert_eq!(stats.p95, Duration::from_millis(19));
+        assert_eq!(stats.samples, 20);
+        assert!(!stats.has_spike());
+
+        let mut millis = vec![10; 18];
+        millis.extend([100, 100]);
+        assert!(stats_of(&millis).unwrap().has_spike());
+
+        let single = stats_of(&[42]).unwrap();
+        assert_eq!((single.median, single.p95), (single.last, single.last));
+        assert!(!single.has_spike());
+    }
+
+    #[test]
+    fn lasting_change_shows_within_a_few_pings() {
+        let mut millis = vec![100; 10];
+        millis.extend([200; 10]);
+        let stats = stats_of(&millis).unwrap();
+        assert!(
+            stats.average > Duration::from_millis(170),
+            "{:?}",
+            stats.average
+        );
+    }
+
+    #[test]
+    fn one_outlier_doesnt_spike_the_average() {
+        let _state = crate::net::p2p::lock_global_state();
+        futures::executor::block_on(async {
+            set_connection_status(ConnectionStatus::connected()).await;
+            for _ in 0..PING_WINDOW - 1 {
+                add_round_trip(Duration::from_millis(100)).await;
+            }
+            let stats = add_round_trip(Duration::from_secs(2)).await;
+
+            // The outlier counts as four deviations above the average, not its full 1.9 seconds
+            assert_near(get_ping_average().await.unwrap(), 112.5);
+            assert_eq!(get_connection_ping().await, Some(112_500));
+            assert_eq!(get_ping_last().await, Some(Duration::from_secs(2)));
+            // The jitter shows it in full
+            assert_near(get_ping_jitter().await.unwrap(), 475.0);
+            assert!(!stats.has_spike());
+
+            // Once it's out of the window, it's forgotten
+            for _ in 0..PING_WINDOW {
+                add_round_trip(Duration::from_millis(100)).await;
+            }
+            assert_near(get_ping_average().await.unwrap(), 100.0);
+            assert_eq!(get_network_stats().await.unwrap().samples, PING_WINDOW);
+
+            set_connection_status(ConnectionStatus::Disconnected).await;
+            assert_eq!(get_network_stats().await, None);
+        });
+    }
 }
@@ -0,0 +1,45 @@
+//! Runs every `replay/<name>.ron` script, and compares its snapshot to `replay/<name>.golden`.
+//! `cargo run --bin replay -- --bless` writes the golden files again after a change to the rules.
+#![cfg(feature = "gui")]
+
+use std::{fs, path::Path};
+
+use the_checker_mater::game::replay::Script;
+
+fn run(script: &Path) -> anyhow::Result<()> {
+    let snapshot = Script::parse(&fs::read_to_string(script)?)?.run()?;
+    let golden = script.with_extension("golden");
+    let expected = fs::read_to_string(&golden)
+        .map_err(|e| anyhow::anyhow!("Can't read {:?}: {}. Run with --bless", golden, e))?;
+    if snapshot != expected {
+        return Err(anyhow::anyhow!("expected:\n{}got:\n{}", expected, snapshot));
+    }
+    Ok(())
+}
+
+#[test]
+fn replay_scripts() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("replay");
+    let mut scripts: Vec<_> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("Can't read {:?}: {}", dir, e))
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ron"))
+        .collect();
+    scripts.sort();
+
+    assert!(!scripts.is_empty(), "{:?} has no scripts", dir);
+    let failures: Vec<String> = scripts
+        .iter()
+        .filter_map(|script| {
+            let e = run(script).err()?;
+            Some(format!("{}\n{}", script.file_stem()?.to_string_lossy(), e))
+        })
+        .collect();
+    assert!(
+        failures.is_empty(),
+        "{} of {} scripts failed:\n{}",
+        failures.len(),
+        scripts.len(),
+        failures.join("\n")
+    );
+}
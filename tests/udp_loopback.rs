@@ -0,0 +1,684 @@
+//! Plays a short game between a host and a client over real UDP sockets on 127.0.0.1: The client
+//! joins, White makes a move, which the other side must get, and then the client disconnects,
+//! which the host must notice. The host must be able to export a debug bundle of the game.
+//!
+//! A client playing with other options must be refused instead, a client whose board differs from
+//! the host's must get the host's board when it resyncs, and both sides must measure their ping,
+//! also with a latency injected by the client. A client keeping its main runtime busy must go on
+//! pinging. A client that hears nothing from the host must rebind its socket, and go on from the new
+//! port. A client that vanishes must forfeit after the grace period, unless the game waits for it.
+//! A client hosting a game of its own must tell from a probe that the other side hosts too, and
+//! join it as a client.
+//!
+//! The network state is global, so the client runs in a child process of its own: This test binary
+//! again, running only the test of the client. Since it binds real sockets, it's ignored by default:
+//!
+//! ```text
+//! cargo test --no-default-features --test udp_loopback -- --ignored
+//! ```
+
+use std::{
+    env, fs,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    process::{Child, Command},
+    sync::{mpsc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::NaiveDateTime;
+use the_checker_mater::{
+    game::{
+        fen,
+        history::{self, Source},
+        options::{AbandonmentPolicy, GameOptions},
+        GameAction, Move, PieceColor, PieceData,
+    },
+    net::interface::{
+        self, GameOverReason, GameResult, Latency, NetworkSimulation, OptionsMismatch, OptionsState,
+    },
+};
+use tokio::runtime::Runtime;
+
+/// Tells the child process the join code, and that it is the client.
+const JOIN_CODE_VAR: &str = "UDP_LOOPBACK_JOIN_CODE";
+
+/// The username the client joins with.
+const CLIENT_USERNAME: &str = "loopback";
+
+/// Held by the tests hosting a game, since the network state of the process can only host one at a
+/// time.
+static HOSTING: Mutex<()> = Mutex::new(());
+
+/// The longest wait for each step of the game.
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL: Duration = Duration::from_millis(50);
+
+/// The first move of the game, seen from White's side.
+fn first_move() -> Move {
+    Move {
+        index: 23,
+        end: 19,
+        captured: None,
+        promoted: false,
+    }
+}
+
+/// Poll `done` until it returns something, or `STEP_TIMEOUT` passes.
+fn wait_for<T>(what: &str, mut done: impl FnMut() -> Option<T>) -> T {
+    let deadline = Instant::now() + STEP_TIMEOUT;
+    loop {
+        if let Some(value) = done() {
+            return value;
+        }
+        assert!(Instant::now() < deadline, "Timed out waiting for {}", what);
+        thread::sleep(POLL);
+    }
+}
+
+/// Run the test `name` of this binary in a child process, as the client joining `join_code`.
+fn spawn_client(name: &str, join_code: &str) -> Child {
+    Command::new(env::current_exe().unwrap())
+        .args([name, "--exact", "--ignored", "--nocapture"])
+        .env(JOIN_CODE_VAR, join_code)
+        .spawn()
+        .unwrap()
+}
+
+/// Play the first move if we are White, or wait for it if we are Black. The moves are sent from our
+/// own side of the board, like the GUI does.
+fn exchange_first_move(color: PieceColor) {
+    let geometry = GameOptions::new().geometry();
+    match color {
+        PieceColor::White => {
+            let (sender, answer) = mpsc::channel();
+            interface::send_game_action(GameAction::MovePiece(first_move()), move |res| {
+                let _ = sender.send(res.map_err(|e| e.to_string()));
+            });
+            let answer = answer
+                .recv_timeout(STEP_TIMEOUT)
+                .expect("The move was never answered");
+            assert_eq!(answer, Ok(()), "The move was refused");
+        }
+        PieceColor::Black => match wait_for("the move", interface::get_next_game_action) {
+            // Black sees it from the other side of the board
+            GameAction::MovePiece(theirs) => {
+                assert_eq!(theirs.reverse(geometry), first_move().reverse(geometry))
+            }
+            action => panic!("Expected the first move, got {:?}", action),
+        },
+    }
+}
+
+/// Host a game for the client test `client`, make the first move with it, run `then`, and wait for
+/// the client to leave.
+fn host_first_move(client: &str, then: impl FnOnce()) {
+    let _hosting = HOSTING.lock().unwrap_or_else(|e| e.into_inner());
+    // Like the frontends, the interface is used from inside a Tokio runtime
+    let runtime = Runtime::new().unwrap();
+    let _guard = runtime.enter();
+    let join_code = interface::start_loopback_host();
+    interface::publish_board(hosts_board());
+
+    let mut client = spawn_client(client, &join_code);
+
+    wait_for("the client to join", || {
+        let state = interface::get_options_state();
+        assert_ne!(
+            state,
+            OptionsState::Mismatch,
+            "The client plays with other options"
+        );
+        // Not `is_connected()`, since a client playing White can have moved and left already
+        (state == OptionsState::Agreed).then_some(())
+    });
+    let color = interface::get_my_color().expect("No color after the coin flip");
+    exchange_first_move(color);
+    then();
+
+    wait_for("the client to leave", interface::opponent_left_message);
+    let status = wait_for("the client to exit", || client.try_wait().unwrap());
+    assert!(status.success(), "The client failed with {}", status);
+    interface::disconnect();
+}
+
+/// Join the host from the child process, and make the first move with it. Returns `None` unless
+/// the process was started by `host_first_move()`.
+fn join_first_move() -> Option<Runtime> {
+    let join_code = env::var(JOIN_CODE_VAR).ok()?;
+    assert_eq!(
+        interface::decode_join_code(&join_code).unwrap().ip(),
+        Ipv4Addr::LOCALHOST
+    );
+
+    let runtime = Runtime::new().unwrap();
+    let guard = runtime.enter();
+    interface::start_lan_client();
+    let (color, _) =
+        interface::connect_to_host_loop(&join_code, CLIENT_USERNAME, interface::JOIN_RETRY, |_| {})
+            .expect("Couldn't join the host");
+    exchange_first_move(color);
+    drop(guard);
+    Some(runtime)
+}
+
+#[test]
+#[ignore = "binds UDP sockets, and starts a child process"]
+fn host_and_client_connect_move_and_disconnect() {
+    host_first_move("loopback_client", || {});
+}
+
+/// The client side of `host_and_client_connect_move_and_disconnect()`. It does nothing unless it's
+/// started by it.
+#[test]
+#[ignore = "only run by host_and_client_connect_move_and_disconnect"]
+fn loopback_client() {
+    let Some(runtime) = join_first_move() else {
+        return;
+    };
+    let _guard = runtime.enter();
+    interface::disconnect();
+}
+
+#[test]
+#[ignore = "binds UDP sockets, and starts a child process"]
+fn host_downgrades_to_client_of_the_other_host() {
+    host_first_move("downgrading_client", || {});
+}
+
+/// The client side of `host_downgrades_to_client_of_the_other_host()`. It hosts a game of its own
+/// first, like when both players clicked host, finds out with a probe that the other side hosts
+/// too, and joins it instead. It does nothing unless it's started by it.
+#[test]
+#[ignore = "only run by host_downgrades_to_client_of_the_other_host"]
+fn downgrading_client() {
+    let Ok(join_code) = env::var(JOIN_CODE_VAR) else {
+        return;
+    };
+    let runtime = Runtime::new().unwrap();
+    let _guard = runtime.enter();
+    let own_code = interface::start_loopback_host();
+    assert!(interface::probe(&join_code).expect("The host didn't answer the probe"));
+    assert!(interface::probe(&own_code).expect("We didn't answer our own probe"));
+
+    let (color, _) = interface::join_as_client_from_host(
+        &join_code,
+        CLIENT_USERNAME,
+        interface::JOIN_RETRY,
+        |_| {},
+    )
+    .expect("Couldn't join the other host");
+    exchange_first_move(color);
+    // Our own host is gone: Nothing answers there, or a client does
+    assert!(!interface::probe(&own_code).unwrap_or(false));
+    interface::disconnect();
+}
+
+/// The board the host publishes: The pieces of a game that has gone on for a while, with a king of
+/// each color.
+fn hosts_board() -> Vec<PieceData> {
+    (0..32)
+        .map(|square| match square % 3 {
+            0 => PieceData::default(),
+            _ => PieceData {
+                is_active: true,
+                color: if square < 16 {
+                    PieceColor::Black
+                } else {
+                    PieceColor::White
+                },
+                is_king: square == 5 || square == 26,
+            },
+        })
+        .collect()
+}
+
+#[test]
+#[ignore = "binds UDP sockets, and starts a child process"]
+fn desynced_client_resyncs_to_the_hosts_board() {
+    host_first_move("resyncing_client", || {});
+}
+
+/// The client side of `desynced_client_resyncs_to_the_hosts_board()`. Its board is the starting
+/// position, which differs from the host's. It does nothing unless it's started by it.
+#[test]
+#[ignore = "only run by desynced_client_resyncs_to_the_hosts_board"]
+fn resyncing_client() {
+    let Some(runtime) = join_first_move() else {
+        return;
+    };
+    let _guard = runtime.enter();
+    let ours = vec![PieceData::default(); 32];
+    interface::publish_board(ours.clone());
+    assert_ne!(ours, hosts_board());
+
+    interface::request_resync().expect("The host didn't send its board");
+    let resynced = interface::take_resync_board().expect("No board to take after the resync");
+    assert_eq!(resynced.board, hosts_board());
+    // After White's first move, it's Black's turn
+    assert_eq!(resynced.move_number, 1);
+    assert_eq!(resynced.side_to_move, PieceColor::Black);
+    assert_eq!(interface::take_resync_board(), None);
+    interface::disconnect();
+}
+
+/// Tells the client that the host is done with it, so it may leave.
+const DONE: &str = "done";
+
+/// Wait for the host to tell us it's done, and leave.
+fn leave_when_done() {
+    let (_, message) = wait_for("the host to be done", interface::get_next_chat_message);
+    assert_eq!(message, DONE);
+    interface::disconnect();
+}
+
+#[test]
+#[ignore = "binds UDP sockets, and starts a child process"]
+fn debug_bundle_holds_the_game() {
+    host_first_move("waiting_client", || {
+        // The game keeps the history, so the test does it instead
+        let before = fen::to_fen(&hosts_board(), PieceColor::White);
+        let after = fen::to_fen(&hosts_board(), PieceColor::Black);
+        history::clear(before);
+        history::push(Source::Local, first_move(), after.clone());
+
+        let dir = env::temp_dir().join(format!("udp_loopback-{}-bundle", std::process::id()));
+        interface::export_debug_bundle(&dir, Some(&after)).unwrap();
+        check_debug_bundle(&dir, &after);
+        fs::remove_dir_all(&dir).unwrap();
+        interface::send_chat_message(DONE).unwrap();
+    });
+}
+
+/// The client side of `debug_bundle_holds_the_game()`. It stays until the host has exported the
+/// bundle. It does nothing unless it's started by it.
+#[test]
+#[ignore = "only run by debug_bundle_holds_the_game"]
+fn waiting_client() {
+    let Some(runtime) = join_first_move() else {
+        return;
+    };
+    let _guard = runtime.enter();
+    leave_when_done();
+}
+
+/// Check that every file of the bundle in `dir` is there and can be read, and that the username of
+/// the client is left out.
+fn check_debug_bundle(dir: &Path, board_fen: &str) {
+    let read = |file: &str| {
+        fs::read_to_string(dir.join(file)).unwrap_or_else(|e| panic!("{}: {}", file, e))
+    };
+    for file in ["connection.txt", "packets.log", "history.log", "board.fen"] {
+        assert!(
+            !read(file).contains(CLIENT_USERNAME),
+            "{} has the username",
+            file
+        );
+    }
+
+    let connection = read("connection.txt");
+    for line in connection.lines() {
+        assert!(line.split_once(": ").is_some(), "Not a field: {:?}", line);
+    }
+    assert!(connection.contains("\nstatus: Connected"), "{}", connection);
+
+    let packets = read("packets.log");
+    for line in packets.lines() {
+        let fields: Vec<&str> = line.splitn(6, ' ').collect();
+        let [date, time, "UTC", arrow, addr, packet] = fields[..] else {
+            panic!("Not a packet: {:?}", line);
+        };
+        let stamp = format!("{} {}", date, time);
+        NaiveDateTime::parse_from_str(&stamp, "%Y-%m-%d %H:%M:%S%.f").unwrap();
+        assert!(arrow == "->" || arrow == "<-", "{:?}", line);
+        addr.parse::<SocketAddr>().unwrap();
+        assert!(
+            packet.starts_with("Request ") || packet.starts_with("Response "),
+            "{:?}",
+            line
+        );
+    }
+    assert!(packets.contains("Connect"), "{}", packets);
+    assert!(packets.contains("GameAction"), "{}", packets);
+
+    let history = read("history.log");
+    let lines: Vec<&str> = history.lines().collect();
+    let [line] = lines[..] else {
+        panic!("Not one move: {:?}", history);
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+    let [number, "Local", _, fen, hash] = fields[..] else {
+        panic!("Not the first move: {:?}", line);
+    };
+    assert_eq!(number.parse::<u16>().unwrap(), 0);
+    fen::from_fen(fen).unwrap();
+    u64::from_str_radix(hash, 16).unwrap();
+
+    assert_eq!(
+        fen::from_fen(&read("board.fen")).unwrap(),
+        fen::from_fen(board_fen).unwrap()
+    );
+}
+
+/// Wait until a ping has come back on this connection, and took some time.
+fn wait_for_ping() {
+    wait_for("a ping", || {
+        interface::get_ping_last().filter(|round_trip| !round_trip.is_zero())
+    });
+}
+
+#[test]
+#[ignore = "binds UDP sockets, and starts a child process"]
+fn both_sides_measure_their_ping() {
+    host_first_move("pinging_client", || {
+        wait_for_ping();
+        interface::send_chat_message(DONE).unwrap();
+    });
+}
+
+/// The client side of `both_sides_measure_their_ping()`. It leaves once it has measured its own
+/// ping, and the host is done measuring its. It does nothing unless it's started by it.
+#[test]
+#[ignore = "only run by both_sides_measure_their_ping"]
+fn pinging_client() {
+    let Some(runtime) = join_first_move() else {
+        return;
+    };
+    let _guard = runtime.enter();
+    wait_for_ping();
+    leave_when_done();
+}
+
+/// The latency the client adds to every packet it sends, so to every round trip.
+const INJECTED_LATENCY: Duration = Duration::from_millis(100);
+/// The most a round trip may take on top of `INJECTED_LATENCY`, on a loopback that can be slow on
+/// a busy machine.
+const LOOPBACK_SLACK: Duration = Duration::from_millis(100);
+
+/// Wait for the statistics of a few pings, and check that their median and 95th percentile are the
+/// injected latency.
+fn assert_injected_latency() {
+    let stats = wait_for("a few pings", || {
+        interface::get_network_stats().filter(|stats| stats.samples >= 3)
+    });
+    for (what, round_trip) in [("median", stats.median), ("95th percentile", stats.p95)] {
+        assert!(
+            INJECTED_LATENCY <= round_trip && round_trip <= INJECTED_LATENCY + LOOPBACK_SLACK,
+            "The {} is {:?}",
+            what,
+            round_trip
+        );
+    }
+    assert!(!stats.has_spike());
+}
+
+#[test]
+#[ignore = "binds UDP sockets, and starts a child process"]
+fn injected_latency_is_measured_by_both_sides() {
+    host_first_move("slow_client", || {
+        assert_injected_latency();
+        interface::send_chat_message(DONE).unwrap();
+    });
+}
+
+/// The client side of `injected_latency_is_measured_by_both_sides()`. Every packet it sends is
+/// delayed, so the round trips of both sides take `INJECTED_LATENCY`. It does nothing unless it's
+/// started by it.
+#[test]
+#[ignore = "only run by injected_latency_is_measured_by_both_sides"]
+fn slow_client() {
+    if env::var(JOIN_CODE_VAR).is_err() {
+        return;
+    }
+    interface::set_network_simulation(NetworkSimulation {
+        latency: Latency {
+            base: INJECTED_LATENCY,
+            jitter: Duration::ZERO,
+        },
+        ..NetworkSimulation::default()
+    });
+    let runtime = join_first_move().unwrap();
+    let _guard = runtime.enter();
+    assert_injected_latency();
+    leave_when_done();
+}
+
+/// How long the client keeps its main runtime busy. The client pings once a second.
+const BUSY_FOR: Duration = Duration::from_secs(4);
+
+#[test]
+#[ignore = "binds UDP sockets, and starts a child process"]
+fn busy_runtime_doesnt_hold_up_the_pings() {
+    host_first_move("busy_client", || {});
+}
+
+/// The client side of `busy_runtime_doesnt_hold_up_the_pings()`. It keeps every worker of its main
+/// runtime busy, like a long AI search would, while the pings must go on from the network runtime
+/// and come back in time. It does nothing unless it's started by it.
+#[test]
+#[ignore = "only run by busy_runtime_doesnt_hold_up_the_pings"]
+fn busy_client() {
+    let Some(runtime) = join_first_move() else {
+        return;
+    };
+    let _guard = runtime.enter();
+    wait_for_ping();
+    let before = interface::get_ping_loss();
+
+    // One more busy task than there are workers, so none is left over
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+    let end = Instant::now() + BUSY_FOR;
+    for _ in 0..=workers {
+        runtime.spawn(async move {
+            while Instant::now() < end {
+                std::hint::spin_loop();
+            }
+        });
+    }
+    // Anything else spawned on the main runtime waits until it's free again
+    let probe = runtime.spawn(async { Instant::now() });
+    while Instant::now() < end {
+        assert!(interface::is_connected(), "The busy runtime lost the host");
+        thread::sleep(POLL);
+    }
+    let ran_at = runtime.block_on(probe).unwrap();
+    assert!(ran_at >= end, "The main runtime wasn't busy");
+
+    let after = interface::get_ping_loss();
+    assert!(
+        after.sent >= before.sent + BUSY_FOR.as_secs() as u32 - 1,
+        "Only {} pings were sent while the runtime was busy",
+        after.sent - before.sent
+    );
+    assert_eq!(after.lost, before.lost, "Pings were lost");
+    interface::disconnect();
+}
+
+/// How long the host drops every packet it sends, so the client hears nothing. It's longer than the
+/// client waits before it rebinds its socket, and short enough for it to reconnect in time.
+const SILENT_FOR: Duration = Duration::from_secs(5);
+
+/// Tells the host that the client is back, from its new socket.
+const REBOUND: &str = "rebound";
+
+#[test]
+#[ignore = "binds UDP sockets, and starts a child process"]
+fn silent_client_rebinds_its_socket_and_moves_there() {
+    host_first_move("rebinding_client", || {
+        interface::set_network_simulation(NetworkSimulation {
+            loss: 100,
+            ..NetworkSimulation::default()
+        });
+        thread::sleep(SILENT_FOR);
+        interface::set_network_simulation(NetworkSimulation::default());
+
+        let (_, message) = wait_for("the client to be back", interface::get_next_chat_message);
+        assert_eq!(message, REBOUND);
+        interface::send_chat_message(DONE).unwrap();
+    });
+}
+
+/// The client side of `silent_client_rebinds_its_socket_and_moves_there()`. It hears nothing from
+/// the host for a while, like a socket that stopped receiving after a sleep, so it must rebind its
+/// socket, and the host must move it to the new port. It does nothing unless it's started by it.
+#[test]
+#[ignore = "only run by silent_client_rebinds_its_socket_and_moves_there"]
+fn rebinding_client() {
+    let Some(runtime) = join_first_move() else {
+        return;
+    };
+    let _guard = runtime.enter();
+    wait_for("the socket to be rebound", || {
+        (interface::get_socket_rebinds() > 0).then_some(())
+    });
+    wait_for("the host to answer the new socket", || {
+        interface::is_connected().then_some(())
+    });
+    assert_eq!(interface::get_socket_rebinds(), 1);
+    interface::send_chat_message(REBOUND).unwrap();
+    leave_when_done();
+}
+
+/// The grace period of the abandonment tests, short enough for a test.
+const GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// The options of the abandonment tests, which both sides must play with.
+fn abandonment_options(forfeit: bool) -> GameOptions {
+    GameOptions {
+        abandonment_policy: if forfeit {
+            AbandonmentPolicy::ForfeitAfter(GRACE_PERIOD)
+        } else {
+            AbandonmentPolicy::PauseForever
+        },
+        ..GameOptions::new()
+    }
+}
+
+/// Host a game with the abandonment policy of `forfeit` for the client test `client`, which makes
+/// the first move with it and then vanishes without a word. Returns how the game ended, as far as
+/// the host knows, after the client has been gone for three grace periods, and how long it took.
+fn host_vanishing_client(client: &str, forfeit: bool) -> Option<(GameResult, Duration)> {
+    let _hosting = HOSTING.lock().unwrap_or_else(|e| e.into_inner());
+    let runtime = Runtime::new().unwrap();
+    let _guard = runtime.enter();
+    interface::set_game_options(abandonment_options(forfeit));
+    let join_code = interface::start_loopback_host();
+
+    let mut client = spawn_client(client, &join_code);
+    wait_for("the client to join", || {
+        (interface::get_options_state() == OptionsState::Agreed).then_some(())
+    });
+    let color = interface::get_my_color().expect("No color after the coin flip");
+    exchange_first_move(color);
+    let status = wait_for("the client to exit", || client.try_wait().unwrap());
+    assert!(status.success(), "The client failed with {}", status);
+
+    let gone_at = Instant::now();
+    let mut result = None;
+    while result.is_none() && gone_at.elapsed() < 3 * GRACE_PERIOD {
+        result = interface::take_game_result().map(|result| (result, gone_at.elapsed()));
+        thread::sleep(POLL);
+    }
+    assert_eq!(interface::opponent_left_message(), None);
+    match result {
+        Some((result, _)) => assert_eq!(result.winner, Some(color), "The host didn't win"),
+        // Without a forfeit, the client could still come back
+        None => assert!(
+            interface::get_other_username().is_some(),
+            "The client was dropped"
+        ),
+    }
+
+    interface::disconnect();
+    interface::set_game_options(GameOptions::new());
+    result
+}
+
+/// Join the host with the abandonment policy of `forfeit`, make the first move, and vanish without
+/// telling the host. Does nothing unless the process was started by `host_vanishing_client()`.
+fn join_and_vanish(forfeit: bool) {
+    // The options are the whole process's, so they are only changed in the client's
+    if env::var(JOIN_CODE_VAR).is_err() {
+        return;
+    }
+    interface::set_game_options(abandonment_options(forfeit));
+    // The process ends without a disconnect, like a crashed game
+    let _runtime = join_first_move();
+}
+
+#[test]
+#[ignore = "binds UDP sockets, and starts a child process"]
+fn vanished_client_forfeits_after_the_grace_period() {
+    let (result, gone_for) =
+        host_vanishing_client("forfeiting_client", true).expect("The client never forfeited");
+    assert_eq!(result.reason, GameOverReason::Timeout);
+    // The grace period started with the last ping, up to a second before the client exited
+    assert!(
+        gone_for + Duration::from_secs(1) >= GRACE_PERIOD,
+        "Forfeited after {:?}",
+        gone_for
+    );
+}
+
+/// The client side of `vanished_client_forfeits_after_the_grace_period()`.
+#[test]
+#[ignore = "only run by vanished_client_forfeits_after_the_grace_period"]
+fn forfeiting_client() {
+    join_and_vanish(true);
+}
+
+#[test]
+#[ignore = "binds UDP sockets, and starts a child process"]
+fn paused_game_waits_for_the_vanished_client() {
+    assert_eq!(host_vanishing_client("paused_client", false), None);
+}
+
+/// The client side of `paused_game_waits_for_the_vanished_client()`.
+#[test]
+#[ignore = "only run by paused_game_waits_for_the_vanished_client"]
+fn paused_client() {
+    join_and_vanish(false);
+}
+
+#[test]
+#[ignore = "binds UDP sockets, and starts a child process"]
+fn client_with_other_options_is_refused() {
+    let _hosting = HOSTING.lock().unwrap_or_else(|e| e.into_inner());
+    let runtime = Runtime::new().unwrap();
+    let _guard = runtime.enter();
+    let join_code = interface::start_loopback_host();
+    let mut client = spawn_client("mismatched_client", &join_code);
+
+    wait_for("the options to be refused", || {
+        (interface::get_options_state() == OptionsState::Mismatch).then_some(())
+    });
+    let status = wait_for("the client to exit", || client.try_wait().unwrap());
+    assert!(status.success(), "The client failed with {}", status);
+    assert!(!interface::is_connected());
+    interface::disconnect();
+}
+
+/// The client side of `client_with_other_options_is_refused()`, playing on a larger board than the
+/// host. It does nothing unless it's started by it.
+#[test]
+#[ignore = "only run by client_with_other_options_is_refused"]
+fn mismatched_client() {
+    let Ok(join_code) = env::var(JOIN_CODE_VAR) else {
+        return;
+    };
+    let runtime = Runtime::new().unwrap();
+    let _guard = runtime.enter();
+    interface::set_game_options(GameOptions {
+        board_size: 10,
+        ..GameOptions::new()
+    });
+
+    interface::start_lan_client();
+    let e =
+        interface::connect_to_host_loop(&join_code, CLIENT_USERNAME, interface::JOIN_RETRY, |_| {})
+            .expect_err("The host took other options");
+    assert!(e.downcast_ref::<OptionsMismatch>().is_some(), "{}", e);
+    assert_eq!(interface::get_options_state(), OptionsState::Mismatch);
+    assert!(!interface::is_connected());
+}
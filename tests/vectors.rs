@@ -0,0 +1,28 @@
+//! Checks the protocol test vectors in `protocol/vectors.json` against the encoders and decoders.
+//! `cargo run --bin vectors -- gen-vectors` writes them again after a change to the wire format.
+
+use std::{fs, path::Path};
+
+use the_checker_mater::net::interface;
+
+#[test]
+fn protocol_vectors() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("protocol/vectors.json");
+    let source = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Can't read {:?}: {}. Run with gen-vectors", path, e));
+    let (count, failures) =
+        interface::verify_protocol_vectors(&source).expect("The vectors aren't valid JSON");
+
+    assert!(count > 0, "{:?} has no vectors", path);
+    let report: Vec<String> = failures
+        .iter()
+        .map(|(name, reason)| format!("{}: {}", name, reason))
+        .collect();
+    assert!(
+        failures.is_empty(),
+        "{} of {} vectors failed:\n{}",
+        failures.len(),
+        count,
+        report.join("\n")
+    );
+}